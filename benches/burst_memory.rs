@@ -0,0 +1,158 @@
+//! Memory held per tracked packet when a TCP trace is grouped into bursts.
+//!
+//! Feeds a 1M-packet bulk transfer (two data segments per ACK) through a
+//! `TcpTracker`, keeps every completed burst alive, and reports the heap in
+//! use per packet alongside the in-memory size of `DataPacket`. The layout
+//! `DataPacket` had before durations were stored as microseconds is included
+//! for comparison.
+//!
+//! Run with `cargo bench --bench burst_memory`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use network_listener::tcp_tracker::Burst;
+use network_listener::{
+    DataPacket, Direction, PacketType, ParsedPacket, TcpFlags, TcpOptions, TcpTracker, Timestamp,
+    TransportPacket,
+};
+use pnet::datalink::MacAddr;
+
+const PACKETS: usize = 1_000_000;
+const MSS: u16 = 1448;
+
+/// Counts live heap bytes so the bench can report what the bursts hold.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// `DataPacket` as it was laid out before the compact representation.
+#[allow(dead_code)]
+struct LegacyDataPacket {
+    payload_len: u16,
+    total_length: u16,
+    sent_time: SystemTime,
+    ack_time: Option<SystemTime>,
+    gap_last_ack: Option<Duration>,
+    gap_last_sent: Option<Duration>,
+    retransmissions: u8,
+    rtt: Option<Duration>,
+}
+
+fn tcp_packet(
+    i: usize,
+    direction: Direction,
+    sequence: u32,
+    acknowledgment: u32,
+    payload_len: u16,
+) -> ParsedPacket {
+    let local: IpAddr = [10, 0, 0, 1].into();
+    let remote: IpAddr = [10, 0, 0, 2].into();
+    let (src_ip, dst_ip) = match direction {
+        Direction::Outgoing => (local, remote),
+        Direction::Incoming => (remote, local),
+    };
+    ParsedPacket {
+        src_ip,
+        dst_ip,
+        src_mac: MacAddr::zero(),
+        dst_mac: MacAddr::zero(),
+        transport: TransportPacket::TCP {
+            sequence,
+            acknowledgment,
+            flags: TcpFlags::new(TcpFlags::ACK),
+            payload_len,
+            options: TcpOptions::new(),
+            src_port: 40000,
+            dst_port: 5201,
+            window_size: 65535,
+        },
+        total_length: payload_len + 52,
+        // 10 µs between packets, about 1 Gbit/s of full segments
+        timestamp: Timestamp::from_micros(1_000_000 + i as u64 * 10),
+        direction,
+        intercepted: false,
+        retry: false,
+        ttl: 64,
+        ip_id: 0,
+        fragment: None,
+        segments: 1,
+    }
+}
+
+/// Two data segments followed by the ACK covering both.
+fn trace() -> Vec<ParsedPacket> {
+    let mut packets = Vec::with_capacity(PACKETS);
+    let mut seq: u32 = 1;
+    while packets.len() < PACKETS {
+        let i = packets.len();
+        packets.push(tcp_packet(i, Direction::Outgoing, seq, 1, MSS));
+        seq = seq.wrapping_add(MSS as u32);
+        packets.push(tcp_packet(i + 1, Direction::Outgoing, seq, 1, MSS));
+        seq = seq.wrapping_add(MSS as u32);
+        packets.push(tcp_packet(i + 2, Direction::Incoming, 1, seq, 0));
+    }
+    packets.truncate(PACKETS);
+    packets
+}
+
+fn main() {
+    let trace = trace();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut tracker = TcpTracker::new();
+    let mut bursts: Vec<Burst> = Vec::new();
+    for packet in &trace {
+        if let Some((burst, _)) = tracker.register_packet(packet) {
+            bursts.push(burst);
+        }
+    }
+    let (sent, received) = tracker.take_bursts();
+    bursts.push(sent);
+    bursts.push(received);
+    let elapsed = start.elapsed().as_secs_f64();
+    let held = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    let tracked: usize = bursts
+        .iter()
+        .map(|burst| match burst {
+            Burst::Tcp(burst) => burst.iter().count(),
+            Burst::Udp(packets) | Burst::Other(packets) => packets.len(),
+        })
+        .sum();
+
+    println!("DataPacket:        {:>4} bytes", size_of::<DataPacket>());
+    println!("legacy DataPacket: {:>4} bytes", size_of::<LegacyDataPacket>());
+    println!("PacketType:        {:>4} bytes", size_of::<PacketType>());
+    println!(
+        "tracked {} packets in {} bursts, {:.1} MiB held, {:.1} bytes/packet",
+        tracked,
+        bursts.len(),
+        held as f64 / (1024.0 * 1024.0),
+        held as f64 / tracked.max(1) as f64
+    );
+    println!(
+        "throughput:        {:>10.0} pkt/s ({:.3}s)",
+        PACKETS as f64 / elapsed,
+        elapsed
+    );
+}
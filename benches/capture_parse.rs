@@ -0,0 +1,128 @@
+//! Compares the two capture-to-parser paths:
+//! - `copy`: copy each frame into an `OwnedPacket`, then parse it (default).
+//! - `in-place`: parse straight from the captured buffer (`client.parse_in_capture`).
+//!
+//! Both paths push their output through a bounded channel drained by a
+//! separate thread, mirroring the capture thread -> parser task hand-off.
+//!
+//! Run with `cargo bench --bench capture_parse`.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::Instant;
+
+use network_listener::listener::capture::{LinkType, OwnedPacket, PCAPMeta};
+use network_listener::ParsedPacket;
+use pcap::PacketHeader;
+use pnet::datalink::MacAddr;
+
+const PACKETS: usize = 2_000_000;
+const CHANNEL_CAPACITY: usize = 1000;
+
+/// Ethernet + IPv4 + TCP (with timestamp option) headers, truncated at snaplen.
+fn frame() -> Vec<u8> {
+    let mut data = Vec::with_capacity(134);
+    data.extend_from_slice(&[0x00; 6]);
+    data.extend_from_slice(&[0x01; 6]);
+    data.extend_from_slice(&[0x08, 0x00]);
+    data.extend_from_slice(&[
+        0x45, 0x00, 0x05, 0xdc, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 0, 0, 1, 10,
+        0, 0, 2,
+    ]);
+    data.extend_from_slice(&[
+        0x13, 0x89, 0xc3, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x80, 0x10, 0xff,
+        0xff, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x08, 0x0a, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x02,
+    ]);
+    data.resize(134, 0xab);
+    data
+}
+
+fn meta() -> PCAPMeta {
+    PCAPMeta {
+        mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
+        ipv4: Ipv4Addr::new(10, 0, 0, 2),
+        ipv6: Ipv6Addr::UNSPECIFIED,
+        name: "bench".to_string(),
+        tstamp_type: None,
+        link_type: LinkType::Ethernet,
+        routes: Vec::new(),
+        addresses: Vec::new(),
+        capture_offset: 0,
+    }
+}
+
+fn header(i: usize, caplen: usize) -> PacketHeader {
+    PacketHeader {
+        ts: libc::timeval {
+            tv_sec: (i / 1_000_000) as libc::time_t,
+            tv_usec: (i % 1_000_000) as libc::suseconds_t,
+        },
+        caplen: caplen as u32,
+        len: 1514,
+    }
+}
+
+/// Default path: copy in the capture thread, parse in the consumer.
+fn bench_copy(data: &[u8]) -> f64 {
+    let (tx, rx) = sync_channel::<OwnedPacket>(CHANNEL_CAPACITY);
+    let consumer = thread::spawn(move || {
+        let meta = meta();
+        let mut parsed = 0usize;
+        while let Ok(packet) = rx.recv() {
+            if ParsedPacket::from_packet(&packet, &meta).is_some() {
+                parsed += 1;
+            }
+        }
+        parsed
+    });
+
+    let start = Instant::now();
+    for i in 0..PACKETS {
+        let packet = OwnedPacket {
+            header: header(i, data.len()),
+            data: data.into(),
+        };
+        tx.send(packet).unwrap();
+    }
+    drop(tx);
+    assert_eq!(consumer.join().unwrap(), PACKETS);
+    start.elapsed().as_secs_f64()
+}
+
+/// `parse_in_capture` path: parse in the capture thread, send only the result.
+fn bench_in_place(data: &[u8]) -> f64 {
+    let (tx, rx) = sync_channel::<ParsedPacket>(CHANNEL_CAPACITY);
+    let consumer = thread::spawn(move || rx.iter().count());
+
+    let meta = meta();
+    let start = Instant::now();
+    for i in 0..PACKETS {
+        if let Some(parsed) = ParsedPacket::from_raw(&header(i, data.len()), data, &meta) {
+            tx.send(parsed).unwrap();
+        }
+    }
+    drop(tx);
+    assert_eq!(consumer.join().unwrap(), PACKETS);
+    start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let data = frame();
+
+    let copy = bench_copy(&data);
+    let in_place = bench_in_place(&data);
+
+    println!(
+        "copy:     {:>10.0} pkt/s ({:.3}s)",
+        PACKETS as f64 / copy,
+        copy
+    );
+    println!(
+        "in-place: {:>10.0} pkt/s ({:.3}s)",
+        PACKETS as f64 / in_place,
+        in_place
+    );
+    println!("speedup:  {:.2}x", copy / in_place);
+}
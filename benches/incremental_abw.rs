@@ -0,0 +1,115 @@
+//! Compares the running available bandwidth estimate with the batch one:
+//! - `batch`: filter and fit every stored point on each query (`PABWESender`).
+//! - `incremental`: update the fits as points arrive, O(1) per query
+//!   (`IncrementalAbw`).
+//!
+//! Points follow a known line with noise and a share of outliers. Only the
+//! bounds filter is used, the stages the incremental estimate has, so the
+//! least squares estimates must agree. The robust ones are compared to the
+//! true available bandwidth.
+//!
+//! Run with `cargo bench --bench incremental_abw`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use network_listener::config::Filter;
+use network_listener::{GinGout, IncrementalAbw, PABWESender, RegressionType, Timestamp};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const POINTS: [usize; 3] = [1_000, 10_000, 100_000];
+/// Queries timed per estimator and size.
+const QUERIES: usize = 100;
+/// Slope and intercept of `gout / gin` over `len / gin`.
+const SLOPE: f64 = 1.0 / 12.5e6;
+const INTERCEPT: f64 = 0.5;
+const MSS: f64 = 1448.0;
+
+fn points(count: usize, rng: &mut StdRng) -> Vec<GinGout> {
+    (0..count)
+        .map(|i| {
+            let x = rng.random_range(4e6..20e6);
+            let gin = MSS / x;
+            let mut ratio = (SLOPE * x + INTERCEPT) * rng.random_range(0.97..1.03);
+            if rng.random_bool(0.05) {
+                ratio *= rng.random_range(1.5..4.0);
+            }
+            GinGout {
+                gin,
+                gout: ratio * gin,
+                len: MSS,
+                num_acked: 1,
+                timestamp: Timestamp::from_micros(i as u64 * 100),
+                app_limited: false,
+            }
+        })
+        .collect()
+}
+
+/// Error of `abw` in percent of `truth`, NaN without an estimate.
+fn error(abw: Option<f64>, truth: f64) -> f64 {
+    abw.map_or(f64::NAN, |abw| 100.0 * (abw - truth) / truth)
+}
+
+fn main() {
+    let truth = (1.0 - INTERCEPT) / SLOPE;
+    let filter = Filter {
+        min_payload: 1362.0,
+        gin_quantile: 0.0,
+        mad_threshold: 0.0,
+    };
+    let mut rng = StdRng::seed_from_u64(7);
+    println!("true abw {:.0} B/s", truth);
+
+    for count in POINTS {
+        let dps = points(count, &mut rng);
+
+        let start = Instant::now();
+        let mut incremental = IncrementalAbw::new(filter.min_payload, f64::MAX);
+        for dp in &dps {
+            incremental.push(dp);
+        }
+        let push = start.elapsed().as_secs_f64();
+
+        let mut batch = PABWESender::new();
+        batch.dps = dps;
+        for regression in RegressionType::ALL {
+            let start = Instant::now();
+            let mut expected = None;
+            for _ in 0..QUERIES {
+                expected = black_box(batch.estimate_abw(regression, &filter, f64::MAX)).0;
+            }
+            let batch_time = start.elapsed().as_secs_f64() / QUERIES as f64;
+
+            let start = Instant::now();
+            let mut estimate = None;
+            for _ in 0..QUERIES {
+                estimate = black_box(incremental.estimate(regression));
+            }
+            let query_time = start.elapsed().as_secs_f64() / QUERIES as f64;
+
+            let (expected, estimate) = (expected.map(|e| e.abw), estimate.map(|e| e.abw));
+            if regression == RegressionType::Simple {
+                let agree = match (expected, estimate) {
+                    (Some(expected), Some(estimate)) => {
+                        (expected - estimate).abs() <= 1e-9 * expected.abs()
+                    }
+                    (expected, estimate) => expected.is_none() && estimate.is_none(),
+                };
+                assert!(agree, "Least squares estimates differ: {:?} {:?}", expected, estimate);
+            }
+            println!(
+                "{:>6} points {:>6}: batch {:>10.3} ms/query ({:+.2}%), \
+                 incremental {:>8.3} µs/query ({:+.2}%), {:.1} ns/push",
+                count,
+                regression.name(),
+                batch_time * 1e3,
+                error(expected, truth),
+                query_time * 1e6,
+                error(estimate, truth),
+                push * 1e9 / count as f64
+            );
+        }
+    }
+}
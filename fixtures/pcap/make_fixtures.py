@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+"""Writes the pcap files the parser tests load with include_bytes.
+
+The packets are made up, with documentation addresses only: the local host
+is 192.0.2.1 / 2001:db8::1 with MAC 02:00:00:00:00:01, the remote host
+198.51.100.2 / 2001:db8::2 with MAC 02:00:00:00:00:02. Checksums are left
+at zero, the parser does not check them. Run from this directory.
+"""
+
+import struct
+
+LOCAL_MAC = bytes([2, 0, 0, 0, 0, 1])
+REMOTE_MAC = bytes([2, 0, 0, 0, 0, 2])
+LOCAL_V4 = bytes([192, 0, 2, 1])
+REMOTE_V4 = bytes([198, 51, 100, 2])
+LOCAL_V6 = bytes.fromhex("20010db8000000000000000000000001")
+REMOTE_V6 = bytes.fromhex("20010db8000000000000000000000002")
+
+# Seconds of the first packet in every file
+START = 1_700_000_000
+
+TCP_FIN, TCP_SYN, TCP_PSH, TCP_ACK = 0x01, 0x02, 0x08, 0x10
+
+
+def write_pcap(name, packets):
+    """packets: (microseconds after START, frame) pairs."""
+    with open(name, "wb") as f:
+        # Microsecond magic, version 2.4, Ethernet
+        f.write(struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, 1))
+        for micros, frame in packets:
+            secs, usecs = divmod(micros, 1_000_000)
+            f.write(struct.pack("<IIII", START + secs, usecs, len(frame), len(frame)))
+            f.write(frame)
+
+
+def ethernet(outgoing, ethertype, payload, vlans=()):
+    dst, src = (REMOTE_MAC, LOCAL_MAC) if outgoing else (LOCAL_MAC, REMOTE_MAC)
+    header = dst + src
+    for tpid, vid in vlans:
+        header += struct.pack(">HH", tpid, vid)
+    return header + struct.pack(">H", ethertype) + payload
+
+
+def ipv4(outgoing, protocol, payload, ident=0):
+    src, dst = (LOCAL_V4, REMOTE_V4) if outgoing else (REMOTE_V4, LOCAL_V4)
+    header = struct.pack(">BBHHHBBH", 0x45, 0, 20 + len(payload), ident, 0x4000, 64, protocol, 0)
+    return header + src + dst + payload
+
+
+def ipv6(outgoing, next_header, payload):
+    src, dst = (LOCAL_V6, REMOTE_V6) if outgoing else (REMOTE_V6, LOCAL_V6)
+    header = struct.pack(">IHBB", 6 << 28, len(payload), next_header, 64)
+    return header + src + dst + payload
+
+
+def tcp(src_port, dst_port, seq, ack, flags, payload_len=0):
+    header = struct.pack(">HHIIBBHHH", src_port, dst_port, seq, ack, 5 << 4, flags, 65535, 0, 0)
+    return header + bytes(payload_len)
+
+
+def udp(src_port, dst_port, payload_len):
+    return struct.pack(">HHHH", src_port, dst_port, 8 + payload_len, 0) + bytes(payload_len)
+
+
+def tcp_transfer():
+    """Handshake, then ten 1000 byte segments 1 ms apart, each pair acked
+    together 20 ms after the second was sent."""
+    local_port, remote_port = 40000, 5001
+    isn_local, isn_remote = 1000, 5000
+    rtt = 20_000
+
+    def out(t, seq, ack, flags, length=0):
+        segment = tcp(local_port, remote_port, seq, ack, flags, length)
+        return (t, ethernet(True, 0x0800, ipv4(True, 6, segment)))
+
+    def into(t, seq, ack, flags):
+        segment = tcp(remote_port, local_port, seq, ack, flags)
+        return (t, ethernet(False, 0x0800, ipv4(False, 6, segment)))
+
+    packets = [
+        out(0, isn_local, 0, TCP_SYN),
+        into(rtt, isn_remote, isn_local + 1, TCP_SYN | TCP_ACK),
+        out(rtt + 100, isn_local + 1, isn_remote + 1, TCP_ACK),
+    ]
+    start = rtt + 1_000
+    seq = isn_local + 1
+    acks = []
+    for i in range(10):
+        t = start + i * 1_000
+        packets.append(out(t, seq, isn_remote + 1, TCP_ACK | TCP_PSH, 1000))
+        seq += 1000
+        if i % 2 == 1:
+            acks.append(into(t + rtt, isn_remote + 1, seq, TCP_ACK))
+    write_pcap("tcp_transfer.pcap", sorted(packets + acks, key=lambda p: p[0]))
+
+
+def udp_stream():
+    """Five 500 byte datagrams 2 ms apart."""
+    packets = [
+        (i * 2_000, ethernet(True, 0x0800, ipv4(True, 17, udp(40001, 5201, 500), ident=i + 1)))
+        for i in range(5)
+    ]
+    write_pcap("udp_stream.pcap", packets)
+
+
+def vlan():
+    """A datagram each way in VLAN 100, and one in QinQ 200/100."""
+    datagram = udp(40002, 53, 40)
+    packets = [
+        (0, ethernet(True, 0x0800, ipv4(True, 17, datagram), vlans=[(0x8100, 100)])),
+        (1_000, ethernet(False, 0x0800, ipv4(False, 17, udp(53, 40002, 80)), vlans=[(0x8100, 100)])),
+        (2_000, ethernet(True, 0x0800, ipv4(True, 17, datagram), vlans=[(0x88A8, 200), (0x8100, 100)])),
+    ]
+    write_pcap("vlan.pcap", packets)
+
+
+def ipv6_extensions():
+    """UDP behind a hop-by-hop header, TCP behind destination options, and
+    the first fragment of a UDP datagram."""
+    # Next header, length 0 (8 octets), PadN of 4
+    hop_by_hop = struct.pack(">BB", 17, 0) + bytes([1, 4, 0, 0, 0, 0])
+    dest_options = struct.pack(">BB", 6, 0) + bytes([1, 4, 0, 0, 0, 0])
+    # Next header UDP, offset 0 with more fragments, identification 7
+    fragment = struct.pack(">BBHI", 17, 0, 0x0001, 7)
+    packets = [
+        (0, ethernet(True, 0x86DD, ipv6(True, 0, hop_by_hop + udp(40003, 5201, 100)))),
+        (1_000, ethernet(True, 0x86DD, ipv6(True, 60, dest_options + tcp(40004, 5001, 1, 0, TCP_SYN)))),
+        (2_000, ethernet(False, 0x86DD, ipv6(False, 44, fragment + udp(5201, 40003, 1000)))),
+    ]
+    write_pcap("ipv6_extensions.pcap", packets)
+
+
+if __name__ == "__main__":
+    tcp_transfer()
+    udp_stream()
+    vlan()
+    ipv6_extensions()
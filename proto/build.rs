@@ -0,0 +1,23 @@
+/// Messages served as JSON by the node's HTTP API, with every message
+/// nested in them.
+const SERIALIZED: [&str; 7] = [
+    ".bandwidth.LinkState",
+    ".bandwidth.EstimatorAbw",
+    ".bandwidth.ThroughputPercentiles",
+    ".bandwidth.FlowCompletionTimes",
+    ".bandwidth.ReceiveWindowStats",
+    ".bandwidth.FlowRecord",
+    ".bandwidth.LinkEstimate",
+];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut builder = tonic_build::configure();
+    for path in SERIALIZED {
+        builder = builder.type_attribute(path, "#[derive(serde::Serialize)]");
+    }
+    builder.compile_protos(&["bandwidth.proto"], &["."])?;
+    if std::env::var_os("CARGO_FEATURE_CORE").is_some() {
+        tonic_build::compile_protos("core.proto")?;
+    }
+    Ok(())
+}
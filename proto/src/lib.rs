@@ -0,0 +1,19 @@
+//! Protobuf messages and gRPC services shared by the listener nodes and the
+//! scheduler, generated from the `.proto` files in this crate.
+
+/// Messages between nodes, and from nodes to the scheduler.
+pub mod bandwidth {
+    tonic::include_proto!("bandwidth");
+
+    /// Version of the messages in `bandwidth.proto`, bumped on breaking
+    /// changes only. See the policy at the top of the file.
+    pub const PROTOCOL_VERSION: u32 = 1;
+    /// Oldest version a peer may speak to be understood by this one.
+    pub const MIN_PROTOCOL_VERSION: u32 = 1;
+}
+
+/// Client for the CORE emulator API.
+#[cfg(feature = "core")]
+pub mod core_api {
+    tonic::include_proto!("core");
+}
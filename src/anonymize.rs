@@ -0,0 +1,428 @@
+//! Pseudonymous addresses in what leaves the node, for sharing datasets, if
+//! `anonymize.enabled`: data messages to the collectors and subscribers,
+//! feature vectors, probe results of the HTTP API, pcap dumps and the ring.
+//! Everything inside the node, the routing export and the messages between
+//! peers keep the real addresses. Subscribers and the HTTP API take peers
+//! by their pseudonyms.
+//!
+//! The mapping is prefix preserving, addresses sharing their first n bits
+//! get pseudonyms sharing their first n bits, so subnets stay subnets. Bit
+//! i of the pseudonym is bit i of the address, flipped by a keyed hash of
+//! the bits before it (Crypto-PAn, with HMAC-SHA256 instead of AES). The
+//! key is kept in `anonymize.key_file` and created on first use. Nodes with
+//! the same key map addresses the same way, and whoever has it can map the
+//! pseudonyms back with `Anonymizer::reveal`.
+//!
+//! In packets and flow records, the ports are zeroed as well, and the MAC
+//! addresses of Ethernet frames. Checksums that no longer match are zeroed,
+//! except the IPv4 header checksum, which is recomputed.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use log::info;
+use sha2::Sha256;
+
+use crate::listener::packet::link_layer::{LinkFrame, LinkType};
+use crate::proto_bw::{data_msg, DataMsg, LinkState, PgmMessage, Rtts};
+use crate::stream_id::IpPair;
+use crate::CONFIG;
+
+/// Shortest key accepted from `anonymize.key_file`.
+const MIN_KEY_LEN: usize = 16;
+/// Pseudonyms remembered per thread, the cache is emptied when it is full.
+const MAX_CACHED: usize = 65_536;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+static ANONYMIZER: OnceLock<Anonymizer> = OnceLock::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Pseudonyms found on this thread, by anonymizer id and address, so the
+    /// threads exporting do not wait on each other for every packet.
+    static PSEUDONYMS: RefCell<HashMap<(u64, IpAddr), IpAddr>> = RefCell::default();
+}
+
+/// Loads the key if `anonymize.enabled`. Called at startup, so a key that
+/// cannot be read fails the start instead of the first export. Until then,
+/// exports keep the real addresses.
+pub fn init() -> Result<()> {
+    if !CONFIG.anonymize.enabled || ANONYMIZER.get().is_some() {
+        return Ok(());
+    }
+    let key = load_key(&CONFIG.anonymize.key_file)?;
+    info!("Exporting pseudonymous addresses");
+    let _ = ANONYMIZER.set(Anonymizer::new(&key));
+    Ok(())
+}
+
+/// The anonymizer for exports, None if they keep the real addresses.
+fn exports() -> Option<&'static Anonymizer> {
+    ANONYMIZER.get()
+}
+
+/// Reads the key in `path`, creating it if there is none.
+fn load_key(path: &Path) -> Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(key) if key.len() >= MIN_KEY_LEN => Ok(key),
+        Ok(_) => Err(anyhow!(
+            "Anonymization key {} is shorter than {} bytes",
+            path.display(),
+            MIN_KEY_LEN
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key: [u8; 32] = rand::random();
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(&key))
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            info!("Created anonymization key {}", path.display());
+            Ok(key.to_vec())
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// `ip` as exported.
+pub fn export_ip(ip: IpAddr) -> IpAddr {
+    exports().map_or(ip, |anonymizer| anonymizer.ip(ip))
+}
+
+/// `pair` as exported.
+pub fn export_pair(pair: IpPair) -> IpPair {
+    exports().map_or(pair, |anonymizer| anonymizer.pair(pair))
+}
+
+/// Rewrites the addresses in `msg` if exports are anonymized.
+pub fn export_data_msg(msg: &mut DataMsg) {
+    if let Some(anonymizer) = exports() {
+        anonymizer.data_msg(msg);
+    }
+}
+
+/// The captured bytes of a frame as exported.
+pub fn export_frame(link_type: LinkType, data: &[u8]) -> Cow<'_, [u8]> {
+    match exports() {
+        Some(anonymizer) => {
+            let mut data = data.to_vec();
+            anonymizer.frame(link_type, &mut data);
+            Cow::Owned(data)
+        }
+        None => Cow::Borrowed(data),
+    }
+}
+
+pub struct Anonymizer {
+    /// Keyed, cloned for every bit.
+    mac: Hmac<Sha256>,
+    /// Tells its pseudonyms apart from those of other keys in `PSEUDONYMS`.
+    id: u64,
+}
+
+impl Anonymizer {
+    pub fn new(key: &[u8]) -> Self {
+        Anonymizer {
+            mac: Hmac::new_from_slice(key).expect("HMAC takes keys of any length"),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Bit flipping bit `index` of an address `width` bits long, whose
+    /// bits before it are `prefix`.
+    fn flip(&self, width: u32, index: u32, prefix: u128) -> u128 {
+        let mut mac = self.mac.clone();
+        mac.update(&[width as u8, index as u8]);
+        mac.update(&prefix.to_be_bytes());
+        (mac.finalize().into_bytes()[0] & 1) as u128
+    }
+
+    /// Maps the `width` bits of `addr`, or maps them back if `reveal`.
+    fn permute(&self, addr: u128, width: u32, reveal: bool) -> u128 {
+        // Bits of the real address found so far
+        let mut real = 0u128;
+        let mut out = 0u128;
+        for index in 0..width {
+            let pos = width - 1 - index;
+            let flip = self.flip(width, index, real.checked_shr(pos + 1).unwrap_or(0));
+            let bit = (addr >> pos) & 1;
+            let mapped = bit ^ flip;
+            real |= (if reveal { mapped } else { bit }) << pos;
+            out |= mapped << pos;
+        }
+        out
+    }
+
+    fn map(&self, ip: IpAddr, reveal: bool) -> IpAddr {
+        match ip {
+            IpAddr::V4(v4) => {
+                let bits = self.permute(u32::from(v4) as u128, 32, reveal);
+                IpAddr::V4((bits as u32).into())
+            }
+            IpAddr::V6(v6) => IpAddr::V6(self.permute(u128::from(v6), 128, reveal).into()),
+        }
+    }
+
+    /// Pseudonym of `ip`.
+    pub fn ip(&self, ip: IpAddr) -> IpAddr {
+        PSEUDONYMS.with_borrow_mut(|cache| {
+            if let Some(pseudonym) = cache.get(&(self.id, ip)) {
+                return *pseudonym;
+            }
+            let pseudonym = self.map(ip, false);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+            cache.insert((self.id, ip), pseudonym);
+            pseudonym
+        })
+    }
+
+    /// The address behind `pseudonym`.
+    pub fn reveal(&self, pseudonym: IpAddr) -> IpAddr {
+        self.map(pseudonym, true)
+    }
+
+    pub fn pair(&self, pair: IpPair) -> IpPair {
+        IpPair::new(self.ip(pair.local()), self.ip(pair.remote()))
+    }
+
+    /// Replaces `s` by its pseudonym if it is an address.
+    fn ip_string(&self, s: &mut String) {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            *s = self.ip(ip).to_string();
+        }
+    }
+
+    pub fn data_msg(&self, msg: &mut DataMsg) {
+        self.ip_string(&mut msg.node_id);
+        match &mut msg.data {
+            Some(data_msg::Data::Bandwidth(bw)) => self.link_states(&mut bw.link_state),
+            Some(data_msg::Data::Hello(hello)) => {
+                self.ip_string(&mut hello.message);
+                // Signs the real address
+                hello.identity = None;
+            }
+            Some(data_msg::Data::Rtts(rtts)) => self.rtts(rtts),
+            Some(data_msg::Data::Pgmmsg(pgm)) => self.pgm(pgm),
+            Some(data_msg::Data::Report(report)) => {
+                self.link_states(&mut report.link_state);
+                if let Some(rtts) = &mut report.rtts {
+                    self.rtts(rtts);
+                }
+                if let Some(pgm) = &mut report.pgm {
+                    self.pgm(pgm);
+                }
+            }
+            Some(data_msg::Data::Events(events)) => {
+                for event in &mut events.events {
+                    self.ip_string(&mut event.sender_ip);
+                    self.ip_string(&mut event.receiver_ip);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn link_states(&self, states: &mut [LinkState]) {
+        for state in states {
+            self.ip_string(&mut state.sender_ip);
+            self.ip_string(&mut state.receiver_ip);
+            self.ip_string(&mut state.next_hop);
+            for flow in &mut state.flows {
+                self.ip_string(&mut flow.remote_ip);
+                flow.local_port = 0;
+                flow.remote_port = 0;
+            }
+        }
+    }
+
+    fn rtts(&self, rtts: &mut Rtts) {
+        for rtt in &mut rtts.rtts {
+            self.ip_string(&mut rtt.sender_ip);
+            self.ip_string(&mut rtt.receiver_ip);
+        }
+    }
+
+    fn pgm(&self, pgm: &mut PgmMessage) {
+        for dps in &mut pgm.pgm_dps {
+            self.ip_string(&mut dps.sender_ip);
+            self.ip_string(&mut dps.receiver_ip);
+        }
+    }
+
+    /// Replaces the address in `bytes`, 4 or 16 of them, by its pseudonym.
+    fn replace_addr(&self, bytes: &mut [u8]) {
+        let ip = if let Ok(octets) = <[u8; 4]>::try_from(&*bytes) {
+            IpAddr::from(octets)
+        } else if let Ok(octets) = <[u8; 16]>::try_from(&*bytes) {
+            IpAddr::from(octets)
+        } else {
+            return;
+        };
+        match self.ip(ip) {
+            IpAddr::V4(v4) => bytes.copy_from_slice(&v4.octets()),
+            IpAddr::V6(v6) => bytes.copy_from_slice(&v6.octets()),
+        }
+    }
+
+    /// Rewrites the IP and transport headers of a captured frame, see the
+    /// module docs. Frames that are not IP are left alone.
+    pub fn frame(&self, link_type: LinkType, data: &mut [u8]) {
+        let Some(frame) = LinkFrame::decode(link_type, data) else {
+            return;
+        };
+        let (start, is_ipv6) = (frame.header_len, frame.is_ipv6);
+        if link_type == LinkType::Ethernet {
+            data[..12].fill(0);
+        }
+        let ip = &mut data[start..];
+        let (header_len, protocol) = if is_ipv6 {
+            if ip.len() < 40 {
+                return;
+            }
+            self.replace_addr(&mut ip[8..24]);
+            self.replace_addr(&mut ip[24..40]);
+            // Extension headers are not followed
+            (40, ip[6])
+        } else {
+            if ip.len() < 20 {
+                return;
+            }
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            if header_len < 20 || ip.len() < header_len {
+                return;
+            }
+            self.replace_addr(&mut ip[12..16]);
+            self.replace_addr(&mut ip[16..20]);
+            ip[10..12].fill(0);
+            let checksum = ipv4_checksum(&ip[..header_len]);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            // Only the first fragment has the transport header
+            if u16::from_be_bytes([ip[6], ip[7]]) & 0x1fff != 0 {
+                return;
+            }
+            (header_len, ip[9])
+        };
+        let segment = &mut ip[header_len..];
+        match protocol {
+            IPPROTO_TCP if segment.len() >= 18 => {
+                segment[..4].fill(0);
+                segment[16..18].fill(0);
+            }
+            IPPROTO_UDP if segment.len() >= 8 => {
+                segment[..4].fill(0);
+                segment[6..8].fill(0);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Internet checksum of an IPv4 header with its checksum field zeroed.
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto_bw::{BandwidthMessage, LinkState};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// Leading bits `a` and `b` have in common.
+    fn common_prefix(a: IpAddr, b: IpAddr) -> u32 {
+        match (a, b) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) ^ u32::from(b)).leading_zeros(),
+            (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a) ^ u128::from(b)).leading_zeros(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_mapping_preserves_prefixes() {
+        let anonymizer = Anonymizer::new(b"0123456789abcdef");
+        let pairs = [
+            ("10.0.0.1", "10.0.0.2"),
+            ("10.0.0.1", "10.0.1.1"),
+            ("10.0.0.1", "192.168.1.1"),
+            ("2001:db8::1", "2001:db8::1:1"),
+        ];
+        for (a, b) in pairs {
+            let (a, b) = (ip(a), ip(b));
+            let (pa, pb) = (anonymizer.ip(a), anonymizer.ip(b));
+            assert_ne!(pa, a);
+            assert_eq!(common_prefix(pa, pb), common_prefix(a, b));
+            assert_eq!(anonymizer.reveal(pa), a);
+            assert_eq!(anonymizer.reveal(pb), b);
+        }
+        let other = Anonymizer::new(b"fedcba9876543210");
+        assert_ne!(other.ip(ip("10.0.0.1")), anonymizer.ip(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_data_msg_and_frame() {
+        let anonymizer = Anonymizer::new(b"0123456789abcdef");
+        let mut msg = DataMsg {
+            data: Some(data_msg::Data::Bandwidth(BandwidthMessage {
+                link_state: vec![LinkState {
+                    sender_ip: "10.0.0.1".to_string(),
+                    receiver_ip: "10.0.0.2".to_string(),
+                    egress_iface: "eth0".to_string(),
+                    ..Default::default()
+                }],
+            })),
+            node_id: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        anonymizer.data_msg(&mut msg);
+        let pseudonym = anonymizer.ip(ip("10.0.0.1")).to_string();
+        assert_eq!(msg.node_id, pseudonym);
+        let Some(data_msg::Data::Bandwidth(bw)) = &msg.data else {
+            panic!("Expected link states");
+        };
+        assert_eq!(bw.link_state[0].sender_ip, pseudonym);
+        assert_eq!(bw.link_state[0].egress_iface, "eth0");
+
+        // IPv4 and UDP on a raw link
+        let mut packet = [
+            [0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0].as_slice(),
+            &[10, 0, 0, 1, 10, 0, 0, 2],
+            // Ports 4000 and 5000, length and checksum
+            &[0x0f, 0xa0, 0x13, 0x88, 0, 8, 0x12, 0x34],
+        ]
+        .concat();
+        anonymizer.frame(LinkType::Raw, &mut packet);
+        assert_eq!(
+            packet[12..16],
+            pseudonym.parse::<std::net::Ipv4Addr>().unwrap().octets()
+        );
+        assert_eq!(packet[20..24], [0, 0, 0, 0]);
+        assert_eq!(packet[26..28], [0, 0]);
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+    }
+}
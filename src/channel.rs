@@ -0,0 +1,46 @@
+//! How failed channel sends are handled.
+//!
+//! A send only fails once the receiving task has exited, which should never
+//! take the sending task down with it. Tasks that have other work to do log
+//! and drop the message with `send_or_log`. Tasks that only exist to feed
+//! the receiver stop by returning the error from `send_or_err`.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use tokio::sync::mpsc::Sender;
+
+/// Sends `value`, logging and dropping it if the receiver is gone. `what`
+/// names the message in the log. Returns true if it was delivered.
+pub async fn send_or_log<T>(tx: &Sender<T>, value: T, what: &str) -> bool {
+    match tx.send(value).await {
+        Ok(()) => true,
+        Err(_) => {
+            warn!("Dropped {}, receiver has stopped", what);
+            false
+        }
+    }
+}
+
+/// Sends `value`, failing with an error naming `what` if the receiver is
+/// gone.
+pub async fn send_or_err<T>(tx: &Sender<T>, value: T, what: &str) -> Result<()> {
+    tx.send(value)
+        .await
+        .map_err(|_| anyhow!("Failed to send {}, receiver has stopped", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn test_closed_receiver() {
+        let (tx, rx) = channel::<u32>(1);
+        assert!(send_or_log(&tx, 1, "number").await);
+        drop(rx);
+        assert!(!send_or_log(&tx, 2, "number").await);
+        let err = send_or_err(&tx, 3, "number").await.unwrap_err();
+        assert!(err.to_string().contains("number"));
+    }
+}
@@ -1,21 +1,89 @@
 use clap::Parser;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::{path::Path, time::Duration, u32};
-use crate::RegressionType;
+use crate::{OffloadMode, RegressionType, SEGMENT_HEADERS};
+use anyhow::bail;
+use tonic::codec::CompressionEncoding;
 
 #[derive(Deserialize, Debug)]
 pub struct AppConfig {
     pub client: Client,
     pub server: Server,
+    #[serde(default)]
+    pub probe: Probe,
+    #[serde(default)]
+    pub filter: Filter,
+    #[serde(default)]
+    pub packet_filter: PacketFilters,
+    #[serde(default)]
+    pub features: Features,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub events: Events,
+    /// Tags sent with every data message and stored by the scheduler, e.g.
+    /// the role of the node, the mobility model or a run tag.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub simulation: Simulation,
+    #[serde(default)]
+    pub privileges: Privileges,
+    #[serde(default)]
+    pub anonymize: Anonymize,
+    #[serde(default)]
+    pub identity: Identity,
+    #[serde(default)]
+    pub capture: CaptureSettings,
+    #[serde(default)]
+    pub tracking: TrackingSettings,
+    #[serde(default)]
+    pub offload: Offload,
+    /// `--selftest`, check the setup and exit, see `selftest`.
+    #[serde(skip)]
+    pub selftest: bool,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Client {
     pub ip: Option<String>,
     pub iface: Option<String>,
+    /// How long to wait at startup for `iface` to appear, in seconds.
+    /// 0 waits forever.
+    #[serde(
+        default = "default_iface_wait",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub iface_wait: Duration,
+    /// Address the gRPC server binds to.
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: IpAddr,
     #[serde(default = "default_listen_port")]
     pub listen_port: u16,
+    /// Only these addresses may say hello to the gRPC server or request
+    /// probes and clock syncs from it, anyone if empty.
+    #[serde(default)]
+    pub known_peers: Vec<IpAddr>,
+    /// Besides this host, the addresses that may change or dump what the
+    /// node does over the gRPC server: set the estimator or subnets, dump a
+    /// link or read the status.
+    #[serde(default)]
+    pub control_peers: Vec<IpAddr>,
+    /// Turn down hellos without a valid identity, see `identity`.
+    #[serde(default)]
+    pub require_identity: bool,
+    /// Requests per second each address may make to the gRPC server, in
+    /// bursts of up to `request_burst`. 0 for no limit.
+    #[serde(default = "default_request_rate")]
+    pub request_rate: f64,
+    #[serde(default = "default_request_burst")]
+    pub request_burst: u32,
     #[serde(default = "default_link_phy_cap")]
     pub link_phy_cap: u32,
     #[serde(
@@ -23,6 +91,10 @@ pub struct Client {
         deserialize_with = "duration_deserialize"
     )]
     pub measurement_window: Duration,
+    /// Timestamp type of the capture, falling back along
+    /// `listener::tstamp::FALLBACK` where the device does not have it.
+    /// "adapter" and "adapter_unsynced" turn on hardware timestamps in the
+    /// driver, best with `timestamp_precision = "nano"`.
     #[serde(
         default = "default_tstamp_type",
         deserialize_with = "tstamp_type_deserialize"
@@ -38,6 +110,144 @@ pub struct Client {
         deserialize_with = "regression_type_deserialize"
     )]
     pub regression_type: RegressionType,
+    /// Run every estimator on each link and report all their estimates,
+    /// besides the one from `regression_type`.
+    #[serde(default)]
+    pub compare_estimators: bool,
+    /// Parse headers in the capture thread and send `ParsedPacket`s instead
+    /// of copying each frame to the parser task.
+    #[serde(default = "default_parse_in_capture")]
+    pub parse_in_capture: bool,
+    /// Keep active probe traffic out of the passive estimates. It is still
+    /// reported separately in `probe_thp_in`/`probe_thp_out`.
+    #[serde(default = "default_exclude_probe_traffic")]
+    pub exclude_probe_traffic: bool,
+    /// Count packets relayed through this node on the links to the previous
+    /// and next hop, instead of twice on the end-to-end link.
+    #[serde(default = "default_relay_links")]
+    pub relay_links: bool,
+    /// Reporting interval for links to peers we have a gRPC connection to.
+    #[serde(
+        default = "default_vip_report_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub vip_report_interval: Duration,
+    /// Reporting interval for all other links, 0 to report them at every
+    /// measurement window.
+    #[serde(
+        default = "default_background_report_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub background_report_interval: Duration,
+    /// How often to probe links to peers, 0 disables it.
+    #[serde(
+        default = "default_vip_probe_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub vip_probe_interval: Duration,
+    /// How often to estimate the clock offset to peers, 0 disables it.
+    #[serde(
+        default = "default_clock_sync_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub clock_sync_interval: Duration,
+    /// How often connected peers are checked for a response, 0 disables it.
+    #[serde(
+        default = "default_health_check_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub health_check_interval: Duration,
+    /// Peers unreachable for longer than this are dropped.
+    #[serde(
+        default = "default_peer_grace_period",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub peer_grace_period: Duration,
+    /// Reporting interval in seconds per remote IP, overrides the above.
+    #[serde(default)]
+    pub link_windows: HashMap<String, u32>,
+    /// Keep traffic on the ports this tool listens on and streams to
+    /// (`server.port`, the collector ports, `client.listen_port`) out of the
+    /// statistics.
+    #[serde(default = "default_exclude_own_ports")]
+    pub exclude_own_ports: bool,
+    /// Additional ports to keep out of the statistics.
+    #[serde(default)]
+    pub exclude_ports: Vec<u16>,
+    /// Prefixes to keep out of the statistics, e.g. "10.0.0.0/24".
+    #[serde(default)]
+    pub exclude_prefixes: Vec<String>,
+    /// Gap in seconds that ends a burst of non-TCP/UDP traffic (ICMP, GRE, ...).
+    #[serde(
+        default = "default_other_burst_gap",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub other_burst_gap: Duration,
+    /// Largest burst of non-TCP/UDP traffic, in packets.
+    #[serde(default = "default_other_burst_packets")]
+    pub other_burst_packets: usize,
+    /// Gap in seconds that ends a burst of UDP traffic.
+    #[serde(
+        default = "default_udp_burst_gap",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub udp_burst_gap: Duration,
+    /// Largest burst of UDP traffic, in packets.
+    #[serde(default = "default_udp_burst_packets")]
+    pub udp_burst_packets: usize,
+    /// Largest TCP burst, in acknowledged groups, before it is cut.
+    #[serde(default = "default_tcp_burst_packets")]
+    pub tcp_burst_packets: usize,
+    /// Largest TCP connection, in payload bytes both ways, whose completion
+    /// time is reported.
+    #[serde(default = "default_short_flow_bytes")]
+    pub short_flow_bytes: u64,
+    /// List the TCP connections that ended in each link state.
+    #[serde(default)]
+    pub flow_records: bool,
+    /// Name the local process behind the connections this node opened in
+    /// those records. Tells what runs on the node, so off by default. Other
+    /// users' processes need root, or CAP_SYS_PTRACE in
+    /// `privileges.keep_caps`. Connections shorter than the procfs poll
+    /// interval are often missed.
+    #[serde(default)]
+    pub flow_owners: bool,
+    /// Interval in seconds between link tables printed to stdout, 0
+    /// disables them. Also set by `--summary`.
+    #[serde(default, deserialize_with = "duration_deserialize")]
+    pub summary_interval: Duration,
+    /// Keep the headers of the last `ring_size_mb` MB of packets here, as
+    /// pcapng. Disabled if unset.
+    #[serde(default)]
+    pub ring_dir: Option<PathBuf>,
+    #[serde(default = "default_ring_size_mb")]
+    pub ring_size_mb: u32,
+    /// What links are keyed by: "host" (each remote IP), "subnet" (remotes
+    /// in the same `aggregation_prefix_v4`/`_v6` subnet) or "next_hop"
+    /// (remotes behind the same gateway). Peers keep their own link.
+    #[serde(default = "default_link_aggregation")]
+    pub link_aggregation: String,
+    #[serde(default = "default_aggregation_prefix_v4")]
+    pub aggregation_prefix_v4: u8,
+    #[serde(default = "default_aggregation_prefix_v6")]
+    pub aggregation_prefix_v6: u8,
+    /// Share of the capture channel in use at which the parser starts
+    /// shedding load, see `listener::load`.
+    #[serde(default = "default_shed_high_watermark")]
+    pub shed_high_watermark: f64,
+    /// Share at which it stops again.
+    #[serde(default = "default_shed_low_watermark")]
+    pub shed_low_watermark: f64,
+    /// Keep each link's baseline RTT and capacity in this file, so they
+    /// survive a restart. Disabled if unset.
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// How often `state_file` is written, in seconds.
+    #[serde(
+        default = "default_state_save_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub state_save_interval: Duration,
 }
 
 #[derive(Deserialize, Debug)]
@@ -52,26 +262,717 @@ pub struct Server {
     pub send_link_states: bool,
     #[serde(default = "default_send_pgm_dps")]
     pub send_pgm_dps: bool,
+    /// Send the link states, RTT samples and PGM data points of a window
+    /// as one Report message, on the link state interval. Schedulers from
+    /// before Report drop them.
+    #[serde(default)]
+    pub batch_reports: bool,
+    /// Log each report window at every hop on this node under the `trace`
+    /// target, see `prost_net::trace`.
+    #[serde(default)]
+    pub trace_windows: bool,
+    /// How often link states are sent. Each link is still only reported
+    /// once its own reporting interval is up.
+    #[serde(
+        default = "default_link_state_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub link_state_interval: Duration,
+    /// How often buffered RTT samples are sent.
+    #[serde(
+        default = "default_rtt_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub rtt_interval: Duration,
+    /// Width in seconds of the buckets RTT samples are sent in, as their
+    /// min, mean, max and count. 0 sends every sample instead.
+    #[serde(
+        default = "default_rtt_bucket",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub rtt_bucket: Duration,
+    /// Send every RTT sample along with the buckets, for debugging.
+    #[serde(default)]
+    pub raw_rtts: bool,
+    /// How often buffered PGM data points are sent.
+    #[serde(
+        default = "default_pgm_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub pgm_interval: Duration,
+    /// Compression of the data stream to the server: "none", "gzip" or "zstd".
+    #[serde(
+        default = "default_compression",
+        deserialize_with = "compression_deserialize"
+    )]
+    pub compression: Option<CompressionEncoding>,
+    /// Most RTT samples or PGM data points in one message, 0 for no limit.
+    /// The oldest are dropped and the message is flagged as truncated.
+    #[serde(default = "default_max_points_per_message")]
+    pub max_points_per_message: usize,
+    /// Most data messages kept for the server while the stream to it is
+    /// down. The oldest are dropped first.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    /// Collectors to stream the data messages to, as "host:port". Only
+    /// `ip`:`port` if empty.
+    #[serde(default)]
+    pub collectors: Vec<String>,
+    /// "all" streams to every collector, "failover" to the first one in
+    /// `collectors` that accepts the stream.
+    #[serde(default = "default_collector_mode")]
+    pub collector_mode: String,
     #[serde(default = "default_probe_technique")]
     pub probe_technique: String,
 }
 
+/// Options used when running active iperf3 probes.
+#[derive(Deserialize, Debug)]
+pub struct Probe {
+    /// Number of parallel streams (`-P`).
+    #[serde(default = "default_iperf_parallel")]
+    pub iperf_parallel: u8,
+    /// Run the test over UDP (`-u`).
+    #[serde(default = "default_iperf_udp")]
+    pub iperf_udp: bool,
+    /// Target bitrate, e.g. "10M" (`-b`). Mostly useful together with UDP.
+    #[serde(default)]
+    pub iperf_bitrate: Option<String>,
+    /// Let the server send to us instead (`-R`).
+    #[serde(default = "default_iperf_reverse")]
+    pub iperf_reverse: bool,
+    /// Duration of scheduled probes in seconds.
+    #[serde(default = "default_probe_duration")]
+    pub duration: u16,
+    /// Skip a scheduled probe if the link's last report had at least this
+    /// many data points left after the gap filter. 0 always probes.
+    #[serde(default)]
+    pub min_passive_points: usize,
+    /// Packets per train in packet train probes.
+    #[serde(default = "default_train_length")]
+    pub train_length: u32,
+    /// UDP payload per train packet in bytes.
+    #[serde(default = "default_train_packet_size")]
+    pub train_packet_size: u32,
+    /// Sending rate within a train in bits/sec, 0 for `client.link_phy_cap`.
+    #[serde(default)]
+    pub train_rate: u64,
+    /// Most probes run at once, further ones are queued.
+    #[serde(default = "default_max_concurrent_probes")]
+    pub max_concurrent: usize,
+    /// Least time in seconds between the start of two probes on a link.
+    #[serde(
+        default = "default_probe_min_spacing",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub min_spacing: Duration,
+    /// ICMP payload sizes in bytes of the echoes in a ping train, see
+    /// `probe::ping`.
+    #[serde(default = "default_ping_train_sizes")]
+    pub ping_train_sizes: Vec<usize>,
+    /// Times each size is sent in a train.
+    #[serde(default = "default_ping_train_rounds")]
+    pub ping_train_rounds: u32,
+    /// How often the peers are pinged, 0 disables it.
+    #[serde(
+        default = "default_ping_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub ping_interval: Duration,
+    /// Echoes sent to each peer every `ping_interval`, a second apart.
+    #[serde(default = "default_ping_count")]
+    pub ping_count: u16,
+    /// How often a ping train is sent to the peers, 0 disables it.
+    #[serde(
+        default = "default_ping_train_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub ping_train_interval: Duration,
+}
+
+/// Per link feature vectors for ML pipelines, see `tracking::features`.
+/// They are always streamed over the `SubscribeFeatures` RPC.
+#[derive(Deserialize, Debug)]
+pub struct Features {
+    /// Interval between feature vectors in seconds, 0 disables them.
+    #[serde(
+        default = "default_features_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub interval: Duration,
+    /// Also append them to this file as CSV.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+/// Read only JSON API over the same metrics as the gRPC server, see
+/// `prost_net::http_api`.
+#[derive(Deserialize, Debug, Default)]
+pub struct Http {
+    /// Address to serve it on, e.g. "0.0.0.0:50080". Disabled if unset.
+    #[serde(default)]
+    pub listen_addr: Option<SocketAddr>,
+}
+
+/// Link costs exported to a routing daemon, see `routing`.
+#[derive(Deserialize, Debug)]
+pub struct Routing {
+    /// "olsrd2" or "babel". Disabled if unset.
+    #[serde(default)]
+    pub daemon: Option<String>,
+    /// Telnet plugin of olsrd2, or the local socket of babeld (`-G`).
+    #[serde(default = "default_routing_addr")]
+    pub addr: String,
+    /// Interface the neighbours are on, for olsrd2. Defaults to `client.iface`.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Interval between exports in seconds. Only changed costs are sent.
+    #[serde(
+        default = "default_routing_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub interval: Duration,
+    /// "abw" or "latency".
+    #[serde(default = "default_routing_metric")]
+    pub metric: String,
+    /// Value of `metric` that costs `base_cost`, bytes/sec or microseconds. The
+    /// cost grows as the abw falls below it or the latency rises above it.
+    #[serde(default = "default_routing_reference")]
+    pub reference: f64,
+    #[serde(default = "default_routing_base_cost")]
+    pub base_cost: u32,
+    #[serde(default = "default_routing_min_cost")]
+    pub min_cost: u32,
+    #[serde(default = "default_routing_max_cost")]
+    pub max_cost: u32,
+    /// Log the commands instead of sending them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_routing_addr() -> String {
+    "127.0.0.1:2009".to_string()
+}
+fn default_routing_interval() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_routing_metric() -> String {
+    "abw".to_string()
+}
+fn default_routing_reference() -> f64 {
+    // 100 Mbit/s
+    12_500_000.0
+}
+fn default_routing_base_cost() -> u32 {
+    256
+}
+fn default_routing_min_cost() -> u32 {
+    1
+}
+fn default_routing_max_cost() -> u32 {
+    65535
+}
+
+/// Link events sent when a metric crosses a threshold, see
+/// `tracking::link_events`. A threshold of 0 disables its event.
+#[derive(Deserialize, Debug)]
+pub struct Events {
+    /// Percent the available bandwidth may fall below its reference.
+    #[serde(default)]
+    pub abw_drop: f64,
+    /// Mean RTT in milliseconds.
+    #[serde(default)]
+    pub rtt_ms: f64,
+    /// Percent of the packets retransmitted.
+    #[serde(default)]
+    pub loss: f64,
+    /// Share of a threshold a metric has to get back past for its event to
+    /// clear, so one hovering around it is not reported over and over.
+    #[serde(default = "default_events_hysteresis")]
+    pub hysteresis: f64,
+}
+
+fn default_events_hysteresis() -> f64 {
+    0.2
+}
+
+/// Pseudonymous addresses in the exported data, see `anonymize`.
+#[derive(Deserialize, Debug)]
+pub struct Anonymize {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Key of the mapping, created if missing. Nodes sharing the key map
+    /// addresses the same way.
+    #[serde(default = "default_anonymize_key_file")]
+    pub key_file: PathBuf,
+}
+
+fn default_anonymize_key_file() -> PathBuf {
+    PathBuf::from("anonymize.key")
+}
+
+/// A node id kept across address changes, see `identity`.
+#[derive(Deserialize, Debug)]
+pub struct Identity {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Ed25519 secret key of the node, created if missing.
+    #[serde(default = "default_identity_key_file")]
+    pub key_file: PathBuf,
+}
+
+fn default_identity_key_file() -> PathBuf {
+    PathBuf::from("node.key")
+}
+
+/// Options of the pcap handle, and how the capture device is watched.
+#[derive(Deserialize, Debug)]
+pub struct CaptureSettings {
+    #[serde(default = "default_promisc")]
+    pub promisc: bool,
+    /// Deliver packets as they arrive instead of in buffered batches.
+    #[serde(default = "default_immediate_mode")]
+    pub immediate_mode: bool,
+    /// Read timeout of the pcap handle in milliseconds, 0 for none.
+    #[serde(default)]
+    pub timeout: i32,
+    /// Bytes kept of each packet, enough for the headers.
+    #[serde(default = "default_snaplen")]
+    pub snaplen: i32,
+    /// First delay between checks for a missing interface, in milliseconds.
+    #[serde(
+        default = "default_iface_retry_min",
+        deserialize_with = "millis_deserialize"
+    )]
+    pub iface_retry_min: Duration,
+    /// Longest delay between them, in seconds.
+    #[serde(
+        default = "default_iface_retry_max",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub iface_retry_max: Duration,
+    /// How often the addresses of the capture device are re-read, in
+    /// seconds.
+    #[serde(
+        default = "default_addr_refresh_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub addr_refresh_interval: Duration,
+}
+
+fn default_promisc() -> bool {
+    true
+}
+fn default_immediate_mode() -> bool {
+    true
+}
+fn default_snaplen() -> i32 {
+    // Max header size
+    60 + 14 + 60
+}
+fn default_iface_retry_min() -> Duration {
+    Duration::from_millis(500)
+}
+fn default_iface_retry_max() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_addr_refresh_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Most packets a burst limit may be set to. Bursts are held in memory
+/// until cut, per stream.
+const MAX_BURST_PACKETS: usize = 100_000;
+
+/// Timeouts and buffers of the parser and the link tracking. The intervals
+/// and timeouts are in seconds unless noted, and must not be 0.
+#[derive(Deserialize, Debug)]
+pub struct TrackingSettings {
+    /// TCP streams idle for this long are dropped.
+    #[serde(
+        default = "default_tcp_stream_timeout",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub tcp_stream_timeout: Duration,
+    /// How often idle streams are dropped and residual bursts flushed.
+    #[serde(
+        default = "default_cleanup_interval",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub cleanup_interval: Duration,
+    /// Resolution of stream flush and idle deadlines.
+    #[serde(
+        default = "default_deadline_tick",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub deadline_tick: Duration,
+    /// Bucket length of the throughput percentiles in link states.
+    #[serde(
+        default = "default_throughput_bucket",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub throughput_bucket: Duration,
+    /// How often links are checked for being due a report.
+    #[serde(
+        default = "default_report_tick",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub report_tick: Duration,
+    /// How long an ARP/ND request may go unanswered before the neighbor is
+    /// considered unreachable.
+    #[serde(
+        default = "default_neighbor_reply_timeout",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub neighbor_reply_timeout: Duration,
+    /// Neighbors not heard from within this are stale.
+    #[serde(
+        default = "default_neighbor_stale",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub neighbor_stale: Duration,
+    /// Neighbors with no activity within this are forgotten.
+    #[serde(
+        default = "default_neighbor_timeout",
+        deserialize_with = "duration_deserialize"
+    )]
+    pub neighbor_timeout: Duration,
+    /// How long packets are held to put them back in capture order, in
+    /// milliseconds.
+    #[serde(
+        default = "default_reorder_window",
+        deserialize_with = "millis_deserialize"
+    )]
+    pub reorder_window: Duration,
+    /// Packets held before the oldest is released regardless of the window.
+    #[serde(default = "default_reorder_capacity")]
+    pub reorder_capacity: usize,
+}
+
+fn default_tcp_stream_timeout() -> Duration {
+    Duration::from_secs(20)
+}
+fn default_cleanup_interval() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_deadline_tick() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_throughput_bucket() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_report_tick() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_neighbor_reply_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+fn default_neighbor_stale() -> Duration {
+    Duration::from_secs(30)
+}
+fn default_neighbor_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+fn default_reorder_window() -> Duration {
+    Duration::from_millis(20)
+}
+fn default_reorder_capacity() -> usize {
+    4096
+}
+
+/// TCP frames longer than the MTU, as captured with GSO/GRO offloads on,
+/// see `listener::packet::offload`.
+#[derive(Deserialize, Debug)]
+pub struct Offload {
+    /// What they are in the estimates: "split" into the segments they were
+    /// on the wire, "exclude" them, or "keep" them as one packet.
+    #[serde(
+        default = "default_offload_mode",
+        deserialize_with = "offload_mode_deserialize"
+    )]
+    pub mode: OffloadMode,
+    /// Modes of single interfaces by name, in place of `mode`.
+    #[serde(default, deserialize_with = "offload_modes_deserialize")]
+    pub interfaces: HashMap<String, OffloadMode>,
+    /// Largest IP packet on the wire, in bytes.
+    #[serde(default = "default_offload_mtu")]
+    pub mtu: u16,
+}
+
+fn default_offload_mode() -> OffloadMode {
+    OffloadMode::Split
+}
+fn default_offload_mtu() -> u16 {
+    1500
+}
+
+/// Privileges given up once the capture is open, see `privileges`.
+#[derive(Deserialize, Debug)]
+pub struct Privileges {
+    #[serde(default)]
+    pub enabled: bool,
+    /// User to switch to, e.g. "nobody". Stays root, with only `keep_caps`,
+    /// if unset.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Capabilities kept, by default those needed to reopen the capture and
+    /// to ping.
+    #[serde(default = "default_keep_caps")]
+    pub keep_caps: Vec<String>,
+}
+
+fn default_keep_caps() -> Vec<String> {
+    vec!["CAP_NET_RAW".to_string(), "CAP_NET_ADMIN".to_string()]
+}
+
+/// Synthetic traffic fed to the parser instead of a capture, see
+/// `listener::simulation`. Runs without root or a capture device.
+#[derive(Deserialize, Debug)]
+pub struct Simulation {
+    /// Also set by `--simulate`.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Address of the simulated node. IPv6 flows come from fd00::1.
+    #[serde(default = "default_sim_local_ip")]
+    pub local_ip: Ipv4Addr,
+    /// A few flows to and from 10.0.0.x if empty.
+    #[serde(default)]
+    pub flows: Vec<SimFlow>,
+}
+
+/// A TCP flow between the simulated node and `remote`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SimFlow {
+    pub remote: IpAddr,
+    /// Payload bytes/sec.
+    pub rate: f64,
+    /// Round trip time in milliseconds.
+    #[serde(default = "default_sim_rtt")]
+    pub rtt: f64,
+    /// Share of the data segments that are lost and sent again, 0 to 1.
+    #[serde(default)]
+    pub loss: f64,
+    /// The remote sends the data instead of this node.
+    #[serde(default)]
+    pub download: bool,
+}
+
+fn default_sim_local_ip() -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 0, 1)
+}
+fn default_sim_rtt() -> f64 {
+    20.0
+}
+
+/// Stages applied to the gap data points before the ABW regression.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Filter {
+    /// Smallest payload in bytes. The default is the MTU minus the largest
+    /// Ethernet, IP and TCP headers.
+    #[serde(default = "default_filter_min_payload")]
+    pub min_payload: f64,
+    /// Share of the smallest input gaps whose average output gap becomes
+    /// the upper bound on input gaps. 0 disables the stage.
+    #[serde(default = "default_filter_gin_quantile")]
+    pub gin_quantile: f64,
+    /// Drop points whose gap ratio is more than this many scaled median
+    /// absolute deviations from the median. 0 disables the stage.
+    #[serde(default)]
+    pub mad_threshold: f64,
+}
+
+/// Packets dropped before they reach the links, see `listener::filter`.
+#[derive(Deserialize, Debug)]
+pub struct PacketFilters {
+    /// Filters applied to each packet, in order: "loopback", "multicast",
+    /// "ports" (`client.exclude_ports` and the own ports), "subnets"
+    /// (`allow_prefixes`, `deny_prefixes` and `client.exclude_prefixes`)
+    /// and "rate".
+    #[serde(default = "default_packet_filter_chain")]
+    pub chain: Vec<String>,
+    /// Only keep traffic with remotes in these prefixes, all if empty.
+    /// Replaced at runtime, with `deny_prefixes`, by the SetSubnets RPC.
+    #[serde(default)]
+    pub allow_prefixes: Vec<String>,
+    /// Drop traffic with an address in these prefixes.
+    #[serde(default)]
+    pub deny_prefixes: Vec<String>,
+    /// Packets per second kept from each remote, the rest are dropped.
+    /// 0 keeps all.
+    #[serde(default)]
+    pub max_packet_rate: u32,
+}
+
+fn default_packet_filter_chain() -> Vec<String> {
+    ["loopback", "multicast", "ports", "subnets", "rate"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_filter_min_payload() -> f64 {
+    1362.0
+}
+fn default_filter_gin_quantile() -> f64 {
+    0.1
+}
+
+fn default_features_interval() -> Duration {
+    Duration::ZERO
+}
+
+fn default_iperf_parallel() -> u8 {
+    1
+}
+fn default_iperf_udp() -> bool {
+    false
+}
+fn default_iperf_reverse() -> bool {
+    false
+}
+fn default_probe_duration() -> u16 {
+    5
+}
+fn default_train_length() -> u32 {
+    32
+}
+fn default_train_packet_size() -> u32 {
+    1400
+}
+fn default_max_concurrent_probes() -> usize {
+    1
+}
+fn default_ping_train_sizes() -> Vec<usize> {
+    vec![64, 256, 512, 1024, 1472]
+}
+fn default_ping_train_rounds() -> u32 {
+    5
+}
+fn default_ping_interval() -> Duration {
+    Duration::ZERO
+}
+fn default_ping_count() -> u16 {
+    5
+}
+fn default_ping_train_interval() -> Duration {
+    Duration::ZERO
+}
+fn default_probe_min_spacing() -> Duration {
+    Duration::from_secs(10)
+}
+
 fn default_regression_type() -> RegressionType {
     RegressionType::Simple
 }
 
+fn default_parse_in_capture() -> bool {
+    false
+}
+
+fn default_exclude_probe_traffic() -> bool {
+    true
+}
+
+fn default_relay_links() -> bool {
+    true
+}
+
+fn default_exclude_own_ports() -> bool {
+    true
+}
+
+fn default_vip_report_interval() -> Duration {
+    Duration::from_secs(5)
+}
+fn default_background_report_interval() -> Duration {
+    Duration::ZERO
+}
+fn default_vip_probe_interval() -> Duration {
+    Duration::ZERO
+}
+fn default_clock_sync_interval() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_health_check_interval() -> Duration {
+    Duration::from_secs(10)
+}
+fn default_peer_grace_period() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_other_burst_gap() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_other_burst_packets() -> usize {
+    100
+}
+
+fn default_udp_burst_gap() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_udp_burst_packets() -> usize {
+    100
+}
+
+fn default_tcp_burst_packets() -> usize {
+    100
+}
+
+fn default_short_flow_bytes() -> u64 {
+    1_000_000
+}
+fn default_ring_size_mb() -> u32 {
+    64
+}
+
+fn default_link_aggregation() -> String {
+    String::from("host")
+}
+
+fn default_aggregation_prefix_v4() -> u8 {
+    24
+}
+
+fn default_aggregation_prefix_v6() -> u8 {
+    64
+}
+
+fn default_shed_high_watermark() -> f64 {
+    0.8
+}
+
+fn default_shed_low_watermark() -> f64 {
+    0.5
+}
+
+fn default_state_save_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
 fn default_server() -> String {
     String::from("172.16.0.254")
 }
 fn default_server_port() -> u16 {
     50041
 }
+fn default_listen_addr() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
 fn default_listen_port() -> u16 {
     40042
 }
+fn default_request_rate() -> f64 {
+    5.0
+}
+fn default_request_burst() -> u32 {
+    20
+}
 fn default_measurement_window() -> Duration {
     Duration::from_secs(20)
 }
+fn default_iface_wait() -> Duration {
+    Duration::from_secs(60)
+}
 fn default_link_phy_cap() -> u32 {
     u32::MAX
 }
@@ -90,6 +991,32 @@ fn default_send_link_states() -> bool {
 fn default_send_pgm_dps() -> bool {
     false
 }
+fn default_link_state_interval() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_rtt_interval() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_rtt_bucket() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_pgm_interval() -> Duration {
+    Duration::from_secs(1)
+}
+fn default_compression() -> Option<CompressionEncoding> {
+    None
+}
+fn default_max_points_per_message() -> usize {
+    10_000
+}
+
+fn default_queue_size() -> usize {
+    1000
+}
+
+fn default_collector_mode() -> String {
+    String::from("all")
+}
 fn default_probe_technique() -> String {
     String::from("iperf3")
 }
@@ -102,6 +1029,14 @@ where
     Ok(Duration::from_secs(s as u64))
 }
 
+fn millis_deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let ms = u32::deserialize(deserializer)?;
+    Ok(Duration::from_millis(ms as u64))
+}
+
 fn precision_deserialize<'de, D>(deserializer: D) -> Result<pcap::Precision, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -129,18 +1064,56 @@ where
     }
 }
 
-fn regression_type_deserialize<'de, D>(deserializer: D) -> Result<RegressionType, D::Error>
+fn compression_deserialize<'de, D>(
+    deserializer: D,
+) -> Result<Option<CompressionEncoding>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
     match s.to_lowercase().as_str() {
-        "rls" => Ok(RegressionType::RLS),
-        "simple" => Ok(RegressionType::Simple),
-        _ => Err(serde::de::Error::custom("Invalid regression type"))
+        "none" => Ok(None),
+        "gzip" => Ok(Some(CompressionEncoding::Gzip)),
+        "zstd" => Ok(Some(CompressionEncoding::Zstd)),
+        _ => Err(serde::de::Error::custom("Invalid compression")),
     }
 }
 
+fn regression_type_deserialize<'de, D>(deserializer: D) -> Result<RegressionType, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse()
+        .map_err(|_| serde::de::Error::custom("Invalid regression type"))
+}
+
+fn offload_mode_deserialize<'de, D>(deserializer: D) -> Result<OffloadMode, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn offload_modes_deserialize<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, OffloadMode>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let modes = HashMap::<String, String>::deserialize(deserializer)?;
+    modes
+        .into_iter()
+        .map(|(iface, mode)| {
+            let mode = mode.parse().map_err(|e| {
+                <D::Error as serde::de::Error>::custom(format!("{}: {}", iface, e))
+            })?;
+            Ok((iface, mode))
+        })
+        .collect()
+}
+
 
 
 impl Default for AppConfig {
@@ -148,6 +1121,22 @@ impl Default for AppConfig {
         AppConfig {
             client: Client::default(),
             server: Server::default(),
+            probe: Probe::default(),
+            filter: Filter::default(),
+            packet_filter: PacketFilters::default(),
+            features: Features::default(),
+            http: Http::default(),
+            routing: Routing::default(),
+            events: Events::default(),
+            metadata: BTreeMap::new(),
+            simulation: Simulation::default(),
+            privileges: Privileges::default(),
+            anonymize: Anonymize::default(),
+            identity: Identity::default(),
+            capture: CaptureSettings::default(),
+            tracking: TrackingSettings::default(),
+            offload: Offload::default(),
+            selftest: false,
         }
     }
 }
@@ -157,12 +1146,51 @@ impl Default for Client {
         Client {
             ip: None,
             iface: None,
+            iface_wait: default_iface_wait(),
+            listen_addr: default_listen_addr(),
             listen_port: default_listen_port(),
+            known_peers: Vec::new(),
+            control_peers: Vec::new(),
+            require_identity: false,
+            request_rate: default_request_rate(),
+            request_burst: default_request_burst(),
             link_phy_cap: default_link_phy_cap(),
             measurement_window: default_measurement_window(),
             tstamp_type: default_tstamp_type(),
             timestamp_precision: default_timestamp_precision(),
             regression_type: default_regression_type(),
+            compare_estimators: false,
+            parse_in_capture: default_parse_in_capture(),
+            exclude_probe_traffic: default_exclude_probe_traffic(),
+            relay_links: default_relay_links(),
+            vip_report_interval: default_vip_report_interval(),
+            background_report_interval: default_background_report_interval(),
+            vip_probe_interval: default_vip_probe_interval(),
+            clock_sync_interval: default_clock_sync_interval(),
+            health_check_interval: default_health_check_interval(),
+            peer_grace_period: default_peer_grace_period(),
+            link_windows: HashMap::new(),
+            exclude_own_ports: default_exclude_own_ports(),
+            exclude_ports: Vec::new(),
+            exclude_prefixes: Vec::new(),
+            other_burst_gap: default_other_burst_gap(),
+            other_burst_packets: default_other_burst_packets(),
+            udp_burst_gap: default_udp_burst_gap(),
+            udp_burst_packets: default_udp_burst_packets(),
+            tcp_burst_packets: default_tcp_burst_packets(),
+            short_flow_bytes: default_short_flow_bytes(),
+            flow_records: false,
+            flow_owners: false,
+            summary_interval: Duration::ZERO,
+            ring_dir: None,
+            ring_size_mb: default_ring_size_mb(),
+            link_aggregation: default_link_aggregation(),
+            aggregation_prefix_v4: default_aggregation_prefix_v4(),
+            aggregation_prefix_v6: default_aggregation_prefix_v6(),
+            shed_high_watermark: default_shed_high_watermark(),
+            shed_low_watermark: default_shed_low_watermark(),
+            state_file: None,
+            state_save_interval: default_state_save_interval(),
         }
     }
 }
@@ -175,11 +1203,194 @@ impl Default for Server {
             send_rtts: default_send_rtts(),
             send_link_states: default_send_link_states(),
             send_pgm_dps: default_send_pgm_dps(),
+            batch_reports: false,
+            trace_windows: false,
+            link_state_interval: default_link_state_interval(),
+            rtt_interval: default_rtt_interval(),
+            rtt_bucket: default_rtt_bucket(),
+            raw_rtts: false,
+            pgm_interval: default_pgm_interval(),
+            compression: default_compression(),
+            max_points_per_message: default_max_points_per_message(),
+            queue_size: default_queue_size(),
+            collectors: Vec::new(),
+            collector_mode: default_collector_mode(),
             probe_technique: default_probe_technique(),
         }
     }
 }
 
+impl Server {
+    /// `collectors`, or `ip`:`port` if none are listed.
+    pub fn collector_addrs(&self) -> Vec<String> {
+        if self.collectors.is_empty() {
+            vec![format!("{}:{}", self.ip, self.port)]
+        } else {
+            self.collectors.clone()
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            min_payload: default_filter_min_payload(),
+            gin_quantile: default_filter_gin_quantile(),
+            mad_threshold: 0.0,
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            interval: default_features_interval(),
+            file: None,
+        }
+    }
+}
+
+impl Default for Routing {
+    fn default() -> Self {
+        Routing {
+            daemon: None,
+            addr: default_routing_addr(),
+            interface: None,
+            interval: default_routing_interval(),
+            metric: default_routing_metric(),
+            reference: default_routing_reference(),
+            base_cost: default_routing_base_cost(),
+            min_cost: default_routing_min_cost(),
+            max_cost: default_routing_max_cost(),
+            dry_run: false,
+        }
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            abw_drop: 0.0,
+            rtt_ms: 0.0,
+            loss: 0.0,
+            hysteresis: default_events_hysteresis(),
+        }
+    }
+}
+
+impl Default for PacketFilters {
+    fn default() -> Self {
+        PacketFilters {
+            chain: default_packet_filter_chain(),
+            allow_prefixes: Vec::new(),
+            deny_prefixes: Vec::new(),
+            max_packet_rate: 0,
+        }
+    }
+}
+
+impl Default for Anonymize {
+    fn default() -> Self {
+        Anonymize {
+            enabled: false,
+            key_file: default_anonymize_key_file(),
+        }
+    }
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity {
+            enabled: false,
+            key_file: default_identity_key_file(),
+        }
+    }
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        CaptureSettings {
+            promisc: default_promisc(),
+            immediate_mode: default_immediate_mode(),
+            timeout: 0,
+            snaplen: default_snaplen(),
+            iface_retry_min: default_iface_retry_min(),
+            iface_retry_max: default_iface_retry_max(),
+            addr_refresh_interval: default_addr_refresh_interval(),
+        }
+    }
+}
+
+impl Default for TrackingSettings {
+    fn default() -> Self {
+        TrackingSettings {
+            tcp_stream_timeout: default_tcp_stream_timeout(),
+            cleanup_interval: default_cleanup_interval(),
+            deadline_tick: default_deadline_tick(),
+            throughput_bucket: default_throughput_bucket(),
+            report_tick: default_report_tick(),
+            neighbor_reply_timeout: default_neighbor_reply_timeout(),
+            neighbor_stale: default_neighbor_stale(),
+            neighbor_timeout: default_neighbor_timeout(),
+            reorder_window: default_reorder_window(),
+            reorder_capacity: default_reorder_capacity(),
+        }
+    }
+}
+
+impl Default for Offload {
+    fn default() -> Self {
+        Offload {
+            mode: default_offload_mode(),
+            interfaces: HashMap::new(),
+            mtu: default_offload_mtu(),
+        }
+    }
+}
+
+impl Default for Privileges {
+    fn default() -> Self {
+        Privileges {
+            enabled: false,
+            user: None,
+            keep_caps: default_keep_caps(),
+        }
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Simulation {
+            enabled: false,
+            local_ip: default_sim_local_ip(),
+            flows: Vec::new(),
+        }
+    }
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe {
+            iperf_parallel: default_iperf_parallel(),
+            iperf_udp: default_iperf_udp(),
+            iperf_bitrate: None,
+            iperf_reverse: default_iperf_reverse(),
+            duration: default_probe_duration(),
+            min_passive_points: 0,
+            train_length: default_train_length(),
+            train_packet_size: default_train_packet_size(),
+            train_rate: 0,
+            max_concurrent: default_max_concurrent_probes(),
+            min_spacing: default_probe_min_spacing(),
+            ping_train_sizes: default_ping_train_sizes(),
+            ping_train_rounds: default_ping_train_rounds(),
+            ping_interval: default_ping_interval(),
+            ping_count: default_ping_count(),
+            ping_train_interval: default_ping_train_interval(),
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct CliArgs {
@@ -191,6 +1402,86 @@ pub struct CliArgs {
 
     #[arg(long)]
     pub iface: Option<String>,
+
+    /// Print a table of the links every SECS seconds, instead of the log.
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "5")]
+    pub summary: Option<u32>,
+
+    /// Feed the listener synthetic traffic instead of capturing, see
+    /// `[simulation]`.
+    #[arg(long)]
+    pub simulate: bool,
+
+    /// Check capture permission, timestamping, iperf3, the gRPC port and
+    /// the collectors, print a report and exit. Exits with 1 on failures.
+    #[arg(long)]
+    pub selftest: bool,
+}
+
+impl AppConfig {
+    /// Checks the tracking limits, which are otherwise only found out about
+    /// once streams go missing.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let tracking = &self.tracking;
+        let intervals = [
+            ("tcp_stream_timeout", tracking.tcp_stream_timeout),
+            ("cleanup_interval", tracking.cleanup_interval),
+            ("deadline_tick", tracking.deadline_tick),
+            ("throughput_bucket", tracking.throughput_bucket),
+            ("report_tick", tracking.report_tick),
+            ("neighbor_reply_timeout", tracking.neighbor_reply_timeout),
+            ("neighbor_stale", tracking.neighbor_stale),
+            ("neighbor_timeout", tracking.neighbor_timeout),
+        ];
+        for (name, interval) in intervals {
+            if interval.is_zero() {
+                bail!("tracking.{} must not be 0", name);
+            }
+        }
+        // Streams would be dropped between two flushes of their bursts
+        if tracking.cleanup_interval > tracking.tcp_stream_timeout {
+            bail!(
+                "tracking.cleanup_interval ({:?}) is longer than tcp_stream_timeout ({:?})",
+                tracking.cleanup_interval,
+                tracking.tcp_stream_timeout
+            );
+        }
+        if tracking.deadline_tick > tracking.cleanup_interval {
+            bail!("tracking.deadline_tick is longer than tracking.cleanup_interval");
+        }
+        if tracking.reorder_capacity == 0 {
+            bail!("tracking.reorder_capacity must not be 0");
+        }
+        let capture = &self.capture;
+        let capture_intervals = [
+            ("iface_retry_min", capture.iface_retry_min),
+            ("addr_refresh_interval", capture.addr_refresh_interval),
+        ];
+        for (name, interval) in capture_intervals {
+            if interval.is_zero() {
+                bail!("capture.{} must not be 0", name);
+            }
+        }
+        let bursts = [
+            ("tcp_burst_packets", self.client.tcp_burst_packets),
+            ("udp_burst_packets", self.client.udp_burst_packets),
+            ("other_burst_packets", self.client.other_burst_packets),
+        ];
+        for (name, packets) in bursts {
+            if !(1..=MAX_BURST_PACKETS).contains(&packets) {
+                bail!("client.{} must be 1 to {}, not {}", name, MAX_BURST_PACKETS, packets);
+            }
+        }
+        if !(0.0..1.0).contains(&self.events.hysteresis) {
+            let hysteresis = self.events.hysteresis;
+            bail!("events.hysteresis must be 0 or more and below 1, not {}", hysteresis);
+        }
+        if self.offload.mtu <= SEGMENT_HEADERS {
+            let mtu = self.offload.mtu;
+            bail!("offload.mtu must be above {}, not {}", SEGMENT_HEADERS, mtu);
+        }
+        Ok(())
+    }
 }
 
 pub fn load_config() -> AppConfig {
@@ -211,6 +1502,20 @@ pub fn load_config() -> AppConfig {
         config.client.iface = Some(iface);
     }
 
+    if let Some(secs) = cli_args.summary {
+        config.client.summary_interval = Duration::from_secs(secs as u64);
+    }
+
+    if cli_args.simulate {
+        config.simulation.enabled = true;
+    }
+
+    config.selftest = cli_args.selftest;
+
+    if let Err(e) = config.validate() {
+        panic!("Invalid config: {}", e);
+    }
+
     config
 }
 
@@ -223,5 +1528,56 @@ mod tests {
         let config = AppConfig::default();
         assert_eq!(config.client.ip, None);
         assert_eq!(config.client.iface, None);
+        assert_eq!(config.client.iface_wait, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_settings_sections() {
+        let config: AppConfig = toml::from_str(
+            "[client]\n[server]\n\
+             [capture]\nsnaplen = 256\niface_retry_min = 100\n\
+             [tracking]\ntcp_stream_timeout = 900\nreorder_window = 5\n\
+             [offload.interfaces]\neth0 = \"exclude\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.capture.snaplen, 256);
+        assert_eq!(config.capture.iface_retry_min, Duration::from_millis(100));
+        assert!(config.capture.promisc);
+        assert_eq!(config.tracking.tcp_stream_timeout, Duration::from_secs(900));
+        assert_eq!(config.tracking.reorder_window, Duration::from_millis(5));
+        assert_eq!(config.tracking.cleanup_interval, Duration::from_secs(10));
+        assert_eq!(config.offload.interfaces["eth0"], OffloadMode::Exclude);
+        assert_eq!((config.offload.mode, config.offload.mtu), (OffloadMode::Split, 1500));
+        let modes = "[client]\n[server]\n[offload.interfaces]\neth0 = \"segment\"\n";
+        assert!(toml::from_str::<AppConfig>(modes).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(AppConfig::default().validate().is_ok());
+
+        let mut config = AppConfig::default();
+        config.tracking.cleanup_interval = Duration::ZERO;
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.tracking.tcp_stream_timeout = Duration::from_secs(5);
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.client.tcp_burst_packets = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.events.hysteresis = 1.0;
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.capture.addr_refresh_interval = Duration::ZERO;
+        assert!(config.validate().is_err());
+
+        let mut config = AppConfig::default();
+        config.offload.mtu = 52;
+        assert!(config.validate().is_err());
     }
 }
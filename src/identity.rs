@@ -0,0 +1,270 @@
+//! Node ids kept across address changes, if `identity.enabled`.
+//!
+//! Each node has an Ed25519 key in `identity.key_file`, created on first
+//! use. Its id is the start of the SHA-256 of the public key, in hex. The
+//! hello exchange carries the public key and a signature of the address the
+//! node announces, so a peer that moved to another address is recognized
+//! and the history of its links carries on. The id is also the node id of
+//! the data messages, so the collector keys the data of a node by it.
+//!
+//! The signature covers when it was made as well. Identities older than
+//! `MAX_AGE`, or not newer than the last one from the node, are turned down
+//! as replays, see `ReplayGuard`.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::proto_bw::PeerIdentity;
+use crate::{Timestamp, CONFIG};
+
+/// Bytes of the public key hash in a node id.
+const ID_LEN: usize = 8;
+/// Most time in milliseconds between signing an identity and checking it,
+/// either way, to allow for clock offsets.
+pub const MAX_AGE: i64 = 300_000;
+/// Nodes whose last identity is remembered by a `ReplayGuard`.
+const MAX_REMEMBERED: usize = 4096;
+
+static IDENTITY: OnceLock<NodeIdentity> = OnceLock::new();
+
+/// Loads the key if `identity.enabled`. Called at startup, so a key that
+/// cannot be read fails the start instead of the first hello. Until then,
+/// the node has no identity.
+pub fn init() -> Result<()> {
+    if !CONFIG.identity.enabled || IDENTITY.get().is_some() {
+        return Ok(());
+    }
+    let identity = NodeIdentity::load(&CONFIG.identity.key_file)?;
+    info!("Node id {}", identity.id());
+    let _ = IDENTITY.set(identity);
+    Ok(())
+}
+
+/// The identity of this node, None without `identity.enabled`.
+pub fn local() -> Option<&'static NodeIdentity> {
+    IDENTITY.get()
+}
+
+/// The identity to send in a hello from `addr`, None without
+/// `identity.enabled`.
+pub fn announce(addr: &str) -> Option<PeerIdentity> {
+    local().map(|identity| identity.announce(addr))
+}
+
+/// Id of the node with `public_key`.
+pub fn key_id(public_key: &[u8]) -> String {
+    Sha256::digest(public_key)[..ID_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// What is signed in an identity.
+fn signed(addr: &str, timestamp: i64) -> Vec<u8> {
+    let mut message = addr.as_bytes().to_vec();
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message
+}
+
+/// An identity whose signature is its own and was fresh when checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    pub id: String,
+    /// None if the node did not know its address.
+    pub addr: Option<IpAddr>,
+    /// When it was signed, in milliseconds since epoch.
+    pub timestamp: i64,
+}
+
+/// Checks the signature of `identity` and that it was made within
+/// `MAX_AGE` of `now`, in milliseconds since epoch. `from` is the address
+/// it came from, if the sender is to be at the address it signed.
+pub fn verify(identity: &PeerIdentity, from: Option<IpAddr>, now: i64) -> Result<Verified> {
+    let public_key: &[u8; 32] = identity
+        .public_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Public key is {} bytes", identity.public_key.len()))?;
+    let signature = Signature::from_slice(&identity.signature)?;
+    VerifyingKey::from_bytes(public_key)?
+        .verify(&signed(&identity.addr, identity.timestamp), &signature)?;
+    let age = now.saturating_sub(identity.timestamp);
+    if age.abs() > MAX_AGE {
+        return Err(anyhow!("Signed {} ms away from now", age));
+    }
+    let addr = identity.addr.parse().ok();
+    if let (Some(addr), Some(from)) = (addr, from) {
+        if addr != from {
+            return Err(anyhow!("Signed for {} but sent from {}", addr, from));
+        }
+    }
+    Ok(Verified {
+        id: key_id(public_key),
+        addr,
+        timestamp: identity.timestamp,
+    })
+}
+
+/// Newest identity seen from each node, to turn down replayed ones.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    newest: HashMap<String, i64>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        ReplayGuard::default()
+    }
+
+    /// False if an identity of the node signed no earlier was seen. Nodes
+    /// whose last identity is past `MAX_AGE` at `now` are forgotten once
+    /// too many are remembered, `verify` turns their replays down.
+    pub fn check(&mut self, verified: &Verified, now: i64) -> bool {
+        if self.newest.len() >= MAX_REMEMBERED {
+            self.newest.retain(|_, newest| now.saturating_sub(*newest) <= MAX_AGE);
+        }
+        match self.newest.get_mut(&verified.id) {
+            Some(newest) if *newest >= verified.timestamp => false,
+            Some(newest) => {
+                *newest = verified.timestamp;
+                true
+            }
+            None => {
+                self.newest.insert(verified.id.clone(), verified.timestamp);
+                true
+            }
+        }
+    }
+}
+
+/// Key of this node.
+pub struct NodeIdentity {
+    key: SigningKey,
+    id: String,
+}
+
+impl NodeIdentity {
+    pub fn new(secret: [u8; 32]) -> Self {
+        let key = SigningKey::from_bytes(&secret);
+        let id = key_id(key.verifying_key().as_bytes());
+        NodeIdentity { key, id }
+    }
+
+    /// Reads the secret key in `path`, creating it if there is none.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(secret) => {
+                let secret: [u8; 32] = secret.as_slice().try_into().map_err(|_| {
+                    anyhow!("Identity key {} is not 32 bytes", path.display())
+                })?;
+                Ok(NodeIdentity::new(secret))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let secret: [u8; 32] = rand::random();
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(&secret))
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                info!("Created identity key {}", path.display());
+                Ok(NodeIdentity::new(secret))
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The public key with a signature of `addr` and the current time.
+    pub fn announce(&self, addr: &str) -> PeerIdentity {
+        self.announce_at(addr, Timestamp::now().as_millis())
+    }
+
+    /// The public key with a signature of `addr` and `timestamp`.
+    pub fn announce_at(&self, addr: &str, timestamp: i64) -> PeerIdentity {
+        PeerIdentity {
+            public_key: self.key.verifying_key().to_bytes().to_vec(),
+            signature: self.key.sign(&signed(addr, timestamp)).to_bytes().to_vec(),
+            addr: addr.to_string(),
+            timestamp,
+        }
+    }
+}
+
+impl std::fmt::Debug for NodeIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeIdentity").field("id", &self.id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    #[test]
+    fn test_announce_and_verify() {
+        let identity = NodeIdentity::new([7; 32]);
+        assert_eq!(identity.id().len(), 2 * ID_LEN);
+
+        let announced = identity.announce_at("10.0.0.1", NOW);
+        let verified = verify(&announced, None, NOW + 1000).unwrap();
+        assert_eq!(verified.id, identity.id());
+        assert_eq!(verified.addr, Some([10, 0, 0, 1].into()));
+
+        // Same key, same id at another address
+        let verified = verify(&identity.announce_at("10.0.1.1", NOW), None, NOW).unwrap();
+        assert_eq!(verified.id, identity.id());
+
+        // Another address or time under the signature of the first
+        let mut forged = announced.clone();
+        forged.addr = "10.0.0.2".to_string();
+        assert!(verify(&forged, None, NOW).is_err());
+        let mut forged = announced.clone();
+        forged.timestamp = NOW + 1;
+        assert!(verify(&forged, None, NOW).is_err());
+
+        // Stale
+        assert!(verify(&announced, None, NOW + MAX_AGE + 1).is_err());
+
+        let verified = verify(&identity.announce_at("", NOW), None, NOW).unwrap();
+        assert_eq!(verified.addr, None);
+
+        // Sent from the address it signed, or another one
+        assert!(verify(&announced, Some([10, 0, 0, 1].into()), NOW).is_ok());
+        assert!(verify(&announced, Some([10, 0, 0, 2].into()), NOW).is_err());
+    }
+
+    #[test]
+    fn test_replays_are_turned_down() {
+        let identity = NodeIdentity::new([7; 32]);
+        let mut guard = ReplayGuard::new();
+        let first = verify(&identity.announce_at("10.0.0.1", NOW), None, NOW).unwrap();
+        let second = verify(&identity.announce_at("10.0.0.1", NOW + 10), None, NOW).unwrap();
+
+        assert!(guard.check(&first, NOW));
+        assert!(!guard.check(&first, NOW));
+        assert!(guard.check(&second, NOW));
+        // Older than the newest seen
+        assert!(!guard.check(&first, NOW));
+
+        let other = NodeIdentity::new([8; 32]);
+        let other = verify(&other.announce_at("10.0.0.2", NOW), None, NOW).unwrap();
+        assert!(guard.check(&other, NOW));
+    }
+}
@@ -1,23 +1,38 @@
 use anyhow::Error as AnyError;
 use tokio::sync::mpsc::{Receiver, Sender};
 use listener::capture::{OwnedPacket, PCAPMeta, PacketCapturer};
+use listener::packet::neighbor::NeighborPacket;
 use probe::iperf_json::IperfResponse;
+use probe::result::ProbeResult;
+use probe::session::ProbeSession;
 use prost_net::bandwidth_server::PbfMsg;
-use surge_ping::SurgeError;
 use std::error::Error;
 
+pub mod anonymize;
+pub mod channel;
+pub mod identity;
 pub mod listener;
 pub mod logging;
+pub mod privileges;
 pub mod probe;
 pub mod prost_net;
+pub mod routing;
+pub mod selftest;
+pub mod summary;
+#[cfg(feature = "scheduler")]
 pub mod scheduler;
 pub mod config;
+pub mod node;
+pub mod tap;
+pub mod timestamp;
 
 pub use listener::packet::*;
 pub use listener::tracking::*;
 pub use prost_net::bandwidth_client::ClientEvent;
 pub use probe::iperf_json::Stream2 as IperfStream;
 pub use config::AppConfig;
+pub use node::NetworkListener;
+pub use timestamp::Timestamp;
 
 pub const IPERF3_PORT: u16 = 5201;
 
@@ -25,40 +40,37 @@ pub type CapEventSender = Sender<CapEvent>;
 pub type CapEventReceiver = Receiver<CapEvent>;
 pub type CaptureResult = Result<(PacketCapturer, PCAPMeta), Box<dyn Error>>;
 
-pub mod proto_bw {
-    tonic::include_proto!("bandwidth");
-}
-
-pub mod core_proto {
-    tonic::include_proto!("core");
-}
+pub use network_listener_proto::bandwidth as proto_bw;
+#[cfg(feature = "core")]
+pub use network_listener_proto::core_api as core_proto;
 
-use tokio::time::Duration;
 use lazy_static::lazy_static;
 
 lazy_static! {
     pub static ref CONFIG: AppConfig = config::load_config();
 }
 
-pub struct Settings {}
-
-impl Settings {
-    pub const PROMISC: bool = true;
-    pub const IMMEDIATE_MODE: bool = true;
-    pub const TIMEOUT: i32 = 0;
-    pub const PRECISION: pcap::Precision = pcap::Precision::Micro;
-    pub const TCP_STREAM_TIMEOUT: Duration = Duration::from_secs(20); //from_secs(900);
-    pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
-    pub const BURST_SIZE: usize = 100; // Limit buffered packets to 100 in individual trackers
-    pub const SNAPLEN: i32 = 60 + 14 + 60; // Max header size=134 bytes.
-    const IPV6HDR: i32 = 40;
-}
-
 pub enum CapEvent {
     Packet(OwnedPacket),
+    /// Packet already parsed by the capture thread (`client.parse_in_capture`).
+    Parsed(ParsedPacket),
+    /// ARP or neighbor discovery message, parsed by the capture thread.
+    Neighbor(NeighborPacket),
     IperfResponse(IperfResponse),
     Protobuf(PbfMsg),
-    PathloadResponse(String),
-    PingResponse(Result<Duration, SurgeError>),
+    /// An active probe was negotiated with a peer.
+    ProbeSession(ProbeSession),
+    /// Result of an active probe, for the link toward its target.
+    Probe(ProbeResult),
+    /// Switch the estimator used for link states.
+    SetEstimator(RegressionType),
+    /// Fill in a status report and send it on the reply channel.
+    Status(tokio::sync::oneshot::Sender<proto_bw::NodeStatus>),
+    /// Send the estimates of the link to a remote, or of every link if None,
+    /// on the reply channel.
+    Estimates(
+        Option<std::net::IpAddr>,
+        tokio::sync::oneshot::Sender<Vec<listener::tracking::estimates::LinkEstimate>>,
+    ),
     Error(AnyError),
 }
@@ -1,28 +1,86 @@
 use anyhow::Result;
-use log::{error, info};
+use log::{error, info, warn};
 use mac_address::{get_mac_address, MacAddress};
-use pcap::{Capture, Device, Inactive, Packet, PacketHeader};
+use pcap::{Active, Capture, Device, Inactive, Packet, PacketHeader, TimestampType};
 use pnet::datalink::MacAddr;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tokio::task;
 
+pub use crate::listener::packet::link_layer::LinkType;
+use crate::listener::dump::{DumpHandle, Dumper};
+use crate::listener::filter::SubnetRules;
+use crate::listener::ring::RingSender;
+use crate::listener::tstamp::{self, ClockDomain};
+use crate::listener::packet::neighbor::NeighborPacket;
+use crate::listener::tracking::self_traffic::SelfTraffic;
 use crate::*;
 
 pub struct PacketCapturer {
-    cap: Capture<Inactive>,
+    cap: Capture<Active>,
+    /// Device name, used to reopen the capture if the interface goes away.
+    name: String,
     sender: CapEventSender,
+    /// Copy of the device metadata, used when parsing in the capture thread.
+    meta: PCAPMeta,
+    /// Publishes the current device metadata whenever the addresses change.
+    meta_tx: Arc<watch::Sender<PCAPMeta>>,
+    /// Subnet rules, mirrored in the capture filter.
+    subnets: watch::Receiver<SubnetRules>,
+    /// Per link pcap dumps, written from the capture thread.
+    dumper: Dumper,
+    dump_handle: DumpHandle,
 }
 
-#[derive(Clone, Debug)]
+/// An address of the capture device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalAddr {
+    pub addr: IpAddr,
+    pub netmask: Option<IpAddr>,
+}
+
+impl LocalAddr {
+    /// Returns true if `ip_addr` is in the subnet of this address, never
+    /// without a netmask.
+    pub fn contains(&self, ip_addr: IpAddr) -> bool {
+        match (self.addr, self.netmask, ip_addr) {
+            (IpAddr::V4(addr), Some(IpAddr::V4(mask)), IpAddr::V4(ip)) => {
+                let mask = u32::from(mask);
+                u32::from(addr) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(addr), Some(IpAddr::V6(mask)), IpAddr::V6(ip)) => {
+                let mask = u128::from(mask);
+                u128::from(addr) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct PCAPMeta {
     pub mac_addr: MacAddr,
     pub ipv4: Ipv4Addr,
     pub ipv6: Ipv6Addr,
     pub name: String,
+    /// Timestamp type of the capture, None for devices not captured on.
+    pub tstamp_type: Option<TimestampType>,
+    pub link_type: LinkType,
+    /// IPv4 routes (destination, mask) going out through this device.
+    pub routes: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// Every address of the device. `ipv4` and `ipv6` are the first of
+    /// their family, and count as local even if missing here.
+    pub addresses: Vec<LocalAddr>,
+    /// Nanoseconds added to the timestamps of the capture. Non-zero only
+    /// for a NIC clock not kept in step with the system clock, which is put
+    /// on it at the first packet after each open.
+    pub capture_offset: i64,
 }
 
 impl PCAPMeta {
-    pub fn new(device: Device, mac_addr: MacAddress) -> Self {
+    pub fn new(device: Device, mac_addr: MacAddress, link_type: LinkType) -> Self {
         let mut ipv4 = None;
         let mut ipv6 = None;
         for addr in &device.addresses {
@@ -40,17 +98,73 @@ impl PCAPMeta {
             ipv4: ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED),
             ipv6: ipv6.unwrap_or(Ipv6Addr::UNSPECIFIED),
             name: device.name.clone(),
+            tstamp_type: None,
+            link_type,
+            routes: Vec::new(),
+            addresses: device
+                .addresses
+                .iter()
+                .map(|addr| LocalAddr {
+                    addr: addr.addr,
+                    netmask: addr.netmask,
+                })
+                .collect(),
+            capture_offset: 0,
         }
     }
 
-    pub fn matches_ip(&self, ip_addr: IpAddr) -> bool {
+    /// Clock the timestamps of the capture come from.
+    pub fn clock_domain(&self) -> ClockDomain {
+        self.tstamp_type.map_or(ClockDomain::Host, ClockDomain::of)
+    }
+
+    /// Returns true if `ip_addr` is reached through this device according
+    /// to the routing table.
+    pub fn routes_via(&self, ip_addr: IpAddr) -> bool {
         match ip_addr {
+            IpAddr::V4(ip) => self
+                .routes
+                .iter()
+                .any(|(dest, mask)| u32::from(ip) & u32::from(*mask) == u32::from(*dest)),
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// Direction of a packet on this device.
+    ///
+    /// Uses the destination MAC on Ethernet. Links without a meaningful MAC
+    /// fall back to the IP addresses: packets to us are incoming, packets from
+    /// us are outgoing, and forwarded packets are outgoing if they are routed
+    /// out through this device.
+    pub fn direction(&self, dst_mac: MacAddr, src_ip: IpAddr, dst_ip: IpAddr) -> Direction {
+        if self.link_type.has_mac() && self.mac_addr != MacAddr::zero() {
+            return Direction::from_mac(dst_mac, self.mac_addr);
+        }
+        if self.matches_ip(dst_ip) {
+            Direction::Incoming
+        } else if self.matches_ip(src_ip) || self.routes_via(dst_ip) {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+
+    /// Returns true if `ip_addr` is one of the device's addresses.
+    pub fn matches_ip(&self, ip_addr: IpAddr) -> bool {
+        let primary = match ip_addr {
             IpAddr::V4(ip) => ip == self.ipv4,
             IpAddr::V6(ip) => ip == self.ipv6,
-        }
+        };
+        primary || self.addresses.iter().any(|local| local.addr == ip_addr)
     }
 
+    /// Local address toward `ip_addr`: the one whose subnet holds it, else
+    /// the first of its family. None if the device has no address of that
+    /// family.
     pub fn get_match(&self, ip_addr: IpAddr) -> Option<IpAddr> {
+        if let Some(local) = self.addresses.iter().find(|local| local.contains(ip_addr)) {
+            return Some(local.addr);
+        }
         match ip_addr {
             IpAddr::V4(_) if self.ipv4 != Ipv4Addr::UNSPECIFIED => Some(IpAddr::V4(self.ipv4)),
             IpAddr::V6(_) if self.ipv6 != Ipv6Addr::UNSPECIFIED => Some(IpAddr::V6(self.ipv6)),
@@ -59,17 +173,24 @@ impl PCAPMeta {
     }
 
     pub fn matches(&self, mac_addr: MacAddr, ip_addr: Option<IpAddr>) -> bool {
-        if mac_addr == self.mac_addr {
-            if let Some(ip) = ip_addr {
-                match ip {
-                    IpAddr::V4(ip) => ip == self.ipv4,
-                    IpAddr::V6(ip) => ip == self.ipv6,
-                }
-            } else {
-                true
-            }
-        } else {
-            false
+        mac_addr == self.mac_addr && ip_addr.is_none_or(|ip| self.matches_ip(ip))
+    }
+}
+
+#[cfg(test)]
+impl PCAPMeta {
+    /// Ethernet device eth0 at 10.0.0.1, for tests to override.
+    pub fn for_test() -> Self {
+        PCAPMeta {
+            mac_addr: MacAddr::zero(),
+            ipv4: Ipv4Addr::new(10, 0, 0, 1),
+            ipv6: Ipv6Addr::UNSPECIFIED,
+            name: "eth0".to_string(),
+            tstamp_type: None,
+            link_type: LinkType::Ethernet,
+            routes: Vec::new(),
+            addresses: Vec::new(),
+            capture_offset: 0,
         }
     }
 }
@@ -104,36 +225,254 @@ impl PacketCapturer {
         }
     }
 
+    /// Wait for the device `name` to exist and be up.
+    ///
+    /// Retries with exponential backoff between `capture.iface_retry_min`
+    /// and `capture.iface_retry_max`. A zero `timeout` waits forever.
+    /// This blocks the calling thread, so it is only called before the
+    /// runtime starts or from a blocking task.
+    pub fn wait_for_device(name: &str, timeout: Duration) -> Result<Device> {
+        let start = Instant::now();
+        let mut backoff = CONFIG.capture.iface_retry_min;
+        loop {
+            match Self::device_by_name(name) {
+                Ok(device) if device.flags.is_up() => return Ok(device),
+                Ok(_) => info!("Device {} is down, waiting", name),
+                Err(e) => info!("{}, waiting", e),
+            }
+            if !timeout.is_zero() && start.elapsed() + backoff > timeout {
+                return Err(anyhow::anyhow!(
+                    "Device {} did not come up within {:?}",
+                    name,
+                    timeout
+                ));
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(CONFIG.capture.iface_retry_max);
+        }
+    }
+
     /// Create a new PacketCapturer instance
     ///
     /// It takes a `CapEventSender` to send captured packets to the parser thread
     /// and an optional device name. If no device name is provided, it will
-    /// use the default interface.
-    pub fn new(sender: CapEventSender, name: Option<String>) -> CaptureResult {
+    /// use the default interface. A named device that does not exist yet is
+    /// waited for, up to `client.iface_wait`. The capture filter follows
+    /// `subnets`.
+    pub fn new(
+        sender: CapEventSender,
+        name: Option<String>,
+        mut subnets: watch::Receiver<SubnetRules>,
+    ) -> CaptureResult {
         let device = match name {
-            Some(name) => Self::device_by_name(&name)?,
+            Some(name) => Self::wait_for_device(&name, CONFIG.client.iface_wait)?,
             None => Device::lookup()?.ok_or("No device available for capture")?,
         };
 
         info!("Using device: {}", device.name);
 
-        let cap = Capture::from_device(device.clone())?
-            .promisc(Settings::PROMISC)
-            .immediate_mode(Settings::IMMEDIATE_MODE)
-            .timeout(Settings::TIMEOUT) // Timeout in milliseconds
-            .tstamp_type(CONFIG.client.tstamp_type)
+        let (cap, tstamp_type) = Self::open_capture(&device, &subnets.borrow_and_update())?;
+        let link_type = LinkType::from(cap.get_datalink());
+        info!("Link type: {:?}", link_type);
+        let meta = Self::build_meta(&device, link_type, Some(tstamp_type))?;
+
+        let (meta_tx, _) = watch::channel(meta.clone());
+        let (dumper, dump_handle) = Dumper::new();
+
+        Ok((
+            PacketCapturer {
+                cap,
+                name: device.name.clone(),
+                sender,
+                meta: meta.clone(),
+                meta_tx: Arc::new(meta_tx),
+                subnets,
+                dumper,
+                dump_handle,
+            },
+            meta,
+        ))
+    }
+
+    /// Subscribe to changes of the device metadata (addresses).
+    pub fn subscribe_meta(&self) -> watch::Receiver<PCAPMeta> {
+        self.meta_tx.subscribe()
+    }
+
+    /// Requests pcap dumps of single links from the capture.
+    pub fn dump_handle(&self) -> DumpHandle {
+        self.dump_handle.clone()
+    }
+
+    /// Publish `meta` if it differs from the current value.
+    ///
+    /// Returns true if subscribers were notified.
+    fn publish_meta(meta_tx: &watch::Sender<PCAPMeta>, meta: PCAPMeta) -> bool {
+        meta_tx.send_if_modified(|current| {
+            if *current == meta {
+                return false;
+            }
+            info!("Addresses of {} changed: {:?}", meta.name, meta);
+            *current = meta;
+            true
+        })
+    }
+
+    /// Spawn a task which re-reads the device addresses every
+    /// `capture.addr_refresh_interval`, so DHCP renewals and
+    /// reconfiguration are picked up while the capture keeps running.
+    pub fn dispatch_meta_refresh(&self) -> task::JoinHandle<()> {
+        let name = self.name.clone();
+        let meta_tx = self.meta_tx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CONFIG.capture.addr_refresh_interval);
+            loop {
+                interval.tick().await;
+                let name = name.clone();
+                let (link_type, tstamp_type, capture_offset) = {
+                    let meta = meta_tx.borrow();
+                    (meta.link_type, meta.tstamp_type, meta.capture_offset)
+                };
+                let meta = task::spawn_blocking(move || {
+                    let device = Self::device_by_name(&name)?;
+                    let mut meta = Self::build_meta(&device, link_type, tstamp_type)?;
+                    meta.capture_offset = capture_offset;
+                    Ok::<_, anyhow::Error>(meta)
+                })
+                .await;
+                match meta {
+                    Ok(Ok(meta)) => {
+                        Self::publish_meta(&meta_tx, meta);
+                    }
+                    // The interface may be down, the capture loop handles that.
+                    Ok(Err(e)) => warn!("Failed to refresh device addresses: {}", e),
+                    Err(e) => error!("Address refresh task failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Configure an inactive capture handle for `device`.
+    fn build_capture(device: &Device, tstamp_type: TimestampType) -> Result<Capture<Inactive>> {
+        Ok(Capture::from_device(device.clone())?
+            .promisc(CONFIG.capture.promisc)
+            .immediate_mode(CONFIG.capture.immediate_mode)
+            .timeout(CONFIG.capture.timeout)
+            .tstamp_type(tstamp_type)
             .precision(CONFIG.client.timestamp_precision)
-            .snaplen(Settings::SNAPLEN);
+            .snaplen(CONFIG.capture.snaplen))
+    }
+
+    /// `client.tstamp_type`, or the first type in `tstamp::FALLBACK` after
+    /// it that `device` has.
+    fn tstamp_type(device: &Device) -> TimestampType {
+        let configured = CONFIG.client.tstamp_type;
+        let supported = match tstamp::supported(&device.name) {
+            Ok(supported) => supported,
+            Err(e) => {
+                warn!("{}", e);
+                return configured;
+            }
+        };
+        let chosen = tstamp::choose(configured, &supported);
+        if chosen != configured {
+            warn!(
+                "{} has no {} timestamps, using {}",
+                device.name,
+                tstamp::name(configured),
+                tstamp::name(chosen)
+            );
+        }
+        chosen
+    }
 
+    /// Open a capture on `device`, with the tool's own traffic and what
+    /// `subnets` drop filtered out. Returns it with its timestamp type.
+    fn open_capture(
+        device: &Device,
+        subnets: &SubnetRules,
+    ) -> Result<(Capture<Active>, TimestampType)> {
+        let tstamp_type = Self::tstamp_type(device);
+        let mut cap = Self::build_capture(device, tstamp_type)?.open()?;
+        info!("Timestamps: {}", tstamp::name(tstamp_type));
+        let filter = Self::capture_filter(subnets);
+        if !filter.is_empty() {
+            Self::set_filter(&mut cap, &filter);
+        }
+        Ok((cap, tstamp_type))
+    }
+
+    /// BPF expression of the capture, empty if it keeps all packets.
+    fn capture_filter(subnets: &SubnetRules) -> String {
+        [SelfTraffic::from_config().bpf(), subnets.bpf()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" and ")
+    }
+
+    fn set_filter(cap: &mut Capture<Active>, filter: &str) {
+        // The parser drops these packets as well, so carry on without the
+        // filter if it does not compile on this link type.
+        match cap.filter(filter, true) {
+            Ok(()) => info!("Capture filter: \"{}\"", filter),
+            Err(e) => warn!("Failed to set capture filter \"{}\": {}", filter, e),
+        }
+    }
+
+    /// Read the MAC and IP addresses and the routes of `device`.
+    fn build_meta(
+        device: &Device,
+        link_type: LinkType,
+        tstamp_type: Option<TimestampType>,
+    ) -> Result<PCAPMeta> {
         let mac_addr = match get_mac_address() {
             Ok(Some(mac)) => mac,
-            Ok(None) => return Err("No MAC address found".into()),
+            // Direction is taken from the IP addresses on these links anyway.
+            Ok(None) if !link_type.has_mac() => MacAddress::new([0; 6]),
+            Ok(None) => return Err(anyhow::anyhow!("No MAC address found")),
             Err(e) => return Err(e.into()),
         };
+        let mut meta = PCAPMeta::new(device.clone(), mac_addr, link_type);
+        meta.routes = Self::read_routes(&device.name);
+        meta.tstamp_type = tstamp_type;
+        Ok(meta)
+    }
 
-        let meta = PCAPMeta::new(device.clone(), mac_addr);
+    /// IPv4 routes through `name` from `/proc/net/route`.
+    fn read_routes(name: &str) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        match procfs::net::route() {
+            Ok(routes) => routes
+                .into_iter()
+                .filter(|route| route.iface == name)
+                .map(|route| (route.destination, route.mask))
+                .collect(),
+            Err(e) => {
+                warn!("Failed to read routing table: {}", e);
+                Vec::new()
+            }
+        }
+    }
 
-        Ok((PacketCapturer { cap, sender }, meta))
+    /// Wait for the device to come back and open a new capture on it.
+    ///
+    /// Returns the new handle together with the device metadata, which
+    /// may differ from before if the addresses changed.
+    fn reopen(name: &str, subnets: &SubnetRules) -> (Capture<Active>, PCAPMeta) {
+        loop {
+            let result = Self::wait_for_device(name, Duration::ZERO).and_then(|device| {
+                let (cap, tstamp_type) = Self::open_capture(&device, subnets)?;
+                let link_type = LinkType::from(cap.get_datalink());
+                Ok((cap, Self::build_meta(&device, link_type, Some(tstamp_type))?))
+            });
+            match result {
+                Ok(reopened) => return reopened,
+                Err(e) => {
+                    warn!("Failed to reopen capture on {}: {}", name, e);
+                    std::thread::sleep(CONFIG.capture.iface_retry_max);
+                }
+            }
+        }
     }
 
     /// Start the asynchronous packet capturing loop
@@ -144,20 +483,69 @@ impl PacketCapturer {
     pub fn start_capture_loop(self) -> task::JoinHandle<Result<()>> {
         // Clone the sender to move into the thread
         let sender = self.sender.clone();
+        let name = self.name;
+        let mut meta = self.meta;
+        let meta_tx = self.meta_tx;
+        let mut meta_rx = meta_tx.subscribe();
+        let mut subnets = self.subnets;
+        let parse_in_capture = CONFIG.client.parse_in_capture;
+        let mut dumper = self.dumper;
+        let mut ring = RingSender::from_config(self.cap.get_datalink(), &meta);
         // Capture needs to be in a blocking task since pcap::Capture is blocking
         task::spawn_blocking(move || {
-            let mut cap = match self.cap.open() {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("Failed to open capture: {}", e);
-                    return Err(e.into());
-                }
-            }; // Open the capture
+            let mut cap = self.cap;
+            // A free running NIC clock is put on the system clock at the
+            // first packet after each open, other clocks are left as they
+            // are with the offset of a new meta
+            let mut sync_clock = meta.clock_domain() == ClockDomain::AdapterUnsynced;
             loop {
+                dumper.poll(&cap);
+                if subnets.has_changed().unwrap_or(false) {
+                    let filter = Self::capture_filter(&subnets.borrow_and_update());
+                    Self::set_filter(&mut cap, &filter);
+                }
                 match cap.next_packet() {
                     Ok(packet) => {
-                        let packet = OwnedPacket::from(packet);
-                        match sender.blocking_send(CapEvent::Packet(packet)) {
+                        if sync_clock {
+                            let precision = CONFIG.client.timestamp_precision;
+                            let stamped = Timestamp::from_timeval_with(packet.header.ts, precision);
+                            let offset = stamped.offset_to(Timestamp::now());
+                            meta_tx.send_modify(|meta| meta.capture_offset = offset);
+                            sync_clock = false;
+                        }
+                        if meta_rx.has_changed().unwrap_or(false) {
+                            meta = meta_rx.borrow_and_update().clone();
+                            if let Some(ring) = &ring {
+                                ring.set_meta(&meta);
+                            }
+                        }
+                        dumper.write(&packet, &meta);
+                        if ring.as_mut().is_some_and(|r| !r.write(&packet)) {
+                            ring = None;
+                        }
+                        // Parsing here only needs the headers, so the frame
+                        // is never copied out of the pcap buffer.
+                        let event = if parse_in_capture {
+                            let parsed = ParsedPacket::from_raw(packet.header, packet.data, &meta);
+                            // ARP is not IP, and ND is also counted as IP traffic
+                            if parsed.as_ref().map_or(true, ParsedPacket::is_icmpv6) {
+                                if let Some(neighbor) =
+                                    NeighborPacket::from_raw(packet.header, packet.data, &meta)
+                                {
+                                    if let Err(e) = sender.blocking_send(CapEvent::Neighbor(neighbor)) {
+                                        error!("Failed to send packet: {}", e);
+                                        return Err(e.into());
+                                    }
+                                }
+                            }
+                            match parsed {
+                                Some(parsed) => CapEvent::Parsed(parsed),
+                                None => continue,
+                            }
+                        } else {
+                            CapEvent::Packet(OwnedPacket::from(packet))
+                        };
+                        match sender.blocking_send(event) {
                             Ok(()) => {}
                             Err(e) => {
                                 error!("Failed to send packet: {}", e);
@@ -165,9 +553,18 @@ impl PacketCapturer {
                             }
                         }
                     }
+                    Err(pcap::Error::TimeoutExpired) => continue,
                     Err(e) => {
-                        error!("Error capturing packet: {}", e);
-                        continue;
+                        // Usually the interface went down or was removed.
+                        // Wait for it to come back instead of spinning on
+                        // the dead handle.
+                        warn!("Capture on {} failed: {}, reopening", name, e);
+                        let rules = subnets.borrow_and_update().clone();
+                        let (new_cap, new_meta) = Self::reopen(&name, &rules);
+                        cap = new_cap;
+                        sync_clock = new_meta.clock_domain() == ClockDomain::AdapterUnsynced;
+                        info!("Capture on {} reopened", name);
+                        Self::publish_meta(&meta_tx, new_meta);
                     }
                 }
             }
@@ -185,10 +582,8 @@ mod tests {
     #[test]
     fn test_pcap_meta_matches_ip() {
         let meta = PCAPMeta {
-            mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
             ipv4: Ipv4Addr::new(192, 168, 1, 1),
-            ipv6: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
-            name: "eth0".to_string(),
+            ..PCAPMeta::for_test()
         };
 
         assert!(meta.matches_ip(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))));
@@ -199,10 +594,8 @@ mod tests {
     #[test]
     fn test_pcap_meta_matches() {
         let meta = PCAPMeta {
-            mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
             ipv4: Ipv4Addr::new(192, 168, 1, 1),
-            ipv6: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
-            name: "eth0".to_string(),
+            ..PCAPMeta::for_test()
         };
 
         assert!(meta.matches(MacAddr::new(0, 0, 0, 0, 0, 0), None));
@@ -221,6 +614,35 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_pcap_meta_secondary_addresses() {
+        let v4 = |d: u8| IpAddr::V4(Ipv4Addr::new(10, 0, d, 1));
+        let mask = Some(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0)));
+        let v6: IpAddr = "fd00::1".parse().unwrap();
+        let local = |addr, netmask| LocalAddr { addr, netmask };
+        let meta = PCAPMeta {
+            ipv6: "fd00::1".parse().unwrap(),
+            name: "tun0".to_string(),
+            link_type: LinkType::Raw,
+            addresses: vec![local(v4(0), mask), local(v4(1), mask), local(v6, None)],
+            ..PCAPMeta::for_test()
+        };
+
+        assert!(meta.matches_ip(v4(1)));
+        assert!(meta.matches_ip(v6));
+        assert!(!meta.matches_ip(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2))));
+        assert!(meta.matches(MacAddr::zero(), Some(v4(1))));
+        // Traffic to and from the secondary address
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 9));
+        assert_eq!(meta.direction(MacAddr::zero(), remote, v4(1)), Direction::Incoming);
+        assert_eq!(meta.direction(MacAddr::zero(), v4(1), remote), Direction::Outgoing);
+
+        assert_eq!(meta.get_match(remote), Some(v4(1)));
+        assert_eq!(meta.get_match(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1))), Some(v4(0)));
+        // Without a netmask only the first address of the family is known
+        assert_eq!(meta.get_match("fd00::2".parse().unwrap()), Some(v6));
+    }
+
     #[test]
     fn test_owned_packet_from_packet() {
         let packet = Packet {
@@ -244,10 +666,53 @@ mod tests {
         assert_eq!(owned_packet.data.len(), 1);
     }
 
+    #[test]
+    fn test_direction_without_mac() {
+        let meta = PCAPMeta {
+            ipv4: Ipv4Addr::new(10, 8, 0, 2),
+            name: "tun0".to_string(),
+            link_type: LinkType::Raw,
+            routes: vec![(Ipv4Addr::new(10, 9, 0, 0), Ipv4Addr::new(255, 255, 0, 0))],
+            ..PCAPMeta::for_test()
+        };
+        let local = IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2));
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1));
+        let routed = IpAddr::V4(Ipv4Addr::new(10, 9, 1, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        let mac = MacAddr::zero();
+
+        assert_eq!(meta.direction(mac, remote, local), Direction::Incoming);
+        assert_eq!(meta.direction(mac, local, remote), Direction::Outgoing);
+        // Forwarded traffic
+        assert_eq!(meta.direction(mac, other, routed), Direction::Outgoing);
+        assert_eq!(meta.direction(mac, routed, other), Direction::Incoming);
+    }
+
+    #[test]
+    fn test_publish_meta_only_notifies_on_change() {
+        let meta = PCAPMeta {
+            ipv4: Ipv4Addr::new(192, 168, 1, 1),
+            ..PCAPMeta::for_test()
+        };
+        let (tx, mut rx) = watch::channel(meta.clone());
+
+        assert!(!PacketCapturer::publish_meta(&tx, meta.clone()));
+        assert!(!rx.has_changed().unwrap());
+
+        let renewed = PCAPMeta {
+            ipv4: Ipv4Addr::new(192, 168, 1, 2),
+            ..meta
+        };
+        assert!(PacketCapturer::publish_meta(&tx, renewed.clone()));
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(*rx.borrow_and_update(), renewed);
+    }
+
     #[test]
     fn test_packet_capturer_new() {
         let (sender, _) = ch::channel(10);
-        let result = PacketCapturer::new(sender, None);
+        let (_subnets_tx, subnets) = watch::channel(SubnetRules::default());
+        let result = PacketCapturer::new(sender, None, subnets);
         assert!(result.is_ok());
     }
 }
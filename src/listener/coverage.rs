@@ -0,0 +1,154 @@
+//! How many of the captured packets the parser gets through to the links,
+//! counted per measurement window and reported in `NodeStatus.coverage`.
+//! How many of those end up in gin/gout points is in `LinkStatus`.
+//!
+//! With `client.parse_in_capture`, packets that do not parse never reach
+//! the parser, so they count neither as captured nor as parsed.
+
+use std::collections::{BTreeMap, HashMap};
+
+use log::info;
+
+use crate::ParsedPacket;
+
+/// Packets at each stage of the parser over a window.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// Packets from the capture.
+    pub captured: u64,
+    /// Of those, IP packets the parser could read.
+    pub parsed: u64,
+    /// Packets with lengths shorter than their headers or past 16 bits,
+    /// parsed with saturated lengths.
+    pub malformed_lengths: u64,
+    /// Parsed packets dropped, by the packet filter that dropped them.
+    pub filtered: BTreeMap<&'static str, u64>,
+    /// Packets handed to the `LinkManager`.
+    pub tracked: u64,
+    /// ACK groups the TCP trackers skipped as empty or out of order.
+    pub skipped_groups: u64,
+    /// TCP frames longer than `offload.mtu`, from GSO/GRO.
+    pub super_frames: u64,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// A packet from the capture, with what the parser read of it.
+    pub fn record_captured(&mut self, parsed: Option<&ParsedPacket>) {
+        self.captured += 1;
+        if let Some(parsed) = parsed {
+            self.parsed += 1;
+            if parsed.malformed_length {
+                self.malformed_lengths += 1;
+            }
+        }
+    }
+
+    /// A packet `filter` dropped.
+    pub fn record_filtered(&mut self, filter: &'static str) {
+        *self.filtered.entry(filter).or_default() += 1;
+    }
+
+    pub fn record_tracked(&mut self) {
+        self.tracked += 1;
+    }
+
+    /// Parsed packets dropped by any filter.
+    pub fn filtered_total(&self) -> u64 {
+        self.filtered.values().sum()
+    }
+
+    /// Counters by name, each filter's as "filtered_<name>".
+    pub fn to_map(&self) -> HashMap<String, u64> {
+        let mut map: HashMap<String, u64> = [
+            ("captured", self.captured),
+            ("parsed", self.parsed),
+            ("malformed_lengths", self.malformed_lengths),
+            ("filtered", self.filtered_total()),
+            ("tracked", self.tracked),
+            ("skipped_groups", self.skipped_groups),
+            ("super_frames", self.super_frames),
+        ]
+        .into_iter()
+        .map(|(name, count)| (name.to_string(), count))
+        .collect();
+        map.extend(
+            self.filtered
+                .iter()
+                .map(|(name, count)| (format!("filtered_{}", name), *count)),
+        );
+        map
+    }
+}
+
+/// The window being counted and the last complete one.
+#[derive(Debug, Default)]
+pub struct CoverageWindows {
+    pub current: Coverage,
+    last: Coverage,
+}
+
+impl CoverageWindows {
+    pub fn new() -> Self {
+        CoverageWindows::default()
+    }
+
+    /// Ends the current window, logging it if any packets were captured.
+    pub fn rotate(&mut self) {
+        self.last = std::mem::take(&mut self.current);
+        let last = &self.last;
+        if last.captured > 0 {
+            info!(
+                "Coverage: {} captured, {} parsed, {} filtered, {} tracked ({:.1}%)",
+                last.captured,
+                last.parsed,
+                last.filtered_total(),
+                last.tracked,
+                100.0 * last.tracked as f64 / last.captured as f64
+            );
+        }
+    }
+
+    /// The last complete window.
+    pub fn last(&self) -> &Coverage {
+        &self.last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Direction, Timestamp};
+
+    #[test]
+    fn test_coverage_windows() {
+        let mut windows = CoverageWindows::new();
+        let timestamp = Timestamp::from_millis(1_000);
+        let packet = ParsedPacket::test_tcp(1, 1, 100, timestamp, Direction::Outgoing);
+        let malformed = ParsedPacket {
+            malformed_length: true,
+            ..ParsedPacket::test_tcp(1, 1, 0, timestamp, Direction::Outgoing)
+        };
+        windows.current.record_captured(Some(&packet));
+        windows.current.record_captured(Some(&malformed));
+        windows.current.record_captured(None);
+        windows.current.record_filtered("loopback");
+        windows.current.record_tracked();
+
+        windows.rotate();
+        assert_eq!(windows.current, Coverage::new());
+        let map = windows.last().to_map();
+        assert_eq!(map["captured"], 3);
+        assert_eq!(map["parsed"], 2);
+        assert_eq!(map["malformed_lengths"], 1);
+        assert_eq!(map["filtered"], 1);
+        assert_eq!(map["filtered_loopback"], 1);
+        assert_eq!(map["tracked"], 1);
+
+        windows.rotate();
+        assert_eq!(windows.last().to_map()["captured"], 0);
+    }
+}
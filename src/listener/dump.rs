@@ -0,0 +1,230 @@
+//! Writes the packets of a single link to a pcap file for a while, so a
+//! misestimate can be traced back to exactly what the trackers saw.
+//!
+//! Dumps are taken from the open capture by the capture thread itself. The
+//! thread only runs when packets arrive, so a dump starts and ends with the
+//! first packet on the interface after it was requested or its time is up.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use pcap::{Activated, Capture, Packet, Savefile};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::anonymize::{export_frame, export_ip};
+use crate::channel::send_or_err;
+use crate::listener::capture::PCAPMeta;
+use crate::stream_id::IpPair;
+use crate::{ParsedPacket, Timestamp};
+
+/// Longest dump that can be requested.
+pub const MAX_DUMP_DURATION: Duration = Duration::from_secs(300);
+
+/// How long past the end of a dump to wait for a packet to close it.
+const DUMP_GRACE: Duration = Duration::from_secs(5);
+
+/// A finished dump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpResult {
+    pub path: PathBuf,
+    pub packets: u64,
+    /// Bytes on the wire, not just the captured headers.
+    pub bytes: u64,
+}
+
+struct DumpJob {
+    pair: IpPair,
+    duration: Duration,
+    reply: oneshot::Sender<Result<DumpResult>>,
+}
+
+impl std::fmt::Debug for DumpJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DumpJob({}, {:?})", self.pair, self.duration)
+    }
+}
+
+/// Requests dumps from the capture thread.
+#[derive(Debug, Clone)]
+pub struct DumpHandle {
+    tx: mpsc::Sender<DumpJob>,
+}
+
+impl DumpHandle {
+    /// Dumps the packets between the two addresses of `pair` for
+    /// `duration`, returning once the file is complete.
+    pub async fn dump(&self, pair: IpPair, duration: Duration) -> Result<DumpResult> {
+        if duration.is_zero() || duration > MAX_DUMP_DURATION {
+            return Err(anyhow!(
+                "Duration must be between 1 and {} seconds",
+                MAX_DUMP_DURATION.as_secs()
+            ));
+        }
+        let (reply, rx) = oneshot::channel();
+        let job = DumpJob { pair, duration, reply };
+        send_or_err(&self.tx, job, "dump request").await?;
+        match tokio::time::timeout(duration + DUMP_GRACE, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(anyhow!("Capture stopped during the dump")),
+            Err(_) => Err(anyhow!("No packets captured to close the dump")),
+        }
+    }
+}
+
+/// Dump being written by the capture thread.
+struct ActiveDump {
+    pair: IpPair,
+    until: Instant,
+    file: Savefile,
+    result: DumpResult,
+    reply: oneshot::Sender<Result<DumpResult>>,
+}
+
+/// Capture thread side of the dumps, one at a time.
+pub struct Dumper {
+    rx: mpsc::Receiver<DumpJob>,
+    active: Option<ActiveDump>,
+}
+
+impl Dumper {
+    pub fn new() -> (Dumper, DumpHandle) {
+        let (tx, rx) = mpsc::channel(4);
+        (Dumper { rx, active: None }, DumpHandle { tx })
+    }
+
+    /// Finishes the current dump if its time is up and starts the next
+    /// one. Called before reading each packet from `cap`.
+    pub fn poll<T: Activated + ?Sized>(&mut self, cap: &Capture<T>) {
+        let expired = self.active.as_ref().is_some_and(|dump| {
+            Instant::now() >= dump.until || dump.reply.is_closed()
+        });
+        if expired {
+            self.finish();
+        }
+        while let Ok(job) = self.rx.try_recv() {
+            if self.active.is_some() {
+                let _ = job.reply.send(Err(anyhow!("Another dump is running")));
+                continue;
+            }
+            let path = std::env::temp_dir().join(format!(
+                "dump-{}-{}-{}.pcap",
+                export_ip(job.pair.local()),
+                export_ip(job.pair.remote()),
+                Timestamp::now().as_millis()
+            ));
+            match cap.savefile(&path) {
+                Ok(file) => {
+                    info!("Dumping {} to {:?} for {:?}", job.pair, path, job.duration);
+                    self.active = Some(ActiveDump {
+                        pair: job.pair,
+                        until: Instant::now() + job.duration,
+                        file,
+                        result: DumpResult {
+                            path,
+                            packets: 0,
+                            bytes: 0,
+                        },
+                        reply: job.reply,
+                    });
+                }
+                Err(e) => {
+                    let _ = job.reply.send(Err(anyhow!("Failed to open {:?}: {}", path, e)));
+                }
+            }
+        }
+    }
+
+    /// Writes `packet` to the current dump if it belongs to the link.
+    pub fn write(&mut self, packet: &Packet, meta: &PCAPMeta) {
+        let Some(dump) = self.active.as_mut() else {
+            return;
+        };
+        let Some(parsed) = ParsedPacket::from_raw(packet.header, packet.data, meta) else {
+            return;
+        };
+        if IpPair::new(parsed.src_ip, parsed.dst_ip) == dump.pair {
+            let data = export_frame(meta.link_type, packet.data);
+            dump.file.write(&Packet::new(packet.header, &data));
+            dump.result.packets += 1;
+            dump.result.bytes += packet.header.len as u64;
+        }
+    }
+
+    fn finish(&mut self) {
+        let Some(mut dump) = self.active.take() else {
+            return;
+        };
+        let result = match dump.file.flush() {
+            Ok(()) => Ok(dump.result),
+            Err(e) => Err(anyhow!("Failed to write {:?}: {}", dump.result.path, e)),
+        };
+        if dump.reply.send(result).is_err() {
+            warn!("Dump of {} finished, but nobody is waiting for it", dump.pair);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pcap::{Linktype, PacketHeader};
+
+    use super::*;
+    use crate::listener::capture::LinkType;
+
+    /// ICMP over IPv4 without payload, as seen on a tun device.
+    fn ipv4_packet(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut data = vec![
+            0x45, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x40, 0x01, 0x00, 0x00,
+        ];
+        data.extend_from_slice(&src);
+        data.extend_from_slice(&dst);
+        data
+    }
+
+    #[test]
+    fn test_dump_only_writes_the_link() {
+        let meta = PCAPMeta {
+            name: "tun0".to_string(),
+            link_type: LinkType::Raw,
+            ..PCAPMeta::for_test()
+        };
+        let cap = Capture::dead(Linktype(101)).unwrap();
+        let (mut dumper, handle) = Dumper::new();
+        let (reply, mut rx) = oneshot::channel();
+        let pair = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let job = DumpJob {
+            pair,
+            duration: Duration::from_secs(60),
+            reply,
+        };
+        handle.tx.try_send(job).unwrap();
+        dumper.poll(&cap);
+
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 1,
+                tv_usec: 0,
+            },
+            caplen: 20,
+            len: 1020,
+        };
+        for (src, dst) in [
+            ([10, 0, 0, 2], [10, 0, 0, 1]),
+            ([10, 0, 0, 1], [10, 0, 0, 3]),
+            ([10, 0, 0, 1], [10, 0, 0, 2]),
+        ] {
+            let data = ipv4_packet(src, dst);
+            dumper.write(&Packet::new(&header, &data), &meta);
+        }
+        assert!(rx.try_recv().is_err());
+
+        dumper.active.as_mut().unwrap().until = Instant::now();
+        dumper.poll(&cap);
+        let result = rx.try_recv().unwrap().unwrap();
+        assert_eq!((result.packets, result.bytes), (2, 2040));
+        assert!(result.path.exists());
+        std::fs::remove_file(result.path).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+//! Packets dropped in the parser before they reach the `LinkManager`.
+//!
+//! Each filter in `packet_filter.chain` sees the packets the ones before it
+//! kept, and counts the packets it drops, reported in `NodeStatus.dropped`.
+//! The subnet rules can be replaced at runtime with the SetSubnets RPC, the
+//! capture filter then follows them where it can.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use pnet::ipnetwork::IpNetwork;
+use tokio::sync::watch;
+
+use crate::listener::tracking::self_traffic::SelfTraffic;
+use crate::stream_id::IpPair;
+use crate::{ParsedPacket, Timestamp, CONFIG};
+
+/// Window `RateFilter` counts packets over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+pub trait PacketFilter: Send {
+    /// Name in `packet_filter.chain` and in the drop counters.
+    fn name(&self) -> &'static str;
+
+    /// Returns true if the packet should be dropped.
+    fn drops(&mut self, packet: &ParsedPacket) -> bool;
+
+    /// Forgets state from before `now`, called on the cleanup interval.
+    fn prune(&mut self, _now: Timestamp) {}
+}
+
+/// Loopback traffic, which never crosses a link.
+pub struct LoopbackFilter;
+
+impl PacketFilter for LoopbackFilter {
+    fn name(&self) -> &'static str {
+        "loopback"
+    }
+
+    fn drops(&mut self, packet: &ParsedPacket) -> bool {
+        packet.src_ip.is_loopback() || packet.dst_ip.is_loopback()
+    }
+}
+
+/// Multicast traffic. Its groups are not hosts to report links to or to
+/// greet over gRPC.
+pub struct MulticastFilter;
+
+impl PacketFilter for MulticastFilter {
+    fn name(&self) -> &'static str {
+        "multicast"
+    }
+
+    fn drops(&mut self, packet: &ParsedPacket) -> bool {
+        packet.src_ip.is_multicast() || packet.dst_ip.is_multicast()
+    }
+}
+
+/// Traffic from or to a port in the list.
+pub struct PortFilter {
+    ports: BTreeSet<u16>,
+}
+
+impl PortFilter {
+    pub fn new(ports: impl IntoIterator<Item = u16>) -> Self {
+        PortFilter {
+            ports: ports.into_iter().collect(),
+        }
+    }
+}
+
+impl PacketFilter for PortFilter {
+    fn name(&self) -> &'static str {
+        "ports"
+    }
+
+    fn drops(&mut self, packet: &ParsedPacket) -> bool {
+        packet
+            .get_src_dst_port()
+            .is_some_and(|(src, dst)| self.ports.contains(&src) || self.ports.contains(&dst))
+    }
+}
+
+/// Prefixes whose traffic is tracked or ignored, from `packet_filter` and
+/// replaced at runtime with the SetSubnets RPC.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubnetRules {
+    /// Only traffic with remotes in these is kept, all if empty.
+    pub allow: Vec<IpNetwork>,
+    /// Traffic with an address in these is dropped.
+    pub deny: Vec<IpNetwork>,
+}
+
+impl SubnetRules {
+    /// Invalid prefixes are logged and skipped.
+    pub fn from_config() -> Self {
+        let config = &CONFIG.packet_filter;
+        SubnetRules {
+            allow: parse_prefixes(&config.allow_prefixes),
+            deny: parse_prefixes(&config.deny_prefixes),
+        }
+    }
+
+    /// Fails on the first invalid prefix.
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self> {
+        let parse = |prefixes: &[String]| -> Result<Vec<IpNetwork>> {
+            prefixes
+                .iter()
+                .map(|p| IpNetwork::from_str(p).with_context(|| format!("Invalid prefix {}", p)))
+                .collect()
+        };
+        Ok(SubnetRules {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    /// Returns true if the packet should be dropped.
+    pub fn drops(&self, packet: &ParsedPacket) -> bool {
+        let denied = self
+            .deny
+            .iter()
+            .any(|network| network.contains(packet.src_ip) || network.contains(packet.dst_ip));
+        let remote = IpPair::from_packet(packet).remote();
+        denied || !(self.allow.is_empty() || self.allow.iter().any(|n| n.contains(remote)))
+    }
+
+    /// BPF expression dropping what the rules do, None if they keep all.
+    ///
+    /// It can only look at both addresses, so allowed traffic is kept if
+    /// either is in a prefix. ARP and ND are always kept for the neighbor
+    /// table.
+    pub fn bpf(&self) -> Option<String> {
+        // BPF rejects host bits in a net expression
+        let nets = |prefixes: &[IpNetwork]| -> Vec<String> {
+            prefixes
+                .iter()
+                .map(|network| format!("net {}/{}", network.network(), network.prefix()))
+                .collect()
+        };
+        let mut terms = Vec::new();
+        if !self.deny.is_empty() {
+            terms.push(format!("not ({})", nets(&self.deny).join(" or ")));
+        }
+        if !self.allow.is_empty() {
+            terms.push(format!(
+                "(arp or icmp6 or {})",
+                nets(&self.allow).join(" or ")
+            ));
+        }
+        (!terms.is_empty()).then(|| terms.join(" and "))
+    }
+}
+
+/// Traffic with an address in `client.exclude_prefixes`, and what the
+/// current `SubnetRules` drop.
+pub struct SubnetFilter {
+    excluded: Vec<IpNetwork>,
+    rules: watch::Receiver<SubnetRules>,
+    /// Copy of the latest rules.
+    current: SubnetRules,
+}
+
+impl SubnetFilter {
+    pub fn new(excluded: Vec<IpNetwork>, mut rules: watch::Receiver<SubnetRules>) -> Self {
+        let current = rules.borrow_and_update().clone();
+        SubnetFilter {
+            excluded,
+            rules,
+            current,
+        }
+    }
+}
+
+impl PacketFilter for SubnetFilter {
+    fn name(&self) -> &'static str {
+        "subnets"
+    }
+
+    fn drops(&mut self, packet: &ParsedPacket) -> bool {
+        if self.rules.has_changed().unwrap_or(false) {
+            self.current = self.rules.borrow_and_update().clone();
+            info!("Subnet rules changed: {:?}", self.current);
+        }
+        let excluded = self
+            .excluded
+            .iter()
+            .any(|network| network.contains(packet.src_ip) || network.contains(packet.dst_ip));
+        excluded || self.current.drops(packet)
+    }
+}
+
+/// Packets beyond `max_rate` per second from or to a remote, so a flood
+/// toward one host does not crowd out the others.
+pub struct RateFilter {
+    max_rate: u32,
+    /// Start of the current window and the packets kept in it, per remote.
+    remotes: HashMap<IpAddr, (Timestamp, u32)>,
+}
+
+impl RateFilter {
+    /// 0 keeps all packets.
+    pub fn new(max_rate: u32) -> Self {
+        RateFilter {
+            max_rate,
+            remotes: HashMap::new(),
+        }
+    }
+}
+
+impl PacketFilter for RateFilter {
+    fn name(&self) -> &'static str {
+        "rate"
+    }
+
+    fn drops(&mut self, packet: &ParsedPacket) -> bool {
+        if self.max_rate == 0 {
+            return false;
+        }
+        let remote = IpPair::from_packet(packet).remote();
+        let (start, count) = self.remotes.entry(remote).or_insert((packet.timestamp, 0));
+        if packet.timestamp >= *start + RATE_WINDOW {
+            (*start, *count) = (packet.timestamp, 0);
+        }
+        *count += 1;
+        *count > self.max_rate
+    }
+
+    fn prune(&mut self, now: Timestamp) {
+        self.remotes
+            .retain(|_, (start, _)| *start + RATE_WINDOW > now);
+    }
+}
+
+/// Filters applied in order, with the packets each one dropped.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<(Box<dyn PacketFilter>, u64)>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        FilterChain::default()
+    }
+
+    /// The filters in `packet_filter.chain`, unknown names are skipped.
+    /// The "subnets" filter follows `subnets`.
+    pub fn from_config(subnets: watch::Receiver<SubnetRules>) -> Self {
+        let config = &CONFIG.packet_filter;
+        let self_traffic = SelfTraffic::from_config();
+        let mut chain = FilterChain::new();
+        for name in &config.chain {
+            match name.as_str() {
+                "loopback" => chain.push(LoopbackFilter),
+                "multicast" => chain.push(MulticastFilter),
+                "ports" => chain.push(PortFilter::new(self_traffic.ports().iter().copied())),
+                "subnets" => chain.push(SubnetFilter::new(
+                    self_traffic.prefixes().to_vec(),
+                    subnets.clone(),
+                )),
+                "rate" => chain.push(RateFilter::new(config.max_packet_rate)),
+                _ => warn!("Ignoring unknown packet filter {}", name),
+            }
+        }
+        chain
+    }
+
+    pub fn push(&mut self, filter: impl PacketFilter + 'static) {
+        self.filters.push((Box::new(filter), 0));
+    }
+
+    /// Returns true if no filter drops the packet.
+    pub fn accepts(&mut self, packet: &ParsedPacket) -> bool {
+        self.dropped_by(packet).is_none()
+    }
+
+    /// Name of the filter that drops the packet, None if none does.
+    pub fn dropped_by(&mut self, packet: &ParsedPacket) -> Option<&'static str> {
+        for (filter, dropped) in &mut self.filters {
+            if filter.drops(packet) {
+                *dropped += 1;
+                return Some(filter.name());
+            }
+        }
+        None
+    }
+
+    pub fn prune(&mut self, now: Timestamp) {
+        for (filter, _) in &mut self.filters {
+            filter.prune(now);
+        }
+    }
+
+    /// Packets dropped by each filter since the start.
+    pub fn dropped(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.filters
+            .iter()
+            .map(|(filter, dropped)| (filter.name(), *dropped))
+    }
+}
+
+fn parse_prefixes(prefixes: &[String]) -> Vec<IpNetwork> {
+    prefixes
+        .iter()
+        .filter_map(|prefix| match IpNetwork::from_str(prefix) {
+            Ok(network) => Some(network),
+            Err(e) => {
+                warn!("Ignoring invalid prefix {}: {}", prefix, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Direction;
+
+    fn packet(remote: [u8; 4], dst_port: u16, millis: u64) -> ParsedPacket {
+        let timestamp = Timestamp::from_millis(millis);
+        ParsedPacket {
+            src_ip: remote.into(),
+            dst_ip: [10, 0, 0, 1].into(),
+            ..ParsedPacket::test_udp(40000, dst_port, 100, timestamp, Direction::Incoming)
+        }
+    }
+
+    #[test]
+    fn test_chain_counts_drops_per_filter() {
+        let mut chain = FilterChain::new();
+        chain.push(LoopbackFilter);
+        chain.push(MulticastFilter);
+        chain.push(PortFilter::new([50051]));
+        let rules = SubnetRules::parse(&["10.0.0.0/24".into()], &["10.0.0.128/25".into()]);
+        let (_tx, rx) = watch::channel(rules.unwrap());
+        chain.push(SubnetFilter::new(Vec::new(), rx));
+
+        assert!(chain.accepts(&packet([10, 0, 0, 2], 5201, 0)));
+        assert!(!chain.accepts(&packet([127, 0, 0, 1], 5201, 0)));
+        assert!(!chain.accepts(&packet([224, 0, 0, 251], 5353, 0)));
+        assert!(!chain.accepts(&packet([10, 0, 0, 2], 50051, 0)));
+        assert!(!chain.accepts(&packet([10, 0, 0, 200], 5201, 0)));
+        assert!(!chain.accepts(&packet([10, 0, 1, 2], 5201, 0)));
+        let dropped: Vec<_> = chain.dropped().collect();
+        assert_eq!(
+            dropped,
+            [
+                ("loopback", 1),
+                ("multicast", 1),
+                ("ports", 1),
+                ("subnets", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subnet_rules_at_runtime() {
+        let (tx, rx) = watch::channel(SubnetRules::default());
+        let mut filter = SubnetFilter::new(vec!["10.1.0.0/16".parse().unwrap()], rx);
+        assert!(!filter.drops(&packet([10, 0, 0, 2], 5201, 0)));
+        assert!(filter.drops(&packet([10, 1, 0, 2], 5201, 0)));
+
+        // Only track 10.0.1.0/24 from now on
+        tx.send_replace(SubnetRules::parse(&["10.0.1.0/24".into()], &[]).unwrap());
+        assert!(filter.drops(&packet([10, 0, 0, 2], 5201, 0)));
+        assert!(!filter.drops(&packet([10, 0, 1, 2], 5201, 0)));
+        // The config exclusions stay
+        assert!(filter.drops(&packet([10, 1, 0, 2], 5201, 0)));
+
+        assert!(SubnetRules::parse(&["10.0.1.0/33".into()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_subnet_bpf() {
+        assert_eq!(SubnetRules::default().bpf(), None);
+        let allow = ["10.0.1.7/24".into(), "fd00::/8".into()];
+        let rules = SubnetRules::parse(&allow, &["10.0.1.128/25".into()]).unwrap();
+        assert_eq!(
+            rules.bpf().unwrap(),
+            "not (net 10.0.1.128/25) and (arp or icmp6 or net 10.0.1.0/24 or net fd00::/8)"
+        );
+    }
+
+    #[test]
+    fn test_rate_filter() {
+        let mut filter = RateFilter::new(2);
+        assert!(!filter.drops(&packet([10, 0, 0, 2], 5201, 0)));
+        assert!(!filter.drops(&packet([10, 0, 0, 2], 5201, 100)));
+        assert!(filter.drops(&packet([10, 0, 0, 2], 5201, 200)));
+        // Other remotes have their own budget
+        assert!(!filter.drops(&packet([10, 0, 0, 3], 5201, 200)));
+        // Next window
+        assert!(!filter.drops(&packet([10, 0, 0, 2], 5201, 1000)));
+
+        filter.prune(Timestamp::from_millis(2000));
+        assert!(filter.remotes.is_empty());
+        assert!(!RateFilter::new(0).drops(&packet([10, 0, 0, 2], 5201, 0)));
+    }
+}
@@ -0,0 +1,112 @@
+//! Load shedding in the parser. When the capture channel fills up faster
+//! than the parser empties it, netlink polling and RTT messages are skipped
+//! and cleanup runs less often, until the channel drains again. Link states
+//! whose window overlapped that time are flagged as degraded.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::CONFIG;
+
+/// Cleanup runs this many times less often while shedding.
+const SHED_CLEANUP_FACTOR: u32 = 3;
+
+/// Cleanup interval while shedding.
+pub fn shed_cleanup_interval() -> Duration {
+    CONFIG.tracking.cleanup_interval.saturating_mul(SHED_CLEANUP_FACTOR)
+}
+
+/// Watches the depth of a channel, with hysteresis between the watermarks.
+#[derive(Debug)]
+pub struct LoadMonitor {
+    /// Depth at which shedding starts.
+    high: usize,
+    /// Depth at which it stops.
+    low: usize,
+    /// When shedding started, None while not shedding.
+    since: Option<Instant>,
+}
+
+impl LoadMonitor {
+    /// Watermarks are shares of `capacity`, `low` is kept below `high`.
+    pub fn new(capacity: usize, high: f64, low: f64) -> Self {
+        let high = ((capacity as f64 * high.clamp(0.0, 1.0)) as usize).clamp(1, capacity.max(1));
+        let low = ((capacity as f64 * low.clamp(0.0, 1.0)) as usize).min(high - 1);
+        LoadMonitor {
+            high,
+            low,
+            since: None,
+        }
+    }
+
+    pub fn from_config(capacity: usize) -> Self {
+        let client = &CONFIG.client;
+        LoadMonitor::new(
+            capacity,
+            client.shed_high_watermark,
+            client.shed_low_watermark,
+        )
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// Records the channel depth. Returns the new state if shedding started
+    /// or stopped.
+    pub fn update(&mut self, depth: usize) -> Option<bool> {
+        match self.since {
+            None if depth >= self.high => {
+                warn!(
+                    "Parser falling behind with {} events queued, shedding load",
+                    depth
+                );
+                self.since = Some(Instant::now());
+                Some(true)
+            }
+            Some(since) if depth <= self.low => {
+                info!(
+                    "Parser caught up after shedding load for {:?}",
+                    since.elapsed()
+                );
+                self.since = None;
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+
+    /// Cleanup interval for the current state.
+    pub fn cleanup_interval(&self) -> Duration {
+        if self.is_shedding() {
+            shed_cleanup_interval()
+        } else {
+            CONFIG.tracking.cleanup_interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watermarks() {
+        let mut monitor = LoadMonitor::new(1000, 0.8, 0.5);
+        assert_eq!(monitor.update(799), None);
+        assert_eq!(monitor.update(800), Some(true));
+        assert_eq!(monitor.cleanup_interval(), shed_cleanup_interval());
+        // Stays on between the watermarks
+        assert_eq!(monitor.update(900), None);
+        assert_eq!(monitor.update(501), None);
+        assert!(monitor.is_shedding());
+        assert_eq!(monitor.update(500), Some(false));
+        assert_eq!(monitor.update(700), None);
+        assert_eq!(monitor.update(1000), Some(true));
+
+        // Inverted watermarks still leave a gap
+        let monitor = LoadMonitor::new(10, 0.5, 0.9);
+        assert_eq!((monitor.high, monitor.low), (5, 4));
+    }
+}
@@ -1,5 +1,14 @@
 pub mod capture;
+pub mod coverage;
+pub mod dump;
+pub mod filter;
+pub mod load;
 pub mod packet;
 pub mod parser;
 pub mod procfs_reader;
+pub mod reorder;
+pub mod ring;
+pub mod routes;
+pub mod simulation;
 pub mod tracking;
+pub mod tstamp;
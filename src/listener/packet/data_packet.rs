@@ -1,31 +1,60 @@
 // Used to store packets which are acked, or sent (udp) or received (tcp) packets.
 
 use std::ops::{Deref, DerefMut};
+use std::time::Duration;
 
+use crate::Timestamp;
+
+
+/// Optional duration stored as whole microseconds, `u32::MAX` meaning none.
+///
+/// Covers gaps and RTTs up to about 71 minutes in 4 bytes, where
+/// `Option<Duration>` takes 16.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Micros(u32);
+
+impl Micros {
+    const NONE: Micros = Micros(u32::MAX);
+
+    fn new(duration: Option<Duration>) -> Self {
+        match duration {
+            Some(d) => Micros(d.as_micros().min(u32::MAX as u128 - 1) as u32),
+            None => Micros::NONE,
+        }
+    }
+
+    fn get(self) -> Option<Duration> {
+        (self != Micros::NONE).then(|| Duration::from_micros(self.0 as u64))
+    }
+}
 
 /// Represents a data packet with timing and transmission metadata.
 ///
-/// Stores payload length, total packet length, timestamps for when the packet was sent
-/// and acknowledged, gaps between successive sends and acknowledgments, retransmission count,
-/// and round-trip time (RTT) if available.
+/// Stores payload length, total packet length, the time the packet was sent,
+/// gaps between successive sends and acknowledgments, retransmission count,
+/// and round-trip time (RTT) if available. Durations are kept as microseconds
+/// and the ack time is derived from the RTT, keeping the packet at 32 bytes.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct DataPacket {
     /// Length of the payload in bytes.
     pub payload_len: u16,
     /// Total length of the packet in bytes. (headers + payload)
     pub total_length: u16,
+    /// Number of retransmissions for this packet.
+    pub retransmissions: u8,
+    /// Segments the packet stands for in the estimates, see
+    /// `ParsedPacket::segments`.
+    pub segments: u8,
     /// Timestamp when the packet was sent.
-    pub sent_time: std::time::SystemTime,
-    /// Timestamp when the packet was acknowledged.
-    pub ack_time: Option<std::time::SystemTime>,
+    pub sent_time: Timestamp,
+    /// Round-trip time (RTT) for this packet, if acknowledged.
+    rtt: Micros,
     /// Time gap between the last acknowledgment and the current packet.
-    pub gap_last_ack: Option<std::time::Duration>,
+    gap_last_ack: Micros,
     /// Time gap between the last sent packet and the current packet.
-    pub gap_last_sent: Option<std::time::Duration>,
-    /// Number of retransmissions for this packet.
-    pub retransmissions: u8,
-    /// Round-trip time (RTT) for this packet, if available.
-    pub rtt: Option<tokio::time::Duration>, // TODO: Change to u32 micros duration is 13 bytes
+    gap_last_sent: Micros,
+    /// Sent with far less in flight than the connection had managed before.
+    app_limited: bool,
 }
 
 /// Classification of a packet as either sent or received.
@@ -87,48 +116,83 @@ impl DataPacket {
     /// - `payload_len`: Length of the packet payload in bytes.
     /// - `total_length`: Total length of the packet (header + payload) in bytes.
     /// - `sent_time`: Timestamp when the packet was sent.
-    /// - `ack_time`: Optional timestamp when the packet was acknowledged.
     /// - `gap_last_ack`: Optional duration since the previous acknowledgment.
     /// - `gap_last_sent`: Optional duration since the last sent packet.
     /// - `retransmissions`: Number of retransmissions for this packet.
-    /// - `rtt`: Optional measured round-trip time.
+    /// - `rtt`: Optional measured round-trip time, which also sets the ack time.
     ///
     /// # Returns
     /// Constructed `DataPacket` instance.
     pub fn new(
         payload_len: u16,
         total_length: u16,
-        sent_time: std::time::SystemTime,
-        ack_time: Option<std::time::SystemTime>,
-        gap_last_ack: Option<std::time::Duration>,
-        gap_last_sent: Option<std::time::Duration>,
+        sent_time: Timestamp,
+        gap_last_ack: Option<Duration>,
+        gap_last_sent: Option<Duration>,
         retransmissions: u8,
-        rtt: Option<tokio::time::Duration>,
+        rtt: Option<Duration>,
     ) -> Self {
         DataPacket {
             payload_len,
             total_length,
-            sent_time,
-            ack_time,
-            gap_last_ack,
-            gap_last_sent,
             retransmissions,
-            rtt,
+            segments: 1,
+            sent_time,
+            rtt: Micros::new(rtt),
+            gap_last_ack: Micros::new(gap_last_ack),
+            gap_last_sent: Micros::new(gap_last_sent),
+            app_limited: false,
         }
     }
 
-    /// Returns an "empty" `DataPacket` with zeroed lengths and UNIX epoch timestamp.
+    /// Returns an "empty" `DataPacket` with zeroed lengths and timestamp.
     pub fn empty() -> Self {
-        DataPacket {
-            payload_len: 0,
-            total_length: 0,
-            sent_time: std::time::SystemTime::UNIX_EPOCH,
-            ack_time: None,
-            gap_last_ack: None,
-            gap_last_sent: None,
-            retransmissions: 0,
-            rtt: None,
-        }
+        DataPacket::new(0, 0, Timestamp::ZERO, None, None, 0, None)
+    }
+
+    /// Round-trip time, if the packet has been acknowledged.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.get()
+    }
+
+    /// Time the acknowledgment was seen, `sent_time + rtt`.
+    pub fn ack_time(&self) -> Option<Timestamp> {
+        self.rtt().map(|rtt| self.sent_time + rtt)
+    }
+
+    pub fn gap_last_ack(&self) -> Option<Duration> {
+        self.gap_last_ack.get()
+    }
+
+    pub fn gap_last_sent(&self) -> Option<Duration> {
+        self.gap_last_sent.get()
+    }
+
+    pub fn set_gap_last_ack(&mut self, gap: Option<Duration>) {
+        self.gap_last_ack = Micros::new(gap);
+    }
+
+    pub fn set_gap_last_sent(&mut self, gap: Option<Duration>) {
+        self.gap_last_sent = Micros::new(gap);
+    }
+
+    /// True if the sender was held back by the application, not the path.
+    ///
+    /// Either the packet was sent with little in flight, or after the sender
+    /// was idle for longer than the RTT.
+    pub fn app_limited(&self) -> bool {
+        self.app_limited
+            || matches!((self.gap_last_sent(), self.rtt()), (Some(gap), Some(rtt)) if gap > rtt)
+    }
+
+    pub fn set_app_limited(&mut self, app_limited: bool) {
+        self.app_limited = app_limited;
+    }
+
+    /// Marks the packet as acknowledged after `rtt`.
+    pub fn set_acked(&mut self, rtt: Duration, gap_last_ack: Option<Duration>) {
+        self.rtt = Micros::new(Some(rtt));
+        self.gap_last_ack = Micros::new(gap_last_ack);
     }
 
     /// Retrieves the last send and acknowledgment gaps (in seconds) along with the acknowledgment time.
@@ -139,8 +203,8 @@ impl DataPacket {
     ///   - `gout`: Time gap (s) since the last acknowledgment.
     ///   - `ack_time`: Timestamp of the acknowledgment.
     /// - `None` if any of these fields are unavailable.
-    pub fn get_gin_gout(&self) -> Option<(f64, f64, std::time::SystemTime)> {
-        match (self.gap_last_sent, self.gap_last_ack, self.ack_time) {
+    pub fn get_gin_gout(&self) -> Option<(f64, f64, Timestamp)> {
+        match (self.gap_last_sent(), self.gap_last_ack(), self.ack_time()) {
             (Some(gin), Some(gout), Some(ack_time)) => Some((
                 gin.as_secs_f64(),
                 gout.as_secs_f64(),
@@ -152,40 +216,26 @@ impl DataPacket {
 
     /// Constructs a `DataPacket` from a lower-level `ParsedPacket`.
     ///
-    /// Extracts the payload length and total length, sets the sent time,
-    /// and leaves timing and retransmission metadata unset, for later filling.
+    /// Extracts the payload length, total length and segments, sets the sent
+    /// time, and leaves timing and retransmission metadata unset, for later
+    /// filling.
     pub fn from_packet(packet: &crate::ParsedPacket) -> Self {
-        match packet.transport {
-            crate::TransportPacket::TCP { payload_len, .. } => DataPacket {
-                payload_len,
-                total_length: packet.total_length,
-                sent_time: packet.timestamp,
-                ack_time: None,
-                gap_last_ack: None,
-                gap_last_sent: None,
-                retransmissions: 0,
-                rtt: None,
-            },
-            crate::TransportPacket::UDP { payload_len, .. } => DataPacket {
+        let payload_len = match packet.transport {
+            crate::TransportPacket::TCP { payload_len, .. }
+            | crate::TransportPacket::UDP { payload_len, .. } => payload_len,
+            _ => 0,
+        };
+        DataPacket {
+            segments: packet.segments,
+            ..DataPacket::new(
                 payload_len,
-                total_length: packet.total_length,
-                sent_time: packet.timestamp,
-                ack_time: None,
-                gap_last_ack: None,
-                gap_last_sent: None,
-                retransmissions: 0,
-                rtt: None,
-            },
-            _ => DataPacket {
-                payload_len: 0,
-                total_length: packet.total_length,
-                sent_time: packet.timestamp,
-                ack_time: None,
-                gap_last_ack: None,
-                gap_last_sent: None,
-                retransmissions: 0,
-                rtt: None,
-            },
+                packet.total_length,
+                packet.timestamp,
+                None,
+                None,
+                0,
+                None,
+            )
         }
     }
 
@@ -199,18 +249,17 @@ impl DataPacket {
 mod tests {
     use super::*;
     use crate::Direction;
-    use std::time::{SystemTime, Duration as StdDuration};
+    use std::time::Duration as StdDuration;
     use tokio::time::Duration as TokioDuration;
     use std::cmp::Ordering;
 
     #[test]
     fn test_new_and_empty() {
-        let now = SystemTime::now();
+        let now = Timestamp::now();
         let dp = DataPacket::new(
             10,
             20,
             now,
-            Some(now),
             Some(StdDuration::new(1, 0)),
             Some(StdDuration::new(2, 0)),
             3,
@@ -219,42 +268,67 @@ mod tests {
         assert_eq!(dp.payload_len, 10);
         assert_eq!(dp.total_length, 20);
         assert_eq!(dp.sent_time, now);
-        assert_eq!(dp.ack_time, Some(now));
-        assert_eq!(dp.gap_last_ack, Some(StdDuration::new(1, 0)));
-        assert_eq!(dp.gap_last_sent, Some(StdDuration::new(2, 0)));
+        assert_eq!(dp.ack_time(), Some(now + StdDuration::from_secs(5)));
+        assert_eq!(dp.gap_last_ack(), Some(StdDuration::new(1, 0)));
+        assert_eq!(dp.gap_last_sent(), Some(StdDuration::new(2, 0)));
         assert_eq!(dp.retransmissions, 3);
-        assert_eq!(dp.rtt, Some(TokioDuration::from_secs(5)));
+        assert_eq!(dp.rtt(), Some(TokioDuration::from_secs(5)));
 
         let empty = DataPacket::empty();
         assert_eq!(empty.payload_len, 0);
         assert_eq!(empty.total_length, 0);
-        assert_eq!(empty.sent_time, SystemTime::UNIX_EPOCH);
-        assert_eq!(empty.ack_time, None);
-        assert_eq!(empty.gap_last_ack, None);
-        assert_eq!(empty.gap_last_sent, None);
+        assert_eq!(empty.sent_time, Timestamp::ZERO);
+        assert_eq!(empty.ack_time(), None);
+        assert_eq!(empty.gap_last_ack(), None);
+        assert_eq!(empty.gap_last_sent(), None);
         assert_eq!(empty.retransmissions, 0);
-        assert_eq!(empty.rtt, None);
+        assert_eq!(empty.rtt(), None);
+    }
+
+    #[test]
+    fn test_compact_layout() {
+        assert!(std::mem::size_of::<DataPacket>() <= 32);
+
+        // Sub-microsecond precision is dropped, long durations saturate
+        let mut dp = DataPacket::empty();
+        dp.set_gap_last_sent(Some(StdDuration::from_nanos(1_500)));
+        assert_eq!(dp.gap_last_sent(), Some(StdDuration::from_micros(1)));
+        dp.set_acked(StdDuration::from_secs(u64::MAX / 2), None);
+        assert_eq!(dp.rtt(), Some(StdDuration::from_micros(u32::MAX as u64 - 1)));
+    }
+
+    #[test]
+    fn test_app_limited() {
+        let mut dp = DataPacket::empty();
+        assert!(!dp.app_limited());
+        // Idle for longer than the RTT before sending
+        dp.set_gap_last_sent(Some(StdDuration::from_millis(30)));
+        dp.set_acked(StdDuration::from_millis(20), None);
+        assert!(dp.app_limited());
+        dp.set_gap_last_sent(Some(StdDuration::from_millis(1)));
+        assert!(!dp.app_limited());
+        dp.set_app_limited(true);
+        assert!(dp.app_limited());
     }
 
     #[test]
     fn test_get_gin_gout_some_and_none() {
-        let now = SystemTime::now();
+        let now = Timestamp::now();
         let dp_some = DataPacket::new(
             0,
             0,
             now,
-            Some(now),
             Some(StdDuration::new(3, 500_000_000)),
             Some(StdDuration::new(1, 250_000_000)),
             0,
-            None,
+            Some(StdDuration::from_millis(20)),
         );
         let result = dp_some.get_gin_gout();
         assert!(result.is_some());
         let (gin, gout, ack_time) = result.unwrap();
         assert!((gin - 1.25).abs() < f64::EPSILON);
         assert!((gout - 3.5).abs() < f64::EPSILON);
-        assert_eq!(ack_time, now);
+        assert_eq!(ack_time, now + StdDuration::from_millis(20));
 
         let dp_none = DataPacket::empty();
         assert!(dp_none.get_gin_gout().is_none());
@@ -262,10 +336,10 @@ mod tests {
 
     #[test]
     fn test_cmp_by_sent_time() {
-        let t1 = SystemTime::UNIX_EPOCH + StdDuration::new(100, 0);
-        let t2 = SystemTime::UNIX_EPOCH + StdDuration::new(200, 0);
-        let dp1 = DataPacket::new(0, 0, t1, None, None, None, 0, None);
-        let dp2 = DataPacket::new(0, 0, t2, None, None, None, 0, None);
+        let t1 = Timestamp::ZERO + StdDuration::new(100, 0);
+        let t2 = Timestamp::ZERO + StdDuration::new(200, 0);
+        let dp1 = DataPacket::new(0, 0, t1, None, None, 0, None);
+        let dp2 = DataPacket::new(0, 0, t2, None, None, 0, None);
         assert_eq!(dp1.cmp_by_sent_time(&dp2), Ordering::Less);
         assert_eq!(dp2.cmp_by_sent_time(&dp1), Ordering::Greater);
         assert_eq!(dp1.cmp_by_sent_time(&dp1), Ordering::Equal);
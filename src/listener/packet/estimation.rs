@@ -1,7 +1,8 @@
-use std::time::SystemTime;
+use log::debug;
 
-// Minimum payload size threshold: MTU (1500 bytes) minus maximum header sizes (IP+Ethernet+TCP).
-const MIN_PAYLOAD_SIZE: f64 = 1362.0;
+use super::packet_registry::RegressionType;
+use crate::config::Filter;
+use crate::Timestamp;
 
 /// A structure holding a pair of gap measurements and the associated packet length.
 #[derive(Debug, Clone)]
@@ -13,9 +14,13 @@ pub struct GinGout {
     /// Packet payload length (bytes).
     pub len: f64,
     /// Number of packets acknowledged by this ack. (cumulative ack number)
+    /// Split super-frames count as their segments.
     pub num_acked: u8,
     /// Timestamp when the ack was observed.
-    pub timestamp: SystemTime,
+    pub timestamp: Timestamp,
+    /// Some of the acknowledged packets were sent while the application,
+    /// not the path, limited the sender. Left out of the estimates.
+    pub app_limited: bool,
 }
 
 impl GinGout {
@@ -25,83 +30,176 @@ impl GinGout {
     /// - `x = len / gin` (bytes per input gap)
     /// - `y = gout / gin` (output-to-input gap ratio)
     /// - `timestamp`: original timestamp
-    pub fn get_dp(&self) -> (f64, f64, SystemTime) {
+    pub fn get_dp(&self) -> (f64, f64, Timestamp) {
         (self.len / self.gin, self.gout / self.gin, self.timestamp)
     }
+
+    /// False if a gap or the length is NaN or infinite.
+    pub fn is_finite(&self) -> bool {
+        self.gin.is_finite() && self.gout.is_finite() && self.len.is_finite()
+    }
+
+    /// True if the point is usable on its own: sent while the path limited
+    /// the sender, finite, with a positive input gap, at least `min_payload`
+    /// and under `phy_cap` (bytes/sec) both ways.
+    pub fn within_bounds(&self, min_payload: f64, phy_cap: f64) -> bool {
+        !self.app_limited
+            && self.is_finite()
+            && self.gin > 0.0
+            && self.len >= min_payload
+            && self.len / self.gin < phy_cap
+            && self.len / self.gout < phy_cap
+    }
+}
+
+/// Points left after each stage of `PABWESender::filter_gin_gacks`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FilterStats {
+    /// Points before filtering.
+    pub input: usize,
+    /// Points dropped as application limited.
+    pub app_limited_dropped: usize,
+    /// Left after the payload size and capacity bounds.
+    pub bounds: usize,
+    /// Left after the input gap quantile rule.
+    pub quantile: usize,
+    /// Left after MAD outlier rejection.
+    pub mad: usize,
+}
+
+/// An available bandwidth estimate and how much to trust it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AbwEstimate {
+    /// Estimated available bandwidth (bytes/sec).
+    pub abw: f64,
+    /// Standard error of `abw` (bytes/sec), None with fewer than three points.
+    pub std_err: Option<f64>,
+    /// Number of data points used in the regression.
+    pub samples: usize,
 }
 
 /// Sender that accumulates `GinGout` data points for passive bandwidth estimation.
 #[derive(Debug)]
 pub struct PABWESender {
     pub dps: Vec<GinGout>,
+    /// Filter statistics from the last estimate.
+    pub last_filter: FilterStats,
 }
 
 impl PABWESender {
     pub fn new() -> Self {
-        PABWESender { dps: Vec::new() }
+        PABWESender {
+            dps: Vec::new(),
+            last_filter: FilterStats::default(),
+        }
     }
 
-    /// Appends a new data point to the collection.
+    /// Appends a new data point to the collection, unless it is not finite.
     pub fn push(&mut self, dp: GinGout) {
+        if !dp.is_finite() {
+            debug!("Dropped gap point that is not finite: {:?}", dp);
+            return;
+        }
         self.dps.push(dp);
     }
 
-    /// Filters data points based on minimum payload, nonzero gaps, and link capacity.
+    /// Filters data points with the stages in the `filter` config section.
     ///
-    /// Steps:
-    /// 1. Discard any `dp` where `gin == 0`, `len < MIN_PAYLOAD_SIZE`, or ratio constraints exceed physical capacity.
-    /// 2. Sort remaining by `gin` ascending.
-    /// 3. Compute average of the smallest 10% of `gin` and corresponding `gout`.
-    /// 4. Retain only points with `gin < average_gout`.
-    ///
-    /// # Returns
-    /// A vector of `GinGout` that passed all filters.
-    pub fn filter_gin_gacks(&mut self) -> Vec<GinGout> {
-        // Convert bit to byte.
-        let phy_cap = crate::CONFIG.client.link_phy_cap as f64 / 8.0;
-
-        let mut filtered: Vec<GinGout> = self
-            .dps
-            .iter()
-            .filter(|dp| {
-                dp.gin > 0.0
-                    && dp.len >= MIN_PAYLOAD_SIZE
-                    && dp.len / dp.gin < phy_cap
-                    && dp.len / dp.gout < phy_cap
-            })
-            .cloned()
-            .collect();
-
-        filtered.sort_by(|gin1, gin2| gin1.gin.partial_cmp(&gin2.gin).unwrap());
-
-        let n = (filtered.len() as f64 * 0.1).ceil() as usize;
-        let _gmin_in = filtered.iter().take(n).map(|dp| dp.gin).sum::<f64>() / n as f64;
-        let gmin_out = filtered.iter().take(n).map(|dp| dp.gout).sum::<f64>() / n as f64;
+    /// Returns the points that passed, and how many were left after each stage.
+    pub fn filter_gin_gacks(&mut self) -> (Vec<GinGout>, FilterStats) {
+        Self::filter(&self.dps, &crate::CONFIG.filter, config_phy_cap())
+    }
 
-        let g_max_in = gmin_out;
+    /// Steps:
+    /// 0. Discard application limited points, the sender did not fill the path.
+    /// 1. Discard any `dp` that is not finite, where `gin == 0`, `len < min_payload`, or
+    ///    ratio constraints exceed physical capacity (`phy_cap`, bytes/sec).
+    /// 2. Sort remaining by `gin` ascending, average the `gout` of the smallest
+    ///    `gin_quantile` of them, and retain only points with `gin` below that average.
+    /// 3. If `mad_threshold` is set, drop points whose `gout / gin` is further than
+    ///    `mad_threshold` scaled MADs from the median.
+    fn filter(dps: &[GinGout], config: &Filter, phy_cap: f64) -> (Vec<GinGout>, FilterStats) {
+        let mut stats = FilterStats {
+            input: dps.len(),
+            ..Default::default()
+        };
 
-        let filtered: Vec<GinGout> = filtered
+        stats.app_limited_dropped = dps.iter().filter(|dp| dp.app_limited).count();
+        let mut filtered: Vec<GinGout> = dps
             .iter()
-            .filter(|dp| dp.gin < g_max_in)
+            .filter(|dp| dp.within_bounds(config.min_payload, phy_cap))
             .cloned()
             .collect();
+        stats.bounds = filtered.len();
+
+        if config.gin_quantile > 0.0 && !filtered.is_empty() {
+            filtered.sort_by(|gin1, gin2| gin1.gin.total_cmp(&gin2.gin));
+            let n = ((filtered.len() as f64 * config.gin_quantile).ceil() as usize)
+                .clamp(1, filtered.len());
+            let g_max_in = filtered.iter().take(n).map(|dp| dp.gout).sum::<f64>() / n as f64;
+            filtered.retain(|dp| dp.gin < g_max_in);
+        }
+        stats.quantile = filtered.len();
+
+        if config.mad_threshold > 0.0 && !filtered.is_empty() {
+            let ratios: Vec<f64> = filtered.iter().map(|dp| dp.gout / dp.gin).collect();
+            let center = median(ratios.clone());
+            let mad = median(ratios.iter().map(|r| (r - center).abs()).collect());
+            // Scaled to match the standard deviation for normal data
+            let scale = 1.4826 * mad;
+            if scale > f64::EPSILON {
+                let mut ratios = ratios.into_iter();
+                filtered.retain(|_| {
+                    let r = ratios.next().unwrap();
+                    (r - center).abs() / scale <= config.mad_threshold
+                });
+            }
+        }
+        stats.mad = filtered.len();
+
+        (filtered, stats)
+    }
 
-        return filtered;
+    /// Estimates available bandwidth with the given regression, filter and
+    /// link capacity (bytes/sec) instead of the ones in the config.
+    pub fn estimate_abw(
+        &mut self,
+        regression_type: RegressionType,
+        filter: &Filter,
+        phy_cap: f64,
+    ) -> (Option<AbwEstimate>, Vec<GinGout>) {
+        match regression_type {
+            RegressionType::RLS => self.rls_abw(filter, phy_cap),
+            RegressionType::Simple => self.ols_abw(filter, phy_cap),
+        }
     }
 
     /// Estimates available bandwidth via ordinary least squares regression.
     ///
-    /// Returns `(Some(bw), used_points)` if estimation succeeded and bandwidth in bytes/sec;
-    /// otherwise `(None, used_points)`.
-    pub fn passive_pgm_abw(&mut self) -> (Option<f64>, Vec<GinGout>) {
+    /// Returns `(Some(estimate), used_points)` if estimation succeeded, bandwidth in
+    /// bytes/sec; otherwise `(None, used_points)`.
+    pub fn passive_pgm_abw(&mut self) -> (Option<AbwEstimate>, Vec<GinGout>) {
+        self.ols_abw(&crate::CONFIG.filter, config_phy_cap())
+    }
+
+    /// Estimates available bandwidth using robust linear regression (IRLS with Huber weighting).
+    pub fn passive_pgm_abw_rls(&mut self) -> (Option<AbwEstimate>, Vec<GinGout>) {
+        self.rls_abw(&crate::CONFIG.filter, config_phy_cap())
+    }
+
+    fn ols_abw(&mut self, filter: &Filter, phy_cap: f64) -> (Option<AbwEstimate>, Vec<GinGout>) {
         // Ensure we have some data points.
         if self.dps.is_empty() {
             return (None, Vec::new());
         }
 
-        let dps = self.filter_gin_gacks();
+        let (dps, stats) = Self::filter(&self.dps, filter, phy_cap);
+        debug!("Gap filter: {:?}", stats);
+        self.last_filter = stats;
 
         let (mut sum_x, mut sum_y, mut sum_xy, mut sum_x2, mut count) = (0.0, 0.0, 0.0, 0.0, 0);
+        let mut xs = Vec::with_capacity(dps.len());
+        let mut ys = Vec::with_capacity(dps.len());
 
         for dp in &dps {
             let x = dp.len / dp.gin;
@@ -111,6 +209,8 @@ impl PABWESender {
             sum_xy += x * y;
             sum_x2 += x * x;
             count += 1;
+            xs.push(x);
+            ys.push(y);
         }
 
         if count == 0 {
@@ -128,20 +228,21 @@ impl PABWESender {
 
         if a.abs() > f64::EPSILON {
             let res = (1.0 - b) / a;
-            if res > 0.0 && res < crate::CONFIG.client.link_phy_cap as f64 / 8.0 {
-                return (Some(res), dps);
+            if res > 0.0 && res < phy_cap {
+                return (Some(Self::make_estimate(res, &xs, &ys, a, b)), dps);
             }
         }
         (None, dps)
     }
 
-    /// Estimates available bandwidth using robust linear regression (IRLS with Huber weighting).
-    pub fn passive_pgm_abw_rls(&mut self) -> (Option<f64>, Vec<GinGout>) {
+    fn rls_abw(&mut self, filter: &Filter, phy_cap: f64) -> (Option<AbwEstimate>, Vec<GinGout>) {
         if self.dps.is_empty() {
             return (None, Vec::new());
         }
 
-        let dps = self.filter_gin_gacks();
+        let (dps, stats) = Self::filter(&self.dps, filter, phy_cap);
+        debug!("Gap filter: {:?}", stats);
+        self.last_filter = stats;
         let mut xs: Vec<f64> = Vec::new();
         let mut ys: Vec<f64> = Vec::new();
 
@@ -171,16 +272,48 @@ impl PABWESender {
 
         // Calculate the result as (1 - b) / a.
         let res = (1.0 - b) / a;
-        if res > 0.0 && res < crate::CONFIG.client.link_phy_cap as f64 / 8.0 {
-            (Some(res), dps)
+        if res > 0.0 && res < phy_cap {
+            (Some(Self::make_estimate(res, &xs, &ys, a, b)), dps)
         } else {
             (None, dps)
         }
     }
 
+    fn make_estimate(abw: f64, xs: &[f64], ys: &[f64], a: f64, b: f64) -> AbwEstimate {
+        AbwEstimate {
+            abw,
+            std_err: Self::abw_std_err(xs, ys, a, b),
+            samples: xs.len(),
+        }
+    }
+
+    /// Standard error of `(1 - b) / a` for the line `y = a * x + b`, from the
+    /// residuals of the fit and the delta method.
+    ///
+    /// Returns `None` with fewer than three points or no spread in `x`.
+    fn abw_std_err(xs: &[f64], ys: &[f64], a: f64, b: f64) -> Option<f64> {
+        let n = xs.len();
+        if n < 3 {
+            return None;
+        }
+        let nf = n as f64;
+        let mean_x = xs.iter().sum::<f64>() / nf;
+        let sxx: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+        if sxx < f64::EPSILON {
+            return None;
+        }
+        let ssr: f64 = xs
+            .iter()
+            .zip(ys)
+            .map(|(x, y)| (y - (a * x + b)).powi(2))
+            .sum();
+        Some(delta_std_err(nf, mean_x, sxx, ssr, a, b))
+    }
+
     /// Performs IRLS-based robust least squares with Huber weights.
     ///
-    /// Returns `Some((slope, intercept))` or `None` on failure.
+    /// Returns `Some((slope, intercept))` or `None` on failure, also if the fit is
+    /// not finite.
     fn robust_least_squares(x: &[f64], y: &[f64]) -> Option<(f64, f64)> {
         let n = x.len();
         if n == 0 {
@@ -224,19 +357,14 @@ impl PABWESender {
             b = new_b;
 
             // Compute absolute residuals.
-            let mut residuals: Vec<f64> = x
+            let residuals: Vec<f64> = x
                 .iter()
                 .zip(y.iter())
                 .map(|(xi, yi)| (yi - (a * xi + b)).abs())
                 .collect();
 
             // Compute the median of the residuals.
-            residuals.sort_by(|r1, r2| r1.partial_cmp(r2).unwrap());
-            let median = if n % 2 == 1 {
-                residuals[n / 2]
-            } else {
-                (residuals[n / 2 - 1] + residuals[n / 2]) / 2.0
-            };
+            let median = median(residuals);
             // Set Huber threshold.
             let mut delta = 1.345 * median;
             if delta < tol {
@@ -249,24 +377,67 @@ impl PABWESender {
                 weights[i] = if res <= delta { 1.0 } else { delta / res };
             }
         }
-        Some((a, b))
+        (a.is_finite() && b.is_finite()).then_some((a, b))
+    }
+}
+
+/// Standard error of `(1 - b) / a` by the delta method, from the number of
+/// points `n`, their mean `x`, the spread `sxx` of `x` and the sum of squared
+/// residuals `ssr` of the fit.
+pub(super) fn delta_std_err(n: f64, mean_x: f64, sxx: f64, ssr: f64, a: f64, b: f64) -> f64 {
+    let s2 = ssr / (n - 2.0);
+    let var_a = s2 / sxx;
+    let var_b = s2 * (1.0 / n + mean_x * mean_x / sxx);
+    let cov_ab = -mean_x * s2 / sxx;
+
+    // Partial derivatives of (1 - b) / a
+    let d_a = -(1.0 - b) / (a * a);
+    let d_b = -1.0 / a;
+    let var = d_a * d_a * var_a + d_b * d_b * var_b + 2.0 * d_a * d_b * cov_ab;
+    var.max(0.0).sqrt()
+}
+
+/// `client.link_phy_cap` in bytes/sec.
+pub(super) fn config_phy_cap() -> f64 {
+    crate::CONFIG.client.link_phy_cap as f64 / 8.0
+}
+
+/// Median of `values`, which must not be empty. NaN sorts last.
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::SystemTime;
+
+    fn dp(gin: f64, gout: f64, len: f64) -> GinGout {
+        GinGout {
+            gin,
+            gout,
+            len,
+            num_acked: 1,
+            timestamp: Timestamp::ZERO,
+            app_limited: false,
+        }
+    }
 
     #[test]
     fn test_get_dp() {
-        let t = SystemTime::now();
+        let t = Timestamp::now();
         let gg = GinGout {
             gin: 2.0,
             gout: 4.0,
             len: 1000.0,
             num_acked: 1,
             timestamp: t,
+            app_limited: false,
         };
         let (x, y, ts) = gg.get_dp();
         assert_eq!(x, 500.0);
@@ -277,8 +448,9 @@ mod tests {
     #[test]
     fn test_filter_empty() {
         let mut s = PABWESender::new();
-        let filtered = s.filter_gin_gacks();
+        let (filtered, stats) = s.filter_gin_gacks();
         assert!(filtered.is_empty());
+        assert_eq!(stats, FilterStats::default());
     }
 
     #[test]
@@ -289,12 +461,55 @@ mod tests {
             gout: 1.0,
             len: 100.0,
             num_acked: 1,
-            timestamp: SystemTime::now(),
+            timestamp: Timestamp::now(),
+            app_limited: false,
         });
-        let filtered = s.filter_gin_gacks();
+        let (filtered, stats) = s.filter_gin_gacks();
         assert!(
             filtered.is_empty(),
-            "Packets below MIN_PAYLOAD_SIZE should be dropped"
+            "Packets below the minimum payload should be dropped"
+        );
+        assert_eq!((stats.input, stats.bounds), (1, 0));
+    }
+
+    #[test]
+    fn test_filter_stages() {
+        // One point with a far larger output gap than the rest
+        let mut dps: Vec<GinGout> =
+            (0..9).map(|i| dp(0.001 + i as f64 * 1e-5, 0.002, 1448.0)).collect();
+        dps.push(dp(0.0015, 0.02, 1448.0));
+        let config = Filter {
+            min_payload: 1362.0,
+            gin_quantile: 0.1,
+            mad_threshold: 0.0,
+        };
+
+        let (filtered, stats) = PABWESender::filter(&dps, &config, f64::MAX);
+        assert_eq!(filtered.len(), 10);
+        assert_eq!((stats.bounds, stats.quantile, stats.mad), (10, 10, 10));
+
+        let mut limited = dps.clone();
+        limited[0].app_limited = true;
+        let (filtered, stats) = PABWESender::filter(&limited, &config, f64::MAX);
+        assert_eq!((stats.input, stats.app_limited_dropped, filtered.len()), (10, 1, 9));
+
+        let config = Filter {
+            mad_threshold: 3.0,
+            ..config
+        };
+        let (filtered, stats) = PABWESender::filter(&dps, &config, f64::MAX);
+        assert_eq!(stats.mad, 9);
+        assert!(filtered.iter().all(|dp| dp.gout < 0.01));
+
+        // Every point filtered out by the bounds
+        let (filtered, stats) = PABWESender::filter(&dps, &config, 1.0);
+        assert!(filtered.is_empty());
+        assert_eq!(
+            stats,
+            FilterStats {
+                input: 10,
+                ..Default::default()
+            }
         );
     }
 
@@ -310,6 +525,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_abw_std_err() {
+        let xs = [1.0, 2.0, 3.0, 4.0];
+        // Too few points
+        assert_eq!(PABWESender::abw_std_err(&xs[..2], &[0.5, 1.0], 0.5, 0.0), None);
+
+        // A perfect fit leaves no uncertainty
+        let exact = [0.5, 1.0, 1.5, 2.0];
+        let err = PABWESender::abw_std_err(&xs, &exact, 0.5, 0.0).unwrap();
+        assert!(err.abs() < 1e-9);
+
+        let noisy = [0.6, 0.9, 1.6, 1.9];
+        let err = PABWESender::abw_std_err(&xs, &noisy, 0.5, 0.0).unwrap();
+        assert!(err > 0.0);
+    }
+
+    #[test]
+    fn test_push_drops_non_finite() {
+        let mut s = PABWESender::new();
+        s.push(dp(f64::NAN, 0.002, 1448.0));
+        s.push(dp(0.001, f64::INFINITY, 1448.0));
+        s.push(dp(0.001, 0.002, f64::NEG_INFINITY));
+        s.push(dp(0.001, 0.002, 1448.0));
+        assert_eq!(s.dps.len(), 1);
+    }
+
+    #[test]
+    fn test_degenerate_points_do_not_panic() {
+        // Zero length gaps turn into NaN and infinite ratios upstream
+        let mut dps: Vec<GinGout> = (1..10).map(|i| dp(i as f64 * 1e-4, 0.002, 1448.0)).collect();
+        dps.extend([
+            dp(f64::NAN, 0.002, 1448.0),
+            dp(0.001, f64::NAN, 1448.0),
+            dp(f64::INFINITY, 0.002, 1448.0),
+            dp(0.001, f64::INFINITY, 1448.0),
+            dp(0.0, 0.0, 1448.0),
+            dp(0.001, 0.0, 1448.0),
+            dp(-0.001, 0.002, 1448.0),
+        ]);
+        let config = Filter {
+            min_payload: 1000.0,
+            gin_quantile: 0.5,
+            mad_threshold: 3.0,
+        };
+
+        let (filtered, stats) = PABWESender::filter(&dps, &config, f64::MAX);
+        assert_eq!(stats.input, 16);
+        assert!(filtered.iter().all(GinGout::is_finite));
+
+        for regression in [RegressionType::RLS, RegressionType::Simple] {
+            let mut s = PABWESender::new();
+            s.dps = dps.clone();
+            let (estimate, _) = s.estimate_abw(regression, &config, f64::MAX);
+            if let Some(estimate) = estimate {
+                assert!(estimate.abw.is_finite());
+            }
+        }
+
+        assert_eq!(median(vec![2.0, f64::NAN, 1.0]), 2.0);
+        assert_eq!(PABWESender::robust_least_squares(&[1.0, 2.0], &[f64::NAN, 1.0]), None);
+    }
+
     #[test]
     fn test_empty_abw_methods() {
         let mut s = PABWESender::new();
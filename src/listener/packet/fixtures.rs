@@ -0,0 +1,162 @@
+//! Packets for tests, from the pcap files in `fixtures/pcap`. The files are
+//! written by `make_fixtures.py` there, which also describes each of them.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use pcap::PacketHeader;
+use pnet::util::MacAddr;
+
+use crate::listener::capture::{OwnedPacket, PCAPMeta};
+use crate::ParsedPacket;
+
+pub const TCP_TRANSFER: &[u8] = include_bytes!("../../../fixtures/pcap/tcp_transfer.pcap");
+pub const UDP_STREAM: &[u8] = include_bytes!("../../../fixtures/pcap/udp_stream.pcap");
+pub const VLAN: &[u8] = include_bytes!("../../../fixtures/pcap/vlan.pcap");
+pub const IPV6_EXTENSIONS: &[u8] = include_bytes!("../../../fixtures/pcap/ipv6_extensions.pcap");
+
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+/// Microsecond timestamps, little endian.
+const MAGIC: u32 = 0xA1B2_C3D4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// The host the fixtures were captured on.
+pub fn meta() -> PCAPMeta {
+    PCAPMeta {
+        mac_addr: MacAddr::new(2, 0, 0, 0, 0, 1),
+        ipv4: Ipv4Addr::new(192, 0, 2, 1),
+        ipv6: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+        name: "fixture".to_string(),
+        ..PCAPMeta::for_test()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The packets of a pcap file, panicking on anything but the little endian
+/// microsecond Ethernet files the fixtures are.
+pub fn load(pcap: &[u8]) -> Vec<OwnedPacket> {
+    assert_eq!(read_u32(pcap, 0), MAGIC, "Not a little endian microsecond pcap");
+    assert_eq!(read_u32(pcap, 20), LINKTYPE_ETHERNET, "Not an Ethernet pcap");
+    let mut packets = Vec::new();
+    let mut offset = GLOBAL_HEADER_LEN;
+    while offset < pcap.len() {
+        let caplen = read_u32(pcap, offset + 8);
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: read_u32(pcap, offset) as _,
+                tv_usec: read_u32(pcap, offset + 4) as _,
+            },
+            caplen,
+            len: read_u32(pcap, offset + 12),
+        };
+        let start = offset + RECORD_HEADER_LEN;
+        offset = start + caplen as usize;
+        packets.push(OwnedPacket {
+            header,
+            data: pcap[start..offset].into(),
+        });
+    }
+    packets
+}
+
+/// Every packet of a pcap file parsed as seen by `meta()`.
+pub fn parse(packets: &[OwnedPacket]) -> Vec<ParsedPacket> {
+    let meta = meta();
+    packets
+        .iter()
+        .map(|packet| ParsedPacket::from_packet(packet, &meta).expect("Fixture does not parse"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::{Direction, TransportPacket};
+
+    #[test]
+    fn test_tcp_transfer() {
+        let packets = load(TCP_TRANSFER);
+        let parsed = parse(&packets);
+        assert_eq!(parsed.len(), 18);
+        assert!(parsed[0].ignore(), "Handshake starts with a SYN");
+        assert!(parsed[1].is_pure_ack());
+        let data: Vec<_> = parsed
+            .iter()
+            .filter(|p| p.direction == Direction::Outgoing)
+            .filter_map(|p| match p.transport {
+                TransportPacket::TCP { payload_len, .. } if payload_len > 0 => Some(payload_len),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(data, vec![1000; 10]);
+        assert_eq!(parsed[0].get_src_dst_port(), Some((40000, 5001)));
+    }
+
+    #[test]
+    fn test_udp_stream() {
+        let parsed = parse(&load(UDP_STREAM));
+        assert_eq!(parsed.len(), 5);
+        for (i, packet) in parsed.iter().enumerate() {
+            assert_eq!(packet.direction, Direction::Outgoing);
+            assert_eq!(packet.ip_id, i as u16 + 1);
+            let expected = TransportPacket::UDP {
+                src_port: 40001,
+                dst_port: 5201,
+                payload_len: 500,
+            };
+            assert_eq!(packet.transport, expected);
+        }
+        let gap = parsed[1].timestamp.saturating_duration_since(parsed[0].timestamp);
+        assert_eq!(gap.as_micros(), 2_000);
+    }
+
+    #[test]
+    fn test_vlan() {
+        let parsed = parse(&load(VLAN));
+        let directions: Vec<_> = parsed.iter().map(|p| p.direction).collect();
+        assert_eq!(
+            directions,
+            vec![Direction::Outgoing, Direction::Incoming, Direction::Outgoing]
+        );
+        let payloads: Vec<_> = parsed
+            .iter()
+            .map(|p| match p.transport {
+                TransportPacket::UDP { payload_len, .. } => payload_len,
+                _ => panic!("Expected UDP past the VLAN tags"),
+            })
+            .collect();
+        assert_eq!(payloads, vec![40, 80, 40]);
+        assert_eq!(parsed[2].src_ip, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[test]
+    fn test_ipv6_extensions() {
+        let parsed = parse(&load(IPV6_EXTENSIONS));
+        let hop_by_hop = TransportPacket::UDP {
+            src_port: 40003,
+            dst_port: 5201,
+            payload_len: 100,
+        };
+        assert_eq!(parsed[0].transport, hop_by_hop);
+        match parsed[1].transport {
+            TransportPacket::TCP { flags, src_port, .. } => {
+                assert!(flags.is_syn());
+                assert_eq!(src_port, 40004);
+            }
+            _ => panic!("Expected TCP past the destination options"),
+        }
+        let fragment = parsed[2].fragment.expect("Expected a fragment");
+        assert!(fragment.is_first());
+        assert_eq!(parsed[2].direction, Direction::Incoming);
+        assert_eq!(parsed[2].get_src_dst_port(), Some((5201, 40003)));
+        match parsed[2].transport {
+            TransportPacket::UDP { payload_len, .. } => assert_eq!(payload_len, 1000),
+            _ => panic!("Expected UDP in the first fragment"),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! IP fragments.
+//!
+//! Only the first fragment of a datagram carries the transport header, the
+//! others would be read as garbage headers. They are kept out of the stream
+//! trackers, but get the ports of the first fragment from `FragmentTable`,
+//! so port based filters and probe tagging still see them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::{ParsedPacket, Timestamp};
+
+/// IPv6 fragment extension header.
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_FRAGMENT_LEN: usize = 8;
+/// IPv4 more fragments flag.
+const IPV4_MORE_FRAGMENTS: u8 = 0b001;
+/// First fragments are forgotten after this long, as the kernel gives up
+/// on reassembly after 30 seconds.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most datagrams tracked, first fragments beyond this are not.
+const MAX_DATAGRAMS: usize = 4096;
+
+/// Where a fragment belongs in its datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    /// Datagram identification, 16 bits on IPv4.
+    pub id: u32,
+    /// Offset of the fragment's data in bytes.
+    pub offset: u16,
+    /// More fragments follow.
+    pub more: bool,
+    /// Source and destination port, from the first fragment.
+    pub ports: Option<(u16, u16)>,
+}
+
+impl Fragment {
+    /// From the IPv4 flags, offset in 8 byte units and identification.
+    /// None if the packet is a whole datagram.
+    pub fn from_ipv4(flags: u8, offset: u16, id: u16) -> Option<Self> {
+        let more = flags & IPV4_MORE_FRAGMENTS != 0;
+        (more || offset > 0).then_some(Fragment {
+            id: id.into(),
+            offset: offset * 8,
+            more,
+            ports: None,
+        })
+    }
+
+    /// From an IPv6 fragment header. Returns the fragment and the protocol
+    /// of the data after it, None if `header` is too short.
+    pub fn from_ipv6(header: &[u8]) -> Option<(Self, u8)> {
+        if header.len() < IPV6_FRAGMENT_LEN {
+            return None;
+        }
+        let offset_flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let fragment = Fragment {
+            id,
+            offset: offset_flags & !0b111,
+            more: offset_flags & 1 != 0,
+            ports: None,
+        };
+        Some((fragment, header[0]))
+    }
+
+    /// Length of the IPv6 fragment header, if `next_header` is one.
+    pub fn ipv6_header_len(next_header: u8) -> Option<usize> {
+        (next_header == IPV6_FRAGMENT).then_some(IPV6_FRAGMENT_LEN)
+    }
+
+    /// The first fragment carries the transport header.
+    pub fn is_first(&self) -> bool {
+        self.offset == 0
+    }
+}
+
+/// Identifies a datagram, as in the reassembly rules.
+type DatagramKey = (IpAddr, IpAddr, u32, u8);
+
+/// Ports of the datagrams whose first fragment was seen lately.
+#[derive(Debug, Default)]
+pub struct FragmentTable {
+    datagrams: HashMap<DatagramKey, ((u16, u16), Timestamp)>,
+}
+
+impl FragmentTable {
+    pub fn new() -> Self {
+        FragmentTable::default()
+    }
+
+    /// Remembers the ports of a first fragment, and gives a later fragment
+    /// the ports of its first one if it was seen. Fragments that arrive
+    /// before the first one keep no ports.
+    pub fn attribute(&mut self, packet: &mut ParsedPacket) {
+        let Some(fragment) = packet.fragment else {
+            return;
+        };
+        let protocol = packet.transport.get_ip_proto().0;
+        let key = (packet.src_ip, packet.dst_ip, fragment.id, protocol);
+        if !fragment.is_first() {
+            let ports = self.datagrams.get(&key).map(|(ports, _)| *ports);
+            packet.fragment = Some(Fragment { ports, ..fragment });
+            return;
+        }
+        if let Some(ports) = packet.get_src_dst_port() {
+            if self.datagrams.len() < MAX_DATAGRAMS || self.datagrams.contains_key(&key) {
+                self.datagrams.insert(key, (ports, packet.timestamp));
+            }
+        }
+    }
+
+    /// Forgets the datagrams whose first fragment is older than
+    /// `FRAGMENT_TIMEOUT`.
+    pub fn prune(&mut self, now: Timestamp) {
+        self.datagrams
+            .retain(|_, (_, seen)| *seen + FRAGMENT_TIMEOUT > now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Direction, TransportPacket};
+
+    fn packet(transport: TransportPacket, fragment: Fragment) -> ParsedPacket {
+        let timestamp = Timestamp::from_millis(1_000_000);
+        ParsedPacket {
+            src_ip: [10, 0, 0, 2].into(),
+            dst_ip: [10, 0, 0, 1].into(),
+            total_length: 1500,
+            fragment: Some(fragment),
+            ..ParsedPacket::test_packet(transport, timestamp, Direction::Incoming)
+        }
+    }
+
+    #[test]
+    fn test_fragments_get_ports_of_first() {
+        assert_eq!(Fragment::from_ipv4(0b010, 0, 7), None);
+        let first = Fragment::from_ipv4(0b001, 0, 7).unwrap();
+        let last = Fragment::from_ipv4(0, 185, 7).unwrap();
+        assert!(first.is_first() && first.more);
+        assert_eq!((last.offset, last.more), (1480, false));
+
+        let (v6, next) = Fragment::from_ipv6(&[17, 0, 0x05, 0xc9, 0, 0, 0, 7]).unwrap();
+        assert_eq!((v6.offset, v6.more, v6.id, next), (1480, true, 7, 17));
+
+        let udp = TransportPacket::UDP {
+            src_port: 5201,
+            dst_port: 40000,
+            payload_len: 1472,
+        };
+        let rest = TransportPacket::OTHER { protocol: 17 };
+        let mut table = FragmentTable::new();
+        // Before the first one, no ports to give
+        let mut early = packet(rest.clone(), last);
+        table.attribute(&mut early);
+        assert_eq!(early.get_src_dst_port(), None);
+
+        table.attribute(&mut packet(udp, first));
+        let mut later = packet(rest.clone(), last);
+        table.attribute(&mut later);
+        assert_eq!(later.get_src_dst_port(), Some((5201, 40000)));
+        // Another datagram
+        let mut other = packet(rest, Fragment { id: 8, ..last });
+        table.attribute(&mut other);
+        assert_eq!(other.get_src_dst_port(), None);
+
+        table.prune(Timestamp::from_millis(1_000_000) + FRAGMENT_TIMEOUT);
+        assert!(table.is_empty());
+    }
+}
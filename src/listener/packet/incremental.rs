@@ -0,0 +1,238 @@
+//! Available bandwidth estimate kept up to date as gin/gout points arrive,
+//! so it can be read at any time without refitting every stored point.
+//!
+//! Only the filters that judge a point on its own apply: application
+//! limited, payload size and capacity bounds. The input gap quantile and MAD
+//! stages of `PABWESender::filter_gin_gacks` need every point, and are left
+//! to the batch estimate. Without them the least squares fit equals the
+//! batch one. The robust fit weighs each point once, by its residual to the
+//! fit so far, where the batch one reweighs every point until it converges.
+
+use super::estimation::{config_phy_cap, delta_std_err, AbwEstimate, GinGout};
+use super::packet_registry::RegressionType;
+
+/// Points before the robust fit starts weighing new ones.
+const MIN_FIT_POINTS: usize = 3;
+/// Huber threshold in residual scales, as in the batch fit.
+const HUBER_K: f64 = 1.345;
+/// Smallest Huber threshold.
+const TOL: f64 = 1e-4;
+/// Step of the running median of the residuals, as a share of it.
+const SCALE_STEP: f64 = 0.05;
+
+/// Weighted sums of a line fit.
+#[derive(Debug, Default, Clone, Copy)]
+struct Sums {
+    n: usize,
+    w: f64,
+    wx: f64,
+    wy: f64,
+    wxx: f64,
+    wxy: f64,
+    wyy: f64,
+}
+
+impl Sums {
+    fn add(&mut self, x: f64, y: f64, w: f64) {
+        self.n += 1;
+        self.w += w;
+        self.wx += w * x;
+        self.wy += w * y;
+        self.wxx += w * x * x;
+        self.wxy += w * x * y;
+        self.wyy += w * y * y;
+    }
+
+    /// `(slope, intercept)` of the fit.
+    fn fit(&self) -> Option<(f64, f64)> {
+        if self.n == 0 {
+            return None;
+        }
+        let denominator = self.w * self.wxx - self.wx * self.wx;
+        if denominator.abs() < f64::EPSILON {
+            return None;
+        }
+        let a = (self.w * self.wxy - self.wx * self.wy) / denominator;
+        let b = (self.wy - a * self.wx) / self.w;
+        (a.is_finite() && b.is_finite()).then_some((a, b))
+    }
+
+    fn estimate(&self, phy_cap: f64) -> Option<AbwEstimate> {
+        let (a, b) = self.fit()?;
+        if a.abs() <= f64::EPSILON {
+            return None;
+        }
+        let abw = (1.0 - b) / a;
+        (abw > 0.0 && abw < phy_cap).then(|| AbwEstimate {
+            abw,
+            std_err: self.std_err(a, b),
+            samples: self.n,
+        })
+    }
+
+    fn std_err(&self, a: f64, b: f64) -> Option<f64> {
+        if self.n < 3 {
+            return None;
+        }
+        let mean_x = self.wx / self.w;
+        let sxx = self.wxx - self.wx * mean_x;
+        if sxx < f64::EPSILON {
+            return None;
+        }
+        let ssr = self.wyy - 2.0 * a * self.wxy - 2.0 * b * self.wy
+            + a * a * self.wxx
+            + 2.0 * a * b * self.wx
+            + b * b * self.w;
+        Some(delta_std_err(self.w, mean_x, sxx, ssr.max(0.0), a, b))
+    }
+}
+
+/// Least squares and robust fits of the points since the last reset.
+#[derive(Debug, Clone)]
+pub struct IncrementalAbw {
+    min_payload: f64,
+    /// Link capacity in bytes/sec.
+    phy_cap: f64,
+    ols: Sums,
+    robust: Sums,
+    /// Running median of the absolute residuals of the robust fit.
+    scale: Option<f64>,
+}
+
+impl IncrementalAbw {
+    pub fn new(min_payload: f64, phy_cap: f64) -> Self {
+        IncrementalAbw {
+            min_payload,
+            phy_cap,
+            ols: Sums::default(),
+            robust: Sums::default(),
+            scale: None,
+        }
+    }
+
+    /// With `filter.min_payload` and `client.link_phy_cap`.
+    pub fn from_config() -> Self {
+        IncrementalAbw::new(crate::CONFIG.filter.min_payload, config_phy_cap())
+    }
+
+    /// Adds a point to both fits, unless the bounds drop it.
+    pub fn push(&mut self, dp: &GinGout) {
+        if !dp.within_bounds(self.min_payload, self.phy_cap) {
+            return;
+        }
+        let x = dp.len / dp.gin;
+        let y = dp.gout / dp.gin;
+        self.ols.add(x, y, 1.0);
+        let weight = match self.robust.fit() {
+            Some((a, b)) if self.robust.n >= MIN_FIT_POINTS => self.huber_weight(a, b, x, y),
+            _ => 1.0,
+        };
+        self.robust.add(x, y, weight);
+    }
+
+    /// Weight of a point with the fit `(a, b)`, moving the residual scale
+    /// toward its residual.
+    fn huber_weight(&mut self, a: f64, b: f64, x: f64, y: f64) -> f64 {
+        let residual = (y - (a * x + b)).abs();
+        let scale = *self.scale.get_or_insert(residual);
+        let step = SCALE_STEP * scale.max(TOL);
+        self.scale = Some(match residual > scale {
+            true => scale + step,
+            false => (scale - step).max(0.0),
+        });
+        let delta = (HUBER_K * scale).max(TOL);
+        match residual <= delta {
+            true => 1.0,
+            false => delta / residual,
+        }
+    }
+
+    /// The estimate of the points so far, in bytes/sec.
+    pub fn estimate(&self, regression_type: RegressionType) -> Option<AbwEstimate> {
+        match regression_type {
+            RegressionType::RLS => self.robust.estimate(self.phy_cap),
+            RegressionType::Simple => self.ols.estimate(self.phy_cap),
+        }
+    }
+
+    /// Points in the fits.
+    pub fn samples(&self) -> usize {
+        self.ols.n
+    }
+
+    /// Forgets every point, keeping the bounds.
+    pub fn reset(&mut self) {
+        *self = IncrementalAbw::new(self.min_payload, self.phy_cap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Filter;
+    use crate::listener::packet::PABWESender;
+    use crate::Timestamp;
+
+    /// Points on `gout / gin = x / 12.5 MB/s + 0.5`, 6.25 MB/s available,
+    /// every tenth one an outlier if `outliers`.
+    fn points(outliers: bool) -> Vec<GinGout> {
+        (0..200)
+            .map(|i| {
+                let x = 5e6 + (i % 50) as f64 * 3e5;
+                let gin = 1448.0 / x;
+                let mut ratio = x / 12.5e6 + 0.5;
+                if outliers && i % 10 == 0 {
+                    ratio *= 3.0;
+                }
+                GinGout {
+                    gin,
+                    gout: ratio * gin,
+                    len: 1448.0,
+                    num_acked: 1,
+                    timestamp: Timestamp::ZERO,
+                    app_limited: false,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_batch_least_squares() {
+        let dps = points(true);
+        let mut incremental = IncrementalAbw::new(1000.0, f64::MAX);
+        dps.iter().for_each(|dp| incremental.push(dp));
+        assert_eq!(incremental.samples(), 200);
+
+        let filter = Filter {
+            min_payload: 1000.0,
+            gin_quantile: 0.0,
+            mad_threshold: 0.0,
+        };
+        let mut batch = PABWESender::new();
+        batch.dps = dps;
+        let (expected, _) = batch.estimate_abw(RegressionType::Simple, &filter, f64::MAX);
+        let expected = expected.unwrap();
+        let estimate = incremental.estimate(RegressionType::Simple).unwrap();
+        assert!((estimate.abw - expected.abw).abs() < 1e-6 * expected.abw);
+        assert_eq!(estimate.samples, expected.samples);
+        let (err, expected_err) = (estimate.std_err.unwrap(), expected.std_err.unwrap());
+        assert!((err - expected_err).abs() < 1e-3 * expected_err);
+
+        incremental.reset();
+        assert_eq!(incremental.estimate(RegressionType::Simple), None);
+    }
+
+    #[test]
+    fn test_robust_fit_resists_outliers() {
+        let mut clean = IncrementalAbw::new(1000.0, f64::MAX);
+        points(false).iter().for_each(|dp| clean.push(dp));
+        let abw = clean.estimate(RegressionType::RLS).unwrap().abw;
+        assert!((abw - 6.25e6).abs() < 1e3);
+
+        let mut noisy = IncrementalAbw::new(1000.0, f64::MAX);
+        points(true).iter().for_each(|dp| noisy.push(dp));
+        let robust = noisy.estimate(RegressionType::RLS).unwrap().abw;
+        let ols = noisy.estimate(RegressionType::Simple).map_or(0.0, |e| e.abw);
+        assert!((robust - 6.25e6).abs() < (ols - 6.25e6).abs());
+    }
+}
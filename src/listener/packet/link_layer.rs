@@ -0,0 +1,358 @@
+use pcap::Linktype;
+use pnet::util::MacAddr;
+
+use super::Direction;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+/// 802.1Q VLAN tag and the 802.1ad outer tag of QinQ.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88A8;
+/// Most VLAN tags looked past, QinQ has two.
+const MAX_VLAN_TAGS: usize = 2;
+
+/// Linux SLL packet types
+const PACKET_HOST: u16 = 0;
+const PACKET_OUTGOING: u16 = 4;
+
+/// 802.11 frame control flags (second byte)
+const IEEE80211_TO_DS: u8 = 0x01;
+const IEEE80211_FROM_DS: u8 = 0x02;
+const IEEE80211_RETRY: u8 = 0x08;
+const IEEE80211_PROTECTED: u8 = 0x40;
+const IEEE80211_ORDER: u8 = 0x80;
+const IEEE80211_TYPE_DATA: u8 = 2;
+const IEEE80211_HDRLEN: usize = 24;
+/// LLC + SNAP header preceding the ethertype in 802.11 data frames.
+const LLC_SNAP: [u8; 6] = [0xAA, 0xAA, 0x03, 0x00, 0x00, 0x00];
+
+/// Link layer of the capture device, as reported by pcap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    /// Ethernet frames, direction is taken from the MAC address.
+    Ethernet,
+    /// Bare IP packets without a link header (tun devices).
+    Raw,
+    /// BSD loopback, 4 byte address family header.
+    Null,
+    /// Linux "cooked" capture (any device, some tunnels).
+    LinuxSll,
+    /// Linux "cooked" capture v2, which adds the interface index.
+    LinuxSll2,
+    /// Radiotap followed by an 802.11 frame (monitor mode).
+    Radiotap,
+}
+
+impl LinkType {
+    /// Length of the link layer header in bytes. Radiotap and 802.11
+    /// headers vary in length, this is the shortest possible.
+    pub fn header_len(&self) -> usize {
+        match self {
+            LinkType::Ethernet => libc::ETH_HLEN as usize,
+            LinkType::Raw => 0,
+            LinkType::Null => 4,
+            LinkType::LinuxSll => 16,
+            LinkType::LinuxSll2 => 20,
+            LinkType::Radiotap => 8 + IEEE80211_HDRLEN + 8,
+        }
+    }
+
+    /// Whether frames carry a MAC address we can use for direction detection.
+    pub fn has_mac(&self) -> bool {
+        matches!(self, LinkType::Ethernet | LinkType::Radiotap)
+    }
+}
+
+impl From<Linktype> for LinkType {
+    fn from(linktype: Linktype) -> Self {
+        match linktype.0 {
+            // DLT_RAW differs between platforms, LINKTYPE_RAW, LINKTYPE_IPV4/6
+            12 | 14 | 101 | 228 | 229 => LinkType::Raw,
+            // DLT_NULL, DLT_LOOP
+            0 | 108 => LinkType::Null,
+            113 => LinkType::LinuxSll,
+            276 => LinkType::LinuxSll2,
+            127 => LinkType::Radiotap,
+            _ => LinkType::Ethernet,
+        }
+    }
+}
+
+/// The parts of a link layer frame the parser cares about.
+#[derive(Debug, PartialEq)]
+pub struct LinkFrame<'a> {
+    pub src_mac: MacAddr,
+    pub dst_mac: MacAddr,
+    /// The IP header and everything after it.
+    pub ip_data: &'a [u8],
+    pub is_ipv6: bool,
+    /// Length of everything in front of `ip_data`.
+    pub header_len: usize,
+    /// Direction given by the link layer itself (SLL packet type).
+    pub direction: Option<Direction>,
+    /// 802.11 retry flag, the frame is a retransmission.
+    pub retry: bool,
+}
+
+impl<'a> LinkFrame<'a> {
+    /// Decode the link layer header of `data`.
+    ///
+    /// Returns `None` for frames which do not carry IPv4 or IPv6.
+    pub fn decode(link_type: LinkType, data: &'a [u8]) -> Option<Self> {
+        match link_type {
+            LinkType::Ethernet => {
+                let (ethertype, header_len) = ethernet_type(data)?;
+                let mut frame = Self::with_ethertype(data, header_len, ethertype)?;
+                frame.dst_mac = mac_at(data, 0)?;
+                frame.src_mac = mac_at(data, 6)?;
+                Some(frame)
+            }
+            LinkType::Raw | LinkType::Null => Self::with_ip_version(data, link_type.header_len()),
+            LinkType::LinuxSll => {
+                let pkttype = read_u16_be(data, 0)?;
+                let mut frame = Self::with_ethertype(data, 16, read_u16_be(data, 14)?)?;
+                frame.src_mac = sll_addr(data, read_u16_be(data, 4)?, 6)?;
+                frame.direction = sll_direction(pkttype);
+                Some(frame)
+            }
+            LinkType::LinuxSll2 => {
+                let pkttype = *data.get(10)? as u16;
+                let mut frame = Self::with_ethertype(data, 20, read_u16_be(data, 0)?)?;
+                frame.src_mac = sll_addr(data, *data.get(11)? as u16, 12)?;
+                frame.direction = sll_direction(pkttype);
+                Some(frame)
+            }
+            LinkType::Radiotap => Self::decode_radiotap(data),
+        }
+    }
+
+    /// Frame where the link header ends with an ethertype, the IP packet
+    /// starts at `header_len`.
+    fn with_ethertype(data: &'a [u8], header_len: usize, ethertype: u16) -> Option<Self> {
+        let is_ipv6 = match ethertype {
+            ETHERTYPE_IPV4 => false,
+            ETHERTYPE_IPV6 => true,
+            _ => return None,
+        };
+        Some(LinkFrame {
+            src_mac: MacAddr::zero(),
+            dst_mac: MacAddr::zero(),
+            ip_data: data.get(header_len..)?,
+            is_ipv6,
+            header_len,
+            direction: None,
+            retry: false,
+        })
+    }
+
+    /// Frame without a protocol field, the IP version is read from the packet.
+    fn with_ip_version(data: &'a [u8], header_len: usize) -> Option<Self> {
+        let ip_data = data.get(header_len..)?;
+        let is_ipv6 = match ip_data.first()? >> 4 {
+            4 => false,
+            6 => true,
+            _ => return None,
+        };
+        Some(LinkFrame {
+            src_mac: MacAddr::zero(),
+            dst_mac: MacAddr::zero(),
+            ip_data,
+            is_ipv6,
+            header_len,
+            direction: None,
+            retry: false,
+        })
+    }
+
+    /// Radiotap header followed by an unencrypted 802.11 data frame.
+    fn decode_radiotap(data: &'a [u8]) -> Option<Self> {
+        let radiotap_len = u16::from_le_bytes([*data.get(2)?, *data.get(3)?]) as usize;
+        let wlan = data.get(radiotap_len..)?;
+
+        let fc = *wlan.first()?;
+        let flags = *wlan.get(1)?;
+        if (fc >> 2) & 0x3 != IEEE80211_TYPE_DATA || flags & IEEE80211_PROTECTED != 0 {
+            return None;
+        }
+        let subtype = fc >> 4;
+        // Null data frames have no body
+        if subtype & 0x4 != 0 {
+            return None;
+        }
+
+        let mut wlan_len = IEEE80211_HDRLEN;
+        if flags & IEEE80211_TO_DS != 0 && flags & IEEE80211_FROM_DS != 0 {
+            wlan_len += 6;
+        }
+        if subtype & 0x8 != 0 {
+            // QoS control, and HT control if the order bit is set
+            wlan_len += 2;
+            if flags & IEEE80211_ORDER != 0 {
+                wlan_len += 4;
+            }
+        }
+
+        if wlan.get(wlan_len..wlan_len + LLC_SNAP.len())? != LLC_SNAP {
+            return None;
+        }
+        let ethertype = read_u16_be(wlan, wlan_len + LLC_SNAP.len())?;
+        let header_len = radiotap_len + wlan_len + LLC_SNAP.len() + 2;
+
+        let mut frame = Self::with_ethertype(data, header_len, ethertype)?;
+        // addr1 is the receiver, addr2 the transmitter
+        frame.dst_mac = mac_at(wlan, 4)?;
+        frame.src_mac = mac_at(wlan, 10)?;
+        frame.retry = flags & IEEE80211_RETRY != 0;
+        Some(frame)
+    }
+}
+
+/// Ethertype and header length for link types that carry one.
+///
+/// Used for non-IP protocols such as ARP, which `LinkFrame::decode` skips.
+pub fn ethertype(link_type: LinkType, data: &[u8]) -> Option<(u16, usize)> {
+    match link_type {
+        LinkType::Ethernet => ethernet_type(data),
+        LinkType::LinuxSll => Some((read_u16_be(data, 14)?, 16)),
+        LinkType::LinuxSll2 => Some((read_u16_be(data, 0)?, 20)),
+        _ => None,
+    }
+}
+
+/// Ethertype of an Ethernet frame past its VLAN tags, and the length of
+/// the header with the tags.
+fn ethernet_type(data: &[u8]) -> Option<(u16, usize)> {
+    let mut header_len = libc::ETH_HLEN as usize;
+    let mut ethertype = read_u16_be(data, header_len - 2)?;
+    for _ in 0..MAX_VLAN_TAGS {
+        if ethertype != ETHERTYPE_VLAN && ethertype != ETHERTYPE_QINQ {
+            break;
+        }
+        header_len += 4;
+        ethertype = read_u16_be(data, header_len - 2)?;
+    }
+    Some((ethertype, header_len))
+}
+
+fn read_u16_be(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]))
+}
+
+fn mac_at(data: &[u8], offset: usize) -> Option<MacAddr> {
+    let b = data.get(offset..offset + 6)?;
+    Some(MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5]))
+}
+
+/// Source address of an SLL header, if it is a MAC address.
+fn sll_addr(data: &[u8], addr_len: u16, addr_offset: usize) -> Option<MacAddr> {
+    if addr_len == 6 {
+        mac_at(data, addr_offset)
+    } else {
+        Some(MacAddr::zero())
+    }
+}
+
+fn sll_direction(pkttype: u16) -> Option<Direction> {
+    match pkttype {
+        PACKET_HOST => Some(Direction::Incoming),
+        PACKET_OUTGOING => Some(Direction::Outgoing),
+        // Broadcast, multicast and other hosts' traffic
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal IPv4 header, the decoders only look at the version.
+    const IPV4: [u8; 20] = [
+        0x45, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 0, 0, 1, 10,
+        0, 0, 2,
+    ];
+
+    #[test]
+    fn test_decode_ethernet() {
+        let mut data = vec![0x02; 6];
+        data.extend_from_slice(&[0x04; 6]);
+        data.extend_from_slice(&[0x86, 0xDD]);
+        data.extend_from_slice(&[0x60; 40]);
+        let frame = LinkFrame::decode(LinkType::Ethernet, &data).unwrap();
+        assert!(frame.is_ipv6);
+        assert_eq!(frame.header_len, 14);
+        assert_eq!(frame.dst_mac, MacAddr::new(2, 2, 2, 2, 2, 2));
+        assert_eq!(frame.src_mac, MacAddr::new(4, 4, 4, 4, 4, 4));
+    }
+
+    #[test]
+    fn test_decode_linux_sll() {
+        let mut data = vec![0x00, 0x04, 0x00, 0x01, 0x00, 0x06];
+        data.extend_from_slice(&[0x0a; 6]);
+        data.extend_from_slice(&[0x00, 0x00, 0x08, 0x00]);
+        data.extend_from_slice(&IPV4);
+        let frame = LinkFrame::decode(LinkType::LinuxSll, &data).unwrap();
+        assert!(!frame.is_ipv6);
+        assert_eq!(frame.header_len, 16);
+        assert_eq!(frame.ip_data, &IPV4);
+        assert_eq!(frame.src_mac, MacAddr::new(10, 10, 10, 10, 10, 10));
+        assert_eq!(frame.direction, Some(Direction::Outgoing));
+    }
+
+    #[test]
+    fn test_decode_linux_sll2() {
+        let mut data = vec![0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x00, 0x06];
+        data.extend_from_slice(&[0x0a; 6]);
+        data.extend_from_slice(&[0x00, 0x00]);
+        data.extend_from_slice(&IPV4);
+        let frame = LinkFrame::decode(LinkType::LinuxSll2, &data).unwrap();
+        assert_eq!(frame.header_len, 20);
+        assert_eq!(frame.ip_data, &IPV4);
+        assert_eq!(frame.direction, Some(Direction::Incoming));
+    }
+
+    #[test]
+    fn test_decode_radiotap_qos_data_retry() {
+        // Radiotap header: version, pad, length 8, no fields present
+        let mut data = vec![0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
+        // QoS data, FromDS + retry
+        data.extend_from_slice(&[0x88, IEEE80211_FROM_DS | IEEE80211_RETRY, 0x00, 0x00]);
+        data.extend_from_slice(&[0x01; 6]); // addr1, receiver
+        data.extend_from_slice(&[0x02; 6]); // addr2, transmitter
+        data.extend_from_slice(&[0x03; 6]); // addr3
+        data.extend_from_slice(&[0x00, 0x00]); // sequence control
+        data.extend_from_slice(&[0x00, 0x00]); // QoS control
+        data.extend_from_slice(&LLC_SNAP);
+        data.extend_from_slice(&[0x08, 0x00]);
+        data.extend_from_slice(&IPV4);
+
+        let frame = LinkFrame::decode(LinkType::Radiotap, &data).unwrap();
+        assert!(frame.retry);
+        assert_eq!(frame.ip_data, &IPV4);
+        assert_eq!(frame.header_len, 8 + 26 + 8);
+        assert_eq!(frame.dst_mac, MacAddr::new(1, 1, 1, 1, 1, 1));
+        assert_eq!(frame.src_mac, MacAddr::new(2, 2, 2, 2, 2, 2));
+    }
+
+    #[test]
+    fn test_decode_radiotap_skips_management_and_protected() {
+        let mut beacon = vec![0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00];
+        beacon.extend_from_slice(&[0x00; 40]);
+        assert!(LinkFrame::decode(LinkType::Radiotap, &beacon).is_none());
+
+        let mut protected = vec![0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08];
+        protected.push(IEEE80211_PROTECTED);
+        protected.extend_from_slice(&[0x00; 40]);
+        assert!(LinkFrame::decode(LinkType::Radiotap, &protected).is_none());
+    }
+
+    #[test]
+    fn test_link_type_from_linktype() {
+        assert_eq!(LinkType::from(Linktype::ETHERNET), LinkType::Ethernet);
+        assert_eq!(LinkType::from(Linktype(12)), LinkType::Raw);
+        assert_eq!(LinkType::from(Linktype(101)), LinkType::Raw);
+        assert_eq!(LinkType::from(Linktype::NULL), LinkType::Null);
+        assert_eq!(LinkType::from(Linktype::LINUX_SLL), LinkType::LinuxSll);
+        assert_eq!(LinkType::from(Linktype(276)), LinkType::LinuxSll2);
+        assert_eq!(LinkType::from(Linktype(127)), LinkType::Radiotap);
+    }
+}
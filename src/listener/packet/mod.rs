@@ -1,13 +1,23 @@
 mod direction;
+mod fragment;
+mod offload;
+pub mod link_layer;
+pub mod neighbor;
 mod packet_builder;
 mod transport_packet;
 mod data_packet;
 mod estimation;
+mod incremental;
 mod packet_registry;
+#[cfg(test)]
+pub mod fixtures;
 
-pub use estimation::PABWESender;
+pub use estimation::{AbwEstimate, GinGout, PABWESender};
+pub use incremental::IncrementalAbw;
 
 pub use direction::Direction;
+pub use fragment::{Fragment, FragmentTable};
+pub use offload::{OffloadMode, OffloadPolicy, SEGMENT_HEADERS};
 pub use packet_builder::ParsedPacket;
 pub use transport_packet::TcpFlags;
 pub use transport_packet::TcpOptions;
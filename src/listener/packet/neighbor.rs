@@ -0,0 +1,172 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use pcap::PacketHeader;
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::Packet;
+
+use super::link_layer::{self, LinkFrame};
+use crate::listener::capture::PCAPMeta;
+use crate::Timestamp;
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborOp {
+    /// ARP request or neighbor solicitation.
+    Request,
+    /// ARP reply or neighbor advertisement.
+    Reply,
+}
+
+/// An ARP or ICMPv6 neighbor discovery message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborPacket {
+    pub op: NeighborOp,
+    /// Address of the node sending the message. For advertisements this is
+    /// the advertised address.
+    pub sender_ip: IpAddr,
+    /// Address being resolved.
+    pub target_ip: IpAddr,
+    pub timestamp: Timestamp,
+}
+
+impl NeighborPacket {
+    /// Parse an ARP or neighbor discovery message from the capture of
+    /// `meta`, `None` for anything else.
+    pub fn from_raw(header: &PacketHeader, data: &[u8], meta: &PCAPMeta) -> Option<Self> {
+        let link_type = meta.link_type;
+        let timestamp = Timestamp::from_timeval(header.ts, meta.capture_offset);
+        if let Some((ETHERTYPE_ARP, header_len)) = link_layer::ethertype(link_type, data) {
+            return Self::from_arp(data.get(header_len..)?, timestamp);
+        }
+        let frame = LinkFrame::decode(link_type, data)?;
+        if frame.is_ipv6 {
+            return Self::from_icmpv6(frame.ip_data, timestamp);
+        }
+        None
+    }
+
+    fn from_arp(data: &[u8], timestamp: Timestamp) -> Option<Self> {
+        let arp = ArpPacket::new(data)?;
+        let op = arp.get_operation();
+        let op = if op == ArpOperations::Request {
+            NeighborOp::Request
+        } else if op == ArpOperations::Reply {
+            NeighborOp::Reply
+        } else {
+            return None;
+        };
+        Some(NeighborPacket {
+            op,
+            sender_ip: IpAddr::V4(arp.get_sender_proto_addr()),
+            target_ip: IpAddr::V4(arp.get_target_proto_addr()),
+            timestamp,
+        })
+    }
+
+    /// Neighbor solicitation/advertisement, assuming no extension headers
+    /// (which ND messages do not use).
+    fn from_icmpv6(data: &[u8], timestamp: Timestamp) -> Option<Self> {
+        let ipv6 = Ipv6Packet::new(data)?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+            return None;
+        }
+        let icmp = ipv6.payload();
+        let target: [u8; 16] = icmp.get(8..24)?.try_into().ok()?;
+        let target = IpAddr::V6(Ipv6Addr::from(target));
+        match *icmp.first()? {
+            ICMPV6_NEIGHBOR_SOLICITATION => {
+                let sender = ipv6.get_source();
+                // Duplicate address detection, nobody is asking
+                if sender.is_unspecified() {
+                    return None;
+                }
+                Some(NeighborPacket {
+                    op: NeighborOp::Request,
+                    sender_ip: IpAddr::V6(sender),
+                    target_ip: target,
+                    timestamp,
+                })
+            }
+            ICMPV6_NEIGHBOR_ADVERTISEMENT => Some(NeighborPacket {
+                op: NeighborOp::Reply,
+                sender_ip: target,
+                target_ip: IpAddr::V6(ipv6.get_destination()),
+                timestamp,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn header(len: usize) -> PacketHeader {
+        PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 1,
+                tv_usec: 0,
+            },
+            caplen: len as u32,
+            len: len as u32,
+        }
+    }
+
+    #[test]
+    fn test_parse_arp_reply() {
+        let mut data = vec![0xff; 6];
+        data.extend_from_slice(&[0x02; 6]);
+        data.extend_from_slice(&[0x08, 0x06]);
+        // Ethernet/IPv4, reply
+        data.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x02]);
+        data.extend_from_slice(&[0x02; 6]);
+        data.extend_from_slice(&[10, 0, 0, 2]);
+        data.extend_from_slice(&[0x01; 6]);
+        data.extend_from_slice(&[10, 0, 0, 1]);
+
+        let packet = NeighborPacket::from_raw(&header(data.len()), &data, &PCAPMeta::for_test())
+            .unwrap();
+        assert_eq!(packet.op, NeighborOp::Reply);
+        assert_eq!(packet.sender_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(packet.target_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn test_parse_neighbor_solicitation() {
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let target: Ipv6Addr = "fe80::2".parse().unwrap();
+
+        let mut data = vec![0x33; 6];
+        data.extend_from_slice(&[0x02; 6]);
+        data.extend_from_slice(&[0x86, 0xDD]);
+        // IPv6 header, payload length 24, next header ICMPv6, hop limit 255
+        data.extend_from_slice(&[0x60, 0, 0, 0, 0, 24, 58, 255]);
+        data.extend_from_slice(&src.octets());
+        data.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        // ICMPv6 neighbor solicitation
+        data.extend_from_slice(&[ICMPV6_NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&target.octets());
+
+        let packet = NeighborPacket::from_raw(&header(data.len()), &data, &PCAPMeta::for_test())
+            .unwrap();
+        assert_eq!(packet.op, NeighborOp::Request);
+        assert_eq!(packet.sender_ip, IpAddr::V6(src));
+        assert_eq!(packet.target_ip, IpAddr::V6(target));
+    }
+
+    #[test]
+    fn test_ignores_ip_traffic() {
+        let mut data = vec![0x00; 12];
+        data.extend_from_slice(&[0x08, 0x00]);
+        data.extend_from_slice(&[0x45; 20]);
+        let meta = PCAPMeta::for_test();
+        assert!(NeighborPacket::from_raw(&header(data.len()), &data, &meta).is_none());
+    }
+}
@@ -0,0 +1,137 @@
+//! GSO/GRO super-frames.
+//!
+//! With segmentation or receive offloads, the capture sees the TCP segments
+//! as the kernel hands them over, up to 64KB, not as they are on the wire.
+//! One such frame stands for many back to back segments, so its length
+//! passes any payload filter while its gaps are those of the whole batch.
+//! What happens to them in the estimates is set per interface in
+//! `[offload]`, see `OffloadMode`.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::{ParsedPacket, TransportPacket, CONFIG};
+
+/// IPv4 and TCP headers without options. No segment on the wire carries
+/// more payload than the MTU less these.
+const MIN_HEADERS: u16 = 40;
+/// IPv4 and TCP headers with the timestamp option, as Linux sends them.
+/// A super-frame is split into segments of the MTU less these, so
+/// `offload.mtu` must be larger.
+pub const SEGMENT_HEADERS: u16 = 52;
+
+/// What a super-frame is in the estimates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffloadMode {
+    /// One packet, as captured.
+    Keep,
+    /// The segments it was cut into on the wire, spreading its gaps and
+    /// payload over them.
+    Split,
+    /// Nothing, ACK groups with one give no gap points.
+    Exclude,
+}
+
+impl FromStr for OffloadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(OffloadMode::Keep),
+            "split" => Ok(OffloadMode::Split),
+            "exclude" => Ok(OffloadMode::Exclude),
+            _ => Err(anyhow!("Unknown offload mode: {}", s)),
+        }
+    }
+}
+
+/// How the TCP frames captured on an interface are tagged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffloadPolicy {
+    pub mode: OffloadMode,
+    /// Largest IP packet on the wire.
+    pub mtu: u16,
+}
+
+impl OffloadPolicy {
+    pub fn new(mode: OffloadMode, mtu: u16) -> Self {
+        OffloadPolicy { mode, mtu }
+    }
+
+    /// From `offload.interfaces`, or `offload.mode` if the interface is not
+    /// there.
+    pub fn for_interface(name: &str) -> Self {
+        let offload = &CONFIG.offload;
+        let mode = offload.interfaces.get(name).copied().unwrap_or(offload.mode);
+        OffloadPolicy::new(mode, offload.mtu)
+    }
+
+    /// True for a TCP frame with more payload than fits in the MTU.
+    pub fn is_super_frame(&self, packet: &ParsedPacket) -> bool {
+        match packet.transport {
+            TransportPacket::TCP { payload_len, .. } => {
+                payload_len > self.mtu.saturating_sub(MIN_HEADERS)
+            }
+            _ => false,
+        }
+    }
+
+    /// Segments a frame stands for in the estimates: 1 for a packet as seen
+    /// on the wire, more for a split super-frame and 0 for an excluded one.
+    pub fn segments(&self, packet: &ParsedPacket) -> u8 {
+        let TransportPacket::TCP { payload_len, .. } = packet.transport else {
+            return 1;
+        };
+        if !self.is_super_frame(packet) {
+            return 1;
+        }
+        match self.mode {
+            OffloadMode::Keep => 1,
+            OffloadMode::Split => {
+                let segment = self.mtu.saturating_sub(SEGMENT_HEADERS).max(1);
+                payload_len.div_ceil(segment).min(u8::MAX as u16) as u8
+            }
+            OffloadMode::Exclude => 0,
+        }
+    }
+
+    /// Tags `packet` with its segments.
+    pub fn apply(&self, packet: &mut ParsedPacket) {
+        packet.segments = self.segments(packet);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Direction, Timestamp};
+
+    fn segment(payload_len: u16) -> ParsedPacket {
+        let timestamp = Timestamp::from_millis(1_000);
+        ParsedPacket::test_tcp(1, 1, payload_len, timestamp, Direction::Outgoing)
+    }
+
+    #[test]
+    fn test_super_frames() {
+        let split = OffloadPolicy::new(OffloadMode::Split, 1500);
+        assert_eq!(split.segments(&segment(1448)), 1);
+        // Without TCP options a full segment is still no super-frame
+        assert_eq!(split.segments(&segment(1460)), 1);
+
+        assert!(!split.is_super_frame(&segment(1460)));
+        let mut packet = segment(64_000);
+        assert!(split.is_super_frame(&packet));
+        split.apply(&mut packet);
+        assert_eq!(packet.segments, 45);
+        assert_eq!(OffloadPolicy::new(OffloadMode::Exclude, 1500).segments(&packet), 0);
+        assert_eq!(OffloadPolicy::new(OffloadMode::Keep, 1500).segments(&packet), 1);
+        // On a jumbo frame link it is a regular packet
+        let jumbo = OffloadPolicy::new(OffloadMode::Exclude, 65_535);
+        assert!(!jumbo.is_super_frame(&packet));
+        assert_eq!(jumbo.segments(&packet), 1);
+
+        assert_eq!("Exclude".parse::<OffloadMode>().unwrap(), OffloadMode::Exclude);
+        assert!("tso".parse::<OffloadMode>().is_err());
+    }
+}
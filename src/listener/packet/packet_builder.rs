@@ -1,34 +1,26 @@
-use libc::ETH_HLEN;
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::ipv6::Ipv6Packet;
-use pnet::packet::Packet;
 use pnet::util::MacAddr;
 use std::net::IpAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::time;
 
 use super::Direction;
+use super::fragment::Fragment;
+use super::link_layer::LinkFrame;
 use crate::listener::capture::{OwnedPacket, PCAPMeta};
+use crate::Timestamp;
+use pcap::PacketHeader;
 use crate::listener::packet::transport_packet::TransportPacket;
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::ip::IpNextHeaderProtocol;
 
 const IPV6HDR: usize = 40;
 const WORD_SIZE: usize = 4;
-
-/// time::Duration and SystemTime uses Nanosecond precision
-pub fn timeval_to_system_time(tv: libc::timeval) -> SystemTime {
-    match crate::Settings::PRECISION {
-        pcap::Precision::Micro => {
-            let dur = time::Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
-            UNIX_EPOCH + dur
-        }
-        pcap::Precision::Nano => {
-            let dur = time::Duration::new(tv.tv_sec as u64, tv.tv_usec as u32);
-            UNIX_EPOCH + dur
-        }
-    }
-}
+/// IPv6 extension headers with the common layout: next header, then the
+/// length in 8 octets past the first 8.
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_DEST_OPTIONS: u8 = 60;
+/// Most extension headers looked past before the transport header.
+const MAX_IPV6_EXTENSIONS: usize = 8;
 
 // -----------------------------------
 // Zero-copy ParsedPacket
@@ -41,30 +33,95 @@ pub struct ParsedPacket {
     pub dst_mac: MacAddr,
     pub transport: TransportPacket,
     pub total_length: u16,
-    pub timestamp: SystemTime,
+    pub timestamp: Timestamp,
     pub direction: Direction,
     pub intercepted: bool,
+    /// 802.11 retry flag, only set on radiotap captures.
+    pub retry: bool,
+    /// IPv4 TTL or IPv6 hop limit as captured.
+    pub ttl: u8,
+    /// IPv4 identification, 0 on IPv6.
+    pub ip_id: u16,
+    /// Set if the packet is a fragment of a larger datagram. Only the first
+    /// fragment has a parsed transport header, see `FragmentTable`.
+    pub fragment: Option<Fragment>,
+    /// Segments the packet stands for in the estimates, 1 unless it is a
+    /// GSO/GRO super-frame, see `OffloadPolicy`.
+    pub segments: u8,
+    /// Set if the length was shorter than the headers or longer than fits
+    /// in 16 bits, and was saturated.
+    pub malformed_length: bool,
+}
+
+/// Fields of the IP header.
+struct IpHeader<'a> {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    /// Everything after the IP headers.
+    payload: &'a [u8],
+    protocol: IpNextHeaderProtocol,
+    /// Length of the IP headers.
+    hdrlen: u16,
+    ttl: u8,
+    ip_id: u16,
+    fragment: Option<Fragment>,
 }
 
 impl<'a> ParsedPacket {
     /// Convert an OwnedPacket into a borrowed ParsedPacket without copying the payload
     pub fn from_packet(packet: &'a OwnedPacket, pcap_meta: &PCAPMeta) -> Option<ParsedPacket> {
-        // Parse Ethernet frame in place
-        let eth = EthernetPacket::new(&packet.data)?;
-        let total_length = packet.header.len as u16;
-        let timestamp = timeval_to_system_time(packet.header.ts);
+        Self::from_raw(&packet.header, &packet.data, pcap_meta)
+    }
+
+    /// Parse directly from a pcap header and the captured frame.
+    ///
+    /// Used by the capture thread when `client.parse_in_capture` is set, so the
+    /// frame never has to be copied into an `OwnedPacket`.
+    pub fn from_raw(
+        header: &PacketHeader,
+        data: &'a [u8],
+        pcap_meta: &PCAPMeta,
+    ) -> Option<ParsedPacket> {
+        // GSO/GRO frames can be longer than an IP packet
+        let mut malformed = false;
+        let total_length = u16::try_from(header.len).unwrap_or_else(|_| {
+            malformed = true;
+            u16::MAX
+        });
+        let timestamp = Timestamp::from_timeval(header.ts, pcap_meta.capture_offset);
+        let frame = LinkFrame::decode(pcap_meta.link_type, data)?;
+        let ip_data = frame.ip_data;
 
         // Extract IP info & payload references
-        let (src_ip, dst_ip, payload, protocol, hdrlen) = Self::get_ip_info(&eth)?;
+        let ip = if frame.is_ipv6 {
+            Self::parse_ipv6_packet(ip_data)?
+        } else {
+            Self::parse_ipv4_packet(ip_data)?
+        };
+        let (src_ip, dst_ip) = (ip.src_ip, ip.dst_ip);
 
-        // Build the transport struct from the raw payload reference
-        let transport = TransportPacket::from_data(
-            payload,
-            protocol,
-            total_length as u16 - (hdrlen + ETH_HLEN as u16),
-        );
+        // Build the transport struct from the raw payload reference, later
+        // fragments have no transport header
+        let transport = match ip.fragment {
+            Some(fragment) if !fragment.is_first() => TransportPacket::OTHER {
+                protocol: ip.protocol.0,
+            },
+            _ => {
+                let headers = ip.hdrlen.saturating_add(frame.header_len as u16);
+                let payload_len = total_length.checked_sub(headers).unwrap_or_else(|| {
+                    malformed = true;
+                    0
+                });
+                let (transport, short) =
+                    TransportPacket::from_data(ip.payload, ip.protocol, payload_len);
+                malformed |= short;
+                transport
+            }
+        };
 
-        let direction = Direction::from_mac(eth.get_destination(), pcap_meta.mac_addr);
+        let direction = frame
+            .direction
+            .unwrap_or_else(|| pcap_meta.direction(frame.dst_mac, src_ip, dst_ip));
 
         // The packet is intercepted if A <-> B <-> C and the packet is marked A <-> C
         let intercepted = !pcap_meta.matches_ip(src_ip) && !pcap_meta.matches_ip(dst_ip);
@@ -72,13 +129,19 @@ impl<'a> ParsedPacket {
         Some(ParsedPacket {
             src_ip,
             dst_ip,
-            src_mac: eth.get_source(),
-            dst_mac: eth.get_destination(),
+            src_mac: frame.src_mac,
+            dst_mac: frame.dst_mac,
             transport,
             total_length,
             timestamp,
             direction,
             intercepted,
+            retry: frame.retry,
+            ttl: ip.ttl,
+            ip_id: ip.ip_id,
+            fragment: ip.fragment,
+            segments: 1,
+            malformed_length: malformed,
         })
     }
 
@@ -98,60 +161,155 @@ impl<'a> ParsedPacket {
         }
     }
 
+    /// ICMPv6 carries neighbor discovery, which is also parsed separately.
+    pub fn is_icmpv6(&self) -> bool {
+        matches!(self.transport, TransportPacket::OTHER { protocol: 58 })
+    }
+
+    /// Transport ports, taken from the first fragment on later ones.
     pub fn get_src_dst_port(&self) -> Option<(u16, u16)> {
         match &self.transport {
             TransportPacket::TCP { dst_port, src_port, .. } | TransportPacket::UDP { dst_port, src_port, .. } => {
                 Some((*src_port, *dst_port))
             }
-            _ => None,
+            _ => self.fragment.and_then(|fragment| fragment.ports),
+        }
+    }
+
+    fn parse_ipv4_packet(payload: &'a [u8]) -> Option<IpHeader<'a>> {
+        let ipv4 = Ipv4Packet::new(payload)?;
+        Some(IpHeader {
+            src_ip: IpAddr::V4(ipv4.get_source()),
+            dst_ip: IpAddr::V4(ipv4.get_destination()),
+            payload: &payload[ipv4.get_header_length() as usize * WORD_SIZE..], // reference to the rest of the IPv4 payload
+            protocol: ipv4.get_next_level_protocol(),
+            hdrlen: ipv4.get_header_length() as u16 * WORD_SIZE as u16,
+            ttl: ipv4.get_ttl(),
+            ip_id: ipv4.get_identification(),
+            fragment: Fragment::from_ipv4(
+                ipv4.get_flags(),
+                ipv4.get_fragment_offset(),
+                ipv4.get_identification(),
+            ),
+        })
+    }
+
+    /// Looks past the hop-by-hop, routing, destination options and fragment
+    /// headers.
+    fn parse_ipv6_packet(payload: &'a [u8]) -> Option<IpHeader<'a>> {
+        let ipv6 = Ipv6Packet::new(payload)?;
+        let mut header = IpHeader {
+            src_ip: IpAddr::V6(ipv6.get_source()),
+            dst_ip: IpAddr::V6(ipv6.get_destination()),
+            payload: &payload[IPV6HDR..], // reference to the rest of the IPv6 payload
+            protocol: ipv6.get_next_header(),
+            hdrlen: IPV6HDR as u16,
+            ttl: ipv6.get_hop_limit(),
+            ip_id: 0,
+            fragment: None,
+        };
+        for _ in 0..MAX_IPV6_EXTENSIONS {
+            let (protocol, len) = match header.protocol.0 {
+                IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS => {
+                    let len = (*header.payload.get(1)? as usize + 1) * 8;
+                    (*header.payload.first()?, len)
+                }
+                next_header => match Fragment::ipv6_header_len(next_header) {
+                    Some(len) => {
+                        let (fragment, protocol) = Fragment::from_ipv6(header.payload)?;
+                        header.fragment = Some(fragment);
+                        (protocol, len)
+                    }
+                    None => break,
+                },
+            };
+            header.payload = header.payload.get(len..)?;
+            header.protocol = IpNextHeaderProtocol(protocol);
+            header.hdrlen += len as u16;
         }
+        Some(header)
     }
+}
 
-    /// Returns (src_ip, dst_ip, payload, protocol)
-    fn get_ip_info(
-        eth: &'a EthernetPacket,
-    ) -> Option<(IpAddr, IpAddr, &'a [u8], IpNextHeaderProtocol, u16)> {
-        match eth.get_ethertype() {
-            EtherTypes::Ipv4 => Self::parse_ipv4_packet(eth.payload()),
-            EtherTypes::Ipv6 => Self::parse_ipv6_packet(eth.payload()),
-            _ => None,
+#[cfg(test)]
+impl ParsedPacket {
+    /// A packet from 10.0.0.1 to 10.0.0.2 carrying `transport`, for tests.
+    /// `total_length` has an IPv4 header, and for TCP the timestamp option.
+    pub fn test_packet(
+        transport: TransportPacket,
+        timestamp: Timestamp,
+        direction: Direction,
+    ) -> Self {
+        let total_length = match transport {
+            TransportPacket::TCP { payload_len, .. } => payload_len + 52,
+            TransportPacket::UDP { payload_len, .. } => payload_len + 28,
+            // A ping with the default 56 bytes of data
+            TransportPacket::ICMP => 84,
+            TransportPacket::OTHER { .. } => 20,
+        };
+        ParsedPacket {
+            src_ip: [10, 0, 0, 1].into(),
+            dst_ip: [10, 0, 0, 2].into(),
+            src_mac: MacAddr::zero(),
+            dst_mac: MacAddr::zero(),
+            transport,
+            total_length,
+            timestamp,
+            direction,
+            intercepted: false,
+            retry: false,
+            ttl: 64,
+            ip_id: 0,
+            fragment: None,
+            segments: 1,
+            malformed_length: false,
         }
     }
 
-    fn parse_ipv4_packet(
-        payload: &'a [u8],
-    ) -> Option<(IpAddr, IpAddr, &'a [u8], IpNextHeaderProtocol, u16)> {
-        let ipv4 = Ipv4Packet::new(payload)?;
-        Some((
-            IpAddr::V4(ipv4.get_source()),
-            IpAddr::V4(ipv4.get_destination()),
-            &payload[ipv4.get_header_length() as usize * WORD_SIZE..], // reference to the rest of the IPv4 payload
-            ipv4.get_next_level_protocol(),
-            ipv4.get_header_length() as u16 * WORD_SIZE as u16,
-        ))
+    /// `test_packet` with a TCP ACK segment from port 40000 to 5201.
+    pub fn test_tcp(
+        sequence: u32,
+        acknowledgment: u32,
+        payload_len: u16,
+        timestamp: Timestamp,
+        direction: Direction,
+    ) -> Self {
+        let transport = TransportPacket::TCP {
+            sequence,
+            acknowledgment,
+            flags: super::TcpFlags::new(super::TcpFlags::ACK),
+            payload_len,
+            options: super::TcpOptions::default(),
+            src_port: 40000,
+            dst_port: 5201,
+            window_size: 65535,
+        };
+        Self::test_packet(transport, timestamp, direction)
     }
 
-    fn parse_ipv6_packet(
-        payload: &'a [u8],
-    ) -> Option<(IpAddr, IpAddr, &'a [u8], IpNextHeaderProtocol, u16)> {
-        let ipv6 = Ipv6Packet::new(payload)?;
-        Some((
-            IpAddr::V6(ipv6.get_source()),
-            IpAddr::V6(ipv6.get_destination()),
-            &payload[crate::Settings::IPV6HDR as usize..], // reference to the rest of the IPv6 payload
-            ipv6.get_next_header(),
-            IPV6HDR as u16,
-        ))
+    /// `test_packet` with a UDP datagram.
+    pub fn test_udp(
+        src_port: u16,
+        dst_port: u16,
+        payload_len: u16,
+        timestamp: Timestamp,
+        direction: Direction,
+    ) -> Self {
+        let transport = TransportPacket::UDP {
+            src_port,
+            dst_port,
+            payload_len,
+        };
+        Self::test_packet(transport, timestamp, direction)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::net::Ipv4Addr;
 
     use super::*;
-    use crate::listener::capture::OwnedPacket;
-    use pcap::PacketHeader;
+    use crate::listener::capture::{LinkType, OwnedPacket};
 
     fn create_tcp_packet() -> Vec<u8> {
         // Build a minimal Ethernet+IPv4 header (14 bytes + 20 bytes) + 20-byte TCP header
@@ -219,10 +377,9 @@ mod tests {
 
         // Parse once with payload
         let pcap_meta = crate::listener::capture::PCAPMeta {
-            mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
             ipv4: Ipv4Addr::new(0, 0, 0, 0),
-            ipv6: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
             name: "test".to_string(),
+            ..crate::listener::capture::PCAPMeta::for_test()
         };
         let parsed = ParsedPacket::from_packet(&owned_packet, &pcap_meta).unwrap();
         assert_eq!(parsed.total_length, 14 + 20 + 1000);
@@ -261,10 +418,9 @@ mod tests {
         };
 
         let pcap_meta = crate::listener::capture::PCAPMeta {
-            mac_addr: MacAddr::new(0, 0, 0, 0, 0, 0),
             ipv4: Ipv4Addr::new(0, 0, 0, 0),
-            ipv6: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
             name: "test".to_string(),
+            ..crate::listener::capture::PCAPMeta::for_test()
         };
 
         let parsed = ParsedPacket::from_packet(&owned_packet, &pcap_meta).unwrap();
@@ -275,4 +431,129 @@ mod tests {
             panic!("Expected TCP packet");
         }
     }
+
+    #[test]
+    fn test_from_raw_matches_from_packet() {
+        let packet_data = create_tcp_packet();
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 1,
+                tv_usec: 0,
+            },
+            caplen: packet_data.len() as u32,
+            len: packet_data.len() as u32 + 1000,
+        };
+        let owned_packet = OwnedPacket {
+            header,
+            data: packet_data.clone().into(),
+        };
+
+        let pcap_meta = crate::listener::capture::PCAPMeta {
+            ipv4: Ipv4Addr::new(0, 0, 0, 0),
+            name: "test".to_string(),
+            ..crate::listener::capture::PCAPMeta::for_test()
+        };
+
+        let owned = ParsedPacket::from_packet(&owned_packet, &pcap_meta).unwrap();
+        let raw = ParsedPacket::from_raw(&header, &packet_data, &pcap_meta).unwrap();
+        assert_eq!(owned.total_length, raw.total_length);
+        assert_eq!(owned.timestamp, raw.timestamp);
+        assert_eq!(owned.transport, raw.transport);
+        assert_eq!(owned.direction, raw.direction);
+    }
+
+    #[test]
+    fn test_from_raw_without_link_header() {
+        // Same packet as seen on a tun device: no Ethernet header
+        let packet_data = create_tcp_packet()[14..].to_vec();
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 1,
+                tv_usec: 0,
+            },
+            caplen: packet_data.len() as u32,
+            len: packet_data.len() as u32 + 1000,
+        };
+
+        let pcap_meta = crate::listener::capture::PCAPMeta {
+            ipv4: Ipv4Addr::new(127, 0, 0, 2),
+            name: "tun0".to_string(),
+            link_type: LinkType::Raw,
+            ..crate::listener::capture::PCAPMeta::for_test()
+        };
+
+        let parsed = ParsedPacket::from_raw(&header, &packet_data, &pcap_meta).unwrap();
+        assert_eq!(parsed.src_ip, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(parsed.direction, Direction::Incoming);
+        if let TransportPacket::TCP { payload_len, .. } = parsed.transport {
+            assert_eq!(payload_len, 1000);
+        } else {
+            panic!("Expected TCP packet");
+        }
+    }
+
+    #[test]
+    fn test_malformed_lengths_saturate() {
+        let packet_data = create_tcp_packet();
+        let pcap_meta = crate::listener::capture::PCAPMeta {
+            ipv4: Ipv4Addr::new(127, 0, 0, 2),
+            ..crate::listener::capture::PCAPMeta::for_test()
+        };
+        let parse = |len: u32| {
+            let header = PacketHeader {
+                ts: libc::timeval {
+                    tv_sec: 1,
+                    tv_usec: 0,
+                },
+                caplen: packet_data.len() as u32,
+                len,
+            };
+            let parsed = ParsedPacket::from_raw(&header, &packet_data, &pcap_meta).unwrap();
+            match parsed.transport {
+                TransportPacket::TCP { payload_len, .. } => {
+                    (parsed.total_length, payload_len, parsed.malformed_length)
+                }
+                other => panic!("Expected TCP packet, got {:?}", other),
+            }
+        };
+
+        // Shorter than the TCP header it carries
+        assert_eq!(parse(14 + 20 + 10), (44, 0, true));
+        // Shorter than the IP header
+        assert_eq!(parse(20), (20, 0, true));
+        // A GRO super-frame past 64 KiB
+        assert_eq!(parse(70_000), (u16::MAX, u16::MAX - 54, true));
+        // Well formed
+        assert_eq!(parse(14 + 20 + 20 + 1000), (1054, 1000, false));
+    }
+
+    #[test]
+    fn test_later_fragment_has_no_transport_header() {
+        // Second fragment of a UDP datagram, at offset 185 * 8 bytes
+        let mut packet_data = create_tcp_packet()[14..34].to_vec();
+        packet_data[4..10].copy_from_slice(&[0x00, 0x07, 0x00, 0xB9, 0x40, 0x11]);
+        // Would be read as ports 80 -> 80
+        packet_data.extend_from_slice(&[0x00, 0x50, 0x00, 0x50, 0x00, 0x08, 0x00, 0x00]);
+        let header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 1,
+                tv_usec: 0,
+            },
+            caplen: packet_data.len() as u32,
+            len: packet_data.len() as u32,
+        };
+
+        let pcap_meta = crate::listener::capture::PCAPMeta {
+            ipv4: Ipv4Addr::new(127, 0, 0, 2),
+            name: "tun0".to_string(),
+            link_type: LinkType::Raw,
+            ..crate::listener::capture::PCAPMeta::for_test()
+        };
+
+        let parsed = ParsedPacket::from_raw(&header, &packet_data, &pcap_meta).unwrap();
+        assert_eq!(parsed.transport, TransportPacket::OTHER { protocol: 17 });
+        let fragment = parsed.fragment.unwrap();
+        assert_eq!((fragment.id, fragment.offset, fragment.more), (7, 1480, false));
+        assert_eq!(parsed.get_src_dst_port(), None);
+    }
 }
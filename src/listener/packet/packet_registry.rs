@@ -1,13 +1,16 @@
+use std::str::FromStr;
+
 use crate::tcp_tracker::Burst;
 
-use super::estimation::{GinGout, PABWESender};
-use std::time::SystemTime;
+use super::estimation::{AbwEstimate, GinGout, PABWESender};
+use super::incremental::IncrementalAbw;
+use crate::Timestamp;
 
 /// Type of regression to use in passive bandwidth estimation.
 ///
 /// - `Simple`: Ordinary least squares regression.
 /// - `RLS`: Robust least squares regression (IRLS with Huber weight).
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RegressionType {
     /// RLS (Robust Least Squares) regression.
     RLS,
@@ -15,6 +18,30 @@ pub enum RegressionType {
     Simple,
 }
 
+impl RegressionType {
+    /// Every estimator, in the order they are reported.
+    pub const ALL: [RegressionType; 2] = [RegressionType::Simple, RegressionType::RLS];
+
+    /// Name as used in the config file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegressionType::RLS => "rls",
+            RegressionType::Simple => "simple",
+        }
+    }
+}
+
+impl FromStr for RegressionType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RegressionType::ALL
+            .into_iter()
+            .find(|r| r.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow::anyhow!("Unknown estimator {}", s))
+    }
+}
+
 /// Registry for tracking packet statistics over time.
 ///
 /// Stores RTT samples, burst throughputs, and uses a PABWE sender
@@ -22,17 +49,28 @@ pub enum RegressionType {
 #[derive(Debug)]
 pub struct PacketRegistry {
     /// Vector of round-trip times (RTTs) in microseconds.
-    pub rtts: Vec<(u32, SystemTime)>,
+    pub rtts: Vec<(u32, Timestamp)>,
     /// Sum of RTTs and the count of RTT samples.
     pub sum_rtt: (f64, u32),
     /// Vector of burst throughput values in bytes.
     pub burst_thput: Vec<f64>,
+    /// Throughput and mean RTT in microseconds of each TCP burst with RTT
+    /// samples.
+    pub burst_rtts: Vec<(f64, f64)>,
     /// PABWE sender instance for bandwidth estimation.
     pub pgm_estimator: PABWESender,
+    /// Estimate over the points in `pgm_estimator`, updated with each burst.
+    incremental: IncrementalAbw,
     /// Minimum RTT value and its corresponding timestamp.
-    min_rtt: (f64, SystemTime),
-    /// Count of retransmissions.
+    min_rtt: (f64, Timestamp),
+    /// Count of retransmissions of the packets with an RTT sample.
     retransmissions: u16,
+    /// Count of acknowledged TCP packets.
+    packets: u32,
+    /// Retransmissions of all acknowledged TCP packets, for `loss`.
+    retransmitted: u32,
+    /// Acknowledged TCP packets behind the GinGout points.
+    gap_packets: u32,
 }
 
 impl Default for PacketRegistry {
@@ -51,9 +89,14 @@ impl PacketRegistry {
             rtts: Vec::new(),
             sum_rtt: (0.0, 0),
             burst_thput: Vec::new(),
+            burst_rtts: Vec::new(),
             pgm_estimator: PABWESender::new(),
-            min_rtt: (f64::MAX, SystemTime::now()),
+            incremental: IncrementalAbw::from_config(),
+            min_rtt: (f64::MAX, Timestamp::ZERO),
             retransmissions: 0,
+            packets: 0,
+            retransmitted: 0,
+            gap_packets: 0,
         }
     }
 
@@ -73,14 +116,40 @@ impl PacketRegistry {
     /// - `RegressionType::Simple`: uses ordinary least squares.
     /// - `RegressionType::RLS`: uses robust IRLS regression.
     ///
-    /// Returns `(estimated_bw, used_data_points)`.
-    pub fn passive_abw(&mut self, regression_type: RegressionType) -> (Option<f64>, Vec<GinGout>) {
+    /// `phy_cap` in bytes/sec, e.g. measured with a ping train, replaces
+    /// `client.link_phy_cap` if set.
+    ///
+    /// Returns `(estimate, used_data_points)`.
+    pub fn passive_abw(
+        &mut self,
+        regression_type: RegressionType,
+        phy_cap: Option<f64>,
+    ) -> (Option<AbwEstimate>, Vec<GinGout>) {
+        if let Some(phy_cap) = phy_cap {
+            let filter = &crate::CONFIG.filter;
+            return self.pgm_estimator.estimate_abw(regression_type, filter, phy_cap);
+        }
         match regression_type {
             RegressionType::RLS => self.pgm_estimator.passive_pgm_abw_rls(),
             RegressionType::Simple => self.pgm_estimator.passive_pgm_abw(),
         }
     }
 
+    /// Estimate of the points since they were last taken, without refitting
+    /// them. Leaves out the input gap quantile and MAD filter stages, see
+    /// `IncrementalAbw`.
+    pub fn running_abw(&self, regression_type: RegressionType) -> Option<AbwEstimate> {
+        self.incremental.estimate(regression_type)
+    }
+
+    /// Takes the gin/gout points, starting the running estimate and the
+    /// count of the packets behind them over.
+    pub fn take_gap_points(&mut self) -> Vec<GinGout> {
+        self.incremental.reset();
+        self.gap_packets = 0;
+        std::mem::take(&mut self.pgm_estimator.dps)
+    }
+
     /// Takes the current registry, replacing it with the default instance.
     ///
     /// Returns the previous state
@@ -95,7 +164,8 @@ impl PacketRegistry {
     /// Ignores other burst types.
     pub fn extend(&mut self, values: Burst) {
         // Record burst throughput regardless of type
-        self.burst_thput.push(values.throughput());
+        let throughput = values.throughput();
+        self.burst_thput.push(throughput);
         // Only process TCP bursts for detailed stats
         match values {
             Burst::Tcp(burst) => {
@@ -107,29 +177,49 @@ impl PacketRegistry {
                                 Some((gin, gout, total_length)) => (gin, gout, total_length),
                                 None => continue,
                             };
-                        self.pgm_estimator.push(GinGout {
-                            gin: gin / ack.len() as f64,
-                            gout: gout / ack.len() as f64,
-                            len: total_length as f64 / ack.len() as f64,
-                            num_acked: ack.len() as u8,
+                        // Excluded super-frames leave no point, split ones
+                        // count as the segments they stand for
+                        if ack.iter().any(|p| p.segments == 0) {
+                            last_ack = Some(ack.ack_time);
+                            continue;
+                        }
+                        let segments = ack.iter().map(|p| p.segments as u32).sum::<u32>();
+                        let dp = GinGout {
+                            gin: gin / segments as f64,
+                            gout: gout / segments as f64,
+                            len: total_length as f64 / segments as f64,
+                            num_acked: segments.min(u8::MAX as u32) as u8,
                             timestamp: ack.ack_time,
-                        });
+                            app_limited: ack.iter().any(|p| p.app_limited()),
+                        };
+                        self.incremental.push(&dp);
+                        self.pgm_estimator.push(dp);
+                        self.gap_packets += segments;
                     }
                     last_ack = Some(ack.ack_time);
                 }
                 // Record RTTs and retransmissions
+                let mut burst_rtt = (0.0, 0);
                 burst.iter().for_each(|p| {
-                    if p.rtt.is_some() {
+                    self.packets += 1;
+                    self.retransmitted += p.retransmissions as u32;
+                    if let Some(rtt) = p.rtt() {
                         self.min_rtt = (
-                            self.min_rtt.0.min(p.rtt.unwrap().as_micros() as f64),
+                            self.min_rtt.0.min(rtt.as_micros() as f64),
                             p.sent_time,
                         );
-                        self.sum_rtt.0 += p.rtt.unwrap().as_micros() as f64;
+                        self.sum_rtt.0 += rtt.as_micros() as f64;
                         self.sum_rtt.1 += 1;
-                        self.retransmissions += p.retransmissions as u16;
-                        self.rtts.push((p.rtt.unwrap().as_micros() as u32, p.sent_time));
+                        self.retransmissions =
+                            self.retransmissions.saturating_add(p.retransmissions as u16);
+                        burst_rtt.0 += rtt.as_micros() as f64;
+                        burst_rtt.1 += 1;
+                        self.rtts.push((rtt.as_micros() as u32, p.sent_time));
                     }
                 });
+                if burst_rtt.1 > 0 {
+                    self.burst_rtts.push((throughput, burst_rtt.0 / burst_rtt.1 as f64));
+                }
             }
             _ => {}
         }
@@ -144,11 +234,26 @@ impl PacketRegistry {
         }
     }
 
-    /// Returns total retransmissions observed.
+    /// Acknowledged TCP packets that went into GinGout points.
+    pub fn gap_packets(&self) -> u32 {
+        self.gap_packets
+    }
+
+    /// Returns the retransmissions of the packets with an RTT sample.
     pub fn retransmissions(&self) -> u16 {
         self.retransmissions
     }
 
+    /// Share of transmissions that were retransmissions, in percent, or
+    /// `None` if no TCP packets were acknowledged.
+    pub fn loss(&self) -> Option<f64> {
+        if self.packets == 0 {
+            return None;
+        }
+        let retransmitted = self.retransmitted as f64;
+        Some(100.0 * retransmitted / (self.packets as f64 + retransmitted))
+    }
+
     /// Returns the average burst throughput (bytes/sec), or `None` if none recorded.
     pub fn avg_burst_thp(&self) -> Option<f64> {
         if self.burst_thput.is_empty() {
@@ -189,17 +294,27 @@ mod tests {
         let empty = Burst::Tcp(TcpBurst { packets: Vec::new() });
         reg.extend(empty);
         assert_eq!(reg.retransmissions(), 0);
+        assert_eq!(reg.loss(), None);
         assert_eq!(reg.burst_thput.len(), 1);
         assert!(reg.avg_burst_thp().is_some());
     }
 
+    #[test]
+    fn test_regression_type_names() {
+        for r in RegressionType::ALL {
+            assert_eq!(r.name().parse::<RegressionType>().unwrap(), r);
+        }
+        assert_eq!("RLS".parse::<RegressionType>().unwrap(), RegressionType::RLS);
+        assert!("kalman".parse::<RegressionType>().is_err());
+    }
+
     #[test]
     fn test_passive_abw_empty() {
         let mut reg = PacketRegistry::new();
-        let (bw_simple, pts_simple) = reg.passive_abw(RegressionType::Simple);
+        let (bw_simple, pts_simple) = reg.passive_abw(RegressionType::Simple, None);
         assert!(bw_simple.is_none());
         assert!(pts_simple.is_empty());
-        let (bw_rls, pts_rls) = reg.passive_abw(RegressionType::RLS);
+        let (bw_rls, pts_rls) = reg.passive_abw(RegressionType::RLS, None);
         assert!(bw_rls.is_none());
         assert!(pts_rls.is_empty());
     }
@@ -51,22 +51,33 @@ impl TransportPacket {
     /// Parses a transport packet from raw payload bytes, given the IP protocol
     /// and total payload length (including headers).
     ///
-    /// Falls back to `OTHER` if parsing fails or protocol unsupported.
-    pub fn from_data(payload: &[u8], protocol: IpNextHeaderProtocol, payload_len: u16) -> Self {
-        match protocol {
+    /// Falls back to `OTHER` if parsing fails or protocol unsupported. A TCP
+    /// length shorter than its header gives a payload of 0, and true along
+    /// with the packet.
+    pub fn from_data(
+        payload: &[u8],
+        protocol: IpNextHeaderProtocol,
+        payload_len: u16,
+    ) -> (Self, bool) {
+        let mut malformed = false;
+        let packet = match protocol {
             IpNextHeaderProtocols::Tcp => {
                 let tcp = match TcpPacket::new(payload) {
                     Some(tcp) => tcp,
                     None => {
                         log::warn!("Failed to parse TCP packet");
-                        return TransportPacket::OTHER {
+                        let other = TransportPacket::OTHER {
                             protocol: protocol.0,
                         };
+                        return (other, false);
                     }
                 };
 
                 let hdr_size = tcp.get_data_offset() as u16 * 4;
-                let payload_len = payload_len - hdr_size as u16;
+                let payload_len = payload_len.checked_sub(hdr_size).unwrap_or_else(|| {
+                    malformed = true;
+                    0
+                });
 
                 TransportPacket::TCP {
                     sequence: tcp.get_sequence(),
@@ -84,9 +95,10 @@ impl TransportPacket {
                     Some(udp) => udp,
                     None => {
                         log::warn!("Failed to parse UDP packet");
-                        return TransportPacket::OTHER {
+                        let other = TransportPacket::OTHER {
                             protocol: protocol.0,
                         };
+                        return (other, false);
                     }
                 };
                 TransportPacket::UDP {
@@ -99,7 +111,8 @@ impl TransportPacket {
             _ => TransportPacket::OTHER {
                 protocol: protocol.0,
             },
-        }
+        };
+        (packet, malformed)
     }
 }
 
@@ -240,14 +253,14 @@ mod tests {
     fn test_from_data_udp_success() {
         // 8-byte UDP header: src=80, dst=443, len=8, checksum=0
         let buf = [0x00,0x50, 0x01,0xbb, 0x00,0x08, 0x00,0x00];
-        let pkt = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Udp, 8);
+        let (pkt, _) = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Udp, 8);
         assert_eq!(pkt, TransportPacket::UDP { src_port:80, dst_port:443, payload_len:8 });
     }
 
     #[test]
     fn test_from_data_udp_fail() {
         let buf = [0u8;4];
-        let pkt = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Udp, 4);
+        let (pkt, _) = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Udp, 4);
         if let TransportPacket::OTHER { protocol } = pkt { assert_eq!(protocol, IpNextHeaderProtocols::Udp.0); } else { panic!("Expected OTHER"); }
     }
 
@@ -262,7 +275,8 @@ mod tests {
         buf[12] = 5 << 4; // data offset = 5
         buf[13] = TcpFlags::ACK;
         buf[14..16].copy_from_slice(&3u16.to_be_bytes()); // window size
-        let pkt = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Tcp, 20);
+        let (pkt, malformed) = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Tcp, 20);
+        assert!(!malformed);
         if let TransportPacket::TCP { sequence, acknowledgment, flags, payload_len, options, src_port, dst_port, window_size } = pkt {
             assert_eq!(src_port, 80);
             assert_eq!(dst_port, 443);
@@ -280,7 +294,7 @@ mod tests {
     #[test]
     fn test_from_data_tcp_fail() {
         let buf = [0u8;10];
-        let pkt = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Tcp, 10);
+        let (pkt, _) = TransportPacket::from_data(&buf, IpNextHeaderProtocols::Tcp, 10);
         if let TransportPacket::OTHER { protocol } = pkt { assert_eq!(protocol, IpNextHeaderProtocols::Tcp.0); } else { panic!("Expected OTHER"); }
     }
 
@@ -3,28 +3,42 @@ use std::str::FromStr;
 
 use crate::probe::iperf_json::IperfResponse;
 use crate::prost_net::bandwidth_client::{ClientEventResult, ClientHandlerEvent};
+use crate::prost_net::bandwidth_server::PbfMsg;
+use crate::proto_bw::{HelloRequest, NodeStatus};
 use crate::CONFIG;
 
+use super::coverage::CoverageWindows;
+use super::filter::{FilterChain, SubnetRules};
+use super::load::{shed_cleanup_interval, LoadMonitor};
+use super::packet::FragmentTable;
+use super::packet::neighbor::NeighborPacket;
+use super::reorder::ReorderBuffer;
 use super::procfs_reader::{self, get_interface, get_interface_info, NetStat};
 use super::tracking::link::LinkManager;
+use crate::tap::{LinkStateConsumers, Tap};
 
 use crate::{
-    stream_id::from_iperf_connected, CapEvent, CapEventReceiver, OwnedPacket, PCAPMeta,
-    ParsedPacket, Settings,
+    CapEvent, CapEventReceiver, Direction, OwnedPacket, PCAPMeta, ParsedPacket, Timestamp,
 };
 use anyhow::Result;
 use log::{error, info};
 use neli_wifi::{Bss, Station};
-use pnet::packet::ip::IpNextHeaderProtocols;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 use tokio::{
     sync::mpsc::{channel, Receiver, Sender},
+    sync::watch,
     time,
 };
 
 const CHANNEL_CAPACITY: usize = 10; // Capacity for most MPSC channels in number of messages.
 
+/// Message intervals are not checked more often than the report tick.
+fn report_interval(interval: Duration) -> Duration {
+    interval.max(CONFIG.tracking.report_tick)
+}
+
 #[derive(Debug)]
 pub struct NetlinkData {
     /// List of currently connected stations
@@ -48,7 +62,19 @@ pub struct PeriodicData {
 pub struct Parser {
     packet_stream: CapEventReceiver,
     pcap_meta: Arc<PCAPMeta>,
+    /// Updates to `pcap_meta` published by the capturer.
+    meta_rx: watch::Receiver<PCAPMeta>,
     link_manager: LinkManager,
+    /// Puts packets back in capture order before they reach the trackers.
+    reorder: ReorderBuffer,
+    /// Ports of fragmented datagrams, for their later fragments.
+    fragments: FragmentTable,
+    /// Drops packets before they reach the `LinkManager`.
+    filters: FilterChain,
+    /// Packets through each stage, per measurement window.
+    coverage: CoverageWindows,
+    /// Depth of `packet_stream`, for load shedding.
+    load: LoadMonitor,
     netlink_data: Vec<NetlinkData>,
     netstat_data: Option<NetStat>,
     crx: Receiver<ClientEventResult>,
@@ -60,8 +86,10 @@ impl Parser {
     /// # Arguments
     ///
     /// * `packet_stream` – channel receiving `CapEvent`s, including packets, ping, pathload, etc.
-    /// * `pcap_meta` – metadata about this host’s capture interface (MAC, IP).
+    /// * `meta_rx` – metadata about this host’s capture interface (MAC, IP),
+    ///   updated when the addresses change.
     /// * `client_sender` – channel sender for pushing `ClientHandlerEvent`s (e.g. to the gRPC client).
+    /// * `subnets` – subnet rules for the "subnets" packet filter, updated at runtime.
     ///
     /// # Returns
     ///
@@ -70,16 +98,28 @@ impl Parser {
     pub fn new(
         packet_stream: CapEventReceiver,
         // "Metadata" from the pcap capture, aka this devices MAC and IP addresses
-        pcap_meta: Arc<PCAPMeta>,
+        mut meta_rx: watch::Receiver<PCAPMeta>,
         client_sender: Sender<ClientHandlerEvent>,
+        tap: Tap,
+        subnets: watch::Receiver<SubnetRules>,
     ) -> Result<(Self, Sender<ClientEventResult>)> {
         let (ctx, crx): (Sender<ClientEventResult>, Receiver<ClientEventResult>) =
             channel(CHANNEL_CAPACITY);
+        let pcap_meta = Arc::new(meta_rx.borrow_and_update().clone());
+        let mut link_manager = LinkManager::new(client_sender, pcap_meta.clone());
+        link_manager.set_tap(tap);
+        let load = LoadMonitor::from_config(packet_stream.max_capacity());
         Ok((
             Parser {
                 packet_stream,
                 pcap_meta: pcap_meta.clone(),
-                link_manager: LinkManager::new(client_sender, pcap_meta.clone()),
+                meta_rx,
+                link_manager,
+                reorder: ReorderBuffer::default(),
+                fragments: FragmentTable::new(),
+                filters: FilterChain::from_config(subnets),
+                coverage: CoverageWindows::new(),
+                load,
                 netlink_data: Vec::new(),
                 netstat_data: None,
                 crx,
@@ -88,6 +128,12 @@ impl Parser {
         ))
     }
 
+    /// Hands every link state reported to `consumers`, see
+    /// `tap::LinkStateConsumer`.
+    pub fn set_link_state_consumers(&mut self, consumers: LinkStateConsumers) {
+        self.link_manager.set_consumers(consumers);
+    }
+
     /// Spawn the parser’s main loop onto the Tokio runtime.
     ///
     /// Returns a `JoinHandle` which can be `.await`ed or `.abort()`ed.
@@ -103,6 +149,7 @@ impl Parser {
     ///  - periodic system polls,
     ///  - client replies (e.g. iperf ready notifications),
     ///  - cleanup intervals,
+    ///  - load shedding, see `listener::load`,
     ///  - measurement windows triggering bandwidth reports (as well as
     /// rtt/packet gap data reports if enabled).
     pub async fn start(mut self) {
@@ -126,35 +173,112 @@ impl Parser {
         // Spawn the periodic poller
         let (ptx, mut prx): (Sender<PeriodicData>, Receiver<PeriodicData>) =
             channel(CHANNEL_CAPACITY);
+        let (shed_tx, shed_rx) = watch::channel(false);
 
         let periodic_handle = tokio::spawn(async move {
-            Parser::periodic(ptx, idx).await;
+            Parser::periodic(ptx, idx, shed_rx).await;
         });
 
         // Set up timers
         let mut measurement_window = time::interval(CONFIG.client.measurement_window);
-        let mut interval = time::interval(Settings::CLEANUP_INTERVAL);
+        let mut report_tick = time::interval(CONFIG.tracking.report_tick);
+        // Each message type goes out on its own schedule
+        let mut link_state_tick = time::interval(report_interval(CONFIG.server.link_state_interval));
+        let mut rtt_tick = time::interval(report_interval(CONFIG.server.rtt_interval));
+        let mut pgm_tick = time::interval(report_interval(CONFIG.server.pgm_interval));
+        let mut clock_tick = time::interval(report_interval(CONFIG.client.clock_sync_interval));
+        let mut ping_tick = time::interval(report_interval(CONFIG.probe.ping_interval));
+        let mut ping_train_tick =
+            time::interval(report_interval(CONFIG.probe.ping_train_interval));
+        let mut feature_tick = time::interval(report_interval(CONFIG.features.interval));
+        let mut interval = time::interval(CONFIG.tracking.cleanup_interval);
 
         loop {
             tokio::select! {
                 // Received MPSC data from the packet capture or another source
                 // Some of these events remains unused, but are kept for future use
                 Some(cap_ev) = self.packet_stream.recv() => {
+                    if let Some(shedding) = self.load.update(self.packet_stream.len()) {
+                        self.link_manager.set_load_shedding(shedding);
+                        shed_tx.send_replace(shedding);
+                        let period = self.load.cleanup_interval();
+                        interval = time::interval_at(time::Instant::now() + period, period);
+                    }
                     match cap_ev {
                         CapEvent::Packet(packet) => {
                             self.handle_capture(packet);
+                            self.release_packets();
+                        }
+                        CapEvent::Parsed(packet) => {
+                            self.coverage.current.record_captured(Some(&packet));
+                            self.reorder.push(packet, Instant::now());
+                            self.release_packets();
+                        }
+                        CapEvent::Neighbor(packet) => {
+                            self.link_manager.insert_neighbor(packet);
                         }
                         CapEvent::IperfResponse(data) => {
                             self.handle_iperf(data);
                         }
                         CapEvent::Protobuf(pbf) => {
                             info!("Received protobuf: {:?}", pbf);
+                            if let PbfMsg::HelloRequest(
+                                HelloRequest {
+                                    identity: Some(identity),
+                                    ..
+                                },
+                                Some(from),
+                            ) = &pbf
+                            {
+                                self.link_manager.record_peer_identity(identity, *from);
+                            }
                         }
-                        CapEvent::PathloadResponse(s) => {
-                            info!("Received pathload response: {:?}", s);
+                        CapEvent::ProbeSession(session) => {
+                            info!(
+                                "Probe session {} ({}) with {} for {:?}",
+                                session.id, session.technique, session.peer, session.duration
+                            );
+                            self.link_manager.register_probe(session);
                         }
-                        CapEvent::PingResponse(res) => {
-                            info!("Received ping response: {:?}", res);
+                        CapEvent::Probe(result) => {
+                            self.link_manager.insert_probe_result(result);
+                        }
+                        CapEvent::SetEstimator(estimator) => {
+                            self.link_manager.set_estimator(estimator);
+                        }
+                        CapEvent::Status(reply) => {
+                            let mut status = NodeStatus {
+                                load_shedding: self.load.is_shedding(),
+                                ..Default::default()
+                            };
+                            status
+                                .tables
+                                .insert(String::from("reorder_buffer"), self.reorder.len() as u64);
+                            status
+                                .tables
+                                .insert(String::from("fragments"), self.fragments.len() as u64);
+                            status.dropped.extend(
+                                self.filters
+                                    .dropped()
+                                    .map(|(name, dropped)| (name.to_string(), dropped)),
+                            );
+                            status.coverage = self.coverage.last().to_map();
+                            status
+                                .queues
+                                .insert(String::from("capture"), self.packet_stream.len() as u64);
+                            status
+                                .queues
+                                .insert(String::from("client_replies"), self.crx.len() as u64);
+                            self.link_manager.send_status(status, reply).await;
+                        }
+                        CapEvent::Estimates(remote, reply) => {
+                            let estimates = match remote {
+                                Some(remote) => {
+                                    self.link_manager.estimate(remote).into_iter().collect()
+                                }
+                                None => self.link_manager.estimates(),
+                            };
+                            let _ = reply.send(estimates);
                         }
                         CapEvent::Error(e) => {
                             error!("Error received: {:?}", e);
@@ -162,6 +286,15 @@ impl Parser {
                     }
                 },
 
+                // Local addresses changed (DHCP renewal, reconfiguration, ...)
+                Ok(()) = self.meta_rx.changed() => {
+                    // Existing links keep their state, links keyed on the
+                    // old address will time out on their own.
+                    self.pcap_meta = Arc::new(self.meta_rx.borrow_and_update().clone());
+                    info!("Interface metadata changed: {:?}", self.pcap_meta);
+                    self.link_manager.set_pcap_meta(self.pcap_meta.clone());
+                },
+
                 // Received netlink/procfs data from the periodic poller
                 Some(periodic_data) = prx.recv() => {
                     self.handle_periodic(periodic_data);
@@ -173,20 +306,85 @@ impl Parser {
                         ClientEventResult::ServerConnected(ip) => {
                             self.link_manager.add_important_link(IpAddr::from_str(ip.as_str()));
                         },
+                        ClientEventResult::HelloReply(ip, Ok(reply)) => {
+                            match IpAddr::from_str(&ip) {
+                                Ok(from) => self.link_manager.record_peer_capabilities(reply, from),
+                                Err(_) => info!("Hello reply from invalid address {}", ip),
+                            }
+                        },
+                        ClientEventResult::ClockSample(ip, sample) => {
+                            if let Ok(ip) = IpAddr::from_str(&ip) {
+                                self.link_manager.record_clock_sample(ip, sample);
+                            }
+                        },
+                        ClientEventResult::PeerRemoved(ip) => {
+                            info!("Peer {} removed", ip);
+                            self.link_manager.remove_important_link(ip);
+                        },
+                        ClientEventResult::ProbeStatus { ip, technique, status } => {
+                            info!("{} probe to {}: {:?}", technique, ip, status);
+                        },
                         _ => info!("Received reply: {:?}", reply),
                     }
                 },
 
                 // Routine cleanup
                 _ = interval.tick() => {
+                    let late = self.reorder.take_late();
+                    if late > 0 {
+                        info!("{} packets arrived out of order past the reorder window", late);
+                    }
+                    let now = Timestamp::now();
+                    self.fragments.prune(now);
+                    self.filters.prune(now);
                     self.link_manager.periodic().await;
                 },
 
-                // Trigger bandwidth summary reporting
+                // Let the client handler connect to newly seen peers
                 _ = measurement_window.tick() => {
-                    self.link_manager.send_bandwidth().await;
+                    self.coverage.current.skipped_groups = self.link_manager.take_skipped_groups();
+                    self.coverage.current.super_frames = self.link_manager.take_super_frames();
+                    self.coverage.rotate();
                     self.link_manager.send_init_clients_msg().await;
                 },
+
+                // Probe peers that are due
+                _ = report_tick.tick() => {
+                    self.release_packets();
+                    self.link_manager.schedule_vip_probes().await;
+                    self.link_manager.send_events().await;
+                },
+
+                // Report links whose window is up
+                _ = link_state_tick.tick() => {
+                    self.release_packets();
+                    self.link_manager.send_bandwidth().await;
+                },
+
+                _ = rtt_tick.tick() => {
+                    self.link_manager.send_rtts().await;
+                },
+
+                _ = pgm_tick.tick() => {
+                    self.link_manager.send_pgm().await;
+                },
+
+                _ = clock_tick.tick() => {
+                    self.link_manager.sync_clocks().await;
+                },
+
+                _ = ping_tick.tick() => {
+                    self.link_manager.ping_peers().await;
+                },
+
+                _ = ping_train_tick.tick() => {
+                    self.link_manager.send_ping_trains().await;
+                },
+
+                _ = feature_tick.tick() => {
+                    self.release_packets();
+                    self.link_manager.export_features();
+                },
                 else => {
                     // Both streams have ended
                     self.stop(vec![periodic_handle]).await;
@@ -204,13 +402,22 @@ impl Parser {
     }
 
     /// Periodically polls procfs and netlink at the given interface index.
+    /// Netlink is skipped, and polls are further apart, while `shedding`.
     ///
     /// Sends `PeriodicData` to the provided channel until it is closed.
-    async fn periodic(tx: Sender<PeriodicData>, idx: Option<i32>) {
+    async fn periodic(
+        tx: Sender<PeriodicData>,
+        idx: Option<i32>,
+        shedding: watch::Receiver<bool>,
+    ) {
         loop {
-            let netstat = procfs_reader::proc_net().await;
+            let shed = *shedding.borrow();
+            // Owners take a walk through every process, skipped while shedding
+            let netstat = procfs_reader::proc_net(CONFIG.client.flow_owners && !shed).await;
             let interface = match idx {
-                Some(idx) => Some(get_interface_info(idx).await.unwrap()),
+                Some(_) if shed => None,
+                // The interface may be gone for a while, skip the sample then.
+                Some(idx) => get_interface_info(idx).await.ok(),
                 None => None,
             };
 
@@ -223,13 +430,18 @@ impl Parser {
                 break;
             }
 
-            time::sleep(Settings::CLEANUP_INTERVAL).await;
+            let pause = if shed {
+                shed_cleanup_interval()
+            } else {
+                CONFIG.tracking.cleanup_interval
+            };
+            time::sleep(pause).await;
         }
     }
 
 
     /// Integrate a new `PeriodicData` sample into our sliding windows.
-    fn handle_periodic(&mut self, data: PeriodicData) {
+    fn handle_periodic(&mut self, mut data: PeriodicData) {
         match data.netlink_data {
             Some(data) => self.netlink_data.push(data),
             _ => (),
@@ -238,51 +450,70 @@ impl Parser {
             self.netlink_data.remove(0);
         }
 
+        let owners = std::mem::take(&mut data.netstat_data.owners);
+        if !owners.is_empty() {
+            self.link_manager.update_flow_owners(owners);
+        }
         self.netstat_data = Some(data.netstat_data);
     }
 
     /// Parse and forward a single captured packet to the `LinkManager`.
     fn handle_capture(&mut self, packet: OwnedPacket) {
         // Handle the captured packet
-        let parsed_packet = match ParsedPacket::from_packet(&packet, &self.pcap_meta) {
-            Some(packet) => packet,
-            None => return,
-        };
+        let parsed_packet = ParsedPacket::from_packet(&packet, &self.pcap_meta);
+        self.coverage.current.record_captured(parsed_packet.as_ref());
+
+        // ARP is not IP, and ND is also counted as IP traffic
+        if parsed_packet.as_ref().map_or(true, ParsedPacket::is_icmpv6) {
+            if let Some(neighbor) =
+                NeighborPacket::from_raw(&packet.header, &packet.data, &self.pcap_meta)
+            {
+                self.link_manager.insert_neighbor(neighbor);
+            }
+        }
 
-        self.link_manager.insert(parsed_packet);
+        if let Some(parsed_packet) = parsed_packet {
+            self.reorder.push(parsed_packet, Instant::now());
+        }
     }
 
-    /// Handle an iperf JSON response, extract throughput, and forward to the `LinkManager`.
-    fn handle_iperf(&mut self, iperf_data: IperfResponse) {
-        match iperf_data {
-            IperfResponse::Error(_) => {
-                // Do nothing for now
+    /// Forward packets that have been held long enough to the `LinkManager`,
+    /// unless the `FilterChain` drops them.
+    fn release_packets(&mut self) {
+        let now = Instant::now();
+        while let Some(mut packet) = self.reorder.pop_ready(now) {
+            // Before the port filters, later fragments have no ports
+            self.fragments.attribute(&mut packet);
+            match self.filters.dropped_by(&packet) {
+                Some(filter) => self.coverage.current.record_filtered(filter),
+                None => {
+                    self.coverage.current.record_tracked();
+                    self.link_manager.insert(packet);
+                }
             }
-            IperfResponse::Success(s) => {
-                let connected = s.start.connected;
-                if connected.len() == 1 {
-                    let (_, ip_pair) = from_iperf_connected(
-                        connected.first().unwrap(),
-                        IpNextHeaderProtocols::Tcp,
-                    );
-
-                    let mut stream = None;
-                    if s.end.sum_sent.sender == true {
-                        // We are the client.
-                        if let Some(strm) = s.end.streams.first().take() {
-                            stream = Some(strm);
-                        }
-                    }
+        }
+    }
 
-                    self.link_manager.insert_iperf_result(
-                        ip_pair,
-                        s.end
-                            .sum_received
-                            .bits_per_second
-                            .max(s.end.sum_sent.bits_per_second),
-                        stream,
-                    ); // ! FIXME This is a hack
-                }
+    /// Handle an iperf JSON response from either end of a test and forward
+    /// its throughput to the `LinkManager`, in the direction the data went.
+    fn handle_iperf(&mut self, iperf_data: IperfResponse) {
+        let s = match iperf_data {
+            IperfResponse::Error(e) => {
+                info!("Iperf test failed: {}", e.error);
+                return;
+            }
+            IperfResponse::Success(s) => s,
+        };
+        let direction = match s.sends() {
+            true => Direction::Outgoing,
+            false => Direction::Incoming,
+        };
+        let bps = s.bits_per_second();
+        for ip_pair in s.links() {
+            self.link_manager.insert_iperf_result(ip_pair, bps, direction);
+            if let Some(udp) = s.end.udp() {
+                self.link_manager
+                    .insert_iperf_udp_result(ip_pair, udp.jitter_ms, udp.lost_percent);
             }
         }
     }
@@ -9,6 +9,7 @@ use super::{
 use neli_wifi::{AsyncSocket, Interface};
 use pnet::packet::ip::IpNextHeaderProtocols;
 use procfs::net::{TcpNetEntry, UdpNetEntry};
+use procfs::process::FDTarget;
 use std::error::Error;
 
 /// Represents a single network connection entry, either TCP or UDP,
@@ -26,6 +27,51 @@ pub enum NetEntry {
 pub struct NetStat {
     pub tcp: HashMap<(StreamKey, IpPair), NetEntry>,
     pub udp: HashMap<(StreamKey, IpPair), NetEntry>,
+    /// Process holding the socket of each connection, only filled if asked
+    /// for. Processes of other users are missing without root.
+    pub owners: HashMap<(StreamKey, IpPair), SocketOwner>,
+}
+
+/// A process with a socket open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOwner {
+    pub pid: i32,
+    /// Command name, as in /proc/<pid>/comm.
+    pub name: String,
+}
+
+/// Maps socket inodes to the processes holding them, from /proc/*/fd. A
+/// socket shared by several processes, e.g. after a fork, goes to one of
+/// them.
+pub fn socket_owners() -> HashMap<u64, SocketOwner> {
+    let mut owners = HashMap::new();
+    let Ok(processes) = procfs::process::all_processes() else {
+        return owners;
+    };
+    // Processes may exit while being read, or not be readable at all
+    for process in processes.filter_map(|res| res.ok()) {
+        let Ok(fds) = process.fd() else {
+            continue;
+        };
+        let Ok(stat) = process.stat() else {
+            continue;
+        };
+        for fd in fds.filter_map(|res| res.ok()) {
+            if let FDTarget::Socket(inode) = fd.target {
+                owners.entry(inode).or_insert_with(|| SocketOwner {
+                    pid: process.pid,
+                    name: stat.comm.clone(),
+                });
+            }
+        }
+    }
+    owners
+}
+
+/// Addresses of IPv4 connections on IPv6 sockets are IPv4-mapped, but the
+/// packets are IPv4.
+fn canonical((key, pair): (StreamKey, IpPair)) -> (StreamKey, IpPair) {
+    (key, IpPair::new(pair.local().to_canonical(), pair.remote().to_canonical()))
 }
 
 /// Asynchronously reads and parses network connection tables from procfs.
@@ -35,32 +81,38 @@ pub struct NetStat {
 /// - `/proc/net/udp` and `/proc/net/udp6`
 ///
 /// Each raw entry is converted into a `NetEntry` and inserted into a `NetStat`.
+/// With `with_owners`, the process behind each connection is looked up too,
+/// see `socket_owners`.
 ///
 /// # Returns
 /// A `NetStat` containing the current snapshot of TCP and UDP connections.
-pub async fn proc_net() -> NetStat {
+pub async fn proc_net(with_owners: bool) -> NetStat {
     let tcp = [procfs::net::tcp(), procfs::net::tcp6()];
     let udp = [procfs::net::udp(), procfs::net::udp6()];
 
     let entries = tcp.into_iter().filter_map(|res| res.ok()).flatten();
     let udp_entries = udp.into_iter().filter_map(|res| res.ok()).flatten();
 
-    let mut nstat = NetStat {
-        tcp: HashMap::new(),
-        udp: HashMap::new(),
+    let mut nstat = NetStat::default();
+    let inodes = if with_owners {
+        socket_owners()
+    } else {
+        HashMap::new()
     };
 
     for tcp_entry in entries {
-        nstat.tcp.insert(
-            from_tcp_net_entry(&tcp_entry, IpNextHeaderProtocols::Tcp),
-            NetEntry::Tcp { entry: tcp_entry },
-        );
+        let key = from_tcp_net_entry(&tcp_entry, IpNextHeaderProtocols::Tcp);
+        if let Some(owner) = inodes.get(&tcp_entry.inode) {
+            nstat.owners.insert(canonical(key), owner.clone());
+        }
+        nstat.tcp.insert(key, NetEntry::Tcp { entry: tcp_entry });
     }
     for udp_entry in udp_entries {
-        nstat.udp.insert(
-            from_udp_net_entry(&udp_entry, IpNextHeaderProtocols::Udp),
-            NetEntry::Udp { entry: udp_entry },
-        );
+        let key = from_udp_net_entry(&udp_entry, IpNextHeaderProtocols::Udp);
+        if let Some(owner) = inodes.get(&udp_entry.inode) {
+            nstat.owners.insert(canonical(key), owner.clone());
+        }
+        nstat.udp.insert(key, NetEntry::Udp { entry: udp_entry });
     }
     nstat
 }
@@ -107,3 +159,21 @@ pub async fn get_interface(device_name: &str) -> Result<Interface, Box<dyn Error
     }
     Err("Interface not found".into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socket_owners() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let netstat = proc_net(true).await;
+        let owner = netstat
+            .owners
+            .iter()
+            .find(|((key, _), _)| key.local_port() == Some(port))
+            .map(|(_, owner)| owner);
+        assert_eq!(owner.map(|o| o.pid), Some(std::process::id() as i32));
+    }
+}
@@ -0,0 +1,194 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+use crate::{ParsedPacket, Timestamp, CONFIG};
+
+/// Puts parsed packets back in capture order before they reach the trackers.
+///
+/// Packets are held for `window` of capture time, measured with the pcap
+/// timestamps. The capture clock is advanced between packets with the
+/// monotonic clock, so quiet links are still flushed. Released timestamps
+/// never go backwards: a packet arriving after a later one was released is
+/// clamped to that packet's timestamp, so gap and RTT computations never see
+/// a negative duration.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    heap: BinaryHeap<Reverse<Entry>>,
+    window: Duration,
+    capacity: usize,
+    /// Insertion counter, keeps packets with equal timestamps in arrival order.
+    seq: u64,
+    /// Newest capture timestamp seen and when it arrived.
+    newest: Option<(Timestamp, Instant)>,
+    /// Timestamp of the last released packet.
+    released: Option<Timestamp>,
+    /// Packets clamped since the last call to `take_late`.
+    late: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    timestamp: Timestamp,
+    seq: u64,
+    packet: ParsedPacket,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.seq).cmp(&(other.timestamp, other.seq))
+    }
+}
+
+impl Default for ReorderBuffer {
+    fn default() -> Self {
+        ReorderBuffer::new(CONFIG.tracking.reorder_window, CONFIG.tracking.reorder_capacity)
+    }
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        ReorderBuffer {
+            heap: BinaryHeap::new(),
+            window,
+            capacity,
+            seq: 0,
+            newest: None,
+            released: None,
+            late: 0,
+        }
+    }
+
+    pub fn push(&mut self, packet: ParsedPacket, now: Instant) {
+        let timestamp = packet.timestamp;
+        if self.newest.map_or(true, |(newest, _)| timestamp > newest) {
+            self.newest = Some((timestamp, now));
+        }
+        self.heap.push(Reverse(Entry {
+            timestamp,
+            seq: self.seq,
+            packet,
+        }));
+        self.seq += 1;
+    }
+
+    /// Pops the oldest packet if it has been held for the full window, or
+    /// if the buffer is over capacity.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<ParsedPacket> {
+        let Reverse(head) = self.heap.peek()?;
+        if self.heap.len() <= self.capacity {
+            let (newest, arrived) = self.newest?;
+            let capture_now = newest + now.saturating_duration_since(arrived);
+            if head.timestamp + self.window > capture_now {
+                return None;
+            }
+        }
+        self.heap.pop().map(|Reverse(entry)| self.release(entry))
+    }
+
+    /// Releases every buffered packet in order.
+    pub fn drain(&mut self) -> Vec<ParsedPacket> {
+        let mut packets = Vec::with_capacity(self.heap.len());
+        while let Some(Reverse(entry)) = self.heap.pop() {
+            packets.push(self.release(entry));
+        }
+        packets
+    }
+
+    /// Number of packets that arrived too late and were clamped.
+    pub fn take_late(&mut self) -> u64 {
+        std::mem::take(&mut self.late)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    fn release(&mut self, mut entry: Entry) -> ParsedPacket {
+        match self.released {
+            Some(released) if entry.timestamp < released => {
+                entry.packet.timestamp = released;
+                self.late += 1;
+            }
+            _ => self.released = Some(entry.timestamp),
+        }
+        entry.packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Direction;
+
+    fn packet(ms: u64) -> ParsedPacket {
+        let timestamp = Timestamp::from_millis(ms);
+        ParsedPacket::test_udp(4000, 5000, 100, timestamp, Direction::Outgoing)
+    }
+
+    fn millis(packet: &ParsedPacket) -> i64 {
+        packet.timestamp.as_millis()
+    }
+
+    #[test]
+    fn test_reorders_within_window() {
+        let mut buffer = ReorderBuffer::new(Duration::from_millis(20), 100);
+        let now = Instant::now();
+        buffer.push(packet(1010), now);
+        buffer.push(packet(1000), now);
+        buffer.push(packet(1005), now);
+        assert!(buffer.pop_ready(now).is_none());
+
+        // The capture clock moves on with the monotonic clock
+        let later = now + Duration::from_millis(30);
+        let order: Vec<i64> = std::iter::from_fn(|| buffer.pop_ready(later))
+            .map(|p| millis(&p))
+            .collect();
+        assert_eq!(order, vec![1000, 1005, 1010]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_late_packets_are_clamped() {
+        let mut buffer = ReorderBuffer::new(Duration::from_millis(20), 100);
+        let now = Instant::now();
+        buffer.push(packet(1000), now);
+        assert_eq!(millis(&buffer.drain()[0]), 1000);
+
+        // Arrives after a newer packet was released
+        buffer.push(packet(990), now);
+        assert_eq!(millis(&buffer.drain()[0]), 1000);
+        assert_eq!(buffer.take_late(), 1);
+        assert_eq!(buffer.take_late(), 0);
+    }
+
+    #[test]
+    fn test_capacity_forces_release() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        for ms in [1002, 1001, 1000] {
+            buffer.push(packet(ms), now);
+        }
+        assert_eq!(millis(&buffer.pop_ready(now).unwrap()), 1000);
+        assert!(buffer.pop_ready(now).is_none());
+        assert_eq!(buffer.len(), 2);
+    }
+}
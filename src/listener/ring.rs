@@ -0,0 +1,369 @@
+//! Rolling capture of the headers of the last few MB of packets, kept on
+//! disk as pcapng, so the packets behind an odd estimate can still be looked
+//! at after the fact. Each packet carries a comment with its link and
+//! direction.
+//!
+//! Written by a thread of its own to `client.ring_dir`, spread over
+//! `RING_SEGMENTS` files. The capture thread only copies the packets to it,
+//! and drops them for the ring if it falls behind. The oldest file is
+//! deleted when a new one is started, files left by earlier runs included.
+//! Files are named after the start of the capture, so a restart does not
+//! overwrite the packets that led up to it.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use pcap::Packet;
+
+use crate::anonymize::{export_frame, export_pair};
+use crate::listener::capture::{OwnedPacket, PCAPMeta};
+use crate::stream_id::IpPair;
+use crate::{ParsedPacket, Timestamp, CONFIG};
+
+/// Files the ring is spread over.
+const RING_SEGMENTS: u64 = 4;
+/// Packets waiting for the writer thread.
+const RING_QUEUE: usize = 4096;
+/// Longest time written packets may sit in the buffer.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const SHB_TYPE: u32 = 0x0A0D_0D0A;
+const IDB_TYPE: u32 = 0x0000_0001;
+const EPB_TYPE: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_TSRESOL: u16 = 9;
+
+/// Block of `block_type` around `body`, which must be padded to 32 bits.
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&block_type.to_ne_bytes());
+    block.extend_from_slice(&len.to_ne_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_ne_bytes());
+    block
+}
+
+/// Appends an option, padding its value to 32 bits.
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_ne_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_ne_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// Section header and the single interface, with nanosecond timestamps.
+fn file_header(link_type: u16, snaplen: u32) -> Vec<u8> {
+    let mut shb = Vec::new();
+    shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+    shb.extend_from_slice(&1u16.to_ne_bytes());
+    shb.extend_from_slice(&0u16.to_ne_bytes());
+    // Section length not known
+    shb.extend_from_slice(&(-1i64).to_ne_bytes());
+
+    let mut idb = Vec::new();
+    idb.extend_from_slice(&link_type.to_ne_bytes());
+    idb.extend_from_slice(&0u16.to_ne_bytes());
+    idb.extend_from_slice(&snaplen.to_ne_bytes());
+    push_option(&mut idb, IF_TSRESOL, &[9]);
+    push_option(&mut idb, OPT_END, &[]);
+
+    let mut header = block(SHB_TYPE, &shb);
+    header.extend(block(IDB_TYPE, &idb));
+    header
+}
+
+/// Enhanced packet block of the captured bytes in `data`.
+fn packet_block(timestamp: Timestamp, data: &[u8], orig_len: u32, comment: &str) -> Vec<u8> {
+    let nanos = timestamp.as_nanos();
+    let mut epb = Vec::with_capacity(data.len() + comment.len() + 32);
+    epb.extend_from_slice(&0u32.to_ne_bytes());
+    epb.extend_from_slice(&((nanos >> 32) as u32).to_ne_bytes());
+    epb.extend_from_slice(&(nanos as u32).to_ne_bytes());
+    epb.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+    epb.extend_from_slice(&orig_len.to_ne_bytes());
+    epb.extend_from_slice(data);
+    epb.resize(epb.len().next_multiple_of(4), 0);
+    if !comment.is_empty() {
+        push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+    }
+    block(EPB_TYPE, &epb)
+}
+
+/// Link and direction of a packet, empty if it is not IP.
+fn comment(packet: &OwnedPacket, meta: &PCAPMeta) -> String {
+    match ParsedPacket::from_raw(&packet.header, &packet.data, meta) {
+        Some(parsed) => format!(
+            "{} ({:?})",
+            export_pair(IpPair::from_packet(&parsed)),
+            parsed.direction
+        ),
+        None => String::new(),
+    }
+}
+
+/// Start of the capture and index of a ring file, None if `path` is not one.
+fn segment_of(path: &Path) -> Option<(i64, u64)> {
+    let name = path.file_name()?.to_str()?;
+    let (started, segment) = name.strip_prefix("ring-")?.strip_suffix(".pcapng")?.split_once('-')?;
+    Some((started.parse().ok()?, segment.parse().ok()?))
+}
+
+/// Ring files in `dir` left by earlier runs, oldest first.
+fn earlier_segments(dir: &Path) -> io::Result<VecDeque<PathBuf>> {
+    let mut segments: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| Some((segment_of(&path)?, path)))
+        .collect();
+    segments.sort();
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+/// What the capture thread hands the writer thread.
+enum RingEntry {
+    Meta(PCAPMeta),
+    Packet(OwnedPacket),
+}
+
+/// Capture end of the ring, writing happens on a thread of its own.
+pub struct RingSender {
+    tx: SyncSender<RingEntry>,
+    /// Packets left out because the writer was behind.
+    dropped: u64,
+}
+
+impl RingSender {
+    /// None unless `client.ring_dir` is set.
+    pub fn from_config(link_type: pcap::Linktype, meta: &PCAPMeta) -> Option<Self> {
+        let ring = RingCapture::from_config(link_type)?;
+        let (tx, rx) = sync_channel(RING_QUEUE);
+        let spawned = std::thread::Builder::new()
+            .name("ring".to_string())
+            .spawn({
+                let meta = meta.clone();
+                move || ring.run(rx, meta)
+            });
+        if let Err(e) = spawned {
+            warn!("Not keeping captured headers: {}", e);
+            return None;
+        }
+        Some(RingSender { tx, dropped: 0 })
+    }
+
+    /// Capture metadata for the packets from now on.
+    pub fn set_meta(&self, meta: &PCAPMeta) {
+        // A lost update only leaves some comments with the old addresses
+        let _ = self.tx.try_send(RingEntry::Meta(meta.clone()));
+    }
+
+    /// Hands `packet` to the writer. Returns false if the writer has
+    /// stopped and the ring should be dropped.
+    pub fn write(&mut self, packet: &Packet) -> bool {
+        match self.tx.try_send(RingEntry::Packet(OwnedPacket::from(packet.clone()))) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Ring writer is falling behind, leaving packets out");
+                }
+                self.dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+pub struct RingCapture {
+    dir: PathBuf,
+    /// Size a file may grow to before the next one is started.
+    segment_bytes: u64,
+    header: Vec<u8>,
+    /// Start of the capture, in the file names.
+    started: i64,
+    next_segment: u64,
+    /// Oldest first, the last one is being written.
+    segments: VecDeque<PathBuf>,
+    file: Option<BufWriter<File>>,
+    written: u64,
+    last_flush: Instant,
+}
+
+impl RingCapture {
+    /// Keeps about `total_bytes` of packets in `dir`, counting the files
+    /// left there by earlier runs.
+    pub fn new(dir: PathBuf, total_bytes: u64, link_type: u16) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        info!("Keeping the last {} bytes of captured headers in {:?}", total_bytes, dir);
+        let segments = earlier_segments(&dir)?;
+        Ok(RingCapture {
+            dir,
+            segment_bytes: (total_bytes / RING_SEGMENTS).max(1),
+            header: file_header(link_type, CONFIG.capture.snaplen as u32),
+            started: Timestamp::now().as_millis(),
+            next_segment: 0,
+            segments,
+            file: None,
+            written: 0,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// None unless `client.ring_dir` is set.
+    pub fn from_config(link_type: pcap::Linktype) -> Option<Self> {
+        let dir = CONFIG.client.ring_dir.clone()?;
+        let total_bytes = CONFIG.client.ring_size_mb as u64 * 1_000_000;
+        match RingCapture::new(dir, total_bytes, link_type.0 as u16) {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                warn!("Not keeping captured headers: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Starts the next file, deleting the oldest if the ring is full.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        while self.segments.len() as u64 >= RING_SEGMENTS {
+            if let Some(oldest) = self.segments.pop_front() {
+                std::fs::remove_file(oldest)?;
+            }
+        }
+        let path = self
+            .dir
+            .join(format!("ring-{}-{:06}.pcapng", self.started, self.next_segment));
+        self.next_segment += 1;
+        let mut file = BufWriter::new(File::create(&path)?);
+        file.write_all(&self.header)?;
+        self.written = self.header.len() as u64;
+        self.segments.push_back(path);
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn try_write(&mut self, block: &[u8]) -> io::Result<()> {
+        if self.file.is_none() || self.written + block.len() as u64 > self.segment_bytes {
+            self.rotate()?;
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        file.write_all(block)?;
+        self.written += block.len() as u64;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            file.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Adds `packet` to the ring. Returns false if the ring failed and
+    /// should be dropped.
+    fn write(&mut self, packet: &OwnedPacket, meta: &PCAPMeta) -> bool {
+        let block = packet_block(
+            Timestamp::from_timeval(packet.header.ts, meta.capture_offset),
+            &export_frame(meta.link_type, &packet.data),
+            packet.header.len,
+            &comment(packet, meta),
+        );
+        match self.try_write(&block) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to write captured headers to {:?}, stopping: {}", self.dir, e);
+                false
+            }
+        }
+    }
+
+    /// Writes the packets from `rx` until the capture drops its end or
+    /// writing fails.
+    fn run(mut self, rx: Receiver<RingEntry>, mut meta: PCAPMeta) {
+        while let Ok(entry) = rx.recv() {
+            match entry {
+                RingEntry::Meta(new_meta) => meta = new_meta,
+                RingEntry::Packet(packet) => {
+                    if !self.write(&packet, &meta) {
+                        return;
+                    }
+                }
+            }
+        }
+        if let Some(mut file) = self.file.take() {
+            if let Err(e) = file.flush() {
+                warn!("Failed to write captured headers to {:?}: {}", self.dir, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_blocks_are_framed() {
+        let header = file_header(1, 134);
+        let shb_len = u32_at(&header, 4) as usize;
+        assert_eq!(u32_at(&header, shb_len - 4) as usize, shb_len);
+        assert_eq!(u32_at(&header, shb_len), IDB_TYPE);
+        assert_eq!(header.len(), shb_len + u32_at(&header, shb_len + 4) as usize);
+
+        let ts = Timestamp::from_nanos(0x1_0000_0002);
+        let epb = packet_block(ts, &[1, 2, 3, 4, 5], 1500, "10.0.0.1 -> 10.0.0.2 (Outgoing)");
+        assert_eq!(epb.len() % 4, 0);
+        assert_eq!(u32_at(&epb, 4) as usize, epb.len());
+        assert_eq!(u32_at(&epb, epb.len() - 4) as usize, epb.len());
+        assert_eq!((u32_at(&epb, 12), u32_at(&epb, 16)), (1, 2));
+        assert_eq!((u32_at(&epb, 20), u32_at(&epb, 24)), (5, 1500));
+        // Data padded to 8 bytes, then the comment
+        assert_eq!(u32_at(&epb, 36) & 0xffff, OPT_COMMENT as u32);
+    }
+
+    #[test]
+    fn test_ring_keeps_the_newest_files() {
+        let dir = std::env::temp_dir().join(format!("ring-test-{}", std::process::id()));
+        let mut ring = RingCapture::new(dir.clone(), 4 * 200, 1).unwrap();
+        let block = packet_block(Timestamp::from_millis(1), &[0; 60], 60, "");
+        for _ in 0..20 {
+            ring.try_write(&block).unwrap();
+        }
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files as u64, RING_SEGMENTS);
+        assert_eq!(ring.next_segment, 20);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ring_prunes_earlier_runs() {
+        let dir = std::env::temp_dir().join(format!("ring-restart-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for segment in 0..RING_SEGMENTS {
+            File::create(dir.join(format!("ring-1000-{:06}.pcapng", segment))).unwrap();
+        }
+        File::create(dir.join("notes.txt")).unwrap();
+
+        let mut ring = RingCapture::new(dir.clone(), 4 * 200, 1).unwrap();
+        let block = packet_block(Timestamp::from_millis(1), &[0; 60], 60, "");
+        ring.try_write(&block).unwrap();
+        // The oldest file of the last run made room for the new one
+        assert!(!dir.join("ring-1000-000000.pcapng").exists());
+        assert!(dir.join("ring-1000-000001.pcapng").exists());
+        assert!(dir.join("notes.txt").exists());
+        assert_eq!(ring.segments.len() as u64, RING_SEGMENTS);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
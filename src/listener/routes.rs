@@ -0,0 +1,198 @@
+//! The kernel routing table, to tell the next hop and egress interface
+//! toward a remote. Read from `/proc/net/route` and `/proc/net/ipv6_route`,
+//! the same source as the routes of the capture device, and re-read now
+//! and then so route changes in the mesh are picked up.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::warn;
+use pnet::ipnetwork::IpNetwork;
+
+/// How long a read of the table is used before it is read again.
+const ROUTE_REFRESH: Duration = Duration::from_secs(30);
+/// Most remotes whose next hop is kept between reads of the table.
+const MAX_CACHED_HOPS: usize = 4096;
+
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteEntry {
+    pub network: IpNetwork,
+    /// None if the network is on link.
+    pub gateway: Option<IpAddr>,
+    pub iface: String,
+    pub metric: u32,
+}
+
+/// Where packets toward a remote leave this host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextHop {
+    /// The gateway, or the remote itself if it is on link.
+    pub addr: IpAddr,
+    pub iface: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    routes: Vec<RouteEntry>,
+    read_at: Option<Instant>,
+    /// Next hops looked up since the last read, as the lookup walks all the
+    /// routes and is done for each packet.
+    hops: RefCell<HashMap<IpAddr, Option<NextHop>>>,
+}
+
+impl RouteTable {
+    pub fn new(routes: Vec<RouteEntry>) -> Self {
+        RouteTable {
+            routes,
+            read_at: None,
+            hops: RefCell::default(),
+        }
+    }
+
+    /// Reads the IPv4 and IPv6 tables, leaving out what cannot be read.
+    pub fn read() -> Self {
+        let mut routes = Vec::new();
+        for result in [read_ipv4(), read_ipv6()] {
+            match result {
+                Ok(read) => routes.extend(read),
+                Err(e) => warn!("Failed to read routing table: {:#}", e),
+            }
+        }
+        RouteTable {
+            routes,
+            read_at: Some(Instant::now()),
+            hops: RefCell::default(),
+        }
+    }
+
+    /// Reads the table again if the last read is older than `ROUTE_REFRESH`.
+    pub fn refresh(&mut self) {
+        if self.read_at.is_none_or(|at| at.elapsed() >= ROUTE_REFRESH) {
+            *self = RouteTable::read();
+        }
+    }
+
+    /// Next hop of the most specific route to `ip`, the one with the lowest
+    /// metric if there are several.
+    pub fn lookup(&self, ip: IpAddr) -> Option<NextHop> {
+        let mut hops = self.hops.borrow_mut();
+        if let Some(hop) = hops.get(&ip) {
+            return hop.clone();
+        }
+        if hops.len() >= MAX_CACHED_HOPS {
+            hops.clear();
+        }
+        let hop = self.find(ip);
+        hops.insert(ip, hop.clone());
+        hop
+    }
+
+    fn find(&self, ip: IpAddr) -> Option<NextHop> {
+        let route = self
+            .routes
+            .iter()
+            .filter(|route| route.network.contains(ip))
+            .max_by_key(|route| (route.network.prefix(), std::cmp::Reverse(route.metric)))?;
+        Some(NextHop {
+            addr: route.gateway.unwrap_or(ip),
+            iface: route.iface.clone(),
+        })
+    }
+}
+
+fn read_ipv4() -> Result<Vec<RouteEntry>> {
+    let routes = procfs::net::route().context("/proc/net/route")?;
+    Ok(routes
+        .into_iter()
+        .filter(|route| u32::from(route.flags) & RTF_UP != 0)
+        .filter_map(|route| {
+            let network =
+                IpNetwork::with_netmask(route.destination.into(), route.mask.into()).ok()?;
+            Some(RouteEntry {
+                network,
+                gateway: (!route.gateway.is_unspecified()).then_some(route.gateway.into()),
+                iface: route.iface,
+                metric: route.metrics,
+            })
+        })
+        .collect())
+}
+
+fn read_ipv6() -> Result<Vec<RouteEntry>> {
+    let table =
+        std::fs::read_to_string("/proc/net/ipv6_route").context("/proc/net/ipv6_route")?;
+    Ok(parse_ipv6_routes(&table))
+}
+
+/// Parses the lines of `/proc/net/ipv6_route`: destination, prefix length,
+/// source, source prefix length, next hop, metric, reference count, use
+/// count, flags and interface, all but the interface in hex. Rejecting
+/// routes are left out.
+fn parse_ipv6_routes(table: &str) -> Vec<RouteEntry> {
+    let addr = |hex: &str| u128::from_str_radix(hex, 16).ok().map(Ipv6Addr::from);
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [dest, prefix, _, _, next_hop, metric, _, _, flags, iface] = fields[..] else {
+                return None;
+            };
+            let flags = u32::from_str_radix(flags, 16).ok()?;
+            if flags & RTF_UP == 0 || flags & RTF_REJECT != 0 {
+                return None;
+            }
+            let prefix = u8::from_str_radix(prefix, 16).ok()?;
+            let next_hop = addr(next_hop)?;
+            Some(RouteEntry {
+                network: IpNetwork::new(addr(dest)?.into(), prefix).ok()?,
+                gateway: (!next_hop.is_unspecified()).then_some(next_hop.into()),
+                iface: iface.to_string(),
+                metric: u32::from_str_radix(metric, 16).ok()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv6_routes_and_lookup() {
+        let table = "\
+20010db8000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+";
+        let mut routes = parse_ipv6_routes(table);
+        assert_eq!(routes.len(), 2);
+        routes.push(RouteEntry {
+            network: "10.0.0.0/24".parse().unwrap(),
+            gateway: None,
+            iface: "wlan0".to_string(),
+            metric: 0,
+        });
+        routes.push(RouteEntry {
+            network: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some("10.0.0.1".parse().unwrap()),
+            iface: "wlan0".to_string(),
+            metric: 0,
+        });
+        let table = RouteTable::new(routes);
+        let hop = |ip: &str| table.lookup(ip.parse().unwrap()).map(|h| h.addr.to_string());
+
+        assert_eq!(hop("2001:db8::5").as_deref(), Some("2001:db8::5"));
+        assert_eq!(hop("2001:db9::5").as_deref(), Some("fe80::1"));
+        assert_eq!(hop("10.0.0.7").as_deref(), Some("10.0.0.7"));
+        assert_eq!(hop("8.8.8.8").as_deref(), Some("10.0.0.1"));
+        assert_eq!(table.lookup("8.8.8.8".parse().unwrap()).unwrap().iface, "wlan0");
+        // Each remote is looked up once
+        assert_eq!(table.hops.borrow().len(), 4);
+    }
+}
@@ -0,0 +1,395 @@
+//! Synthetic traffic, to work on the UI, exports and estimators without root
+//! or a capture device. A `TrafficModel` plays TCP flows with the rates, RTTs
+//! and loss in `[simulation]` and feeds the parser the packets a capture on
+//! this node would have seen, so everything after the capture runs as usual.
+//!
+//! The local end opens every flow. Data segments are acked one by one, an
+//! RTT later if this node sends the data and right away if it receives it.
+//! Lost segments are sent again an RTT later, and are only seen by the
+//! capture if this node sent them.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use pnet::datalink::MacAddr;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::channel::send_or_err;
+use crate::config::SimFlow;
+use crate::listener::capture::{LinkType, PCAPMeta};
+use crate::listener::dump::{DumpHandle, Dumper};
+use crate::{
+    CapEvent, CapEventSender, Direction, ParsedPacket, TcpFlags, TcpOptions, Timestamp,
+    TransportPacket, CONFIG,
+};
+
+/// Interval between steps of the model.
+const SIM_TICK: Duration = Duration::from_millis(10);
+/// Payload of a data segment.
+const MSS: u16 = 1448;
+/// Port of the remote end of every flow.
+const REMOTE_PORT: u16 = 443;
+/// Local port of the first flow, the others follow.
+const FIRST_LOCAL_PORT: u16 = 49152;
+const WINDOW_SIZE: u16 = 65535;
+/// Most payload sent at once after a late step, in seconds of the rate.
+const MAX_BURST: f64 = 0.1;
+/// ACKs of this node's data come up to this share of the RTT late.
+const ACK_JITTER: f64 = 0.1;
+/// Time this node takes to ack the data it receives.
+const ACK_DELAY: Duration = Duration::from_micros(100);
+const SIM_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+
+/// Played if `simulation.flows` is empty.
+fn default_flows() -> Vec<SimFlow> {
+    vec![
+        SimFlow {
+            remote: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            rate: 1_000_000.0,
+            rtt: 20.0,
+            loss: 0.0,
+            download: false,
+        },
+        SimFlow {
+            remote: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)),
+            rate: 500_000.0,
+            rtt: 50.0,
+            loss: 0.01,
+            download: true,
+        },
+    ]
+}
+
+/// Metadata of the simulated device, an IP link without MAC addresses.
+pub fn simulated_meta(ipv4: Ipv4Addr) -> PCAPMeta {
+    PCAPMeta {
+        mac_addr: MacAddr::zero(),
+        ipv4,
+        ipv6: SIM_IPV6,
+        name: "sim".to_string(),
+        tstamp_type: None,
+        link_type: LinkType::Raw,
+        routes: Vec::new(),
+        addresses: Vec::new(),
+        capture_offset: 0,
+    }
+}
+
+struct FlowState {
+    flow: SimFlow,
+    local: (IpAddr, u16),
+    remote: (IpAddr, u16),
+    rtt: Duration,
+    /// Next sequence number of the end sending the data.
+    data_seq: u32,
+    /// Sequence number of the end sending the acks.
+    ack_seq: u32,
+    /// Payload bytes the flow may send.
+    credit: f64,
+    /// End of the handshake, None before it is sent.
+    established: Option<Timestamp>,
+    /// Time of the last ack, so acks stay in order.
+    last_ack: Timestamp,
+}
+
+impl FlowState {
+    fn new(flow: SimFlow, local: IpAddr, port: u16) -> Self {
+        FlowState {
+            local: (local, port),
+            remote: (flow.remote, REMOTE_PORT),
+            rtt: Duration::from_secs_f64(flow.rtt.max(0.0) / 1000.0),
+            data_seq: rand::random(),
+            ack_seq: rand::random(),
+            credit: 0.0,
+            established: None,
+            last_ack: Timestamp::ZERO,
+            flow,
+        }
+    }
+
+    fn segment(
+        &self,
+        from_local: bool,
+        timestamp: Timestamp,
+        (sequence, acknowledgment): (u32, u32),
+        flags: u8,
+        payload_len: u16,
+    ) -> ParsedPacket {
+        let (src, dst) = if from_local {
+            (self.local, self.remote)
+        } else {
+            (self.remote, self.local)
+        };
+        let ip_header: u16 = if src.0.is_ipv4() { 20 } else { 40 };
+        ParsedPacket {
+            src_ip: src.0,
+            dst_ip: dst.0,
+            src_mac: MacAddr::zero(),
+            dst_mac: MacAddr::zero(),
+            transport: TransportPacket::TCP {
+                sequence,
+                acknowledgment,
+                flags: TcpFlags::new(flags),
+                payload_len,
+                options: TcpOptions::new(),
+                src_port: src.1,
+                dst_port: dst.1,
+                window_size: WINDOW_SIZE,
+            },
+            total_length: ip_header + 20 + payload_len,
+            timestamp,
+            direction: if from_local {
+                Direction::Outgoing
+            } else {
+                Direction::Incoming
+            },
+            intercepted: false,
+            retry: false,
+            ttl: 64,
+            ip_id: 0,
+            fragment: None,
+            segments: 1,
+            malformed_length: false,
+        }
+    }
+
+    /// SYN at `now`, the answer and the final ACK an RTT later.
+    fn handshake(&mut self, now: Timestamp, pending: &mut Vec<ParsedPacket>) {
+        let (local_isn, remote_isn) = if self.flow.download {
+            (self.ack_seq, self.data_seq)
+        } else {
+            (self.data_seq, self.ack_seq)
+        };
+        let answered = now + self.rtt;
+        pending.push(self.segment(true, now, (local_isn, 0), TcpFlags::SYN, 0));
+        pending.push(self.segment(
+            false,
+            answered,
+            (remote_isn, local_isn.wrapping_add(1)),
+            TcpFlags::SYN | TcpFlags::ACK,
+            0,
+        ));
+        pending.push(self.segment(
+            true,
+            answered,
+            (local_isn.wrapping_add(1), remote_isn.wrapping_add(1)),
+            TcpFlags::ACK,
+            0,
+        ));
+        self.data_seq = self.data_seq.wrapping_add(1);
+        self.ack_seq = self.ack_seq.wrapping_add(1);
+        self.established = Some(answered);
+    }
+
+    /// A data segment sent at `at` and its ack, with the retransmissions
+    /// if it is lost.
+    fn send(&mut self, mut at: Timestamp, pending: &mut Vec<ParsedPacket>) {
+        let download = self.flow.download;
+        let seq = self.data_seq;
+        self.data_seq = seq.wrapping_add(MSS as u32);
+        let data =
+            |flow: &Self, at| flow.segment(!download, at, (seq, flow.ack_seq), TcpFlags::ACK, MSS);
+        while rand::random::<f64>() < self.flow.loss.min(0.99) {
+            if !download {
+                pending.push(data(self, at));
+            }
+            at = at + self.rtt;
+        }
+        pending.push(data(self, at));
+
+        let delay = if download {
+            ACK_DELAY
+        } else {
+            self.rtt.mul_f64(1.0 + ACK_JITTER * rand::random::<f64>())
+        };
+        self.last_ack = self.last_ack.max(at + delay);
+        let ack = (self.ack_seq, self.data_seq);
+        pending.push(self.segment(download, self.last_ack, ack, TcpFlags::ACK, 0));
+    }
+
+    /// Sends what the rate allows between `since` and `now`, spread evenly.
+    fn step(&mut self, since: Timestamp, now: Timestamp, pending: &mut Vec<ParsedPacket>) {
+        let Some(established) = self.established else {
+            self.handshake(now, pending);
+            return;
+        };
+        let start = since.max(established);
+        let elapsed = now.saturating_duration_since(start);
+        let rate = self.flow.rate.max(0.0);
+        self.credit =
+            (self.credit + rate * elapsed.as_secs_f64()).min(rate * MAX_BURST + MSS as f64);
+        let segments = (self.credit / MSS as f64) as u32;
+        for i in 0..segments {
+            self.credit -= MSS as f64;
+            self.send(start + elapsed.mul_f64(i as f64 / segments as f64), pending);
+        }
+    }
+}
+
+/// Generates the packets of the configured flows, see the module docs.
+pub struct TrafficModel {
+    flows: Vec<FlowState>,
+    /// Packets of the future, released once their time has come.
+    pending: Vec<ParsedPacket>,
+    last_step: Option<Timestamp>,
+    meta_tx: watch::Sender<PCAPMeta>,
+    dump_handle: DumpHandle,
+}
+
+impl TrafficModel {
+    pub fn new(meta: PCAPMeta, flows: Vec<SimFlow>) -> Self {
+        let flows = flows
+            .into_iter()
+            .filter_map(|flow| match meta.get_match(flow.remote) {
+                Some(local) => Some((flow, local)),
+                None => {
+                    warn!("No local address toward {}, not simulating it", flow.remote);
+                    None
+                }
+            })
+            .zip(FIRST_LOCAL_PORT..)
+            .map(|((flow, local), port)| FlowState::new(flow, local, port))
+            .collect();
+        // There are no raw packets to dump, requests fail
+        let (_, dump_handle) = Dumper::new();
+        TrafficModel {
+            flows,
+            pending: Vec::new(),
+            last_step: None,
+            meta_tx: watch::channel(meta).0,
+            dump_handle,
+        }
+    }
+
+    /// The flows in `[simulation]`, from `simulation.local_ip`.
+    pub fn from_config() -> (Self, PCAPMeta) {
+        let config = &CONFIG.simulation;
+        let meta = simulated_meta(config.local_ip);
+        let flows = if config.flows.is_empty() {
+            default_flows()
+        } else {
+            config.flows.clone()
+        };
+        (TrafficModel::new(meta.clone(), flows), meta)
+    }
+
+    /// The metadata never changes, but the parser and server take it like
+    /// from the capture.
+    pub fn subscribe_meta(&self) -> watch::Receiver<PCAPMeta> {
+        self.meta_tx.subscribe()
+    }
+
+    pub fn dump_handle(&self) -> DumpHandle {
+        self.dump_handle.clone()
+    }
+
+    /// Packets of all flows up to `now`, oldest first. The first step only
+    /// opens the flows.
+    pub fn step(&mut self, now: Timestamp) -> Vec<ParsedPacket> {
+        let since = self.last_step.replace(now).unwrap_or(now);
+        for flow in &mut self.flows {
+            flow.step(since, now, &mut self.pending);
+        }
+        self.pending.sort_by_key(|packet| packet.timestamp);
+        let due = self
+            .pending
+            .partition_point(|packet| packet.timestamp <= now);
+        self.pending.drain(..due).collect()
+    }
+
+    /// Steps the model every `SIM_TICK`, sending the packets to the parser.
+    pub fn dispatch(mut self, sender: CapEventSender) -> JoinHandle<Result<()>> {
+        info!("Simulating {} flows instead of capturing", self.flows.len());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SIM_TICK);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                for packet in self.step(Timestamp::now()) {
+                    send_or_err(&sender, CapEvent::Parsed(packet), "simulated packet").await?;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(remote: &str, download: bool, loss: f64) -> SimFlow {
+        SimFlow {
+            remote: remote.parse().unwrap(),
+            rate: 1_448_000.0,
+            rtt: 20.0,
+            loss,
+            download,
+        }
+    }
+
+    fn payload(packets: &[ParsedPacket], direction: Direction) -> u64 {
+        packets
+            .iter()
+            .filter(|p| p.direction == direction)
+            .map(|p| match p.transport {
+                TransportPacket::TCP { payload_len, .. } => payload_len as u64,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_flows_follow_their_rate() {
+        let meta = simulated_meta(Ipv4Addr::new(10, 0, 0, 1));
+        let flows = vec![flow("10.0.0.2", false, 0.0), flow("fd00::2", true, 0.0)];
+        let mut model = TrafficModel::new(meta, flows);
+
+        let start = Timestamp::from_millis(1_000_000);
+        let opened = model.step(start);
+        assert_eq!(opened.len(), 2);
+        assert!(opened.iter().all(|p| p.direction == Direction::Outgoing));
+
+        let mut packets = Vec::new();
+        for ms in (10..=1000).step_by(10) {
+            packets.extend(model.step(start + Duration::from_millis(ms)));
+        }
+        assert!(packets.is_sorted_by_key(|p| p.timestamp));
+        assert!(packets
+            .iter()
+            .all(|p| p.timestamp <= start + Duration::from_secs(1)));
+        // 980 ms of data at 1000 segments/sec
+        let segments = |direction| payload(&packets, direction) / MSS as u64;
+        assert!((970..=981).contains(&segments(Direction::Outgoing)));
+        assert!((970..=981).contains(&segments(Direction::Incoming)));
+        let v6 = packets.iter().filter(|p| p.src_ip.is_ipv6()).count();
+        assert!(v6 > 0 && v6 < packets.len());
+    }
+
+    #[test]
+    fn test_lost_segments_are_sent_again() {
+        let meta = simulated_meta(Ipv4Addr::new(10, 0, 0, 1));
+        let mut model = TrafficModel::new(meta, vec![flow("10.0.0.2", false, 0.5)]);
+        let start = Timestamp::from_millis(1_000_000);
+        model.step(start);
+        let packets = model.step(start + Duration::from_millis(500));
+        let mut seqs: Vec<u32> = packets
+            .iter()
+            .filter_map(|p| match p.transport {
+                TransportPacket::TCP {
+                    sequence,
+                    payload_len,
+                    ..
+                } if payload_len > 0 => Some(sequence),
+                _ => None,
+            })
+            .collect();
+        let sent = seqs.len();
+        seqs.sort_unstable();
+        seqs.dedup();
+        assert!(seqs.len() < sent);
+    }
+}
@@ -0,0 +1,129 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use log::warn;
+use pnet::ipnetwork::IpNetwork;
+
+use super::stream_id::IpPair;
+use crate::listener::routes::RouteTable;
+use crate::CONFIG;
+
+/// What the links of the `LinkManager` are keyed by, see
+/// `client.link_aggregation`.
+///
+/// Traffic to many hosts behind one bottleneck, e.g. a gateway, is better
+/// estimated as one link: the estimator then sees all of the traffic
+/// sharing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkAggregation {
+    /// A link per remote IP.
+    Host,
+    /// A link per remote subnet, keyed by its network address.
+    Subnet { v4_prefix: u8, v6_prefix: u8 },
+    /// A link per next hop toward the remote, from the routing table.
+    /// Remotes without a route keep their own link.
+    NextHop,
+}
+
+impl FromStr for LinkAggregation {
+    type Err = anyhow::Error;
+
+    /// Subnets get the prefixes from the config.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "host" => Ok(LinkAggregation::Host),
+            "subnet" => Ok(LinkAggregation::Subnet {
+                v4_prefix: CONFIG.client.aggregation_prefix_v4.min(32),
+                v6_prefix: CONFIG.client.aggregation_prefix_v6.min(128),
+            }),
+            "next_hop" | "nexthop" => Ok(LinkAggregation::NextHop),
+            _ => Err(anyhow!("Unknown link aggregation: {}", s)),
+        }
+    }
+}
+
+impl LinkAggregation {
+    /// From `client.link_aggregation`, per host if it is invalid.
+    pub fn from_config() -> Self {
+        CONFIG
+            .client
+            .link_aggregation
+            .parse()
+            .unwrap_or_else(|e| {
+                warn!("{}, keeping a link per host", e);
+                LinkAggregation::Host
+            })
+    }
+
+    /// Remote that traffic to `remote` is counted under.
+    pub fn remote(&self, remote: IpAddr, routes: &RouteTable) -> IpAddr {
+        match *self {
+            LinkAggregation::Host => remote,
+            LinkAggregation::Subnet {
+                v4_prefix,
+                v6_prefix,
+            } => {
+                let prefix = if remote.is_ipv4() { v4_prefix } else { v6_prefix };
+                IpNetwork::new(remote, prefix)
+                    .map(|network| network.network())
+                    .unwrap_or(remote)
+            }
+            LinkAggregation::NextHop => routes.lookup(remote).map_or(remote, |hop| hop.addr),
+        }
+    }
+
+    /// False if links may be keyed by a remote that is not a host.
+    pub fn keys_are_hosts(&self) -> bool {
+        !matches!(self, LinkAggregation::Subnet { .. })
+    }
+
+    /// Link that `ip_pair` is counted in.
+    pub fn key(&self, ip_pair: IpPair, routes: &RouteTable) -> IpPair {
+        IpPair::new(ip_pair.local(), self.remote(ip_pair.remote(), routes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::routes::RouteEntry;
+
+    #[test]
+    fn test_aggregated_remotes() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let routes = RouteTable::new(vec![
+            RouteEntry {
+                network: "10.0.0.0/24".parse().unwrap(),
+                gateway: None,
+                iface: "eth0".to_string(),
+                metric: 0,
+            },
+            RouteEntry {
+                network: "0.0.0.0/0".parse().unwrap(),
+                gateway: Some(ip("10.0.0.1")),
+                iface: "eth0".to_string(),
+                metric: 0,
+            },
+        ]);
+
+        let subnet = LinkAggregation::Subnet {
+            v4_prefix: 24,
+            v6_prefix: 64,
+        };
+        assert_eq!(subnet.remote(ip("192.168.5.77"), &routes), ip("192.168.5.0"));
+        assert_eq!(subnet.remote(ip("2001:db8::1:2"), &routes), ip("2001:db8::"));
+
+        let next_hop = LinkAggregation::NextHop;
+        assert_eq!(next_hop.remote(ip("10.0.0.7"), &routes), ip("10.0.0.7"));
+        assert_eq!(next_hop.remote(ip("8.8.8.8"), &routes), ip("10.0.0.1"));
+        assert_eq!(next_hop.remote(ip("2001:db8::1"), &routes), ip("2001:db8::1"));
+
+        let pair = IpPair::new(ip("10.0.0.2"), ip("1.1.1.1"));
+        let key = next_hop.key(pair, &routes);
+        assert_eq!((key.local(), key.remote()), (ip("10.0.0.2"), ip("10.0.0.1")));
+        assert_eq!(LinkAggregation::Host.key(pair, &routes), pair);
+        assert!("nexthop".parse::<LinkAggregation>().is_ok());
+        assert!("network".parse::<LinkAggregation>().is_err());
+    }
+}
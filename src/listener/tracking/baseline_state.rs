@@ -0,0 +1,187 @@
+//! Per-link baselines kept in `client.state_file` across restarts.
+//!
+//! The baseline RTT takes a few reports to settle and the capacity needs a
+//! ping train, so without them a restarted node reports no bufferbloat and
+//! unbounded ABW estimates for a while. Saved baselines are picked up by
+//! the links as they show up again, and age out of the baseline RTT like
+//! the lowest RTT of any report.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::stream_id::IpPair;
+use crate::CONFIG;
+
+/// Saved baselines older than this are dropped, the path may have changed.
+const MAX_STATE_AGE: Duration = Duration::from_secs(3600);
+
+/// What a link keeps across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct LinkBaseline {
+    /// Lowest RTT over the recent reports, microseconds.
+    pub min_rtt: Option<f64>,
+    /// Path capacity in bytes/sec from the last ping train.
+    pub capacity: Option<f64>,
+}
+
+impl LinkBaseline {
+    pub fn is_empty(&self) -> bool {
+        self.min_rtt.is_none() && self.capacity.is_none()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    local: IpAddr,
+    remote: IpAddr,
+    #[serde(flatten)]
+    baseline: LinkBaseline,
+    /// When the baseline was saved, Unix time in milliseconds.
+    saved: i64,
+}
+
+/// Reads and writes the state file.
+#[derive(Debug)]
+pub struct BaselineState {
+    path: PathBuf,
+    /// Loaded baselines of the links not seen again yet, with the time they
+    /// were saved.
+    pending: HashMap<IpPair, (LinkBaseline, i64)>,
+    /// When the file is next written.
+    next_save: Instant,
+}
+
+impl BaselineState {
+    /// None unless `client.state_file` is set.
+    pub fn from_config() -> Option<Self> {
+        let path = CONFIG.client.state_file.clone()?;
+        Some(BaselineState::load(path))
+    }
+
+    /// Loads the baselines saved in `path`. A missing or unreadable file is
+    /// logged and left to be written anew.
+    pub fn load(path: PathBuf) -> Self {
+        let now = chrono::Utc::now().timestamp_millis();
+        let pending = match read_entries(&path) {
+            Ok(entries) => {
+                let total = entries.len();
+                let pending: HashMap<_, _> = entries
+                    .into_iter()
+                    .filter(|e| now - e.saved <= MAX_STATE_AGE.as_millis() as i64)
+                    .map(|e| (IpPair::new(e.local, e.remote), (e.baseline, e.saved)))
+                    .collect();
+                info!(
+                    "Restored baselines of {} links from {}, {} too old",
+                    pending.len(),
+                    path.display(),
+                    total - pending.len()
+                );
+                pending
+            }
+            Err(e) => {
+                warn!("No baselines restored: {:#}", e);
+                HashMap::new()
+            }
+        };
+        BaselineState {
+            path,
+            pending,
+            next_save: Instant::now() + CONFIG.client.state_save_interval,
+        }
+    }
+
+    /// Whether some loaded baselines still wait for their link.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The loaded baseline of `ip_pair`, once.
+    pub fn take(&mut self, ip_pair: &IpPair) -> Option<LinkBaseline> {
+        self.pending.remove(ip_pair).map(|(baseline, _)| baseline)
+    }
+
+    /// Whether `client.state_save_interval` has passed since the last save.
+    pub fn save_due(&self) -> bool {
+        Instant::now() >= self.next_save
+    }
+
+    /// Writes the baselines of the current links, and the loaded ones not
+    /// seen again yet. The file is replaced at once, so a crash while saving
+    /// leaves the last one.
+    pub fn save(&mut self, links: impl IntoIterator<Item = (IpPair, LinkBaseline)>) -> Result<()> {
+        self.next_save = Instant::now() + CONFIG.client.state_save_interval;
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut entries: Vec<Entry> = links
+            .into_iter()
+            .filter(|(_, baseline)| !baseline.is_empty())
+            .map(|(ip_pair, baseline)| entry(ip_pair, baseline, now))
+            .collect();
+        entries.extend(
+            self.pending
+                .iter()
+                .map(|(&ip_pair, &(baseline, saved))| entry(ip_pair, baseline, saved)),
+        );
+        let json = serde_json::to_vec_pretty(&entries)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json).with_context(|| format!("Writing {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Replacing {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+fn entry(ip_pair: IpPair, baseline: LinkBaseline, saved: i64) -> Entry {
+    Entry {
+        local: ip_pair.local(),
+        remote: ip_pair.remote(),
+        baseline,
+        saved,
+    }
+}
+
+fn read_entries(path: &Path) -> Result<Vec<Entry>> {
+    let json = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+    serde_json::from_slice(&json).with_context(|| format!("Parsing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = std::env::temp_dir().join(format!("baselines-{}.json", std::process::id()));
+        let pair = |remote: u8| IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, remote].into());
+        let baseline = LinkBaseline {
+            min_rtt: Some(1500.0),
+            capacity: Some(1e7),
+        };
+
+        let mut state = BaselineState::load(path.clone());
+        assert!(!state.has_pending());
+        let links = [(pair(2), baseline), (pair(3), LinkBaseline::default())];
+        state.save(links).unwrap();
+
+        let mut state = BaselineState::load(path.clone());
+        assert!(state.has_pending());
+        assert_eq!(state.take(&pair(3)), None);
+        assert_eq!(state.take(&pair(2)), Some(baseline));
+        assert_eq!(state.take(&pair(2)), None);
+
+        // Baselines not picked up are written again with their own age
+        let stale = chrono::Utc::now().timestamp_millis() - MAX_STATE_AGE.as_millis() as i64 - 1;
+        state.pending.insert(pair(4), (baseline, stale));
+        state.save([]).unwrap();
+        let state = BaselineState::load(path.clone());
+        assert!(!state.has_pending());
+        fs::remove_file(path).unwrap();
+    }
+}
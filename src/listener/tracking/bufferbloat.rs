@@ -0,0 +1,126 @@
+//! RTT inflation under load, a sign of a deep queue at the bottleneck.
+//!
+//! The baseline is the lowest RTT seen on the link over the last few
+//! reports. The loaded RTT is the median RTT of the fastest bursts in the
+//! current report. On a link without bufferbloat the two stay close even
+//! when it is saturated.
+
+use std::collections::VecDeque;
+
+use crate::throughput::percentile;
+
+/// Reports the baseline is taken over, so it follows route changes.
+const BASELINE_WINDOWS: usize = 10;
+/// Bursts at or above this throughput percentile count as loaded.
+const LOADED_PERCENTILE: f64 = 0.75;
+/// Fewest bursts with a known throughput and RTT to compare.
+const MIN_BURSTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bufferbloat {
+    /// Lowest RTT over the recent reports, microseconds.
+    pub baseline: f64,
+    /// Median RTT of the fastest bursts, microseconds.
+    pub loaded: f64,
+}
+
+impl Bufferbloat {
+    /// Loaded over baseline RTT, close to 1 if the queue does not grow
+    /// under load.
+    pub fn score(&self) -> f64 {
+        self.loaded / self.baseline
+    }
+}
+
+/// Lowest RTT of each of the last reports with RTT samples.
+#[derive(Debug, Default)]
+pub struct BaselineRtt {
+    mins: VecDeque<f64>,
+}
+
+impl BaselineRtt {
+    pub fn new() -> Self {
+        BaselineRtt::default()
+    }
+
+    /// Lowest RTT over the recent reports, microseconds.
+    pub fn get(&self) -> Option<f64> {
+        self.mins.iter().copied().reduce(f64::min)
+    }
+
+    /// Adds the lowest RTT of a report and compares the fastest of its
+    /// `bursts` with the baseline. `bursts` are (bytes/sec, mean RTT in
+    /// microseconds).
+    pub fn update(&mut self, min_rtt: Option<f64>, bursts: &[(f64, f64)]) -> Option<Bufferbloat> {
+        if let Some(rtt) = min_rtt {
+            if self.mins.len() == BASELINE_WINDOWS {
+                self.mins.pop_front();
+            }
+            self.mins.push_back(rtt);
+        }
+        let baseline = self.get().filter(|&rtt| rtt > 0.0)?;
+        let loaded = loaded_rtt(bursts)?;
+        Some(Bufferbloat { baseline, loaded })
+    }
+
+    /// Starts from a baseline saved by an earlier run, microseconds. It ages
+    /// out like the lowest RTT of a report.
+    pub fn seed(&mut self, rtt: f64) {
+        self.update(Some(rtt), &[]);
+    }
+}
+
+/// Median RTT of the bursts at or above `LOADED_PERCENTILE` throughput.
+fn loaded_rtt(bursts: &[(f64, f64)]) -> Option<f64> {
+    // A throughput of 0 is unknown
+    let bursts: Vec<_> = bursts.iter().filter(|(thp, _)| *thp > 0.0).collect();
+    if bursts.len() < MIN_BURSTS {
+        return None;
+    }
+    let mut thps: Vec<f64> = bursts.iter().map(|(thp, _)| *thp).collect();
+    thps.sort_by(|a, b| a.total_cmp(b));
+    let threshold = percentile(&thps, LOADED_PERCENTILE);
+    let mut rtts: Vec<f64> = bursts
+        .iter()
+        .filter(|(thp, _)| *thp >= threshold)
+        .map(|(_, rtt)| *rtt)
+        .collect();
+    rtts.sort_by(|a, b| a.total_cmp(b));
+    Some(percentile(&rtts, 0.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_inflates_with_throughput() {
+        let mut baseline = BaselineRtt::new();
+        // Too few bursts to tell
+        assert_eq!(baseline.update(Some(10_000.0), &[(1e6, 50_000.0)]), None);
+        assert_eq!(baseline.get(), Some(10_000.0));
+
+        let bursts = [
+            (1e5, 12_000.0),
+            (2e5, 11_000.0),
+            (0.0, 90_000.0),
+            (3e5, 15_000.0),
+            (4e6, 40_000.0),
+            (5e6, 60_000.0),
+            (1e5, 10_500.0),
+        ];
+        // The report's own minimum is above the baseline
+        let bloat = baseline.update(Some(10_500.0), &bursts).unwrap();
+        assert_eq!(bloat.baseline, 10_000.0);
+        assert_eq!(bloat.loaded, 40_000.0);
+        assert_eq!(bloat.score(), 4.0);
+
+        // The old minimum ages out
+        for _ in 0..BASELINE_WINDOWS {
+            baseline.update(Some(20_000.0), &[]);
+        }
+        assert_eq!(baseline.get(), Some(20_000.0));
+        baseline.update(None, &[]);
+        assert_eq!(baseline.get(), Some(20_000.0));
+    }
+}
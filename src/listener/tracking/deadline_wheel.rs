@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::Timestamp;
+
+/// Keys indexed by the time something has to be done about them.
+///
+/// Deadlines are rounded up to whole ticks, so a key is never returned before
+/// its deadline and expiring is proportional to the number of keys due, not
+/// the number scheduled.
+#[derive(Debug)]
+pub struct DeadlineWheel<K> {
+    tick_nanos: u64,
+    slots: BTreeMap<u64, HashSet<K>>,
+    deadlines: HashMap<K, u64>,
+}
+
+impl<K: Hash + Eq + Copy> DeadlineWheel<K> {
+    pub fn new(tick: Duration) -> Self {
+        DeadlineWheel {
+            tick_nanos: (tick.as_nanos() as u64).max(1),
+            slots: BTreeMap::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Sets the deadline for `key`, replacing any earlier one.
+    pub fn schedule(&mut self, key: K, at: Timestamp) {
+        let slot = self.slot(at);
+        if let Some(old) = self.deadlines.insert(key, slot) {
+            if old == slot {
+                return;
+            }
+            self.remove_from_slot(&key, old);
+        }
+        self.slots.entry(slot).or_default().insert(key);
+    }
+
+    /// Sets the deadline for `key` unless it is already due sooner.
+    pub fn schedule_min(&mut self, key: K, at: Timestamp) {
+        match self.deadlines.get(&key) {
+            Some(&slot) if slot <= self.slot(at) => {}
+            _ => self.schedule(key, at),
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(slot) = self.deadlines.remove(key) {
+            self.remove_from_slot(key, slot);
+        }
+    }
+
+    /// Removes and returns every key whose deadline is at or before `now`.
+    pub fn expire(&mut self, now: Timestamp) -> Vec<K> {
+        let due_slots = now.as_nanos() / self.tick_nanos;
+        let pending = self.slots.split_off(&(due_slots + 1));
+        let due = std::mem::replace(&mut self.slots, pending);
+        let mut keys = Vec::new();
+        for key in due.into_values().flatten() {
+            self.deadlines.remove(&key);
+            keys.push(key);
+        }
+        keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    fn slot(&self, at: Timestamp) -> u64 {
+        at.as_nanos().div_ceil(self.tick_nanos)
+    }
+
+    fn remove_from_slot(&mut self, key: &K, slot: u64) {
+        if let Some(keys) = self.slots.get_mut(&slot) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.slots.remove(&slot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire_only_due_keys() {
+        let mut wheel = DeadlineWheel::new(Duration::from_secs(1));
+        let t0 = Timestamp::from_millis(10_000);
+        wheel.schedule(1, t0 + Duration::from_millis(500));
+        wheel.schedule(2, t0 + Duration::from_secs(5));
+        wheel.schedule(3, t0 + Duration::from_secs(20));
+
+        // Rounded up, so not returned early
+        assert!(wheel.expire(t0 + Duration::from_millis(900)).is_empty());
+        assert_eq!(wheel.expire(t0 + Duration::from_secs(1)), vec![1]);
+
+        let mut due = wheel.expire(t0 + Duration::from_secs(30));
+        due.sort();
+        assert_eq!(due, vec![2, 3]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_reschedule() {
+        let mut wheel = DeadlineWheel::new(Duration::from_secs(1));
+        let t0 = Timestamp::from_millis(10_000);
+        wheel.schedule(1, t0 + Duration::from_secs(10));
+
+        // Only ever brings the deadline closer
+        wheel.schedule_min(1, t0 + Duration::from_secs(20));
+        wheel.schedule_min(1, t0 + Duration::from_secs(2));
+        assert_eq!(wheel.len(), 1);
+        assert_eq!(wheel.expire(t0 + Duration::from_secs(2)), vec![1]);
+
+        wheel.schedule(1, t0 + Duration::from_secs(2));
+        wheel.schedule(1, t0 + Duration::from_secs(10));
+        assert!(wheel.expire(t0 + Duration::from_secs(5)).is_empty());
+
+        wheel.remove(&1);
+        assert!(wheel.expire(t0 + Duration::from_secs(60)).is_empty());
+    }
+}
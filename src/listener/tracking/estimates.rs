@@ -0,0 +1,179 @@
+//! The current estimates of each link, for code in the same process and
+//! local tools: `LinkManager::estimate`, the `LinkQuery` handle to it from
+//! other tasks, the `GetEstimates` RPC and `GET /estimates` of the HTTP API.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use crate::anonymize::export_ip;
+use crate::proto_bw::LinkEstimate as LinkEstimateProto;
+use crate::{CapEvent, CapEventSender};
+
+/// A link is stale once its last report is this many report windows old.
+pub const STALE_WINDOWS: u32 = 2;
+
+/// Time the parser has to answer a `LinkQuery`.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the last report of a link said, kept for `LinkEstimate`.
+#[derive(Debug, Clone, Copy)]
+pub struct Reported {
+    pub abw: Option<f64>,
+    pub abw_down: Option<f64>,
+    pub capacity: Option<f64>,
+    pub latency: Option<f64>,
+    pub thp_in: f64,
+    pub thp_out: f64,
+    pub at: Instant,
+}
+
+/// Estimates of a link as of now. Those from the last report are None
+/// before it, and may be old, see `age` and `stale`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkEstimate {
+    pub local: IpAddr,
+    /// The remote, or what its traffic is counted under with
+    /// `client.link_aggregation`.
+    pub remote: IpAddr,
+    /// Available bandwidth toward the remote in the last report, bytes/sec.
+    pub abw: Option<f64>,
+    /// Available bandwidth from the remote in the last report, bytes/sec.
+    pub abw_down: Option<f64>,
+    /// Available bandwidth toward the remote from the gap points since the
+    /// last report, bytes/sec.
+    pub running_abw: Option<f64>,
+    /// From the last ping train, bytes/sec.
+    pub capacity: Option<f64>,
+    /// Mean RTT toward the remote in the last report, microseconds.
+    pub latency: Option<f64>,
+    /// Bytes/sec in the last report, 0 before it.
+    pub thp_in: f64,
+    pub thp_out: f64,
+    /// Time since the last report, None before it.
+    pub age: Option<Duration>,
+    /// No report within `STALE_WINDOWS` report windows.
+    pub stale: bool,
+}
+
+impl LinkEstimate {
+    /// `reported` as of `now`, with the running estimate of the link.
+    pub fn new(
+        local: IpAddr,
+        remote: IpAddr,
+        reported: Option<&Reported>,
+        running_abw: Option<f64>,
+        window: Duration,
+        now: Instant,
+    ) -> Self {
+        let age = reported.map(|r| now.saturating_duration_since(r.at));
+        LinkEstimate {
+            local,
+            remote,
+            abw: reported.and_then(|r| r.abw),
+            abw_down: reported.and_then(|r| r.abw_down),
+            running_abw,
+            capacity: reported.and_then(|r| r.capacity),
+            latency: reported.and_then(|r| r.latency),
+            thp_in: reported.map_or(0.0, |r| r.thp_in),
+            thp_out: reported.map_or(0.0, |r| r.thp_out),
+            age,
+            stale: age.is_none_or(|age| age > window * STALE_WINDOWS),
+        }
+    }
+
+    /// As served over gRPC, with the addresses as exported.
+    pub fn to_proto(&self) -> LinkEstimateProto {
+        LinkEstimateProto {
+            local_ip: export_ip(self.local).to_string(),
+            remote_ip: export_ip(self.remote).to_string(),
+            abw: self.abw.unwrap_or(0.0),
+            abw_down: self.abw_down.unwrap_or(0.0),
+            running_abw: self.running_abw.unwrap_or(0.0),
+            capacity: self.capacity.unwrap_or(0.0),
+            latency: self.latency.unwrap_or(0.0),
+            thp_in: self.thp_in,
+            thp_out: self.thp_out,
+            age: self.age.map_or(0.0, |age| age.as_secs_f64()),
+            reported: self.age.is_some(),
+            stale: self.stale,
+        }
+    }
+}
+
+/// Asks the parser, which owns the links, for their estimates.
+#[derive(Debug, Clone)]
+pub struct LinkQuery {
+    sender: CapEventSender,
+}
+
+impl LinkQuery {
+    pub fn new(sender: CapEventSender) -> Self {
+        LinkQuery { sender }
+    }
+
+    /// Estimates of the link the traffic to `remote` is counted in, None if
+    /// there has been none.
+    pub async fn estimate(&self, remote: IpAddr) -> Result<Option<LinkEstimate>> {
+        Ok(self.ask(Some(remote)).await?.pop())
+    }
+
+    /// Estimates of every link.
+    pub async fn estimates(&self) -> Result<Vec<LinkEstimate>> {
+        self.ask(None).await
+    }
+
+    async fn ask(&self, remote: Option<IpAddr>) -> Result<Vec<LinkEstimate>> {
+        let (reply, rx) = oneshot::channel();
+        self.sender
+            .send(CapEvent::Estimates(remote, reply))
+            .await
+            .map_err(|_| anyhow!("Parser has stopped"))?;
+        match tokio::time::timeout(QUERY_TIMEOUT, rx).await {
+            Ok(Ok(estimates)) => Ok(estimates),
+            Ok(Err(_)) => Err(anyhow!("Estimate request was dropped")),
+            Err(_) => Err(anyhow!("Parser did not answer in time")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_age_and_stale() {
+        let now = Instant::now();
+        let (local, remote) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let window = Duration::from_secs(10);
+
+        let unreported = LinkEstimate::new(local, remote, None, Some(1e6), window, now);
+        assert_eq!((unreported.abw, unreported.age), (None, None));
+        assert_eq!(unreported.running_abw, Some(1e6));
+        assert!(unreported.stale);
+        assert!(!unreported.to_proto().reported);
+
+        let reported = Reported {
+            abw: Some(2e6),
+            abw_down: None,
+            capacity: None,
+            latency: Some(5_000.0),
+            thp_in: 10.0,
+            thp_out: 20.0,
+            at: now,
+        };
+        let fresh = LinkEstimate::new(local, remote, Some(&reported), None, window, now);
+        assert_eq!(fresh.abw, Some(2e6));
+        assert_eq!(fresh.age, Some(Duration::ZERO));
+        assert!(!fresh.stale);
+
+        let later = now + Duration::from_secs(21);
+        let old = LinkEstimate::new(local, remote, Some(&reported), None, window, later);
+        assert!(old.stale);
+        let proto = old.to_proto();
+        assert_eq!((proto.age, proto.abw, proto.abw_down), (21.0, 2e6, 0.0));
+    }
+}
@@ -0,0 +1,347 @@
+//! Per link feature vectors for ML pipelines, taken over fixed intervals.
+//!
+//! The feature set is defined here. To add a feature, add a `Feature`
+//! variant, collect what it needs in `FeatureWindow` and return it from
+//! `FeatureWindow::value`. Exporters only see names and values, so they pick
+//! it up as is.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::info;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+use crate::anonymize::export_pair;
+use crate::proto_bw;
+use crate::stream_id::IpPair;
+use crate::tap::{Tap, TapEvent, TapFilter};
+use crate::tcp_tracker::Burst;
+use crate::{Direction, ParsedPacket, Timestamp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// Inter-arrival time of incoming packets, µs.
+    IatInMean,
+    IatInVar,
+    /// Inter-arrival time of outgoing packets, µs.
+    IatOutMean,
+    IatOutVar,
+    /// Burst size in bytes.
+    BurstBytesMean,
+    BurstBytesVar,
+    /// Burst size in packets.
+    BurstPacketsMean,
+    /// Gap between two ACKs over the gap between the segments they acked.
+    AckGapRatioMean,
+    AckGapRatioVar,
+    Retransmissions,
+    PacketsIn,
+    PacketsOut,
+    BytesIn,
+    BytesOut,
+}
+
+impl Feature {
+    /// Every feature, in the order of the values in a `FeatureVector`.
+    pub const ALL: [Feature; 14] = [
+        Feature::IatInMean,
+        Feature::IatInVar,
+        Feature::IatOutMean,
+        Feature::IatOutVar,
+        Feature::BurstBytesMean,
+        Feature::BurstBytesVar,
+        Feature::BurstPacketsMean,
+        Feature::AckGapRatioMean,
+        Feature::AckGapRatioVar,
+        Feature::Retransmissions,
+        Feature::PacketsIn,
+        Feature::PacketsOut,
+        Feature::BytesIn,
+        Feature::BytesOut,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::IatInMean => "iat_in_mean",
+            Feature::IatInVar => "iat_in_var",
+            Feature::IatOutMean => "iat_out_mean",
+            Feature::IatOutVar => "iat_out_var",
+            Feature::BurstBytesMean => "burst_bytes_mean",
+            Feature::BurstBytesVar => "burst_bytes_var",
+            Feature::BurstPacketsMean => "burst_packets_mean",
+            Feature::AckGapRatioMean => "ack_gap_ratio_mean",
+            Feature::AckGapRatioVar => "ack_gap_ratio_var",
+            Feature::Retransmissions => "retransmissions",
+            Feature::PacketsIn => "packets_in",
+            Feature::PacketsOut => "packets_out",
+            Feature::BytesIn => "bytes_in",
+            Feature::BytesOut => "bytes_out",
+        }
+    }
+}
+
+/// Running mean and variance (Welford).
+#[derive(Debug, Default, Clone, Copy)]
+struct Moments {
+    n: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    fn add(&mut self, x: f64) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    /// 0 without samples.
+    fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// Population variance, 0 with fewer than two samples.
+    fn variance(&self) -> f64 {
+        if self.n < 2 {
+            0.0
+        } else {
+            self.m2 / self.n as f64
+        }
+    }
+}
+
+/// Features of one link over one interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureVector {
+    pub link: IpPair,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// One value per feature, in the order of `Feature::ALL`.
+    pub values: Vec<f64>,
+}
+
+impl FeatureVector {
+    pub fn csv_header() -> String {
+        let names: Vec<&str> = Feature::ALL.iter().map(|f| f.name()).collect();
+        format!("sender_ip,receiver_ip,start,end,{}", names.join(","))
+    }
+
+    /// One CSV line matching `csv_header`, timestamps in ms.
+    pub fn to_csv(&self) -> String {
+        let values: Vec<String> = self.values.iter().map(|v| v.to_string()).collect();
+        format!(
+            "{},{},{},{},{}",
+            self.link.local(),
+            self.link.remote(),
+            self.start.as_millis(),
+            self.end.as_millis(),
+            values.join(",")
+        )
+    }
+
+    pub fn to_proto(&self) -> proto_bw::FeatureVector {
+        proto_bw::FeatureVector {
+            sender_ip: self.link.local().to_string(),
+            receiver_ip: self.link.remote().to_string(),
+            start: self.start.as_millis() as i64,
+            end: self.end.as_millis() as i64,
+            names: Feature::ALL.iter().map(|f| f.name().to_string()).collect(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+/// Collects the features of one link until taken.
+#[derive(Debug)]
+pub struct FeatureWindow {
+    start: Timestamp,
+    /// Kept across intervals, so the first gap of an interval is counted.
+    last_in: Option<Timestamp>,
+    last_out: Option<Timestamp>,
+    iat_in: Moments,
+    iat_out: Moments,
+    burst_bytes: Moments,
+    burst_packets: Moments,
+    ack_gap_ratio: Moments,
+    retransmissions: u64,
+    packets_in: u64,
+    packets_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl FeatureWindow {
+    pub fn new(start: Timestamp) -> Self {
+        FeatureWindow {
+            start,
+            last_in: None,
+            last_out: None,
+            iat_in: Moments::default(),
+            iat_out: Moments::default(),
+            burst_bytes: Moments::default(),
+            burst_packets: Moments::default(),
+            ack_gap_ratio: Moments::default(),
+            retransmissions: 0,
+            packets_in: 0,
+            packets_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    pub fn record_packet(&mut self, packet: &ParsedPacket) {
+        let (last, iat) = match packet.direction {
+            Direction::Incoming => {
+                self.packets_in += 1;
+                self.bytes_in += packet.total_length as u64;
+                (&mut self.last_in, &mut self.iat_in)
+            }
+            Direction::Outgoing => {
+                self.packets_out += 1;
+                self.bytes_out += packet.total_length as u64;
+                (&mut self.last_out, &mut self.iat_out)
+            }
+        };
+        let seen = *last;
+        if let Some(gap) = seen.and_then(|seen| packet.timestamp.checked_duration_since(seen)) {
+            iat.add(gap.as_secs_f64() * 1e6);
+        }
+        *last = Some(seen.map_or(packet.timestamp, |seen| seen.max(packet.timestamp)));
+    }
+
+    pub fn record_burst(&mut self, burst: &Burst) {
+        if burst.is_empty() {
+            return;
+        }
+        self.burst_bytes.add(burst.burst_size_bytes() as f64);
+        self.burst_packets.add(burst.len() as f64);
+        if let Burst::Tcp(burst) = burst {
+            for pair in burst.packets.windows(2) {
+                let Some((gin, gout, _)) = pair[1].get_gin_gout_len(pair[0].ack_time) else {
+                    continue;
+                };
+                if gin > 0.0 {
+                    self.ack_gap_ratio.add(gout / gin);
+                }
+            }
+            self.retransmissions += burst.iter().map(|p| p.retransmissions as u64).sum::<u64>();
+        }
+    }
+
+    /// True if no packets were seen since the last take.
+    pub fn is_empty(&self) -> bool {
+        self.packets_in == 0 && self.packets_out == 0
+    }
+
+    fn value(&self, feature: Feature) -> f64 {
+        match feature {
+            Feature::IatInMean => self.iat_in.mean(),
+            Feature::IatInVar => self.iat_in.variance(),
+            Feature::IatOutMean => self.iat_out.mean(),
+            Feature::IatOutVar => self.iat_out.variance(),
+            Feature::BurstBytesMean => self.burst_bytes.mean(),
+            Feature::BurstBytesVar => self.burst_bytes.variance(),
+            Feature::BurstPacketsMean => self.burst_packets.mean(),
+            Feature::AckGapRatioMean => self.ack_gap_ratio.mean(),
+            Feature::AckGapRatioVar => self.ack_gap_ratio.variance(),
+            Feature::Retransmissions => self.retransmissions as f64,
+            Feature::PacketsIn => self.packets_in as f64,
+            Feature::PacketsOut => self.packets_out as f64,
+            Feature::BytesIn => self.bytes_in as f64,
+            Feature::BytesOut => self.bytes_out as f64,
+        }
+    }
+
+    /// The features up to `now` and a fresh window, None if the link was
+    /// idle.
+    pub fn take(&mut self, link: IpPair, now: Timestamp) -> Option<FeatureVector> {
+        let mut window = FeatureWindow::new(now);
+        window.last_in = self.last_in;
+        window.last_out = self.last_out;
+        let taken = std::mem::replace(self, window);
+        if taken.is_empty() {
+            return None;
+        }
+        Some(FeatureVector {
+            link,
+            start: taken.start,
+            end: now,
+            values: Feature::ALL.iter().map(|&f| taken.value(f)).collect(),
+        })
+    }
+}
+
+/// Appends the feature vectors published on `tap` to `path` as CSV, with a
+/// header if the file is new.
+pub fn dispatch_file_writer(path: PathBuf, tap: &Tap) -> JoinHandle<Result<()>> {
+    let mut features = Box::pin(tap.subscribe(TapFilter::features()));
+    tokio::spawn(async move {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        if file.metadata().await?.len() == 0 {
+            file.write_all(format!("{}\n", FeatureVector::csv_header()).as_bytes())
+                .await?;
+        }
+        info!("Writing feature vectors to {}", path.display());
+        while let Some(event) = features.next().await {
+            if let TapEvent::Features(mut vector) = event {
+                vector.link = export_pair(vector.link);
+                file.write_all(format!("{}\n", vector.to_csv()).as_bytes())
+                    .await?;
+                file.flush().await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn packet(timestamp: Timestamp, direction: Direction) -> ParsedPacket {
+        ParsedPacket::test_udp(4000, 5000, 100, timestamp, direction)
+    }
+
+    #[test]
+    fn test_take_features() {
+        let t0 = Timestamp::from_millis(1_000_000);
+        let link = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let mut window = FeatureWindow::new(t0);
+        // Outgoing every 1 and 3 ms
+        for ms in [0, 1, 4, 5, 8] {
+            window.record_packet(&packet(t0 + Duration::from_millis(ms), Direction::Outgoing));
+        }
+        window.record_packet(&packet(t0, Direction::Incoming));
+
+        let end = t0 + Duration::from_millis(10);
+        let vector = window.take(link, end).unwrap();
+        let value = |feature: Feature| {
+            let i = Feature::ALL.iter().position(|&f| f == feature).unwrap();
+            vector.values[i]
+        };
+        assert_eq!(vector.values.len(), Feature::ALL.len());
+        assert_eq!((vector.start, vector.end), (t0, end));
+        assert!((value(Feature::IatOutMean) - 2000.0).abs() < 1e-6);
+        assert!((value(Feature::IatOutVar) - 1e6).abs() < 1e-3);
+        assert_eq!(value(Feature::IatInMean), 0.0);
+        assert_eq!(value(Feature::PacketsOut), 5.0);
+        assert_eq!(value(Feature::BytesIn), 128.0);
+
+        // Nothing new, the last arrival carries over
+        assert!(window.take(link, end).is_none());
+        window.record_packet(&packet(end, Direction::Incoming));
+        let vector = window.take(link, end + Duration::from_millis(10)).unwrap();
+        assert_eq!(vector.values[0], 10_000.0);
+        assert_eq!(FeatureVector::csv_header().split(',').count(), vector.to_csv().split(',').count());
+    }
+}
@@ -0,0 +1,219 @@
+//! Flow completion time of TCP connections, from the SYN to the first FIN
+//! or RST. Short flows are what most requests look like, and their
+//! completion time says more about how a link feels than its throughput.
+//!
+//! Connections whose SYN was not seen, e.g. those open before the capture
+//! started, are not timed.
+//!
+//! With `client.flow_records`, each connection that ended is also listed in
+//! the link state, named after the local process that opened it with
+//! `client.flow_owners`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::listener::procfs_reader::SocketOwner;
+use crate::stream_id::{IpPair, StreamKey};
+use crate::throughput::percentile;
+use crate::{Direction, TcpFlags, Timestamp};
+
+/// Most flow records kept per link and report, later ones are left out.
+pub const MAX_FLOW_RECORDS: usize = 1000;
+/// How long the owner of a connection is remembered after the last procfs
+/// poll that saw it, flows are reported a while after they end.
+const OWNER_TTL: Duration = Duration::from_secs(300);
+
+/// A connection that has finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlowCompletion {
+    /// From the SYN to the first FIN or RST.
+    pub duration: Duration,
+    /// Payload bytes in both directions.
+    pub bytes: u64,
+    /// Opened by this host.
+    pub outgoing: bool,
+}
+
+/// A connection that ended, for the flow records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinishedFlow {
+    pub key: StreamKey,
+    /// Hosts of the connection, which may differ from the link's if links
+    /// are aggregated.
+    pub hosts: IpPair,
+    pub completion: FlowCompletion,
+    /// Local process that opened it, if known.
+    pub process: Option<String>,
+}
+
+/// Processes behind the connections of this host, from the procfs polls.
+#[derive(Debug, Default)]
+pub struct FlowOwners {
+    owners: HashMap<(StreamKey, IpPair), (String, Timestamp)>,
+}
+
+impl FlowOwners {
+    pub fn new() -> Self {
+        FlowOwners::default()
+    }
+
+    /// Adds the owners from a poll at `now`, and forgets those not seen for
+    /// `OWNER_TTL`.
+    pub fn update(&mut self, owners: HashMap<(StreamKey, IpPair), SocketOwner>, now: Timestamp) {
+        for (key, owner) in owners {
+            self.owners.insert(key, (owner.name, now));
+        }
+        self.owners.retain(|_, (_, seen)| now.saturating_duration_since(*seen) < OWNER_TTL);
+    }
+
+    /// Name of the process behind the connection, if it was seen.
+    pub fn get(&self, key: StreamKey, hosts: IpPair) -> Option<&str> {
+        self.owners.get(&(key, hosts)).map(|(name, _)| name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+}
+
+/// Times one TCP connection.
+#[derive(Debug, Default)]
+pub struct FlowTimer {
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+    bytes: u64,
+    outgoing: bool,
+    taken: bool,
+}
+
+impl FlowTimer {
+    pub fn new() -> Self {
+        FlowTimer::default()
+    }
+
+    /// Records a segment in either direction.
+    pub fn record(
+        &mut self,
+        timestamp: Timestamp,
+        flags: TcpFlags,
+        payload_len: u16,
+        direction: Direction,
+    ) {
+        if self.end.is_some() {
+            return;
+        }
+        // Retransmitted SYNs do not restart the timer
+        if flags.is_syn() && !flags.is_ack() && self.start.is_none() {
+            self.start = Some(timestamp);
+            self.outgoing = direction == Direction::Outgoing;
+        }
+        if self.start.is_none() {
+            return;
+        }
+        self.bytes += payload_len as u64;
+        if flags.is_fin() || flags.is_rst() {
+            self.end = Some(timestamp);
+        }
+    }
+
+    /// The completed flow, only once.
+    pub fn take_completion(&mut self) -> Option<FlowCompletion> {
+        if self.taken {
+            return None;
+        }
+        let (start, end) = (self.start?, self.end?);
+        self.taken = true;
+        Some(FlowCompletion {
+            duration: end.saturating_duration_since(start),
+            bytes: self.bytes,
+            outgoing: self.outgoing,
+        })
+    }
+}
+
+/// Completion times of the short flows in a report window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FctPercentiles {
+    /// Seconds
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+    pub flows: u32,
+}
+
+impl FctPercentiles {
+    /// None without any flows. `times` are in seconds.
+    pub fn new(mut times: Vec<f64>) -> Option<Self> {
+        if times.is_empty() {
+            return None;
+        }
+        times.sort_by(|a, b| a.total_cmp(b));
+        Some(FctPercentiles {
+            p50: percentile(&times, 0.5),
+            p95: percentile(&times, 0.95),
+            max: times[times.len() - 1],
+            flows: times.len() as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+
+    #[test]
+    fn test_syn_to_fin() {
+        let t0 = Timestamp::from_millis(1_000_000);
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+
+        let (out, inc) = (Direction::Outgoing, Direction::Incoming);
+
+        let mut timer = FlowTimer::new();
+        // Open before the SYN was seen
+        timer.record(at(0), TcpFlags::new(TcpFlags::ACK), 100, inc);
+        timer.record(at(10), TcpFlags::new(TcpFlags::SYN), 0, out);
+        timer.record(at(20), TcpFlags::new(TcpFlags::SYN | TcpFlags::ACK), 0, inc);
+        timer.record(at(30), TcpFlags::new(TcpFlags::ACK), 500, out);
+        assert_eq!(timer.take_completion(), None);
+        timer.record(at(250), TcpFlags::new(TcpFlags::FIN | TcpFlags::ACK), 1500, inc);
+        timer.record(at(260), TcpFlags::new(TcpFlags::FIN | TcpFlags::ACK), 0, out);
+
+        let flow = timer.take_completion().unwrap();
+        assert_eq!(flow.duration, Duration::from_millis(240));
+        assert_eq!(flow.bytes, 2000);
+        assert!(flow.outgoing);
+        assert_eq!(timer.take_completion(), None);
+
+        let fct = FctPercentiles::new(vec![0.3, 0.1, 0.2]).unwrap();
+        assert_eq!((fct.p50, fct.max, fct.flows), (0.2, 0.3, 3));
+        assert_eq!(FctPercentiles::new(Vec::new()), None);
+    }
+
+    #[test]
+    fn test_flow_owners() {
+        let t0 = Timestamp::from_millis(1_000_000);
+        let key = StreamKey::new(IpNextHeaderProtocols::Tcp, Some(40000), Some(443));
+        let hosts = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let curl = SocketOwner {
+            pid: 42,
+            name: "curl".to_string(),
+        };
+
+        let mut owners = FlowOwners::new();
+        owners.update(HashMap::from([((key, hosts), curl)]), t0);
+        assert_eq!(owners.get(key, hosts), Some("curl"));
+        let other = StreamKey::new(IpNextHeaderProtocols::Tcp, Some(40001), Some(443));
+        assert_eq!(owners.get(other, hosts), None);
+
+        // Kept a while after the connection is gone
+        owners.update(HashMap::new(), t0 + Duration::from_secs(60));
+        assert_eq!(owners.len(), 1);
+        owners.update(HashMap::new(), t0 + OWNER_TTL);
+        assert_eq!(owners.len(), 0);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::listener::packet::ParsedPacket;
-use crate::{Direction, PacketType};
+use crate::{Direction, PacketType, Timestamp, CONFIG};
 use pnet::packet::ip::IpNextHeaderProtocol;
+use std::time::Duration;
 
 use super::tcp_tracker::Burst;
 
@@ -8,11 +9,10 @@ use super::tcp_tracker::Burst;
 ///
 /// A `GenericTracker` accumulates incoming and outgoing `PacketType` instances,
 /// resetting and emitting bursts when a sufficient time gap has elapsed or
-/// when the burst grows too large.
+/// when the burst grows too large (`client.other_burst_gap` and
+/// `client.other_burst_packets`).
 ///
-/// This tracker is not currently used for any specific purpose, but serves as a
-/// placeholder for future implementations which may require tracking
-/// other transport-layer protocols.
+/// Used for ICMP and any other protocol without a dedicated tracker.
 #[derive(Debug)]
 pub struct GenericTracker {
     /// Protocol type of the packets being tracked.
@@ -22,27 +22,47 @@ pub struct GenericTracker {
     /// Outgoing burst of packets.
     burst_out: Vec<PacketType>,
     /// Timestamp of the last incoming packet.
-    last_in: std::time::SystemTime,
+    last_in: Timestamp,
     /// Timestamp of the last outgoing packet.
-    last_out: std::time::SystemTime,
+    last_out: Timestamp,
+    /// Gap which ends a burst.
+    max_gap: Duration,
+    /// Packets at which a burst is cut.
+    max_packets: usize,
 }
 
 impl GenericTracker {
-    /// Creates a new `GenericTracker` for the given IP protocol.
+    /// Creates a new `GenericTracker` for the given IP protocol, with burst
+    /// thresholds from the config.
     pub fn new(protocol: IpNextHeaderProtocol) -> Self {
+        Self::with_thresholds(
+            protocol,
+            CONFIG.client.other_burst_gap,
+            CONFIG.client.other_burst_packets,
+        )
+    }
+
+    pub fn with_thresholds(
+        protocol: IpNextHeaderProtocol,
+        max_gap: Duration,
+        max_packets: usize,
+    ) -> Self {
         GenericTracker {
             protocol,
             burst_in: Vec::new(),
             burst_out: Vec::new(),
-            last_in: std::time::SystemTime::UNIX_EPOCH,
-            last_out: std::time::SystemTime::UNIX_EPOCH,
+            last_in: Timestamp::ZERO,
+            last_out: Timestamp::ZERO,
+            max_gap,
+            max_packets: max_packets.max(1),
         }
     }
 
     /// Registers a parsed packet, returning a completed burst if one has just ended.
     ///
     /// A burst ends and is emitted when the time since the last packet
-    /// in its direction exceeds 1 second, or when the burst reaches 100 packets.
+    /// in its direction exceeds the maximum gap, or when the burst reaches
+    /// the maximum number of packets.
     ///
     /// # Arguments
     ///
@@ -57,15 +77,14 @@ impl GenericTracker {
         let mut ret = Vec::new();
 
         // Choose the burst and last timestamp based on direction
-        let (mut burst, last) = match packet.direction {
+        let (burst, last) = match packet.direction {
             Direction::Incoming => (&mut self.burst_in, &mut self.last_in),
             Direction::Outgoing => (&mut self.burst_out, &mut self.last_out),
         };
 
-        if let Ok(dur) = packet.timestamp.duration_since(*last) {
-            if dur > std::time::Duration::from_secs(1) || burst.len() == 100 {
-                std::mem::swap(&mut ret, &mut burst);
-            }
+        let dur = packet.timestamp.saturating_duration_since(*last);
+        if dur > self.max_gap || burst.len() >= self.max_packets {
+            std::mem::swap(&mut ret, burst);
         }
 
         // Add this packet and update last timestamp
@@ -75,20 +94,20 @@ impl GenericTracker {
         if ret.is_empty() {
             None
         } else {
-            Some((Burst::Udp(ret), packet.direction))
+            Some((Burst::Other(ret), packet.direction))
         }
     }
 
     /// Takes all accumulated bursts, resetting the tracker.
     ///
     /// # Returns
-    /// - A pair `(incoming_burst, outgoing_burst)`, each wrapped in `Burst::Other`.
+    /// - A pair `(outgoing_burst, incoming_burst)`, each wrapped in `Burst::Other`.
     pub fn take_bursts(&mut self) -> (Burst, Burst) {
         let mut in_burst = Vec::new();
         let mut out_burst = Vec::new();
         std::mem::swap(&mut in_burst, &mut self.burst_in);
         std::mem::swap(&mut out_burst, &mut self.burst_out);
-        (Burst::Other(in_burst), Burst::Other(out_burst))
+        (Burst::Other(out_burst), Burst::Other(in_burst))
     }
 }
 
@@ -105,10 +124,40 @@ mod tests {
         assert_eq!(tracker.protocol, IpNextHeaderProtocols::Udp);
     }
 
+    #[test]
+    fn test_bursts_cut_by_thresholds() {
+        use crate::{Direction, TransportPacket};
+
+        let packet = |ms: u64| {
+            let timestamp = Timestamp::from_millis(ms);
+            ParsedPacket::test_packet(TransportPacket::ICMP, timestamp, Direction::Outgoing)
+        };
+        let mut tracker = GenericTracker::with_thresholds(
+            IpNextHeaderProtocols::Icmp,
+            Duration::from_millis(100),
+            3,
+        );
+
+        // Cut by size
+        assert!(tracker.register_packet(&packet(1000)).is_none());
+        assert!(tracker.register_packet(&packet(1010)).is_none());
+        assert!(tracker.register_packet(&packet(1020)).is_none());
+        match tracker.register_packet(&packet(1030)) {
+            Some((Burst::Other(packets), Direction::Outgoing)) => assert_eq!(packets.len(), 3),
+            _ => panic!("Expected an outgoing Other burst"),
+        }
+
+        // Cut by gap
+        match tracker.register_packet(&packet(1500)) {
+            Some((Burst::Other(packets), _)) => assert_eq!(packets.len(), 1),
+            _ => panic!("Expected an Other burst"),
+        }
+    }
+
     #[test]
     fn test_take_bursts_empty() {
         let mut tracker = GenericTracker::new(IpNextHeaderProtocols::Tcp);
-        let (in_burst, out_burst) = tracker.take_bursts();
+        let (out_burst, in_burst) = tracker.take_bursts();
         match in_burst {
             Burst::Other(vec) => assert!(vec.is_empty()),
             _ => panic!("Expected Other variant for incoming burst"),
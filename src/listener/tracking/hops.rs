@@ -0,0 +1,143 @@
+//! Path changes from the TTL (hop limit) of incoming packets.
+//!
+//! Senders start from one of a few initial TTLs, so the hops a packet took
+//! are its distance to the next one up. The count is kept per remote host,
+//! as the flows of a host share its path while hosts behind an aggregated
+//! link may not. A different count means the host moved to a path through
+//! another number of hops, e.g. after a reroute in the mesh. It has to hold
+//! for `CONFIRM_PACKETS` packets in a row, so a stray packet does not count.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::Timestamp;
+
+/// Initial TTLs in use, Windows starts at 128 and most others at 64.
+const INITIAL_TTLS: [u8; 4] = [32, 64, 128, 255];
+/// Packets in a row with a new hop count before the path counts as changed.
+const CONFIRM_PACKETS: u32 = 8;
+/// Hosts not heard from for this long are forgotten.
+const HOST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Hops from the sender to here of a packet that arrived with `ttl`.
+pub fn hops(ttl: u8) -> u8 {
+    let initial = INITIAL_TTLS.into_iter().find(|&initial| initial >= ttl);
+    initial.unwrap_or(u8::MAX) - ttl
+}
+
+/// A host reached over a different number of hops than before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathChange {
+    pub remote: IpAddr,
+    pub from: u8,
+    pub to: u8,
+    /// Arrival of the packet that confirmed the new count.
+    pub timestamp: Timestamp,
+}
+
+#[derive(Debug)]
+struct HostHops {
+    hops: u8,
+    /// A different count and the packets in a row that had it.
+    candidate: Option<(u8, u32)>,
+    last_seen: Timestamp,
+}
+
+/// Hop counts of the hosts sending over a link.
+#[derive(Debug, Default)]
+pub struct HopTracker {
+    hosts: HashMap<IpAddr, HostHops>,
+    /// Path changes since the last `take_changes`.
+    changes: u32,
+}
+
+impl HopTracker {
+    /// Records the TTL of a packet from `remote`. Returns the change if the
+    /// packet confirmed a new hop count.
+    pub fn record(&mut self, remote: IpAddr, ttl: u8, timestamp: Timestamp) -> Option<PathChange> {
+        let hops = hops(ttl);
+        let host = self.hosts.entry(remote).or_insert(HostHops {
+            hops,
+            candidate: None,
+            last_seen: timestamp,
+        });
+        host.last_seen = timestamp;
+        if hops == host.hops {
+            host.candidate = None;
+            return None;
+        }
+        let seen = match host.candidate {
+            Some((candidate, seen)) if candidate == hops => seen + 1,
+            _ => 1,
+        };
+        if seen < CONFIRM_PACKETS {
+            host.candidate = Some((hops, seen));
+            return None;
+        }
+        let change = PathChange {
+            remote,
+            from: host.hops,
+            to: hops,
+            timestamp,
+        };
+        host.hops = hops;
+        host.candidate = None;
+        self.changes += 1;
+        Some(change)
+    }
+
+    /// Hops from `remote`, None if nothing was heard from it.
+    pub fn hops(&self, remote: IpAddr) -> Option<u8> {
+        self.hosts.get(&remote).map(|host| host.hops)
+    }
+
+    /// Take the number of path changes since the last call.
+    pub fn take_changes(&mut self) -> u32 {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Forgets the hosts not heard from in `HOST_TIMEOUT`.
+    pub fn prune(&mut self, now: Timestamp) {
+        self.hosts
+            .retain(|_, host| host.last_seen + HOST_TIMEOUT > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hops() {
+        assert_eq!(hops(64), 0);
+        assert_eq!(hops(61), 3);
+        assert_eq!(hops(120), 8);
+        assert_eq!(hops(250), 5);
+        assert_eq!(hops(1), 31);
+    }
+
+    #[test]
+    fn test_path_change_needs_confirmation() {
+        let remote: IpAddr = [10, 0, 0, 2].into();
+        let t0 = Timestamp::from_millis(1_000_000);
+        let mut tracker = HopTracker::default();
+        assert_eq!(tracker.record(remote, 62, t0), None);
+        assert_eq!(tracker.hops(remote), Some(2));
+
+        // A stray packet over another path
+        assert_eq!(tracker.record(remote, 60, t0), None);
+        assert_eq!(tracker.record(remote, 62, t0), None);
+        for _ in 1..CONFIRM_PACKETS {
+            assert_eq!(tracker.record(remote, 60, t0), None);
+        }
+        let change = tracker.record(remote, 60, t0).unwrap();
+        assert_eq!((change.from, change.to), (2, 4));
+        assert_eq!(tracker.hops(remote), Some(4));
+        assert_eq!(tracker.take_changes(), 1);
+        assert_eq!(tracker.take_changes(), 0);
+
+        tracker.prune(t0 + HOST_TIMEOUT);
+        assert_eq!(tracker.hops(remote), None);
+    }
+}
@@ -1,44 +1,142 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
-    net::{AddrParseError, IpAddr},
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr},
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    str::FromStr,
+    time::Duration,
 };
 
 use crate::{
     proto_bw::{
-        data_msg, BandwidthMessage, DataMsg, LinkState as LinkStateProto, PgmDp, PgmDps,
-        PgmMessage, Rtt, RttMessage, Rtts,
+        data_msg, BandwidthMessage, DataMsg, HelloReply, LinkState as LinkStateProto,
+        EstimatorAbw, FlowCompletionTimes, FlowRecord, LinkEvents, LinkStatus, NodeCapabilities,
+        NodeStatus, PeerIdentity, PgmDp, PgmDps, PgmMessage, ProbeSource, ReceiveWindowStats,
+        Report, Rtt, RttBucket, RttMessage, Rtts, ThroughputPercentiles,
     },
-    PacketRegistry,
+    probe::session::{ProbeSession, ProbeTechnique},
+    probe::ping::{PingReply, PingTrainResult},
+    probe::result::{ProbeOutcome, ProbeResult},
+    probe::train::TrainResult,
+    tap::{LinkStateConsumers, PacketSummary, ProbeSample, Tap, TapEvent},
+    prost_net::capabilities::{check_compatible, supports_probe},
+    prost_net::clock::{ClockOffset, ClockSample},
+    prost_net::trace,
+    AbwEstimate, OffloadPolicy, PacketRegistry, RegressionType,
 };
 
-use log::{info, warn};
+use log::{debug, info, warn};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 
 use crate::{
+    channel::send_or_log,
+    identity,
     listener::{packet::ParsedPacket, tracking::stream_manager::StreamManager},
     prost_net::bandwidth_client::ClientHandlerEvent,
     CONFIG,
 };
 
-use super::stream_id::IpPair;
-use crate::PCAPMeta;
+use super::aggregation::LinkAggregation;
+use super::baseline_state::BaselineState;
+use super::bufferbloat::Bufferbloat;
+use super::estimates::{LinkEstimate, Reported};
+use super::flow_time::{FctPercentiles, FinishedFlow, FlowOwners};
+use super::link_events::{LinkEventTracker, Metrics};
+use super::rwnd::WindowSummary;
+use super::neighbors::{NeighborTable, Reachability};
+use super::probe_traffic::ProbeTraffic;
+use super::relay::{RelayCopy, RelayTracker};
+use super::stream_id::{IpPair, StreamKey};
+use super::throughput::Percentiles;
+use crate::listener::capture::LinkType;
+use crate::listener::procfs_reader::SocketOwner;
+use crate::listener::routes::{NextHop, RouteTable};
+use crate::listener::packet::neighbor::NeighborPacket;
+use crate::{Direction, PCAPMeta, Timestamp};
 
 type Streams = HashMap<IpPair, StreamManager>;
 
+/// Time between the echoes sent to a peer by `ping_peers`.
+const PING_SPACING: Duration = Duration::from_secs(1);
+
 /// Manages multiple IP-pair streams, collects metrics, and sends protobuf messages.
 #[derive(Debug)]
 pub struct LinkManager {
     /// Active streams keyed by local/remote IP pairs.
     links: Streams,
-    /// links of special interest (Naming needs to be changed)
+    /// Links to peers we have a gRPC connection to. These report more
+    /// often and get probed on a schedule.
     vip_links: HashSet<IpPair>,
+    /// Last time a probe was requested towards each VIP peer.
+    last_vip_probe: HashMap<IpAddr, Instant>,
+    /// Data points toward the remote that passed the gap filter in each
+    /// link's last report.
+    passive_points: HashMap<IpPair, usize>,
+    /// What each link's last report said, for `estimate`.
+    reported: HashMap<IpPair, Reported>,
+    /// Threshold crossings of each link, see `send_events`.
+    events: LinkEventTracker,
+    /// Clock offset to each peer from SyncClock exchanges.
+    clock_offsets: HashMap<IpAddr, ClockOffset>,
+    /// Capabilities advertised by peers in their hello replies.
+    peer_capabilities: HashMap<IpAddr, NodeCapabilities>,
+    /// Node ids peers sent in their hellos, with `identity.enabled` on
+    /// their end.
+    peer_ids: HashMap<IpAddr, String>,
+    /// Last address of each node id in `peer_ids`.
+    peer_addrs: HashMap<String, IpAddr>,
+    /// Turns down replayed hellos, which could move a peer back.
+    peer_replays: identity::ReplayGuard,
+    /// Active probe sessions, used to tag measurement traffic.
+    probe_traffic: ProbeTraffic,
+    /// Packets seen twice on their way through this node.
+    relay: RelayTracker,
+    /// What `links` are keyed by, peers always get their own link.
+    aggregation: LinkAggregation,
+    /// Kernel routing table, for the next hop of each link.
+    routes: RouteTable,
+    /// The parser is shedding load, see `set_load_shedding`.
+    shedding: bool,
+    /// Links whose report window overlapped the last load shedding, to be
+    /// flagged as degraded in their next report.
+    degraded: HashSet<IpPair>,
+    /// Remotes counted under another link since the last
+    /// `send_init_clients_msg`, offered to the client handler in its place.
+    aggregated_hosts: HashSet<IpAddr>,
+    /// Reachability of neighbors from ARP and neighbor discovery.
+    neighbors: NeighborTable,
+    /// Processes behind local connections, with `client.flow_owners`.
+    flow_owners: FlowOwners,
+    /// Baselines kept across restarts, None unless `client.state_file` is
+    /// set.
+    baseline_state: Option<BaselineState>,
+    /// RTT samples waiting for the next `send_rtts`.
+    pending_rtts: Vec<RttMessage>,
+    /// PGM data points waiting for the next `send_pgm`.
+    pending_pgm: Vec<PgmDps>,
+    /// Start of the window of the next report, with `server.batch_reports`.
+    window_start: Timestamp,
+    /// Number of the last report window, sent along for tracing it.
+    window_id: u64,
+    /// Estimator behind `abw` in link states, can be switched at runtime.
+    estimator: RegressionType,
+    /// Published to library users, see `tap`.
+    tap: Tap,
+    /// Called with every link state reported.
+    consumers: LinkStateConsumers,
     /// Channel to send events to the bandwidth client handler.
     client_sender: Sender<ClientHandlerEvent>,
     /// Metadata from PCAP (local IPs).
     pcap_meta: Arc<PCAPMeta>,
+    /// Tags the super-frames of the captured interface.
+    offload: OffloadPolicy,
+    /// ACK groups the TCP trackers of every link skipped since the last
+    /// `take_skipped_groups`.
+    skipped_groups: u64,
+    /// Super-frames since the last `take_super_frames`.
+    super_frames: u64,
 }
 
 impl LinkManager {
@@ -47,8 +145,66 @@ impl LinkManager {
         LinkManager {
             links: HashMap::new(),
             vip_links: HashSet::new(),
+            last_vip_probe: HashMap::new(),
+            passive_points: HashMap::new(),
+            reported: HashMap::new(),
+            events: LinkEventTracker::from_config(),
+            peer_capabilities: HashMap::new(),
+            peer_ids: HashMap::new(),
+            peer_addrs: HashMap::new(),
+            peer_replays: identity::ReplayGuard::new(),
+            clock_offsets: HashMap::new(),
+            probe_traffic: ProbeTraffic::new(),
+            relay: RelayTracker::new(),
+            aggregation: LinkAggregation::from_config(),
+            routes: RouteTable::read(),
+            shedding: false,
+            degraded: HashSet::new(),
+            aggregated_hosts: HashSet::new(),
+            neighbors: NeighborTable::new(),
+            flow_owners: FlowOwners::new(),
+            baseline_state: BaselineState::from_config(),
+            pending_rtts: Vec::new(),
+            pending_pgm: Vec::new(),
+            window_start: Timestamp::now(),
+            window_id: 0,
+            estimator: CONFIG.client.regression_type,
+            tap: Tap::new(),
+            consumers: LinkStateConsumers::default(),
             client_sender,
+            offload: OffloadPolicy::for_interface(&pcap_meta.name),
             pcap_meta,
+            skipped_groups: 0,
+            super_frames: 0,
+        }
+    }
+
+    /// Publishes packets, bursts, link states and probe results on `tap`.
+    pub fn set_tap(&mut self, tap: Tap) {
+        self.tap = tap;
+    }
+
+    /// Hands every link state reported to `consumers`.
+    pub fn set_consumers(&mut self, consumers: LinkStateConsumers) {
+        self.consumers = consumers;
+    }
+
+    /// While shedding, link states are flagged as degraded and no RTT
+    /// messages are built. Links reported after it ends are flagged once more,
+    /// their window covered part of it.
+    pub fn set_load_shedding(&mut self, shedding: bool) {
+        if self.shedding && !shedding {
+            self.degraded.extend(self.links.keys().copied());
+        }
+        self.shedding = shedding;
+    }
+
+    /// Switches the estimator used for `abw` and `abw_down` from the next
+    /// report on.
+    pub fn set_estimator(&mut self, estimator: RegressionType) {
+        if self.estimator != estimator {
+            info!("Switching estimator from {} to {}", self.estimator.name(), estimator.name());
+            self.estimator = estimator;
         }
     }
 
@@ -61,53 +217,268 @@ impl LinkManager {
         self.links.get(&ip_pair)
     }
 
-    /// Inserts a parsed packet into the appropriate stream manager.
-    ///
-    /// Filters out loopback and multicast, and any packet to/from the server port.
-    pub fn insert(&mut self, packet: ParsedPacket) {
-        // Ignore if loopback
-        if packet.src_ip.is_loopback() || packet.dst_ip.is_loopback() {
-            return;
+    /// Inserts a parsed packet into the appropriate stream manager. The
+    /// parser has already dropped what its `FilterChain` filters out.
+    /// Packets generated by active probes are counted separately, and kept out
+    /// of the trackers if `client.exclude_probe_traffic` is set. GSO/GRO
+    /// super-frames are tagged as `[offload]` says for the interface.
+    pub fn insert(&mut self, mut packet: ParsedPacket) {
+        if self.offload.is_super_frame(&packet) {
+            self.super_frames += 1;
         }
-        // This is done in the current implementation as a hack to avoid spamming
-        // all clients seen with gRPC hello messages.
-        if packet.src_ip.is_multicast() || packet.dst_ip.is_multicast() {
-            return;
+        self.offload.apply(&mut packet);
+        let host_pair = match self.relay_hop(&mut packet) {
+            Some(hop_pair) => hop_pair,
+            None => IpPair::from_packet(&packet),
+        };
+        let ip_pair = self.link_key(host_pair);
+        if ip_pair.remote() != host_pair.remote() {
+            self.aggregated_hosts.insert(host_pair.remote());
+        }
+
+        let stream_manager = self
+            .links
+            .entry(ip_pair)
+            .or_insert_with(StreamManager::default);
+
+        if let Some(change) = stream_manager.record_ttl(&packet) {
+            info!(
+                "Path change: {} is {} hops away, was {}",
+                change.remote, change.to, change.from
+            );
+            self.tap.publish(TapEvent::PathChange(ip_pair, change));
         }
 
-        if let Some((src_port, dst_port)) = packet.get_src_dst_port() {
-            if dst_port == crate::CONFIG.server.port || src_port == crate::CONFIG.server.port {
+        let probe = self.probe_traffic.matches(&packet, host_pair.remote());
+        let tapped = self.tap.is_active();
+        if tapped {
+            self.tap.publish(TapEvent::Packet(PacketSummary::new(&packet, probe)));
+        }
+        if probe {
+            stream_manager.record_probe_packet(&packet);
+            if CONFIG.client.exclude_probe_traffic {
                 return;
             }
         }
-        let ip_pair = IpPair::from_packet(&packet);
+        stream_manager.set_summarize_bursts(tapped);
+        stream_manager.record_packet(&packet);
+        self.skipped_groups += stream_manager.take_skipped_groups();
+        for burst in stream_manager.take_burst_summaries() {
+            self.tap.publish(TapEvent::Burst(ip_pair, burst));
+        }
+    }
+
+    /// ACK groups the TCP trackers skipped since the last call, for the
+    /// parser's `Coverage`.
+    pub fn take_skipped_groups(&mut self) -> u64 {
+        std::mem::take(&mut self.skipped_groups)
+    }
+
+    /// GSO/GRO super-frames inserted since the last call, for the parser's
+    /// `Coverage`.
+    pub fn take_super_frames(&mut self) -> u64 {
+        std::mem::take(&mut self.super_frames)
+    }
+
+    /// Link to the hop a copy of a relayed packet came from or goes to, see
+    /// `client.relay_links`. The copy is turned to be received from or sent
+    /// to the hop.
+    fn relay_hop(&mut self, packet: &mut ParsedPacket) -> Option<IpPair> {
+        if !CONFIG.client.relay_links {
+            return None;
+        }
+        let (direction, remote) = match self.relay.observe(packet)? {
+            RelayCopy::Ingress => (Direction::Incoming, packet.src_ip),
+            RelayCopy::Egress => (Direction::Outgoing, packet.dst_ip),
+        };
+        let hop = self.routes.lookup(remote).map_or(remote, |hop| hop.addr);
+        packet.direction = direction;
+        Some(self.host_pair(hop))
+    }
+
+    /// Link the traffic of `host_pair` is counted in, see
+    /// `client.link_aggregation`.
+    fn link_key(&self, host_pair: IpPair) -> IpPair {
+        if self.vip_links.contains(&host_pair) {
+            return host_pair;
+        }
+        self.aggregation.key(host_pair, &self.routes)
+    }
+
+    /// Records an ARP or neighbor discovery message.
+    pub fn insert_neighbor(&mut self, packet: NeighborPacket) {
+        let from_local = self.pcap_meta.matches_ip(packet.sender_ip);
+        self.neighbors.insert(&packet, from_local);
+    }
+
+    /// Replaces the local interface metadata after the capture was reopened.
+    pub fn set_pcap_meta(&mut self, pcap_meta: Arc<PCAPMeta>) {
+        self.offload = OffloadPolicy::for_interface(&pcap_meta.name);
+        self.pcap_meta = pcap_meta;
+    }
+
+    /// Registers an active probe session so its traffic can be tagged.
+    pub fn register_probe(&mut self, session: ProbeSession) {
+        self.probe_traffic.register(session);
+    }
 
+    /// Inserts an iperf throughput in bits per second for a link, `Outgoing`
+    /// if this host sent the test data.
+    pub fn insert_iperf_result(&mut self, ip_pair: IpPair, bps: f64, direction: Direction) {
+        let recorded = self
+            .links
+            .entry(ip_pair)
+            .or_insert_with(StreamManager::default)
+            .record_probe(ProbeSource::Iperf, direction, bps / 8.0, None);
+        if recorded {
+            self.publish_probe(ip_pair.remote(), ProbeTechnique::Iperf3, bps / 8.0);
+        }
+    }
+
+    /// Inserts jitter and loss from a UDP iperf test for a given link.
+    pub fn insert_iperf_udp_result(&mut self, ip_pair: IpPair, jitter_ms: f64, lost_percent: f64) {
         self.links
             .entry(ip_pair)
             .or_insert_with(StreamManager::default)
-            .record_packet(&packet);
+            .record_iperf_udp_result(jitter_ms, lost_percent);
     }
 
-    /// Inserts iperf measurement results into the registry for a given stream.
-    ///
-    /// Proof of concept for future active measurement integration.
-    pub fn insert_iperf_result(
+    /// The local address toward `remote`, see `PCAPMeta::get_match`, and
+    /// `remote`.
+    fn host_pair(&self, remote: IpAddr) -> IpPair {
+        let local = self.pcap_meta.get_match(remote).unwrap_or(match remote {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        IpPair::new(local, remote)
+    }
+
+    /// Records the result of an active probe on the link toward its target.
+    pub fn insert_probe_result(&mut self, result: ProbeResult) {
+        let ProbeResult {
+            target,
+            probe_id,
+            elapsed,
+            outcome,
+            ..
+        } = result;
+        debug!("Probe {:?} of {} done after {:?}", probe_id, target, elapsed);
+        let ip_pair = self.link_key(self.host_pair(target));
+        match outcome {
+            ProbeOutcome::Ping(reply) => self.insert_ping_reply(ip_pair, reply),
+            ProbeOutcome::PingTrain(train) => self.insert_ping_train(ip_pair, train),
+            ProbeOutcome::Train(train) => self.insert_train_result(ip_pair, train, probe_id),
+            ProbeOutcome::Pathload(pathload) => {
+                // Run as the receiver, it measures the path from the target
+                info!(
+                    "Pathload from {}: {:.0} to {:.0} bytes/sec",
+                    target, pathload.low, pathload.high
+                );
+                let recorded = self
+                    .links
+                    .entry(ip_pair)
+                    .or_insert_with(StreamManager::default)
+                    .record_probe(
+                        ProbeSource::Pathload,
+                        Direction::Incoming,
+                        pathload.abw(),
+                        probe_id,
+                    );
+                if recorded {
+                    self.publish_probe(target, ProbeTechnique::Pathload, pathload.abw());
+                }
+            }
+        }
+    }
+
+    fn publish_probe(&self, remote: IpAddr, technique: ProbeTechnique, throughput: f64) {
+        self.tap.publish(TapEvent::Probe(ProbeSample {
+            remote,
+            technique,
+            throughput,
+        }));
+    }
+
+    /// Records the samples from packet trains a peer sent to this node.
+    fn insert_train_result(
         &mut self,
         ip_pair: IpPair,
-        bps: f64,
-        stream: Option<&crate::IperfStream>,
+        result: TrainResult,
+        probe_id: Option<u64>,
     ) {
         self.links
             .entry(ip_pair)
             .or_insert_with(StreamManager::default)
-            .record_iperf_result(bps, stream);
+            .record_train_result(&result.samples, probe_id);
+        for &throughput in &result.samples {
+            self.publish_probe(ip_pair.remote(), ProbeTechnique::Train, throughput);
+        }
+    }
+
+    /// Records the path capacity measured with a ping train. It bounds the
+    /// link's ABW estimates in place of `client.link_phy_cap`.
+    fn insert_ping_train(&mut self, ip_pair: IpPair, result: PingTrainResult) {
+        let Some(capacity) = result.capacity() else {
+            info!("No capacity from the ping train on {}: {:?}", ip_pair, result.sizes);
+            return;
+        };
+        info!("Capacity on {}: {:.0} bytes/sec", ip_pair, capacity);
+        self.links
+            .entry(ip_pair)
+            .or_insert_with(StreamManager::default)
+            .record_capacity(capacity);
+    }
+
+    fn insert_ping_reply(&mut self, ip_pair: IpPair, reply: PingReply) {
+        let rtt = match reply.rtt {
+            Ok(rtt) => Some(rtt),
+            Err(e) => {
+                debug!("Ping {} on {} failed: {}", reply.seq, ip_pair, e);
+                None
+            }
+        };
+        self.links
+            .entry(ip_pair)
+            .or_insert_with(StreamManager::default)
+            .record_ping(rtt);
     }
 
     /// Used by the parser task to perform periodic tasks.
     /// As for now, this is just a pass-through to the stream managers.
     pub async fn periodic(&mut self) {
-        for (_, stream_manager) in self.links.iter_mut() {
+        self.probe_traffic.prune();
+        self.neighbors.prune(Timestamp::now());
+        self.relay.prune(Timestamp::now());
+        self.routes.refresh();
+        let tapped = self.tap.is_active();
+        for (ip_pair, stream_manager) in self.links.iter_mut() {
+            stream_manager.set_summarize_bursts(tapped);
             stream_manager.periodic();
+            for burst in stream_manager.take_burst_summaries() {
+                self.tap.publish(TapEvent::Burst(*ip_pair, burst));
+            }
+        }
+        self.update_baseline_state();
+    }
+
+    /// Hands saved baselines to the links that showed up again, and writes
+    /// `client.state_file` when it is due.
+    fn update_baseline_state(&mut self) {
+        let Some(state) = &mut self.baseline_state else {
+            return;
+        };
+        if state.has_pending() {
+            for (ip_pair, stream_manager) in self.links.iter_mut() {
+                if let Some(baseline) = state.take(ip_pair) {
+                    stream_manager.restore_baseline(baseline);
+                }
+            }
+        }
+        if state.save_due() {
+            let links = self.links.iter().map(|(ip_pair, m)| (*ip_pair, m.baseline()));
+            if let Err(e) = state.save(links) {
+                warn!("Failed to save link baselines: {:#}", e);
+            }
         }
     }
 
@@ -115,135 +486,672 @@ impl LinkManager {
     /// gRPC hello response or message.
     /// This is a temporary solution until we have a better way to handle
     /// this logic.
+    ///
+    /// The link reports from now on even if no traffic is seen on it, so an
+    /// idle peer can be told apart from a dead node.
     pub fn add_important_link(&mut self, ip_addr: Result<IpAddr, AddrParseError>) {
         if let Ok(ip_addr) = ip_addr {
-            self.vip_links
-                .insert(IpPair::new(self.pcap_meta.ipv4.into(), ip_addr));
+            let ip_pair = self.host_pair(ip_addr);
+            self.vip_links.insert(ip_pair);
+            self.links.entry(ip_pair).or_insert_with(StreamManager::default);
         } else {
             info!("Failed to parse IP address");
         }
     }
 
-    /// Sends bandwidth, RTT, and PGM data messages over the client channel.
-    ///
-    /// The only part of this function that should be used in production is the
-    /// `send_bandwidth` function. The rest is for gathering data for analysis.
-    ///
-    /// TODO: Avoid excessive creation of messages.
-    pub async fn send_bandwidth(&mut self) {
-        let (bw_message, rtt_message, pgm_dps) = self.build_messages();
+    /// Stops treating the link to a peer as important, once the client
+    /// handler has given up on it.
+    pub fn remove_important_link(&mut self, ip_addr: IpAddr) {
+        self.vip_links.remove(&self.host_pair(ip_addr));
+    }
 
-        let bw_message = DataMsg {
-            data: Some(data_msg::Data::Bandwidth(bw_message)),
+    /// Returns true if the link is to a peer we have a gRPC connection to.
+    pub fn is_vip(&self, ip_pair: &IpPair) -> bool {
+        self.vip_links.contains(ip_pair)
+    }
+
+    /// Reporting interval for a link: a per-IP override from
+    /// `client.link_windows`, else the VIP or background interval.
+    pub fn report_window(&self, ip_pair: &IpPair) -> Duration {
+        Self::window_for(ip_pair, self.is_vip(ip_pair))
+    }
+
+    fn window_for(ip_pair: &IpPair, is_vip: bool) -> Duration {
+        if let Some(secs) = CONFIG.client.link_windows.get(&ip_pair.remote().to_string()) {
+            return Duration::from_secs(*secs as u64);
+        }
+        if is_vip {
+            CONFIG.client.vip_report_interval
+        } else if CONFIG.client.background_report_interval.is_zero() {
+            CONFIG.client.measurement_window
+        } else {
+            CONFIG.client.background_report_interval
+        }
+    }
+
+    /// True if the link's last report had at least `min_points` data points
+    /// left after the gap filter, so passive estimates can stand on their own.
+    /// Always false for links that have not reported yet.
+    pub fn has_passive_data(&self, ip_pair: &IpPair, min_points: usize) -> bool {
+        min_points > 0
+            && self
+                .passive_points
+                .get(ip_pair)
+                .is_some_and(|&points| points >= min_points)
+    }
+
+    /// Current estimates of the link the traffic to `remote` is counted in,
+    /// None if there has been no traffic to it.
+    pub fn estimate(&self, remote: IpAddr) -> Option<LinkEstimate> {
+        let ip_pair = self.link_key(self.host_pair(remote));
+        let stream_manager = self.links.get(&ip_pair)?;
+        Some(self.link_estimate(&ip_pair, stream_manager, Instant::now()))
+    }
+
+    /// Current estimates of every link, ordered by remote.
+    pub fn estimates(&self) -> Vec<LinkEstimate> {
+        let now = Instant::now();
+        let mut estimates: Vec<LinkEstimate> = self
+            .links
+            .iter()
+            .map(|(ip_pair, stream_manager)| self.link_estimate(ip_pair, stream_manager, now))
+            .collect();
+        estimates.sort_by_key(|e| (e.remote, e.local));
+        estimates
+    }
+
+    fn link_estimate(
+        &self,
+        ip_pair: &IpPair,
+        stream_manager: &StreamManager,
+        now: Instant,
+    ) -> LinkEstimate {
+        LinkEstimate::new(
+            ip_pair.local(),
+            ip_pair.remote(),
+            self.reported.get(ip_pair),
+            stream_manager.sent.running_abw(self.estimator).map(|e| e.abw),
+            self.report_window(ip_pair),
+            now,
+        )
+    }
+
+    /// Requests a probe towards every VIP peer that has not been probed
+    /// within `client.vip_probe_interval`, if the peer supports the
+    /// configured technique.
+    ///
+    /// Links with enough passive data (`probe.min_passive_points`) are left
+    /// alone until their traffic drops off.
+    pub async fn schedule_vip_probes(&mut self) {
+        let interval = CONFIG.client.vip_probe_interval;
+        if interval.is_zero() {
+            return;
+        }
+        let technique = match ProbeTechnique::from_str(&CONFIG.server.probe_technique) {
+            Ok(technique) => technique,
+            Err(e) => {
+                warn!("Invalid probe technique: {}", e);
+                return;
+            }
         };
 
-        let rtt_message = DataMsg {
-            data: Some(data_msg::Data::Rtts(rtt_message)),
+        let due: Vec<IpAddr> = self
+            .vip_links
+            .iter()
+            .filter(|ip_pair| !self.has_passive_data(ip_pair, CONFIG.probe.min_passive_points))
+            .map(|ip_pair| ip_pair.remote())
+            .filter(|ip| {
+                self.last_vip_probe
+                    .get(ip)
+                    .map_or(true, |last| last.elapsed() >= interval)
+            })
+            .filter(|ip| {
+                self.peer_capabilities
+                    .get(ip)
+                    .is_some_and(|caps| supports_probe(caps, technique.as_str()))
+            })
+            .collect();
+
+        for ip in due {
+            self.last_vip_probe.insert(ip, Instant::now());
+            let request = ClientHandlerEvent::RequestProbe {
+                ip,
+                technique,
+                duration: CONFIG.probe.duration,
+            };
+            send_or_log(&self.client_sender, request, "probe request").await;
+        }
+    }
+
+    /// Stores the capabilities and the node id a peer sent back in its
+    /// hello reply, received from `from`.
+    pub fn record_peer_capabilities(&mut self, reply: HelloReply, from: IpAddr) {
+        if let Some(identity) = &reply.identity {
+            self.record_peer_identity(identity, from);
+        }
+        let ip_addr = match reply.ip_addr.parse::<IpAddr>() {
+            Ok(ip_addr) => ip_addr,
+            Err(_) => {
+                info!("Failed to parse IP address in hello reply");
+                return;
+            }
         };
+        let capabilities = reply.capabilities.unwrap_or_default();
+        if let Err(reason) = check_compatible(&capabilities) {
+            // Still tracked passively, but not probed or reported as a peer
+            warn!("Peer {} is incompatible: {}", ip_addr, reason);
+            self.peer_capabilities.remove(&ip_addr);
+            self.remove_important_link(ip_addr);
+            return;
+        }
+        info!(
+            "Peer {} (v{}) supports {:?}, window {}s",
+            ip_addr, capabilities.version, capabilities.probes, capabilities.measurement_window
+        );
+        self.peer_capabilities.insert(ip_addr, capabilities);
+    }
 
-        if CONFIG.server.send_link_states {
-            match self
-                .client_sender
-                .send(ClientHandlerEvent::SendDataMsg(bw_message))
-                .await
-            {
-                Ok(_) => (),
-                Err(e) => warn!("Failed to send bandwidth message: {}", e),
+    /// Stores the node id a peer sent in a hello from `from`. If the node was
+    /// known at another address, what is kept about it moves to the new
+    /// one, so the history of its link carries on. Identities signed for
+    /// another address than `from` are turned down, else any node could
+    /// take over the link of another.
+    pub fn record_peer_identity(&mut self, identity: &PeerIdentity, from: IpAddr) {
+        let now = Timestamp::now().as_millis();
+        let verified = match identity::verify(identity, Some(from), now) {
+            Ok(verified) => verified,
+            Err(e) => {
+                warn!("Invalid identity in hello: {}", e);
+                return;
             }
+        };
+        if !self.peer_replays.check(&verified, now) {
+            warn!("Replayed identity of {} in hello", verified.id);
+            return;
+        }
+        let (id, ip) = match verified.addr {
+            Some(ip) => (verified.id, ip),
+            None => {
+                debug!("Peer {} did not announce its address", verified.id);
+                return;
+            }
+        };
+        if let Some(old) = self.peer_addrs.insert(id.clone(), ip).filter(|old| *old != ip) {
+            info!("Peer {} moved from {} to {}", id, old, ip);
+            self.peer_ids.remove(&old);
+            self.renumber(old, ip);
         }
+        self.peer_ids.insert(ip, id);
+    }
 
-        if CONFIG.server.send_rtts {
-            match self
-                .client_sender
-                .send(ClientHandlerEvent::SendDataMsg(rtt_message))
-                .await
-            {
-                Ok(_) => (),
-                Err(e) => warn!("Failed to send rtt message: {}", e),
+    /// Moves the link to the peer at `old`, and what is kept about it, to
+    /// `new`. The history at `old` wins over the little seen at `new` since
+    /// the move.
+    fn renumber(&mut self, old: IpAddr, new: IpAddr) {
+        let (old_pair, new_pair) = (self.host_pair(old), self.host_pair(new));
+        if let Some(stream_manager) = self.links.remove(&old_pair) {
+            self.links.insert(new_pair, stream_manager);
+        }
+        if self.vip_links.remove(&old_pair) {
+            self.vip_links.insert(new_pair);
+        }
+        if self.degraded.remove(&old_pair) {
+            self.degraded.insert(new_pair);
+        }
+        if let Some(points) = self.passive_points.remove(&old_pair) {
+            self.passive_points.insert(new_pair, points);
+        }
+        if let Some(reported) = self.reported.remove(&old_pair) {
+            self.reported.insert(new_pair, reported);
+        }
+        self.events.rename(old_pair, new_pair);
+        if let Some(last) = self.last_vip_probe.remove(&old) {
+            self.last_vip_probe.insert(new, last);
+        }
+        if let Some(offset) = self.clock_offsets.remove(&old) {
+            self.clock_offsets.insert(new, offset);
+        }
+        if let Some(capabilities) = self.peer_capabilities.remove(&old) {
+            self.peer_capabilities.insert(new, capabilities);
+        }
+    }
+
+    /// Asks the client handler for a clock offset exchange with every peer,
+    /// unless `client.clock_sync_interval` is 0.
+    pub async fn sync_clocks(&mut self) {
+        if CONFIG.client.clock_sync_interval.is_zero() {
+            return;
+        }
+        send_or_log(&self.client_sender, ClientHandlerEvent::SyncClocks, "clock sync request").await;
+    }
+
+    /// Asks the client handler to ping every peer `probe.ping_count` times,
+    /// unless `probe.ping_interval` is 0.
+    pub async fn ping_peers(&mut self) {
+        if CONFIG.probe.ping_interval.is_zero() {
+            return;
+        }
+        for ip in self.peers() {
+            let ping = ClientHandlerEvent::DoPing {
+                ip,
+                count: CONFIG.probe.ping_count,
+                interval: PING_SPACING,
+            };
+            send_or_log(&self.client_sender, ping, "ping request").await;
+        }
+    }
+
+    /// Asks the client handler for a ping train to every peer, unless
+    /// `probe.ping_train_interval` is 0.
+    pub async fn send_ping_trains(&mut self) {
+        if CONFIG.probe.ping_train_interval.is_zero() {
+            return;
+        }
+        for ip in self.peers() {
+            let train = ClientHandlerEvent::DoPingTrain { ip };
+            send_or_log(&self.client_sender, train, "ping train request").await;
+        }
+    }
+
+    /// Remotes of the links to peers.
+    fn peers(&self) -> Vec<IpAddr> {
+        self.vip_links.iter().map(|ip_pair| ip_pair.remote()).collect()
+    }
+
+    /// Publishes the ML feature vector of every link with traffic since the
+    /// last call, unless `features.interval` is 0.
+    pub fn export_features(&mut self) {
+        if CONFIG.features.interval.is_zero() {
+            return;
+        }
+        let now = Timestamp::now();
+        for (ip_pair, stream_manager) in self.links.iter_mut() {
+            if let Some(features) = stream_manager.take_features(*ip_pair, now) {
+                self.tap.publish(TapEvent::Features(features));
             }
         }
+    }
+
+    /// Adds the tracked links and the sizes of the tables kept here to
+    /// `status`, and passes it on to the client handler to add the peers.
+    pub async fn send_status(&self, mut status: NodeStatus, reply: oneshot::Sender<NodeStatus>) {
+        for (ip_pair, stream_manager) in &self.links {
+            let rtt_samples = stream_manager.sent.rtts.len() + stream_manager.received.rtts.len();
+            let gap_points = stream_manager.sent.pgm_estimator.dps.len()
+                + stream_manager.received.pgm_estimator.dps.len();
+            let gap_packets =
+                stream_manager.sent.gap_packets() + stream_manager.received.gap_packets();
+            status.links.push(LinkStatus {
+                local_ip: ip_pair.local().to_string(),
+                remote_ip: ip_pair.remote().to_string(),
+                streams: stream_manager.stream_count() as u32,
+                last_seen: stream_manager.last_seen().map_or(0, Timestamp::as_millis),
+                vip: self.is_vip(ip_pair),
+                rtt_samples: rtt_samples as u64,
+                gap_points: gap_points as u64,
+                since_report: stream_manager.since_report().as_secs_f64(),
+                packets: stream_manager.packets() as u64,
+                gap_packets: gap_packets as u64,
+            });
+        }
+        // Most recently active first
+        status.links.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
 
+        let tables = [
+            ("links", self.links.len()),
+            ("vip_links", self.vip_links.len()),
+            ("peer_capabilities", self.peer_capabilities.len()),
+            ("clock_offsets", self.clock_offsets.len()),
+            ("probe_sessions", self.probe_traffic.len()),
+            ("neighbors", self.neighbors.len()),
+            ("relay_copies", self.relay.len()),
+            ("relayed_pairs", self.relay.relayed()),
+            ("flow_owners", self.flow_owners.len()),
+            ("pending_rtts", self.pending_rtts.len()),
+            ("pending_pgm", self.pending_pgm.len()),
+        ];
+        status
+            .tables
+            .extend(tables.map(|(name, len)| (name.to_string(), len as u64)));
+
+        let request = ClientHandlerEvent::Status { status, reply };
+        send_or_log(&self.client_sender, request, "status request").await;
+    }
+
+    /// Adds the socket owners from a procfs poll, see `FlowOwners`.
+    pub fn update_flow_owners(&mut self, owners: HashMap<(StreamKey, IpPair), SocketOwner>) {
+        self.flow_owners.update(owners, Timestamp::now());
+    }
+
+    /// Adds the result of a clock offset exchange with a peer.
+    pub fn record_clock_sample(&mut self, ip_addr: IpAddr, sample: ClockSample) {
+        self.clock_offsets.entry(ip_addr).or_default().add(sample);
+    }
+
+    /// Peer clock minus local clock in ns, if it has been estimated.
+    pub fn clock_offset(&self, ip_addr: &IpAddr) -> Option<i64> {
+        self.clock_offsets.get(ip_addr).and_then(ClockOffset::offset)
+    }
+
+    /// Returns the capabilities advertised by a peer, if it has said hello.
+    pub fn peer_capabilities(&self, ip_addr: &IpAddr) -> Option<&NodeCapabilities> {
+        self.peer_capabilities.get(ip_addr)
+    }
+
+    /// Sends link states for the links that are due a report over the
+    /// client channel. Their RTT samples and PGM data points are buffered
+    /// until the next `send_rtts` and `send_pgm`, or sent along in one
+    /// report with `server.batch_reports`.
+    pub async fn send_bandwidth(&mut self) {
+        let (bw_message, rtt_message, pgm_dps) = self.build_messages();
+        if bw_message.link_state.is_empty() {
+            // No link is due for a report
+            return;
+        }
+        self.window_id += 1;
+
+        if CONFIG.server.send_rtts {
+            self.pending_rtts.extend(rtt_message.rtts);
+        }
         if CONFIG.server.send_pgm_dps {
-            match self
-                .client_sender
-                .send(ClientHandlerEvent::SendDataMsg(DataMsg {
-                    data: Some(data_msg::Data::Pgmmsg(pgm_dps)),
-                }))
-                .await
-            {
-                Ok(_) => (),
-                Err(e) => warn!("Failed to send pgm message: {}", e),
-            }
+            self.pending_pgm.extend(pgm_dps.pgm_dps);
+        }
+
+        if CONFIG.server.batch_reports {
+            let report = self.take_report(bw_message, Timestamp::now());
+            self.send_data_msg(data_msg::Data::Report(report), "report")
+                .await;
+        } else if CONFIG.server.send_link_states {
+            self.send_data_msg(data_msg::Data::Bandwidth(bw_message), "bandwidth")
+                .await;
         }
     }
 
-    /// Returns all remote IPs currently tracked.
+    /// The link states in `bw_message` and everything buffered since the
+    /// last report, for the window ending at `now`.
+    fn take_report(&mut self, bw_message: BandwidthMessage, now: Timestamp) -> Report {
+        let window_start = std::mem::replace(&mut self.window_start, now);
+        let link_state = if CONFIG.server.send_link_states {
+            bw_message.link_state
+        } else {
+            Vec::new()
+        };
+        Report {
+            window_start: window_start.as_millis(),
+            window_end: now.as_millis(),
+            link_state,
+            rtts: self.take_rtts(),
+            pgm: self.take_pgm(),
+        }
+    }
+
+    /// Sends the link events raised or cleared by what each link has seen
+    /// since its last report, if any threshold is set in `[events]`.
+    pub async fn send_events(&mut self) {
+        if !self.events.is_enabled() {
+            return;
+        }
+        let timestamp = Timestamp::now().as_millis();
+        let mut events = Vec::new();
+        for (ip_pair, stream_manager) in &self.links {
+            let metrics = Metrics {
+                abw: stream_manager.sent.running_abw(self.estimator).map(|e| e.abw),
+                rtt: stream_manager.sent.avg_rtt(),
+                loss: stream_manager.sent.loss(),
+            };
+            events.extend(self.events.check(*ip_pair, &metrics, timestamp));
+        }
+        if !events.is_empty() {
+            self.send_data_msg(data_msg::Data::Events(LinkEvents { events }), "events")
+                .await;
+        }
+    }
+
+    /// Sends the RTT samples buffered since the last call. They go out with
+    /// the link states instead with `server.batch_reports`.
+    pub async fn send_rtts(&mut self) {
+        if CONFIG.server.batch_reports {
+            return;
+        }
+        if let Some(rtts) = self.take_rtts() {
+            self.send_data_msg(data_msg::Data::Rtts(rtts), "rtt").await;
+        }
+    }
+
+    /// Sends the PGM data points buffered since the last call. They go out
+    /// with the link states instead with `server.batch_reports`.
+    pub async fn send_pgm(&mut self) {
+        if CONFIG.server.batch_reports {
+            return;
+        }
+        if let Some(pgm) = self.take_pgm() {
+            self.send_data_msg(data_msg::Data::Pgmmsg(pgm), "pgm").await;
+        }
+    }
+
+    /// The buffered RTT samples, None if there are none.
+    fn take_rtts(&mut self) -> Option<Rtts> {
+        if self.pending_rtts.is_empty() {
+            return None;
+        }
+        let mut rtts = std::mem::take(&mut self.pending_rtts);
+        let truncated = truncate_oldest(&mut rtts, CONFIG.server.max_points_per_message, |m| {
+            &mut m.rtt
+        });
+        Some(Rtts { rtts, truncated })
+    }
+
+    /// The buffered PGM data points, None if there are none.
+    fn take_pgm(&mut self) -> Option<PgmMessage> {
+        if self.pending_pgm.is_empty() {
+            return None;
+        }
+        let mut pgm_dps = std::mem::take(&mut self.pending_pgm);
+        let truncated = truncate_oldest(&mut pgm_dps, CONFIG.server.max_points_per_message, |m| {
+            &mut m.pgm_dp
+        });
+        Some(PgmMessage { pgm_dps, truncated })
+    }
+
+    async fn send_data_msg(&self, data: data_msg::Data, kind: &str) {
+        // Sequenced by the client handler
+        let msg = DataMsg {
+            data: Some(data),
+            window_id: self.window_id,
+            ..Default::default()
+        };
+        trace::hop(&msg, format_args!("built as {}", kind));
+        let what = format!("{} message", kind);
+        send_or_log(&self.client_sender, ClientHandlerEvent::SendDataMsg(msg), &what).await;
+    }
+
+    /// Returns all remote IPs currently tracked, and the hosts seen behind
+    /// aggregated links. Subnet links are left out, they are not hosts.
     pub fn collect_external_ips(&self) -> Vec<IpAddr> {
-        self.links.keys().map(|ip_pair| ip_pair.remote()).collect()
+        let mut ips: HashSet<IpAddr> = self
+            .links
+            .keys()
+            .filter(|ip_pair| {
+                self.aggregation.keys_are_hosts() || self.vip_links.contains(ip_pair)
+            })
+            .map(|ip_pair| ip_pair.remote())
+            .collect();
+        ips.extend(&self.aggregated_hosts);
+        ips.into_iter().collect()
     }
 
     /// Sends initial client registration message with known IPs.
     pub async fn send_init_clients_msg(&mut self) {
-        self.client_sender
-            .send(ClientHandlerEvent::InitClients {
-                ips: self.collect_external_ips(),
-            })
-            .await
-            .unwrap();
+        let ips = self.collect_external_ips();
+        self.aggregated_hosts.clear();
+        send_or_log(&self.client_sender, ClientHandlerEvent::InitClients { ips }, "client init").await;
     }
 
-    /// Creates an RTT message from a vector of RTTs and an IP pair.
-    pub fn get_rtt_message(rtts: Vec<(u32, SystemTime)>, ip_pair: IpPair) -> RttMessage {
-        let messages: Vec<Rtt> = rtts
-            .into_iter()
-            .map(|(rtt, timestamp)| Rtt {
-                rtt: rtt as f64,
-                // This is bad practice. Safe for now, as timestamps will always be in the past.
-                timestamp: timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis() as i64,
-            })
-            .collect();
+    /// Creates an RTT message from the samples of a link, in microseconds.
+    /// They are aggregated into buckets of `bucket`, and only sent as they
+    /// are if `raw` is set or `bucket` is zero.
+    pub fn get_rtt_message(
+        rtts: Vec<(u32, Timestamp)>,
+        ip_pair: IpPair,
+        bucket: Duration,
+        raw: bool,
+    ) -> RttMessage {
+        let buckets = if bucket.is_zero() {
+            Vec::new()
+        } else {
+            bucket_rtts(&rtts, bucket)
+        };
+        let messages: Vec<Rtt> = if raw || bucket.is_zero() {
+            rtts.into_iter()
+                .map(|(rtt, timestamp)| Rtt {
+                    rtt: rtt as f64,
+                    timestamp: timestamp.as_millis(),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
 
         RttMessage {
             sender_ip: ip_pair.local().to_string(),
             receiver_ip: ip_pair.remote().to_string(),
             rtt: messages,
+            buckets,
+        }
+    }
+
+    /// Takes the gin/gout points collected in `pkt_reg` for data sent from
+    /// `sender` to `receiver`.
+    fn take_pgm(pkt_reg: &mut PacketRegistry, sender: IpAddr, receiver: IpAddr, tstamp: i64) -> PgmDps {
+        let pgm_dp: Vec<PgmDp> = pkt_reg
+            .take_gap_points()
+            .into_iter()
+            .map(|dp| PgmDp {
+                gin: dp.gin,
+                gout: dp.gout,
+                len: dp.len as i32,
+                num_acked: dp.num_acked as i32,
+                app_limited: dp.app_limited,
+            })
+            .collect();
+        let app_limited_fraction = if pgm_dp.is_empty() {
+            0.0
+        } else {
+            pgm_dp.iter().filter(|dp| dp.app_limited).count() as f64 / pgm_dp.len() as f64
+        };
+        PgmDps {
+            pgm_dp,
+            app_limited_fraction,
+            timestamp: tstamp,
+            sender_ip: sender.to_string(),
+            receiver_ip: receiver.to_string(),
         }
     }
 
     /// Internal helper to produce LinkState and PGM for one stream.
+    ///
+    /// PGM points are returned for both directions, remote to local only if
+    /// the remote sent any acknowledged data.
+    ///
+    /// `estimator` gives `abw` and `abw_down`. With `client.compare_estimators`
+    /// set, every estimator is also run on the data sent toward the remote.
     fn get_link_state(
         stream_manager: &mut StreamManager,
         pkt_reg: &mut PacketRegistry,
+        received: &mut PacketRegistry,
         ip_pair: IpPair,
-    ) -> (Link, PgmDps) {
-        let (abw, _dps) = pkt_reg.passive_abw(crate::CONFIG.client.regression_type);
-        let tstamp = chrono::Utc::now().timestamp_millis();
-
-        let pgm = PgmDps {
-            pgm_dp: std::mem::take(&mut pkt_reg.pgm_estimator.dps)
+        link_type: LinkType,
+        link_alive: bool,
+        estimator: RegressionType,
+    ) -> (Link, Vec<PgmDps>) {
+        let capacity = stream_manager.capacity();
+        let (abw, _dps) = pkt_reg.passive_abw(estimator, capacity);
+        let (abw_down, _dps) = received.passive_abw(estimator, capacity);
+        let estimates = if CONFIG.client.compare_estimators {
+            RegressionType::ALL
                 .into_iter()
-                .map(|dp| PgmDp {
-                    gin: dp.gin,
-                    gout: dp.gout,
-                    len: dp.len as i32,
-                    num_acked: dp.num_acked as i32,
-                })
-                .collect(),
-            timestamp: tstamp,
-            sender_ip: ip_pair.local().to_string(),
-            receiver_ip: ip_pair.remote().to_string(),
+                .filter_map(|r| pkt_reg.passive_abw(r, capacity).0.map(|e| (r, e)))
+                .collect()
+        } else {
+            Vec::new()
         };
+        let tstamp = chrono::Utc::now().timestamp_millis();
+        let idle = stream_manager.is_idle();
+        // Normalize by the actual time since this link last reported
+        let window = stream_manager.take_report_elapsed().as_secs_f64();
+        // Retries are only visible when capturing 802.11 frames
+        let retry_rate = stream_manager
+            .take_retry_rate()
+            .filter(|_| link_type == LinkType::Radiotap);
+        let (jitter, loss) = stream_manager.take_udp_result();
+        let (ping_rtt, ping_loss) = stream_manager.take_ping_result();
+        let hops = stream_manager.hops(ip_pair.remote());
+        let path_changes = stream_manager.take_path_changes();
+        let (other_bytes, other_protocols) = stream_manager.take_other_traffic();
+        let fragmented_bytes = stream_manager.take_fragmented_bytes();
+        let (thp_in_dist, thp_out_dist) = stream_manager.take_thp_percentiles(Timestamp::now());
+        let bufferbloat = stream_manager.update_bufferbloat(pkt_reg);
+        let fct = stream_manager.take_fct_percentiles();
+        let rwnd = stream_manager.take_window_stats();
+        let bw = stream_manager.probe_bw(Direction::Outgoing).copied();
+        let bw_down = stream_manager.probe_bw(Direction::Incoming).copied();
+
+        let mut pgm = vec![Self::take_pgm(pkt_reg, ip_pair.local(), ip_pair.remote(), tstamp)];
+        let pgm_down = Self::take_pgm(received, ip_pair.remote(), ip_pair.local(), tstamp);
+        if !pgm_down.pgm_dp.is_empty() {
+            pgm.push(pgm_down);
+        }
         let state = LinkState {
             thp_in: stream_manager.take_received() as f64
-                / crate::CONFIG.client.measurement_window.as_secs_f64(),
+                / window,
             thp_out: stream_manager.take_sent() as f64
-                / crate::CONFIG.client.measurement_window.as_secs_f64(),
-            bw: Some(stream_manager.tcp_thput()),
-            abw,
+                / window,
+            bw: bw.map(|p| p.throughput * 8.0),
+            bw_down: bw_down.map(|p| p.throughput * 8.0),
+            bw_source: bw.map(|p| p.source),
+            bw_down_source: bw_down.map(|p| p.source),
+            probe_thp_in: stream_manager.take_probe_received() as f64
+                / window,
+            probe_thp_out: stream_manager.take_probe_sent() as f64
+                / window,
+            abw: abw.map(|e| e.abw),
+            abw_std_err: abw.and_then(|e| e.std_err),
+            abw_samples: abw.map_or(0, |e| e.samples as u32),
             latency: pkt_reg.avg_rtt(),
             delay: None,
-            jitter: None,
-            loss: None,
+            jitter,
+            loss,
+            abw_down: abw_down.map(|e| e.abw),
+            abw_down_std_err: abw_down.and_then(|e| e.std_err),
+            abw_down_samples: abw_down.map_or(0, |e| e.samples as u32),
+            latency_down: received.avg_rtt(),
+            loss_up: pkt_reg.loss(),
+            loss_down: received.loss(),
+            burst_thp_in: received.avg_burst_thp(),
+            train_abw_in: stream_manager.take_train_abw(),
+            clock_offset: None,
+            next_hop: None,
+            peer_id: None,
+            degraded: false,
+            flows: Vec::new(),
+            capacity,
+            ping_rtt,
+            ping_loss,
+            hops,
+            path_changes,
+            thp_in_dist,
+            thp_out_dist,
+            idle,
+            bufferbloat,
+            fct,
+            rwnd,
+            retry_rate,
+            link_alive,
+            other_bytes,
+            other_protocols,
+            fragmented_bytes,
+            estimator,
+            estimates,
             timestamp: tstamp,
         };
         (Link { ip_pair, state }, pgm)
@@ -255,23 +1163,128 @@ impl LinkManager {
         let mut rtts = Vec::new();
         let mut pgm_dps = Vec::new();
         for (ip_pair, stream_manager) in self.links.iter_mut() {
+            let report_window = Self::window_for(ip_pair, self.vip_links.contains(ip_pair));
+            if stream_manager.since_report() < report_window {
+                continue;
+            }
+            // Down if the last ARP/ND request for the remote went unanswered.
+            // Remotes behind a gateway never show up and are assumed up.
+            let link_alive = self.neighbors.reachability(&ip_pair.remote(), Timestamp::now())
+                != Some(Reachability::Unreachable);
             let mut sent_registry = stream_manager.sent.take();
-            let _ = stream_manager.received.take();
-            let (link, pgm) = Self::get_link_state(stream_manager, &mut sent_registry, *ip_pair);
-            let rtt_msg = Self::get_rtt_message(sent_registry.rtts, *ip_pair);
-            links.push(link.to_proto());
-            rtts.push(rtt_msg);
-            pgm_dps.push(pgm);
+            let mut received_registry = stream_manager.received.take();
+            let (mut link, pgm) = Self::get_link_state(
+                stream_manager,
+                &mut sent_registry,
+                &mut received_registry,
+                *ip_pair,
+                self.pcap_meta.link_type,
+                link_alive,
+                self.estimator,
+            );
+            link.state.clock_offset = self
+                .clock_offsets
+                .get(&ip_pair.remote())
+                .and_then(ClockOffset::offset);
+            link.state.next_hop = self.routes.lookup(ip_pair.remote());
+            link.state.peer_id = self.peer_ids.get(&ip_pair.remote()).cloned();
+            link.state.degraded = self.degraded.remove(ip_pair) || self.shedding;
+            link.state.flows = stream_manager.take_finished_flows();
+            if CONFIG.client.flow_owners {
+                for flow in link.state.flows.iter_mut().filter(|f| f.completion.outgoing) {
+                    flow.process = self.flow_owners.get(flow.key, flow.hosts).map(String::from);
+                }
+            }
+            self.passive_points
+                .insert(*ip_pair, sent_registry.pgm_estimator.last_filter.mad);
+            self.reported.insert(*ip_pair, link.state.reported());
+            if !self.shedding {
+                rtts.push(Self::get_rtt_message(
+                    sent_registry.rtts,
+                    *ip_pair,
+                    CONFIG.server.rtt_bucket,
+                    CONFIG.server.raw_rtts,
+                ));
+            }
+            let link_state = link.to_proto();
+            if self.tap.is_active() {
+                self.tap.publish(TapEvent::LinkState(link_state.clone()));
+            }
+            self.consumers.notify(&link_state);
+            links.push(link_state);
+            pgm_dps.extend(pgm);
         }
 
         (
             BandwidthMessage { link_state: links },
-            Rtts { rtts },
-            PgmMessage { pgm_dps },
+            Rtts {
+                rtts,
+                truncated: false,
+            },
+            PgmMessage {
+                pgm_dps,
+                truncated: false,
+            },
         )
     }
 }
 
+/// Min, mean, max and count of the RTT samples in each `width` of time,
+/// oldest first.
+fn bucket_rtts(rtts: &[(u32, Timestamp)], width: Duration) -> Vec<RttBucket> {
+    let width = width.as_nanos().min(u64::MAX as u128).max(1) as u64;
+    // Start of the bucket to (min, max, sum, count)
+    let mut buckets: BTreeMap<u64, (u32, u32, f64, u32)> = BTreeMap::new();
+    for &(rtt, timestamp) in rtts {
+        let start = timestamp.as_nanos() / width * width;
+        let bucket = buckets.entry(start).or_insert((rtt, rtt, 0.0, 0));
+        bucket.0 = bucket.0.min(rtt);
+        bucket.1 = bucket.1.max(rtt);
+        bucket.2 += rtt as f64;
+        bucket.3 += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|(start, (min, max, sum, count))| RttBucket {
+            timestamp: Timestamp::from_nanos(start).as_millis(),
+            min: min as f64,
+            mean: sum / count as f64,
+            max: max as f64,
+            count,
+        })
+        .collect()
+}
+
+/// Drops the oldest points until at most `max_points` are left across all
+/// groups, removing groups left empty. Groups and the points within them are
+/// in the order they were collected. Returns true if anything was dropped,
+/// a limit of 0 keeps everything.
+fn truncate_oldest<G, P>(
+    groups: &mut Vec<G>,
+    max_points: usize,
+    points: impl Fn(&mut G) -> &mut Vec<P>,
+) -> bool {
+    if max_points == 0 {
+        return false;
+    }
+    let total: usize = groups.iter_mut().map(|g| points(g).len()).sum();
+    let mut excess = total.saturating_sub(max_points);
+    if excess == 0 {
+        return false;
+    }
+    for group in groups.iter_mut() {
+        let group = points(group);
+        let n = excess.min(group.len());
+        group.drain(..n);
+        excess -= n;
+        if excess == 0 {
+            break;
+        }
+    }
+    groups.retain_mut(|g| !points(g).is_empty());
+    true
+}
+
 /// Represents the measured and estimated state of a link at an instant.
 /// Most of the parameters are unused, but kept for future use.
 ///
@@ -286,8 +1299,17 @@ pub struct LinkState {
     thp_in: f64,
     /// Throughput out (Measured)
     thp_out: f64,
-    /// bps, None if not available (unused)
+    /// bps toward the remote from the freshest valid probe, None if not available
     bw: Option<f64>,
+    /// bps from probes the remote sent, None if not available
+    bw_down: Option<f64>,
+    /// Probes behind `bw` and `bw_down`
+    bw_source: Option<ProbeSource>,
+    bw_down_source: Option<ProbeSource>,
+    /// Throughput in generated by active probes
+    probe_thp_in: f64,
+    /// Throughput out generated by active probes
+    probe_thp_out: f64,
     /// bps, None if not available (Available bandwidth, estimated)
     abw: Option<f64>,
     /// ms rtt, None if not available (Measured)
@@ -298,11 +1320,93 @@ pub struct LinkState {
     jitter: Option<f64>,
     /// %, None if not available (Measured, unused)
     loss: Option<f64>,
+    /// Available bandwidth from the remote, estimated from the bursts it
+    /// sent. `abw` is the estimate toward the remote.
+    abw_down: Option<f64>,
+    /// Time from data sent by the remote until this host acknowledged it.
+    /// `latency` covers data sent by this host.
+    latency_down: Option<f64>,
+    /// % of TCP segments sent by this host that were retransmitted
+    loss_up: Option<f64>,
+    /// % of TCP segments sent by the remote that were retransmitted
+    loss_down: Option<f64>,
+    /// Average throughput of bursts sent by the remote, bytes/sec
+    burst_thp_in: Option<f64>,
+    /// Median arrival rate of packet trains sent by the remote, bytes/sec
+    train_abw_in: Option<f64>,
+    /// Remote clock minus local clock in ns, None until estimated
+    clock_offset: Option<i64>,
+    /// Where packets toward the remote leave this host, None without a route
+    next_hop: Option<NextHop>,
+    /// Node id of the remote, None unless it sent one in its hello
+    peer_id: Option<String>,
+    /// The parser shed load during the window
+    degraded: bool,
+    /// TCP connections that ended this window
+    flows: Vec<FinishedFlow>,
+    /// bytes/sec, from the last ping train, None if none was run
+    capacity: Option<f64>,
+    /// seconds, mean RTT of the pings this window, None if none was answered
+    ping_rtt: Option<f64>,
+    /// % of the pings this window left unanswered, None if none was sent
+    ping_loss: Option<f64>,
+    /// Hops from the remote, None if it sent nothing lately
+    hops: Option<u8>,
+    /// Path changes of the remote or hosts behind it this window
+    path_changes: u32,
+    /// Spread of the received throughput over the window
+    thp_in_dist: Option<Percentiles>,
+    /// Spread of the sent throughput over the window
+    thp_out_dist: Option<Percentiles>,
+    /// No traffic at all since the last report
+    idle: bool,
+    /// RTT toward the remote under load against its baseline
+    bufferbloat: Option<Bufferbloat>,
+    /// Completion times of the short TCP flows that ended this window
+    fct: Option<FctPercentiles>,
+    /// Receive window advertised by the remote this window
+    rwnd: Option<WindowSummary>,
+    /// Standard error of `abw`, None with too few points
+    abw_std_err: Option<f64>,
+    /// Data points behind `abw`
+    abw_samples: u32,
+    /// Standard error of `abw_down`, None with too few points
+    abw_down_std_err: Option<f64>,
+    /// Data points behind `abw_down`
+    abw_down_samples: u32,
+    /// Share of 802.11 frames retransmitted, None unless capturing radiotap
+    retry_rate: Option<f64>,
+    /// False if the remote stopped answering ARP/ND requests
+    link_alive: bool,
+    /// Bytes of traffic other than TCP and UDP, both directions
+    other_bytes: u64,
+    /// IP protocol numbers of that traffic
+    other_protocols: Vec<u8>,
+    /// Bytes of IP fragments, both directions
+    fragmented_bytes: u64,
+    /// Estimator behind `abw` and `abw_down`
+    estimator: RegressionType,
+    /// Estimates toward the remote from every estimator that produced one,
+    /// empty unless `client.compare_estimators` is set
+    estimates: Vec<(RegressionType, AbwEstimate)>,
     /// Timestamp of the measurement
     timestamp: i64,
 }
 
 impl LinkState {
+    /// What `LinkManager::estimate` keeps of the report.
+    fn reported(&self) -> Reported {
+        Reported {
+            abw: self.abw,
+            abw_down: self.abw_down,
+            capacity: self.capacity,
+            latency: self.latency,
+            thp_in: self.thp_in,
+            thp_out: self.thp_out,
+            at: Instant::now(),
+        }
+    }
+
     /// Converts internal state to protobuf message.
     pub fn to_proto(&self) -> LinkStateProto {
         LinkStateProto {
@@ -311,16 +1415,105 @@ impl LinkState {
             thp_in: self.thp_in,
             thp_out: self.thp_out,
             bw: self.bw.unwrap_or(0.0),
+            bw_down: self.bw_down.unwrap_or(0.0),
+            bw_source: self.bw_source.unwrap_or(ProbeSource::Unknown) as i32,
+            bw_down_source: self.bw_down_source.unwrap_or(ProbeSource::Unknown) as i32,
             abw: self.abw.unwrap_or(0.0),
             latency: self.latency.unwrap_or(0.0),
             delay: self.delay.unwrap_or(0.0),
             jitter: self.jitter.unwrap_or(0.0),
             loss: self.loss.unwrap_or(0.0),
             timestamp: self.timestamp,
+            probe_thp_in: self.probe_thp_in,
+            probe_thp_out: self.probe_thp_out,
+            retry_rate: self.retry_rate.unwrap_or(0.0),
+            link_alive: self.link_alive,
+            abw_down: self.abw_down.unwrap_or(0.0),
+            latency_down: self.latency_down.unwrap_or(0.0),
+            loss_up: self.loss_up.unwrap_or(0.0),
+            loss_down: self.loss_down.unwrap_or(0.0),
+            burst_thp_in: self.burst_thp_in.unwrap_or(0.0),
+            train_abw_in: self.train_abw_in.unwrap_or(0.0),
+            clock_offset: self.clock_offset.unwrap_or(0),
+            next_hop: self
+                .next_hop
+                .as_ref()
+                .map_or_else(String::new, |hop| hop.addr.to_string()),
+            egress_iface: self
+                .next_hop
+                .as_ref()
+                .map_or_else(String::new, |hop| hop.iface.clone()),
+            peer_id: self.peer_id.clone().unwrap_or_default(),
+            degraded: self.degraded,
+            flows: self.flows.iter().map(flow_to_proto).collect(),
+            capacity: self.capacity.unwrap_or(0.0),
+            ping_rtt: self.ping_rtt.unwrap_or(0.0),
+            ping_loss: self.ping_loss.unwrap_or(0.0),
+            hops: self.hops.map_or(-1, i32::from),
+            path_changes: self.path_changes,
+            thp_in_dist: self.thp_in_dist.map(percentiles_to_proto),
+            thp_out_dist: self.thp_out_dist.map(percentiles_to_proto),
+            idle: self.idle,
+            rtt_baseline: self.bufferbloat.map_or(0.0, |b| b.baseline),
+            rtt_loaded: self.bufferbloat.map_or(0.0, |b| b.loaded),
+            bufferbloat: self.bufferbloat.map_or(0.0, |b| b.score()),
+            fct: self.fct.map(|f| FlowCompletionTimes {
+                p50: f.p50,
+                p95: f.p95,
+                max: f.max,
+                flows: f.flows,
+            }),
+            rwnd: self.rwnd.map(|w| ReceiveWindowStats {
+                avg: w.avg.unwrap_or(0.0),
+                min: w.min,
+                max: w.max,
+                samples: w.samples,
+                stalls: w.stalls,
+                stalled: w.stalled.as_secs_f64(),
+            }),
+            abw_std_err: self.abw_std_err.unwrap_or(0.0),
+            abw_samples: self.abw_samples,
+            abw_down_std_err: self.abw_down_std_err.unwrap_or(0.0),
+            abw_down_samples: self.abw_down_samples,
+            other_bytes: self.other_bytes,
+            other_protocols: self.other_protocols.iter().map(|&p| p as u32).collect(),
+            fragmented_bytes: self.fragmented_bytes,
+            estimator: self.estimator.name().to_string(),
+            estimates: self
+                .estimates
+                .iter()
+                .map(|(estimator, e)| EstimatorAbw {
+                    estimator: estimator.name().to_string(),
+                    abw: e.abw,
+                    std_err: e.std_err.unwrap_or(0.0),
+                    samples: e.samples as u32,
+                })
+                .collect(),
         }
     }
 }
 
+fn flow_to_proto(flow: &FinishedFlow) -> FlowRecord {
+    FlowRecord {
+        remote_ip: flow.hosts.remote().to_string(),
+        local_port: flow.key.local_port().unwrap_or(0) as u32,
+        remote_port: flow.key.remote_port().unwrap_or(0) as u32,
+        outgoing: flow.completion.outgoing,
+        duration: flow.completion.duration.as_secs_f64(),
+        bytes: flow.completion.bytes,
+        process: flow.process.clone().unwrap_or_default(),
+    }
+}
+
+fn percentiles_to_proto(p: Percentiles) -> ThroughputPercentiles {
+    ThroughputPercentiles {
+        p5: p.p5,
+        p50: p.p50,
+        p95: p.p95,
+        buckets: p.buckets,
+    }
+}
+
 #[derive(Debug)]
 pub struct Link {
     ip_pair: IpPair,
@@ -353,25 +1546,159 @@ impl Display for Link {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flow_time::FlowCompletion;
+    use pnet::packet::ip::IpNextHeaderProtocols;
     use std::net::IpAddr;
 
+    /// A manager on `PCAPMeta::for_test`, and what it sends the client
+    /// handler.
+    fn manager() -> (LinkManager, tokio::sync::mpsc::Receiver<ClientHandlerEvent>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        (LinkManager::new(tx, Arc::new(PCAPMeta::for_test())), rx)
+    }
+
     #[test]
     fn test_linkstate_display_and_proto() {
         let state = LinkState {
             thp_in: 1.0,
             thp_out: 2.0,
             bw: Some(3.0),
+            bw_down: None,
+            bw_source: Some(ProbeSource::Iperf),
+            bw_down_source: None,
+            probe_thp_in: 0.0,
+            probe_thp_out: 0.0,
             abw: Some(4.0),
             latency: Some(5.0),
             delay: None,
             jitter: None,
             loss: None,
+            abw_down: Some(2.0),
+            latency_down: None,
+            loss_up: Some(1.5),
+            loss_down: None,
+            burst_thp_in: Some(8.0),
+            train_abw_in: None,
+            clock_offset: Some(-1500),
+            next_hop: Some(NextHop {
+                addr: [10, 0, 0, 254].into(),
+                iface: "wlan0".to_string(),
+            }),
+            peer_id: Some("0123456789abcdef".to_string()),
+            degraded: true,
+            flows: vec![FinishedFlow {
+                key: StreamKey::new(IpNextHeaderProtocols::Tcp, Some(40000), Some(443)),
+                hosts: IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into()),
+                completion: FlowCompletion {
+                    duration: Duration::from_millis(250),
+                    bytes: 2000,
+                    outgoing: true,
+                },
+                process: Some("curl".to_string()),
+            }],
+            capacity: Some(1e7),
+            ping_rtt: Some(0.004),
+            ping_loss: Some(25.0),
+            hops: Some(0),
+            path_changes: 2,
+            thp_in_dist: Some(Percentiles {
+                p5: 0.5,
+                p50: 1.0,
+                p95: 4.0,
+                buckets: 10,
+            }),
+            thp_out_dist: None,
+            idle: false,
+            bufferbloat: Some(Bufferbloat {
+                baseline: 10_000.0,
+                loaded: 25_000.0,
+            }),
+            fct: None,
+            rwnd: None,
+            abw_std_err: Some(0.5),
+            abw_samples: 12,
+            abw_down_std_err: None,
+            abw_down_samples: 0,
+            retry_rate: None,
+            link_alive: true,
+            other_bytes: 0,
+            other_protocols: Vec::new(),
+            fragmented_bytes: 4500,
+            estimator: RegressionType::RLS,
+            estimates: vec![(
+                RegressionType::Simple,
+                AbwEstimate {
+                    abw: 3.5,
+                    std_err: None,
+                    samples: 12,
+                },
+            )],
             timestamp: 0,
         };
         let s = format!("{}", state);
         assert!(s.contains("thp_in: 1.00"));
         let proto = state.to_proto();
         assert_eq!(proto.thp_in, 1.0);
+        assert_eq!(proto.abw, 4.0);
+        assert_eq!(proto.abw_down, 2.0);
+        assert_eq!(proto.bw_source(), ProbeSource::Iperf);
+        assert_eq!(proto.bw_down_source(), ProbeSource::Unknown);
+        assert_eq!(proto.next_hop, "10.0.0.254");
+        assert_eq!(proto.egress_iface, "wlan0");
+        assert_eq!(proto.peer_id, "0123456789abcdef");
+        assert!(proto.degraded);
+        assert_eq!(proto.flows[0].remote_port, 443);
+        assert_eq!(proto.flows[0].duration, 0.25);
+        assert_eq!(proto.flows[0].process, "curl");
+        assert_eq!(proto.capacity, 1e7);
+        assert_eq!((proto.ping_rtt, proto.ping_loss), (0.004, 25.0));
+        assert_eq!((proto.hops, proto.path_changes), (0, 2));
+        assert_eq!(proto.fragmented_bytes, 4500);
+        assert_eq!(proto.loss_up, 1.5);
+        assert_eq!(proto.loss_down, 0.0);
+        assert_eq!(proto.burst_thp_in, 8.0);
+        assert_eq!(proto.abw_std_err, 0.5);
+        assert_eq!(proto.abw_samples, 12);
+        assert_eq!(proto.estimator, "rls");
+        assert_eq!(proto.estimates.len(), 1);
+        assert_eq!(proto.estimates[0].estimator, "simple");
+        assert_eq!(proto.estimates[0].abw, 3.5);
+        assert_eq!(proto.clock_offset, -1500);
+        assert_eq!(proto.thp_in_dist.unwrap().p95, 4.0);
+        assert!(proto.thp_out_dist.is_none());
+        assert_eq!(proto.bufferbloat, 2.5);
+    }
+
+    #[test]
+    fn test_truncate_oldest() {
+        let mut groups = vec![vec![1, 2, 3], vec![4, 5], vec![6]];
+        assert!(!truncate_oldest(&mut groups, 0, |g| g));
+        assert!(!truncate_oldest(&mut groups, 6, |g| g));
+
+        assert!(truncate_oldest(&mut groups, 2, |g| g));
+        assert_eq!(groups, vec![vec![5], vec![6]]);
+    }
+
+    #[test]
+    fn test_rtt_buckets() {
+        let t0 = Timestamp::from_millis(1_000_000);
+        let rtts = vec![
+            (300, t0),
+            (100, t0 + Duration::from_millis(500)),
+            (50, t0 + Duration::from_millis(2100)),
+            (200, t0 + Duration::from_millis(999)),
+        ];
+        let ip_pair = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let msg = LinkManager::get_rtt_message(rtts.clone(), ip_pair, Duration::from_secs(1), false);
+        assert!(msg.rtt.is_empty());
+        assert_eq!(msg.buckets.len(), 2);
+        let first = &msg.buckets[0];
+        assert_eq!((first.timestamp, first.count), (1_000_000, 3));
+        assert_eq!((first.min, first.mean, first.max), (100.0, 200.0, 300.0));
+        assert_eq!(msg.buckets[1].timestamp, 1_002_000);
+
+        let msg = LinkManager::get_rtt_message(rtts, ip_pair, Duration::ZERO, false);
+        assert_eq!((msg.rtt.len(), msg.buckets.len()), (4, 0));
     }
 
     #[test]
@@ -384,15 +1711,198 @@ mod tests {
                 thp_in: 0.0,
                 thp_out: 0.0,
                 bw: None,
+                bw_down: None,
+                bw_source: None,
+                bw_down_source: None,
+                probe_thp_in: 0.0,
+                probe_thp_out: 0.0,
                 abw: None,
                 latency: None,
                 delay: None,
                 jitter: None,
                 loss: None,
+                abw_down: None,
+                latency_down: None,
+                loss_up: None,
+                loss_down: None,
+                burst_thp_in: None,
+                train_abw_in: None,
+                clock_offset: None,
+                next_hop: None,
+                peer_id: None,
+                degraded: false,
+                flows: Vec::new(),
+                capacity: None,
+                ping_rtt: None,
+                ping_loss: None,
+                hops: None,
+                path_changes: 0,
+                thp_in_dist: None,
+                thp_out_dist: None,
+                idle: true,
+                bufferbloat: None,
+                fct: None,
+                rwnd: None,
+                abw_std_err: None,
+                abw_samples: 0,
+                abw_down_std_err: None,
+                abw_down_samples: 0,
+                retry_rate: None,
+                link_alive: true,
+                other_bytes: 0,
+                other_protocols: Vec::new(),
+                fragmented_bytes: 0,
+                estimator: RegressionType::Simple,
+                estimates: Vec::new(),
                 timestamp: 0,
             },
         };
         let s = format!("{}", lp);
         assert!(s.contains("192.168.1.1"));
     }
+
+    #[test]
+    fn test_vip_links_report_faster() {
+        let (mut manager, _rx) = manager();
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let ip_pair = IpPair::new([10, 0, 0, 1].into(), peer);
+
+        assert!(!manager.is_vip(&ip_pair));
+        // Every measurement window by default
+        assert_eq!(manager.report_window(&ip_pair), CONFIG.client.measurement_window);
+
+        manager.add_important_link(Ok(peer));
+        assert!(manager.is_vip(&ip_pair));
+        // Reported on even before any traffic is seen
+        assert!(manager.links.get(&ip_pair).is_some_and(StreamManager::is_idle));
+        assert_eq!(manager.report_window(&ip_pair), CONFIG.client.vip_report_interval);
+    }
+
+    #[test]
+    fn test_has_passive_data() {
+        let (mut manager, _rx) = manager();
+        let ip_pair = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+
+        // Not reported yet
+        assert!(!manager.has_passive_data(&ip_pair, 10));
+
+        manager.passive_points.insert(ip_pair, 25);
+        assert!(manager.has_passive_data(&ip_pair, 10));
+        assert!(!manager.has_passive_data(&ip_pair, 50));
+        // Disabled
+        assert!(!manager.has_passive_data(&ip_pair, 0));
+    }
+
+    #[test]
+    fn test_peer_keeps_link_across_addresses() {
+        let (mut manager, _rx) = manager();
+        let peer = identity::NodeIdentity::new([3; 32]);
+        let (old, new): (IpAddr, IpAddr) = ([10, 0, 0, 2].into(), [10, 0, 0, 3].into());
+        let (old_pair, new_pair) = (manager.host_pair(old), manager.host_pair(new));
+
+        let now = Timestamp::now().as_millis();
+
+        manager.add_important_link(Ok(old));
+        manager.record_peer_identity(&peer.announce_at("10.0.0.2", now), old);
+        manager.passive_points.insert(old_pair, 25);
+        assert_eq!(manager.peer_ids.get(&old).map(String::as_str), Some(peer.id()));
+
+        // Another node cannot claim the address
+        let other = identity::NodeIdentity::new([4; 32]);
+        manager.record_peer_identity(&other.announce_at("10.0.0.2", now), new);
+        assert_eq!(manager.peer_ids.get(&old).map(String::as_str), Some(peer.id()));
+
+        manager.record_peer_identity(&peer.announce_at("10.0.0.3", now + 1), new);
+        assert!(!manager.links.contains_key(&old_pair) && manager.links.contains_key(&new_pair));
+        assert!(manager.is_vip(&new_pair));
+        assert!(manager.has_passive_data(&new_pair, 10));
+        assert_eq!(manager.peer_ids.get(&new).map(String::as_str), Some(peer.id()));
+        assert!(!manager.peer_ids.contains_key(&old));
+
+        // A replay of the first hello does not move it back
+        manager.record_peer_identity(&peer.announce_at("10.0.0.2", now), old);
+        assert!(manager.links.contains_key(&new_pair));
+    }
+
+    #[test]
+    fn test_probe_results_go_to_target_link() {
+        let (mut manager, _rx) = manager();
+        let target: IpAddr = [10, 0, 0, 2].into();
+        let ip_pair = IpPair::new([10, 0, 0, 1].into(), target);
+        let started = Timestamp::now();
+
+        let reply = PingReply {
+            seq: 0,
+            size: 64,
+            rtt: Ok(Duration::from_millis(2)),
+        };
+        manager.insert_probe_result(ProbeResult::new(
+            target,
+            None,
+            started,
+            ProbeOutcome::Ping(reply),
+        ));
+        let echoes = [(0, Some(Duration::from_millis(1))), (1000, Some(Duration::from_millis(2)))];
+        let train = PingTrainResult::new(&echoes);
+        manager.insert_probe_result(ProbeResult::new(
+            target,
+            None,
+            started,
+            ProbeOutcome::PingTrain(train),
+        ));
+
+        assert_eq!(manager.links.len(), 1);
+        let link = manager.links.get_mut(&ip_pair).unwrap();
+        assert_eq!(link.take_ping_result(), (Some(0.002), Some(0.0)));
+        assert!(link.capacity().is_some_and(|c| (c - 2_000_000.0).abs() < 1.0));
+    }
+
+    #[test]
+    fn test_take_report() {
+        let (mut manager, _rx) = manager();
+        manager.window_start = Timestamp::from_millis(1_000_000);
+        manager.pending_rtts.push(RttMessage::default());
+        let bw_message = BandwidthMessage {
+            link_state: vec![LinkStateProto::default()],
+        };
+
+        let report = manager.take_report(bw_message, Timestamp::from_millis(1_005_000));
+        assert_eq!((report.window_start, report.window_end), (1_000_000, 1_005_000));
+        assert_eq!(report.link_state.len(), 1);
+        assert_eq!(report.rtts.map(|r| r.rtts.len()), Some(1));
+        assert!(report.pgm.is_none());
+        assert!(manager.pending_rtts.is_empty());
+        // The next window starts where this one ended
+        assert_eq!(manager.window_start, Timestamp::from_millis(1_005_000));
+    }
+
+    /// The client handler having stopped must not take the parser down.
+    #[tokio::test]
+    async fn test_client_handler_gone() {
+        let (mut manager, rx) = manager();
+        manager.add_important_link(Ok([10, 0, 0, 2].into()));
+        drop(rx);
+
+        manager.send_init_clients_msg().await;
+        manager.send_bandwidth().await;
+        manager.send_rtts().await;
+        manager.send_pgm().await;
+    }
+
+    #[tokio::test]
+    async fn test_status_goes_to_client_handler() {
+        let (mut manager, mut rx) = manager();
+        manager.add_important_link(Ok([10, 0, 0, 2].into()));
+
+        let (reply, _reply_rx) = oneshot::channel();
+        manager.send_status(NodeStatus::default(), reply).await;
+        let Some(ClientHandlerEvent::Status { status, .. }) = rx.recv().await else {
+            panic!("Expected a status request");
+        };
+        assert_eq!(status.links.len(), 1);
+        assert!(status.links[0].vip);
+        assert_eq!(status.links[0].remote_ip, "10.0.0.2");
+        assert_eq!(status.links[0].last_seen, 0);
+        assert_eq!(status.tables.get("vip_links"), Some(&1));
+    }
 }
@@ -0,0 +1,235 @@
+//! Link events: a metric of a link crossing a threshold of the `[events]`
+//! section, checked every `tracking.report_tick` on what the link has seen
+//! since its last report, so consumers hear of it before the next link state.
+//!
+//! Each event is raised once when its metric goes above the threshold, and
+//! cleared once the metric is back below it by `events.hysteresis`. The ABW
+//! drop is taken against a moving average of the ABW, which stands still
+//! while the drop is raised.
+
+use std::collections::HashMap;
+
+use crate::proto_bw::{LinkEvent, LinkEventKind};
+use crate::stream_id::IpPair;
+use crate::CONFIG;
+
+/// Weight of a new ABW in its reference.
+const REFERENCE_WEIGHT: f64 = 0.2;
+
+const KINDS: [LinkEventKind; 3] = [
+    LinkEventKind::AbwDrop,
+    LinkEventKind::RttHigh,
+    LinkEventKind::LossHigh,
+];
+
+/// Thresholds of the events, 0 disables one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Thresholds {
+    /// Percent below the reference ABW.
+    pub abw_drop: f64,
+    pub rtt_ms: f64,
+    /// Percent of the packets retransmitted.
+    pub loss: f64,
+    /// Share of a threshold a metric has to get back past to clear.
+    pub hysteresis: f64,
+}
+
+impl Thresholds {
+    pub fn from_config() -> Self {
+        let events = &CONFIG.events;
+        Thresholds {
+            abw_drop: events.abw_drop,
+            rtt_ms: events.rtt_ms,
+            loss: events.loss,
+            hysteresis: events.hysteresis,
+        }
+    }
+
+    fn of(&self, kind: LinkEventKind) -> f64 {
+        match kind {
+            LinkEventKind::AbwDrop => self.abw_drop,
+            LinkEventKind::RttHigh => self.rtt_ms,
+            LinkEventKind::LossHigh => self.loss,
+            LinkEventKind::Unspecified => 0.0,
+        }
+    }
+}
+
+/// What a link has seen since its last report.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Metrics {
+    /// Bytes/sec toward the remote.
+    pub abw: Option<f64>,
+    /// Mean RTT in microseconds.
+    pub rtt: Option<f64>,
+    /// Percent of the packets retransmitted.
+    pub loss: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+struct LinkTrack {
+    abw_reference: Option<f64>,
+    /// Raised events, by their place in `KINDS`.
+    raised: [bool; 3],
+}
+
+impl LinkTrack {
+    fn value(&self, kind: LinkEventKind, metrics: &Metrics) -> Option<f64> {
+        match kind {
+            LinkEventKind::AbwDrop => {
+                let (abw, reference) = (metrics.abw?, self.abw_reference?);
+                (reference > 0.0).then(|| 100.0 * (reference - abw) / reference)
+            }
+            LinkEventKind::RttHigh => metrics.rtt.map(|rtt| rtt / 1000.0),
+            LinkEventKind::LossHigh => metrics.loss,
+            LinkEventKind::Unspecified => None,
+        }
+    }
+
+    fn update_reference(&mut self, abw: Option<f64>) {
+        let Some(abw) = abw else {
+            return;
+        };
+        self.abw_reference = Some(match self.abw_reference {
+            Some(reference) => reference + REFERENCE_WEIGHT * (abw - reference),
+            None => abw,
+        });
+    }
+}
+
+/// Event state of every link.
+#[derive(Debug, Default)]
+pub struct LinkEventTracker {
+    thresholds: Thresholds,
+    links: HashMap<IpPair, LinkTrack>,
+}
+
+impl LinkEventTracker {
+    pub fn new(thresholds: Thresholds) -> Self {
+        LinkEventTracker {
+            thresholds,
+            links: HashMap::new(),
+        }
+    }
+
+    pub fn from_config() -> Self {
+        LinkEventTracker::new(Thresholds::from_config())
+    }
+
+    /// False if every threshold is 0.
+    pub fn is_enabled(&self) -> bool {
+        KINDS.iter().any(|&kind| self.thresholds.of(kind) > 0.0)
+    }
+
+    /// The events raised or cleared by the metrics of a link, stamped with
+    /// `timestamp` in milliseconds since epoch.
+    pub fn check(&mut self, ip_pair: IpPair, metrics: &Metrics, timestamp: i64) -> Vec<LinkEvent> {
+        let thresholds = self.thresholds;
+        let track = self.links.entry(ip_pair).or_default();
+        let mut events = Vec::new();
+        for (i, kind) in KINDS.into_iter().enumerate() {
+            let threshold = thresholds.of(kind);
+            if threshold <= 0.0 {
+                continue;
+            }
+            let Some(value) = track.value(kind, metrics) else {
+                continue;
+            };
+            let raised = match track.raised[i] {
+                false => value > threshold,
+                true => value >= threshold * (1.0 - thresholds.hysteresis),
+            };
+            if raised == track.raised[i] {
+                continue;
+            }
+            track.raised[i] = raised;
+            events.push(LinkEvent {
+                sender_ip: ip_pair.local().to_string(),
+                receiver_ip: ip_pair.remote().to_string(),
+                kind: kind as i32,
+                raised,
+                value,
+                threshold,
+                timestamp,
+            });
+        }
+        // Not while the ABW drop is raised
+        if !track.raised[0] {
+            track.update_reference(metrics.abw);
+        }
+        events
+    }
+
+    /// Moves the state of a link to another key.
+    pub fn rename(&mut self, old: IpPair, new: IpPair) {
+        if let Some(track) = self.links.remove(&old) {
+            self.links.insert(new, track);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            abw_drop: 50.0,
+            rtt_ms: 100.0,
+            loss: 0.0,
+            hysteresis: 0.2,
+        }
+    }
+
+    #[test]
+    fn test_rtt_hysteresis() {
+        let ip_pair = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let mut events = LinkEventTracker::new(thresholds());
+        let mut check = |rtt_ms: f64, loss: f64| {
+            let metrics = Metrics {
+                abw: None,
+                rtt: Some(rtt_ms * 1000.0),
+                loss: Some(loss),
+            };
+            events.check(ip_pair, &metrics, 0)
+        };
+
+        assert!(check(90.0, 50.0).is_empty(), "Loss is disabled");
+        let raised = check(120.0, 0.0);
+        assert_eq!(raised.len(), 1);
+        assert!(raised[0].raised);
+        assert_eq!(raised[0].kind(), LinkEventKind::RttHigh);
+        assert_eq!((raised[0].value, raised[0].threshold), (120.0, 100.0));
+        // Raised once, and kept until below 80 ms
+        assert!(check(130.0, 0.0).is_empty());
+        assert!(check(85.0, 0.0).is_empty());
+        let cleared = check(70.0, 0.0);
+        assert_eq!(cleared.len(), 1);
+        assert!(!cleared[0].raised);
+        // An unset kind is none of them
+        assert_eq!(LinkEvent::default().kind(), LinkEventKind::Unspecified);
+    }
+
+    #[test]
+    fn test_abw_drop_holds_reference() {
+        let ip_pair = IpPair::new([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let mut events = LinkEventTracker::new(thresholds());
+        let mut check = |abw: f64| {
+            let metrics = Metrics {
+                abw: Some(abw),
+                ..Default::default()
+            };
+            events.check(ip_pair, &metrics, 0)
+        };
+
+        assert!(check(10e6).is_empty());
+        assert!(check(10e6).is_empty());
+        let raised = check(4e6);
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].value, 60.0);
+        // The reference stays at 10 MB/s while the drop lasts
+        assert!(check(4e6).is_empty());
+        assert!(check(5.5e6).is_empty());
+        assert!(!check(7e6)[0].raised);
+    }
+}
@@ -1,8 +1,24 @@
+pub mod aggregation;
+pub mod baseline_state;
+pub mod bufferbloat;
+pub mod deadline_wheel;
+pub mod estimates;
+pub mod features;
+pub mod flow_time;
 pub mod generic_tracker;
+pub mod hops;
 pub mod link;
+pub mod link_events;
+pub mod neighbors;
+pub mod probe_history;
+pub mod probe_traffic;
+pub mod relay;
+pub mod rwnd;
+pub mod self_traffic;
 pub mod stream_id;
 pub mod stream_manager;
 pub mod tcp_tracker;
+pub mod throughput;
 pub mod tracker;
 pub mod udp_tracker;
 
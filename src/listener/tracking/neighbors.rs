@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::listener::packet::neighbor::{NeighborOp, NeighborPacket};
+use crate::{Timestamp, CONFIG};
+
+/// Reachability of a neighbor, as seen from ARP and neighbor discovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reachability {
+    /// Heard from within `tracking.neighbor_stale`.
+    Reachable,
+    /// Not heard from recently, but no request has gone unanswered.
+    Stale,
+    /// A request from this host got no reply within
+    /// `tracking.neighbor_reply_timeout`.
+    Unreachable,
+}
+
+#[derive(Debug, Default)]
+struct Neighbor {
+    /// Oldest unanswered request from this host.
+    pending: Option<Timestamp>,
+    /// Last message sent by the neighbor.
+    last_seen: Option<Timestamp>,
+}
+
+impl Neighbor {
+    fn last_activity(&self) -> Option<Timestamp> {
+        self.pending.max(self.last_seen)
+    }
+}
+
+/// Per-neighbor reachability built from ARP and ICMPv6 ND messages.
+///
+/// Requests from this host are matched against replies (or any other
+/// message) from the neighbor, using packet timestamps throughout.
+#[derive(Debug, Default)]
+pub struct NeighborTable {
+    neighbors: HashMap<IpAddr, Neighbor>,
+}
+
+impl NeighborTable {
+    pub fn new() -> Self {
+        NeighborTable::default()
+    }
+
+    /// Number of neighbors known.
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    /// Records a message. `from_local` is true if this host sent it.
+    pub fn insert(&mut self, packet: &NeighborPacket, from_local: bool) {
+        if from_local {
+            if packet.op == NeighborOp::Request {
+                let neighbor = self.neighbors.entry(packet.target_ip).or_default();
+                // Keep the first request, retransmissions should not reset it
+                neighbor.pending.get_or_insert(packet.timestamp);
+            }
+            return;
+        }
+        // Any message from the neighbor proves it is alive
+        let neighbor = self.neighbors.entry(packet.sender_ip).or_default();
+        neighbor.pending = None;
+        neighbor.last_seen = neighbor.last_seen.max(Some(packet.timestamp));
+    }
+
+    /// Reachability of `ip` at `now`, None if no messages have been seen.
+    pub fn reachability(&self, ip: &IpAddr, now: Timestamp) -> Option<Reachability> {
+        let neighbor = self.neighbors.get(ip)?;
+        if let Some(pending) = neighbor.pending {
+            if elapsed(pending, now) > CONFIG.tracking.neighbor_reply_timeout {
+                return Some(Reachability::Unreachable);
+            }
+        }
+        match neighbor.last_seen {
+            Some(last_seen) if elapsed(last_seen, now) <= CONFIG.tracking.neighbor_stale => {
+                Some(Reachability::Reachable)
+            }
+            _ => Some(Reachability::Stale),
+        }
+    }
+
+    /// Drops neighbors with no activity within `tracking.neighbor_timeout`.
+    pub fn prune(&mut self, now: Timestamp) {
+        self.neighbors.retain(|_, neighbor| {
+            neighbor
+                .last_activity()
+                .is_some_and(|t| elapsed(t, now) <= CONFIG.tracking.neighbor_timeout)
+        });
+    }
+}
+
+fn elapsed(then: Timestamp, now: Timestamp) -> Duration {
+    now.saturating_duration_since(then)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCAL: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const REMOTE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn packet(op: NeighborOp, sender_ip: IpAddr, target_ip: IpAddr, ts: Timestamp) -> NeighborPacket {
+        NeighborPacket {
+            op,
+            sender_ip,
+            target_ip,
+            timestamp: ts,
+        }
+    }
+
+    #[test]
+    fn test_request_reply() {
+        let mut table = NeighborTable::new();
+        let t0 = Timestamp::from_millis(1_000_000);
+        assert_eq!(table.reachability(&REMOTE, t0), None);
+
+        table.insert(&packet(NeighborOp::Request, LOCAL, REMOTE, t0), true);
+        assert_eq!(table.reachability(&REMOTE, t0), Some(Reachability::Stale));
+
+        let t1 = t0 + Duration::from_millis(5);
+        table.insert(&packet(NeighborOp::Reply, REMOTE, LOCAL, t1), false);
+        assert_eq!(table.reachability(&REMOTE, t1), Some(Reachability::Reachable));
+
+        let later = t1 + CONFIG.tracking.neighbor_stale + Duration::from_secs(1);
+        assert_eq!(table.reachability(&REMOTE, later), Some(Reachability::Stale));
+    }
+
+    #[test]
+    fn test_unanswered_request() {
+        let mut table = NeighborTable::new();
+        let t0 = Timestamp::from_millis(1_000_000);
+        table.insert(&packet(NeighborOp::Reply, REMOTE, LOCAL, t0), false);
+
+        let t1 = t0 + Duration::from_secs(1);
+        table.insert(&packet(NeighborOp::Request, LOCAL, REMOTE, t1), true);
+        // A retransmitted request does not restart the timeout
+        let t2 = t1 + Duration::from_secs(1);
+        table.insert(&packet(NeighborOp::Request, LOCAL, REMOTE, t2), true);
+
+        let timeout = t1 + CONFIG.tracking.neighbor_reply_timeout + Duration::from_millis(1);
+        assert_eq!(table.reachability(&REMOTE, timeout), Some(Reachability::Unreachable));
+
+        // Requests from the neighbor count as a sign of life
+        table.insert(&packet(NeighborOp::Request, REMOTE, LOCAL, timeout), false);
+        assert_eq!(table.reachability(&REMOTE, timeout), Some(Reachability::Reachable));
+
+        table.prune(timeout + CONFIG.tracking.neighbor_timeout + Duration::from_secs(1));
+        assert_eq!(table.reachability(&REMOTE, timeout), None);
+    }
+}
@@ -0,0 +1,150 @@
+//! The last results of active probes on a link, iperf, pathload and the
+//! built-in packet trains alike, so a link state reports the freshest one
+//! that is still valid instead of whichever came last.
+//!
+//! A result counts once: one with the probe id of a kept result from the
+//! same source, or without an id and the value of one from the same source
+//! within `DUPLICATE_WINDOW`, is dropped.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::proto_bw::ProbeSource;
+use crate::Direction;
+
+/// Results kept per link.
+pub const HISTORY_LEN: usize = 8;
+
+/// Results are valid for this many measurement windows.
+pub const VALID_WINDOWS: u32 = 3;
+
+/// Results without a probe id this close with the same value are one.
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(2);
+
+/// A bandwidth measured by an active probe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeMeasurement {
+    pub source: ProbeSource,
+    /// `Outgoing` if this host sent the probe traffic.
+    pub direction: Direction,
+    /// Bytes/sec.
+    pub throughput: f64,
+    /// Id of the negotiated session, None for probes without one.
+    pub probe_id: Option<u64>,
+    pub at: Instant,
+}
+
+impl ProbeMeasurement {
+    fn duplicates(&self, other: &ProbeMeasurement) -> bool {
+        if self.source != other.source || self.direction != other.direction {
+            return false;
+        }
+        match (self.probe_id, other.probe_id) {
+            (Some(id), Some(other_id)) => id == other_id,
+            (None, None) => {
+                self.throughput == other.throughput
+                    && self.at.max(other.at) - self.at.min(other.at) <= DUPLICATE_WINDOW
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The last `HISTORY_LEN` results on a link, oldest first.
+#[derive(Debug, Default)]
+pub struct ProbeHistory {
+    results: VecDeque<ProbeMeasurement>,
+}
+
+impl ProbeHistory {
+    /// Keeps a result, dropping the oldest one when full. False if it was a
+    /// duplicate.
+    pub fn record(&mut self, measurement: ProbeMeasurement) -> bool {
+        if self.results.iter().any(|r| r.duplicates(&measurement)) {
+            return false;
+        }
+        if self.results.len() == HISTORY_LEN {
+            self.results.pop_front();
+        }
+        self.results.push_back(measurement);
+        true
+    }
+
+    /// The newest result in `direction` at most `max_age` old at `now`.
+    pub fn freshest(
+        &self,
+        direction: Direction,
+        max_age: Duration,
+        now: Instant,
+    ) -> Option<&ProbeMeasurement> {
+        self.results
+            .iter()
+            .filter(|r| r.direction == direction)
+            .filter(|r| now.saturating_duration_since(r.at) <= max_age)
+            .max_by_key(|r| r.at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(
+        source: ProbeSource,
+        throughput: f64,
+        probe_id: Option<u64>,
+        at: Instant,
+    ) -> ProbeMeasurement {
+        ProbeMeasurement {
+            source,
+            direction: Direction::Outgoing,
+            throughput,
+            probe_id,
+            at,
+        }
+    }
+
+    #[test]
+    fn test_freshest_valid() {
+        let start = Instant::now();
+        let mut history = ProbeHistory::default();
+        history.record(measurement(ProbeSource::Iperf, 1e6, None, start));
+        let later = start + Duration::from_secs(30);
+        history.record(measurement(ProbeSource::Pathload, 2e6, Some(1), later));
+        history.record(ProbeMeasurement {
+            direction: Direction::Incoming,
+            ..measurement(ProbeSource::Builtin, 3e6, None, later)
+        });
+
+        let max_age = Duration::from_secs(60);
+        let freshest = history.freshest(Direction::Outgoing, max_age, later).unwrap();
+        assert_eq!(freshest.source, ProbeSource::Pathload);
+        // Older ones are still kept, but not valid
+        let now = later + Duration::from_secs(61);
+        assert!(history.freshest(Direction::Outgoing, max_age, now).is_none());
+        assert_eq!(history.results.len(), 3);
+    }
+
+    #[test]
+    fn test_duplicates_and_len() {
+        let start = Instant::now();
+        let mut history = ProbeHistory::default();
+        assert!(history.record(measurement(ProbeSource::Pathload, 2e6, Some(7), start)));
+        let retried = start + Duration::from_secs(10);
+        assert!(!history.record(measurement(ProbeSource::Pathload, 2.1e6, Some(7), retried)));
+        assert!(history.record(measurement(ProbeSource::Iperf, 1e6, None, start)));
+        let second = start + Duration::from_secs(1);
+        assert!(!history.record(measurement(ProbeSource::Iperf, 1e6, None, second)));
+        // The same value later is a new result
+        let next = start + Duration::from_secs(20);
+        assert!(history.record(measurement(ProbeSource::Iperf, 1e6, None, next)));
+
+        for i in 0..HISTORY_LEN as u64 {
+            history.record(measurement(ProbeSource::Pathload, 1e6, Some(100 + i), next));
+        }
+        assert_eq!(history.results.len(), HISTORY_LEN);
+        assert!(history.results.iter().all(|r| r.source == ProbeSource::Pathload));
+    }
+}
@@ -0,0 +1,116 @@
+use std::net::IpAddr;
+
+use crate::probe::session::ProbeSession;
+use crate::{ParsedPacket, IPERF3_PORT};
+
+/// Keeps track of active probe sessions so the traffic they generate can be
+/// told apart from passively observed traffic.
+///
+/// A packet is probe traffic if it belongs to a registered session (same peer
+/// and one of the session's ports), or if it uses the port of the always-on
+/// iperf3 server.
+#[derive(Debug, Default)]
+pub struct ProbeTraffic {
+    sessions: Vec<ProbeSession>,
+}
+
+impl ProbeTraffic {
+    pub fn new() -> Self {
+        ProbeTraffic::default()
+    }
+
+    /// Register a session agreed on with a peer.
+    pub fn register(&mut self, session: ProbeSession) {
+        self.sessions.push(session);
+    }
+
+    /// Drop sessions which can no longer generate traffic.
+    pub fn prune(&mut self) {
+        self.sessions.retain(|session| session.is_active());
+    }
+
+    /// Number of sessions currently registered.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Returns true if the packet to/from `remote` was generated by a probe.
+    pub fn matches(&self, packet: &ParsedPacket, remote: IpAddr) -> bool {
+        let (src_port, dst_port) = match packet.get_src_dst_port() {
+            Some(ports) => ports,
+            None => return false,
+        };
+        if src_port == IPERF3_PORT || dst_port == IPERF3_PORT {
+            return true;
+        }
+        self.sessions.iter().any(|session| {
+            session.peer == remote
+                && session.is_active()
+                && (session.uses_port(src_port) || session.uses_port(dst_port))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::session::ProbeTechnique;
+    use crate::{Direction, Timestamp};
+    use std::time::Duration;
+
+    fn udp_packet(src: IpAddr, dst: IpAddr, src_port: u16, dst_port: u16) -> ParsedPacket {
+        let timestamp = Timestamp::now();
+        ParsedPacket {
+            src_ip: src,
+            dst_ip: dst,
+            ..ParsedPacket::test_udp(src_port, dst_port, 100, timestamp, Direction::Outgoing)
+        }
+    }
+
+    #[test]
+    fn test_matches_registered_session() {
+        let local: IpAddr = [10, 0, 0, 1].into();
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let other: IpAddr = [10, 0, 0, 3].into();
+
+        let mut probes = ProbeTraffic::new();
+        probes.register(ProbeSession::new(
+            1,
+            peer,
+            5300,
+            ProbeTechnique::Iperf3,
+            Duration::from_secs(10),
+        ));
+
+        assert!(probes.matches(&udp_packet(local, peer, 40000, 5300), peer));
+        assert!(!probes.matches(&udp_packet(local, other, 40000, 5300), other));
+        assert!(!probes.matches(&udp_packet(local, peer, 40000, 5301), peer));
+    }
+
+    #[test]
+    fn test_matches_default_iperf_port() {
+        let local: IpAddr = [10, 0, 0, 1].into();
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let probes = ProbeTraffic::new();
+        assert!(probes.matches(&udp_packet(peer, local, IPERF3_PORT, 40000), peer));
+    }
+
+    #[test]
+    fn test_prune_keeps_active() {
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let mut probes = ProbeTraffic::new();
+        probes.register(ProbeSession::new(
+            1,
+            peer,
+            5300,
+            ProbeTechnique::Iperf3,
+            Duration::from_secs(10),
+        ));
+        probes.prune();
+        assert_eq!(probes.len(), 1);
+    }
+}
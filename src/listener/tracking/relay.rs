@@ -0,0 +1,201 @@
+//! Packets relayed through this node.
+//!
+//! A node forwarding traffic between two others sees each packet twice, once
+//! arriving from the previous hop and once leaving toward the next. Counted
+//! under the end-to-end pair, the bytes of the flow are counted both ways,
+//! and the TCP trackers take the copies for a retransmission and an ACK.
+//!
+//! The copies are told apart by the fields forwarding leaves alone: the
+//! addresses, the IPv4 identification and the TCP sequence numbers or the
+//! UDP ports. Once a pair of hosts was seen relayed, its copies are counted
+//! on the links to the previous and next hop instead, the first copy as
+//! received from the previous hop and the second as sent to the next.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::stream_id::IpPair;
+use crate::{ParsedPacket, Timestamp, TransportPacket};
+
+/// Most time between the two copies of a packet.
+const COPY_HORIZON: Duration = Duration::from_millis(50);
+/// Pairs not relayed for this long go back to the end-to-end link.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most first copies waiting for their twin.
+const MAX_COPIES: usize = 8192;
+
+/// Which copy of a relayed packet this is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayCopy {
+    /// Arriving from the previous hop.
+    Ingress,
+    /// Leaving toward the next hop.
+    Egress,
+}
+
+/// Fields of a packet that forwarding does not change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CopyKey {
+    src: std::net::IpAddr,
+    dst: std::net::IpAddr,
+    ip_id: u16,
+    seq: u32,
+    ack: u32,
+    len: u16,
+}
+
+impl CopyKey {
+    /// None if the packet has nothing to tell it from its neighbors, i.e.
+    /// neither an IPv4 identification nor TCP sequence numbers.
+    fn from_packet(packet: &ParsedPacket) -> Option<Self> {
+        let (seq, ack, len) = match packet.transport {
+            TransportPacket::TCP {
+                sequence,
+                acknowledgment,
+                payload_len,
+                ..
+            } => (sequence, acknowledgment, payload_len),
+            _ if packet.ip_id == 0 => return None,
+            TransportPacket::UDP {
+                src_port,
+                dst_port,
+                payload_len,
+            } => ((src_port as u32) << 16 | dst_port as u32, 0, payload_len),
+            _ => (0, 0, packet.total_length),
+        };
+        Some(CopyKey {
+            src: packet.src_ip,
+            dst: packet.dst_ip,
+            ip_id: packet.ip_id,
+            seq,
+            ack,
+            len,
+        })
+    }
+}
+
+/// Finds the packets seen twice and the pairs of hosts relayed through
+/// this node.
+#[derive(Debug, Default)]
+pub struct RelayTracker {
+    /// First copies, by when they were seen.
+    copies: HashMap<CopyKey, Timestamp>,
+    /// Pairs seen relayed, by when they last were.
+    relayed: HashMap<IpPair, Timestamp>,
+}
+
+impl RelayTracker {
+    pub fn new() -> Self {
+        RelayTracker::default()
+    }
+
+    /// Which copy `packet` is, None unless it is between two other hosts
+    /// that were seen relayed. The copy that shows the pair is relayed is
+    /// an egress copy, its twin was already counted end to end. None as
+    /// well if there is no room to remember it, as it cannot be told from
+    /// a second copy whose first was not remembered either.
+    pub fn observe(&mut self, packet: &ParsedPacket) -> Option<RelayCopy> {
+        if !packet.intercepted {
+            return None;
+        }
+        let key = CopyKey::from_packet(packet)?;
+        // Both ways of the pair under one key, as `Pair` hashes in order
+        let (src, dst) = (packet.src_ip, packet.dst_ip);
+        let pair = IpPair::new(src.min(dst), src.max(dst));
+        let now = packet.timestamp;
+
+        let twin = self
+            .copies
+            .remove(&key)
+            .filter(|seen| *seen + COPY_HORIZON >= now);
+        if twin.is_some() {
+            self.relayed.insert(pair, now);
+            return Some(RelayCopy::Egress);
+        }
+        if self.copies.len() >= MAX_COPIES {
+            return None;
+        }
+        self.copies.insert(key, now);
+        let relayed = self.relayed.get_mut(&pair)?;
+        *relayed = now;
+        Some(RelayCopy::Ingress)
+    }
+
+    /// Forgets first copies past the horizon and pairs no longer relayed.
+    pub fn prune(&mut self, now: Timestamp) {
+        self.copies.retain(|_, seen| *seen + COPY_HORIZON > now);
+        self.relayed.retain(|_, seen| *seen + RELAY_TIMEOUT > now);
+    }
+
+    /// First copies waiting for their twin.
+    pub fn len(&self) -> usize {
+        self.copies.len()
+    }
+
+    /// Pairs seen relayed lately.
+    pub fn relayed(&self) -> usize {
+        self.relayed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Direction;
+
+    fn segment(sequence: u32, ms: u64, direction: Direction) -> ParsedPacket {
+        let timestamp = Timestamp::from_millis(ms);
+        ParsedPacket {
+            dst_ip: [10, 0, 2, 1].into(),
+            intercepted: true,
+            ttl: 63,
+            ..ParsedPacket::test_tcp(sequence, 1, 1448, timestamp, direction)
+        }
+    }
+
+    #[test]
+    fn test_copies_of_relayed_packets() {
+        let mut relay = RelayTracker::new();
+        // Not known to be relayed until the first twin shows up
+        assert_eq!(relay.observe(&segment(1, 1000, Direction::Incoming)), None);
+        assert_eq!(
+            relay.observe(&segment(1, 1001, Direction::Outgoing)),
+            Some(RelayCopy::Egress)
+        );
+
+        assert_eq!(
+            relay.observe(&segment(1449, 1010, Direction::Incoming)),
+            Some(RelayCopy::Ingress)
+        );
+        assert_eq!(
+            relay.observe(&segment(1449, 1011, Direction::Outgoing)),
+            Some(RelayCopy::Egress)
+        );
+        // Too late to be a copy of the last one, a retransmission
+        assert_eq!(
+            relay.observe(&segment(1449, 1200, Direction::Incoming)),
+            Some(RelayCopy::Ingress)
+        );
+        assert_eq!(relay.len(), 1);
+
+        // Only traffic between two other hosts is relayed
+        let mut local = segment(2897, 1300, Direction::Incoming);
+        local.intercepted = false;
+        assert_eq!(relay.observe(&local), None);
+
+        // Copies that cannot be remembered are not known to be either
+        for sequence in 0..MAX_COPIES as u32 {
+            relay.observe(&segment(10_000 + sequence, 1250, Direction::Incoming));
+        }
+        assert_eq!(relay.len(), MAX_COPIES);
+        assert_eq!(relay.observe(&segment(5, 1251, Direction::Outgoing)), None);
+
+        relay.prune(Timestamp::from_millis(1300) + RELAY_TIMEOUT);
+        assert!(relay.is_empty());
+        assert_eq!(relay.observe(&segment(4345, 70_000, Direction::Incoming)), None);
+    }
+}
@@ -0,0 +1,194 @@
+//! Receive window advertised by remote peers, to tell transfers held back
+//! by the receiver from those limited by the network.
+//!
+//! Window scaling is only known from the handshake, so the size of the
+//! window is only sampled on connections whose SYNs were seen. Zero window
+//! stalls are detected on every connection.
+
+use std::time::Duration;
+
+use crate::{Direction, ParsedPacket, Timestamp, TransportPacket};
+
+/// Largest shift allowed by RFC 7323.
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Receive window of the remote end of one TCP connection.
+#[derive(Debug, Default)]
+pub struct ReceiveWindow {
+    /// Scale offered in the SYN of each end.
+    local_scale: Option<u8>,
+    remote_scale: Option<u8>,
+    /// The SYN of each end was seen.
+    syn_seen: (bool, bool),
+    /// Start of the current zero window stall.
+    zero_since: Option<Timestamp>,
+}
+
+impl ReceiveWindow {
+    pub fn new() -> Self {
+        ReceiveWindow::default()
+    }
+
+    /// Records the window of a segment, adding it to `stats` if it was
+    /// advertised by the remote.
+    pub fn record(&mut self, packet: &ParsedPacket, stats: &mut WindowStats) {
+        let TransportPacket::TCP {
+            flags,
+            options,
+            window_size,
+            ..
+        } = &packet.transport
+        else {
+            return;
+        };
+        if flags.is_syn() {
+            match packet.direction {
+                Direction::Outgoing => {
+                    self.local_scale = options.scale;
+                    self.syn_seen.0 = true;
+                }
+                Direction::Incoming => {
+                    self.remote_scale = options.scale;
+                    self.syn_seen.1 = true;
+                }
+            }
+            // The window in a SYN is never scaled, and says little
+            return;
+        }
+        if packet.direction != Direction::Incoming || flags.is_rst() || !flags.is_ack() {
+            return;
+        }
+        if *window_size == 0 {
+            if self.zero_since.is_none() {
+                self.zero_since = Some(packet.timestamp);
+                stats.stalls += 1;
+            }
+        } else if let Some(since) = self.zero_since.take() {
+            stats.stalled += packet.timestamp.saturating_duration_since(since);
+        }
+        if self.syn_seen == (true, true) {
+            // Scaling is in use only if both ends offered it
+            let shift = match (self.local_scale, self.remote_scale) {
+                (Some(_), Some(scale)) => scale.min(MAX_WINDOW_SCALE),
+                _ => 0,
+            };
+            stats.add((*window_size as u32) << shift);
+        }
+    }
+}
+
+/// Windows advertised by the remote across the connections on a link.
+#[derive(Debug, Default)]
+pub struct WindowStats {
+    sum: f64,
+    samples: u32,
+    min: u32,
+    max: u32,
+    /// Zero window stalls that started.
+    stalls: u32,
+    /// Time spent in stalls that ended.
+    stalled: Duration,
+}
+
+/// `WindowStats` over a report window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowSummary {
+    /// Bytes, None without samples
+    pub avg: Option<f64>,
+    pub min: u32,
+    pub max: u32,
+    pub samples: u32,
+    pub stalls: u32,
+    pub stalled: Duration,
+}
+
+impl WindowStats {
+    fn add(&mut self, window: u32) {
+        if self.samples == 0 || window < self.min {
+            self.min = window;
+        }
+        self.max = self.max.max(window);
+        self.sum += window as f64;
+        self.samples += 1;
+    }
+
+    /// Takes the summary since the last call, None if nothing was seen.
+    pub fn take(&mut self) -> Option<WindowSummary> {
+        let stats = std::mem::take(self);
+        if stats.samples == 0 && stats.stalls == 0 && stats.stalled.is_zero() {
+            return None;
+        }
+        Some(WindowSummary {
+            avg: if stats.samples > 0 {
+                Some(stats.sum / stats.samples as f64)
+            } else {
+                None
+            },
+            min: stats.min,
+            max: stats.max,
+            samples: stats.samples,
+            stalls: stats.stalls,
+            stalled: stats.stalled,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TcpFlags, TcpOptions};
+
+    fn segment(
+        ms: u64,
+        direction: Direction,
+        flags: u8,
+        scale: Option<u8>,
+        window: u16,
+    ) -> ParsedPacket {
+        let transport = TransportPacket::TCP {
+            sequence: 0,
+            acknowledgment: 0,
+            flags: TcpFlags::new(flags),
+            payload_len: 0,
+            options: TcpOptions {
+                scale,
+                ..TcpOptions::new()
+            },
+            src_port: 4000,
+            dst_port: 5000,
+            window_size: window,
+        };
+        let timestamp = Timestamp::from_millis(1_000_000 + ms);
+        ParsedPacket::test_packet(transport, timestamp, direction)
+    }
+
+    #[test]
+    fn test_scaled_windows_and_stalls() {
+        let ack = TcpFlags::ACK;
+        let mut rwnd = ReceiveWindow::new();
+        let mut stats = WindowStats::default();
+        for packet in [
+            segment(0, Direction::Outgoing, TcpFlags::SYN, Some(7), 64240),
+            segment(10, Direction::Incoming, TcpFlags::SYN | ack, Some(2), 65160),
+            segment(20, Direction::Incoming, ack, None, 1000),
+            // Ours does not count
+            segment(25, Direction::Outgoing, ack, None, 10),
+            segment(30, Direction::Incoming, ack, None, 0),
+            segment(40, Direction::Incoming, ack, None, 0),
+            segment(130, Direction::Incoming, ack, None, 500),
+        ] {
+            rwnd.record(&packet, &mut stats);
+        }
+        let summary = stats.take().unwrap();
+        assert_eq!((summary.min, summary.max, summary.samples), (0, 4000, 4));
+        assert_eq!(summary.avg, Some(1500.0));
+        assert_eq!((summary.stalls, summary.stalled), (1, Duration::from_millis(100)));
+        assert_eq!(stats.take(), None);
+
+        // Joined mid connection, unknown scale
+        let mut rwnd = ReceiveWindow::new();
+        rwnd.record(&segment(0, Direction::Incoming, ack, None, 0), &mut stats);
+        let summary = stats.take().unwrap();
+        assert_eq!((summary.avg, summary.stalls), (None, 1));
+    }
+}
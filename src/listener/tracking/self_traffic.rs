@@ -0,0 +1,107 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use log::warn;
+use pnet::ipnetwork::IpNetwork;
+
+use crate::CONFIG;
+
+/// Traffic which should never show up in the link statistics: the tool's
+/// own gRPC control plane, plus any ports or prefixes listed in the config.
+///
+/// Applied both as a BPF filter on the capture and by the "ports" and
+/// "subnets" filters in the parser, which also cover captures where the
+/// filter could not be compiled.
+#[derive(Debug, Clone, Default)]
+pub struct SelfTraffic {
+    ports: BTreeSet<u16>,
+    prefixes: Vec<IpNetwork>,
+}
+
+impl SelfTraffic {
+    pub fn new() -> Self {
+        SelfTraffic::default()
+    }
+
+    /// Exclusions from `client.exclude_ports` and `client.exclude_prefixes`,
+    /// and the ports this tool listens on if `client.exclude_own_ports` is set.
+    pub fn from_config() -> Self {
+        let mut filter = SelfTraffic::new();
+        if CONFIG.client.exclude_own_ports {
+            filter.add_port(CONFIG.server.port);
+            filter.add_port(CONFIG.client.listen_port);
+            let collector_ports = CONFIG
+                .server
+                .collector_addrs()
+                .into_iter()
+                .filter_map(|addr| addr.rsplit_once(':')?.1.parse().ok());
+            for port in collector_ports {
+                filter.add_port(port);
+            }
+        }
+        for port in &CONFIG.client.exclude_ports {
+            filter.add_port(*port);
+        }
+        for prefix in &CONFIG.client.exclude_prefixes {
+            match IpNetwork::from_str(prefix) {
+                Ok(network) => filter.add_prefix(network),
+                Err(e) => warn!("Ignoring invalid prefix {}: {}", prefix, e),
+            }
+        }
+        filter
+    }
+
+    pub fn add_port(&mut self, port: u16) {
+        self.ports.insert(port);
+    }
+
+    pub fn add_prefix(&mut self, network: IpNetwork) {
+        self.prefixes.push(network);
+    }
+
+    pub fn ports(&self) -> &BTreeSet<u16> {
+        &self.ports
+    }
+
+    pub fn prefixes(&self) -> &[IpNetwork] {
+        &self.prefixes
+    }
+
+    /// BPF expression dropping the excluded traffic, None if nothing is excluded.
+    pub fn bpf(&self) -> Option<String> {
+        let terms: Vec<String> = self
+            .ports
+            .iter()
+            .map(|port| format!("port {}", port))
+            // BPF rejects host bits in a net expression
+            .chain(
+                self.prefixes
+                    .iter()
+                    .map(|network| format!("net {}/{}", network.network(), network.prefix())),
+            )
+            .collect();
+        if terms.is_empty() {
+            return None;
+        }
+        Some(format!("not ({})", terms.join(" or ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bpf() {
+        let mut filter = SelfTraffic::new();
+        assert_eq!(filter.bpf(), None);
+
+        filter.add_port(40042);
+        filter.add_port(50041);
+        filter.add_prefix("10.1.0.0/16".parse().unwrap());
+        assert_eq!(
+            filter.bpf().unwrap(),
+            "not (port 40042 or port 50041 or net 10.1.0.0/16)"
+        );
+    }
+}
@@ -6,8 +6,6 @@ use crate::{Direction, ParsedPacket, TransportPacket};
 use pnet::packet::ip::IpNextHeaderProtocol;
 use procfs::net::{TcpNetEntry, UdpNetEntry};
 
-use crate::probe::iperf_json::Connected;
-
 pub type IpPair = Pair<IpAddr>;
 
 /// Marker trait for types that can be used in a `Pair`.
@@ -82,7 +80,7 @@ impl Display for Pair<IpAddr> {
 /// A key identifying a transport-layer stream: a pair of ports plus protocol.
 ///
 /// The [`StreamKey`] is used inside the `StreamManager` to identify the stream.
-#[derive(Debug, PartialEq, Hash, Eq)]
+#[derive(Debug, PartialEq, Hash, Eq, Clone, Copy)]
 pub struct StreamKey {
     ports: Pair<Option<u16>>,
     protocol: IpNextHeaderProtocol,
@@ -98,6 +96,14 @@ impl StreamKey {
         }
     }
 
+    pub fn local_port(&self) -> Option<u16> {
+        self.ports.local()
+    }
+
+    pub fn remote_port(&self) -> Option<u16> {
+        self.ports.remote()
+    }
+
     /// Construct from separate src/dst ports and direction.
     pub fn from_direction(
         protocol: IpNextHeaderProtocol,
@@ -137,24 +143,6 @@ impl Display for StreamKey {
 }
 
 
-/// Create a `StreamKey` and `Pair<IpAddr>` from an `iperf` JSON-connected struct.
-pub fn from_iperf_connected(
-    connected: &Connected,
-    proto: IpNextHeaderProtocol,
-) -> (StreamKey, Pair<IpAddr>) {
-    (
-        StreamKey::new(
-            proto,
-            Some(connected.local_port as u16),
-            Some(connected.remote_port as u16),
-        ),
-        Pair::new(
-            connected.local_host.parse().unwrap(),
-            connected.remote_host.parse().unwrap(),
-        ),
-    )
-}
-
 /// Macro to generate helpers from procfs net entries (TCP/UDP).
 ///
 /// Each function returns the corresponding `StreamKey` and IP `Pair`.
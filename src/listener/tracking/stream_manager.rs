@@ -1,11 +1,24 @@
 use crate::{
-    stream_id::StreamKey,
+    baseline_state::LinkBaseline,
+    bufferbloat::{BaselineRtt, Bufferbloat},
+    deadline_wheel::DeadlineWheel,
+    features::{FeatureVector, FeatureWindow},
+    flow_time::{FctPercentiles, FinishedFlow, MAX_FLOW_RECORDS},
+    hops::{HopTracker, PathChange},
+    probe_history::{ProbeHistory, ProbeMeasurement, VALID_WINDOWS},
+    proto_bw::ProbeSource,
+    rwnd::{WindowStats, WindowSummary},
+    stream_id::{IpPair, StreamKey},
+    throughput::{Percentiles, ThroughputSeries},
     tracker::{Tracker, TrackerState},
-    PacketRegistry, ParsedPacket,
+    tap::BurstSummary,
+    tcp_tracker::Burst,
+    Direction, PacketRegistry, ParsedPacket, Timestamp,
 };
 use pnet::packet::ip::IpNextHeaderProtocol;
-use std::collections::HashMap;
-use tokio::time::Instant;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
 
 /// Manages active transport streams, tracking their packet bursts and throughput.
 ///
@@ -15,18 +28,81 @@ use tokio::time::Instant;
 pub struct StreamManager {
     /// HashMap for all streams
     streams: HashMap<StreamKey, Tracker<TrackerState>>,
+    /// When each stream is next due a flush or an idle check, so `periodic`
+    /// only visits those.
+    deadlines: DeadlineWheel<StreamKey>,
+    /// Streams idle for this long are dropped, `tracking.tcp_stream_timeout`.
+    stream_timeout: Duration,
+    /// Residual bursts are flushed at most this long after they start,
+    /// `tracking.cleanup_interval`.
+    flush_interval: Duration,
     /// Registry for outgoing streams (Including incoming acks).
     pub sent: PacketRegistry,
     /// Registry for streams from other nodes.
     pub received: PacketRegistry,
-    /// TCP throughput in bytes per second.
-    tcp_thput: f64,
-    /// Last time iperf was run.
-    pub last_iperf: Option<Instant>,
+    /// Bandwidths measured by iperf, pathload and packet trains.
+    probes: ProbeHistory,
+    /// Jitter in milliseconds from the last UDP iperf test.
+    udp_jitter: Option<f64>,
+    /// Packet loss in percent from the last UDP iperf test.
+    udp_loss: Option<f64>,
+    /// Path capacity in bytes/sec from the last ping train.
+    capacity: Option<f64>,
+    /// RTTs in seconds of the pings answered since the last report.
+    ping_rtts: Vec<f64>,
+    /// Pings without a reply since the last report.
+    pings_lost: u32,
+    /// Lowest RTT toward the remote over the last reports.
+    baseline_rtt: BaselineRtt,
+    /// Hops from the remote hosts, from the TTL of their packets.
+    hops: HopTracker,
+    /// Arrival rates in bytes/sec of packet trains from the remote since the
+    /// last report.
+    train_samples: Vec<f64>,
     /// Total bytes sent.
     bytes_sent: u32,
     /// Total bytes received.
     bytes_received: u32,
+    /// Bytes sent by active probes.
+    probe_bytes_sent: u32,
+    /// Bytes received from active probes.
+    probe_bytes_received: u32,
+    /// Bytes sent per `tracking.throughput_bucket`.
+    sent_series: ThroughputSeries,
+    /// Bytes received per `tracking.throughput_bucket`.
+    received_series: ThroughputSeries,
+    /// Bytes of traffic other than TCP and UDP since the last report.
+    other_bytes: u64,
+    /// IP protocol numbers of that traffic.
+    other_protocols: BTreeSet<u8>,
+    /// Bytes of IP fragments since the last report.
+    fragmented_bytes: u64,
+    /// Completion times in seconds of the short TCP flows that ended since
+    /// the last report.
+    flow_times: Vec<f64>,
+    /// TCP connections that ended since the last report, only with
+    /// `client.flow_records`.
+    finished_flows: Vec<FinishedFlow>,
+    /// Receive windows advertised by the remote since the last report.
+    window_stats: WindowStats,
+    /// Packets seen since the last report.
+    packets: u32,
+    /// Packets with the 802.11 retry flag since the last report.
+    retries: u32,
+    /// ACK groups the TCP trackers skipped since the last
+    /// `take_skipped_groups`.
+    skipped_groups: u64,
+    /// Time of the last report for this link.
+    last_report: Instant,
+    /// Timestamp of the last packet on the link, probes included.
+    last_seen: Option<Timestamp>,
+    /// Keep a summary of each completed burst, for the event tap.
+    summarize_bursts: bool,
+    /// Bursts completed since the last `take_burst_summaries`.
+    burst_summaries: Vec<BurstSummary>,
+    /// ML features since the last `take_features`, None unless
+    /// `features.interval` is set.
+    features: Option<FeatureWindow>,
 }
 
 impl StreamManager {
@@ -34,75 +110,357 @@ impl StreamManager {
     pub fn default() -> Self {
         StreamManager {
             streams: HashMap::new(),
+            deadlines: DeadlineWheel::new(crate::CONFIG.tracking.deadline_tick),
+            stream_timeout: crate::CONFIG.tracking.tcp_stream_timeout,
+            flush_interval: crate::CONFIG.tracking.cleanup_interval,
             sent: PacketRegistry::new(),
             received: PacketRegistry::new(),
-            tcp_thput: 0.0,
-            last_iperf: None,
+            probes: ProbeHistory::default(),
+            udp_jitter: None,
+            udp_loss: None,
+            capacity: None,
+            ping_rtts: Vec::new(),
+            pings_lost: 0,
+            baseline_rtt: BaselineRtt::new(),
+            hops: HopTracker::default(),
+            train_samples: Vec::new(),
             bytes_sent: 0,
             bytes_received: 0,
+            probe_bytes_sent: 0,
+            probe_bytes_received: 0,
+            sent_series: ThroughputSeries::new(crate::CONFIG.tracking.throughput_bucket),
+            received_series: ThroughputSeries::new(crate::CONFIG.tracking.throughput_bucket),
+            other_bytes: 0,
+            other_protocols: BTreeSet::new(),
+            fragmented_bytes: 0,
+            flow_times: Vec::new(),
+            finished_flows: Vec::new(),
+            window_stats: WindowStats::default(),
+            packets: 0,
+            retries: 0,
+            skipped_groups: 0,
+            last_report: Instant::now(),
+            last_seen: None,
+            summarize_bursts: false,
+            burst_summaries: Vec::new(),
+            features: (!crate::CONFIG.features.interval.is_zero())
+                .then(|| FeatureWindow::new(Timestamp::now())),
         }
     }
 
-    /// Record a new iperf throughput result (in bits per second).
-    ///
-    /// Updates `tcp_thput` and stamps the current instant.
-    pub fn record_iperf_result(&mut self, bps: f64, _stream: Option<&crate::IperfStream>) {
-        // Check if in out is very different
-        self.last_iperf = Some(Instant::now());
-        self.tcp_thput = bps;
+    /// Record a bandwidth in bytes/sec measured by an active probe,
+    /// `Outgoing` if this host sent the probe traffic. False if the result
+    /// was already recorded.
+    pub fn record_probe(
+        &mut self,
+        source: ProbeSource,
+        direction: Direction,
+        throughput: f64,
+        probe_id: Option<u64>,
+    ) -> bool {
+        self.probes.record(ProbeMeasurement {
+            source,
+            direction,
+            throughput,
+            probe_id,
+            at: Instant::now(),
+        })
     }
 
-    /// Return the most recent TCP throughput if the last measurement is older
-    /// If iperf is not used, this will always return 0.0.
-    /// than the configured measurement window; otherwise return 0.0.
-    pub fn tcp_thput(&self) -> f64 {
-        if let Some(last_iperf) = self.last_iperf {
-            if last_iperf.elapsed() > crate::CONFIG.client.measurement_window {
-                return self.tcp_thput;
-            }
+    /// Record jitter (ms) and loss (%) from a UDP iperf test.
+    pub fn record_iperf_udp_result(&mut self, jitter_ms: f64, lost_percent: f64) {
+        self.udp_jitter = Some(jitter_ms);
+        self.udp_loss = Some(lost_percent);
+    }
+
+    /// Record the samples from a packet train probe the remote sent, and
+    /// their median as a probe result.
+    pub fn record_train_result(&mut self, samples: &[f64], probe_id: Option<u64>) {
+        self.train_samples.extend_from_slice(samples);
+        if let Some(abw) = median(&mut samples.to_vec()) {
+            self.record_probe(ProbeSource::Builtin, Direction::Incoming, abw, probe_id);
+        }
+    }
+
+    /// Record the path capacity in bytes/sec measured with a ping train.
+    pub fn record_capacity(&mut self, capacity: f64) {
+        self.capacity = Some(capacity);
+    }
+
+    /// Path capacity in bytes/sec, if a ping train measured it.
+    pub fn capacity(&self) -> Option<f64> {
+        self.capacity
+    }
+
+    /// What the link keeps across restarts.
+    pub fn baseline(&self) -> LinkBaseline {
+        LinkBaseline {
+            min_rtt: self.baseline_rtt.get(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Picks up a baseline saved by an earlier run. A capacity measured
+    /// since takes precedence.
+    pub fn restore_baseline(&mut self, baseline: LinkBaseline) {
+        if let Some(rtt) = baseline.min_rtt {
+            self.baseline_rtt.seed(rtt);
+        }
+        self.capacity = self.capacity.or(baseline.capacity);
+    }
+
+    /// Record the TTL of a packet from a remote host. Returns the change if
+    /// the host is now further away or closer.
+    pub fn record_ttl(&mut self, packet: &ParsedPacket) -> Option<PathChange> {
+        if packet.direction != Direction::Incoming || packet.intercepted {
+            return None;
+        }
+        self.hops.record(packet.src_ip, packet.ttl, packet.timestamp)
+    }
+
+    /// Hops from `remote`, None if no packet from it was seen lately.
+    pub fn hops(&self, remote: IpAddr) -> Option<u8> {
+        self.hops.hops(remote)
+    }
+
+    /// Take the number of path changes since the last call.
+    pub fn take_path_changes(&mut self) -> u32 {
+        self.hops.take_changes()
+    }
+
+    /// Record a ping to the remote, with its RTT or None if it was lost.
+    pub fn record_ping(&mut self, rtt: Option<std::time::Duration>) {
+        match rtt {
+            Some(rtt) => self.ping_rtts.push(rtt.as_secs_f64()),
+            None => self.pings_lost += 1,
+        }
+    }
+
+    /// Take the mean ping RTT in seconds and the % of pings lost since the
+    /// last call, None if no ping was sent.
+    pub fn take_ping_result(&mut self) -> (Option<f64>, Option<f64>) {
+        let rtts = std::mem::take(&mut self.ping_rtts);
+        let lost = std::mem::take(&mut self.pings_lost);
+        let sent = rtts.len() as u32 + lost;
+        if sent == 0 {
+            return (None, None);
         }
-        return 0.0;
+        let rtt = (!rtts.is_empty()).then(|| rtts.iter().sum::<f64>() / rtts.len() as f64);
+        (rtt, Some(lost as f64 * 100.0 / sent as f64))
+    }
+
+    /// Take the median packet train rate since the last call, if any.
+    pub fn take_train_abw(&mut self) -> Option<f64> {
+        median(&mut std::mem::take(&mut self.train_samples))
+    }
+
+    /// Update the RTT baseline with the data sent since the last report and
+    /// compare the RTT of its fastest bursts with it.
+    pub fn update_bufferbloat(&mut self, sent: &PacketRegistry) -> Option<Bufferbloat> {
+        self.baseline_rtt.update(sent.min_rtt(), &sent.burst_rtts)
+    }
+
+    /// Take the jitter and loss from the last UDP iperf test, if any.
+    pub fn take_udp_result(&mut self) -> (Option<f64>, Option<f64>) {
+        (self.udp_jitter.take(), self.udp_loss.take())
+    }
+
+    /// The freshest probe result in `direction`, if one is less than
+    /// `VALID_WINDOWS` measurement windows old.
+    pub fn probe_bw(&self, direction: Direction) -> Option<&ProbeMeasurement> {
+        let max_age = crate::CONFIG.client.measurement_window * VALID_WINDOWS;
+        self.probes.freshest(direction, max_age, Instant::now())
     }
 
     /// Process a parsed packet: updates byte counters, registers bursts,
     /// and appends them to the appropriate registry.
     pub fn record_packet(&mut self, packet: &ParsedPacket) {
+        self.packets += 1;
+        self.see(packet.timestamp);
+        if packet.retry {
+            self.retries += 1;
+        }
+        if let Some(features) = &mut self.features {
+            features.record_packet(packet);
+        }
         match packet.direction {
             crate::Direction::Incoming => {
                 self.bytes_received += packet.total_length as u32;
+                self.received_series
+                    .record(packet.timestamp, packet.total_length as u64);
             }
             crate::Direction::Outgoing => {
                 self.bytes_sent += packet.total_length as u32;
+                self.sent_series
+                    .record(packet.timestamp, packet.total_length as u64);
+            }
+        }
+        if let Some(fragment) = packet.fragment {
+            self.fragmented_bytes += packet.total_length as u64;
+            // Later fragments carry no transport header for the trackers
+            if !fragment.is_first() {
+                return;
+            }
+        }
+        match packet.transport {
+            crate::TransportPacket::TCP { .. } | crate::TransportPacket::UDP { .. } => {}
+            _ => {
+                self.other_bytes += packet.total_length as u64;
+                self.other_protocols.insert(packet.transport.get_ip_proto().0);
             }
         }
 
         let stream_id = StreamKey::from_packet(packet);
+        // Residual bursts are flushed at most a cleanup interval after they start
+        let flush_at = packet.timestamp + self.flush_interval;
+        self.deadlines.schedule_min(stream_id, flush_at);
         // Get or create a tracker for this stream and register the packet.
         // The register_packet method will return a burst if one is completed.
-        let (burst, direction) = match self
-            .streams
-            .entry(stream_id)
-            .or_insert_with(|| {
-                Tracker::<TrackerState>::new(packet.timestamp, packet.transport.get_ip_proto())
-            })
-            .register_packet(packet)
-        {
+        let tracker = self.streams.entry(stream_id).or_insert_with(|| {
+            Tracker::<TrackerState>::new(packet.timestamp, packet.transport.get_ip_proto())
+        });
+        let completed = tracker.register_packet(packet);
+        if let TrackerState::Tcp(tcp) = &mut tracker.state {
+            tcp.record_window(packet, &mut self.window_stats);
+            self.skipped_groups += tcp.take_skipped_groups();
+            if let Some(flow) = tcp.take_completed_flow() {
+                if flow.bytes <= crate::CONFIG.client.short_flow_bytes {
+                    self.flow_times.push(flow.duration.as_secs_f64());
+                }
+                if crate::CONFIG.client.flow_records
+                    && self.finished_flows.len() < MAX_FLOW_RECORDS
+                {
+                    self.finished_flows.push(FinishedFlow {
+                        key: stream_id,
+                        hosts: IpPair::from_packet(packet),
+                        completion: flow,
+                        process: None,
+                    });
+                }
+            }
+        }
+        let (burst, direction) = match completed {
             Some((burst, direction)) => (burst, direction),
             None => return,
         };
 
-        // Match the direction of the packet and append the burst to the
-        // appropriate registry.
+        self.absorb(burst, direction, packet.transport.get_ip_proto().0);
+    }
+
+    /// Append a completed burst to the registry for its direction.
+    fn absorb(&mut self, burst: Burst, direction: Direction, protocol: u8) {
+        if self.summarize_bursts && !burst.is_empty() {
+            self.burst_summaries
+                .push(BurstSummary::new(&burst, direction, protocol));
+        }
+        if let Some(features) = &mut self.features {
+            features.record_burst(&burst);
+        }
         match direction {
+            Direction::Incoming => self.received.extend(burst),
+            Direction::Outgoing => self.sent.extend(burst),
+        }
+    }
+
+    /// Start or stop keeping burst summaries.
+    pub fn set_summarize_bursts(&mut self, summarize: bool) {
+        self.summarize_bursts = summarize;
+    }
+
+    /// Summaries of the bursts completed since the last call.
+    pub fn take_burst_summaries(&mut self) -> Vec<BurstSummary> {
+        std::mem::take(&mut self.burst_summaries)
+    }
+
+    /// Count a packet generated by an active probe, without feeding it to
+    /// the trackers.
+    pub fn record_probe_packet(&mut self, packet: &ParsedPacket) {
+        self.see(packet.timestamp);
+        match packet.direction {
             crate::Direction::Incoming => {
-                self.received.extend(burst);
+                self.probe_bytes_received += packet.total_length as u32;
             }
             crate::Direction::Outgoing => {
-                self.sent.extend(burst);
+                self.probe_bytes_sent += packet.total_length as u32;
             }
         }
     }
 
+    /// reset the probe sent bytes counter and return the value
+    pub fn take_probe_sent(&mut self) -> u32 {
+        std::mem::take(&mut self.probe_bytes_sent)
+    }
+
+    /// reset the probe received bytes counter and return the value
+    pub fn take_probe_received(&mut self) -> u32 {
+        std::mem::take(&mut self.probe_bytes_received)
+    }
+
+    /// Bytes of non-TCP/UDP traffic and the protocols seen since the last
+    /// call, resetting both.
+    pub fn take_other_traffic(&mut self) -> (u64, Vec<u8>) {
+        let protocols = std::mem::take(&mut self.other_protocols);
+        (std::mem::take(&mut self.other_bytes), protocols.into_iter().collect())
+    }
+
+    /// Bytes of IP fragments since the last call, resetting the count.
+    pub fn take_fragmented_bytes(&mut self) -> u64 {
+        std::mem::take(&mut self.fragmented_bytes)
+    }
+
+    /// ACK groups the TCP trackers skipped since the last call.
+    pub fn take_skipped_groups(&mut self) -> u64 {
+        std::mem::take(&mut self.skipped_groups)
+    }
+
+    /// Share of packets with the retry flag since the last call, None if
+    /// no packets were seen.
+    pub fn take_retry_rate(&mut self) -> Option<f64> {
+        let packets = std::mem::take(&mut self.packets);
+        let retries = std::mem::take(&mut self.retries);
+        if packets == 0 {
+            return None;
+        }
+        Some(retries as f64 / packets as f64)
+    }
+
+    /// Packets tracked since the last report, probes excluded.
+    pub fn packets(&self) -> u32 {
+        self.packets
+    }
+
+    /// True if nothing has been seen on the link since the last report,
+    /// probe traffic included. Check before the counters are taken.
+    pub fn is_idle(&self) -> bool {
+        self.packets == 0 && self.probe_bytes_sent == 0 && self.probe_bytes_received == 0
+    }
+
+    fn see(&mut self, timestamp: Timestamp) {
+        self.last_seen = self.last_seen.max(Some(timestamp));
+    }
+
+    /// Timestamp of the last packet seen on the link.
+    pub fn last_seen(&self) -> Option<Timestamp> {
+        self.last_seen
+    }
+
+    /// Number of transport streams being tracked.
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Time since the last report.
+    pub fn since_report(&self) -> std::time::Duration {
+        self.last_report.elapsed()
+    }
+
+    /// Restart the report timer and return the time since the last report.
+    pub fn take_report_elapsed(&mut self) -> std::time::Duration {
+        let elapsed = self.last_report.elapsed();
+        self.last_report = Instant::now();
+        elapsed
+    }
+
     /// reset the sent bytes counter and return the value
     pub fn take_sent(&mut self) -> u32 {
         std::mem::take(&mut self.bytes_sent)
@@ -113,30 +471,77 @@ impl StreamManager {
         std::mem::take(&mut self.bytes_received)
     }
 
-    /// Perform periodic actions:
-    /// - Flush any residual bursts from all trackers.
-    /// - Prune streams that have been idle longer than the TCP_STREAM_TIMEOUT.
+    /// Take the throughput percentiles in and out over the buckets completed
+    /// by `now`.
+    pub fn take_thp_percentiles(&mut self, now: Timestamp) -> (Option<Percentiles>, Option<Percentiles>) {
+        (
+            self.received_series.take_percentiles(now),
+            self.sent_series.take_percentiles(now),
+        )
+    }
+
+    /// Take the completion times of the short flows that ended since the
+    /// last call.
+    pub fn take_fct_percentiles(&mut self) -> Option<FctPercentiles> {
+        FctPercentiles::new(std::mem::take(&mut self.flow_times))
+    }
+
+    /// Take the TCP connections that ended since the last call.
+    pub fn take_finished_flows(&mut self) -> Vec<FinishedFlow> {
+        std::mem::take(&mut self.finished_flows)
+    }
+
+    /// Take the receive window statistics since the last call.
+    pub fn take_window_stats(&mut self) -> Option<WindowSummary> {
+        self.window_stats.take()
+    }
+
+    /// Take the ML features of this link up to `now`, None if they are
+    /// disabled or the link was idle.
+    pub fn take_features(&mut self, link: IpPair, now: Timestamp) -> Option<FeatureVector> {
+        self.features.as_mut()?.take(link, now)
+    }
+
+    /// Perform periodic actions on the streams whose deadline has passed:
+    /// - Flush any residual bursts.
+    /// - Prune streams that have been idle longer than the TCP_STREAM_TIMEOUT,
+    ///   otherwise check them again once they could have become idle.
     pub fn periodic(&mut self) {
-        for stream in self.streams.values_mut() {
+        self.periodic_at(Timestamp::now());
+    }
+
+    fn periodic_at(&mut self, now: Timestamp) {
+        self.hops.prune(now);
+        for key in self.deadlines.expire(now) {
+            let Some(stream) = self.streams.get_mut(&key) else {
+                continue;
+            };
             // Take residual bursts.
             let (sent, received) = match stream.state {
                 TrackerState::Tcp(ref mut tracker) => tracker.take_bursts(),
                 TrackerState::Udp(ref mut tracker) => tracker.take_bursts(),
                 TrackerState::Other(ref mut tracker) => tracker.take_bursts(),
             };
-            self.sent.extend(sent);
-            self.received.extend(received);
-        }
-        self.streams.retain(|_, t| {
+            let protocol = stream.protocol.0;
+            let last_registered = stream.last_registered;
+            self.absorb(sent, Direction::Outgoing, protocol);
+            self.absorb(received, Direction::Incoming, protocol);
+
             // Keep only streams active within the timeout
-            t.last_registered.elapsed().unwrap() < crate::Settings::TCP_STREAM_TIMEOUT
-        });
+            let idle_until = last_registered + self.stream_timeout;
+            if idle_until <= now {
+                self.streams.remove(&key);
+            } else {
+                self.deadlines.schedule(key, idle_until);
+            }
+        }
     }
 
     pub fn take_streams(&mut self, keys: Vec<StreamKey>) -> Vec<Tracker<TrackerState>> {
         let mut taken = Vec::new();
 
         for key in keys {
+            self.deadlines.remove(&key);
             if let Some(tracker) = self.streams.remove(&key) {
                 taken.push(tracker);
             }
@@ -152,6 +557,19 @@ impl StreamManager {
     }
 }
 
+fn median(samples: &mut [f64]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.total_cmp(b));
+    let n = samples.len();
+    Some(if n % 2 == 1 {
+        samples[n / 2]
+    } else {
+        (samples[n / 2 - 1] + samples[n / 2]) / 2.0
+    })
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -163,20 +581,24 @@ mod tests {
         let mut mgr = StreamManager::default();
         assert_eq!(mgr.take_sent(), 0, "bytes_sent should start at 0");
         assert_eq!(mgr.take_received(), 0, "bytes_received should start at 0");
-        assert!(mgr.last_iperf.is_none(), "no iperf timestamp initially");
-        assert_eq!(mgr.tcp_thput(), 0.0, "throughput should be zero with no measurements");
+        assert!(mgr.probe_bw(Direction::Outgoing).is_none(), "no probe results initially");
         assert!(mgr.sent.pgm_estimator.dps.is_empty(), "sent registry should be empty");
         assert!(mgr.received.pgm_estimator.dps.is_empty(), "received registry should be empty");
     }
 
-    /// Ensure `record_iperf_result` updates `last_iperf` and `tcp_thput`,
+    /// A fresh probe result is reported, in its own direction only.
     #[test]
-    fn test_record_iperf_and_thput_within_window() {
+    fn test_record_probe_within_window() {
         let mut mgr = StreamManager::default();
-        mgr.record_iperf_result(42.5, None);
-        assert!(mgr.last_iperf.is_some(), "last_iperf should be set");
-        // Immediately after recording, elapsed < window → throughput must be 0.0
-        assert_eq!(mgr.tcp_thput(), 0.0, "within window, reported throughput is 0.0");
+        assert!(mgr.record_probe(ProbeSource::Iperf, Direction::Outgoing, 42.5, None));
+        let probe = mgr.probe_bw(Direction::Outgoing).expect("fresh result is valid");
+        assert_eq!((probe.source, probe.throughput), (ProbeSource::Iperf, 42.5));
+        assert!(mgr.probe_bw(Direction::Incoming).is_none(), "this host sent the test data");
+
+        mgr.record_train_result(&[10.0, 30.0, 20.0], Some(3));
+        let probe = mgr.probe_bw(Direction::Incoming).unwrap();
+        assert_eq!((probe.source, probe.throughput), (ProbeSource::Builtin, 20.0));
+        assert_eq!(mgr.take_train_abw(), Some(20.0));
     }
 
     /// Verify that `take_sent` and `take_received` reset counters to zero.
@@ -191,4 +613,174 @@ mod tests {
         assert_eq!(mgr.take_received(), 200, "should return previous received bytes");
         assert_eq!(mgr.take_received(), 0, "counter resets to 0 after take_received");
     }
-}
\ No newline at end of file
+
+    /// UDP jitter and loss are reported once and then cleared.
+    #[test]
+    fn test_take_udp_result() {
+        let mut mgr = StreamManager::default();
+        assert_eq!(mgr.take_udp_result(), (None, None));
+        mgr.record_iperf_udp_result(0.25, 10.0);
+        assert_eq!(mgr.take_udp_result(), (Some(0.25), Some(10.0)));
+        assert_eq!(mgr.take_udp_result(), (None, None));
+    }
+
+    /// Ping RTTs are averaged over the replies, loss over all pings sent.
+    #[test]
+    fn test_take_ping_result() {
+        use std::time::Duration;
+
+        let mut mgr = StreamManager::default();
+        assert_eq!(mgr.take_ping_result(), (None, None));
+        mgr.record_ping(Some(Duration::from_millis(10)));
+        mgr.record_ping(None);
+        mgr.record_ping(Some(Duration::from_millis(30)));
+        mgr.record_ping(None);
+        let (rtt, loss) = mgr.take_ping_result();
+        assert!((rtt.unwrap() - 0.02).abs() < 1e-9);
+        assert_eq!(loss, Some(50.0));
+        mgr.record_ping(None);
+        assert_eq!(mgr.take_ping_result(), (None, Some(100.0)));
+        assert_eq!(mgr.take_ping_result(), (None, None));
+    }
+
+    /// Only streams whose deadline passed are flushed, idle ones are dropped.
+    #[test]
+    fn test_periodic_visits_due_streams() {
+        use crate::Direction;
+        use crate::CONFIG;
+
+        let t0 = Timestamp::from_millis(1_000_000);
+        let packet = |src_port: u16, timestamp: Timestamp| {
+            ParsedPacket::test_udp(src_port, 5000, 100, timestamp, Direction::Outgoing)
+        };
+
+        let tracking = &CONFIG.tracking;
+        let mut mgr = StreamManager::default();
+        mgr.record_packet(&packet(4000, t0));
+        mgr.record_packet(&packet(4001, t0 + tracking.cleanup_interval));
+        assert_eq!(mgr.deadlines.len(), 2);
+
+        // Only the first stream is due a flush
+        mgr.periodic_at(t0 + tracking.cleanup_interval);
+        assert_eq!(mgr.streams.len(), 2);
+        assert_eq!(mgr.deadlines.len(), 2);
+
+        mgr.periodic_at(t0 + tracking.tcp_stream_timeout);
+        assert_eq!(mgr.streams.len(), 1);
+
+        mgr.periodic_at(t0 + tracking.cleanup_interval + tracking.tcp_stream_timeout);
+        assert!(mgr.streams.is_empty());
+        assert!(mgr.deadlines.is_empty());
+    }
+
+    /// ICMP and other protocols are counted apart from TCP and UDP.
+    #[test]
+    fn test_other_traffic() {
+        use crate::{Direction, TransportPacket};
+
+        let timestamp = Timestamp::from_millis(1_000_000);
+        let packet = |transport: TransportPacket| ParsedPacket {
+            total_length: 128,
+            ..ParsedPacket::test_packet(transport, timestamp, Direction::Outgoing)
+        };
+
+        let mut mgr = StreamManager::default();
+        mgr.record_packet(&packet(TransportPacket::ICMP));
+        mgr.record_packet(&packet(TransportPacket::OTHER { protocol: 47 }));
+        mgr.record_packet(&packet(TransportPacket::UDP {
+            src_port: 4000,
+            dst_port: 5000,
+            payload_len: 100,
+        }));
+        assert_eq!(mgr.take_other_traffic(), (256, vec![1, 47]));
+        assert_eq!(mgr.take_other_traffic(), (0, vec![]));
+    }
+
+    /// Fragments are counted, later ones are kept out of the trackers.
+    #[test]
+    fn test_fragmented_traffic() {
+        use crate::{Direction, Fragment, TransportPacket};
+
+        let timestamp = Timestamp::from_millis(1_000_000);
+        let packet = |transport: TransportPacket, offset: u16| ParsedPacket {
+            total_length: 1500,
+            fragment: Some(Fragment {
+                id: 7,
+                offset,
+                more: offset == 0,
+                ports: Some((4000, 5000)),
+            }),
+            ..ParsedPacket::test_packet(transport, timestamp, Direction::Outgoing)
+        };
+
+        let mut mgr = StreamManager::default();
+        let udp = TransportPacket::UDP {
+            src_port: 4000,
+            dst_port: 5000,
+            payload_len: 1472,
+        };
+        mgr.record_packet(&packet(udp, 0));
+        mgr.record_packet(&packet(TransportPacket::OTHER { protocol: 17 }, 1480));
+        assert_eq!(mgr.take_fragmented_bytes(), 3000);
+        assert_eq!(mgr.take_other_traffic(), (0, vec![]));
+        assert_eq!(mgr.streams.len(), 1);
+        assert_eq!(mgr.take_sent(), 3000);
+    }
+
+    /// Idle until a packet shows up, and again once the counters are taken.
+    #[test]
+    fn test_is_idle() {
+        use crate::{Direction, TransportPacket};
+
+        let timestamp = Timestamp::from_millis(1_000_000);
+        let packet =
+            ParsedPacket::test_packet(TransportPacket::ICMP, timestamp, Direction::Outgoing);
+
+        let mut mgr = StreamManager::default();
+        assert!(mgr.is_idle());
+        mgr.record_probe_packet(&packet);
+        assert!(!mgr.is_idle());
+        mgr.take_probe_sent();
+        assert!(mgr.is_idle());
+
+        mgr.record_packet(&packet);
+        assert!(!mgr.is_idle());
+        mgr.take_retry_rate();
+        assert!(mgr.is_idle());
+    }
+
+    /// Retry rate is reset on every take.
+    #[test]
+    fn test_take_retry_rate() {
+        let mut mgr = StreamManager::default();
+        assert_eq!(mgr.take_retry_rate(), None);
+        mgr.packets = 4;
+        mgr.retries = 1;
+        assert_eq!(mgr.take_retry_rate(), Some(0.25));
+        assert_eq!(mgr.take_retry_rate(), None);
+    }
+
+    /// A captured transfer acked every two segments, 20 ms after sending.
+    #[test]
+    fn test_tcp_transfer_fixture() {
+        use crate::listener::packet::fixtures;
+
+        let packets = fixtures::load(fixtures::TCP_TRANSFER);
+        let mut mgr = StreamManager::default();
+        for packet in fixtures::parse(&packets) {
+            mgr.record_packet(&packet);
+        }
+        let last = Timestamp::from_timeval(packets.last().unwrap().header.ts, 0);
+        mgr.periodic_at(last + crate::CONFIG.tracking.cleanup_interval);
+
+        // The SYN and the ten segments
+        assert_eq!(mgr.sent.rtts.len(), 11);
+        assert!(mgr.sent.rtts.iter().all(|(rtt, _)| (20_000..=21_000).contains(rtt)));
+        assert_eq!(mgr.sent.min_rtt(), Some(20_000.0));
+        // One burst, a point per ack after the first
+        assert_eq!(mgr.sent.burst_rtts.len(), 1);
+        assert_eq!(mgr.sent.pgm_estimator.dps.len(), 5);
+        assert_eq!(mgr.sent.gap_packets(), 10);
+        assert!(mgr.received.rtts.is_empty());
+    }
+}
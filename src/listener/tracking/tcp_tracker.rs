@@ -1,9 +1,11 @@
 use std::collections::BTreeMap;
-use std::time::SystemTime;
-
+use smallvec::SmallVec;
 use tokio::time::Duration;
 
-use crate::{Direction, PacketType, ParsedPacket, TransportPacket};
+use crate::{Direction, PacketType, ParsedPacket, Timestamp, TransportPacket, CONFIG};
+
+use super::flow_time::{FlowCompletion, FlowTimer};
+use super::rwnd::{ReceiveWindow, WindowStats};
 
 /// Compare two TCP sequence numbers, taking into account wrap-around.
 ///
@@ -15,6 +17,11 @@ fn seq_less_equal(a: u32, b: u32) -> bool {
     seq_cmp(a, b) <= 0
 }
 
+/// A segment sent with less than this share of the stream's peak bytes in
+/// flight is counted as application limited. Low enough that the window
+/// halving after a loss is not mistaken for it.
+const APP_LIMITED_SHARE: f64 = 0.25;
+
 /// A burst of TCP packets that have been acknowledged together.
 #[derive(Debug)]
 pub struct TcpBurst {
@@ -50,6 +57,15 @@ impl Burst {
         }
     }
 
+    /// Number of packets in the burst.
+    pub fn len(&self) -> usize {
+        match self {
+            Burst::Tcp(burst) => burst.iter().count(),
+            Burst::Udp(packets) => packets.len(),
+            Burst::Other(packets) => packets.len(),
+        }
+    }
+
     /// Returns `true` if the burst contains no packets.
     pub fn is_empty(&self) -> bool {
         match self {
@@ -60,28 +76,14 @@ impl Burst {
     }
 
     /// Helper to compute duration between first and last packet times.
-    fn get_time_duration(packets: &Vec<PacketType>) -> Option<Duration> {
-        if packets.len() > 1 {
-            let mut first = SystemTime::UNIX_EPOCH;
-            let mut last = SystemTime::UNIX_EPOCH;
-            for packet in packets {
-                if packet.sent_time < first {
-                    first = packet.sent_time;
-                }
-                if packet.sent_time > last {
-                    last = packet.sent_time;
-                }
-            }
-            match last.duration_since(first) {
-                Ok(d) => return Some(d),
-                Err(_) => return None,
-            }
-        }
-        None
+    fn get_time_duration(packets: &[PacketType]) -> Option<Duration> {
+        let first = packets.iter().map(|p| p.sent_time).min()?;
+        let last = packets.iter().map(|p| p.sent_time).max()?;
+        last.checked_duration_since(first).filter(|d| !d.is_zero())
     }
 
     /// Compute throughput in bytes per second over the burst.
-    fn get_throughput(packets: &Vec<PacketType>) -> f64 {
+    fn get_throughput(packets: &[PacketType]) -> f64 {
         if let Some(d) = Self::get_time_duration(packets) {
             packets.iter().map(|p| p.total_length as f64).sum::<f64>() / d.as_secs_f64()
         } else {
@@ -127,18 +129,12 @@ impl TcpBurst {
         self.packets.iter().map(|acked| acked.total_length).sum()
     }
 
-    /// Duration from first packet sent to final ACK.
+    /// Duration from first packet sent to final ACK, None if the burst is
+    /// empty or the ACK is not after the first packet.
     pub fn time_duration(&self) -> Option<Duration> {
-        if let Some(first) = self.packets.first() {
-            let first = first.acked_packets.first().unwrap().sent_time;
-            let last = self.packets.last().unwrap().ack_time;
-            match last.duration_since(first) {
-                Ok(d) => Some(d),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+        let first = self.packets.first()?.acked_packets.first()?.sent_time;
+        let last = self.packets.last()?.ack_time;
+        last.checked_duration_since(first).filter(|d| !d.is_zero())
     }
 
     /// Throughput of this TCP burst (bytes per second).
@@ -157,34 +153,43 @@ impl From<TcpBurst> for Burst {
     }
 }
 
+/// Packets covered by one ACK. Usually one or two with delayed ACKs, so
+/// these are kept inline instead of allocating per ACK.
+pub type AckedPackets = SmallVec<[PacketType; 2]>;
+
 /// Represents a set of packets acknowledged together, with timing metadata.
 #[derive(Debug)]
 pub struct Acked {
-    acked_packets: Vec<PacketType>,
+    acked_packets: AckedPackets,
     /// Time when the ACK was received.
-    pub ack_time: SystemTime,
-    first_sent_time: Option<SystemTime>,
-    last_sent_time: SystemTime,
+    pub ack_time: Timestamp,
+    first_sent_time: Option<Timestamp>,
+    last_sent_time: Timestamp,
     /// Total length of all acked packets.
     pub total_length: u32,
 }
 
 impl Acked {
-    /// Create a new `Acked` group from raw packet list and timing.
+    /// Create a new `Acked` group from raw packet list and timing. None if
+    /// there are no packets, or the ACK was captured before the last of
+    /// them, which only a capture out of order gives.
     fn from_acked(
-        acked_packets: Vec<PacketType>,
-        ack_time: SystemTime,
-        first_sent_time: Option<SystemTime>,
-    ) -> Self {
-        let last_sent_time = acked_packets.last().unwrap().sent_time;
+        acked_packets: AckedPackets,
+        ack_time: Timestamp,
+        first_sent_time: Option<Timestamp>,
+    ) -> Option<Self> {
+        let last_sent_time = acked_packets.last()?.sent_time;
+        if ack_time < last_sent_time {
+            return None;
+        }
         let total_length = acked_packets.iter().map(|p| p.total_length as u32).sum();
-        Acked {
+        Some(Acked {
             acked_packets,
             ack_time,
             first_sent_time,
             last_sent_time,
             total_length,
-        }
+        })
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &PacketType> {
@@ -194,10 +199,10 @@ impl Acked {
     /// Compute gap-in, gap-out, and payload length since last ACK.
     ///
     /// Returns `(gap_in_secs, gap_out_secs, total_payload_bytes)` if possible.
-    pub fn get_gin_gout_len(&self, last_ack: SystemTime) -> Option<(f64, f64, u32)> {
+    pub fn get_gin_gout_len(&self, last_ack: Timestamp) -> Option<(f64, f64, u32)> {
         if let Some(first_sent_time) = self.first_sent_time {
-            let gin = self.last_sent_time.duration_since(first_sent_time).ok()?;
-            let gout = self.ack_time.duration_since(last_ack).ok()?;
+            let gin = self.last_sent_time.checked_duration_since(first_sent_time)?;
+            let gout = self.ack_time.checked_duration_since(last_ack)?;
             let total_length = self
                 .acked_packets
                 .iter()
@@ -218,21 +223,41 @@ impl Acked {
 #[derive(Debug)]
 struct TcpStream {
     packets: BTreeMap<u32, PacketType>,
-    last_ack: Option<SystemTime>,
-    last_sent: Option<SystemTime>,
-    last_registered: Option<SystemTime>,
+    last_ack: Option<Timestamp>,
+    last_sent: Option<Timestamp>,
+    last_registered: Option<Timestamp>,
     cur_burst: TcpBurst,
     max_rtt: Duration,
+    /// Payload bytes sent and not yet acknowledged.
+    in_flight: u32,
+    /// Most bytes in flight, halved with each completed burst.
+    peak_in_flight: u32,
+    /// Acknowledged groups at which a burst is cut.
+    max_packets: usize,
+    /// ACK groups skipped by `Acked::from_acked` since they were last taken.
+    skipped: u64,
 }
 
 impl TcpStream {
+    fn new(max_packets: usize) -> Self {
+        TcpStream {
+            packets: BTreeMap::new(),
+            last_ack: None,
+            last_sent: None,
+            last_registered: None,
+            cur_burst: TcpBurst::default(),
+            max_rtt: Duration::from_secs(10),
+            in_flight: 0,
+            peak_in_flight: 0,
+            max_packets,
+            skipped: 0,
+        }
+    }
+
     /// Update and return inter-packet gap since last sent packet.
-    fn get_gap_last_sent(&mut self, new: SystemTime) -> Option<Duration> {
+    fn get_gap_last_sent(&mut self, new: Timestamp) -> Option<Duration> {
         let gap: Option<Duration> = match self.last_sent {
-            Some(last_sent) => match new.duration_since(last_sent) {
-                Ok(d) => Some(d),
-                Err(_) => None,
-            },
+            Some(last_sent) => new.checked_duration_since(last_sent),
             None => None,
         };
         self.last_sent = Some(new);
@@ -240,12 +265,9 @@ impl TcpStream {
     }
 
     /// Update and return inter-ACK gap since last ACK.
-    fn get_gap_last_ack(&mut self, new: SystemTime) -> Option<Duration> {
+    fn get_gap_last_ack(&mut self, new: Timestamp) -> Option<Duration> {
         let gap: Option<Duration> = match self.last_ack {
-            Some(last_ack) => match new.duration_since(last_ack) {
-                Ok(d) => Some(d),
-                Err(_) => None,
-            },
+            Some(last_ack) => new.checked_duration_since(last_ack),
             None => None,
         };
         self.last_ack = Some(new);
@@ -254,7 +276,7 @@ impl TcpStream {
 
     /// Register a packet into the TCP stream, possibly producing a completed burst.
     fn register_packet(&mut self, packet: &ParsedPacket) -> Option<TcpBurst> {
-        let mut acked_packets = Vec::new();
+        let mut acked_packets = AckedPackets::new();
         let mut ret = None;
 
         if let TransportPacket::TCP {
@@ -268,54 +290,57 @@ impl TcpStream {
             let mut pkt = PacketType::from_packet(packet);
             if self.cur_burst.packets.len() > 0 {
                 if let Some(last_registered) = self.last_registered {
-                    if let Ok(d) = packet.timestamp.duration_since(last_registered) {
-                        if d > self.max_rtt || self.cur_burst.packets.len() > 100 {
-                            // Indiana Jones moment (Replace self.cur_burst with default)
-                            ret = Some(std::mem::take(&mut self.cur_burst));
-                            self.last_registered = None;
-                            self.max_rtt = self.max_rtt / 2;
-                        }
+                    let d = packet.timestamp.saturating_duration_since(last_registered);
+                    if d > self.max_rtt || self.cur_burst.packets.len() > self.max_packets {
+                        // Indiana Jones moment (Replace self.cur_burst with default)
+                        ret = Some(std::mem::take(&mut self.cur_burst));
+                        self.last_registered = None;
+                        self.max_rtt = self.max_rtt / 2;
+                        self.peak_in_flight /= 2;
                     }
                 }
             }
 
             if flags.is_ack() && *payload_len == 0 {
                 // Pure ACK acknowledges local packets.
-                pkt.gap_last_ack = self.get_gap_last_ack(pkt.sent_time);
+                let gap = self.get_gap_last_ack(pkt.sent_time);
+                pkt.set_gap_last_ack(gap);
                 acked_packets = self.update_acked_packets(*acknowledgment, pkt);
             } else {
                 // Set new last sent time and calculate gap
-                pkt.gap_last_sent = self.get_gap_last_sent(pkt.sent_time);
+                let gap = self.get_gap_last_sent(pkt.sent_time);
+                pkt.set_gap_last_sent(gap);
                 self.track_packet(*sequence, pkt);
             }
         }
-        if acked_packets.len() > 0 {
-            let last_sent: Option<SystemTime> =
-                if let Some(prev_ack) = self.cur_burst.packets.last() {
-                    Some(prev_ack.last_sent_time)
-                } else {
-                    None
-                };
-            self.cur_burst.packets.push(Acked::from_acked(
-                acked_packets,
-                packet.timestamp,
-                last_sent,
-            ));
+        if !acked_packets.is_empty() {
+            let last_sent = self.cur_burst.packets.last().map(|prev| prev.last_sent_time);
+            match Acked::from_acked(acked_packets, packet.timestamp, last_sent) {
+                Some(acked) => self.cur_burst.packets.push(acked),
+                None => self.skipped += 1,
+            }
         }
         self.last_registered = Some(packet.timestamp);
         ret
     }
 
     /// Track an outgoing packet by sequence number, handling retransmissions.
-    fn track_packet(&mut self, sequence: u32, packet: PacketType) {
+    fn track_packet(&mut self, sequence: u32, mut packet: PacketType) {
         match self.packets.get_mut(&sequence) {
             Some(existing) => {
                 existing.retransmissions += 1;
                 // If we don't do this we will calculate a way too high RTT
                 existing.sent_time = packet.sent_time;
-                existing.gap_last_sent = packet.gap_last_sent;
+                existing.set_gap_last_sent(packet.gap_last_sent());
             }
             None => {
+                // Well below what the connection had in flight before, the
+                // sender had nothing more to send
+                packet.set_app_limited(
+                    (self.in_flight as f64) < APP_LIMITED_SHARE * self.peak_in_flight as f64,
+                );
+                self.in_flight = self.in_flight.saturating_add(packet.payload_len as u32);
+                self.peak_in_flight = self.peak_in_flight.max(self.in_flight);
                 self.packets.insert(sequence, packet);
             }
         }
@@ -323,16 +348,14 @@ impl TcpStream {
 
     /// Update and remove all packets in the provided map that are
     /// fully acknowledged. Also update RTT and register the "sent" packet.
-    fn update_acked_packets(&mut self, ack: u32, pkt: PacketType) -> Vec<PacketType> {
-        let mut acked = Vec::new();
-        let mut keys_to_remove = Vec::new();
+    fn update_acked_packets(&mut self, ack: u32, pkt: PacketType) -> AckedPackets {
+        let mut acked = AckedPackets::new();
+        let mut keys_to_remove: SmallVec<[u32; 4]> = SmallVec::new();
         for (&seq, sent_packet) in self.packets.iter_mut() {
             if seq_less_equal(seq.wrapping_add(sent_packet.payload_len as u32), ack) {
-                if let Ok(rtt_duration) = pkt.sent_time.duration_since(sent_packet.sent_time) {
+                if let Some(rtt_duration) = pkt.sent_time.checked_duration_since(sent_packet.sent_time) {
                     self.max_rtt = std::cmp::max(self.max_rtt, rtt_duration);
-                    sent_packet.rtt = Some(rtt_duration);
-                    sent_packet.ack_time = Some(pkt.sent_time);
-                    sent_packet.gap_last_ack = pkt.gap_last_ack;
+                    sent_packet.set_acked(rtt_duration, pkt.gap_last_ack());
                 }
                 keys_to_remove.push(seq);
             } else {
@@ -342,6 +365,7 @@ impl TcpStream {
 
         for seq in keys_to_remove {
             if let Some(p) = self.packets.remove(&seq) {
+                self.in_flight = self.in_flight.saturating_sub(p.payload_len as u32);
                 acked.push(p);
             }
         }
@@ -356,6 +380,8 @@ impl TcpStream {
 pub struct TcpTracker {
     sent: TcpStream,
     received: TcpStream,
+    flow: FlowTimer,
+    rwnd: ReceiveWindow,
 }
 
 impl Default for TcpTracker {
@@ -366,26 +392,37 @@ impl Default for TcpTracker {
 
 impl TcpTracker {
     pub fn new() -> Self {
+        Self::with_burst_packets(CONFIG.client.tcp_burst_packets)
+    }
+
+    /// A tracker cutting bursts at `max_packets` acknowledged groups.
+    pub fn with_burst_packets(max_packets: usize) -> Self {
+        let max_packets = max_packets.max(1);
         TcpTracker {
-            sent: TcpStream {
-                packets: BTreeMap::new(),
-                last_ack: None,
-                last_sent: None,
-                last_registered: None,
-                cur_burst: TcpBurst::default(),
-                max_rtt: Duration::from_secs(10),
-            },
-            received: TcpStream {
-                packets: BTreeMap::new(),
-                last_ack: None,
-                last_sent: None,
-                last_registered: None,
-                cur_burst: TcpBurst::default(),
-                max_rtt: Duration::from_secs(10),
-            },
+            sent: TcpStream::new(max_packets),
+            received: TcpStream::new(max_packets),
+            flow: FlowTimer::new(),
+            rwnd: ReceiveWindow::new(),
         }
     }
 
+    /// Adds the receive window the remote advertised in `packet` to the
+    /// link's `stats`.
+    pub fn record_window(&mut self, packet: &ParsedPacket, stats: &mut WindowStats) {
+        self.rwnd.record(packet, stats);
+    }
+
+    /// Duration and size of the connection once it has ended, only once.
+    pub fn take_completed_flow(&mut self) -> Option<FlowCompletion> {
+        self.flow.take_completion()
+    }
+
+    /// ACK groups skipped in both directions since the last call, see
+    /// `Acked::from_acked`.
+    pub fn take_skipped_groups(&mut self) -> u64 {
+        std::mem::take(&mut self.sent.skipped) + std::mem::take(&mut self.received.skipped)
+    }
+
     /// Consume and return any accumulated bursts from both sides.
     /// Used for cleaning up after a connection is closed.
     pub fn take_bursts(&mut self) -> (Burst, Burst) {
@@ -398,6 +435,12 @@ impl TcpTracker {
     ///
     /// Returns `(burst, direction)` if a burst completed.
     pub fn register_packet(&mut self, packet: &ParsedPacket) -> Option<(Burst, Direction)> {
+        if let TransportPacket::TCP {
+            flags, payload_len, ..
+        } = &packet.transport
+        {
+            self.flow.record(packet.timestamp, *flags, *payload_len, packet.direction);
+        }
         let (burst, direction) = match packet.direction {
             Direction::Incoming => {
                 if packet.is_pure_ack() {
@@ -426,8 +469,133 @@ impl TcpTracker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{OffloadMode, OffloadPolicy, PacketRegistry};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use std::time::Duration;
 
+    /// A segment of the connection from 10.0.0.1:40000 to 10.0.0.2:5201.
+    fn segment(
+        direction: Direction,
+        seq: u32,
+        ack: u32,
+        payload_len: u16,
+        ms: u64,
+    ) -> ParsedPacket {
+        let (local, remote) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let (src_ip, dst_ip, ports) = match direction {
+            Direction::Outgoing => (local, remote, (40000, 5201)),
+            Direction::Incoming => (remote, local, (5201, 40000)),
+        };
+        let timestamp = Timestamp::from_millis(ms);
+        let mut packet = ParsedPacket {
+            src_ip,
+            dst_ip,
+            ..ParsedPacket::test_tcp(seq, ack, payload_len, timestamp, direction)
+        };
+        if let TransportPacket::TCP { src_port, dst_port, .. } = &mut packet.transport {
+            (*src_port, *dst_port) = ports;
+        }
+        packet
+    }
+
+    /// Bursts the estimator can use: no empty groups, and a duration and
+    /// throughput where there is one.
+    fn assert_sane(burst: &Burst) {
+        if let Burst::Tcp(tcp) = burst {
+            assert!(tcp.packets.iter().all(|acked| acked.len() > 0));
+            assert!(tcp.time_duration().is_none_or(|d| !d.is_zero()));
+            assert!(tcp.throughput().is_none_or(f64::is_finite));
+        }
+        assert!(burst.throughput().is_finite());
+    }
+
+    #[test]
+    fn test_ack_before_data_is_skipped() {
+        let mut tracker = TcpTracker::with_burst_packets(4);
+        tracker.register_packet(&segment(Direction::Outgoing, 1000, 1, 1000, 10));
+        // Captured before the data it acknowledges
+        tracker.register_packet(&segment(Direction::Incoming, 1, 2000, 0, 5));
+        assert_eq!(tracker.take_skipped_groups(), 1);
+
+        tracker.register_packet(&segment(Direction::Outgoing, 2000, 1, 1000, 20));
+        tracker.register_packet(&segment(Direction::Incoming, 1, 3000, 0, 30));
+        assert_eq!(tracker.take_skipped_groups(), 0);
+        let (sent, received) = tracker.take_bursts();
+        assert!(received.is_empty());
+        let Burst::Tcp(sent) = sent else {
+            panic!("Expected a TCP burst");
+        };
+        assert_eq!(sent.packets.len(), 1);
+        assert_eq!(sent.time_duration(), Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_empty_burst_has_no_duration() {
+        let burst = TcpBurst::default();
+        assert_eq!(burst.time_duration(), None);
+        assert_eq!(Burst::from(burst).throughput(), 0.0);
+    }
+
+    #[test]
+    fn test_random_ack_orderings() {
+        let mut rng = StdRng::seed_from_u64(3182);
+        for _ in 0..50 {
+            let mut tracker = TcpTracker::with_burst_packets(rng.random_range(1..8));
+            // Near the wrap of the sequence space
+            let base = u32::MAX - rng.random_range(0..20_000);
+            let mut ms: u64 = 1_000;
+            for _ in 0..300 {
+                // Mostly forward, at times back, as a capture out of order
+                ms = match rng.random_bool(0.1) {
+                    true => ms.saturating_sub(rng.random_range(0..50)),
+                    false => ms + rng.random_range(0..20),
+                };
+                let direction = match rng.random_bool(0.5) {
+                    true => Direction::Outgoing,
+                    false => Direction::Incoming,
+                };
+                let seq = base.wrapping_add(rng.random_range(0..40) * 1448);
+                let payload_len = if rng.random_bool(0.5) { 0 } else { 1448 };
+                let packet = segment(direction, seq, seq.wrapping_add(1448), payload_len, ms);
+                if let Some((burst, _)) = tracker.register_packet(&packet) {
+                    assert_sane(&burst);
+                }
+            }
+            let (sent, received) = tracker.take_bursts();
+            assert_sane(&sent);
+            assert_sane(&received);
+        }
+    }
+
+    #[test]
+    fn test_super_frames_in_gap_points() {
+        let registry = |mode: OffloadMode| {
+            let policy = OffloadPolicy::new(mode, 1500);
+            let mut tracker = TcpTracker::with_burst_packets(8);
+            for i in 0..3 {
+                let (seq, ms) = (1 + i * 64_000, 10 + i as u64 * 10);
+                let mut data = segment(Direction::Outgoing, seq, 1, 64_000, ms);
+                policy.apply(&mut data);
+                tracker.register_packet(&data);
+                tracker.register_packet(&segment(Direction::Incoming, 1, seq + 64_000, 0, ms + 5));
+            }
+            let mut registry = PacketRegistry::new();
+            registry.extend(tracker.take_bursts().0);
+            registry
+        };
+        let points = |mode: OffloadMode| registry(mode).take_gap_points();
+        // Gaps and payload spread over the 45 segments on the wire
+        assert_eq!(registry(OffloadMode::Split).gap_packets(), 90);
+        let split = points(OffloadMode::Split);
+        assert_eq!(split.len(), 2);
+        assert_eq!(split[0].num_acked, 45);
+        assert!((split[0].len - 64_000.0 / 45.0).abs() < 1e-9);
+        assert!((split[0].gin - 0.01 / 45.0).abs() < 1e-9);
+        assert_eq!(points(OffloadMode::Keep)[0].len, 64_000.0);
+        assert!(points(OffloadMode::Exclude).is_empty());
+    }
+
     #[test]
     fn test_seq_cmp_wraparound() {
         // 0 follows 0xFFFF_FFFF by wrap-around
@@ -446,10 +614,10 @@ mod tests {
     #[test]
     fn test_sort_by_time() {
         #[derive(Clone)]
-        struct P { sent_time: SystemTime }
-        let t1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
-        let t2 = SystemTime::UNIX_EPOCH + Duration::from_secs(2);
-        let t3 = SystemTime::UNIX_EPOCH + Duration::from_secs(3);
+        struct P { sent_time: Timestamp }
+        let t1 = Timestamp::ZERO + Duration::from_secs(1);
+        let t2 = Timestamp::ZERO + Duration::from_secs(2);
+        let t3 = Timestamp::ZERO + Duration::from_secs(3);
         let mut pkts = vec![P { sent_time: t3 }, P { sent_time: t1 }, P { sent_time: t2 }];
         pkts.sort_by(|a, b| a.sent_time.cmp(&b.sent_time));
         assert_eq!(pkts[0].sent_time, t1);
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use crate::Timestamp;
+
+/// Most idle buckets added for a single gap, so a link silent for a long
+/// time does not fill memory with zeros.
+const MAX_IDLE_BUCKETS: u64 = 3600;
+
+/// Throughput distribution over the buckets of a report window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles {
+    /// Bytes/sec
+    pub p5: f64,
+    pub p50: f64,
+    pub p95: f64,
+    /// Buckets the percentiles were taken over.
+    pub buckets: u32,
+}
+
+/// Bytes per fixed length bucket, from the first packet on.
+///
+/// Buckets are assigned by packet timestamp. Buckets with no traffic after
+/// the first packet count as zero, so bursty links get a wide spread.
+#[derive(Debug)]
+pub struct ThroughputSeries {
+    bucket_nanos: u64,
+    /// Index of the bucket being filled.
+    current: Option<u64>,
+    /// Bytes in the current bucket.
+    bytes: u64,
+    /// Bytes in each completed bucket since the last take.
+    buckets: Vec<u64>,
+}
+
+impl ThroughputSeries {
+    pub fn new(bucket: Duration) -> Self {
+        ThroughputSeries {
+            bucket_nanos: (bucket.as_nanos() as u64).max(1),
+            current: None,
+            bytes: 0,
+            buckets: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, at: Timestamp, bytes: u64) {
+        self.advance(at.as_nanos() / self.bucket_nanos);
+        self.bytes += bytes;
+    }
+
+    /// Percentiles of the buckets completed by `now` since the last call.
+    pub fn take_percentiles(&mut self, now: Timestamp) -> Option<Percentiles> {
+        if self.current.is_some() {
+            self.advance(now.as_nanos() / self.bucket_nanos);
+        }
+        let buckets = std::mem::take(&mut self.buckets);
+        if buckets.is_empty() {
+            return None;
+        }
+        let secs = self.bucket_nanos as f64 / 1e9;
+        let mut rates: Vec<f64> = buckets.iter().map(|&b| b as f64 / secs).collect();
+        rates.sort_by(|a, b| a.total_cmp(b));
+        Some(Percentiles {
+            p5: percentile(&rates, 0.05),
+            p50: percentile(&rates, 0.5),
+            p95: percentile(&rates, 0.95),
+            buckets: rates.len() as u32,
+        })
+    }
+
+    /// Completes the current bucket and any idle ones before `bucket`.
+    /// Late packets are counted in the current bucket.
+    fn advance(&mut self, bucket: u64) {
+        match self.current {
+            None => self.current = Some(bucket),
+            Some(current) if bucket > current => {
+                self.buckets.push(std::mem::take(&mut self.bytes));
+                let idle = (bucket - current - 1).min(MAX_IDLE_BUCKETS);
+                self.buckets.resize(self.buckets.len() + idle as usize, 0);
+                self.current = Some(bucket);
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+/// Nearest rank percentile of sorted, non-empty `values`.
+pub(crate) fn percentile(values: &[f64], p: f64) -> f64 {
+    let rank = (p * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bursty_vs_steady() {
+        let t0 = Timestamp::from_millis(1_000_000);
+        let second = |s: u64| t0 + Duration::from_secs(s);
+
+        let mut steady = ThroughputSeries::new(Duration::from_secs(1));
+        for s in 0..20 {
+            steady.record(second(s), 1000);
+        }
+        let p = steady.take_percentiles(second(20)).unwrap();
+        assert_eq!(p.buckets, 20);
+        assert_eq!((p.p5, p.p50, p.p95), (1000.0, 1000.0, 1000.0));
+
+        // Same bytes in two bursts, idle in between
+        let mut bursty = ThroughputSeries::new(Duration::from_secs(1));
+        bursty.record(second(0), 10_000);
+        bursty.record(second(19), 10_000);
+        let p = bursty.take_percentiles(second(20)).unwrap();
+        assert_eq!(p.buckets, 20);
+        assert_eq!((p.p5, p.p50, p.p95), (0.0, 0.0, 10_000.0));
+
+        assert!(bursty.take_percentiles(second(20)).is_none());
+    }
+}
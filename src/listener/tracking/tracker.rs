@@ -1,4 +1,4 @@
-use std::time::SystemTime;
+use crate::Timestamp;
 
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 
@@ -40,13 +40,13 @@ impl DefaultState for TrackerState {
 
 #[derive(Debug)]
 pub struct Tracker<TState> {
-    pub last_registered: SystemTime,
+    pub last_registered: Timestamp,
     pub protocol: IpNextHeaderProtocol,
     pub state: TState,
 }
 
 impl<TState: DefaultState> Tracker<TState> {
-    pub fn new(timestamp: SystemTime, protocol: IpNextHeaderProtocol) -> Self {
+    pub fn new(timestamp: Timestamp, protocol: IpNextHeaderProtocol) -> Self {
         Self {
             last_registered: timestamp,
             protocol,
@@ -1,64 +1,144 @@
-use std::time::SystemTime;
+use std::time::Duration;
+
+use crate::Timestamp;
 
 use procfs::net::UdpState;
 
 use crate::Direction;
 use crate::PacketType;
 use crate::ParsedPacket;
+use crate::CONFIG;
 
 use super::tcp_tracker::Burst;
 
+/// Packets of one direction of a UDP flow, grouped into bursts.
+#[derive(Debug, Default)]
+struct UdpStream {
+    burst: Vec<PacketType>,
+    /// Time of the last packet, in this or an earlier burst.
+    last_sent: Option<Timestamp>,
+}
+
+impl UdpStream {
+    /// Adds a packet, with the gap since the one before it. Returns the
+    /// burst it ended, if any.
+    fn register_packet(
+        &mut self,
+        packet: &ParsedPacket,
+        max_gap: Duration,
+        max_packets: usize,
+    ) -> Option<Vec<PacketType>> {
+        let gap = self
+            .last_sent
+            .and_then(|last| packet.timestamp.checked_duration_since(last));
+        let ended = match gap {
+            _ if self.burst.is_empty() => None,
+            Some(gap) if gap > max_gap => Some(std::mem::take(&mut self.burst)),
+            _ if self.burst.len() >= max_packets => Some(std::mem::take(&mut self.burst)),
+            _ => None,
+        };
+
+        let mut pkt = PacketType::from_packet(packet);
+        pkt.set_gap_last_sent(gap);
+        self.burst.push(pkt);
+        self.last_sent = Some(packet.timestamp);
+        ended
+    }
+}
+
+/// Tracks both directions of a UDP flow, producing bursts.
+///
+/// A burst ends when the gap to the previous packet in its direction
+/// exceeds `client.udp_burst_gap`, or it reaches `client.udp_burst_packets`.
+/// Every packet carries the gap to the one sent before it, as on TCP.
 #[derive(Debug)]
 pub struct UdpTracker {
     pub state: Option<UdpState>,
-    burst_in: Vec<PacketType>,
-    burst_out: Vec<PacketType>,
-    last_in: SystemTime,
-    last_out: SystemTime,
+    received: UdpStream,
+    sent: UdpStream,
+    /// Gap which ends a burst.
+    max_gap: Duration,
+    /// Packets at which a burst is cut.
+    max_packets: usize,
 }
 
 impl Default for UdpTracker {
     fn default() -> Self {
+        Self::with_thresholds(CONFIG.client.udp_burst_gap, CONFIG.client.udp_burst_packets)
+    }
+}
+
+impl UdpTracker {
+    pub fn with_thresholds(max_gap: Duration, max_packets: usize) -> Self {
         UdpTracker {
             state: Some(UdpState::Established),
-            burst_in: Vec::new(),
-            burst_out: Vec::new(),
-            last_in: SystemTime::UNIX_EPOCH,
-            last_out: SystemTime::UNIX_EPOCH,
+            received: UdpStream::default(),
+            sent: UdpStream::default(),
+            max_gap,
+            max_packets: max_packets.max(1),
         }
     }
-}
 
-impl UdpTracker {
+    /// Registers a packet, returning the burst it ended, if any.
     pub fn register_packet(&mut self, packet: &ParsedPacket) -> Option<(Burst, Direction)> {
-        let mut ret = Vec::new();
-
-        let (burst, last) = match packet.direction {
-            Direction::Incoming => (&mut self.burst_in, &mut self.last_in),
-            Direction::Outgoing => (&mut self.burst_out, &mut self.last_out),
+        let stream = match packet.direction {
+            Direction::Incoming => &mut self.received,
+            Direction::Outgoing => &mut self.sent,
         };
+        stream
+            .register_packet(packet, self.max_gap, self.max_packets)
+            .map(|burst| (Burst::Udp(burst), packet.direction))
+    }
 
-        if let Ok(dur) = packet.timestamp.duration_since(*last) {
-            if dur > std::time::Duration::from_secs(1) || burst.len() == 100 {
-                std::mem::swap(&mut ret, burst);
-            }
-        }
+    /// Takes the bursts in progress, as (sent, received) like
+    /// `TcpTracker::take_bursts`.
+    pub fn take_bursts(&mut self) -> (Burst, Burst) {
+        (
+            Burst::Udp(std::mem::take(&mut self.sent.burst)),
+            Burst::Udp(std::mem::take(&mut self.received.burst)),
+        )
+    }
+}
 
-        burst.push(PacketType::from_packet(packet));
-        *last = packet.timestamp;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if ret.is_empty() {
-            None
-        } else {
-            Some((Burst::Udp(ret), packet.direction))
-        }
+    fn packet(ms: u64, direction: Direction) -> ParsedPacket {
+        ParsedPacket::test_udp(40000, 5201, 972, Timestamp::from_millis(ms), direction)
     }
 
-    pub fn take_bursts(&mut self) -> (Burst, Burst) {
-        let mut in_burst = Vec::new();
-        let mut out_burst = Vec::new();
-        std::mem::swap(&mut in_burst, &mut self.burst_in);
-        std::mem::swap(&mut out_burst, &mut self.burst_out);
-        (Burst::Udp(in_burst), Burst::Udp(out_burst))
+    #[test]
+    fn test_bursts_per_direction() {
+        let mut tracker = UdpTracker::with_thresholds(Duration::from_millis(100), 3);
+        for ms in [1000, 1010, 1020] {
+            assert!(tracker.register_packet(&packet(ms, Direction::Outgoing)).is_none());
+        }
+        // The other direction has its own burst and gaps
+        assert!(tracker.register_packet(&packet(1025, Direction::Incoming)).is_none());
+
+        // Cut by size
+        let Some((burst, Direction::Outgoing)) =
+            tracker.register_packet(&packet(1030, Direction::Outgoing))
+        else {
+            panic!("Expected an outgoing burst");
+        };
+        assert_eq!(burst.len(), 3);
+        // 3000 bytes over 20 ms
+        assert!((burst.throughput() - 150_000.0).abs() < 1e-6);
+        let gaps: Vec<_> = burst.flatten().iter().map(|p| p.gap_last_sent()).collect();
+        let ten = Some(Duration::from_millis(10));
+        assert_eq!(gaps, vec![None, ten, ten]);
+
+        // Cut by gap, the gap stays with the packet that starts the next one
+        let Some((burst, _)) = tracker.register_packet(&packet(1500, Direction::Outgoing)) else {
+            panic!("Expected a burst");
+        };
+        assert_eq!(burst.len(), 1);
+        assert!(tracker.register_packet(&packet(1040, Direction::Incoming)).is_none());
+        let (sent, received) = tracker.take_bursts();
+        assert_eq!((sent.len(), received.len()), (1, 2));
+        let sent = sent.flatten();
+        assert_eq!(sent[0].gap_last_sent(), Some(Duration::from_millis(470)));
     }
 }
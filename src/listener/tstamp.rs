@@ -0,0 +1,185 @@
+//! Timestamp types a device supports. Many drivers, in VMs especially,
+//! only have the host clock. Where `client.tstamp_type` is not supported,
+//! the capture falls back along `FALLBACK`: adapter, high precision host,
+//! then host. The type in use is in `PCAPMeta` and the `tstamp_type` tag
+//! of the data messages.
+//!
+//! The adapter types have libpcap turn on hardware timestamping in the
+//! driver, so the gaps are measured by the NIC. Which clock that is, is the
+//! `ClockDomain` of the capture, in the `clock_domain` tag.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+
+use anyhow::{anyhow, Result};
+use pcap::TimestampType;
+
+/// The libpcap calls for listing timestamp types, which the pcap crate does
+/// not wrap. Resolved against the libpcap it links.
+mod ffi {
+    use std::ffi::{c_char, c_int};
+
+    /// Opaque `pcap_t`.
+    #[repr(C)]
+    pub struct PcapT {
+        _private: [u8; 0],
+    }
+
+    pub const PCAP_ERRBUF_SIZE: usize = 256;
+
+    extern "C" {
+        pub fn pcap_create(source: *const c_char, errbuf: *mut c_char) -> *mut PcapT;
+        pub fn pcap_list_tstamp_types(p: *mut PcapT, types: *mut *mut c_int) -> c_int;
+        pub fn pcap_free_tstamp_types(types: *mut c_int);
+        pub fn pcap_close(p: *mut PcapT);
+    }
+}
+
+/// Types tried in turn, from the configured one on.
+pub const FALLBACK: [TimestampType; 3] = [
+    TimestampType::Adapter,
+    TimestampType::HostHighPrec,
+    TimestampType::Host,
+];
+
+fn from_raw(value: c_int) -> Option<TimestampType> {
+    match value {
+        0 => Some(TimestampType::Host),
+        1 => Some(TimestampType::HostLowPrec),
+        2 => Some(TimestampType::HostHighPrec),
+        3 => Some(TimestampType::Adapter),
+        4 => Some(TimestampType::AdapterUnsynced),
+        // Unsynced high precision host stamps, unknown to the pcap crate
+        _ => None,
+    }
+}
+
+/// Timestamp types of the device `name`. Empty if it only has the default,
+/// the host clock.
+pub fn supported(name: &str) -> Result<Vec<TimestampType>> {
+    let source = CString::new(name)?;
+    let mut errbuf = [0 as c_char; ffi::PCAP_ERRBUF_SIZE];
+    // SAFETY: `source` is NUL terminated and `errbuf` is as large as libpcap
+    // writes to. The handle is only created to be asked, and never activated.
+    let handle = unsafe { ffi::pcap_create(source.as_ptr(), errbuf.as_mut_ptr()) };
+    if handle.is_null() {
+        // SAFETY: on failure libpcap leaves a NUL terminated message in
+        // `errbuf`.
+        let error = unsafe { CStr::from_ptr(errbuf.as_ptr()) };
+        return Err(anyhow!("Failed to open {}: {}", name, error.to_string_lossy()));
+    }
+    let mut types: *mut c_int = ptr::null_mut();
+    // SAFETY: `handle` is the live handle from `pcap_create` above.
+    let count = unsafe { ffi::pcap_list_tstamp_types(handle, &mut types) };
+    let supported = if count < 0 {
+        Err(anyhow!("Failed to list the timestamp types of {}", name))
+    } else if types.is_null() {
+        Ok(Vec::new())
+    } else {
+        // SAFETY: on success `types` holds `count` values, ours until freed.
+        let list = unsafe { std::slice::from_raw_parts(types, count as usize) }
+            .iter()
+            .filter_map(|&value| from_raw(value))
+            .collect();
+        // SAFETY: `types` is from `pcap_list_tstamp_types`, and the list
+        // above is a copy of it.
+        unsafe { ffi::pcap_free_tstamp_types(types) };
+        Ok(list)
+    };
+    // SAFETY: `handle` is live and not used after this.
+    unsafe { ffi::pcap_close(handle) };
+    supported
+}
+
+/// `configured` if the device has it, otherwise the first of the types
+/// after it in `FALLBACK` that it has. The host clock is always there, also
+/// when not listed.
+pub fn choose(configured: TimestampType, supported: &[TimestampType]) -> TimestampType {
+    let later: &[TimestampType] = match configured {
+        TimestampType::Adapter | TimestampType::AdapterUnsynced => &FALLBACK[1..],
+        TimestampType::HostHighPrec => &FALLBACK[2..],
+        _ => &[],
+    };
+    std::iter::once(configured)
+        .chain(later.iter().copied())
+        .find(|t| *t == TimestampType::Host || supported.contains(t))
+        .unwrap_or(TimestampType::Host)
+}
+
+/// Clock the capture timestamps come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockDomain {
+    /// The system clock, read by the kernel as the packet comes in.
+    Host,
+    /// The NIC clock, kept in step with the system clock.
+    Adapter,
+    /// The NIC clock running free. Its timestamps are moved onto the system
+    /// clock by the offset at the first packet, see `PCAPMeta::capture_offset`.
+    AdapterUnsynced,
+}
+
+impl ClockDomain {
+    pub fn of(tstamp_type: TimestampType) -> Self {
+        match tstamp_type {
+            TimestampType::Adapter => ClockDomain::Adapter,
+            TimestampType::AdapterUnsynced => ClockDomain::AdapterUnsynced,
+            _ => ClockDomain::Host,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClockDomain::Host => "host",
+            ClockDomain::Adapter => "adapter",
+            ClockDomain::AdapterUnsynced => "adapter_unsynced",
+        }
+    }
+}
+
+/// Name in the config file.
+pub fn name(tstamp_type: TimestampType) -> &'static str {
+    match tstamp_type {
+        TimestampType::Host => "host",
+        TimestampType::HostLowPrec => "host_lowprec",
+        TimestampType::HostHighPrec => "host_highprec",
+        TimestampType::Adapter => "adapter",
+        TimestampType::AdapterUnsynced => "adapter_unsynced",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_raw() {
+        assert_eq!(from_raw(0), Some(TimestampType::Host));
+        assert_eq!(from_raw(3), Some(TimestampType::Adapter));
+        assert_eq!(from_raw(4), Some(TimestampType::AdapterUnsynced));
+        // PCAP_TSTAMP_HOST_HIPREC_UNSYNCED
+        assert_eq!(from_raw(5), None);
+    }
+
+    #[test]
+    fn test_choose_follows_fallback() {
+        let supported = [TimestampType::Host, TimestampType::Adapter];
+        assert_eq!(choose(TimestampType::Adapter, &supported), TimestampType::Adapter);
+        assert_eq!(choose(TimestampType::Adapter, &[]), TimestampType::Host);
+        assert_eq!(
+            choose(TimestampType::AdapterUnsynced, &supported),
+            TimestampType::Host
+        );
+        assert_eq!(choose(TimestampType::Host, &[]), TimestampType::Host);
+
+        // A VM NIC with only the host clocks
+        let supported = [TimestampType::Host, TimestampType::HostHighPrec];
+        assert_eq!(
+            choose(TimestampType::Adapter, &supported),
+            TimestampType::HostHighPrec
+        );
+        assert_eq!(
+            choose(TimestampType::HostLowPrec, &supported),
+            TimestampType::Host
+        );
+    }
+}
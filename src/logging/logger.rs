@@ -1,7 +1,14 @@
 use fern;
 
-pub fn setup_logging() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
+/// Logs to `output.log`, and to stdout unless it is taken by something
+/// else, e.g. the link summaries. `trace` turns on the window traces.
+pub fn setup_logging(stdout: bool, trace: bool) -> Result<(), fern::InitError> {
+    let trace_level = if trace {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    let mut dispatch = fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
                 "{}[{}][{}] {}",
@@ -12,9 +19,12 @@ pub fn setup_logging() -> Result<(), fern::InitError> {
             ))
         })
         .level(log::LevelFilter::Info)
-        .chain(fern::log_file("output.log")?)
-        .chain(std::io::stdout())
-        .apply()?;
+        .level_for(crate::prost_net::trace::TARGET, trace_level)
+        .chain(fern::log_file("output.log")?);
+    if stdout {
+        dispatch = dispatch.chain(std::io::stdout());
+    }
+    dispatch.apply()?;
 
     Ok(())
 }
@@ -0,0 +1,305 @@
+//! The listener as a whole: capture, parser, gRPC client and servers.
+
+use crate::anonymize;
+use crate::features;
+use crate::identity;
+use crate::listener::capture::{PCAPMeta, PacketCapturer};
+use crate::listener::dump::DumpHandle;
+use crate::listener::filter::SubnetRules;
+use crate::listener::parser::Parser;
+use crate::listener::tracking::estimates::LinkQuery;
+use crate::listener::simulation::TrafficModel;
+use crate::privileges;
+use crate::probe::iperf::IperfServer;
+use crate::prost_net::bandwidth_client::{ClientHandler, ClientHandlerEvent};
+use crate::prost_net::bandwidth_server::BwServer;
+use crate::prost_net::http_api::dispatch_http_api;
+use crate::prost_net::metrics_store::MetricsStore;
+use crate::proto_bw::DataMsg;
+use crate::routing::RoutingExport;
+use crate::summary::dispatch_summary;
+use crate::tap::{LinkStateConsumer, LinkStateConsumers, Tap, TapEvent, TapFilter};
+use crate::{CapEvent, CapEventReceiver, CapEventSender, CONFIG, IPERF3_PORT};
+use log::{info, warn};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::{broadcast, watch};
+use tokio::sync::mpsc::{channel, unbounded_channel};
+use tokio::task::JoinHandle;
+use tokio_stream::Stream;
+use tokio_util::sync::CancellationToken;
+
+/// Time the gRPC server has to finish open calls after a shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type EventSender = tokio::sync::mpsc::UnboundedSender<EventMessage>;
+pub type EventReceiver = tokio::sync::mpsc::UnboundedReceiver<EventMessage>;
+
+// Struct representation of the crate.
+pub struct NetworkListener {
+    event_receiver: EventReceiver,
+    _event_sender: EventSender,
+    handles: Vec<JoinHandle<()>>,
+    result_handles: Vec<JoinHandle<anyhow::Result<()>>>,
+    /// The gRPC server, stopped gracefully by `shutdown`.
+    bw_server_handle: Option<JoinHandle<anyhow::Result<()>>>,
+    shutdown: CancellationToken,
+    tap: Tap,
+    consumers: LinkStateConsumers,
+    /// Opened by `open`, taken by `start`.
+    source: Option<(PacketSource, CapEventSender, CapEventReceiver)>,
+    /// Subnet rules of the packet filter and capture, set by the SetSubnets
+    /// RPC.
+    subnets: Arc<watch::Sender<SubnetRules>>,
+}
+
+/// Enum representing events that can be sent to the main event loop.
+/// The idea is to be able to pause and resume the packet capture to do
+/// active measurements, but this is not implemented or used.
+pub enum EventMessage {
+    /// Pause the packet capture
+    PausePCAP(tokio::time::Duration),
+    /// Resume the packet capture
+    ResumePCAP,
+}
+
+/// Where the parser gets its packets from.
+enum PacketSource {
+    Capture(PacketCapturer),
+    /// `simulation.enabled`, see `listener::simulation`.
+    Simulation(TrafficModel),
+}
+
+impl PacketSource {
+    fn from_config(
+        sender: CapEventSender,
+        subnets: watch::Receiver<SubnetRules>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if CONFIG.simulation.enabled {
+            let (model, meta) = TrafficModel::from_config();
+            info!("Simulating traffic on {:?}", meta);
+            return Ok(PacketSource::Simulation(model));
+        }
+        let (pcap, pcap_meta) = PacketCapturer::new(sender, CONFIG.client.iface.clone(), subnets)?;
+        info!("Capturing on {:?}", pcap_meta);
+        Ok(PacketSource::Capture(pcap))
+    }
+
+    fn subscribe_meta(&self) -> watch::Receiver<PCAPMeta> {
+        match self {
+            PacketSource::Capture(pcap) => pcap.subscribe_meta(),
+            PacketSource::Simulation(model) => model.subscribe_meta(),
+        }
+    }
+
+    fn dump_handle(&self) -> DumpHandle {
+        match self {
+            PacketSource::Capture(pcap) => pcap.dump_handle(),
+            PacketSource::Simulation(model) => model.dump_handle(),
+        }
+    }
+
+    /// Starts feeding the parser, the capture on the sender it was opened
+    /// with.
+    fn dispatch(
+        self,
+        sender: CapEventSender,
+    ) -> (Option<JoinHandle<()>>, JoinHandle<anyhow::Result<()>>) {
+        match self {
+            PacketSource::Capture(pcap) => {
+                let meta_refresh_h = pcap.dispatch_meta_refresh();
+                (Some(meta_refresh_h), pcap.start_capture_loop())
+            }
+            PacketSource::Simulation(model) => (None, model.dispatch(sender)),
+        }
+    }
+}
+
+impl NetworkListener {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let (_event_sender, event_receiver) = unbounded_channel();
+        Ok(Self {
+            event_receiver,
+            _event_sender,
+            handles: vec![],
+            result_handles: vec![],
+            bw_server_handle: None,
+            shutdown: CancellationToken::new(),
+            tap: Tap::new(),
+            consumers: LinkStateConsumers::default(),
+            source: None,
+            subnets: Arc::new(watch::channel(SubnetRules::from_config()).0),
+        })
+    }
+
+    /// Events seen by the listener, from now on. Subscribe before `start`
+    /// to not miss any.
+    pub fn subscribe(&self, filter: TapFilter) -> impl Stream<Item = TapEvent> {
+        self.tap.subscribe(filter)
+    }
+
+    /// Calls `consumer` with every link state reported, without going
+    /// through gRPC. Register before `start`.
+    pub fn add_link_state_consumer(&mut self, consumer: Arc<dyn LinkStateConsumer>) {
+        self.consumers.add(consumer);
+    }
+
+    /// Opens the capture, or the simulation, then drops the privileges it
+    /// needed if `privileges.enabled`. Call it before the tokio runtime
+    /// starts, see `privileges`. `start` opens it if it is not open yet.
+    pub fn open(&mut self) -> Result<(), Box<dyn Error>> {
+        let (sender, receiver) = channel::<CapEvent>(1000);
+        let source = PacketSource::from_config(sender.clone(), self.subnets.subscribe())?;
+        // The keys may only be readable before the drop
+        anonymize::init()?;
+        identity::init()?;
+        privileges::drop_from_config()?;
+        self.source = Some((source, sender, receiver));
+        Ok(())
+    }
+
+    /// Start all the different tasks and components of the network listener.
+    /// This includes the packet capture, parser, client handler, and server.
+    ///
+    /// It creates channels for communication between the components and
+    /// dispatches the tasks to run concurrently.
+    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Starting packet capture");
+
+        if self.source.is_none() {
+            // Opening waits for the interface to come up, which would hold
+            // up the other tasks on this worker thread
+            let flavor = Handle::try_current().map(|handle| handle.runtime_flavor());
+            match flavor {
+                Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(|| self.open())?,
+                _ => self.open()?,
+            }
+        }
+        let (source, sender, receiver) = self.source.take().ok_or("Packet source not open")?;
+        let (client_sender, client_receiver) = channel::<ClientHandlerEvent>(100);
+        let (bw_message_bc, _bw_message_rx) = broadcast::channel::<DataMsg>(4);
+        let bw_message_bc = Arc::new(bw_message_bc);
+
+        let (mut parser, ctx) = Parser::new(
+            receiver,
+            source.subscribe_meta(),
+            client_sender,
+            self.tap.clone(),
+            self.subnets.subscribe(),
+        )?;
+        let routing = RoutingExport::from_config(&CONFIG.routing)?;
+        let mut consumers = self.consumers.clone();
+        if let Some(routing) = &routing {
+            consumers.add(routing.consumer());
+        }
+        parser.set_link_state_consumers(consumers);
+        let store = MetricsStore::new();
+        let client_handler = ClientHandler::new(
+            ctx,
+            client_receiver,
+            sender.clone(),
+            bw_message_bc.clone(),
+            store.clone(),
+            source.subscribe_meta(),
+        );
+        let server = IperfServer::new(IPERF3_PORT, sender.clone())?;
+
+        // Pass Arc reference to the bandwidth message channel
+        let bw_server = BwServer::new(
+            sender.clone(),
+            source.subscribe_meta(),
+            bw_message_bc.clone(),
+            self.tap.clone(),
+            source.dump_handle(),
+            store.clone(),
+            self.subnets.clone(),
+        );
+
+        // Bound first, so a taken port fails the start
+        let bw_server_h = bw_server.dispatch_server(self.shutdown.clone())?;
+        let summary_interval = CONFIG.client.summary_interval;
+        if !summary_interval.is_zero() {
+            self.handles.push(dispatch_summary(store.clone(), summary_interval));
+        }
+        if let Some(addr) = CONFIG.http.listen_addr {
+            let query = LinkQuery::new(sender.clone());
+            let http_h = dispatch_http_api(addr, store, query, &self.tap, self.shutdown.clone())?;
+            self.result_handles.push(http_h);
+        }
+        if let Some(routing) = routing {
+            self.result_handles.push(routing.dispatch());
+        }
+        let bw_client_h = client_handler.dispatch_client_handler();
+        let (meta_refresh_h, cap_h) = source.dispatch(sender.clone());
+        let parser_h = parser.dispatch_parser();
+        let server_h = server.dispatch_server();
+        //let pathload_h = network_listener::probe::pathload::dispatch_server();
+
+        self.handles.push(parser_h);
+        self.handles.push(bw_client_h);
+        self.handles.extend(meta_refresh_h);
+        //self.handles.push(pathload_h);
+        self.result_handles.push(cap_h);
+        self.result_handles.push(server_h);
+        self.bw_server_handle = Some(bw_server_h);
+        if let Some(path) = &CONFIG.features.file {
+            self.result_handles
+                .push(features::dispatch_file_writer(path.clone(), &self.tap));
+        }
+        Ok(())
+    }
+
+    pub async fn blocking_event_loop(mut self) -> Self {
+        // Event loop
+        loop {
+            tokio::select! {
+                Some(event) = self.event_receiver.recv() => match event {
+                    EventMessage::PausePCAP(_) => {
+                        info!("Not implemented (pause packet capture)");
+                    },
+                    EventMessage::ResumePCAP => {
+                        info!("Not implemented (resume packet capture)");
+                    },
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Received Ctrl-C, Stopping all tasks");
+                    break;
+                },
+                else => {
+                    info!("Event channel closed");
+                    break;
+                }
+            }
+        }
+
+        self
+    }
+
+    pub async fn stop(self) {
+        // Let the gRPC server finish open calls and close its socket
+        self.shutdown.cancel();
+        if let Some(handle) = self.bw_server_handle {
+            match tokio::time::timeout(SHUTDOWN_TIMEOUT, handle).await {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(e))) => warn!("gRPC server failed: {}", e),
+                Ok(Err(e)) => warn!("gRPC server task failed: {}", e),
+                Err(_) => warn!("gRPC server did not stop within {:?}", SHUTDOWN_TIMEOUT),
+            }
+        }
+        // Stop the parser
+        for handle in self.handles {
+            if handle.is_finished() {
+                continue;
+            }
+            handle.abort();
+        }
+        for handle in self.result_handles {
+            if handle.is_finished() {
+                continue;
+            }
+            handle.abort();
+        }
+    }
+}
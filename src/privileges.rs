@@ -0,0 +1,139 @@
+//! Gives up root once the capture is open, see `[privileges]`.
+//!
+//! Every capability but `keep_caps` is dropped, from the bounding set too,
+//! so programs started later (iperf3) cannot get them back. With `user`
+//! set, the process also switches to that user and its group, keeping
+//! `keep_caps` across the switch. Files written later, e.g. the ring, the
+//! state file, dumps and feature files, must then be writable by that user.
+//!
+//! Linux keeps capabilities per thread, and new threads inherit them from
+//! the thread that starts them. The drop must happen while the process has
+//! a single thread, before the tokio runtime starts, see
+//! `NetworkListener::open`.
+
+use std::ffi::CString;
+
+use anyhow::{anyhow, Context, Result};
+use caps::{CapSet, Capability, CapsHashSet};
+use log::{info, warn};
+
+use crate::config::Privileges;
+use crate::CONFIG;
+
+/// Parses names like "CAP_NET_RAW" or "net_raw".
+fn parse_capability(name: &str) -> Result<Capability> {
+    let name = name.to_uppercase();
+    let name = if name.starts_with("CAP_") {
+        name
+    } else {
+        format!("CAP_{}", name)
+    };
+    name.parse()
+        .map_err(|_| anyhow!("Unknown capability: {}", name))
+}
+
+/// Uid and gid of `user`.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).context("User name")?;
+    // Only called before other threads exist, getpwnam is not reentrant
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(anyhow!("No such user: {}", user));
+    }
+    let passwd = unsafe { &*passwd };
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Fails with the OS error if `ret` is not 0.
+fn check(ret: libc::c_int, what: &str) -> Result<()> {
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).context(what.to_string());
+    }
+    Ok(())
+}
+
+/// Whether permitted capabilities survive a switch away from root.
+fn set_keep_caps(keep: bool) -> Result<()> {
+    // The kernel reads every argument as unsigned long
+    let (keep, unused): (libc::c_ulong, libc::c_ulong) = (keep.into(), 0);
+    let ret = unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, keep, unused, unused, unused) };
+    check(ret, "PR_SET_KEEPCAPS")
+}
+
+/// Switches to `uid` and `gid`, without supplementary groups. Effective
+/// capabilities are cleared by the switch.
+fn switch_user(uid: libc::uid_t, gid: libc::gid_t) -> Result<()> {
+    set_keep_caps(true)?;
+    unsafe {
+        check(libc::setgroups(0, std::ptr::null()), "setgroups")?;
+        check(libc::setgid(gid), "setgid")?;
+        check(libc::setuid(uid), "setuid")?;
+    }
+    set_keep_caps(false)
+}
+
+/// Drops the privileges as configured in `privileges`.
+pub fn drop_privileges(privileges: &Privileges) -> Result<()> {
+    let wanted = privileges
+        .keep_caps
+        .iter()
+        .map(|name| parse_capability(name))
+        .collect::<Result<CapsHashSet>>()?;
+    // Capabilities the process does not have cannot be kept
+    let permitted = caps::read(None, CapSet::Permitted).context("Reading capabilities")?;
+    let keep: CapsHashSet = wanted.intersection(&permitted).copied().collect();
+    let user = match &privileges.user {
+        Some(user) => Some(lookup_user(user)?),
+        None => None,
+    };
+    if tokio::runtime::Handle::try_current().is_ok() {
+        warn!("Dropping privileges after the runtime started, its other threads keep them");
+    }
+
+    // Needs CAP_SETPCAP, which goes with the rest
+    if caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP)? {
+        for cap in caps::all().difference(&keep) {
+            caps::drop(None, CapSet::Bounding, *cap)
+                .with_context(|| format!("Dropping {} from the bounding set", cap))?;
+        }
+    } else {
+        warn!("Cannot shrink the bounding set, started programs may regain capabilities");
+    }
+    if let Some((uid, gid)) = user {
+        switch_user(uid, gid)?;
+    }
+    caps::clear(None, CapSet::Ambient).context("Clearing the ambient set")?;
+    caps::clear(None, CapSet::Inheritable).context("Clearing the inheritable set")?;
+    caps::set(None, CapSet::Permitted, &keep).context("Setting the permitted set")?;
+    caps::set(None, CapSet::Effective, &keep).context("Setting the effective set")?;
+
+    let mut kept: Vec<String> = keep.iter().map(|cap| cap.to_string()).collect();
+    kept.sort();
+    match &privileges.user {
+        Some(user) => info!("Running as {}, keeping {:?}", user, kept),
+        None => info!("Dropped all capabilities but {:?}", kept),
+    }
+    Ok(())
+}
+
+/// Drops the privileges if `privileges.enabled`.
+pub fn drop_from_config() -> Result<()> {
+    if !CONFIG.privileges.enabled {
+        return Ok(());
+    }
+    drop_privileges(&CONFIG.privileges).context("Failed to drop privileges")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capability() {
+        let cap = |name| parse_capability(name).unwrap();
+        assert_eq!(cap("CAP_NET_RAW"), Capability::CAP_NET_RAW);
+        assert_eq!(cap("net_admin"), Capability::CAP_NET_ADMIN);
+        assert!(parse_capability("net_magic").is_err());
+        assert!(lookup_user("no-such-user-here").is_err());
+    }
+}
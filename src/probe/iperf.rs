@@ -1,14 +1,20 @@
 use std::process::Stdio;
 
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
 
-use anyhow::Result;
-use log::info;
-use tokio::process::Command;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use tokio::process::{Child, ChildStdout, Command};
 
+use crate::channel::{send_or_err, send_or_log};
 use crate::probe::iperf_json::IperfResponse;
 use crate::*;
 
+/// Ports tried for a one-off server before giving up.
+const ONE_OFF_ATTEMPTS: usize = 3;
+/// Time iperf3 has to bind its port, or exit if it is taken.
+const LISTEN_DELAY: tokio::time::Duration = tokio::time::Duration::from_millis(100);
+
 /// Represents an `iperf3` server process that listens for incoming tests
 /// and forwards parsed JSON results as `CapEvent::IperfResponse`.
 #[derive(Debug)]
@@ -17,6 +23,8 @@ pub struct IperfServer {
     listen_port: u16,
     /// Channel to send parsed `CapEvent`s
     sender: CapEventSender,
+    /// Exit after serving a single test (`iperf3 -s -1`)
+    one_off: bool,
 }
 
 impl IperfServer {
@@ -29,9 +37,47 @@ impl IperfServer {
         Ok(IperfServer {
             listen_port,
             sender,
+            one_off: false,
         })
     }
 
+    /// Create a server that handles a single test and then exits.
+    ///
+    /// Used to prepare a negotiated probe session on its own port.
+    pub fn one_off(listen_port: u16, sender: CapEventSender) -> Self {
+        IperfServer {
+            listen_port,
+            sender,
+            one_off: true,
+        }
+    }
+
+    /// Starts a one-off server on `port`, or on a free port if None, and
+    /// returns the port once iperf3 listens on it.
+    ///
+    /// iperf3 binds the port itself, so a port found free may be taken
+    /// before it does. It then exits right away, and another free port is
+    /// tried.
+    pub async fn dispatch_one_off(port: Option<u16>, sender: CapEventSender) -> Result<u16> {
+        let attempts = if port.is_some() { 1 } else { ONE_OFF_ATTEMPTS };
+        for _ in 0..attempts {
+            let port = match port {
+                Some(port) => port,
+                None => free_port()?,
+            };
+            let server = IperfServer::one_off(port, sender.clone());
+            let (mut child, reader) = server.spawn()?;
+            tokio::time::sleep(LISTEN_DELAY).await;
+            if let Some(status) = child.try_wait()? {
+                warn!("iperf server on port {} exited with {}", port, status);
+                continue;
+            }
+            tokio::spawn(async move { server.forward(child, reader).await });
+            return Ok(port);
+        }
+        bail!("Failed to start an iperf server")
+    }
+
     /// Launch the server loop on a Tokio task.
     ///
     /// Returns a `JoinHandle` resolving to `Result<()>` when the server stops.
@@ -48,14 +94,30 @@ impl IperfServer {
         info!("Starting iperf server on port {}", port);
 
         // Spawn iperf3 server process
+        let (child, reader) = self.spawn()?;
+        self.forward(child, reader).await
+    }
+
+    /// Spawns `iperf3 -s` with its stdout piped.
+    fn spawn(&self) -> Result<(Child, Lines<BufReader<ChildStdout>>)> {
         let mut cmd = Command::new("iperf3");
-        cmd.args(["-s", "--json", "-p", &port.to_string()]);
+        cmd.args(["-s", "--json", "-p", &self.listen_port.to_string()]);
+        if self.one_off {
+            cmd.arg("-1");
+        }
         cmd.stdout(Stdio::piped());
 
-        let mut child = cmd.spawn().expect("Failed to start iperf server");
-        let stdout = child.stdout.take().expect("Failed to capture stdout");
-        let mut reader = BufReader::new(stdout).lines();
+        let mut child = cmd.spawn().context("Failed to start iperf server")?;
+        let stdout = child.stdout.take().context("Failed to capture stdout")?;
+        Ok((child, BufReader::new(stdout).lines()))
+    }
 
+    /// Sends each result iperf3 prints until it exits.
+    async fn forward(
+        &self,
+        mut child: Child,
+        mut reader: Lines<BufReader<ChildStdout>>,
+    ) -> Result<()> {
         // Separate task to log exit status
         tokio::spawn(async move {
             let status = child.wait().await.expect("Failed to wait on child");
@@ -75,9 +137,8 @@ impl IperfServer {
                 let parsed_json: IperfResponse =
                     serde_json::from_str::<IperfResponse>(&json_buffer)
                         .expect("Failed to parse JSON");
-                self.sender
-                    .send(CapEvent::IperfResponse(parsed_json))
-                    .await.expect("Failed to send iperf response");
+                send_or_err(&self.sender, CapEvent::IperfResponse(parsed_json), "iperf response")
+                    .await?;
                 json_buffer.clear();
             }
         }
@@ -85,20 +146,94 @@ impl IperfServer {
     }
 }
 
+/// Asks the OS for a TCP port that is free for now.
+fn free_port() -> std::io::Result<u16> {
+    let listener = std::net::TcpListener::bind(("0.0.0.0", 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+
+/// Client side options for an iperf3 test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IperfOptions {
+    /// Number of parallel streams (`-P`)
+    pub parallel: u8,
+    /// Use UDP instead of TCP (`-u`)
+    pub udp: bool,
+    /// Target bitrate such as "10M" (`-b`)
+    pub bitrate: Option<String>,
+    /// Server sends, client receives (`-R`)
+    pub reverse: bool,
+}
+
+impl Default for IperfOptions {
+    fn default() -> Self {
+        IperfOptions {
+            parallel: 1,
+            udp: false,
+            bitrate: None,
+            reverse: false,
+        }
+    }
+}
+
+impl IperfOptions {
+    /// Options from the `[probe]` section of the config.
+    pub fn from_config() -> Self {
+        IperfOptions {
+            parallel: CONFIG.probe.iperf_parallel.max(1),
+            udp: CONFIG.probe.iperf_udp,
+            bitrate: CONFIG.probe.iperf_bitrate.clone(),
+            reverse: CONFIG.probe.iperf_reverse,
+        }
+    }
+
+    /// Command line arguments for these options.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.parallel > 1 {
+            args.push("-P".to_string());
+            args.push(self.parallel.to_string());
+        }
+        if self.udp {
+            args.push("-u".to_string());
+        }
+        if let Some(bitrate) = &self.bitrate {
+            args.push("-b".to_string());
+            args.push(bitrate.clone());
+        }
+        if self.reverse {
+            args.push("-R".to_string());
+        }
+        args
+    }
+}
 
 /// Spawns a Tokio task to run a single iperf client test.
 ///
 /// Results are sent back via `sender` as `CapEvent::IperfResponse`.
-pub fn dispatch_iperf_client(dest_ip: String, port: u16, duration: u16, sender: CapEventSender) {
+pub fn dispatch_iperf_client(
+    dest_ip: String,
+    port: u16,
+    duration: u16,
+    options: IperfOptions,
+    sender: CapEventSender,
+) {
     tokio::spawn(async move {
-        do_iperf_test(&dest_ip, port, duration, sender).await;
+        do_iperf_test(&dest_ip, port, duration, &options, sender).await;
     });
 }
 
 /// Executes `iperf3 -c` against `dest_ip:port` for `duration` seconds,
 /// reads JSON output, parses into `IperfResponse`, and forwards
 /// via `sender`.
-pub async fn do_iperf_test(dest_ip: &str, port: u16, duration: u16, sender: CapEventSender) {
+pub async fn do_iperf_test(
+    dest_ip: &str,
+    port: u16,
+    duration: u16,
+    options: &IperfOptions,
+    sender: CapEventSender,
+) {
     // Build and spawn client process
     let mut cmd = Command::new("iperf3");
     cmd.args([
@@ -111,6 +246,7 @@ pub async fn do_iperf_test(dest_ip: &str, port: u16, duration: u16, sender: CapE
         "-t",
         &duration.to_string(),
     ]);
+    cmd.args(options.args());
 
 
     cmd.stdout(Stdio::piped());
@@ -138,10 +274,31 @@ pub async fn do_iperf_test(dest_ip: &str, port: u16, duration: u16, sender: CapE
             // Parse JSON
             let parsed_json =
                 serde_json::from_str::<IperfResponse>(&json_buffer).expect("Failed to parse JSON");
-            sender
-                .send(CapEvent::IperfResponse(parsed_json))
-                .await.expect("Failed to send iperf response");
+            if !send_or_log(&sender, CapEvent::IperfResponse(parsed_json), "iperf response").await {
+                return;
+            }
             json_buffer.clear();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_options_have_no_args() {
+        assert!(IperfOptions::default().args().is_empty());
+    }
+
+    #[test]
+    fn test_options_args() {
+        let options = IperfOptions {
+            parallel: 4,
+            udp: true,
+            bitrate: Some("10M".to_string()),
+            reverse: true,
+        };
+        assert_eq!(options.args(), vec!["-P", "4", "-u", "-b", "10M", "-R"]);
+    }
+}
@@ -1,6 +1,10 @@
+use std::net::IpAddr;
+
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::stream_id::IpPair;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum IperfResponse {
@@ -27,10 +31,90 @@ pub struct Success {
     pub start: Start,
 }
 
+impl Success {
+    /// The end of the test this node was. Without `accepted_connection`,
+    /// as in output older than iperf 3.1, the client.
+    pub fn role(&self) -> IperfRole {
+        match self.start.accepted_connection {
+            Some(_) => IperfRole::Server,
+            None => IperfRole::Client,
+        }
+    }
+
+    /// True if this node sent the test data: the client unless the test was
+    /// reversed with -R. Falls back to `sender` of the summary without
+    /// `test_start`.
+    pub fn sends(&self) -> bool {
+        match (&self.start.test_start, self.end.udp()) {
+            (Some(test), _) => (self.role() == IperfRole::Client) != (test.reverse != 0),
+            (None, Some(udp)) => udp.sender,
+            (None, None) => self.end.sum_sent.sender,
+        }
+    }
+
+    /// What the receiving end got in bits per second, the rate of the path in
+    /// the direction of the test. Both ends report it, the client once the
+    /// server has sent its results.
+    pub fn bits_per_second(&self) -> f64 {
+        if let Some(udp) = self.end.udp() {
+            return udp.bits_per_second;
+        }
+        match self.end.sum_received.bits_per_second {
+            bps if bps > 0.0 => bps,
+            _ => self.end.sum_sent.bits_per_second,
+        }
+    }
+
+    /// The links the test ran over, local first. With -P there is one
+    /// connection per stream, usually all between the same pair of hosts.
+    pub fn links(&self) -> Vec<IpPair> {
+        let mut links = Vec::new();
+        for connected in &self.start.connected {
+            let Some(ip_pair) = connected.ip_pair() else {
+                continue;
+            };
+            if !links.contains(&ip_pair) {
+                links.push(ip_pair);
+            }
+        }
+        links
+    }
+}
+
+/// The end of the test a result is from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IperfRole {
+    Client,
+    Server,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Start {
     pub connected: Vec<Connected>,
+    /// Set on the client.
+    #[serde(rename = "connecting_to", default)]
+    pub connecting_to: Option<HostPort>,
+    /// Set on the server, the control connection of the client.
+    #[serde(rename = "accepted_connection", default)]
+    pub accepted_connection: Option<HostPort>,
+    #[serde(rename = "test_start", default)]
+    pub test_start: Option<TestStart>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostPort {
+    pub host: String,
+    pub port: i64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TestStart {
+    pub protocol: String,
+    pub num_streams: i64,
+    /// 1 if the server sent the data (-R).
+    pub reverse: i64,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -47,6 +131,16 @@ pub struct Connected {
     pub remote_port: i64,
 }
 
+impl Connected {
+    /// The hosts of the connection, with IPv4-mapped addresses of a dual
+    /// stack server as IPv4. None if either does not parse.
+    pub fn ip_pair(&self) -> Option<IpPair> {
+        let local: IpAddr = self.local_host.parse().ok()?;
+        let remote: IpAddr = self.remote_host.parse().ok()?;
+        Some(IpPair::new(local.to_canonical(), remote.to_canonical()))
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Interval {
@@ -71,17 +165,46 @@ pub struct Sum {
 #[serde(rename_all = "camelCase")]
 pub struct End {
     pub streams: Vec<Stream2>,
-    #[serde(rename = "sum_sent")]
+    /// Missing in UDP mode on older iperf3 versions.
+    #[serde(rename = "sum_sent", default)]
     pub sum_sent: SumSent,
-    #[serde(rename = "sum_received")]
+    /// Missing in UDP mode on older iperf3 versions.
+    #[serde(rename = "sum_received", default)]
     pub sum_received: SumReceived,
+    /// UDP mode summary.
+    pub sum: Option<UdpSum>,
+}
+
+impl End {
+    /// Returns the UDP summary if this was a UDP test.
+    pub fn udp(&self) -> Option<&UdpSum> {
+        self.sum.as_ref()
+    }
 }
 
+/// Per stream results. TCP streams carry `sender`/`receiver`, UDP streams `udp`.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Stream2 {
-    pub sender: Sender,
-    pub receiver: Receiver,
+    pub sender: Option<Sender>,
+    pub receiver: Option<Receiver>,
+    pub udp: Option<UdpSum>,
+}
+
+/// UDP results, used both for the end summary and for each stream.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpSum {
+    pub start: f64,
+    pub end: f64,
+    pub seconds: f64,
+    pub bytes: i64,
+    pub bits_per_second: f64,
+    pub jitter_ms: f64,
+    pub lost_packets: i64,
+    pub packets: i64,
+    pub lost_percent: f64,
+    pub sender: bool,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -145,3 +268,121 @@ pub struct SumReceived {
     pub bits_per_second: f64,
     pub sender: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UDP_RESULT: &str = r#"{
+        "start": {
+            "connected": [{
+                "socket": 5,
+                "local_host": "10.0.0.1",
+                "local_port": 43210,
+                "remote_host": "10.0.0.2",
+                "remote_port": 5201
+            }]
+        },
+        "intervals": [{
+            "sum": {
+                "start": 0, "end": 1.0, "seconds": 1.0, "bytes": 131072,
+                "bits_per_second": 1048576, "packets": 90, "omitted": false, "sender": true
+            }
+        }],
+        "end": {
+            "streams": [{
+                "udp": {
+                    "socket": 5, "start": 0, "end": 1.0, "seconds": 1.0, "bytes": 131072,
+                    "bits_per_second": 1048576, "jitter_ms": 0.25, "lost_packets": 9,
+                    "packets": 90, "lost_percent": 10.0, "out_of_order": 0, "sender": true
+                }
+            }],
+            "sum": {
+                "start": 0, "end": 1.0, "seconds": 1.0, "bytes": 131072,
+                "bits_per_second": 1048576, "jitter_ms": 0.25, "lost_packets": 9,
+                "packets": 90, "lost_percent": 10.0, "sender": true
+            }
+        }
+    }"#;
+
+    #[test]
+    fn test_parse_udp_result() {
+        let parsed: IperfResponse = serde_json::from_str(UDP_RESULT).unwrap();
+        let success = match parsed {
+            IperfResponse::Success(s) => s,
+            IperfResponse::Error(e) => panic!("Expected success, got {:?}", e),
+        };
+        let udp = success.end.udp().expect("UDP summary");
+        assert_eq!(udp.lost_packets, 9);
+        assert_eq!(udp.packets, 90);
+        assert!((udp.jitter_ms - 0.25).abs() < f64::EPSILON);
+        assert!((udp.lost_percent - 10.0).abs() < f64::EPSILON);
+
+        let stream = success.end.streams.first().unwrap();
+        assert!(stream.sender.is_none());
+        assert_eq!(stream.udp.as_ref().unwrap().lost_packets, 9);
+    }
+
+    const CLIENT_TCP: &str = include_str!("../../fixtures/iperf/client_tcp.json");
+    const SERVER_TCP: &str = include_str!("../../fixtures/iperf/server_tcp.json");
+    const SERVER_REVERSE: &str = include_str!("../../fixtures/iperf/server_reverse.json");
+
+    fn success(json: &str) -> Success {
+        match serde_json::from_str(json).unwrap() {
+            IperfResponse::Success(s) => s,
+            IperfResponse::Error(e) => panic!("Expected success, got {:?}", e),
+        }
+    }
+
+    /// Local and remote of each link, `IpPair` equality ignores the order.
+    fn hosts(success: &Success) -> Vec<(IpAddr, IpAddr)> {
+        success.links().iter().map(|l| (l.local(), l.remote())).collect()
+    }
+
+    #[test]
+    fn test_client_and_server_of_one_test() {
+        // Both ends of `iperf3 -c 10.0.0.2 -P 2 -t 1 -J`
+        let (a, b): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let client = success(CLIENT_TCP);
+        assert_eq!(client.role(), IperfRole::Client);
+        assert!(client.sends());
+        assert_eq!(hosts(&client), vec![(a, b)]);
+
+        let server = success(SERVER_TCP);
+        assert_eq!(server.role(), IperfRole::Server);
+        assert!(!server.sends());
+        assert!(!server.end.sum_sent.sender);
+        assert_eq!(hosts(&server), vec![(b, a)]);
+
+        // Both report what the server received
+        assert_eq!(client.bits_per_second(), 82032457.3);
+        assert_eq!(server.bits_per_second(), client.bits_per_second());
+    }
+
+    #[test]
+    fn test_reverse_server() {
+        // Server end of `iperf3 -c 10.0.0.2 -R -t 1 -J` on a dual stack socket
+        let (a, b): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let server = success(SERVER_REVERSE);
+        assert_eq!(server.role(), IperfRole::Server);
+        assert!(server.sends());
+        assert_eq!(hosts(&server), vec![(b, a)]);
+        assert_eq!(server.bits_per_second(), 89110159.6);
+    }
+
+    #[test]
+    fn test_without_test_start() {
+        // Older output only tells the direction through `sender`
+        let udp = success(UDP_RESULT);
+        assert_eq!(udp.role(), IperfRole::Client);
+        assert!(udp.sends());
+        assert_eq!(udp.bits_per_second(), 1048576.0);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let parsed: IperfResponse =
+            serde_json::from_str(r#"{"error": "unable to connect to server"}"#).unwrap();
+        assert!(matches!(parsed, IperfResponse::Error(_)));
+    }
+}
@@ -1,4 +1,7 @@
 pub mod iperf;
 pub mod iperf_json;
 pub mod pathload;
-pub mod ping;
\ No newline at end of file
+pub mod ping;
+pub mod result;
+pub mod session;
+pub mod train;
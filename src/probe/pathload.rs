@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use std::process::Stdio;
 
 use tokio::io::{AsyncBufReadExt, BufReader};
@@ -5,6 +6,8 @@ use tokio::io::{AsyncBufReadExt, BufReader};
 use log::info;
 use tokio::process::Command;
 
+use crate::channel::send_or_log;
+use crate::probe::result::{ProbeOutcome, ProbeResult};
 use crate::*;
 
 pub fn dispatch_server() -> tokio::task::JoinHandle<()> {
@@ -22,17 +25,74 @@ pub fn dispatch_server() -> tokio::task::JoinHandle<()> {
     })
 }
 
-pub fn dispatch_pathload_client(sender: CapEventSender, ip_addr: String) {
+/// Starts a `pathload_snd` that serves a single receiver and then exits.
+///
+/// Used when a peer has asked for a pathload probe session.
+pub fn dispatch_single_server() -> tokio::task::JoinHandle<()> {
+    info!("Starting single session pathload_snd");
+    let mut cmd = Command::new("pathload_snd");
+
+    cmd.args(["-q"]);
+
+    tokio::spawn(async move {
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                info!("Failed to start pathload server: {}", e);
+                return;
+            }
+        };
+        match child.wait().await {
+            Ok(status) => info!("pathload server exited with: {}", status),
+            Err(e) => info!("Failed to wait on pathload server: {}", e),
+        }
+    })
+}
+
+/// Available bandwidth range reported by `pathload_rcv`, in bytes/sec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathloadResult {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl PathloadResult {
+    /// Parses the `PATHLOAD.ABWL` and `PATHLOAD.ABWH` fields of a NetLogger
+    /// line as written with `-N`, in Mbps.
+    pub fn parse(line: &str) -> Option<Self> {
+        let field = |name: &str| -> Option<f64> {
+            let value = line
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))?;
+            let mbps: f64 = value.strip_suffix("Mbps").unwrap_or(value).parse().ok()?;
+            Some(mbps * 1e6 / 8.0)
+        };
+        Some(PathloadResult {
+            low: field("PATHLOAD.ABWL")?,
+            high: field("PATHLOAD.ABWH")?,
+        })
+    }
+
+    /// Middle of the range.
+    pub fn abw(&self) -> f64 {
+        (self.low + self.high) / 2.0
+    }
+}
+
+pub fn dispatch_pathload_client(sender: CapEventSender, peer: IpAddr, probe_id: Option<u64>) {
     tokio::spawn(async move {
-        do_pathload_test(sender, ip_addr).await;
+        do_pathload_test(sender, peer, probe_id).await;
     });
 }
 
-pub async fn do_pathload_test(sender: CapEventSender, ip_addr: String) {
+/// Runs `pathload_rcv` against `peer` and sends each result it reports as a
+/// `CapEvent::Probe`.
+pub async fn do_pathload_test(sender: CapEventSender, peer: IpAddr, probe_id: Option<u64>) {
     info!("Starting pathload_rcv");
+    let started = Timestamp::now();
     let mut cmd = Command::new("pathload_rcv");
 
-    cmd.args(["-q", "-s", &ip_addr, "-N", "/dev/stdout"]);
+    cmd.args(["-q", "-s", &peer.to_string(), "-N", "/dev/stdout"]);
     cmd.stdout(Stdio::piped());
 
     let mut child = cmd.spawn().expect("Failed to start pathload_rcv");
@@ -48,12 +108,30 @@ pub async fn do_pathload_test(sender: CapEventSender, ip_addr: String) {
     });
 
     while let Some(line) = reader.next_line().await.unwrap() {
-        if line.starts_with("DATE=") {
-            sender.send(CapEvent::PathloadResponse(line)).await.unwrap_or_else(
-                |e| {
-                    info!("Failed to send pathload response: {}", e)
-                }
-            );
+        if !line.starts_with("DATE=") {
+            continue;
         }
+        let Some(result) = PathloadResult::parse(&line) else {
+            info!("No available bandwidth in pathload response: {:?}", line);
+            continue;
+        };
+        let result = ProbeResult::new(peer, probe_id, started, ProbeOutcome::Pathload(result));
+        send_or_log(&sender, CapEvent::Probe(result), "pathload result").await;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_netlogger_line() {
+        let line = "DATE=20240101120000.000000 HOST=rcv PROG=pathload LVL=Usage \
+                    PATHLOAD.SNDR=snd PATHLOAD.FLEETS=8 PATHLOAD.BYTES_RECV=1000 \
+                    PATHLOAD.ABWL=40.0Mbps PATHLOAD.ABWH=48.0Mbps PATHLOAD.EXTSTAT=NOTAVAIL";
+        let result = PathloadResult::parse(line).unwrap();
+        assert_eq!((result.low, result.high), (5e6, 6e6));
+        assert_eq!(result.abw(), 5.5e6);
+        assert_eq!(PathloadResult::parse("DATE=20240101120000.000000 HOST=rcv"), None);
+    }
+}
@@ -1,12 +1,26 @@
-/// Partial implementation of an active probing module using ICMP echo requests.
-/// Needs further development and testing.
-use std::collections::HashMap;
+//! Active probing with ICMP echo requests.
+//!
+//! Besides single echoes, `PingManager` sends ping trains: echoes of several
+//! sizes, all sent back to back in one burst before the replies are in. The
+//! smallest RTT of each size leaves out the queueing, and grows with the
+//! size by the time the path takes to serialize the extra bytes. The slope
+//! of a line through them is the serialization delay per byte, which gives
+//! the capacity, as in pathchar. An echo reply is as large as the request,
+//! so the bytes cross the path twice. Over several hops the delays add up
+//! and the capacity comes out below that of the narrowest hop.
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
+use std::time::Duration;
+
+use futures::future::join_all;
+use log::warn;
 use rand::random;
 use surge_ping::{Client, Config, PingIdentifier, PingSequence, SurgeError, ICMP};
 use tokio::sync::mpsc;
 
-use crate::{CapEvent, CapEventSender};
+use crate::channel::send_or_log;
+use crate::probe::result::{ProbeOutcome, ProbeResult};
+use crate::{CapEvent, CapEventSender, Timestamp, CONFIG};
 
 /// Commands sent to the PingManager.
 pub enum PingCommand {
@@ -21,29 +35,145 @@ pub enum PingCommand {
         seq: PingSequence,
         payload: Vec<u8>,
     },
+    /// Send a ping train to the host, `rounds` echoes of each of `sizes`
+    /// bytes of payload.
+    Train {
+        host: IpAddr,
+        sizes: Vec<usize>,
+        rounds: u32,
+    },
+}
+
+impl PingCommand {
+    /// A train with the sizes and rounds from the `[probe]` section.
+    pub fn train_from_config(host: IpAddr) -> Self {
+        PingCommand::Train {
+            host,
+            sizes: CONFIG.probe.ping_train_sizes.clone(),
+            rounds: CONFIG.probe.ping_train_rounds,
+        }
+    }
+}
+
+/// Reply to a single echo request.
+#[derive(Debug)]
+pub struct PingReply {
+    pub seq: u16,
+    /// ICMP payload in bytes.
+    pub size: usize,
+    pub rtt: Result<Duration, SurgeError>,
+}
+
+/// RTTs of the echoes of one size in a ping train.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeRtt {
+    /// ICMP payload in bytes.
+    pub size: usize,
+    pub sent: u32,
+    pub received: u32,
+    /// None if no reply came back.
+    pub min_rtt: Option<Duration>,
+    pub mean_rtt: Option<Duration>,
+}
+
+/// Outcome of a ping train.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingTrainResult {
+    /// Smallest size first.
+    pub sizes: Vec<SizeRtt>,
+    /// Serialization delay per payload byte there and back, in seconds.
+    /// None with replies for fewer than two sizes, or if the RTT does not
+    /// grow with the size.
+    pub delay_per_byte: Option<f64>,
+}
+
+impl PingTrainResult {
+    /// Summarizes the size and RTT of each echo sent, None for those lost.
+    pub fn new(echoes: &[(usize, Option<Duration>)]) -> Self {
+        let mut by_size: BTreeMap<usize, Vec<Option<Duration>>> = BTreeMap::new();
+        for &(size, rtt) in echoes {
+            by_size.entry(size).or_default().push(rtt);
+        }
+        let sizes: Vec<SizeRtt> = by_size
+            .into_iter()
+            .map(|(size, rtts)| {
+                let replies: Vec<Duration> = rtts.iter().flatten().copied().collect();
+                let mean_rtt = (!replies.is_empty())
+                    .then(|| replies.iter().sum::<Duration>() / replies.len() as u32);
+                SizeRtt {
+                    size,
+                    sent: rtts.len() as u32,
+                    received: replies.len() as u32,
+                    min_rtt: replies.iter().min().copied(),
+                    mean_rtt,
+                }
+            })
+            .collect();
+        let delay_per_byte = min_rtt_slope(&sizes);
+        PingTrainResult {
+            sizes,
+            delay_per_byte,
+        }
+    }
+
+    /// Time to serialize `size` bytes of payload there and back.
+    pub fn serialization_delay(&self, size: usize) -> Option<Duration> {
+        self.delay_per_byte
+            .map(|delay| Duration::from_secs_f64(delay * size as f64))
+    }
+
+    /// Capacity of the path in bytes/sec.
+    pub fn capacity(&self) -> Option<f64> {
+        // Every byte crosses the path twice
+        self.delay_per_byte.map(|delay| 2.0 / delay)
+    }
+}
+
+/// Least squares slope of the smallest RTT in seconds over the size.
+fn min_rtt_slope(sizes: &[SizeRtt]) -> Option<f64> {
+    let points: Vec<(f64, f64)> = sizes
+        .iter()
+        .filter_map(|s| Some((s.size as f64, s.min_rtt?.as_secs_f64())))
+        .collect();
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let slope = sxy / sxx;
+    (slope > 0.0).then_some(slope)
 }
 
-/// Manages pingers for different hosts.
+/// Commands waiting for the task of a host. Beyond these, further ones are
+/// dropped.
+const HOST_QUEUE: usize = 16;
+
+/// Hands the commands for each host to a task of its own, so a host that
+/// does not answer only holds up its own echoes.
 pub struct PingManager {
-    // Stores an active pinger for each host.
-    pingers: HashMap<IpAddr, surge_ping::Pinger>,
+    /// Commands for the task of each host.
+    hosts: HashMap<IpAddr, mpsc::Sender<PingCommand>>,
     clientv4: Client,
     clientv6: Client,
     sender: CapEventSender,
 }
 
 impl PingManager {
-    pub fn new(sender: CapEventSender) -> Self {
-        Self {
-            pingers: HashMap::new(),
-            clientv4: PingManager::default_config(ICMP::V4),
-            clientv6: PingManager::default_config(ICMP::V6),
+    /// Fails if the ICMP sockets cannot be opened, e.g. without CAP_NET_RAW.
+    pub fn new(sender: CapEventSender) -> std::io::Result<Self> {
+        Ok(Self {
+            hosts: HashMap::new(),
+            clientv4: PingManager::default_config(ICMP::V4)?,
+            clientv6: PingManager::default_config(ICMP::V6)?,
             sender,
-        }
+        })
     }
 
-    fn default_config(kind: ICMP) -> Client {
-        Client::new(&Config::builder().kind(kind).build()).unwrap()
+    fn default_config(kind: ICMP) -> std::io::Result<Client> {
+        Client::new(&Config::builder().kind(kind).build())
     }
 
     fn get_client(&self, host: &IpAddr) -> &Client {
@@ -53,47 +183,128 @@ impl PingManager {
         }
     }
 
-    /// Creates and stores a pinger for the given host using the provided Config.
-    async fn create_pinger(&mut self, host: IpAddr, config: Config) -> Result<(), SurgeError> {
-        let client = Client::new(&config)?;
+    /// Starts the task pinging `host` with `client`, replacing any earlier
+    /// one.
+    async fn spawn(&mut self, host: IpAddr, client: Client) {
+        let (tx, rx) = mpsc::channel(HOST_QUEUE);
         let pinger = client.pinger(host, PingIdentifier(random())).await;
-        self.pingers.insert(host, pinger);
-        Ok(())
-    }
-
-    /// Gets an existing pinger for the host or creates one with a default Config.
-    async fn get_or_create_pinger(&mut self, host: IpAddr) -> Result<&mut surge_ping::Pinger, SurgeError> {
-        if !self.pingers.contains_key(&host) {
-            // Use a default configuration based on the IP type.
-            let client = self.get_client(&host);
-            let pinger = client.pinger(host, PingIdentifier(random())).await;
-            self.pingers.insert(host, pinger);
-        }
-        Ok(self.pingers.get_mut(&host).unwrap())
+        let host_pinger = HostPinger {
+            host,
+            client,
+            pinger,
+            sender: self.sender.clone(),
+        };
+        tokio::spawn(host_pinger.run(rx));
+        self.hosts.insert(host, tx);
     }
 
     /// Event loop for handling incoming ping commands.
     pub async fn run(mut self, mut rx: mpsc::Receiver<PingCommand>) {
         while let Some(cmd) = rx.recv().await {
-            match cmd {
+            let host = match &cmd {
                 PingCommand::Register { host, config } => {
-                    let res = self.create_pinger(host, config).await;
-                    if let Err(e) = res {
-                        let _ = self.sender.send(CapEvent::PingResponse(Err(e)));
+                    match Client::new(config) {
+                        Ok(client) => self.spawn(*host, client).await,
+                        Err(e) => warn!("Failed to register {} for pings: {}", host, e),
                     }
+                    continue;
                 }
-                PingCommand::Ping { host, seq, payload } => {
-                    let result = match self.get_or_create_pinger(host).await {
-                        Ok(pinger) => {
-                            pinger.ping(seq, &payload)
-                                .await
-                                .map(|(_packet, duration)| duration)
-                        }
-                        Err(e) => Err(e),
-                    };
-                    let _ = self.sender.send(CapEvent::PingResponse(result));
-                }
+                PingCommand::Ping { host, .. } | PingCommand::Train { host, .. } => *host,
+            };
+            if self.hosts.get(&host).is_none_or(mpsc::Sender::is_closed) {
+                let client = self.get_client(&host).clone();
+                self.spawn(host, client).await;
+            }
+            if self.hosts[&host].try_send(cmd).is_err() {
+                warn!("Pings to {} are backed up, dropping one", host);
             }
         }
     }
 }
+
+/// Pings one host, one command after the other.
+struct HostPinger {
+    host: IpAddr,
+    client: Client,
+    pinger: surge_ping::Pinger,
+    sender: CapEventSender,
+}
+
+impl HostPinger {
+    async fn run(mut self, mut rx: mpsc::Receiver<PingCommand>) {
+        while let Some(cmd) = rx.recv().await {
+            let started = Timestamp::now();
+            let outcome = match cmd {
+                PingCommand::Ping { seq, payload, .. } => {
+                    let rtt = self
+                        .pinger
+                        .ping(seq, &payload)
+                        .await
+                        .map(|(_packet, duration)| duration);
+                    ProbeOutcome::Ping(PingReply {
+                        seq: seq.0,
+                        size: payload.len(),
+                        rtt,
+                    })
+                }
+                PingCommand::Train { sizes, rounds, .. } => {
+                    ProbeOutcome::PingTrain(self.train(&sizes, rounds).await)
+                }
+                PingCommand::Register { .. } => continue,
+            };
+            let result = ProbeResult::new(self.host, None, started, outcome);
+            send_or_log(&self.sender, CapEvent::Probe(result), "ping result").await;
+        }
+    }
+
+    /// Sends the echoes of a train back to back, every size once per round,
+    /// and waits for all the replies. Each echo has its own pinger, sharing
+    /// the identifier, so none waits for the reply to the one before.
+    async fn train(&self, sizes: &[usize], rounds: u32) -> PingTrainResult {
+        let ident = PingIdentifier(random());
+        let echo_sizes = (0..rounds).flat_map(|_| sizes.iter().copied());
+        let mut echoes = Vec::new();
+        for (seq, size) in echo_sizes.enumerate() {
+            let mut pinger = self.client.pinger(self.host, ident).await;
+            echoes.push(async move {
+                let rtt = pinger.ping(PingSequence(seq as u16), &vec![0; size]).await;
+                (size, rtt.ok().map(|(_packet, rtt)| rtt))
+            });
+        }
+        // The first poll of each sends its echo
+        PingTrainResult::new(&join_all(echoes).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_train_capacity() {
+        let us = |us: u64| Some(Duration::from_micros(us));
+        // 1 ms base RTT, 1000 bytes take 0.2 ms there and back
+        let echoes = [
+            (1000, us(1500)),
+            (0, us(1000)),
+            (500, us(1100)),
+            (1000, us(1200)),
+            (500, None),
+            (0, us(1300)),
+        ];
+        let result = PingTrainResult::new(&echoes);
+        assert_eq!(result.sizes.len(), 3);
+        assert_eq!(result.sizes[0].size, 0);
+        assert_eq!((result.sizes[1].sent, result.sizes[1].received), (2, 1));
+        assert_eq!(result.sizes[2].min_rtt, us(1200));
+        assert_eq!(result.sizes[2].mean_rtt, us(1350));
+        let capacity = result.capacity().unwrap();
+        assert!((capacity - 10_000_000.0).abs() < 1.0, "{}", capacity);
+        let delay = result.serialization_delay(500).unwrap();
+        assert!((delay.as_secs_f64() - 0.0001).abs() < 1e-9);
+
+        // A single size gives no slope
+        let result = PingTrainResult::new(&[(64, us(1000)), (64, us(2000))]);
+        assert_eq!(result.capacity(), None);
+    }
+}
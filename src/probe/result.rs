@@ -0,0 +1,53 @@
+//! Results of active probes as sent to the parser, each tagged with the host
+//! it probed and the session it belonged to, so it is recorded on the link
+//! that was probed.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::probe::pathload::PathloadResult;
+use crate::probe::ping::{PingReply, PingTrainResult};
+use crate::probe::train::TrainResult;
+use crate::Timestamp;
+
+/// What a probe measured.
+#[derive(Debug)]
+pub enum ProbeOutcome {
+    Ping(PingReply),
+    /// RTTs by size, see `probe::ping`.
+    PingTrain(PingTrainResult),
+    /// Samples from packet trains the target sent to this node.
+    Train(TrainResult),
+    Pathload(PathloadResult),
+}
+
+/// An active probe of a host and what it measured.
+#[derive(Debug)]
+pub struct ProbeResult {
+    /// The host on the other end of the probed path.
+    pub target: IpAddr,
+    /// Id of the negotiated session, None for probes without one.
+    pub probe_id: Option<u64>,
+    pub started: Timestamp,
+    /// From the start of the probe to its result.
+    pub elapsed: Duration,
+    pub outcome: ProbeOutcome,
+}
+
+impl ProbeResult {
+    /// The result of a probe of `target` started at `started`, ending now.
+    pub fn new(
+        target: IpAddr,
+        probe_id: Option<u64>,
+        started: Timestamp,
+        outcome: ProbeOutcome,
+    ) -> Self {
+        ProbeResult {
+            target,
+            probe_id,
+            started,
+            elapsed: Timestamp::now().saturating_duration_since(started),
+            outcome,
+        }
+    }
+}
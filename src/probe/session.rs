@@ -0,0 +1,124 @@
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tokio::time::{Duration, Instant};
+
+/// Longest probe a peer will agree to run.
+pub const MAX_PROBE_DURATION: Duration = Duration::from_secs(60);
+/// How long after its nominal end a session is still considered active,
+/// covering setup time and the final result exchange.
+pub const PROBE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// Fixed ports used by pathload (UDP probing and TCP control).
+pub const PATHLOAD_PORTS: [u16; 2] = [55001, 55002];
+
+/// Active probing techniques that can be negotiated between peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeTechnique {
+    Iperf3,
+    Pathload,
+    /// UDP packet trains, see `probe::train`.
+    Train,
+}
+
+impl ProbeTechnique {
+    /// Name used in the proto messages and the config file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeTechnique::Iperf3 => "iperf3",
+            ProbeTechnique::Pathload => "pathload",
+            ProbeTechnique::Train => "train",
+        }
+    }
+}
+
+impl FromStr for ProbeTechnique {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "iperf3" => Ok(ProbeTechnique::Iperf3),
+            "pathload" => Ok(ProbeTechnique::Pathload),
+            "train" => Ok(ProbeTechnique::Train),
+            _ => Err(anyhow::anyhow!("Unknown probe technique: {}", s)),
+        }
+    }
+}
+
+impl Display for ProbeTechnique {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An active probe agreed on by both peers.
+///
+/// Both the initiator and the peer report the session to their parser, so the
+/// traffic it generates can be told apart from passively observed traffic.
+#[derive(Debug, Clone)]
+pub struct ProbeSession {
+    /// Identifier assigned by the peer that accepted the probe.
+    pub id: u64,
+    /// The other end of the probe.
+    pub peer: IpAddr,
+    /// Port the probe server listens on (0 if the tool uses fixed ports).
+    pub port: u16,
+    pub technique: ProbeTechnique,
+    pub duration: Duration,
+    /// When the session was agreed on.
+    pub started: Instant,
+}
+
+impl ProbeSession {
+    pub fn new(id: u64, peer: IpAddr, port: u16, technique: ProbeTechnique, duration: Duration) -> Self {
+        ProbeSession {
+            id,
+            peer,
+            port,
+            technique,
+            duration,
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns true while the probe may still be generating traffic.
+    pub fn is_active(&self) -> bool {
+        self.started.elapsed() < self.duration + PROBE_GRACE_PERIOD
+    }
+
+    /// Returns true if `port` belongs to this session.
+    pub fn uses_port(&self, port: u16) -> bool {
+        match self.technique {
+            ProbeTechnique::Iperf3 | ProbeTechnique::Train => port == self.port,
+            ProbeTechnique::Pathload => PATHLOAD_PORTS.contains(&port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_technique_round_trip() {
+        for technique in [ProbeTechnique::Iperf3, ProbeTechnique::Pathload, ProbeTechnique::Train] {
+            assert_eq!(technique.as_str().parse::<ProbeTechnique>().unwrap(), technique);
+        }
+        assert_eq!("IPERF3".parse::<ProbeTechnique>().unwrap(), ProbeTechnique::Iperf3);
+        assert!("trex".parse::<ProbeTechnique>().is_err());
+    }
+
+    #[test]
+    fn test_session_ports() {
+        let peer: IpAddr = [10, 0, 0, 1].into();
+        let session = ProbeSession::new(1, peer, 5300, ProbeTechnique::Iperf3, Duration::from_secs(5));
+        assert!(session.uses_port(5300));
+        assert!(!session.uses_port(55001));
+        assert!(session.is_active());
+
+        let session = ProbeSession::new(2, peer, 0, ProbeTechnique::Pathload, Duration::from_secs(5));
+        assert!(session.uses_port(55001));
+        assert!(session.uses_port(55002));
+        assert!(!session.uses_port(5300));
+    }
+}
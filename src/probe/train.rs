@@ -0,0 +1,314 @@
+//! Active probing with short UDP packet trains, without external tools.
+//!
+//! The initiator sends trains of equally sized packets at a fixed rate to a
+//! port the peer opened for the session. The peer timestamps the arrivals and
+//! takes the rate the packets left the path at, the bytes after the first
+//! packet over the time from the first to the last arrival, as one ABW sample
+//! per train. The bottleneck only spreads out trains sent faster than the
+//! available bandwidth, so the rate should be at or above the expected ABW.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+use crate::probe::result::{ProbeOutcome, ProbeResult};
+use crate::probe::session::PROBE_GRACE_PERIOD;
+use crate::{CapEvent, CapEventSender, Timestamp, CONFIG};
+
+/// Probe id, train index and sequence number.
+pub const TRAIN_HEADER: u32 = 12;
+/// Largest UDP payload that fits in a 1500 byte MTU over IPv4.
+pub const MAX_TRAIN_PACKET: u32 = 1472;
+pub const MAX_TRAIN_LENGTH: u32 = 1000;
+/// Fastest rate a train is sent at in bits/sec.
+pub const MAX_TRAIN_RATE: u64 = 10_000_000_000;
+/// Time between the start of two trains. A train has to fit within it.
+pub const TRAIN_INTERVAL: Duration = Duration::from_millis(200);
+/// Share of a train that has to arrive for it to give a sample.
+const MIN_TRAIN_ARRIVALS: f64 = 0.5;
+/// Longest wait spun on rather than slept, sleeps overshoot by about as much.
+const MAX_SPIN: Duration = Duration::from_micros(200);
+
+/// Shape of the trains in a session, agreed on in the probe request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrainParams {
+    /// Packets per train.
+    pub length: u32,
+    /// UDP payload per packet in bytes, header included.
+    pub packet_size: u32,
+    /// Sending rate within a train in bits/sec.
+    pub rate: u64,
+}
+
+impl TrainParams {
+    /// Parameters from the `[probe]` section of the config. A rate of 0
+    /// means `client.link_phy_cap`.
+    pub fn from_config() -> Self {
+        TrainParams {
+            length: CONFIG.probe.train_length,
+            packet_size: CONFIG.probe.train_packet_size,
+            rate: match CONFIG.probe.train_rate {
+                0 => CONFIG.client.link_phy_cap as u64,
+                rate => rate,
+            },
+        }
+    }
+
+    /// Checks the parameters a peer asked for.
+    pub fn validate(&self) -> Result<()> {
+        if self.length < 2 || self.length > MAX_TRAIN_LENGTH {
+            return Err(anyhow!("Train length must be between 2 and {}", MAX_TRAIN_LENGTH));
+        }
+        if self.packet_size < TRAIN_HEADER || self.packet_size > MAX_TRAIN_PACKET {
+            return Err(anyhow!(
+                "Packet size must be between {} and {} bytes",
+                TRAIN_HEADER,
+                MAX_TRAIN_PACKET
+            ));
+        }
+        if self.rate == 0 || self.rate > MAX_TRAIN_RATE {
+            return Err(anyhow!("Train rate must be between 1 and {} bits/sec", MAX_TRAIN_RATE));
+        }
+        if self.packet_gap() * (self.length - 1) > TRAIN_INTERVAL {
+            return Err(anyhow!("Train takes longer than {:?} to send", TRAIN_INTERVAL));
+        }
+        Ok(())
+    }
+
+    /// Time between two packets in a train.
+    pub fn packet_gap(&self) -> Duration {
+        Duration::from_secs_f64(self.packet_size as f64 * 8.0 / self.rate as f64)
+    }
+}
+
+/// Samples collected by the receiving end of a session.
+#[derive(Debug, Clone)]
+pub struct TrainResult {
+    /// Arrival rate in bytes/sec of each train that got through.
+    pub samples: Vec<f64>,
+}
+
+fn encode_header(buf: &mut [u8], probe_id: u64, train: u16, seq: u16) {
+    buf[..8].copy_from_slice(&probe_id.to_be_bytes());
+    buf[8..10].copy_from_slice(&train.to_be_bytes());
+    buf[10..12].copy_from_slice(&seq.to_be_bytes());
+}
+
+fn decode_header(buf: &[u8]) -> Option<(u64, u16, u16)> {
+    if buf.len() < TRAIN_HEADER as usize {
+        return None;
+    }
+    Some((
+        u64::from_be_bytes(buf[..8].try_into().ok()?),
+        u16::from_be_bytes(buf[8..10].try_into().ok()?),
+        u16::from_be_bytes(buf[10..12].try_into().ok()?),
+    ))
+}
+
+/// Rate the packets of one train arrived at, in bytes/sec.
+///
+/// `arrivals` holds the arrival time of each packet received. None if too
+/// much of the train was lost.
+pub fn dispersion_rate(arrivals: &[Timestamp], params: &TrainParams) -> Option<f64> {
+    let needed = (params.length as f64 * MIN_TRAIN_ARRIVALS).max(2.0);
+    if (arrivals.len() as f64) < needed {
+        return None;
+    }
+    let first = arrivals.iter().min()?;
+    let last = arrivals.iter().max()?;
+    let elapsed = last.checked_duration_since(*first)?.as_secs_f64();
+    if elapsed <= 0.0 {
+        return None;
+    }
+    Some((arrivals.len() - 1) as f64 * params.packet_size as f64 / elapsed)
+}
+
+/// Number of trains sent in a session lasting `duration`.
+fn train_count(duration: Duration) -> u32 {
+    ((duration.as_secs_f64() / TRAIN_INTERVAL.as_secs_f64()) as u32).clamp(1, u16::MAX as u32)
+}
+
+fn unspecified(peer: IpAddr, port: u16) -> SocketAddr {
+    match peer {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+    }
+}
+
+/// Opens `port` (0 picks a free one) for a session with `peer` and collects
+/// its trains in the background until the session is over. The samples are
+/// sent as a `CapEvent::Probe` with `peer` as the target.
+///
+/// Returns the port listened on.
+pub async fn dispatch_receiver(
+    peer: IpAddr,
+    port: u16,
+    probe_id: u64,
+    params: TrainParams,
+    duration: Duration,
+    sender: CapEventSender,
+) -> std::io::Result<u16> {
+    let started = Timestamp::now();
+    let socket = UdpSocket::bind(unspecified(peer, port)).await?;
+    let port = socket.local_addr()?.port();
+
+    tokio::spawn(async move {
+        let deadline = Instant::now() + duration + PROBE_GRACE_PERIOD;
+        let mut trains: BTreeMap<u16, BTreeMap<u16, Timestamp>> = BTreeMap::new();
+        let mut buf = vec![0u8; MAX_TRAIN_PACKET as usize];
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            // Stamped in user space, so scheduling delays add some noise
+            let now = Timestamp::now();
+            let (len, from) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Train receiver on port {} failed: {}", port, e);
+                    break;
+                }
+            };
+            if from.ip() != peer {
+                continue;
+            }
+            match decode_header(&buf[..len]) {
+                Some((id, train, seq)) if id == probe_id && (seq as u32) < params.length => {
+                    trains.entry(train).or_default().entry(seq).or_insert(now);
+                }
+                _ => {}
+            }
+        }
+
+        let samples: Vec<f64> = trains
+            .values()
+            .filter_map(|arrivals| {
+                let arrivals: Vec<Timestamp> = arrivals.values().copied().collect();
+                dispersion_rate(&arrivals, &params)
+            })
+            .collect();
+        info!(
+            "Packet train probe {} from {}: {} of {} trains usable",
+            probe_id,
+            peer,
+            samples.len(),
+            trains.len()
+        );
+        let train = TrainResult { samples };
+        let result = ProbeResult::new(peer, Some(probe_id), started, ProbeOutcome::Train(train));
+        if let Err(e) = sender.send(CapEvent::Probe(result)).await {
+            warn!("Failed to send packet train result: {}", e);
+        }
+    });
+    Ok(port)
+}
+
+/// Sends the trains of a session to `peer:port`.
+///
+/// Runs on a blocking thread, as the gaps within a train are far shorter
+/// than what the timer can sleep for. Does nothing if `params` are invalid.
+pub fn dispatch_sender(
+    peer: IpAddr,
+    port: u16,
+    probe_id: u64,
+    params: TrainParams,
+    duration: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = params.validate() {
+            warn!("Not sending packet trains to {}: {}", peer, e);
+            return;
+        }
+        if let Err(e) = send_trains(SocketAddr::new(peer, port), probe_id, &params, duration) {
+            warn!("Packet train probe to {} failed: {}", peer, e);
+        }
+    })
+}
+
+/// Waits until `due`, sleeping for all but the last `MAX_SPIN` of it.
+fn wait_until(due: std::time::Instant) {
+    while let Some(left) = due.checked_duration_since(std::time::Instant::now()) {
+        if left > MAX_SPIN {
+            std::thread::sleep(left - MAX_SPIN);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+fn send_trains(
+    addr: SocketAddr,
+    probe_id: u64,
+    params: &TrainParams,
+    duration: Duration,
+) -> std::io::Result<()> {
+    let socket = std::net::UdpSocket::bind(unspecified(addr.ip(), 0))?;
+    socket.connect(addr)?;
+    let mut buf = vec![0u8; params.packet_size as usize];
+    let gap = params.packet_gap();
+    let trains = train_count(duration);
+
+    let start = std::time::Instant::now();
+    for train in 0..trains {
+        let train_start = start + TRAIN_INTERVAL * train;
+        for seq in 0..params.length {
+            wait_until(train_start + gap * seq);
+            encode_header(&mut buf, probe_id, train as u16, seq as u16);
+            socket.send(&buf)?;
+        }
+    }
+    info!("Sent {} packet trains to {}", trains, addr);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> TrainParams {
+        TrainParams {
+            length: 10,
+            packet_size: 1000,
+            rate: 100_000_000,
+        }
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut buf = vec![0u8; 64];
+        encode_header(&mut buf, 42, 7, 9);
+        assert_eq!(decode_header(&buf), Some((42, 7, 9)));
+        assert_eq!(decode_header(&buf[..8]), None);
+    }
+
+    #[test]
+    fn test_dispersion_rate() {
+        let params = params();
+        // 1000 bytes every 100 µs, 10 MB/s
+        let arrivals: Vec<Timestamp> = (0..10)
+            .map(|i| Timestamp::from_micros(1_000_000 + i * 100))
+            .collect();
+        let rate = dispersion_rate(&arrivals, &params).unwrap();
+        assert!((rate - 1e7).abs() < 1.0, "{}", rate);
+
+        // Less than half the train
+        assert!(dispersion_rate(&arrivals[..4], &params).is_none());
+        // No spread
+        let same = vec![Timestamp::from_micros(1_000_000); 10];
+        assert!(dispersion_rate(&same, &params).is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(params().validate().is_ok());
+        assert!(TrainParams { length: 1, ..params() }.validate().is_err());
+        assert!(TrainParams { packet_size: 4, ..params() }.validate().is_err());
+        assert!(TrainParams { rate: 0, ..params() }.validate().is_err());
+        assert!(TrainParams { rate: MAX_TRAIN_RATE + 1, ..params() }.validate().is_err());
+        assert!(TrainParams { packet_size: MAX_TRAIN_PACKET + 1, ..params() }.validate().is_err());
+        // 1000 packets at 1 Mbit/s take 8 seconds
+        assert!(TrainParams { length: 1000, rate: 1_000_000, ..params() }.validate().is_err());
+    }
+}
@@ -1,27 +1,39 @@
-use crate::probe::iperf::dispatch_iperf_client;
-use crate::probe::pathload::dispatch_pathload_client;
+use crate::anonymize::export_data_msg;
+use crate::channel::{send_or_err, send_or_log};
+use crate::identity;
+use crate::listener::capture::PCAPMeta;
+use crate::listener::tstamp::{self, ClockDomain};
+use crate::probe::iperf::{do_iperf_test, IperfOptions};
+use crate::probe::pathload::do_pathload_test;
+use crate::probe::ping::{PingCommand, PingManager};
+use crate::probe::session::{ProbeSession, ProbeTechnique};
+use crate::probe::train::{self, TrainParams};
+use crate::prost_net::capabilities::local_capabilities;
+use crate::prost_net::metrics_store::MetricsStore;
+use crate::prost_net::clock::ClockSample;
+use crate::prost_net::probe_limiter::{Admission, ProbeLimiter, ProbeStatus};
+use crate::prost_net::trace;
+use crate::prost_net::upstream::{upstreams_from_config, UpstreamQueue};
 use crate::proto_bw::client_data_service_client::ClientDataServiceClient;
-use crate::proto_bw::{BandwidthRequest, DataMsg};
-use crate::{proto_bw, CapEvent, CapEventSender};
+use crate::proto_bw::{data_msg, BandwidthRequest, DataMsg, HelloMessage, NodeStatus, PeerStatus};
+use crate::{proto_bw, CapEvent, CapEventSender, Timestamp};
 use anyhow::{Error, Result};
 use futures::future::join_all;
-use log::info;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use pcap::TimestampType;
 use proto_bw::bandwidth_service_client::BandwidthServiceClient;
-use proto_bw::{HelloReply, HelloRequest};
-use tokio_stream::wrappers::BroadcastStream;
+use proto_bw::{ClockRequest, HelloReply, HelloRequest, ProbeReply, ProbeRequest};
+use surge_ping::PingSequence;
 use tokio_stream::StreamExt;
 use tonic::Request;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{oneshot, watch, OwnedSemaphorePermit};
 use tokio::task::JoinHandle;
 use tokio::time::{timeout, Duration, Instant};
-use bytes::BytesMut;
-use futures::SinkExt;
-use prost::Message;
-use tokio::net::TcpStream;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 /// Events that the client task can respond to.
 #[derive(Debug)]
@@ -29,6 +41,16 @@ pub enum ClientEvent {
     /// Sends a hello message to the given IP.
     /// The provided `reply_tx` will receive the result.
     SendHello { message: String },
+    /// Asks the peer to prepare a probe session.
+    /// The peer's answer is sent on `reply`.
+    RequestProbe {
+        request: ProbeRequest,
+        reply: oneshot::Sender<Result<ProbeReply, Error>>,
+    },
+    /// Runs one clock offset exchange with the peer.
+    SyncClock,
+    /// Checks that the peer still answers, the result is sent on `reply`.
+    HealthCheck { reply: oneshot::Sender<bool> },
     /// Stops the client task.
     Stop,
 }
@@ -37,10 +59,35 @@ pub enum ClientHandlerEvent {
     InitClients { ips: Vec<IpAddr> },
     SendHello { ip: IpAddr, message: String },
     BroadcastHello { message: String },
+    /// Runs a clock offset exchange with every connected peer.
+    SyncClocks,
     Stop,
-    DoIperf3(String, u16, u16),
+    /// Run iperf3 against (ip, port) for a duration in seconds, without
+    /// negotiating with the peer.
+    DoIperf3(String, u16, u16, IperfOptions),
     DoPathloadTest(String),
+    /// Negotiate a probe with the peer and run it once the peer has accepted.
+    RequestProbe {
+        ip: IpAddr,
+        technique: ProbeTechnique,
+        duration: u16,
+    },
     SendDataMsg(DataMsg),
+    /// Pings `ip` `count` times, `interval` apart. The replies are
+    /// attributed to the link toward `ip`.
+    DoPing {
+        ip: IpAddr,
+        count: u16,
+        interval: Duration,
+    },
+    /// Runs a ping train toward `ip` as configured in `[probe]`, to measure
+    /// the path capacity.
+    DoPingTrain { ip: IpAddr },
+    /// Adds the peers and probes to a status report on its way to `reply`.
+    Status {
+        status: NodeStatus,
+        reply: oneshot::Sender<NodeStatus>,
+    },
 }
 
 pub enum ClientStatus {
@@ -67,41 +114,215 @@ impl ClientStatus {
 
 #[derive(Debug)]
 pub enum ClientEventResult {
-    HelloReply(Result<HelloReply, tonic::Status>),
+    /// Answer to a hello sent to the peer at the given IP.
+    HelloReply(String, Result<HelloReply, tonic::Status>),
     ServerConnectError(Error),
     ServerConnected(String),
+    /// Result of a clock offset exchange with the peer at the given IP.
+    ClockSample(String, ClockSample),
+    /// The peer has not been reachable for `client.peer_grace_period` and
+    /// was dropped. It is connected to again if it shows up in `InitClients`.
+    PeerRemoved(IpAddr),
+    /// What the probe limiter did with a probe request.
+    ProbeStatus {
+        ip: IpAddr,
+        technique: ProbeTechnique,
+        status: ProbeStatus,
+    },
+}
+
+/// A probe waiting for, or holding, a slot in the probe limiter.
+#[derive(Debug)]
+enum ProbeJob {
+    /// Negotiated with the peer before it runs.
+    Negotiated {
+        technique: ProbeTechnique,
+        duration: u16,
+    },
+    Iperf3 {
+        port: u16,
+        duration: u16,
+        options: IperfOptions,
+    },
+    Pathload,
+}
+
+impl ProbeJob {
+    fn technique(&self) -> ProbeTechnique {
+        match self {
+            ProbeJob::Negotiated { technique, .. } => *technique,
+            ProbeJob::Iperf3 { .. } => ProbeTechnique::Iperf3,
+            ProbeJob::Pathload => ProbeTechnique::Pathload,
+        }
+    }
 }
 
 pub type OuterClient = (Sender<ClientEvent>, tokio::task::JoinHandle<()>);
 
 pub struct BwClient {
+    /// Address of the peer.
+    ip: String,
     event_rx: Receiver<ClientEvent>,
     reply_tx: Sender<ClientEventResult>,
     connection: BandwidthServiceClient<tonic::transport::Channel>,
     status: Option<ClientStatus>,
 }
 
+/// Connection state of one peer.
+struct Peer {
+    client: Option<OuterClient>,
+    /// Failed connects and health checks since the peer last answered.
+    failures: u32,
+    /// No connection attempt before this.
+    retry_at: Instant,
+    /// When the peer last answered, or was first seen.
+    last_seen: Instant,
+}
+
+impl Peer {
+    fn new(now: Instant) -> Self {
+        Peer {
+            client: None,
+            failures: 0,
+            retry_at: now,
+            last_seen: now,
+        }
+    }
+
+    fn sender(&self) -> Option<&Sender<ClientEvent>> {
+        self.client.as_ref().map(|(tx, _)| tx)
+    }
+
+    /// Drops the client and backs off before the next connection attempt.
+    fn fail(&mut self, now: Instant) {
+        if let Some((tx, _)) = self.client.take() {
+            let _ = tx.try_send(ClientEvent::Stop);
+        }
+        self.failures += 1;
+        self.retry_at = now + reconnect_backoff(self.failures);
+    }
+}
+
+/// First delay before reconnecting to a peer, doubled on every failure.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// Time a peer has to answer a health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Wait before streaming to the collectors again after every one of them
+/// failed.
+const UPSTREAM_RETRY: Duration = Duration::from_secs(5);
+/// Time a collector has to accept the connection.
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Delay before the next connection attempt after `failures` failures in a
+/// row.
+fn reconnect_backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (RECONNECT_BACKOFF_MIN * 2u32.pow(doublings)).min(RECONNECT_BACKOFF_MAX)
+}
+
+lazy_static! {
+    /// Start of this node's data sequence, in milliseconds since epoch.
+    static ref NODE_EPOCH: i64 = Timestamp::now().as_millis();
+}
+
+/// Identifies this node's data messages to the collector: its id with
+/// `identity.enabled`, else its configured address, else its host name.
+fn node_id() -> String {
+    match identity::local() {
+        Some(identity) => identity.id().to_string(),
+        None => crate::CONFIG.client.ip.clone().unwrap_or_else(host_name),
+    }
+}
+
+/// Name of the host, empty if it cannot be read.
+fn host_name() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// The address of this node, empty if not configured.
+fn local_addr() -> String {
+    crate::CONFIG.client.ip.clone().unwrap_or_default()
+}
+
+/// Scenario tags for the collector, from the `[metadata]` section, and the
+/// timestamp type and clock domain of the capture, `tstamp_type`, unless
+/// tagged there.
+fn node_metadata(tstamp_type: Option<TimestampType>) -> HashMap<String, String> {
+    let mut metadata: HashMap<_, _> = crate::CONFIG.metadata.clone().into_iter().collect();
+    if let Some(tstamp_type) = tstamp_type {
+        metadata
+            .entry(String::from("tstamp_type"))
+            .or_insert_with(|| tstamp::name(tstamp_type).to_string());
+        metadata
+            .entry(String::from("clock_domain"))
+            .or_insert_with(|| ClockDomain::of(tstamp_type).as_str().to_string());
+    }
+    metadata
+}
+
 pub struct ClientHandler {
-    clients: HashMap<IpAddr, Option<OuterClient>>,
+    clients: HashMap<IpAddr, Peer>,
+    /// Port the servers of the peers listen on, `client.listen_port`.
+    port: u16,
     reply_tx: Sender<ClientEventResult>,
     event_rx: Receiver<ClientHandlerEvent>,
     cap_ev_tx: CapEventSender,
     bw_message_bc: Arc<tokio::sync::broadcast::Sender<proto_bw::DataMsg>>,
+    /// Every active probe started by this node goes through here.
+    probe_limiter: ProbeLimiter<ProbeJob>,
+    /// Health check results, peer and whether it answered.
+    health_tx: Sender<(IpAddr, bool)>,
+    health_rx: Receiver<(IpAddr, bool)>,
+    /// Sequence number of the last data message sent to the collector.
+    data_seq: u64,
+    /// Keeps the last link states sent, for new subscribers.
+    store: MetricsStore,
+    /// Data messages waiting for the streams to the collectors.
+    upstreams: Vec<UpstreamQueue>,
+    /// The capture, for the timestamp type in the metadata.
+    pcap_meta: watch::Receiver<PCAPMeta>,
+    /// Commands for the ping manager, None until the event loop starts or
+    /// if the ICMP sockets could not be opened.
+    ping_tx: Option<Sender<PingCommand>>,
 }
 
+/// How often queued probes are checked for a free slot.
+const PROBE_QUEUE_TICK: Duration = Duration::from_secs(1);
+
+/// ICMP payload of plain pings, as sent by ping(8).
+const PING_PAYLOAD: usize = 56;
+
 impl ClientHandler {
     pub fn new(
         reply_tx: Sender<ClientEventResult>,
         event_rx: Receiver<ClientHandlerEvent>,
         cap_ev_tx: CapEventSender,
         bw_message_bc: Arc<tokio::sync::broadcast::Sender<proto_bw::DataMsg>>,
+        store: MetricsStore,
+        pcap_meta: watch::Receiver<PCAPMeta>,
     ) -> Self {
+        let (health_tx, health_rx) = channel(100);
         ClientHandler {
             clients: HashMap::new(),
+            port: crate::CONFIG.client.listen_port,
             reply_tx,
             event_rx,
             cap_ev_tx,
             bw_message_bc,
+            probe_limiter: ProbeLimiter::new(
+                crate::CONFIG.probe.max_concurrent,
+                crate::CONFIG.probe.min_spacing,
+            ),
+            health_tx,
+            health_rx,
+            data_seq: 0,
+            store,
+            upstreams: upstreams_from_config(),
+            pcap_meta,
+            ping_tx: None,
         }
     }
 
@@ -113,9 +334,12 @@ impl ClientHandler {
 
     async fn send_hello(&mut self, ip: IpAddr, message: String) {
         // Send hello to all clients
-        if let Some(outer) = self.clients.get_mut(&ip) {
-            if let Some((tx, _)) = outer {
-                tx.send(ClientEvent::SendHello { message }).await.unwrap();
+        if let Some(peer) = self.clients.get_mut(&ip) {
+            if let Some(tx) = peer.sender().cloned() {
+                if tx.send(ClientEvent::SendHello { message }).await.is_err() {
+                    info!("Client task for {} has stopped", ip);
+                    peer.fail(Instant::now());
+                }
             } else {
                 info!("Tried to send hello to uninitiated client {}", ip);
             }
@@ -124,27 +348,302 @@ impl ClientHandler {
         }
     }
 
-    pub async fn start_event_loop(mut self) {
-        let receiver = self.bw_message_bc.subscribe();
+    /// Health checks connected peers, reconnects to those whose backoff is
+    /// over and drops the ones gone for longer than the grace period.
+    async fn check_peers(&mut self) {
+        let now = Instant::now();
+        let grace = crate::CONFIG.client.peer_grace_period;
+        let mut removed = Vec::new();
+        let mut due = Vec::new();
+        for (ip, peer) in self.clients.iter_mut() {
+            match &peer.client {
+                Some((_, handle)) if handle.is_finished() => {
+                    info!("Client task for {} has stopped", ip);
+                    peer.fail(now);
+                }
+                Some((tx, _)) => {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    // Skip peers that are still busy
+                    if tx.try_send(ClientEvent::HealthCheck { reply: reply_tx }).is_ok() {
+                        let ip = *ip;
+                        let health_tx = self.health_tx.clone();
+                        tokio::spawn(async move {
+                            let alive = matches!(
+                                timeout(HEALTH_CHECK_TIMEOUT * 2, reply_rx).await,
+                                Ok(Ok(true))
+                            );
+                            let _ = health_tx.send((ip, alive)).await;
+                        });
+                    }
+                    continue;
+                }
+                None => {}
+            }
+            if now.saturating_duration_since(peer.last_seen) > grace {
+                removed.push(*ip);
+            } else if peer.retry_at <= now {
+                due.push(*ip);
+            }
+        }
+
+        for ip in removed {
+            info!("Removing peer {}, unreachable for over {:?}", ip, grace);
+            self.clients.remove(&ip);
+            send_or_log(&self.reply_tx, ClientEventResult::PeerRemoved(ip), "peer removal").await;
+        }
+        if !due.is_empty() {
+            self.connect(due).await;
+        }
+    }
+
+    /// Adds the peers, the probe limiter and this task's queues to `status`.
+    fn add_status(&self, mut status: NodeStatus) -> NodeStatus {
+        let now = Instant::now();
+        for (ip, peer) in &self.clients {
+            status.peers.push(PeerStatus {
+                ip: ip.to_string(),
+                connected: peer.client.is_some(),
+                failures: peer.failures,
+                last_seen: now.saturating_duration_since(peer.last_seen).as_secs_f64(),
+                retry_in: peer.retry_at.saturating_duration_since(now).as_secs_f64(),
+            });
+        }
+        status.peers.sort_by(|a, b| a.ip.cmp(&b.ip));
+        status.probes_running = self.probe_limiter.running() as u32;
+        status.probes_queued = self.probe_limiter.queued() as u32;
+        status
+            .queues
+            .insert(String::from("client_handler"), self.event_rx.len() as u64);
+        status
+            .queues
+            .insert(String::from("data_broadcast"), self.bw_message_bc.len() as u64);
+        status
+            .upstreams
+            .extend(self.upstreams.iter().map(UpstreamQueue::status));
+        status
+    }
+
+    /// Records the result of a health check.
+    fn record_health(&mut self, ip: IpAddr, alive: bool) {
+        let Some(peer) = self.clients.get_mut(&ip) else {
+            return;
+        };
+        let now = Instant::now();
+        if alive {
+            peer.failures = 0;
+            peer.last_seen = now;
+        } else if peer.client.is_some() {
+            info!("Peer {} failed its health check", ip);
+            peer.fail(now);
+        }
+    }
+
+    /// Runs the probe now if the limiter allows it, queues it if all slots
+    /// are taken, and tells the parser which it was.
+    async fn submit_probe(&mut self, ip: IpAddr, job: ProbeJob) {
+        let technique = job.technique();
+        let admission = self.probe_limiter.request(ip, job, Instant::now());
+        let status = admission.status();
+        if let Admission::Start(permit, job) = admission {
+            self.run_probe(permit, ip, job).await;
+        }
+        // Best effort, the parser may be waiting on this task
+        let _ = self.reply_tx.try_send(ClientEventResult::ProbeStatus {
+            ip,
+            technique,
+            status,
+        });
+    }
+
+    /// Starts queued probes while there are free slots.
+    async fn start_queued_probes(&mut self) {
+        while let Some((permit, ip, job)) = self.probe_limiter.next_ready(Instant::now()) {
+            let technique = job.technique();
+            self.run_probe(permit, ip, job).await;
+            let _ = self.reply_tx.try_send(ClientEventResult::ProbeStatus {
+                ip,
+                technique,
+                status: ProbeStatus::Started,
+            });
+        }
+    }
+
+    /// Runs an admitted probe in the background. The slot is freed once it
+    /// is done.
+    async fn run_probe(&mut self, permit: OwnedSemaphorePermit, ip: IpAddr, job: ProbeJob) {
+        let cap_ev_tx = self.cap_ev_tx.clone();
+        match job {
+            ProbeJob::Negotiated { technique, duration } => {
+                self.request_probe(permit, ip, technique, duration).await;
+            }
+            ProbeJob::Iperf3 {
+                port,
+                duration,
+                options,
+            } => {
+                tokio::spawn(async move {
+                    do_iperf_test(&ip.to_string(), port, duration, &options, cap_ev_tx).await;
+                    drop(permit);
+                });
+            }
+            ProbeJob::Pathload => {
+                tokio::spawn(async move {
+                    do_pathload_test(cap_ev_tx, ip, None).await;
+                    drop(permit);
+                });
+            }
+        }
+    }
+
+    /// Ask the peer at `ip` to prepare a probe session, and start the probe
+    /// once it has been acknowledged.
+    ///
+    /// The negotiation runs on its own task so the event loop is not blocked
+    /// while waiting for the peer. `permit` is held until the probe is done.
+    async fn request_probe(
+        &mut self,
+        permit: OwnedSemaphorePermit,
+        ip: IpAddr,
+        technique: ProbeTechnique,
+        duration: u16,
+    ) {
+        let tx = match self.clients.get(&ip).and_then(Peer::sender) {
+            Some(tx) => tx.clone(),
+            None => {
+                info!("Tried to request a probe from unknown client {}", ip);
+                return;
+            }
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let train_params = TrainParams::from_config();
+        let request = ProbeRequest {
+            technique: technique.to_string(),
+            port: 0,
+            duration: duration as u32,
+            train_length: train_params.length,
+            train_packet_size: train_params.packet_size,
+            train_rate: train_params.rate,
+        };
+        if tx
+            .send(ClientEvent::RequestProbe {
+                request,
+                reply: reply_tx,
+            })
+            .await
+            .is_err()
+        {
+            info!("Client task for {} has stopped", ip);
+            return;
+        }
+
         let cap_ev_tx = self.cap_ev_tx.clone();
         tokio::spawn(async move {
-            match stream_data_msg(
-                receiver,
-                &format!(
-                "{}:{}",
-                &crate::CONFIG.server.ip,
-                &crate::CONFIG.server.port
-                ),
-                cap_ev_tx,
-            ).await {
-                Ok(_) => {}
-                Err(e) => {
-                    info!("Failed to stream data message: {}", e);
+            let reply = match reply_rx.await {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(e)) => {
+                    info!("Probe request to {} failed: {}", ip, e);
+                    return;
+                }
+                Err(_) => return,
+            };
+            if !reply.accepted {
+                info!("Probe request to {} rejected: {}", ip, reply.reason);
+                return;
+            }
+
+            let port = match u16::try_from(reply.port) {
+                Ok(port) => port,
+                Err(_) => {
+                    let e = anyhow::anyhow!("Probe reply from {} has port {}", ip, reply.port);
+                    send_or_log(&cap_ev_tx, CapEvent::Error(e), "probe error").await;
+                    return;
+                }
+            };
+            let session = ProbeSession::new(
+                reply.probe_id,
+                ip,
+                port,
+                technique,
+                Duration::from_secs(duration as u64),
+            );
+            send_or_log(&cap_ev_tx, CapEvent::ProbeSession(session), "probe session").await;
+
+            match technique {
+                ProbeTechnique::Iperf3 => {
+                    let options = IperfOptions::from_config();
+                    do_iperf_test(&ip.to_string(), port, duration, &options, cap_ev_tx).await;
+                }
+                ProbeTechnique::Pathload => {
+                    do_pathload_test(cap_ev_tx, ip, Some(reply.probe_id)).await
+                }
+                ProbeTechnique::Train => {
+                    let _ = train::dispatch_sender(
+                        ip,
+                        port,
+                        reply.probe_id,
+                        train_params,
+                        Duration::from_secs(duration as u64),
+                    )
+                    .await;
                 }
             }
+            drop(permit);
         });
+    }
+
+    pub async fn start_event_loop(mut self) {
+        match PingManager::new(self.cap_ev_tx.clone()) {
+            Ok(manager) => {
+                let (ping_tx, ping_rx) = channel(100);
+                tokio::spawn(manager.run(ping_rx));
+                self.ping_tx = Some(ping_tx);
+            }
+            Err(e) => warn!("Failed to open ICMP sockets, pings are disabled: {}", e),
+        }
+        for upstream in self.upstreams.clone() {
+            let cap_ev_tx = self.cap_ev_tx.clone();
+            let pcap_meta = self.pcap_meta.clone();
+            tokio::spawn(async move {
+                loop {
+                    // The first collector is tried again once the others failed
+                    for index in 0..upstream.addrs().len() {
+                        // Read on each connect, the capture may have reopened
+                        let metadata = node_metadata(pcap_meta.borrow().tstamp_type);
+                        let result =
+                            stream_data_msg(&upstream, index, metadata, cap_ev_tx.clone()).await;
+                        if let Err(e) = result {
+                            info!("Failed to stream data message: {}", e);
+                        }
+                        upstream.disconnected();
+                    }
+                    tokio::time::sleep(UPSTREAM_RETRY).await;
+                }
+            });
+        }
 
-        while let Some(event) = self.event_rx.recv().await {
+        let mut probe_tick = tokio::time::interval(PROBE_QUEUE_TICK);
+        let health_interval = crate::CONFIG.client.health_check_interval;
+        let mut health_tick = tokio::time::interval(health_interval.max(PROBE_QUEUE_TICK));
+        loop {
+            let event = tokio::select! {
+                event = self.event_rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = probe_tick.tick() => {
+                    self.start_queued_probes().await;
+                    continue;
+                }
+                _ = health_tick.tick(), if !health_interval.is_zero() => {
+                    self.check_peers().await;
+                    continue;
+                }
+                Some((ip, alive)) = self.health_rx.recv() => {
+                    self.record_health(ip, alive);
+                    continue;
+                }
+            };
             match event {
                 ClientHandlerEvent::SendHello { ip, message } => {
                     // Send hello to all clients
@@ -160,13 +659,57 @@ impl ClientHandler {
                         self.send_hello(ip, message.clone()).await;
                     }
                 }
-                ClientHandlerEvent::DoIperf3(ip, port, duration) => {
-                    dispatch_iperf_client(ip, port, duration, self.cap_ev_tx.clone());
+                ClientHandlerEvent::SyncClocks => {
+                    for tx in self.clients.values().filter_map(Peer::sender) {
+                        // Skip peers that are still busy with the last one
+                        let _ = tx.try_send(ClientEvent::SyncClock);
+                    }
+                }
+                ClientHandlerEvent::DoIperf3(ip, port, duration, options) => {
+                    match ip.parse::<IpAddr>() {
+                        Ok(ip) => {
+                            let job = ProbeJob::Iperf3 { port, duration, options };
+                            self.submit_probe(ip, job).await;
+                        }
+                        Err(e) => info!("Invalid iperf3 target {}: {}", ip, e),
+                    }
+                }
+                ClientHandlerEvent::DoPathloadTest(ip) => match ip.parse::<IpAddr>() {
+                    Ok(ip) => self.submit_probe(ip, ProbeJob::Pathload).await,
+                    Err(e) => info!("Invalid pathload target {}: {}", ip, e),
+                },
+                ClientHandlerEvent::RequestProbe { ip, technique, duration } => {
+                    let job = ProbeJob::Negotiated { technique, duration };
+                    self.submit_probe(ip, job).await;
+                }
+                ClientHandlerEvent::DoPing { ip, count, interval } => {
+                    self.ping(ip, count, interval);
+                }
+                ClientHandlerEvent::DoPingTrain { ip } => {
+                    if let Some(ping_tx) = &self.ping_tx {
+                        let train = PingCommand::train_from_config(ip);
+                        send_or_log(ping_tx, train, "ping train").await;
+                    } else {
+                        info!("Pings are disabled, no ping train to {}", ip);
+                    }
                 }
-                ClientHandlerEvent::DoPathloadTest(ip) => {
-                    dispatch_pathload_client(self.cap_ev_tx.clone(), ip);
+                ClientHandlerEvent::Status { status, reply } => {
+                    let _ = reply.send(self.add_status(status));
                 }
-                ClientHandlerEvent::SendDataMsg(bw) => {
+                ClientHandlerEvent::SendDataMsg(mut bw) => {
+                    // Numbered even when nobody is listening, so the
+                    // collector sees what it missed as a gap
+                    self.data_seq += 1;
+                    bw.seq = self.data_seq;
+                    bw.node_id = node_id();
+                    bw.epoch = *NODE_EPOCH;
+                    bw.metadata = node_metadata(self.pcap_meta.borrow().tstamp_type);
+                    export_data_msg(&mut bw);
+                    trace::hop(&bw, "sequenced");
+                    self.store.update(&bw);
+                    for upstream in &self.upstreams {
+                        upstream.push(bw.clone());
+                    }
                     if self.bw_message_bc.receiver_count() > 0 {
                         match self.bw_message_bc.send(bw) {
                             Ok(_) => {}
@@ -175,106 +718,124 @@ impl ClientHandler {
                             }
                         }
                     }
-
-
-                    // let cap_ev_tx = self.cap_ev_tx.clone();
-                    // tokio::spawn(async move {
-                    //     send_message(
-                    //         &format!(
-                    //             "{}:{}",
-                    //             &crate::CONFIG.server.ip,
-                    //             &crate::CONFIG.server.port
-                    //         ),
-                    //         bw,
-                    //         cap_ev_tx,
-                    //     )
-                    //     .await;
-                    // });
                 }
             }
         }
     }
 
-    /// For each IP address, run BwClient::new concurrently.
-    /// Then, wait for all tasks to finish and store the returned client handles.
+    /// Queues `count` pings to `ip`, `interval` apart, in a task of its own.
+    fn ping(&self, ip: IpAddr, count: u16, interval: Duration) {
+        let Some(ping_tx) = self.ping_tx.clone() else {
+            info!("Pings are disabled, not pinging {}", ip);
+            return;
+        };
+        tokio::spawn(async move {
+            for seq in 0..count {
+                if seq > 0 {
+                    tokio::time::sleep(interval).await;
+                }
+                let ping = PingCommand::Ping {
+                    host: ip,
+                    seq: PingSequence(seq),
+                    payload: vec![0; PING_PAYLOAD],
+                };
+                if ping_tx.send(ping).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Connects to the given peers that are not connected yet and are not
+    /// backing off after a failed attempt.
     pub async fn init_clients(&mut self, ips: Vec<IpAddr>) {
+        let now = Instant::now();
+        let ips = ips
+            .into_iter()
+            .filter(|ip| match self.clients.get(ip) {
+                Some(peer) => peer.client.is_none() && peer.retry_at <= now,
+                None => true,
+            })
+            .collect();
+        self.connect(ips).await;
+    }
+
+    /// For each IP address, run BwClient::connect concurrently.
+    /// Then, wait for all tasks to finish and store the returned client handles.
+    async fn connect(&mut self, ips: Vec<IpAddr>) {
         let mut tasks = Vec::new();
 
-        for ip in ips {
-            if self.clients.contains_key(&ip) {
-                continue;
-            }
+        for &ip in &ips {
             let reply_txc = self.reply_tx.clone();
-            // Clone the IP so we can return it along with the client.
-            let ip_clone = ip;
             let ip_str = ip.to_string();
-
-            // Spawn a task that calls BwClient::new and returns (IpAddr, OuterClient).
+            let port = self.port;
             tasks.push(tokio::spawn(async move {
-                let client_tuple = BwClient::new(ip_str, reply_txc).await;
-                (ip_clone, client_tuple)
+                BwClient::connect(ip_str, port, reply_txc).await
             }));
         }
 
         // Wait for all tasks to complete.
-        let results = join_all(tasks).await.into_iter();
+        let results = join_all(tasks).await;
 
-        for res in results {
-            match res {
-                Ok((ip, client_result)) => match client_result {
-                    Ok((client_handle, client_tx)) => {
-                        self.clients.insert(ip, Some((client_tx, client_handle)));
-                    }
-                    Err(e) => {
-                        self.reply_tx
-                            .send(ClientEventResult::ServerConnectError(e))
-                            .await
-                            .unwrap();
-                    }
-                },
-                Err(e) => {
-                    self.reply_tx
-                        .send(ClientEventResult::ServerConnectError(e.into()))
-                        .await
-                        .unwrap();
+        for (ip, res) in ips.into_iter().zip(results) {
+            let now = Instant::now();
+            let peer = self.clients.entry(ip).or_insert_with(|| Peer::new(now));
+            let error = match res {
+                Ok(Ok((client_handle, client_tx))) => {
+                    peer.client = Some((client_tx, client_handle));
+                    peer.failures = 0;
+                    peer.last_seen = now;
+                    // Exchange capabilities with the new peer right away.
+                    self.send_hello(ip, String::from("hello")).await;
+                    continue;
                 }
-            }
+                Ok(Err(e)) => e,
+                Err(e) => e.into(),
+            };
+            peer.fail(now);
+            send_or_log(&self.reply_tx, ClientEventResult::ServerConnectError(error), "connect error")
+                .await;
         }
     }
 }
 
 impl BwClient {
-    pub async fn send_hello(&mut self, message: String) {
+    /// Sends a result to the parser. Fails only if the parser has stopped.
+    async fn reply(&self, result: ClientEventResult) -> Result<()> {
+        send_or_err(&self.reply_tx, result, "reply to the parser").await
+    }
+
+    pub async fn send_hello(&mut self, message: String) -> Result<()> {
         // On self.connection, send a hello request
-        let request = tonic::Request::new(HelloRequest { name: message });
+        let request = tonic::Request::new(HelloRequest {
+            name: message,
+            capabilities: Some(local_capabilities()),
+            identity: identity::announce(&local_addr()),
+        });
 
         let response =
             match timeout(Duration::from_secs(3), self.connection.say_hello(request)).await {
                 Ok(Ok(response)) => response.into_inner(),
                 Ok(Err(e)) => {
                     self.status = Some(ClientStatus::new_disconnected());
-                    self.reply_tx
-                        .send(ClientEventResult::HelloReply(Err(e)))
-                        .await
-                        .unwrap();
-                    return;
+                    return self.reply(ClientEventResult::HelloReply(self.ip.clone(), Err(e))).await;
                 }
                 Err(_) => {
                     self.status = Some(ClientStatus::new_disconnected());
-                    return;
+                    return Ok(());
                 }
             };
-        // let response = self.connection.say_hello(request);
 
-        self.reply_tx
-            .send(ClientEventResult::HelloReply(Ok(response)))
-            .await
-            .unwrap();
         self.status = Some(ClientStatus::new_connected());
+        self.reply(ClientEventResult::HelloReply(self.ip.clone(), Ok(response))).await
     }
 
     pub async fn send_hello_noreply(&mut self, message: String) -> Result<HelloReply, Error> {
-        let request = tonic::Request::new(HelloRequest { name: message });
+        let request = tonic::Request::new(HelloRequest {
+            name: message,
+            capabilities: Some(local_capabilities()),
+            identity: identity::announce(&local_addr()),
+        });
 
         let response =
             match timeout(Duration::from_secs(3), self.connection.say_hello(request)).await {
@@ -292,6 +853,59 @@ impl BwClient {
         Ok(response)
     }
 
+    /// Ask the peer to prepare a probe session.
+    pub async fn request_probe(&mut self, request: ProbeRequest) -> Result<ProbeReply, Error> {
+        match timeout(
+            Duration::from_secs(3),
+            self.connection.request_probe(tonic::Request::new(request)),
+        )
+        .await
+        {
+            Ok(Ok(response)) => Ok(response.into_inner()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow::anyhow!("Probe request timed out")),
+        }
+    }
+
+    /// Runs one clock offset exchange and reports the sample, if the peer
+    /// answered in time.
+    pub async fn sync_clock(&mut self) -> Result<()> {
+        let t1 = Timestamp::now().as_nanos() as i64;
+        let request = tonic::Request::new(ClockRequest { t1 });
+        let reply = match timeout(Duration::from_secs(3), self.connection.sync_clock(request)).await {
+            Ok(Ok(response)) => response.into_inner(),
+            Ok(Err(e)) => {
+                info!("Clock sync with {} failed: {}", self.ip, e);
+                return Ok(());
+            }
+            Err(_) => return Ok(()),
+        };
+        let t4 = Timestamp::now().as_nanos() as i64;
+
+        match ClockSample::from_exchange(reply.t1, reply.t2, reply.t3, t4) {
+            Some(sample) => self.reply(ClientEventResult::ClockSample(self.ip.clone(), sample)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// True if the peer answers. Uses the SyncClock RPC, which has no side
+    /// effects on the peer.
+    pub async fn health_check(&mut self) -> bool {
+        let request = tonic::Request::new(ClockRequest {
+            t1: Timestamp::now().as_nanos() as i64,
+        });
+        let alive = matches!(
+            timeout(HEALTH_CHECK_TIMEOUT, self.connection.sync_clock(request)).await,
+            Ok(Ok(_))
+        );
+        self.status = Some(if alive {
+            ClientStatus::new_connected()
+        } else {
+            ClientStatus::new_disconnected()
+        });
+        alive
+    }
+
     /// Subscribe to the bandwidth service.
     /// This will return a stream of DataMsg messages.
     pub async fn subscribe_bandwidth(
@@ -303,7 +917,10 @@ impl BwClient {
         let mut client = BandwidthServiceClient::connect(format!("http://{}:{}", ip, port)).await?;
 
         let stream = client
-            .subscribe_bandwidth(tonic::Request::new(BandwidthRequest { name }))
+            .subscribe_bandwidth(tonic::Request::new(BandwidthRequest {
+                name,
+                ..Default::default()
+            }))
             .await?;
 
         Ok(stream)
@@ -312,22 +929,35 @@ impl BwClient {
     pub async fn start_event_loop(mut self) -> JoinHandle<()> {
         tokio::spawn(async move {
             while let Some(event) = self.event_rx.recv().await {
-                match event {
-                    ClientEvent::SendHello { message } => {
-                        self.send_hello(message).await;
+                let result = match event {
+                    ClientEvent::SendHello { message } => self.send_hello(message).await,
+                    ClientEvent::RequestProbe { request, reply } => {
+                        let _ = reply.send(self.request_probe(request).await);
+                        Ok(())
+                    }
+                    ClientEvent::SyncClock => self.sync_clock().await,
+                    ClientEvent::HealthCheck { reply } => {
+                        let _ = reply.send(self.health_check().await);
+                        Ok(())
                     }
                     ClientEvent::Stop => break,
+                };
+                if let Err(e) = result {
+                    warn!("Stopping client for {}: {}", self.ip, e);
+                    break;
                 }
             }
         })
     }
 
-    pub async fn new(
+    /// Connects to the peer's server on `port` and starts the event loop.
+    pub async fn connect(
         ip: String,
+        port: u16,
         reply_tx: Sender<ClientEventResult>,
     ) -> Result<(tokio::task::JoinHandle<()>, Sender<ClientEvent>)> {
         let (tx, rx) = channel::<ClientEvent>(10);
-        let addr = format!("http://{}:{}", ip, crate::CONFIG.client.listen_port);
+        let addr = format!("http://{}:{}", ip, port);
         let connect_timeout = Duration::from_secs(3);
         let connection = match timeout(connect_timeout, BandwidthServiceClient::connect(addr)).await
         {
@@ -341,17 +971,14 @@ impl BwClient {
         };
 
         let client = BwClient {
+            ip: ip.clone(),
             event_rx: rx,
             reply_tx,
             connection,
             status: None,
         };
 
-        client
-            .reply_tx
-            .send(ClientEventResult::ServerConnected(ip))
-            .await
-            .unwrap();
+        client.reply(ClientEventResult::ServerConnected(ip)).await?;
 
         let handle = client.start_event_loop().await;
 
@@ -362,37 +989,64 @@ impl BwClient {
 /// Client side streaming of DataMsg.
 /// This can be used to avoid having to request data from each client, instead
 /// an address can be provided and the client will stream data to the server.
+///
+/// Streams the messages in `upstream` to its collector at `index` until
+/// the stream ends, acknowledging them in `upstream` as the collector acks
+/// them. Messages left in it are sent by the next call. The hello opening
+/// the stream carries `metadata`.
 pub async fn stream_data_msg(
-    stream: tokio::sync::broadcast::Receiver<proto_bw::DataMsg>,
-    peer_addr: &str,
+    upstream: &UpstreamQueue,
+    index: usize,
+    metadata: HashMap<String, String>,
     cap_ev_tx: CapEventSender,
 ) -> Result<(), Error> {
-    let mut client = loop {
-        match ClientDataServiceClient::connect(format!("http://{}", peer_addr)).await {
-            Ok(client) => break client,
-            Err(e) => {
-                cap_ev_tx
-                    .send(CapEvent::Error(anyhow::anyhow!("Failed to connect to remote: {}", e)))
-                    .await
-                    .unwrap_or(());
-                tokio::time::sleep(Duration::from_secs(5)).await;
-            }
+    let peer_addr = &upstream.addrs()[index];
+    let connect = ClientDataServiceClient::connect(format!("http://{}", peer_addr));
+    let result = timeout(UPSTREAM_CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| anyhow::anyhow!("Connection to remote {} timed out", peer_addr))
+        .and_then(|r| {
+            r.map_err(|e| anyhow::anyhow!("Failed to connect to remote {}: {}", peer_addr, e))
+        });
+    let mut client = match result {
+        Ok(client) => client,
+        Err(e) => {
+            cap_ev_tx
+                .send(CapEvent::Error(anyhow::anyhow!("{}", e)))
+                .await
+                .unwrap_or(());
+            return Err(e);
         }
     };
     info!("Connected to remote server: {}", peer_addr);
-    let bc_stream = BroadcastStream::new(stream);
-
-    let msg_stream = bc_stream.filter_map(|res| {
-        match res {
-            Ok(msg) => Some(msg),
-            Err(_) => None,
-        }
+    if let Some(encoding) = crate::CONFIG.server.compression {
+        client = client.send_compressed(encoding);
+    }
+    // Open the stream with a hello so the collector knows what this node can do.
+    let mut hello = DataMsg {
+        data: Some(data_msg::Data::Hello(HelloMessage {
+            message: local_addr(),
+            capabilities: Some(local_capabilities()),
+            identity: identity::announce(&local_addr()),
+        })),
+        seq: 0,
+        node_id: node_id(),
+        epoch: *NODE_EPOCH,
+        metadata,
+        window_id: 0,
+    };
+    export_data_msg(&mut hello);
+    let to = format!("streamed to {}", peer_addr);
+    let msgs = upstream.stream(index).map(move |msg| {
+        trace::hop(&msg, &to);
+        msg
     });
+    let msg_stream = tokio_stream::once(hello).chain(msgs);
 
     let request = Request::new(msg_stream);
     info!("Starting data stream to remote server");
-    match client.client_stream(request).await {
-        Ok(response) => info!("Received response: {:?}", response),
+    let mut acks = match client.acked_stream(request).await {
+        Ok(response) => response.into_inner(),
         Err(e) => {
             cap_ev_tx
                 .send(CapEvent::Error(anyhow::anyhow!("Failed to connect: {}", e)))
@@ -400,40 +1054,65 @@ pub async fn stream_data_msg(
                 .unwrap_or(());
             return Err(e.into());
         }
+    };
+    while let Some(ack) = acks.message().await? {
+        upstream.acknowledge(ack.seq);
     }
+    info!("Data stream to {} ended", peer_addr);
 
     Ok(())
 }
 
-/// Sends measurement data by TCP to the listening server.
-pub async fn send_message(peer_addr: &str, message: DataMsg, cap_ev_tx: CapEventSender) {
-    let res = async move {
-        let stream = match timeout(Duration::from_secs(4), TcpStream::connect(peer_addr)).await {
-            Ok(Ok(stream)) => stream,
-            Ok(Err(e)) => {
-                return Err(e.into());
-            }
-            Err(_) => {
-                return Err(anyhow::anyhow!("Connection timed out"));
-            }
-        };
-        let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::simulation::simulated_meta;
+    use crate::prost_net::test_server::start_server;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn test_peer_lifecycle() {
+        let server = start_server().await;
+        let (reply_tx, _reply_rx) = channel(16);
+        let (_event_tx, event_rx) = channel(1);
+        let (cap_ev_tx, _cap_ev_rx) = channel(16);
+        let bw_message_bc = Arc::new(tokio::sync::broadcast::channel(16).0);
+        let meta = watch::channel(simulated_meta(Ipv4Addr::LOCALHOST)).1;
+        let store = MetricsStore::new();
+        let mut handler =
+            ClientHandler::new(reply_tx, event_rx, cap_ev_tx, bw_message_bc, store, meta);
+        handler.port = server.port;
+        let ip: IpAddr = [127, 0, 0, 1].into();
 
-        // Create and encode a HelloMessage.
-        let mut buf = BytesMut::with_capacity(message.encoded_len());
-        message.encode(&mut buf)?;
+        // Started, and answers health checks
+        handler.init_clients(vec![ip]).await;
+        let tx = handler.clients[&ip].sender().unwrap().clone();
+        let (reply, alive) = oneshot::channel();
+        tx.send(ClientEvent::HealthCheck { reply }).await.unwrap();
+        assert!(alive.await.unwrap());
 
-        // Send the length-delimited message.
-        framed.send(buf.freeze()).await?;
-        Ok(())
+        // Stopped after a failed health check, and not connected to again
+        // while backing off
+        handler.record_health(ip, false);
+        handler.init_clients(vec![ip]).await;
+        let peer = &handler.clients[&ip];
+        assert!(peer.client.is_none());
+        assert_eq!(peer.failures, 1);
+
+        // Restarted once the backoff is over
+        handler.clients.get_mut(&ip).unwrap().retry_at = Instant::now();
+        handler.check_peers().await;
+        let peer = &handler.clients[&ip];
+        assert!(peer.client.is_some());
+        assert_eq!(peer.failures, 0);
+        server.shutdown.cancel();
     }
-    .await;
 
-    if let Err(e) = res {
-        // Ignore send errors, as the receiver may have disconnected.
-        cap_ev_tx
-            .send(CapEvent::Error(e.into()))
-            .await
-            .unwrap_or(());
+    #[test]
+    fn test_reconnect_backoff() {
+        assert_eq!(reconnect_backoff(1), RECONNECT_BACKOFF_MIN);
+        assert_eq!(reconnect_backoff(2), RECONNECT_BACKOFF_MIN * 2);
+        assert_eq!(reconnect_backoff(4), RECONNECT_BACKOFF_MIN * 8);
+        assert_eq!(reconnect_backoff(100), RECONNECT_BACKOFF_MAX);
     }
 }
@@ -1,50 +1,168 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::mpsc::channel;
+use tokio::sync::{watch, Semaphore};
 use tokio_stream::StreamExt;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tonic::{transport::Server, Request, Response, Status};
 
 use proto_bw::bandwidth_service_server::{BandwidthService, BandwidthServiceServer};
-use proto_bw::{BandwidthMessage, BandwidthRequest, HelloReply, HelloRequest};
-use tokio_stream::wrappers::{ReceiverStream, BroadcastStream};
+use proto_bw::{
+    BandwidthMessage, BandwidthRequest, ClockReply, ClockRequest, DumpReply, DumpRequest,
+    EstimateReply, EstimateRequest, EstimatorReply, EstimatorRequest, FeatureRequest,
+    FeatureVector, HelloReply, HelloRequest, NodeStatus, ProbeReply, ProbeRequest, StatusRequest,
+    SubnetReply, SubnetRequest,
+};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream, TcpListenerStream};
 use tokio::sync::broadcast::Sender;
 
+use crate::anonymize::export_pair;
+use crate::channel::send_or_log;
+use crate::identity;
 use crate::listener::capture::PCAPMeta;
+use crate::listener::dump::{DumpHandle, MAX_DUMP_DURATION};
+use crate::listener::filter::SubnetRules;
+use crate::listener::tracking::estimates::LinkQuery;
+use crate::probe::iperf::IperfServer;
+use crate::probe::pathload;
+use crate::probe::session::{
+    ProbeSession, ProbeTechnique, MAX_PROBE_DURATION, PROBE_GRACE_PERIOD,
+};
+use crate::probe::train::{self, TrainParams};
+use crate::prost_net::capabilities::{check_compatible, local_capabilities, supports_probe};
+use crate::prost_net::metrics_store::{DataFilter, MetricsStore};
+use crate::prost_net::request_guard::RequestGuard;
 use crate::proto_bw::DataMsg;
+use crate::stream_id::IpPair;
+use crate::tap::{Tap, TapEvent, TapFilter};
 use crate::{proto_bw, CapEventSender};
-use crate::CapEvent;
+use crate::{CapEvent, RegressionType, Timestamp};
 
 #[derive(Debug)]
 pub enum PbfMsg {
     HelloReply(HelloReply),
-    HelloRequest(HelloRequest),
+    /// A hello and the address it came from.
+    HelloRequest(HelloRequest, Option<IpAddr>),
     BandwidthMessage(BandwidthMessage),
     BandwidthRequest(BandwidthRequest),
 }
 
+/// Time given to a freshly spawned probe server to start listening before
+/// the initiator is told to go ahead.
+const PROBE_SETUP_DELAY: tokio::time::Duration = tokio::time::Duration::from_millis(200);
+
+/// Probe sessions this node runs for peers at once.
+const MAX_PROBE_SESSIONS: usize = 4;
+
+/// Time the parser and client handler have to fill in a status report.
+const STATUS_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
 #[derive(Debug)]
 pub struct BwServer {
     sender: CapEventSender,
-    pcap_meta: Arc<PCAPMeta>,
+    pcap_meta: watch::Receiver<PCAPMeta>,
     bw_tx_stream: Arc<Sender<DataMsg>>,
+    /// Source of the feature vectors for `SubscribeFeatures`.
+    tap: Tap,
+    /// Writes the link dumps for `DumpLink`.
+    dump: DumpHandle,
+    /// Last known link states, sent to new subscribers.
+    store: MetricsStore,
+    /// Subnet rules of the parser and capture, for `SetSubnets`.
+    subnets: Arc<watch::Sender<SubnetRules>>,
+    started: tokio::time::Instant,
+    /// Id handed out to the next accepted probe session.
+    next_probe_id: AtomicU64,
+    /// A permit per running probe session, held until it ends.
+    probe_slots: Arc<Semaphore>,
+    /// Turns down hellos, probe requests and clock syncs from unknown,
+    /// too eager or forged peers.
+    guard: RequestGuard,
 }
 
 impl BwServer {
-    pub fn new(sender: CapEventSender, pcap_meta: Arc<PCAPMeta>, bw_tx_stream:  Arc<Sender<DataMsg>>) -> Self {
-        BwServer { sender, pcap_meta, bw_tx_stream }
+    pub fn new(
+        sender: CapEventSender,
+        pcap_meta: watch::Receiver<PCAPMeta>,
+        bw_tx_stream: Arc<Sender<DataMsg>>,
+        tap: Tap,
+        dump: DumpHandle,
+        store: MetricsStore,
+        subnets: Arc<watch::Sender<SubnetRules>>,
+    ) -> Self {
+        BwServer {
+            sender,
+            pcap_meta,
+            bw_tx_stream,
+            tap,
+            dump,
+            store,
+            subnets,
+            started: tokio::time::Instant::now(),
+            next_probe_id: AtomicU64::new(1),
+            probe_slots: Arc::new(Semaphore::new(MAX_PROBE_SESSIONS)),
+            guard: RequestGuard::from_config(),
+        }
     }
 
-    /// Spawns the server in the background.
-    /// Consumes self, returns a handle to the task
-    pub fn dispatch_server(self) -> JoinHandle<Result<()>> {
-        tokio::spawn(async move {
-            let addr = format!("0.0.0.0:{}", crate::CONFIG.client.listen_port).parse().expect("Failed to parse address");
+    /// Turns down requests that change or dump what the node does, unless
+    /// they come from this host or `client.control_peers`.
+    fn admit_control<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        self.guard.admit_control(peer, tokio::time::Instant::now())?;
+        Ok(())
+    }
+
+    /// Reply used when a probe request is turned down.
+    fn reject_probe(reason: impl Into<String>) -> ProbeReply {
+        ProbeReply {
+            accepted: false,
+            port: 0,
+            probe_id: 0,
+            reason: reason.into(),
+        }
+    }
 
+    /// Binds `client.listen_addr` and `client.listen_port` and spawns the
+    /// server in the background, until `shutdown` is cancelled.
+    ///
+    /// Fails right away if the address cannot be bound, e.g. if the port is
+    /// taken. Consumes self, returns a handle to the task.
+    pub fn dispatch_server(self, shutdown: CancellationToken) -> Result<JoinHandle<Result<()>>> {
+        let client = &crate::CONFIG.client;
+        let addr = SocketAddr::new(client.listen_addr, client.listen_port);
+        let listener = std::net::TcpListener::bind(addr)
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                tokio::net::TcpListener::from_std(listener)
+            })
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        info!("gRPC server listening on {}", addr);
+
+        Ok(self.serve(listener, shutdown))
+    }
+
+    /// Spawns the server on a bound listener, until `shutdown` is cancelled.
+    pub fn serve(
+        self,
+        listener: tokio::net::TcpListener,
+        shutdown: CancellationToken,
+    ) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move {
+            let addr = listener.local_addr()?;
             Server::builder()
                 .add_service(BandwidthServiceServer::new(self))
-                .serve(addr)
+                .serve_with_incoming_shutdown(
+                    TcpListenerStream::new(listener),
+                    shutdown.cancelled(),
+                )
                 .await?;
+            info!("gRPC server on {} stopped", addr);
             Ok(())
         })
     }
@@ -53,50 +171,88 @@ impl BwServer {
 #[tonic::async_trait]
 impl BandwidthService for BwServer {
     type SubscribeBandwidthStream = ReceiverStream<Result<DataMsg, Status>>;
+    type SubscribeFeaturesStream = ReceiverStream<Result<FeatureVector, Status>>;
 
     async fn say_hello(
         &self,
         request: Request<HelloRequest>,
     ) -> Result<Response<HelloReply>, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
         let inner = request.into_inner();
+        self.guard
+            .admit_hello(peer, inner.identity.as_ref(), tokio::time::Instant::now())?;
+        let peer_caps = inner.capabilities.clone().unwrap_or_default();
+        if let Err(reason) = check_compatible(&peer_caps) {
+            warn!("Refusing hello from {}: {}", inner.name, reason);
+            return Err(Status::failed_precondition(reason));
+        }
+        let ip_addr = self.pcap_meta.borrow().ipv4.to_string();
         let reply = HelloReply {
-            ip_addr: self.pcap_meta.ipv4.to_string(),
+            identity: identity::announce(&ip_addr),
+            ip_addr,
+            capabilities: Some(local_capabilities()),
         };
 
-        self.sender
-            .send(CapEvent::Protobuf(PbfMsg::HelloRequest(inner)))
-            .await.expect("Failed to send protobuf message");
+        let hello = CapEvent::Protobuf(PbfMsg::HelloRequest(inner, peer));
+        send_or_log(&self.sender, hello, "hello request").await;
 
         Ok(Response::new(reply))
     }
 
+    /// Handler for the GetBandwidth RPC.
+    /// Returns the last known state of the links asked for.
     async fn get_bandwidth(
         &self,
-        _: Request<BandwidthRequest>,
+        request: Request<BandwidthRequest>,
     ) -> Result<Response<DataMsg>, Status> {
-        panic!("Not implemented yet");
+        let mut filter = DataFilter::new(request.get_ref());
+        let snapshot = self
+            .store
+            .snapshot()
+            .and_then(|msg| filter.apply(msg))
+            .ok_or_else(|| Status::unavailable("No link has been reported yet"))?;
+        Ok(Response::new(snapshot))
     }
 
     /// Handler for the SubscribeBandwidth RPC.
-    /// This will subscribe to the broadcast channel for DataMsg and stream these
-    /// to the client asking for data.
+    /// Sends the last known link states, then streams the data messages as
+    /// they are sent. Each subscriber gets the links and kinds of messages
+    /// it asked for, and the state of a link no more often than its
+    /// `min_interval_ms`.
     async fn subscribe_bandwidth(
         &self,
-        _: Request<BandwidthRequest>,
+        request: Request<BandwidthRequest>,
     ) -> Result<Response<Self::SubscribeBandwidthStream>, Status> {
+        let request = request.into_inner();
+        let mut filter = DataFilter::new(&request);
         let (tx, rx) = channel::<Result<DataMsg, Status>>(16);
 
+        // Subscribed before the snapshot is taken, so nothing falls between
         let mut bc_stream = BroadcastStream::from(self.bw_tx_stream.subscribe());
+        let snapshot = if request.no_snapshot {
+            None
+        } else {
+            self.store.snapshot().and_then(|msg| filter.apply(msg))
+        };
 
         tokio::spawn(async move {
+            if let Some(snapshot) = snapshot {
+                if tx.send(Ok(snapshot)).await.is_err() {
+                    return;
+                }
+            }
             while let Some(item) = bc_stream.next().await {
-                let out = match item {
-                    Ok(msg) => Ok(msg),
-                    Err(e) => {
-                        Err(Status::internal(format!("Error: {}", e)))
+                let msg = match item {
+                    Ok(msg) => msg,
+                    Err(BroadcastStreamRecvError::Lagged(n)) => {
+                        warn!("Subscriber {} fell behind, skipped {} messages", request.name, n);
+                        continue;
                     }
                 };
-                if tx.send(out).await.is_err() {
+                let Some(msg) = filter.apply(msg) else {
+                    continue;
+                };
+                if tx.send(Ok(msg)).await.is_err() {
                     // receiver dropped
                     break;
                 }
@@ -105,4 +261,442 @@ impl BandwidthService for BwServer {
         let stream = ReceiverStream::new(rx);
         Ok(Response::new(stream))
     }
+
+    /// Handler for the SubscribeFeatures RPC.
+    /// Streams the feature vectors of every link as they are taken. Nothing
+    /// is sent unless `features.interval` is set.
+    async fn subscribe_features(
+        &self,
+        _: Request<FeatureRequest>,
+    ) -> Result<Response<Self::SubscribeFeaturesStream>, Status> {
+        let (tx, rx) = channel::<Result<FeatureVector, Status>>(16);
+
+        let mut features = Box::pin(self.tap.subscribe(TapFilter::features()));
+
+        tokio::spawn(async move {
+            while let Some(event) = features.next().await {
+                let TapEvent::Features(mut vector) = event else {
+                    continue;
+                };
+                vector.link = export_pair(vector.link);
+                if tx.send(Ok(vector.to_proto())).await.is_err() {
+                    // receiver dropped
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    /// Handler for the RequestProbe RPC.
+    /// Prepares a probe server for the requesting peer and only acknowledges
+    /// once it has been started, so the initiator never probes a closed port.
+    async fn request_probe(
+        &self,
+        request: Request<ProbeRequest>,
+    ) -> Result<Response<ProbeReply>, Status> {
+        let peer = request
+            .remote_addr()
+            .map(|addr| addr.ip())
+            .ok_or_else(|| Status::invalid_argument("Unknown peer address"))?;
+        self.guard.admit_probe(Some(peer), tokio::time::Instant::now())?;
+        let inner = request.into_inner();
+
+        let technique = match inner.technique.parse::<ProbeTechnique>() {
+            Ok(technique) => technique,
+            Err(e) => return Ok(Response::new(Self::reject_probe(e.to_string()))),
+        };
+        if !supports_probe(&local_capabilities(), technique.as_str()) {
+            return Ok(Response::new(Self::reject_probe(format!(
+                "{} is not available on this node",
+                technique
+            ))));
+        }
+        let duration = tokio::time::Duration::from_secs(inner.duration as u64);
+        if duration.is_zero() || duration > MAX_PROBE_DURATION {
+            return Ok(Response::new(Self::reject_probe(format!(
+                "Duration must be between 1 and {} seconds",
+                MAX_PROBE_DURATION.as_secs()
+            ))));
+        }
+
+        let Ok(slot) = self.probe_slots.clone().try_acquire_owned() else {
+            return Ok(Response::new(Self::reject_probe("Too many probe sessions")));
+        };
+        let probe_id = self.next_probe_id.fetch_add(1, Ordering::Relaxed);
+        let port = match technique {
+            ProbeTechnique::Iperf3 => {
+                let port = match inner.port {
+                    0 => None,
+                    port => Some(
+                        u16::try_from(port)
+                            .map_err(|_| Status::invalid_argument("Invalid port"))?,
+                    ),
+                };
+                IperfServer::dispatch_one_off(port, self.sender.clone())
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?
+            }
+            ProbeTechnique::Pathload => {
+                // pathload uses its own fixed ports
+                pathload::dispatch_single_server();
+                0
+            }
+            ProbeTechnique::Train => {
+                let params = TrainParams {
+                    length: inner.train_length,
+                    packet_size: inner.train_packet_size,
+                    rate: inner.train_rate,
+                };
+                if let Err(e) = params.validate() {
+                    return Ok(Response::new(Self::reject_probe(e.to_string())));
+                }
+                let port = u16::try_from(inner.port)
+                    .map_err(|_| Status::invalid_argument("Invalid port"))?;
+                train::dispatch_receiver(peer, port, probe_id, params, duration, self.sender.clone())
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?
+            }
+        };
+        tokio::time::sleep(PROBE_SETUP_DELAY).await;
+
+        info!("Accepted {} probe {} from {} on port {}", technique, probe_id, peer, port);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(duration + PROBE_GRACE_PERIOD).await;
+            drop(slot);
+        });
+        let session = ProbeSession::new(probe_id, peer, port, technique, duration);
+        if let Err(e) = self.sender.send(CapEvent::ProbeSession(session)).await {
+            warn!("Failed to register probe session: {}", e);
+        }
+
+        Ok(Response::new(ProbeReply {
+            accepted: true,
+            port: port as u32,
+            probe_id,
+            reason: String::new(),
+        }))
+    }
+
+    /// Handler for the SetEstimator RPC.
+    /// Switches the estimator behind `abw` in the link states this node reports.
+    async fn set_estimator(
+        &self,
+        request: Request<EstimatorRequest>,
+    ) -> Result<Response<EstimatorReply>, Status> {
+        self.admit_control(&request)?;
+        let estimator = match request.into_inner().estimator.parse::<RegressionType>() {
+            Ok(estimator) => estimator,
+            Err(e) => {
+                return Ok(Response::new(EstimatorReply {
+                    accepted: false,
+                    reason: e.to_string(),
+                }))
+            }
+        };
+        self.sender
+            .send(CapEvent::SetEstimator(estimator))
+            .await
+            .map_err(|_| Status::unavailable("Parser has stopped"))?;
+
+        Ok(Response::new(EstimatorReply {
+            accepted: true,
+            reason: String::new(),
+        }))
+    }
+
+    /// Handler for the SyncClock RPC.
+    /// Timestamps the request so the peer can estimate the clock offset.
+    async fn sync_clock(
+        &self,
+        request: Request<ClockRequest>,
+    ) -> Result<Response<ClockReply>, Status> {
+        let t2 = Timestamp::now().as_nanos() as i64;
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        self.guard.admit(peer, tokio::time::Instant::now())?;
+        let t1 = request.into_inner().t1;
+        Ok(Response::new(ClockReply {
+            t1,
+            t2,
+            t3: Timestamp::now().as_nanos() as i64,
+        }))
+    }
+
+    /// Handler for the DumpLink RPC.
+    /// Writes the packets of one link to a pcap file on this node for a
+    /// while, and answers once the file is complete.
+    async fn dump_link(
+        &self,
+        request: Request<DumpRequest>,
+    ) -> Result<Response<DumpReply>, Status> {
+        self.admit_control(&request)?;
+        let inner = request.into_inner();
+        let remote = inner
+            .remote_ip
+            .parse::<IpAddr>()
+            .map_err(|e| Status::invalid_argument(format!("Invalid remote ip: {}", e)))?;
+        let local: IpAddr = if inner.local_ip.is_empty() {
+            let meta = self.pcap_meta.borrow();
+            if remote.is_ipv4() {
+                meta.ipv4.into()
+            } else {
+                meta.ipv6.into()
+            }
+        } else {
+            inner
+                .local_ip
+                .parse::<IpAddr>()
+                .map_err(|e| Status::invalid_argument(format!("Invalid local ip: {}", e)))?
+        };
+        let duration = tokio::time::Duration::from_secs(inner.duration as u64);
+        if duration.is_zero() || duration > MAX_DUMP_DURATION {
+            return Err(Status::invalid_argument(format!(
+                "Duration must be between 1 and {} seconds",
+                MAX_DUMP_DURATION.as_secs()
+            )));
+        }
+
+        let result = self
+            .dump
+            .dump(IpPair::new(local, remote), duration)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        info!("Dumped {} packets to {:?}", result.packets, result.path);
+
+        Ok(Response::new(DumpReply {
+            path: result.path.to_string_lossy().into_owned(),
+            packets: result.packets,
+            bytes: result.bytes,
+        }))
+    }
+
+    /// Handler for the GetStatus RPC.
+    /// Collects the internal state of the parser and client handler, which
+    /// fill in their parts in turn.
+    async fn get_status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<NodeStatus>, Status> {
+        self.admit_control(&request)?;
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(CapEvent::Status(reply))
+            .await
+            .map_err(|_| Status::unavailable("Parser has stopped"))?;
+        let mut status = match tokio::time::timeout(STATUS_TIMEOUT, rx).await {
+            Ok(Ok(status)) => status,
+            Ok(Err(_)) => return Err(Status::unavailable("Status request was dropped")),
+            Err(_) => return Err(Status::deadline_exceeded("Node did not report its status in time")),
+        };
+        status.uptime = self.started.elapsed().as_secs();
+        status.rejected = self.guard.rejected();
+        Ok(Response::new(status))
+    }
+
+    /// Handler for the GetEstimates RPC.
+    /// Current estimates of the link to the peer, or of every link.
+    async fn get_estimates(
+        &self,
+        request: Request<EstimateRequest>,
+    ) -> Result<Response<EstimateReply>, Status> {
+        let peer = request.into_inner().peer;
+        let query = LinkQuery::new(self.sender.clone());
+        let estimates = if peer.is_empty() {
+            query.estimates().await
+        } else {
+            let remote: IpAddr = peer
+                .parse()
+                .map_err(|_| Status::invalid_argument(format!("Invalid peer {}", peer)))?;
+            match query.estimate(remote).await {
+                Ok(Some(estimate)) => Ok(vec![estimate]),
+                Ok(None) => return Err(Status::not_found(format!("No link to {}", peer))),
+                Err(e) => Err(e),
+            }
+        }
+        .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(Response::new(EstimateReply {
+            estimates: estimates.iter().map(|e| e.to_proto()).collect(),
+        }))
+    }
+
+    /// Handler for the SetSubnets RPC.
+    /// Replaces the prefixes whose traffic is tracked and ignored, in the
+    /// packet filter and, where the link type allows, the capture filter.
+    async fn set_subnets(
+        &self,
+        request: Request<SubnetRequest>,
+    ) -> Result<Response<SubnetReply>, Status> {
+        self.admit_control(&request)?;
+        let inner = request.into_inner();
+        let rules = match SubnetRules::parse(&inner.allow, &inner.deny) {
+            Ok(rules) => rules,
+            Err(e) => {
+                return Ok(Response::new(SubnetReply {
+                    accepted: false,
+                    reason: format!("{:#}", e),
+                }))
+            }
+        };
+        info!("Tracking {:?} and ignoring {:?}", inner.allow, inner.deny);
+        self.subnets.send_replace(rules);
+
+        Ok(Response::new(SubnetReply {
+            accepted: true,
+            reason: String::new(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prost_net::bandwidth_client::{BwClient, ClientEvent, ClientEventResult};
+    use crate::prost_net::test_server::start_server;
+    use crate::proto_bw::bandwidth_service_client::BandwidthServiceClient;
+    use crate::proto_bw::{data_msg, LinkState, Rtts};
+    use tokio::time::{timeout, Duration};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn link_state(sender_ip: &str, receiver_ip: &str) -> LinkState {
+        LinkState {
+            sender_ip: sender_ip.to_string(),
+            receiver_ip: receiver_ip.to_string(),
+            thp_in: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hello_round_trip() {
+        let mut server = start_server().await;
+        let (reply_tx, mut reply_rx) = channel(16);
+        let (_, client) = BwClient::connect("127.0.0.1".to_string(), server.port, reply_tx)
+            .await
+            .unwrap();
+        let Some(ClientEventResult::ServerConnected(ip)) = reply_rx.recv().await else {
+            panic!("Client did not connect");
+        };
+        assert_eq!(ip, "127.0.0.1");
+
+        client
+            .send(ClientEvent::SendHello {
+                message: "10.0.0.2".to_string(),
+            })
+            .await
+            .unwrap();
+        let reply = timeout(WAIT, reply_rx.recv()).await.unwrap();
+        let Some(ClientEventResult::HelloReply(_, Ok(reply))) = reply else {
+            panic!("Hello was not answered");
+        };
+        assert_eq!(reply.ip_addr, "10.0.0.1");
+        assert!(reply.capabilities.is_some());
+
+        // The server hands the hello to the parser
+        let event = timeout(WAIT, server.cap_rx.recv()).await.unwrap();
+        let Some(CapEvent::Protobuf(PbfMsg::HelloRequest(request, _))) = event else {
+            panic!("Hello was not passed on");
+        };
+        assert_eq!(request.name, "10.0.0.2");
+        server.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_bandwidth() {
+        let server = start_server().await;
+        server.store.update(&DataMsg {
+            data: Some(data_msg::Data::Bandwidth(BandwidthMessage {
+                link_state: vec![link_state("10.0.0.1", "10.0.0.2")],
+            })),
+            ..Default::default()
+        });
+
+        let addr = format!("http://127.0.0.1:{}", server.port);
+        let mut client = BandwidthServiceClient::connect(addr).await.unwrap();
+        let mut stream = client
+            .subscribe_bandwidth(BandwidthRequest {
+                name: "test".to_string(),
+                peers: vec!["10.0.0.3".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        // The peer asked for is not in the snapshot, the first message is
+        // the link to it
+        let links = [link_state("10.0.0.1", "10.0.0.2"), link_state("10.0.0.1", "10.0.0.3")];
+        let sent = DataMsg {
+            data: Some(data_msg::Data::Bandwidth(BandwidthMessage {
+                link_state: links.to_vec(),
+            })),
+            seq: 7,
+            ..Default::default()
+        };
+        server.bw_tx.send(sent).unwrap();
+        server
+            .bw_tx
+            .send(DataMsg {
+                data: Some(data_msg::Data::Rtts(Rtts::default())),
+                seq: 8,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let msg = timeout(WAIT, stream.message()).await.unwrap().unwrap().unwrap();
+        assert_eq!(msg.seq, 7);
+        let Some(data_msg::Data::Bandwidth(bw)) = msg.data else {
+            panic!("Expected link states");
+        };
+        assert_eq!(bw.link_state, vec![links[1].clone()]);
+
+        // Dropped by the filter, nothing more arrives
+        let rest = timeout(Duration::from_millis(200), stream.message()).await;
+        assert!(rest.is_err());
+        server.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_probe_needs_hello() {
+        let server = start_server().await;
+        let addr = format!("http://127.0.0.1:{}", server.port);
+        let mut client = BandwidthServiceClient::connect(addr).await.unwrap();
+        let status = client
+            .request_probe(ProbeRequest {
+                technique: "train".to_string(),
+                duration: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        server.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_set_subnets_from_this_host() {
+        let server = start_server().await;
+        let addr = format!("http://127.0.0.1:{}", server.port);
+        let mut client = BandwidthServiceClient::connect(addr).await.unwrap();
+        let reply = client
+            .set_subnets(SubnetRequest {
+                allow: vec!["10.0.0.0/8".to_string()],
+                deny: Vec::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(reply.accepted, "{}", reply.reason);
+        let reply = client
+            .set_subnets(SubnetRequest {
+                allow: vec!["10.0.0.0/33".to_string()],
+                deny: Vec::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!reply.accepted);
+        server.shutdown.cancel();
+    }
 }
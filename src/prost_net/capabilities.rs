@@ -0,0 +1,103 @@
+use std::env;
+use std::path::Path;
+
+use crate::proto_bw::{NodeCapabilities, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// External probe tools and the binary each one needs.
+const PROBES: [(&str, &str); 2] = [("iperf3", "iperf3"), ("pathload", "pathload_rcv")];
+/// Probes built into the listener, always available.
+const BUILTIN_PROBES: [&str; 1] = ["train"];
+
+/// Returns true if `binary` is found in one of the `PATH` directories.
+fn in_path(binary: &str) -> bool {
+    match env::var_os("PATH") {
+        Some(paths) => env::split_paths(&paths).any(|dir| Path::new(&dir).join(binary).is_file()),
+        None => false,
+    }
+}
+
+/// Capabilities of this node, sent in hello requests and replies.
+pub fn local_capabilities() -> NodeCapabilities {
+    NodeCapabilities {
+        probes: PROBES
+            .iter()
+            .filter(|(_, binary)| in_path(binary))
+            .map(|(name, _)| name.to_string())
+            .chain(BUILTIN_PROBES.iter().map(|name| name.to_string()))
+            .collect(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        measurement_window: crate::CONFIG.client.measurement_window.as_secs(),
+        link_phy_cap: crate::CONFIG.client.link_phy_cap,
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+    }
+}
+
+/// Checks that this node and the peer understand each other's messages.
+/// Peers from before versioning report version 0 and are refused.
+pub fn check_compatible(peer: &NodeCapabilities) -> Result<(), String> {
+    if peer.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "peer speaks protocol version {} (listener {}), at least {} is needed",
+            peer.protocol_version, peer.version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    if peer.min_protocol_version > PROTOCOL_VERSION {
+        return Err(format!(
+            "peer needs protocol version {} or later (listener {}), this node speaks {}",
+            peer.min_protocol_version, peer.version, PROTOCOL_VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// Returns true if the peer advertises the given probe.
+pub fn supports_probe(peer: &NodeCapabilities, probe: &str) -> bool {
+    peer.probes.iter().any(|p| p == probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(window: u64) -> NodeCapabilities {
+        NodeCapabilities {
+            probes: vec!["iperf3".to_string()],
+            version: "0.1.0".to_string(),
+            measurement_window: window,
+            link_phy_cap: 0,
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    #[test]
+    fn test_supports_probe() {
+        let peer = caps(20);
+        assert!(supports_probe(&peer, "iperf3"));
+        assert!(!supports_probe(&peer, "pathload"));
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        assert!(check_compatible(&caps(20)).is_ok());
+        // Before versioning
+        let old = NodeCapabilities {
+            protocol_version: 0,
+            min_protocol_version: 0,
+            ..caps(20)
+        };
+        assert!(check_compatible(&old).is_err());
+        let newer = NodeCapabilities {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..caps(20)
+        };
+        assert!(check_compatible(&newer).is_ok());
+        let breaking = NodeCapabilities {
+            protocol_version: PROTOCOL_VERSION + 1,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            ..caps(20)
+        };
+        assert!(check_compatible(&breaking).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Clock offset between peers, estimated NTP style from timestamped
+//! SyncClock exchanges.
+//!
+//! `t1` is when the request left this node, `t2` when the peer received it,
+//! `t3` when the peer replied and `t4` when the reply arrived, all in ns
+//! since the epoch on the respective clock.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Exchanges kept per peer. The one with the lowest round trip time is the
+/// least disturbed by queueing and gives the offset.
+pub const CLOCK_SAMPLES: usize = 8;
+
+/// Result of one exchange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    /// Peer clock minus local clock, ns.
+    pub offset: i64,
+    /// Round trip time, not counting the time the peer held the request.
+    pub rtt: Duration,
+}
+
+impl ClockSample {
+    /// None if the timestamps are inconsistent, e.g. the peer replied before
+    /// it received the request.
+    pub fn from_exchange(t1: i64, t2: i64, t3: i64, t4: i64) -> Option<Self> {
+        let rtt = (t4 - t1) - (t3 - t2);
+        if rtt < 0 || t3 < t2 {
+            return None;
+        }
+        Some(ClockSample {
+            offset: ((t2 - t1) + (t3 - t4)) / 2,
+            rtt: Duration::from_nanos(rtt as u64),
+        })
+    }
+}
+
+/// Offset to one peer from its most recent exchanges.
+#[derive(Debug, Default)]
+pub struct ClockOffset {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockOffset {
+    pub fn new() -> Self {
+        ClockOffset::default()
+    }
+
+    pub fn add(&mut self, sample: ClockSample) {
+        if self.samples.len() == CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// The sample with the lowest round trip time, if any.
+    pub fn best(&self) -> Option<ClockSample> {
+        self.samples.iter().min_by_key(|s| s.rtt).copied()
+    }
+
+    /// Peer clock minus local clock in ns. Subtract it from a peer timestamp
+    /// to get local time.
+    pub fn offset(&self) -> Option<i64> {
+        self.best().map(|s| s.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_from_exchange() {
+        // Peer is 1 ms ahead, 200 µs each way, 50 µs to reply
+        let t1 = 1_000_000_000;
+        let t2 = t1 + 200_000 + 1_000_000;
+        let t3 = t2 + 50_000;
+        let t4 = t3 - 1_000_000 + 200_000;
+        let sample = ClockSample::from_exchange(t1, t2, t3, t4).unwrap();
+        assert_eq!(sample.offset, 1_000_000);
+        assert_eq!(sample.rtt, Duration::from_micros(400));
+
+        assert!(ClockSample::from_exchange(t1, t3, t2, t4).is_none());
+    }
+
+    #[test]
+    fn test_min_rtt_wins() {
+        let mut clock = ClockOffset::new();
+        assert_eq!(clock.offset(), None);
+        clock.add(ClockSample { offset: 500, rtt: Duration::from_millis(5) });
+        clock.add(ClockSample { offset: 100, rtt: Duration::from_millis(1) });
+        clock.add(ClockSample { offset: 900, rtt: Duration::from_millis(9) });
+        assert_eq!(clock.offset(), Some(100));
+
+        // The best sample ages out
+        for _ in 0..CLOCK_SAMPLES {
+            clock.add(ClockSample { offset: 300, rtt: Duration::from_millis(3) });
+        }
+        assert_eq!(clock.offset(), Some(300));
+    }
+}
@@ -0,0 +1,172 @@
+//! Read only HTTP JSON API, for consumers that do not speak gRPC. Served
+//! from the same `MetricsStore` as `SubscribeBandwidth`:
+//!
+//! - `GET /links`: last state of every link
+//! - `GET /links/{peer}`: last state of the links to or from an IP
+//! - `GET /probes`: the latest active probe results, oldest first
+//! - `GET /estimates`: current estimates of every link, from the parser
+//! - `GET /estimates/{peer}`: current estimates of the link to an IP
+//!
+//! Link states and estimates are the `LinkState` and `LinkEstimate` proto
+//! messages, with the same field names.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use axum::extract::{FromRef, Path, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use log::info;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::listener::tracking::estimates::LinkQuery;
+use crate::prost_net::metrics_store::{MetricsStore, ProbeRecord};
+use crate::proto_bw::{LinkEstimate, LinkState};
+use crate::tap::Tap;
+
+#[derive(Clone)]
+struct ApiState {
+    store: MetricsStore,
+    query: LinkQuery,
+}
+
+impl FromRef<ApiState> for MetricsStore {
+    fn from_ref(state: &ApiState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<ApiState> for LinkQuery {
+    fn from_ref(state: &ApiState) -> Self {
+        state.query.clone()
+    }
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/links", get(links))
+        .route("/links/{peer}", get(peer_links))
+        .route("/probes", get(probes))
+        .route("/estimates", get(estimates))
+        .route("/estimates/{peer}", get(peer_estimate))
+        .with_state(state)
+}
+
+async fn links(State(store): State<MetricsStore>) -> Json<Vec<LinkState>> {
+    Json(store.links())
+}
+
+async fn peer_links(
+    State(store): State<MetricsStore>,
+    Path(peer): Path<String>,
+) -> Result<Json<Vec<LinkState>>, StatusCode> {
+    let links = store.links_of(&peer);
+    if links.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(links))
+}
+
+async fn probes(State(store): State<MetricsStore>) -> Json<Vec<ProbeRecord>> {
+    Json(store.probes())
+}
+
+async fn estimates(
+    State(query): State<LinkQuery>,
+) -> Result<Json<Vec<LinkEstimate>>, StatusCode> {
+    let estimates = query
+        .estimates()
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(estimates.iter().map(|e| e.to_proto()).collect()))
+}
+
+async fn peer_estimate(
+    State(query): State<LinkQuery>,
+    Path(peer): Path<String>,
+) -> Result<Json<LinkEstimate>, StatusCode> {
+    let remote: IpAddr = peer.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    match query.estimate(remote).await {
+        Ok(Some(estimate)) => Ok(Json(estimate.to_proto())),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+/// Binds `addr` and serves the API in the background until `shutdown` is
+/// cancelled. Probe results published on `tap` are kept in `store` from now
+/// on. Estimates are asked of the parser through `query`.
+///
+/// Fails right away if the address cannot be bound.
+pub fn dispatch_http_api(
+    addr: SocketAddr,
+    store: MetricsStore,
+    query: LinkQuery,
+    tap: &Tap,
+    shutdown: CancellationToken,
+) -> Result<JoinHandle<Result<()>>> {
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            tokio::net::TcpListener::from_std(listener)
+        })
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    info!("HTTP API listening on {}", addr);
+    let recorder = store.dispatch_probe_recorder(tap);
+
+    Ok(tokio::spawn(async move {
+        let result = axum::serve(listener, router(ApiState { store, query }))
+            .with_graceful_shutdown(async move { shutdown.cancelled().await })
+            .await;
+        recorder.abort();
+        result?;
+        info!("HTTP API on {} stopped", addr);
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto_bw::{data_msg, BandwidthMessage, DataMsg};
+
+    #[tokio::test]
+    async fn test_links_of_peer() {
+        let store = MetricsStore::new();
+        store.update(&DataMsg {
+            data: Some(data_msg::Data::Bandwidth(BandwidthMessage {
+                link_state: vec![LinkState {
+                    sender_ip: "10.0.0.1".to_string(),
+                    receiver_ip: "10.0.0.2".to_string(),
+                    thp_in: 1.0,
+                    ..Default::default()
+                }],
+            })),
+            ..Default::default()
+        });
+
+        let Json(links) = peer_links(State(store.clone()), Path("10.0.0.2".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(links[0].thp_in, 1.0);
+        let json = serde_json::to_value(&links).unwrap();
+        assert_eq!(json[0]["receiver_ip"], "10.0.0.2");
+
+        let missing = peer_links(State(store), Path("10.0.0.3".to_string())).await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_estimates_errors() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        drop(receiver);
+        let query = LinkQuery::new(sender);
+
+        let bad = peer_estimate(State(query.clone()), Path("not-an-ip".to_string())).await;
+        assert_eq!(bad.unwrap_err(), StatusCode::BAD_REQUEST);
+        let stopped = estimates(State(query)).await;
+        assert_eq!(stopped.unwrap_err(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
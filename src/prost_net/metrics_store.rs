@@ -0,0 +1,447 @@
+//! Last known metrics of each link and the latest probe results, for
+//! consumers that connect between reports, and the per subscriber filter of
+//! `SubscribeBandwidth`.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+use crate::anonymize::export_ip;
+use crate::proto_bw::{data_msg, BandwidthMessage, BandwidthRequest, DataKind, DataMsg, LinkState};
+use crate::tap::{ProbeSample, Tap, TapEvent, TapFilter};
+use crate::Timestamp;
+
+/// Probe results kept, the oldest are dropped first.
+const MAX_PROBES: usize = 256;
+
+/// An active probe result, as served by the HTTP API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProbeRecord {
+    pub remote: IpAddr,
+    pub technique: &'static str,
+    /// Bytes/sec
+    pub throughput: f64,
+    /// Milliseconds since epoch, when the result came in.
+    pub timestamp: i64,
+}
+
+/// Shared between the client handler, which fills it from the data
+/// messages it sends, and the servers that read it.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsStore {
+    inner: Arc<RwLock<Stored>>,
+}
+
+#[derive(Debug, Default)]
+struct Stored {
+    /// Keyed by sender and receiver IP.
+    links: BTreeMap<(String, String), LinkState>,
+    /// Of the last message, so snapshots look like the messages sent.
+    node_id: String,
+    epoch: i64,
+    /// Oldest first.
+    probes: VecDeque<ProbeRecord>,
+}
+
+impl MetricsStore {
+    pub fn new() -> Self {
+        MetricsStore::default()
+    }
+
+    /// Keeps the link states in `msg`, if it has any.
+    pub fn update(&self, msg: &DataMsg) {
+        let link_state = match &msg.data {
+            Some(data_msg::Data::Bandwidth(bw)) => &bw.link_state,
+            Some(data_msg::Data::Report(report)) => &report.link_state,
+            _ => return,
+        };
+        let mut stored = self.inner.write().unwrap();
+        stored.node_id.clone_from(&msg.node_id);
+        stored.epoch = msg.epoch;
+        for state in link_state {
+            stored.links.insert(
+                (state.sender_ip.clone(), state.receiver_ip.clone()),
+                state.clone(),
+            );
+        }
+    }
+
+    /// Last state of every link, ordered by sender and receiver.
+    pub fn links(&self) -> Vec<LinkState> {
+        self.inner.read().unwrap().links.values().cloned().collect()
+    }
+
+    /// Last state of the links to or from `peer`.
+    pub fn links_of(&self, peer: &str) -> Vec<LinkState> {
+        self.inner
+            .read()
+            .unwrap()
+            .links
+            .values()
+            .filter(|l| l.sender_ip == peer || l.receiver_ip == peer)
+            .cloned()
+            .collect()
+    }
+
+    pub fn record_probe(&self, sample: &ProbeSample, timestamp: Timestamp) {
+        let mut stored = self.inner.write().unwrap();
+        if stored.probes.len() == MAX_PROBES {
+            stored.probes.pop_front();
+        }
+        stored.probes.push_back(ProbeRecord {
+            remote: export_ip(sample.remote),
+            technique: sample.technique.as_str(),
+            throughput: sample.throughput,
+            timestamp: timestamp.as_millis(),
+        });
+    }
+
+    /// The latest probe results, oldest first.
+    pub fn probes(&self) -> Vec<ProbeRecord> {
+        self.inner.read().unwrap().probes.iter().cloned().collect()
+    }
+
+    /// Keeps the probe results published on `tap` until it is dropped.
+    pub fn dispatch_probe_recorder(&self, tap: &Tap) -> JoinHandle<()> {
+        let store = self.clone();
+        let mut probes = Box::pin(tap.subscribe(TapFilter::probes()));
+        tokio::spawn(async move {
+            while let Some(event) = probes.next().await {
+                if let TapEvent::Probe(sample) = event {
+                    store.record_probe(&sample, Timestamp::now());
+                }
+            }
+        })
+    }
+
+    /// The last link states as one message, None before the first report.
+    pub fn snapshot(&self) -> Option<DataMsg> {
+        let stored = self.inner.read().unwrap();
+        if stored.links.is_empty() {
+            return None;
+        }
+        let link_state = stored.links.values().cloned().collect();
+        Some(DataMsg {
+            data: Some(data_msg::Data::Bandwidth(BandwidthMessage { link_state })),
+            node_id: stored.node_id.clone(),
+            epoch: stored.epoch,
+            ..Default::default()
+        })
+    }
+}
+
+/// What a `SubscribeBandwidth` subscriber asked for.
+#[derive(Debug, Clone, Default)]
+pub struct DataFilter {
+    /// Remote IPs, all if empty.
+    peers: HashSet<String>,
+    /// Sender and receiver IP, empty for any. All links if empty.
+    links: Vec<(String, String)>,
+    /// All if empty.
+    kinds: HashSet<DataKind>,
+    /// Shortest time between two states of a link, zero for none.
+    min_interval: Duration,
+    /// When the state of each link was last let through, with
+    /// `min_interval`.
+    last_sent: HashMap<(String, String), Instant>,
+}
+
+impl DataFilter {
+    pub fn new(request: &BandwidthRequest) -> Self {
+        DataFilter {
+            peers: request.peers.iter().cloned().collect(),
+            links: request
+                .links
+                .iter()
+                .map(|l| (l.sender_ip.clone(), l.receiver_ip.clone()))
+                .collect(),
+            kinds: request.kinds().collect(),
+            min_interval: Duration::from_millis(request.min_interval_ms as u64),
+            last_sent: HashMap::new(),
+        }
+    }
+
+    fn kind(data: &data_msg::Data) -> DataKind {
+        match data {
+            data_msg::Data::Bandwidth(_) => DataKind::Bandwidth,
+            data_msg::Data::Hello(_) => DataKind::Hello,
+            data_msg::Data::Rtts(_) => DataKind::Rtts,
+            data_msg::Data::Pgmmsg(_) => DataKind::Pgm,
+            data_msg::Data::Report(_) => DataKind::Report,
+            data_msg::Data::Events(_) => DataKind::Events,
+        }
+    }
+
+    fn wants_kind(&self, kind: DataKind) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(&kind)
+    }
+
+    fn wants_link(&self, sender: &str, receiver: &str) -> bool {
+        let peer = self.peers.is_empty()
+            || self.peers.contains(receiver)
+            || self.peers.contains(sender);
+        let link = self.links.is_empty()
+            || self.links.iter().any(|(s, r)| {
+                (s.is_empty() || s == sender) && (r.is_empty() || r == receiver)
+            });
+        peer && link
+    }
+
+    /// True if the state of a link is wanted and due at `now`, which counts
+    /// it as sent.
+    fn wants_link_state(&mut self, state: &LinkState, now: Instant) -> bool {
+        if !self.wants_link(&state.sender_ip, &state.receiver_ip) {
+            return false;
+        }
+        if self.min_interval.is_zero() {
+            return true;
+        }
+        let key = (state.sender_ip.clone(), state.receiver_ip.clone());
+        match self.last_sent.get(&key) {
+            Some(last) if now.duration_since(*last) < self.min_interval => false,
+            _ => {
+                self.last_sent.insert(key, now);
+                true
+            }
+        }
+    }
+
+    /// The part of `msg` the subscriber asked for, None if nothing is left.
+    /// Reports are cut down to the kinds asked for unless they are one.
+    /// Link states within `min_interval` of the last one sent of their link
+    /// are left out.
+    pub fn apply(&mut self, mut msg: DataMsg) -> Option<DataMsg> {
+        let now = Instant::now();
+        let data = msg.data.as_mut()?;
+        if let data_msg::Data::Report(report) = data {
+            if !self.wants_kind(DataKind::Report) {
+                if !self.wants_kind(DataKind::Bandwidth) {
+                    report.link_state.clear();
+                }
+                if !self.wants_kind(DataKind::Rtts) {
+                    report.rtts = None;
+                }
+                if !self.wants_kind(DataKind::Pgm) {
+                    report.pgm = None;
+                }
+            }
+        } else if !self.wants_kind(Self::kind(data)) {
+            return None;
+        }
+        // Reports may have been cut down to nothing
+        let every_link = self.peers.is_empty() && self.links.is_empty();
+        if every_link && self.min_interval.is_zero() && !matches!(data, data_msg::Data::Report(_)) {
+            return Some(msg);
+        }
+        let empty = match data {
+            data_msg::Data::Bandwidth(bw) => {
+                bw.link_state.retain(|l| self.wants_link_state(l, now));
+                bw.link_state.is_empty()
+            }
+            data_msg::Data::Rtts(rtts) => {
+                rtts.rtts
+                    .retain(|r| self.wants_link(&r.sender_ip, &r.receiver_ip));
+                rtts.rtts.is_empty()
+            }
+            data_msg::Data::Pgmmsg(pgm) => {
+                pgm.pgm_dps
+                    .retain(|p| self.wants_link(&p.sender_ip, &p.receiver_ip));
+                pgm.pgm_dps.is_empty()
+            }
+            data_msg::Data::Report(report) => {
+                report.link_state.retain(|l| self.wants_link_state(l, now));
+                if let Some(rtts) = &mut report.rtts {
+                    rtts.rtts
+                        .retain(|r| self.wants_link(&r.sender_ip, &r.receiver_ip));
+                }
+                if let Some(pgm) = &mut report.pgm {
+                    pgm.pgm_dps
+                        .retain(|p| self.wants_link(&p.sender_ip, &p.receiver_ip));
+                }
+                report.link_state.is_empty()
+                    && report.rtts.as_ref().is_none_or(|r| r.rtts.is_empty())
+                    && report.pgm.as_ref().is_none_or(|p| p.pgm_dps.is_empty())
+            }
+            data_msg::Data::Events(events) => {
+                events
+                    .events
+                    .retain(|e| self.wants_link(&e.sender_ip, &e.receiver_ip));
+                events.events.is_empty()
+            }
+            data_msg::Data::Hello(_) => false,
+        };
+        (!empty).then_some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::session::ProbeTechnique;
+    use crate::proto_bw::{LinkSelector, Report, Rtts, RttMessage};
+
+    fn link(sender: &str, receiver: &str, thp_in: f64) -> LinkState {
+        LinkState {
+            sender_ip: sender.to_string(),
+            receiver_ip: receiver.to_string(),
+            thp_in,
+            ..Default::default()
+        }
+    }
+
+    fn bandwidth(links: Vec<LinkState>) -> DataMsg {
+        DataMsg {
+            data: Some(data_msg::Data::Bandwidth(BandwidthMessage { link_state: links })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_snapshot_and_filter() {
+        let store = MetricsStore::new();
+        assert!(store.snapshot().is_none());
+        store.update(&bandwidth(vec![link("10.0.0.1", "10.0.0.2", 1.0)]));
+        store.update(&bandwidth(vec![
+            link("10.0.0.1", "10.0.0.3", 2.0),
+            link("10.0.0.1", "10.0.0.2", 3.0),
+        ]));
+        let links = store.links();
+        assert_eq!(links.len(), 2);
+        assert_eq!((links[0].receiver_ip.as_str(), links[0].thp_in), ("10.0.0.2", 3.0));
+
+        let mut request = BandwidthRequest {
+            peers: vec!["10.0.0.3".to_string()],
+            ..Default::default()
+        };
+        let mut filter = DataFilter::new(&request);
+        let Some(DataMsg {
+            data: Some(data_msg::Data::Bandwidth(bw)),
+            ..
+        }) = filter.apply(store.snapshot().unwrap())
+        else {
+            panic!("Expected link states");
+        };
+        assert_eq!(bw.link_state.len(), 1);
+        assert_eq!(bw.link_state[0].receiver_ip, "10.0.0.3");
+
+        let rtts = DataMsg {
+            data: Some(data_msg::Data::Rtts(Rtts {
+                rtts: vec![RttMessage {
+                    sender_ip: "10.0.0.1".to_string(),
+                    receiver_ip: "10.0.0.2".to_string(),
+                    rtt: Vec::new(),
+                    buckets: Vec::new(),
+                }],
+                truncated: false,
+            })),
+            ..Default::default()
+        };
+        assert!(filter.apply(rtts.clone()).is_none());
+
+        request.peers.clear();
+        request.push_kinds(DataKind::Bandwidth);
+        let mut filter = DataFilter::new(&request);
+        assert!(filter.apply(rtts).is_none());
+        assert!(filter.apply(store.snapshot().unwrap()).is_some());
+    }
+
+    #[test]
+    fn test_filter_cuts_reports() {
+        let report = DataMsg {
+            data: Some(data_msg::Data::Report(Report {
+                link_state: vec![link("10.0.0.1", "10.0.0.2", 1.0)],
+                rtts: Some(Rtts {
+                    rtts: vec![RttMessage {
+                        sender_ip: "10.0.0.1".to_string(),
+                        receiver_ip: "10.0.0.3".to_string(),
+                        rtt: Vec::new(),
+                        buckets: Vec::new(),
+                    }],
+                    truncated: false,
+                }),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let store = MetricsStore::new();
+        store.update(&report);
+        assert_eq!(store.links().len(), 1);
+
+        let mut request = BandwidthRequest::default();
+        request.push_kinds(DataKind::Rtts);
+        let Some(data_msg::Data::Report(cut)) = DataFilter::new(&request)
+            .apply(report.clone())
+            .and_then(|msg| msg.data)
+        else {
+            panic!("Expected a report");
+        };
+        assert!(cut.link_state.is_empty());
+        assert!(cut.rtts.is_some());
+
+        // Nothing left of it for these
+        request.peers.push("10.0.0.2".to_string());
+        assert!(DataFilter::new(&request).apply(report.clone()).is_none());
+        let mut request = BandwidthRequest::default();
+        request.push_kinds(DataKind::Hello);
+        assert!(DataFilter::new(&request).apply(report).is_none());
+    }
+
+    #[test]
+    fn test_filter_links_and_interval() {
+        let links = || {
+            bandwidth(vec![
+                link("10.0.0.1", "10.0.0.2", 1.0),
+                link("10.0.0.2", "10.0.0.1", 2.0),
+                link("10.0.0.1", "10.0.0.3", 3.0),
+            ])
+        };
+        let request = BandwidthRequest {
+            links: vec![LinkSelector {
+                sender_ip: String::new(),
+                receiver_ip: "10.0.0.2".to_string(),
+            }],
+            min_interval_ms: 60_000,
+            ..Default::default()
+        };
+        let mut filter = DataFilter::new(&request);
+        let Some(data_msg::Data::Bandwidth(bw)) = filter.apply(links()).and_then(|m| m.data)
+        else {
+            panic!("Expected link states");
+        };
+        assert_eq!(bw.link_state, vec![link("10.0.0.1", "10.0.0.2", 1.0)]);
+
+        // Too soon for the link again
+        assert!(filter.apply(links()).is_none());
+        let mut request = request;
+        request.min_interval_ms = 0;
+        assert!(DataFilter::new(&request).apply(links()).is_some());
+    }
+
+    #[test]
+    fn test_probes_keep_the_latest() {
+        let store = MetricsStore::new();
+        store.update(&bandwidth(vec![
+            link("10.0.0.1", "10.0.0.2", 1.0),
+            link("10.0.0.3", "10.0.0.1", 2.0),
+        ]));
+        assert_eq!(store.links_of("10.0.0.3").len(), 1);
+        assert!(store.links_of("10.0.0.4").is_empty());
+
+        for i in 0..MAX_PROBES + 1 {
+            let sample = ProbeSample {
+                remote: [10, 0, 0, 2].into(),
+                technique: ProbeTechnique::Train,
+                throughput: i as f64,
+            };
+            store.record_probe(&sample, Timestamp::from_millis(1_000_000));
+        }
+        let probes = store.probes();
+        assert_eq!(probes.len(), MAX_PROBES);
+        assert_eq!((probes[0].throughput, probes[0].technique), (1.0, "train"));
+    }
+}
@@ -1,2 +1,12 @@
 pub mod bandwidth_client;
 pub mod bandwidth_server;
+pub mod capabilities;
+pub mod clock;
+pub mod http_api;
+pub mod metrics_store;
+pub mod probe_limiter;
+pub mod request_guard;
+#[cfg(test)]
+pub mod test_server;
+pub mod trace;
+pub mod upstream;
@@ -0,0 +1,151 @@
+//! Caps on active probing, so probes do not pile up and drown the passive
+//! measurements they are meant to complement.
+//!
+//! At most `max_concurrent` probes run at once, each holding a permit until
+//! it is done. Probes beyond that wait in a short queue. A link is not
+//! probed again until `min_spacing` has passed since its last probe started.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+
+/// Probes waiting for a free slot, beyond which new ones are rejected.
+pub const MAX_QUEUED_PROBES: usize = 16;
+
+/// What happened to a probe request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeStatus {
+    Started,
+    /// Queued until a slot frees up.
+    Deferred,
+    Rejected(String),
+}
+
+/// Outcome of `ProbeLimiter::request`.
+#[derive(Debug)]
+pub enum Admission<T> {
+    /// Run `T` now, holding the permit until it is done.
+    Start(OwnedSemaphorePermit, T),
+    Deferred,
+    Rejected(String),
+}
+
+impl<T> Admission<T> {
+    pub fn status(&self) -> ProbeStatus {
+        match self {
+            Admission::Start(..) => ProbeStatus::Started,
+            Admission::Deferred => ProbeStatus::Deferred,
+            Admission::Rejected(reason) => ProbeStatus::Rejected(reason.clone()),
+        }
+    }
+}
+
+/// Admits probes toward peers, `T` being whatever is needed to run one.
+#[derive(Debug)]
+pub struct ProbeLimiter<T> {
+    max_concurrent: usize,
+    slots: Arc<Semaphore>,
+    min_spacing: Duration,
+    /// When the last probe toward each peer started.
+    last_probe: HashMap<IpAddr, Instant>,
+    queue: VecDeque<(IpAddr, T)>,
+}
+
+impl<T> ProbeLimiter<T> {
+    /// A `max_concurrent` of 0 is treated as 1.
+    pub fn new(max_concurrent: usize, min_spacing: Duration) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+        ProbeLimiter {
+            max_concurrent,
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            min_spacing,
+            last_probe: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Decides on a new probe toward `ip`.
+    pub fn request(&mut self, ip: IpAddr, probe: T, now: Instant) -> Admission<T> {
+        if let Some(last) = self.last_probe.get(&ip) {
+            let since = now.saturating_duration_since(*last);
+            if since < self.min_spacing {
+                return Admission::Rejected(format!(
+                    "last probe started {:?} ago, minimum spacing is {:?}",
+                    since, self.min_spacing
+                ));
+            }
+        }
+        if self.queue.iter().any(|(queued, _)| *queued == ip) {
+            return Admission::Rejected(String::from("a probe is already queued"));
+        }
+        // Queued probes go first
+        if self.queue.is_empty() {
+            if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+                self.last_probe.insert(ip, now);
+                return Admission::Start(permit, probe);
+            }
+        }
+        if self.queue.len() >= MAX_QUEUED_PROBES {
+            return Admission::Rejected(String::from("probe queue is full"));
+        }
+        self.queue.push_back((ip, probe));
+        Admission::Deferred
+    }
+
+    /// The oldest queued probe, if a slot is free.
+    pub fn next_ready(&mut self, now: Instant) -> Option<(OwnedSemaphorePermit, IpAddr, T)> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        let permit = self.slots.clone().try_acquire_owned().ok()?;
+        let (ip, probe) = self.queue.pop_front()?;
+        self.last_probe.insert(ip, now);
+        Some((permit, ip, probe))
+    }
+
+    /// Probes currently running.
+    pub fn running(&self) -> usize {
+        self.max_concurrent - self.slots.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_and_queue() {
+        let a: IpAddr = [10, 0, 0, 2].into();
+        let b: IpAddr = [10, 0, 0, 3].into();
+        let now = Instant::now();
+        let mut limiter = ProbeLimiter::new(1, Duration::from_secs(10));
+
+        let permit = match limiter.request(a, "a", now) {
+            Admission::Start(permit, "a") => permit,
+            other => panic!("Unexpected admission {:?}", other),
+        };
+        assert_eq!(limiter.running(), 1);
+        // Too soon for the same link
+        assert!(matches!(limiter.request(a, "a", now), Admission::Rejected(_)));
+        // No free slot
+        assert_eq!(limiter.request(b, "b", now).status(), ProbeStatus::Deferred);
+        assert!(matches!(limiter.request(b, "b", now), Admission::Rejected(_)));
+        assert!(limiter.next_ready(now).is_none());
+
+        drop(permit);
+        let (_permit, ip, probe) = limiter.next_ready(now).unwrap();
+        assert_eq!((ip, probe), (b, "b"));
+        assert_eq!(limiter.queued(), 0);
+
+        // Spacing is up for a, but b holds the slot
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.request(a, "a", later).status(), ProbeStatus::Deferred);
+    }
+}
@@ -0,0 +1,361 @@
+//! Checks on the requests other nodes make to the gRPC server: hellos,
+//! probe requests and clock syncs. Only `client.known_peers` get through,
+//! if set, each address within `client.request_rate`. Hellos with an
+//! identity must carry a valid, fresh one, and with
+//! `client.require_identity` they must carry one. Without `known_peers`,
+//! probes are only run for peers that said hello first. Requests that
+//! change or dump what the node does are only taken from this host and
+//! `client.control_peers`. Requests turned down are counted by reason for
+//! the node status.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use log::debug;
+use tokio::time::Instant;
+use tonic::Status;
+
+use crate::identity::{self, ReplayGuard, Verified};
+use crate::proto_bw::PeerIdentity;
+use crate::Timestamp;
+
+/// Addresses whose request rate is tracked, beyond which the idle ones are
+/// forgotten.
+const MAX_SOURCES: usize = 4096;
+
+/// Why a request was turned down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    UnknownPeer,
+    NotControl,
+    NoHello,
+    RateLimited,
+    MissingIdentity,
+    InvalidIdentity(String),
+    Replayed,
+}
+
+impl Rejection {
+    /// Key of the counter in the node status.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::UnknownPeer => "unknown_peer",
+            Rejection::NotControl => "not_control",
+            Rejection::NoHello => "no_hello",
+            Rejection::RateLimited => "rate_limited",
+            Rejection::MissingIdentity => "missing_identity",
+            Rejection::InvalidIdentity(_) => "invalid_identity",
+            Rejection::Replayed => "replayed",
+        }
+    }
+}
+
+impl From<Rejection> for Status {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::UnknownPeer => Status::permission_denied("Not a known peer"),
+            Rejection::NotControl => Status::permission_denied("Not allowed to control the node"),
+            Rejection::NoHello => Status::permission_denied("No hello from this peer"),
+            Rejection::RateLimited => Status::resource_exhausted("Too many requests"),
+            Rejection::MissingIdentity => Status::unauthenticated("Hello without an identity"),
+            Rejection::InvalidIdentity(e) => {
+                Status::unauthenticated(format!("Invalid identity: {}", e))
+            }
+            Rejection::Replayed => Status::unauthenticated("Replayed identity"),
+        }
+    }
+}
+
+/// Requests an address may still make, refilled at the request rate.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    replays: ReplayGuard,
+    /// Addresses whose hello got through.
+    greeted: HashSet<IpAddr>,
+    rejected: HashMap<&'static str, u64>,
+}
+
+/// Decides on the requests to the gRPC server, shared by its handlers.
+#[derive(Debug)]
+pub struct RequestGuard {
+    /// Anyone if empty.
+    known_peers: HashSet<IpAddr>,
+    /// Besides the loopback addresses.
+    control_peers: HashSet<IpAddr>,
+    require_identity: bool,
+    /// Requests per second, 0 for no limit.
+    rate: f64,
+    burst: f64,
+    state: Mutex<State>,
+}
+
+impl RequestGuard {
+    pub fn new(known_peers: &[IpAddr], require_identity: bool, rate: f64, burst: u32) -> Self {
+        RequestGuard {
+            known_peers: known_peers.iter().copied().collect(),
+            control_peers: HashSet::new(),
+            require_identity,
+            rate: rate.max(0.0),
+            burst: burst.max(1) as f64,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Lets `peers` control the node as well as this host.
+    pub fn with_control_peers(mut self, peers: &[IpAddr]) -> Self {
+        self.control_peers = peers.iter().copied().collect();
+        self
+    }
+
+    pub fn from_config() -> Self {
+        let client = &crate::CONFIG.client;
+        RequestGuard::new(
+            &client.known_peers,
+            client.require_identity,
+            client.request_rate,
+            client.request_burst,
+        )
+        .with_control_peers(&client.control_peers)
+    }
+
+    /// Lets a request from `peer` through, or counts why it was not.
+    /// `peer` is None if the transport has no address.
+    pub fn admit(&self, peer: Option<IpAddr>, now: Instant) -> Result<(), Rejection> {
+        let mut state = self.state.lock().unwrap();
+        let admitted = self.check(&mut state, peer, now);
+        Self::count(&mut state, peer, admitted)
+    }
+
+    /// `admit` for a hello, which must also carry a valid identity if it
+    /// has one or `require_identity` is set. The identity, if any.
+    pub fn admit_hello(
+        &self,
+        peer: Option<IpAddr>,
+        identity: Option<&PeerIdentity>,
+        now: Instant,
+    ) -> Result<Option<Verified>, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        let admitted = self
+            .check(&mut state, peer, now)
+            .and_then(|()| self.check_identity(&mut state, peer, identity));
+        if let (Ok(_), Some(peer)) = (&admitted, peer) {
+            if state.greeted.len() < MAX_SOURCES {
+                state.greeted.insert(peer);
+            }
+        }
+        Self::count(&mut state, peer, admitted)
+    }
+
+    /// `admit` for a probe request, which must come from one of
+    /// `known_peers` or, without them, a peer whose hello got through.
+    pub fn admit_probe(&self, peer: Option<IpAddr>, now: Instant) -> Result<(), Rejection> {
+        let mut state = self.state.lock().unwrap();
+        let admitted = self.check(&mut state, peer, now).and_then(|()| {
+            let greeted = peer.is_some_and(|ip| state.greeted.contains(&ip));
+            match self.known_peers.is_empty() && !greeted {
+                true => Err(Rejection::NoHello),
+                false => Ok(()),
+            }
+        });
+        Self::count(&mut state, peer, admitted)
+    }
+
+    /// `admit` for a request that changes or dumps what the node does,
+    /// which must come from this host or one of `control_peers`.
+    pub fn admit_control(&self, peer: Option<IpAddr>, now: Instant) -> Result<(), Rejection> {
+        let mut state = self.state.lock().unwrap();
+        let allowed =
+            peer.is_some_and(|ip| ip.is_loopback() || self.control_peers.contains(&ip));
+        let admitted = match allowed {
+            true => self.limit(&mut state, peer, now),
+            false => Err(Rejection::NotControl),
+        };
+        Self::count(&mut state, peer, admitted)
+    }
+
+    /// Requests turned down since the start, by reason.
+    pub fn rejected(&self) -> HashMap<String, u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .rejected
+            .iter()
+            .map(|(reason, count)| (reason.to_string(), *count))
+            .collect()
+    }
+
+    fn check(
+        &self,
+        state: &mut State,
+        peer: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), Rejection> {
+        let known = peer.is_some_and(|ip| self.known_peers.contains(&ip));
+        if !self.known_peers.is_empty() && !known {
+            return Err(Rejection::UnknownPeer);
+        }
+        self.limit(state, peer, now)
+    }
+
+    /// Takes a token from the bucket of `peer`, if requests are limited.
+    fn limit(
+        &self,
+        state: &mut State,
+        peer: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), Rejection> {
+        let Some(peer) = peer.filter(|_| self.rate > 0.0) else {
+            return Ok(());
+        };
+        if state.buckets.len() >= MAX_SOURCES && !state.buckets.contains_key(&peer) {
+            // Full buckets are no different from new ones
+            let (rate, burst) = (self.rate, self.burst);
+            state.buckets.retain(|_, bucket| {
+                let idle = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + idle * rate < burst
+            });
+        }
+        let bucket = state.buckets.entry(peer).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Rejection::RateLimited);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn check_identity(
+        &self,
+        state: &mut State,
+        peer: Option<IpAddr>,
+        identity: Option<&PeerIdentity>,
+    ) -> Result<Option<Verified>, Rejection> {
+        let Some(identity) = identity else {
+            return match self.require_identity {
+                true => Err(Rejection::MissingIdentity),
+                false => Ok(None),
+            };
+        };
+        let now = Timestamp::now().as_millis();
+        let verified = identity::verify(identity, peer, now)
+            .map_err(|e| Rejection::InvalidIdentity(e.to_string()))?;
+        if !state.replays.check(&verified, now) {
+            return Err(Rejection::Replayed);
+        }
+        Ok(Some(verified))
+    }
+
+    fn count<T>(
+        state: &mut State,
+        peer: Option<IpAddr>,
+        admitted: Result<T, Rejection>,
+    ) -> Result<T, Rejection> {
+        if let Err(rejection) = &admitted {
+            debug!("Turned down request from {:?}: {:?}", peer, rejection);
+            *state.rejected.entry(rejection.reason()).or_default() += 1;
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::NodeIdentity;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_known_peers_and_rate() {
+        let known: IpAddr = [10, 0, 0, 2].into();
+        let guard = RequestGuard::new(&[known], false, 1.0, 2);
+        let now = Instant::now();
+
+        assert_eq!(guard.admit(Some([10, 0, 0, 9].into()), now), Err(Rejection::UnknownPeer));
+        assert_eq!(guard.admit(None, now), Err(Rejection::UnknownPeer));
+
+        assert_eq!(guard.admit(Some(known), now), Ok(()));
+        assert_eq!(guard.admit(Some(known), now), Ok(()));
+        assert_eq!(guard.admit(Some(known), now), Err(Rejection::RateLimited));
+        // A token back after a second
+        let later = now + Duration::from_secs(1);
+        assert_eq!(guard.admit(Some(known), later), Ok(()));
+        assert_eq!(guard.admit(Some(known), later), Err(Rejection::RateLimited));
+
+        let rejected = guard.rejected();
+        assert_eq!(rejected["unknown_peer"], 2);
+        assert_eq!(rejected["rate_limited"], 2);
+    }
+
+    #[test]
+    fn test_hello_identity() {
+        let guard = RequestGuard::new(&[], true, 0.0, 1);
+        let peer: Option<IpAddr> = Some([10, 0, 0, 2].into());
+        let now = Instant::now();
+        let node = NodeIdentity::new([5; 32]);
+
+        assert_eq!(guard.admit_hello(peer, None, now), Err(Rejection::MissingIdentity));
+
+        let hello = node.announce("10.0.0.2");
+        let verified = guard.admit_hello(peer, Some(&hello), now).unwrap().unwrap();
+        assert_eq!(verified.id, node.id());
+        assert_eq!(guard.admit_hello(peer, Some(&hello), now), Err(Rejection::Replayed));
+
+        let mut forged = node.announce("10.0.0.2");
+        forged.addr = "10.0.0.3".to_string();
+        assert!(matches!(
+            guard.admit_hello(peer, Some(&forged), now),
+            Err(Rejection::InvalidIdentity(_))
+        ));
+        // Signed for another address than it came from
+        let elsewhere = NodeIdentity::new([6; 32]).announce("10.0.0.3");
+        assert!(matches!(
+            guard.admit_hello(peer, Some(&elsewhere), now),
+            Err(Rejection::InvalidIdentity(_))
+        ));
+        assert_eq!(guard.rejected().values().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn test_probe_after_hello() {
+        let guard = RequestGuard::new(&[], false, 0.0, 1);
+        let peer: Option<IpAddr> = Some([10, 0, 0, 2].into());
+        let now = Instant::now();
+
+        assert_eq!(guard.admit_probe(peer, now), Err(Rejection::NoHello));
+        assert_eq!(guard.admit_hello(peer, None, now), Ok(None));
+        assert_eq!(guard.admit_probe(peer, now), Ok(()));
+        assert_eq!(guard.admit_probe(Some([10, 0, 0, 3].into()), now), Err(Rejection::NoHello));
+
+        // Known peers need no hello
+        let known = RequestGuard::new(&[[10, 0, 0, 2].into()], false, 0.0, 1);
+        assert_eq!(known.admit_probe(peer, now), Ok(()));
+    }
+
+    #[test]
+    fn test_control_from_this_host() {
+        let peer: IpAddr = [10, 0, 0, 2].into();
+        let guard = RequestGuard::new(&[peer], false, 0.0, 1);
+        let now = Instant::now();
+
+        // Known peers do not control the node
+        assert_eq!(guard.admit_control(Some(peer), now), Err(Rejection::NotControl));
+        assert_eq!(guard.admit_control(None, now), Err(Rejection::NotControl));
+        assert_eq!(guard.admit_control(Some([127, 0, 0, 1].into()), now), Ok(()));
+        assert_eq!(guard.admit_control(Some("::1".parse().unwrap()), now), Ok(()));
+
+        let guard = guard.with_control_peers(&[peer]);
+        assert_eq!(guard.admit_control(Some(peer), now), Ok(()));
+    }
+}
@@ -0,0 +1,56 @@
+//! A gRPC server for tests, on an ephemeral port of the loopback address.
+
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc::channel;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::listener::dump::Dumper;
+use crate::listener::filter::SubnetRules;
+use crate::listener::simulation::simulated_meta;
+use crate::prost_net::bandwidth_server::BwServer;
+use crate::prost_net::metrics_store::MetricsStore;
+use crate::proto_bw::DataMsg;
+use crate::tap::Tap;
+use crate::CapEventReceiver;
+
+pub struct TestServer {
+    pub port: u16,
+    pub cap_rx: CapEventReceiver,
+    pub bw_tx: Arc<Sender<DataMsg>>,
+    pub store: MetricsStore,
+    pub shutdown: CancellationToken,
+}
+
+/// A server for 10.0.0.1 on an ephemeral port of the loopback address.
+pub async fn start_server() -> TestServer {
+    let (cap_tx, cap_rx) = channel(16);
+    let (_, meta) = watch::channel(simulated_meta(Ipv4Addr::new(10, 0, 0, 1)));
+    let bw_tx = Arc::new(tokio::sync::broadcast::channel(16).0);
+    let (_, dump) = Dumper::new();
+    let store = MetricsStore::new();
+    let subnets = Arc::new(watch::channel(SubnetRules::default()).0);
+    let server = BwServer::new(
+        cap_tx,
+        meta,
+        bw_tx.clone(),
+        Tap::new(),
+        dump,
+        store.clone(),
+        subnets,
+    );
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let shutdown = CancellationToken::new();
+    server.serve(listener, shutdown.clone());
+    TestServer {
+        port,
+        cap_rx,
+        bw_tx,
+        store,
+        shutdown,
+    }
+}
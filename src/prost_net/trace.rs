@@ -0,0 +1,56 @@
+//! Following one report window from the parser to the collector's database.
+//!
+//! The parser numbers its report windows, and every DataMsg built in a
+//! window carries the number in `window_id`. With the node id and start
+//! time, also in the message, it names the window on every hop. Nodes log
+//! the hops under the `trace` target with `server.trace_windows`, the
+//! scheduler prints them with `--trace`.
+
+use std::fmt::{self, Display};
+
+use log::debug;
+
+use crate::proto_bw::DataMsg;
+
+/// Log target of the window traces.
+pub const TARGET: &str = "trace";
+
+/// Names the window of a message: node, node start and window.
+pub struct WindowTrace<'a>(pub &'a DataMsg);
+
+impl Display for WindowTrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = self.0;
+        let node = if msg.node_id.is_empty() { "-" } else { &msg.node_id };
+        write!(f, "window {}/{}/{} seq {}", node, msg.epoch, msg.window_id, msg.seq)
+    }
+}
+
+/// Logs that the message reached `hop`. Messages from no window are left
+/// out, e.g. hellos and snapshots.
+pub fn hop(msg: &DataMsg, hop: impl Display) {
+    if msg.window_id != 0 {
+        debug!(target: TARGET, "{} {}", WindowTrace(msg), hop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_trace() {
+        let msg = DataMsg {
+            seq: 12,
+            epoch: 1_700_000_000_000,
+            window_id: 3,
+            ..Default::default()
+        };
+        assert_eq!(WindowTrace(&msg).to_string(), "window -/1700000000000/3 seq 12");
+        let msg = DataMsg {
+            node_id: "10.0.0.1".to_string(),
+            ..msg
+        };
+        assert_eq!(WindowTrace(&msg).to_string(), "window 10.0.0.1/1700000000000/3 seq 12");
+    }
+}
@@ -0,0 +1,238 @@
+//! Data messages on their way to the collectors. They wait here while the
+//! stream to a collector is down and are sent once it is back, instead of
+//! being lost like on the broadcast. Sent messages are kept until the
+//! collector acks them, and sent again on the next stream if it does not.
+//! Bounded by `server.queue_size`, the oldest messages are dropped first.
+//!
+//! With several collectors in `server.collectors`, each gets its own queue
+//! and stream, or they share one queue that is streamed to the first one
+//! that accepts it, see `server.collector_mode`.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use log::warn;
+use tokio::sync::Notify;
+use tokio_stream::Stream;
+
+use crate::proto_bw::{DataMsg, UpstreamStatus};
+use crate::CONFIG;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectorMode {
+    /// Every collector gets every message.
+    All,
+    /// Messages go to the first collector in the list that accepts the
+    /// stream, the others are backups.
+    Failover,
+}
+
+impl FromStr for CollectorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(CollectorMode::All),
+            "failover" => Ok(CollectorMode::Failover),
+            _ => Err(anyhow!("Unknown collector mode: {}", s)),
+        }
+    }
+}
+
+/// Queues for the collectors in the config, see the module docs.
+pub fn upstreams_from_config() -> Vec<UpstreamQueue> {
+    let server = &CONFIG.server;
+    let mode = server.collector_mode.parse().unwrap_or_else(|e| {
+        warn!("{}, streaming to every collector", e);
+        CollectorMode::All
+    });
+    upstreams(server.collector_addrs(), mode, server.queue_size)
+}
+
+fn upstreams(addrs: Vec<String>, mode: CollectorMode, capacity: usize) -> Vec<UpstreamQueue> {
+    match mode {
+        CollectorMode::All => addrs
+            .into_iter()
+            .map(|addr| UpstreamQueue::new(vec![addr], capacity))
+            .collect(),
+        CollectorMode::Failover => vec![UpstreamQueue::new(addrs, capacity)],
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queued {
+    msgs: VecDeque<DataMsg>,
+    /// Taken by the stream, but not acked by the collector yet, oldest
+    /// first.
+    unacked: VecDeque<DataMsg>,
+    connected: bool,
+    /// Index in `addrs` of the collector streamed to last.
+    current: usize,
+    enqueued: u64,
+    dropped: u64,
+    delivered: u64,
+}
+
+/// Shared between the client handler, which fills it, and the task
+/// streaming it to one of `addrs`.
+#[derive(Debug, Clone)]
+pub struct UpstreamQueue {
+    /// Collectors in order of preference.
+    addrs: Vec<String>,
+    capacity: usize,
+    inner: Arc<Mutex<Queued>>,
+    notify: Arc<Notify>,
+}
+
+impl UpstreamQueue {
+    pub fn new(addrs: Vec<String>, capacity: usize) -> Self {
+        UpstreamQueue {
+            addrs,
+            capacity: capacity.max(1),
+            inner: Arc::new(Mutex::new(Queued::default())),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn addrs(&self) -> &[String] {
+        &self.addrs
+    }
+
+    /// Queues `msg`, dropping the oldest message if the queue is full.
+    pub fn push(&self, msg: DataMsg) {
+        {
+            let mut queued = self.inner.lock().unwrap();
+            if queued.msgs.len() + queued.unacked.len() >= self.capacity {
+                if queued.unacked.pop_front().is_none() {
+                    queued.msgs.pop_front();
+                }
+                queued.dropped += 1;
+            }
+            queued.msgs.push_back(msg);
+            queued.enqueued += 1;
+        }
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next message to send. It is kept until acked.
+    async fn next(&self) -> DataMsg {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut queued = self.inner.lock().unwrap();
+                if let Some(msg) = queued.msgs.pop_front() {
+                    queued.unacked.push_back(msg.clone());
+                    return msg;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Messages from the queue for one stream to the collector at
+    /// `addrs()[index]`.
+    pub fn stream(&self, index: usize) -> impl Stream<Item = DataMsg> + Send + 'static {
+        {
+            let mut queued = self.inner.lock().unwrap();
+            queued.connected = true;
+            queued.current = index;
+        }
+        futures::stream::unfold(self.clone(), |queue| async move {
+            let msg = queue.next().await;
+            Some((msg, queue))
+        })
+    }
+
+    /// The collector got the messages up to `seq`, they count as delivered.
+    pub fn acknowledge(&self, seq: u64) {
+        let mut queued = self.inner.lock().unwrap();
+        while queued.unacked.front().is_some_and(|msg| msg.seq <= seq) {
+            queued.unacked.pop_front();
+            queued.delivered += 1;
+        }
+    }
+
+    /// The stream to the collector ended. The messages it had taken that
+    /// were not acked go back to the front, to be sent again on the next
+    /// stream. The collector can tell a message it got twice by its
+    /// sequence number.
+    pub fn disconnected(&self) {
+        let mut queued = self.inner.lock().unwrap();
+        queued.connected = false;
+        while let Some(msg) = queued.unacked.pop_back() {
+            queued.msgs.push_front(msg);
+        }
+    }
+
+    pub fn status(&self) -> UpstreamStatus {
+        let queued = self.inner.lock().unwrap();
+        UpstreamStatus {
+            addr: self.addrs.get(queued.current).cloned().unwrap_or_default(),
+            connected: queued.connected,
+            queued: (queued.msgs.len() + queued.unacked.len()) as u64,
+            enqueued: queued.enqueued,
+            dropped: queued.dropped,
+            delivered: queued.delivered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn msg(seq: u64) -> DataMsg {
+        DataMsg {
+            seq,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_keeps_messages_across_reconnects() {
+        let queue = UpstreamQueue::new(vec!["127.0.0.1:50041".to_string()], 3);
+        for seq in 1..=4 {
+            queue.push(msg(seq));
+        }
+        let status = queue.status();
+        assert_eq!((status.queued, status.enqueued, status.dropped), (3, 4, 1));
+
+        let mut stream = Box::pin(queue.stream(0));
+        assert_eq!(stream.next().await.unwrap().seq, 2);
+        assert_eq!(stream.next().await.unwrap().seq, 3);
+        assert_eq!(stream.next().await.unwrap().seq, 4);
+        assert!(queue.status().connected);
+        // Taken is not delivered
+        assert_eq!(queue.status().delivered, 0);
+        queue.acknowledge(2);
+
+        // 3 and 4 were not acked when the stream failed
+        drop(stream);
+        queue.disconnected();
+        let status = queue.status();
+        assert_eq!((status.queued, status.delivered), (2, 1));
+        assert!(!status.connected);
+
+        let mut stream = Box::pin(queue.stream(0));
+        assert_eq!(stream.next().await.unwrap().seq, 3);
+        assert_eq!(stream.next().await.unwrap().seq, 4);
+        queue.acknowledge(4);
+        let status = queue.status();
+        assert_eq!((status.queued, status.delivered), (0, 3));
+    }
+
+    #[test]
+    fn test_collector_modes() {
+        let addrs = vec!["10.0.0.1:50041".to_string(), "10.0.0.2:50041".to_string()];
+        let all = upstreams(addrs.clone(), CollectorMode::All, 10);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[1].status().addr, "10.0.0.2:50041");
+        let failover = upstreams(addrs, CollectorMode::Failover, 10);
+        assert_eq!(failover.len(), 1);
+        assert_eq!(failover[0].addrs().len(), 2);
+        assert!("Failover".parse::<CollectorMode>().is_ok());
+    }
+}
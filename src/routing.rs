@@ -0,0 +1,347 @@
+//! Exports a cost per neighbour, derived from its link state, to the
+//! routing daemon of the mesh, so the estimates can steer routing.
+//!
+//! olsrd2 gets the costs through its telnet plugin, as connections of the
+//! `constant_metric` plugin. babeld gets them through its local
+//! configuration socket (`babeld -G`). Babel has no per neighbour cost, so
+//! the cost is added to the routes learned from the neighbour by an input
+//! filter.
+//!
+//! A changed cost replaces the one sent before. olsrd2 has the old entry
+//! removed. babeld uses the first filter that matches and cannot remove a
+//! single one, so its input filters are flushed and all of them sent again.
+//!
+//! Every remote with a link state gets a cost; the daemon ignores those
+//! that are not its neighbours.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::config::Routing;
+use crate::proto_bw::LinkState;
+use crate::tap::LinkStateConsumer;
+use crate::CONFIG;
+
+/// Longest a daemon has to take the commands and answer.
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingDaemon {
+    Olsrd2,
+    Babel,
+}
+
+impl FromStr for RoutingDaemon {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "olsrd2" => Ok(RoutingDaemon::Olsrd2),
+            "babel" | "babeld" => Ok(RoutingDaemon::Babel),
+            _ => Err(anyhow!("Unknown routing daemon: {}", s)),
+        }
+    }
+}
+
+impl RoutingDaemon {
+    /// Commands that set the cost of each neighbour in `changed`, which are
+    /// on `iface`, replacing those in `sent`, the costs the daemon has.
+    fn commands(
+        &self,
+        changed: &BTreeMap<IpAddr, u32>,
+        sent: &BTreeMap<IpAddr, u32>,
+        iface: &str,
+    ) -> Vec<String> {
+        let mut commands = Vec::new();
+        match self {
+            RoutingDaemon::Olsrd2 => {
+                let connection = |ip: &IpAddr, cost: &u32| {
+                    format!("constant_metric.connection={} {} {}", iface, ip, cost)
+                };
+                for (ip, cost) in changed {
+                    if let Some(old) = sent.get(ip) {
+                        commands.push(format!("config remove {}", connection(ip, old)));
+                    }
+                    commands.push(format!("config set {}", connection(ip, cost)));
+                }
+                commands.push("config commit".to_string());
+            }
+            RoutingDaemon::Babel => {
+                let mut costs = sent.clone();
+                costs.extend(changed);
+                commands.push("flush filter in".to_string());
+                commands.extend(
+                    costs
+                        .iter()
+                        .map(|(ip, cost)| format!("in neigh {} metric {}", ip, cost)),
+                );
+            }
+        }
+        commands.push("quit".to_string());
+        commands
+    }
+
+    /// Finds the errors in a reply to `commands`.
+    fn check_reply(&self, reply: &str) -> Result<()> {
+        let failed = match self {
+            RoutingDaemon::Olsrd2 => reply.lines().any(|l| l.to_lowercase().contains("error")),
+            RoutingDaemon::Babel => reply.lines().any(|l| l == "bad" || l == "no"),
+        };
+        if failed {
+            return Err(anyhow!("Daemon refused the costs: {}", reply.trim()));
+        }
+        Ok(())
+    }
+}
+
+/// What the cost of a link is derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CostMetric {
+    /// Available bandwidth toward the neighbour, the cost falls as it rises.
+    Abw,
+    /// Latency toward the neighbour, the cost rises with it.
+    Latency,
+}
+
+impl FromStr for CostMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "abw" => Ok(CostMetric::Abw),
+            "latency" => Ok(CostMetric::Latency),
+            _ => Err(anyhow!("Unknown cost metric: {}", s)),
+        }
+    }
+}
+
+/// Maps a link state to a cost, see `config::Routing`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostMapping {
+    pub metric: CostMetric,
+    /// Value of `metric` that costs `base_cost`.
+    pub reference: f64,
+    pub base_cost: u32,
+    pub min_cost: u32,
+    pub max_cost: u32,
+}
+
+impl CostMapping {
+    /// None if the metric is not known for the link.
+    pub fn cost(&self, ls: &LinkState) -> Option<u32> {
+        let ratio = match self.metric {
+            CostMetric::Abw if ls.abw > 0.0 => self.reference / ls.abw,
+            CostMetric::Latency if ls.latency > 0.0 => ls.latency / self.reference,
+            _ => return None,
+        };
+        let cost = (self.base_cost as f64 * ratio).round() as u32;
+        Some(cost.clamp(self.min_cost, self.max_cost))
+    }
+}
+
+/// Latest cost of each neighbour, filled from the link states.
+#[derive(Debug, Clone)]
+struct RouteCosts {
+    mapping: CostMapping,
+    costs: Arc<Mutex<BTreeMap<IpAddr, u32>>>,
+}
+
+impl LinkStateConsumer for RouteCosts {
+    fn on_link_state(&self, ls: &LinkState) {
+        let (Some(cost), Ok(neighbour)) = (self.mapping.cost(ls), ls.receiver_ip.parse()) else {
+            return;
+        };
+        self.costs.lock().unwrap().insert(neighbour, cost);
+    }
+}
+
+/// Writes the costs that changed to the daemon every `routing.interval`.
+#[derive(Debug)]
+pub struct RoutingExport {
+    daemon: RoutingDaemon,
+    addr: String,
+    iface: String,
+    interval: Duration,
+    dry_run: bool,
+    costs: RouteCosts,
+    /// Costs the daemon has.
+    sent: BTreeMap<IpAddr, u32>,
+}
+
+impl RoutingExport {
+    /// None unless `routing.daemon` is set.
+    pub fn from_config(config: &Routing) -> Result<Option<Self>> {
+        let Some(daemon) = &config.daemon else {
+            return Ok(None);
+        };
+        let daemon: RoutingDaemon = daemon.parse()?;
+        let iface = match (&config.interface, &CONFIG.client.iface) {
+            (Some(iface), _) | (None, Some(iface)) => iface.clone(),
+            (None, None) if daemon == RoutingDaemon::Olsrd2 => {
+                return Err(anyhow!("routing.interface must be set for olsrd2"));
+            }
+            (None, None) => String::new(),
+        };
+        let mapping = CostMapping {
+            metric: config.metric.parse()?,
+            reference: config.reference,
+            base_cost: config.base_cost,
+            min_cost: config.min_cost,
+            max_cost: config.max_cost,
+        };
+        Ok(Some(RoutingExport {
+            daemon,
+            addr: config.addr.clone(),
+            iface,
+            interval: config.interval,
+            dry_run: config.dry_run,
+            costs: RouteCosts {
+                mapping,
+                costs: Arc::new(Mutex::new(BTreeMap::new())),
+            },
+            sent: BTreeMap::new(),
+        }))
+    }
+
+    /// Register with the parser to get the link states.
+    pub fn consumer(&self) -> Arc<dyn LinkStateConsumer> {
+        Arc::new(self.costs.clone())
+    }
+
+    /// Costs that are new or changed since the last export.
+    fn changed(&self) -> BTreeMap<IpAddr, u32> {
+        let costs = self.costs.costs.lock().unwrap();
+        costs
+            .iter()
+            .filter(|(ip, cost)| self.sent.get(ip) != Some(cost))
+            .map(|(ip, cost)| (*ip, *cost))
+            .collect()
+    }
+
+    async fn send(&self, commands: &[String]) -> Result<()> {
+        let exchange = async {
+            let mut stream = TcpStream::connect(&self.addr).await?;
+            stream.write_all(format!("{}\n", commands.join("\n")).as_bytes()).await?;
+            let mut reply = String::new();
+            // Both close the connection on quit
+            stream.read_to_string(&mut reply).await?;
+            anyhow::Ok(reply)
+        };
+        let reply = tokio::time::timeout(DAEMON_TIMEOUT, exchange)
+            .await
+            .map_err(|_| anyhow!("No reply within {:?}", DAEMON_TIMEOUT))?
+            .with_context(|| format!("Failed to talk to {:?} at {}", self.daemon, self.addr))?;
+        debug!("Reply from {:?}: {}", self.daemon, reply.trim());
+        self.daemon.check_reply(&reply)
+    }
+
+    /// Consumes self, returns a handle to the task.
+    pub fn dispatch(mut self) -> JoinHandle<Result<()>> {
+        tokio::spawn(async move {
+            info!(
+                "Exporting link costs to {:?} at {}{}",
+                self.daemon,
+                self.addr,
+                if self.dry_run { " (dry run)" } else { "" }
+            );
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                let changed = self.changed();
+                if changed.is_empty() {
+                    continue;
+                }
+                let commands = self.daemon.commands(&changed, &self.sent, &self.iface);
+                if self.dry_run {
+                    info!("Would send to {:?}: {:?}", self.daemon, commands);
+                } else if let Err(e) = self.send(&commands).await {
+                    // Tried again on the next tick
+                    warn!("Failed to export link costs: {:#}", e);
+                    continue;
+                }
+                self.sent.extend(changed);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_costs_and_commands() {
+        let mapping = CostMapping {
+            metric: CostMetric::Abw,
+            reference: 1e6,
+            base_cost: 256,
+            min_cost: 96,
+            max_cost: 65535,
+        };
+        let link = |receiver: &str, abw: f64| LinkState {
+            receiver_ip: receiver.to_string(),
+            abw,
+            ..Default::default()
+        };
+        assert_eq!(mapping.cost(&link("10.0.0.2", 5e5)), Some(512));
+        assert_eq!(mapping.cost(&link("10.0.0.2", 1e8)), Some(96));
+        assert_eq!(mapping.cost(&link("10.0.0.2", 0.0)), None);
+
+        let costs = RouteCosts {
+            mapping,
+            costs: Default::default(),
+        };
+        costs.on_link_state(&link("10.0.0.3", 1e6));
+        costs.on_link_state(&link("10.0.0.2", 5e5));
+        costs.on_link_state(&link("not an ip", 5e5));
+        let costs = costs.costs.lock().unwrap().clone();
+        assert_eq!(costs.len(), 2);
+
+        let none = BTreeMap::new();
+        assert_eq!(
+            RoutingDaemon::Babel.commands(&costs, &none, ""),
+            [
+                "flush filter in",
+                "in neigh 10.0.0.2 metric 512",
+                "in neigh 10.0.0.3 metric 256",
+                "quit"
+            ]
+        );
+        let olsr = RoutingDaemon::Olsrd2.commands(&costs, &none, "wlan0");
+        assert_eq!(olsr[0], "config set constant_metric.connection=wlan0 10.0.0.2 512");
+        assert_eq!(olsr[2..], ["config commit", "quit"]);
+
+        // A changed cost replaces the old one instead of adding another
+        let changed = BTreeMap::from([("10.0.0.2".parse().unwrap(), 300)]);
+        assert_eq!(
+            RoutingDaemon::Olsrd2.commands(&changed, &costs, "wlan0"),
+            [
+                "config remove constant_metric.connection=wlan0 10.0.0.2 512",
+                "config set constant_metric.connection=wlan0 10.0.0.2 300",
+                "config commit",
+                "quit"
+            ]
+        );
+        assert_eq!(
+            RoutingDaemon::Babel.commands(&changed, &costs, ""),
+            [
+                "flush filter in",
+                "in neigh 10.0.0.2 metric 300",
+                "in neigh 10.0.0.3 metric 256",
+                "quit"
+            ]
+        );
+
+        assert!(RoutingDaemon::Babel.check_reply("BABEL 1.0\nok\n").is_ok());
+        assert!(RoutingDaemon::Babel.check_reply("ok\nbad\n").is_err());
+    }
+}
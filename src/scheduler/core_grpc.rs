@@ -1,6 +1,5 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::SystemTime;
 
 use crate::core_proto::Session as CoreSession;
 use crate::core_proto::core_api_client::CoreApiClient;
@@ -8,6 +7,7 @@ use crate::core_proto::{
     GetSessionRequest, LinkOptions, ThroughputsEvent,
     ThroughputsRequest,
 };
+use crate::Timestamp;
 
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
@@ -192,7 +192,8 @@ pub struct ThroughputDP {
     pub iface2: String,
     pub ip42: String,
     pub throughput: f64,
-    pub timestamp: u128,
+    /// Milliseconds since the epoch.
+    pub timestamp: i64,
 }
 
 #[derive(Debug)]
@@ -262,10 +263,7 @@ async fn thput_event_loop(
                     iface2: iface2.name.clone(),
                     ip42: iface2.ip4.clone(),
                     throughput: iface_thpt.throughput,
-                    timestamp: SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis(),
+                    timestamp: Timestamp::now().as_millis(),
                 };
                 thput_dps.push(dp);
             }
@@ -0,0 +1,222 @@
+//! Recent link time series from the database over HTTP, so dashboards do not
+//! need database credentials of their own:
+//!
+//! - `GET /experiments`: names and descriptions of the experiments
+//! - `GET /series`: link states of the last minutes, one series per link
+//!   and experiment. Takes `minutes` (default `DEFAULT_MINUTES`, at most
+//!   `MAX_MINUTES`), and `experiment`, `sender_ip` and `receiver_ip` to
+//!   only get those.
+//!
+//! Throughputs and ABW are bytes/sec, latency is seconds and times are
+//! milliseconds since epoch.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tokio_postgres::{Client, Row};
+
+pub const DEFAULT_MINUTES: u32 = 15;
+pub const MAX_MINUTES: u32 = 24 * 60;
+/// Rows a single request returns at most, the rest are cut.
+pub const MAX_POINTS: i64 = 100_000;
+
+const SERIES_QUERY: &str = r#"
+SELECT e.name, l.sender_ip, l.receiver_ip, ls.time,
+    ls.thp_in, ls.thp_out, ls.abw, ls.abw_down, ls.latency
+FROM link_state ls
+    JOIN link l ON ls.link_id = l.id
+    JOIN experiment e ON ls.experiment_id = e.id
+WHERE ls.time > now() - make_interval(mins => $1)
+    AND ($2::TEXT IS NULL OR e.name = $2)
+    AND ($3::TEXT IS NULL OR l.sender_ip = $3)
+    AND ($4::TEXT IS NULL OR l.receiver_ip = $4)
+ORDER BY e.name, l.sender_ip, l.receiver_ip, ls.time
+LIMIT $5
+"#;
+
+/// Experiment, sender and receiver of a series.
+pub type SeriesKey = (String, String, String);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SeriesQuery {
+    pub minutes: Option<u32>,
+    pub experiment: Option<String>,
+    pub sender_ip: Option<String>,
+    pub receiver_ip: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Experiment {
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Point {
+    pub time: i64,
+    pub thp_in: Option<f64>,
+    pub thp_out: Option<f64>,
+    pub abw: Option<f64>,
+    pub abw_down: Option<f64>,
+    pub latency: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkSeries {
+    pub experiment: String,
+    pub sender_ip: String,
+    pub receiver_ip: String,
+    /// Oldest first.
+    pub points: Vec<Point>,
+}
+
+/// Rows sorted by experiment and link as series, one per link.
+pub fn group_series(rows: impl IntoIterator<Item = (SeriesKey, Point)>) -> Vec<LinkSeries> {
+    let mut series: Vec<LinkSeries> = Vec::new();
+    for ((experiment, sender_ip, receiver_ip), point) in rows {
+        match series.last_mut() {
+            Some(last)
+                if last.experiment == experiment
+                    && last.sender_ip == sender_ip
+                    && last.receiver_ip == receiver_ip =>
+            {
+                last.points.push(point)
+            }
+            _ => series.push(LinkSeries {
+                experiment,
+                sender_ip,
+                receiver_ip,
+                points: vec![point],
+            }),
+        }
+    }
+    series
+}
+
+fn series_row(row: &Row) -> (SeriesKey, Point) {
+    let time: DateTime<Utc> = row.get(3);
+    let link = (row.get(0), row.get(1), row.get(2));
+    let point = Point {
+        time: time.timestamp_millis(),
+        thp_in: row.get(4),
+        thp_out: row.get(5),
+        abw: row.get(6),
+        abw_down: row.get(7),
+        latency: row.get(8),
+    };
+    (link, point)
+}
+
+fn router(client: Arc<Client>) -> Router {
+    Router::new()
+        .route("/experiments", get(experiments))
+        .route("/series", get(series))
+        .with_state(client)
+}
+
+async fn experiments(
+    State(client): State<Arc<Client>>,
+) -> Result<Json<Vec<Experiment>>, StatusCode> {
+    let rows = client
+        .query("SELECT name, description FROM experiment ORDER BY id DESC", &[])
+        .await
+        .map_err(|e| {
+            eprintln!("Dashboard query failed: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    Ok(Json(
+        rows.iter()
+            .map(|row| Experiment {
+                name: row.get(0),
+                description: row.get(1),
+            })
+            .collect(),
+    ))
+}
+
+async fn series(
+    State(client): State<Arc<Client>>,
+    Query(query): Query<SeriesQuery>,
+) -> Result<Json<Vec<LinkSeries>>, StatusCode> {
+    let minutes = query.minutes.unwrap_or(DEFAULT_MINUTES);
+    if minutes == 0 || minutes > MAX_MINUTES {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let minutes = minutes as i32;
+    let rows = client
+        .query(
+            SERIES_QUERY,
+            &[
+                &minutes,
+                &query.experiment,
+                &query.sender_ip,
+                &query.receiver_ip,
+                &MAX_POINTS,
+            ],
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("Dashboard query failed: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    Ok(Json(group_series(rows.iter().map(series_row))))
+}
+
+/// Binds `addr` and serves the dashboard data from `client` in the
+/// background. Fails right away if the address cannot be bound.
+pub async fn dispatch_dashboard(
+    addr: SocketAddr,
+    client: Client,
+) -> Result<JoinHandle<Result<()>>> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Dashboard data served on {}", addr);
+    Ok(tokio::spawn(async move {
+        axum::serve(listener, router(Arc::new(client))).await?;
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(time: i64) -> Point {
+        Point {
+            time,
+            thp_in: Some(1e6),
+            thp_out: None,
+            abw: Some(5e6),
+            abw_down: None,
+            latency: Some(0.01),
+        }
+    }
+
+    #[test]
+    fn test_group_series() {
+        let link = |experiment: &str, sender: &str, receiver: &str| {
+            (experiment.to_string(), sender.to_string(), receiver.to_string())
+        };
+        let rows = vec![
+            (link("a", "10.0.0.1", "10.0.0.2"), point(1)),
+            (link("a", "10.0.0.1", "10.0.0.2"), point(2)),
+            (link("a", "10.0.0.1", "10.0.0.3"), point(1)),
+            (link("b", "10.0.0.1", "10.0.0.3"), point(3)),
+        ];
+        let series = group_series(rows);
+        assert_eq!(series.len(), 3);
+        assert_eq!(series[0].points, vec![point(1), point(2)]);
+        assert_eq!((series[1].receiver_ip.as_str(), series[1].points.len()), ("10.0.0.3", 1));
+        assert_eq!(series[2].experiment, "b");
+        assert!(group_series(Vec::new()).is_empty());
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
+
 use crate::proto_bw::{BandwidthMessage, PgmMessage, Rtts};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Utc};
 use log::error;
 use tokio_postgres::{types::Timestamp, Client};
 
@@ -8,10 +10,10 @@ use super::core_grpc::ThroughputDP;
 // alias PostgreSQL TIMESTAMPTZ wrapper for clarity.
 type TstampTZ = Timestamp<DateTime<Utc>>;
 
-fn timestamp_to_datetime(timestamp: i64) -> Option<TstampTZ> {
-    // Use Utc.timestamp_millis for milliseconds
-    let dtime = Utc.timestamp_millis_opt(timestamp).single()?;
-    Some(TstampTZ::Value(dtime))
+/// Milliseconds since the epoch from a message, None if out of range.
+fn timestamp_to_datetime(millis: i64) -> Option<TstampTZ> {
+    let time = crate::Timestamp::from_unix_millis(millis)?;
+    Some(TstampTZ::Value(time.to_datetime()))
 }
 
 pub async fn get_and_insert_experiment(
@@ -105,7 +107,15 @@ pub async fn insert_into(
 
 pub async fn upload_probe_gap_measurements(msg: PgmMessage, client: &Client, experiment_id: i32) {
     // For RTT data, our table (named "rtt") has columns: rtt and ts.
-    let cols = ["time", "gin", "gout", "len", "num_acked", "experiment_id"];
+    let cols = [
+        "time",
+        "gin",
+        "gout",
+        "len",
+        "num_acked",
+        "app_limited",
+        "experiment_id",
+    ];
 
     for pgmmsg in &msg.pgm_dps {
         // Convert timestamp to a DateTime<Utc>
@@ -124,6 +134,7 @@ pub async fn upload_probe_gap_measurements(msg: PgmMessage, client: &Client, exp
                 &pgm_dp.gout,
                 &pgm_dp.len,
                 &pgm_dp.num_acked,
+                &pgm_dp.app_limited,
                 &experiment_id,
             ];
             insert_into(
@@ -154,7 +165,7 @@ pub async fn upload_throughput(msg: Vec<ThroughputDP>, client: &Client, experime
 
     for thput in msg {
         // Convert timestamp (milliseconds) to a DateTime<Utc>
-        let ts = match timestamp_to_datetime(thput.timestamp as i64) {
+        let ts = match timestamp_to_datetime(thput.timestamp) {
             Some(ts) => ts,
             None => {
                 eprintln!("Error converting timestamp to DateTime<Utc> for throughput");
@@ -188,8 +199,14 @@ pub async fn upload_throughput(msg: Vec<ThroughputDP>, client: &Client, experime
     }
 }
 
-/// Uploads bandwidth data (for each LinkState) into the database.
-pub async fn upload_bandwidth(msg: BandwidthMessage, client: &Client, experiment_id: i32) {
+/// Uploads bandwidth data (for each LinkState) into the database, as
+/// reported by `node_id`.
+pub async fn upload_bandwidth(
+    msg: BandwidthMessage,
+    node_id: &str,
+    client: &Client,
+    experiment_id: i32,
+) {
     let cols = [
         "thp_in",
         "thp_out",
@@ -199,6 +216,54 @@ pub async fn upload_bandwidth(msg: BandwidthMessage, client: &Client, experiment
         "delay",
         "jitter",
         "loss",
+        "probe_thp_in",
+        "probe_thp_out",
+        "retry_rate",
+        "link_alive",
+        "other_bytes",
+        "other_protocols",
+        "abw_down",
+        "latency_down",
+        "loss_up",
+        "loss_down",
+        "burst_thp_in",
+        "abw_std_err",
+        "abw_samples",
+        "abw_down_std_err",
+        "abw_down_samples",
+        "estimator",
+        "train_abw_in",
+        "clock_offset",
+        "thp_in_p5",
+        "thp_in_p50",
+        "thp_in_p95",
+        "thp_out_p5",
+        "thp_out_p50",
+        "thp_out_p95",
+        "idle",
+        "rtt_baseline",
+        "rtt_loaded",
+        "bufferbloat",
+        "fct_p50",
+        "fct_p95",
+        "fct_max",
+        "fct_flows",
+        "rwnd_avg",
+        "rwnd_min",
+        "rwnd_max",
+        "rwnd_stalls",
+        "rwnd_stalled",
+        "next_hop",
+        "egress_iface",
+        "degraded",
+        "capacity",
+        "ping_rtt",
+        "ping_loss",
+        "hops",
+        "path_changes",
+        "fragmented_bytes",
+        "node_id",
+        "peer_id",
         "time",
         "experiment_id",
     ];
@@ -212,6 +277,44 @@ pub async fn upload_bandwidth(msg: BandwidthMessage, client: &Client, experiment
                 continue;
             }
         };
+        // Postgres has no unsigned types
+        let other_bytes = ls.other_bytes as i64;
+        let other_protocols: Vec<i32> = ls.other_protocols.iter().map(|&p| p as i32).collect();
+        let abw_samples = ls.abw_samples as i32;
+        let abw_down_samples = ls.abw_down_samples as i32;
+        let thp_in_dist = ls.thp_in_dist.as_ref();
+        let thp_out_dist = ls.thp_out_dist.as_ref();
+        let thp_in_p = [
+            thp_in_dist.map(|d| d.p5),
+            thp_in_dist.map(|d| d.p50),
+            thp_in_dist.map(|d| d.p95),
+        ];
+        let thp_out_p = [
+            thp_out_dist.map(|d| d.p5),
+            thp_out_dist.map(|d| d.p50),
+            thp_out_dist.map(|d| d.p95),
+        ];
+        let fct = ls.fct.as_ref();
+        let fct_p = [fct.map(|f| f.p50), fct.map(|f| f.p95), fct.map(|f| f.max)];
+        let fct_flows = fct.map(|f| f.flows as i32);
+        let rwnd = ls.rwnd.as_ref();
+        // Windows without samples have no size
+        let rwnd_size = rwnd.filter(|w| w.samples > 0);
+        let rwnd_avg = rwnd_size.map(|w| w.avg);
+        let rwnd_min = rwnd_size.map(|w| w.min as i64);
+        let rwnd_max = rwnd_size.map(|w| w.max as i64);
+        let rwnd_stalls = rwnd.map(|w| w.stalls as i32);
+        let rwnd_stalled = rwnd.map(|w| w.stalled);
+        let next_hop = (!ls.next_hop.is_empty()).then_some(ls.next_hop.as_str());
+        let egress_iface = (!ls.egress_iface.is_empty()).then_some(ls.egress_iface.as_str());
+        let capacity = (ls.capacity > 0.0).then_some(ls.capacity);
+        let ping_rtt = (ls.ping_rtt > 0.0).then_some(ls.ping_rtt);
+        // With pings sent, either some were answered or the loss is 100%
+        let ping_loss = (ls.ping_rtt > 0.0 || ls.ping_loss > 0.0).then_some(ls.ping_loss);
+        let hops = (ls.hops >= 0).then_some(ls.hops);
+        let path_changes = ls.path_changes as i32;
+        let fragmented_bytes = ls.fragmented_bytes as i64;
+        let peer_id = (!ls.peer_id.is_empty()).then_some(ls.peer_id.as_str());
 
         let values: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![
             &ls.thp_in,
@@ -222,6 +325,54 @@ pub async fn upload_bandwidth(msg: BandwidthMessage, client: &Client, experiment
             &ls.delay,
             &ls.jitter,
             &ls.loss,
+            &ls.probe_thp_in,
+            &ls.probe_thp_out,
+            &ls.retry_rate,
+            &ls.link_alive,
+            &other_bytes,
+            &other_protocols,
+            &ls.abw_down,
+            &ls.latency_down,
+            &ls.loss_up,
+            &ls.loss_down,
+            &ls.burst_thp_in,
+            &ls.abw_std_err,
+            &abw_samples,
+            &ls.abw_down_std_err,
+            &abw_down_samples,
+            &ls.estimator,
+            &ls.train_abw_in,
+            &ls.clock_offset,
+            &thp_in_p[0],
+            &thp_in_p[1],
+            &thp_in_p[2],
+            &thp_out_p[0],
+            &thp_out_p[1],
+            &thp_out_p[2],
+            &ls.idle,
+            &ls.rtt_baseline,
+            &ls.rtt_loaded,
+            &ls.bufferbloat,
+            &fct_p[0],
+            &fct_p[1],
+            &fct_p[2],
+            &fct_flows,
+            &rwnd_avg,
+            &rwnd_min,
+            &rwnd_max,
+            &rwnd_stalls,
+            &rwnd_stalled,
+            &next_hop,
+            &egress_iface,
+            &ls.degraded,
+            &capacity,
+            &ping_rtt,
+            &ping_loss,
+            &hops,
+            &path_changes,
+            &fragmented_bytes,
+            &node_id,
+            &peer_id,
             &ts,
             &experiment_id,
         ];
@@ -235,13 +386,73 @@ pub async fn upload_bandwidth(msg: BandwidthMessage, client: &Client, experiment
             &values,
         )
         .await;
+
+        let estimate_cols = ["estimator", "abw", "std_err", "samples", "time", "experiment_id"];
+        for estimate in &ls.estimates {
+            let samples = estimate.samples as i32;
+            let values: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![
+                &estimate.estimator,
+                &estimate.abw,
+                &estimate.std_err,
+                &samples,
+                &ts,
+                &experiment_id,
+            ];
+            insert_into(
+                client,
+                &ls.sender_ip,
+                &ls.receiver_ip,
+                "abw_estimate",
+                &estimate_cols,
+                &values,
+            )
+            .await;
+        }
+
+        let flow_cols = [
+            "remote_ip",
+            "local_port",
+            "remote_port",
+            "outgoing",
+            "duration",
+            "bytes",
+            "process",
+            "time",
+            "experiment_id",
+        ];
+        for flow in &ls.flows {
+            let (local_port, remote_port) = (flow.local_port as i32, flow.remote_port as i32);
+            let bytes = flow.bytes as i64;
+            let process = (!flow.process.is_empty()).then_some(flow.process.as_str());
+            let values: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![
+                &flow.remote_ip,
+                &local_port,
+                &remote_port,
+                &flow.outgoing,
+                &flow.duration,
+                &bytes,
+                &process,
+                &ts,
+                &experiment_id,
+            ];
+            insert_into(
+                client,
+                &ls.sender_ip,
+                &ls.receiver_ip,
+                "flow",
+                &flow_cols,
+                &values,
+            )
+            .await;
+        }
     }
 }
 
-/// Uploads RTT data (for each Rtt) into the database.
+/// Uploads RTT data (for each Rtt and RttBucket) into the database.
 pub async fn upload_rtt(msg: Rtts, client: &Client, experiment_id: i32) {
     // For RTT data, our table (named "rtt") has columns: rtt and ts.
     let cols = ["rtt", "time", "experiment_id"];
+    let bucket_cols = ["rtt_min", "rtt_mean", "rtt_max", "samples", "time", "experiment_id"];
 
     for rttmsg in &msg.rtts {
         for rtt in &rttmsg.rtt {
@@ -266,5 +477,76 @@ pub async fn upload_rtt(msg: Rtts, client: &Client, experiment_id: i32) {
             )
             .await;
         }
+
+        for bucket in &rttmsg.buckets {
+            let ts = match timestamp_to_datetime(bucket.timestamp) {
+                Some(ts) => ts,
+                None => {
+                    error!("Error converting timestamp to DateTime<Utc> for RTT bucket");
+                    continue;
+                }
+            };
+            let samples = bucket.count as i32;
+
+            let values: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = vec![
+                &bucket.min,
+                &bucket.mean,
+                &bucket.max,
+                &samples,
+                &ts,
+                &experiment_id,
+            ];
+
+            insert_into(
+                client,
+                &rttmsg.sender_ip,
+                &rttmsg.receiver_ip,
+                "rtt_bucket",
+                &bucket_cols,
+                &values,
+            )
+            .await;
+        }
+    }
+}
+
+/// Records the metadata tags of a node, one row per tag.
+pub async fn upload_metadata(
+    node_id: &str,
+    epoch: i64,
+    metadata: &HashMap<String, String>,
+    client: &Client,
+    experiment_id: i32,
+) {
+    let ts = TstampTZ::Value(Utc::now());
+    let query = "INSERT INTO node_metadata (time, experiment_id, node_id, epoch, key, value) \
+                 VALUES ($1, $2, $3, $4, $5, $6)";
+    for (key, value) in metadata {
+        if let Err(e) = client
+            .execute(query, &[&ts, &experiment_id, &node_id, &epoch, key, value])
+            .await
+        {
+            eprintln!("Error inserting record: {}", e);
+        }
+    }
+}
+
+/// Records the sequence number of an accepted data message.
+pub async fn upload_sequence(
+    node_id: &str,
+    epoch: i64,
+    seq: u64,
+    client: &Client,
+    experiment_id: i32,
+) {
+    let ts = TstampTZ::Value(Utc::now());
+    let seq = seq as i64;
+    let query = "INSERT INTO data_sequence (time, experiment_id, node_id, epoch, seq) \
+                 VALUES ($1, $2, $3, $4, $5)";
+    if let Err(e) = client
+        .execute(query, &[&ts, &experiment_id, &node_id, &epoch, &seq])
+        .await
+    {
+        eprintln!("Error inserting record: {}", e);
     }
 }
@@ -1,6 +1,10 @@
 DROP TABLE link CASCADE;
 DROP TABLE link_state CASCADE;
 DROP TABLE rtt CASCADE;
+DROP TABLE rtt_bucket CASCADE;
 DROP TABLE pgm CASCADE;
+DROP TABLE flow CASCADE;
+DROP TABLE data_sequence CASCADE;
+DROP TABLE node_metadata CASCADE;
 DROP TABLE experiment CASCADE;
 DROP TABLE throughput CASCADE;
@@ -0,0 +1,85 @@
+/// Compares the passive ABW estimators on recorded data, see
+/// `network_listener::scheduler::evaluation` for the input format.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use network_listener::config::Filter;
+use network_listener::scheduler::evaluation::{evaluate, read_pgm_csv, read_truth_csv, EvalParams};
+use network_listener::AppConfig;
+
+#[derive(Parser, Debug)]
+#[command(name = "evaluate")]
+struct Args {
+    /// CSV with the PGM data points
+    #[arg(long)]
+    pgm: PathBuf,
+
+    /// CSV with the ground truth throughput
+    #[arg(long)]
+    truth: PathBuf,
+
+    /// Multiplier converting the ground truth to bytes per second
+    #[arg(long, default_value_t = 1.0)]
+    truth_scale: f64,
+
+    /// Listener config to take the `[filter]` section and `link_phy_cap` from
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Window length in seconds
+    #[arg(long, default_value_t = 10)]
+    window: u64,
+
+    /// Step between windows in seconds, defaults to the window length
+    #[arg(long)]
+    step: Option<u64>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let (filter, phy_cap) = match &args.config {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let config: AppConfig = toml::from_str(&contents)?;
+            (config.filter, config.client.link_phy_cap as f64 / 8.0)
+        }
+        None => {
+            let config = AppConfig::default();
+            (Filter::default(), config.client.link_phy_cap as f64 / 8.0)
+        }
+    };
+
+    let dps = read_pgm_csv(&args.pgm)?;
+    let truth = read_truth_csv(&args.truth, args.truth_scale)?;
+    println!("{} data points, {} ground truth samples", dps.len(), truth.len());
+
+    let params = EvalParams {
+        window: Duration::from_secs(args.window),
+        step: Duration::from_secs(args.step.unwrap_or(args.window)),
+        filter,
+        phy_cap,
+    };
+
+    println!(
+        "{:<8} {:>8} {:>9} {:>12} {:>12} {:>12} {:>8}",
+        "name", "windows", "estimates", "mae", "rmse", "bias", "mape %"
+    );
+    for stats in evaluate(&dps, &truth, &params) {
+        println!(
+            "{:<8} {:>8} {:>9} {:>12.0} {:>12.0} {:>12.0} {:>8.1}",
+            stats.estimator.name(),
+            stats.windows,
+            stats.estimates,
+            stats.mae,
+            stats.rmse,
+            stats.bias,
+            stats.mape
+        );
+    }
+    Ok(())
+}
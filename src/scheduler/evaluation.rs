@@ -0,0 +1,283 @@
+//! Offline comparison of the passive ABW estimators against ground truth.
+//!
+//! Both inputs are CSV files with a header row, exported from the scheduler
+//! database, e.g.:
+//!
+//! ```sql
+//! \copy (SELECT (extract(epoch FROM time) * 1000)::bigint AS time_ms, gin, gout, len, num_acked,
+//!        app_limited FROM pgm_detailed WHERE sender_ip = '10.0.0.1' AND receiver_ip = '10.0.0.2')
+//!        TO 'pgm.csv' CSV HEADER
+//! \copy (SELECT (extract(epoch FROM time) * 1000)::bigint AS time_ms, throughput
+//!        FROM throughput WHERE ip41 = '10.0.0.1' AND ip42 = '10.0.0.2')
+//!        TO 'truth.csv' CSV HEADER
+//! ```
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config::Filter;
+use crate::{GinGout, PABWESender, RegressionType, Timestamp};
+
+/// Ground truth sample, bytes/sec at `time_ms` since the epoch.
+#[derive(Debug, Clone, Copy)]
+pub struct Truth {
+    pub time_ms: i64,
+    pub value: f64,
+}
+
+/// How to slice the recording and configure the estimators.
+#[derive(Debug, Clone)]
+pub struct EvalParams {
+    /// Length of each window of data points.
+    pub window: Duration,
+    /// How far the window moves each step.
+    pub step: Duration,
+    pub filter: Filter,
+    /// Link capacity in bytes/sec.
+    pub phy_cap: f64,
+}
+
+/// Error of one estimator over all windows with ground truth.
+#[derive(Debug, Clone)]
+pub struct ErrorStats {
+    pub estimator: RegressionType,
+    /// Windows with both data points and ground truth.
+    pub windows: usize,
+    /// Windows where the estimator produced an estimate.
+    pub estimates: usize,
+    /// Mean absolute error, bytes/sec.
+    pub mae: f64,
+    /// Root mean square error, bytes/sec.
+    pub rmse: f64,
+    /// Mean signed error, bytes/sec. Positive if the estimator overestimates.
+    pub bias: f64,
+    /// Mean absolute error relative to the ground truth, in percent.
+    pub mape: f64,
+}
+
+/// Reads PGM data points with the columns `time_ms`, `gin`, `gout`, `len`
+/// and `num_acked`, and optionally `app_limited`.
+pub fn read_pgm_csv(path: &Path) -> Result<Vec<GinGout>> {
+    let (header, rows) = read_csv(path)?;
+    let time_ms = column(&header, "time_ms")?;
+    let gin = column(&header, "gin")?;
+    let gout = column(&header, "gout")?;
+    let len = column(&header, "len")?;
+    let num_acked = column(&header, "num_acked")?;
+    let app_limited = column(&header, "app_limited").ok();
+
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| -> Result<GinGout> {
+            let field = |col: usize| {
+                row[col]
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("{}: bad value on row {}", path.display(), i + 2))
+            };
+            Ok(GinGout {
+                gin: field(gin)?,
+                gout: field(gout)?,
+                len: field(len)?,
+                num_acked: field(num_acked)? as u8,
+                timestamp: Timestamp::from_millis(field(time_ms)?.max(0.0) as u64),
+                // Postgres exports booleans as t and f
+                app_limited: app_limited.is_some_and(|col| {
+                    matches!(row[col].trim(), "t" | "true" | "1")
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Reads ground truth with the columns `time_ms` and `throughput`, scaled by
+/// `scale` to get bytes/sec.
+pub fn read_truth_csv(path: &Path, scale: f64) -> Result<Vec<Truth>> {
+    let (header, rows) = read_csv(path)?;
+    let time_ms = column(&header, "time_ms")?;
+    let throughput = column(&header, "throughput")?;
+
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| -> Result<Truth> {
+            let parse = |col: usize| {
+                row[col]
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("{}: bad value on row {}", path.display(), i + 2))
+            };
+            Ok(Truth {
+                time_ms: parse(time_ms)? as i64,
+                value: parse(throughput)? * scale,
+            })
+        })
+        .collect()
+}
+
+/// Runs every estimator over sliding windows of `dps` and compares the
+/// estimates with the mean ground truth in the same window.
+pub fn evaluate(dps: &[GinGout], truth: &[Truth], params: &EvalParams) -> Vec<ErrorStats> {
+    let mut dps = dps.to_vec();
+    dps.sort_by_key(|dp| dp.timestamp);
+    let mut truth = truth.to_vec();
+    truth.sort_by_key(|t| t.time_ms);
+
+    let mut acc: Vec<Accumulator> =
+        RegressionType::ALL.iter().map(|_| Accumulator::default()).collect();
+    let (Some(first), Some(last)) = (dps.first(), dps.last()) else {
+        return report(acc);
+    };
+    let step = params.step.max(Duration::from_millis(1));
+
+    let mut start = first.timestamp;
+    while start <= last.timestamp {
+        let end = start + params.window;
+        let from = dps.partition_point(|dp| dp.timestamp < start);
+        let to = dps.partition_point(|dp| dp.timestamp < end);
+        let window = &dps[from..to];
+        let (start_ms, end_ms) = (start.as_millis(), end.as_millis());
+        start = start + step;
+
+        let samples: Vec<f64> = truth
+            .iter()
+            .filter(|t| t.time_ms >= start_ms && t.time_ms < end_ms)
+            .map(|t| t.value)
+            .collect();
+        if window.is_empty() || samples.is_empty() {
+            continue;
+        }
+        let expected = samples.iter().sum::<f64>() / samples.len() as f64;
+
+        for (estimator, acc) in RegressionType::ALL.iter().zip(acc.iter_mut()) {
+            let mut sender = PABWESender::new();
+            sender.dps = window.to_vec();
+            let (estimate, _) = sender.estimate_abw(*estimator, &params.filter, params.phy_cap);
+            acc.add(estimate.map(|e| e.abw), expected);
+        }
+    }
+    report(acc)
+}
+
+#[derive(Debug, Default)]
+struct Accumulator {
+    windows: usize,
+    estimates: usize,
+    sum_abs: f64,
+    sum_sq: f64,
+    sum: f64,
+    sum_rel: f64,
+    rel_count: usize,
+}
+
+impl Accumulator {
+    fn add(&mut self, estimate: Option<f64>, expected: f64) {
+        self.windows += 1;
+        let Some(estimate) = estimate else {
+            return;
+        };
+        let err = estimate - expected;
+        self.estimates += 1;
+        self.sum_abs += err.abs();
+        self.sum_sq += err * err;
+        self.sum += err;
+        if expected > 0.0 {
+            self.sum_rel += err.abs() / expected;
+            self.rel_count += 1;
+        }
+    }
+}
+
+fn report(acc: Vec<Accumulator>) -> Vec<ErrorStats> {
+    RegressionType::ALL
+        .iter()
+        .zip(acc)
+        .map(|(estimator, acc)| {
+            let n = acc.estimates.max(1) as f64;
+            ErrorStats {
+                estimator: *estimator,
+                windows: acc.windows,
+                estimates: acc.estimates,
+                mae: acc.sum_abs / n,
+                rmse: (acc.sum_sq / n).sqrt(),
+                bias: acc.sum / n,
+                mape: 100.0 * acc.sum_rel / acc.rel_count.max(1) as f64,
+            }
+        })
+        .collect()
+}
+
+fn read_csv(path: &Path) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<String> = lines
+        .next()
+        .ok_or_else(|| anyhow!("{} is empty", path.display()))?
+        .split(',')
+        .map(|col| col.trim().to_string())
+        .collect();
+    let rows: Vec<Vec<String>> = lines
+        .map(|line| line.split(',').map(String::from).collect())
+        .collect();
+    if let Some(i) = rows.iter().position(|row| row.len() != header.len()) {
+        return Err(anyhow!("{}: row {} has the wrong number of columns", path.display(), i + 2));
+    }
+    Ok((header, rows))
+}
+
+fn column(header: &[String], name: &str) -> Result<usize> {
+    header
+        .iter()
+        .position(|col| col == name)
+        .ok_or_else(|| anyhow!("Missing column {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_exact_points() {
+        // Points on y = x / 2e6 + 0.5, an available bandwidth of 1 MB/s
+        let dps: Vec<GinGout> = (0..100)
+            .map(|i| {
+                let x = 1.2e6 + i as f64 * 2e4;
+                let gin = 1448.0 / x;
+                GinGout {
+                    gin,
+                    gout: (x / 2e6 + 0.5) * gin,
+                    len: 1448.0,
+                    num_acked: 1,
+                    timestamp: Timestamp::from_millis(1_000_000 + i * 100),
+                    app_limited: false,
+                }
+            })
+            .collect();
+        let truth: Vec<Truth> = (0..10)
+            .map(|i| Truth {
+                time_ms: 1_000_000 + i * 1000,
+                value: 1e6,
+            })
+            .collect();
+        let params = EvalParams {
+            window: Duration::from_secs(5),
+            step: Duration::from_secs(5),
+            filter: Filter {
+                gin_quantile: 0.0,
+                ..Filter::default()
+            },
+            phy_cap: 1e9,
+        };
+
+        let stats = evaluate(&dps, &truth, &params);
+        assert_eq!(stats.len(), RegressionType::ALL.len());
+        for stats in stats {
+            assert_eq!(stats.windows, 2);
+            assert_eq!(stats.estimates, 2);
+            assert!(stats.mape < 1.0, "{:?}", stats);
+        }
+    }
+}
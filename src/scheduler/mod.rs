@@ -1,3 +1,6 @@
+pub mod dashboard;
 pub mod db_util;
 pub mod core_grpc;
-pub mod receiving_server;
\ No newline at end of file
+pub mod evaluation;
+pub mod receiving_server;
+pub mod sequence;
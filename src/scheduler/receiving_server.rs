@@ -1,23 +1,138 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use crate::proto_bw::{DataMsg, HelloMessage};
+use crate::proto_bw::{DataMsg, HelloMessage, StreamAck};
 use crate::proto_bw::client_data_service_server::{ClientDataService, ClientDataServiceServer};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::Server;
-use tonic::{Request, Response, Status, Streaming};
+use tonic::{Code, Request, Response, Status, Streaming};
 use anyhow::Result;
 
+/// Messages queued per connection. Once full, new messages from that
+/// connection are dropped so a flooding node only loses its own data.
+const CONNECTION_QUEUE: usize = 40;
+/// Acks not yet written back to the node. The stream waits for room, so a
+/// node that does not read them is slowed down rather than flooding.
+const ACK_QUEUE: usize = 40;
+
+/// A newly opened data stream, the address it comes from and the queue its
+/// messages arrive on.
+pub type Connection = (u64, String, Receiver<DataMsg>);
+
+/// Counters for one source address, over all its connections.
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionStats {
+    /// Messages received.
+    pub messages: u64,
+    /// Streams that ended on a message that could not be decoded.
+    pub decode_failures: u64,
+    /// Messages dropped because the connection's queue was full.
+    pub dropped: u64,
+}
 
 #[derive(Debug, Clone)]
 pub struct DataReceiver {
-    data_tx: Sender<DataMsg>,
+    conn_tx: Sender<Connection>,
+    next_id: Arc<AtomicU64>,
+    stats: Arc<Mutex<HashMap<String, ConnectionStats>>>,
 }
 
 impl DataReceiver {
-    pub fn new(data_tx: Sender<DataMsg> ) -> Self {
-        DataReceiver { data_tx }
+    /// Every incoming stream gets its own bounded queue, which is handed
+    /// over on `conn_tx`.
+    pub fn new(conn_tx: Sender<Connection>) -> Self {
+        DataReceiver {
+            conn_tx,
+            next_id: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Snapshot of the counters per source address.
+    pub fn stats(&self) -> Vec<(String, ConnectionStats)> {
+        let stats = self.stats.lock().unwrap();
+        let mut stats: Vec<_> = stats.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    fn record(&self, source: &str, update: impl FnOnce(&mut ConnectionStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        update(stats.entry(source.to_string()).or_default());
+    }
+
+    /// Hands a queue for the messages of a new stream over on `conn_tx`, and
+    /// fills it until the stream ends. Without `acks` messages that do not
+    /// fit in the queue are dropped. With them, the stream waits for room
+    /// and each message is acked on `acks` once it is queued, so the node
+    /// sends again whatever was not.
+    async fn receive(
+        &self,
+        request: Request<Streaming<DataMsg>>,
+        acks: Option<Sender<Result<StreamAck, Status>>>,
+    ) -> Result<(), Status> {
+        let source = request
+            .remote_addr()
+            .map_or_else(|| String::from("unknown"), |addr| addr.ip().to_string());
+
+        let (tx, rx) = channel(CONNECTION_QUEUE);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.conn_tx
+            .send((id, source.clone(), rx))
+            .await
+            .map_err(|_| Status::unavailable("Data receiver has stopped"))?;
+
+        let mut stream = request.into_inner();
+        loop {
+            match stream.message().await {
+                Ok(Some(msg)) => {
+                    let seq = msg.seq;
+                    let queued = match &acks {
+                        Some(_) => tx.send(msg).await.is_ok(),
+                        // Never wait on a slow consumer, drop instead
+                        None => tx.try_send(msg).is_ok(),
+                    };
+                    self.record(&source, |stats| {
+                        stats.messages += 1;
+                        if !queued {
+                            stats.dropped += 1;
+                        }
+                    });
+                    if let Some(acks) = &acks {
+                        // Acks are cumulative, nothing past a lost message
+                        if !queued {
+                            return Err(Status::unavailable("Data receiver has stopped"));
+                        }
+                        if acks.send(Ok(StreamAck { seq })).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                Ok(None) => return Ok(()),
+                Err(status) => {
+                    // Decode errors are reported as internal errors
+                    if status.code() == Code::Internal {
+                        self.record(&source, |stats| stats.decode_failures += 1);
+                    }
+                    println!("Data stream from {} failed: {}", source, status);
+                    return Err(status);
+                }
+            }
+        }
+    }
+
+    /// The gRPC service, sharing the counters and connection queue of self.
+    pub fn service(&self) -> ClientDataServiceServer<Self> {
+        // Nodes pick the compression, accept either
+        ClientDataServiceServer::new(self.clone())
+            .accept_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd)
     }
 
     /// Consumes self, returns a handle to the task
@@ -32,9 +147,7 @@ impl DataReceiver {
             let mut backoff = Duration::from_secs(3);
             loop {
                 println!("Attempting to bind gRPC server on {}", addr);
-                let serve_result = Server::builder()
-                    .add_service(ClientDataServiceServer::new(self.clone()))
-                    .serve(addr);
+                let serve_result = Server::builder().add_service(self.service()).serve(addr);
 
                 match serve_result.await {
                     Ok(()) => {
@@ -64,13 +177,90 @@ impl ClientDataService for DataReceiver {
         &self,
         request: Request<Streaming<DataMsg>>,
     ) -> Result<Response<HelloMessage>, Status> {
-        let mut stream = request.into_inner();
-        while let Some(msg) = stream.message().await? {
-            // Send the message back to the main task
-            self.data_tx.send_timeout(msg, Duration::from_secs(2)).await
-                .map_err(|_| Status::internal("Failed to send message to data receiver"))?;
-        }
-        Ok(Response::new(HelloMessage { message: "Goodbye!".into() }))
+        self.receive(request, None).await?;
+        Ok(Response::new(HelloMessage {
+            message: "Goodbye!".into(),
+            capabilities: None,
+            identity: None,
+        }))
     }
 
-}
\ No newline at end of file
+    type AckedStreamStream = ReceiverStream<Result<StreamAck, Status>>;
+
+    async fn acked_stream(
+        &self,
+        request: Request<Streaming<DataMsg>>,
+    ) -> Result<Response<Self::AckedStreamStream>, Status> {
+        let (ack_tx, ack_rx) = channel(ACK_QUEUE);
+        let receiver = self.clone();
+        tokio::spawn(async move {
+            if let Err(status) = receiver.receive(request, Some(ack_tx.clone())).await {
+                let _ = ack_tx.send(Err(status)).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(ack_rx)))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prost_net::bandwidth_client::stream_data_msg;
+    use crate::prost_net::upstream::UpstreamQueue;
+    use crate::proto_bw::data_msg;
+    use tokio::time::timeout;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn test_client_stream_delivers_in_order() {
+        let (conn_tx, mut conn_rx) = channel(4);
+        let receiver = DataReceiver::new(conn_tx);
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = receiver.service();
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+        });
+
+        let upstream = UpstreamQueue::new(vec![addr.to_string()], 10);
+        for seq in 1..=3 {
+            upstream.push(DataMsg {
+                seq,
+                data: Some(data_msg::Data::Hello(HelloMessage::default())),
+                ..Default::default()
+            });
+        }
+        let (cap_tx, _cap_rx) = channel(4);
+        let streamed = upstream.clone();
+        let node = tokio::spawn(async move {
+            stream_data_msg(&streamed, 0, HashMap::new(), cap_tx).await
+        });
+
+        let (_, source, mut msgs) = timeout(WAIT, conn_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(source, "127.0.0.1");
+        // The node opens with a hello of its own
+        let hello = timeout(WAIT, msgs.recv()).await.unwrap().unwrap();
+        assert_eq!(hello.seq, 0);
+        assert!(matches!(hello.data, Some(data_msg::Data::Hello(_))));
+        for seq in 1..=3 {
+            let msg = timeout(WAIT, msgs.recv()).await.unwrap().unwrap();
+            assert_eq!(msg.seq, seq);
+        }
+        assert_eq!(receiver.stats()[0].0, "127.0.0.1");
+        // Acked back to the node
+        timeout(WAIT, async {
+            while upstream.status().delivered < 3 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        node.abort();
+    }
+}
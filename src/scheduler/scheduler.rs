@@ -2,16 +2,28 @@
 /// part of the tool itself.
 
 use clap::Parser;
-use network_listener::proto_bw::data_msg;
+use network_listener::identity::verify;
+use network_listener::Timestamp;
+use network_listener::prost_net::capabilities::check_compatible;
+use network_listener::prost_net::trace::WindowTrace;
+use network_listener::proto_bw::{data_msg, BandwidthMessage};
 use network_listener::scheduler::core_grpc::{self, ThroughputDP};
+use network_listener::scheduler::dashboard::dispatch_dashboard;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio_postgres::Client;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{StreamExt, StreamMap};
 use network_listener::scheduler::receiving_server::DataReceiver;
+use network_listener::scheduler::sequence::{SeqCheck, SequenceTracker};
 
 use network_listener::scheduler::db_util::{
-    upload_bandwidth, upload_probe_gap_measurements, upload_rtt, upload_throughput, get_and_insert_experiment,
+    upload_bandwidth, upload_metadata, upload_probe_gap_measurements, upload_rtt, upload_sequence,
+    upload_throughput, get_and_insert_experiment,
 };
 
 #[derive(Parser, Debug)]
@@ -32,6 +44,15 @@ struct Config {
     /// Description of the experiment
     #[arg(short, long)]
     description: String,
+
+    /// Print each report window as it is received and stored
+    #[arg(long)]
+    trace: bool,
+
+    /// Serve recent link time series over HTTP on this address, e.g.
+    /// 127.0.0.1:8081, for dashboards without database access
+    #[arg(long)]
+    dashboard_addr: Option<SocketAddr>,
 }
 
 #[derive(Deserialize)]
@@ -48,6 +69,7 @@ async fn run_server(
     mut thput_rx: UnboundedReceiver<Vec<ThroughputDP>>,
     experiment_name: String,
     experiment_description: String,
+    trace: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let listen_port = listen_addr
@@ -61,9 +83,18 @@ async fn run_server(
     let experiment_id = get_and_insert_experiment(&client, &experiment_name, &experiment_description).await?;
 
     println!("Experiment ID: {}", experiment_id);
-    let (data_tx, mut data_rx) = tokio::sync::mpsc::channel(40);
-    let data_receiver = DataReceiver::new(data_tx);
-    data_receiver.dispatch_server(listen_port.to_string());
+    let (conn_tx, mut conn_rx) = tokio::sync::mpsc::channel(16);
+    let data_receiver = DataReceiver::new(conn_tx);
+    data_receiver.clone().dispatch_server(listen_port.to_string());
+    // One queue per connection, polled in turn so a single node cannot
+    // starve the others
+    let mut queues = StreamMap::new();
+    let mut sequences = SequenceTracker::new();
+    // Nodes whose protocol version this scheduler does not understand
+    let mut incompatible = HashSet::new();
+    // Last metadata stored for each node start
+    let mut metadata: HashMap<(String, i64), HashMap<String, String>> = HashMap::new();
+    let mut stats_tick = tokio::time::interval(Duration::from_secs(60));
 
     println!("Server listening on {}", listen_addr);
 
@@ -74,15 +105,109 @@ async fn run_server(
                 upload_throughput(thput, &client, experiment_id).await;
             }
 
-            // This just reads raw unencrypted TCP packets as protobuf data
-            Some(bwm) = data_rx.recv() => {
+            Some((id, source, rx)) = conn_rx.recv() => {
+                queues.insert((id, source), ReceiverStream::new(rx));
+            }
+
+            _ = stats_tick.tick() => {
+                for (source, stats) in data_receiver.stats() {
+                    println!(
+                        "{}: {} messages, {} dropped, {} decode failures",
+                        source, stats.messages, stats.dropped, stats.decode_failures
+                    );
+                }
+                for (node, seq) in sequences.nodes() {
+                    println!(
+                        "{}: {} received, {} missing, {} duplicates, {:.1}% complete",
+                        node, seq.received, seq.missing, seq.duplicates,
+                        seq.completeness() * 100.0
+                    );
+                }
+            }
+
+            // Messages streamed by the nodes over gRPC
+            Some(((_, source), bwm)) = queues.next(), if !queues.is_empty() => {
+                // Nodes that send no id are told apart by the address they
+                // connect from, which stays the same across reconnects
+                let node_id = if bwm.node_id.is_empty() {
+                    source
+                } else {
+                    bwm.node_id.clone()
+                };
+                // The hello opening each stream tells if the rest can be read
+                if let Some(data_msg::Data::Hello(hello)) = &bwm.data {
+                    let caps = hello.capabilities.clone().unwrap_or_default();
+                    match check_compatible(&caps) {
+                        Ok(()) => {
+                            incompatible.remove(&node_id);
+                        }
+                        Err(reason) => {
+                            println!("Ignoring data from {}: {}", node_id, reason);
+                            incompatible.insert(node_id.clone());
+                        }
+                    }
+                } else if incompatible.contains(&node_id) {
+                    continue;
+                }
+                if trace && bwm.window_id != 0 {
+                    println!("[trace] {} received from {}", WindowTrace(&bwm), node_id);
+                }
+                match sequences.check(&node_id, bwm.epoch, bwm.seq) {
+                    SeqCheck::Duplicate => {
+                        println!("Skipping duplicate message {} from {}", bwm.seq, node_id);
+                        continue;
+                    }
+                    SeqCheck::Gap(missing) => {
+                        println!("Missed {} messages from {} before {}", missing, node_id, bwm.seq);
+                    }
+                    SeqCheck::InOrder | SeqCheck::Unsequenced => {}
+                }
+                if bwm.seq != 0 {
+                    upload_sequence(&node_id, bwm.epoch, bwm.seq, &client, experiment_id).await;
+                }
+                let key = (node_id.clone(), bwm.epoch);
+                if !bwm.metadata.is_empty() && metadata.get(&key) != Some(&bwm.metadata) {
+                    upload_metadata(&node_id, bwm.epoch, &bwm.metadata, &client, experiment_id)
+                        .await;
+                    metadata.insert(key, bwm.metadata.clone());
+                }
+                let window = (trace && bwm.window_id != 0).then(|| WindowTrace(&bwm).to_string());
                 if let Some(data) = bwm.data {
                     match data {
                         data_msg::Data::Bandwidth(bw) => {
-                            upload_bandwidth(bw, &client, experiment_id).await;
+                            upload_bandwidth(bw, &node_id, &client, experiment_id).await;
                         },
                         data_msg::Data::Hello(hello) => {
-                            println!("Received hello message: {}", hello.message);
+                            match hello.capabilities {
+                                Some(caps) => println!(
+                                    "Received hello from {}: v{} (protocol {}), probes {:?}, window {}s, phy cap {}",
+                                    hello.message, caps.version, caps.protocol_version, caps.probes,
+                                    caps.measurement_window, caps.link_phy_cap
+                                ),
+                                None => println!("Received hello message: {}", hello.message),
+                            }
+                            // Nodes with an identity are keyed by it, their address is a tag
+                            if let Some(identity) = hello.identity {
+                                // The address is only a tag, nodes may stream through NAT
+                                let now = Timestamp::now().as_millis();
+                                match verify(&identity, None, now) {
+                                    Ok(verified) if verified.id == node_id => {
+                                        if let Some(addr) = verified.addr {
+                                            let tags = HashMap::from([
+                                                ("addr".to_string(), addr.to_string()),
+                                            ]);
+                                            upload_metadata(
+                                                &node_id, bwm.epoch, &tags, &client, experiment_id,
+                                            )
+                                            .await;
+                                        }
+                                    }
+                                    Ok(verified) => println!(
+                                        "Node {} sent the identity of {}", node_id, verified.id
+                                    ),
+                                    Err(e) => println!("Invalid identity from {}: {}", node_id, e),
+                                }
+                            }
                         },
                         data_msg::Data::Rtts(rtts) => {
                             upload_rtt(rtts, &client, experiment_id).await;
@@ -90,22 +215,42 @@ async fn run_server(
                         data_msg::Data::Pgmmsg(pgm) => {
                             upload_probe_gap_measurements(pgm, &client, experiment_id).await;
                         }
+                        data_msg::Data::Report(report) => {
+                            let bw = BandwidthMessage { link_state: report.link_state };
+                            upload_bandwidth(bw, &node_id, &client, experiment_id).await;
+                            if let Some(rtts) = report.rtts {
+                                upload_rtt(rtts, &client, experiment_id).await;
+                            }
+                            if let Some(pgm) = report.pgm {
+                                upload_probe_gap_measurements(pgm, &client, experiment_id).await;
+                            }
+                        }
+                        data_msg::Data::Events(events) => {
+                            for event in events.events {
+                                println!(
+                                    "Link event from {}: {:?} {} -> {} {}, {:.1} against {:.1}",
+                                    node_id,
+                                    event.kind(),
+                                    event.sender_ip,
+                                    event.receiver_ip,
+                                    if event.raised { "raised" } else { "cleared" },
+                                    event.value,
+                                    event.threshold
+                                );
+                            }
+                        }
                     }
                 }
+                if let Some(window) = window {
+                    println!("[trace] {} stored", window);
+                }
             }
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Load the configuration from the command line arguments
-    let config = Config::parse();
-
-    let toml_content = std::fs::read_to_string(&config.secrets_file)?;
-    let db_config: DbConfig = toml::from_str(&toml_content)?;
-
-    // Set up the connection to the database
+/// Connects to the database, driving the connection in the background.
+async fn connect(db_config: &DbConfig) -> Result<Client, tokio_postgres::Error> {
     let (client, connection) = tokio_postgres::connect(
         &format!(
             "host={} user={} password={} dbname={}",
@@ -120,6 +265,25 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             eprintln!("connection error: {}", e);
         }
     });
+    Ok(client)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Load the configuration from the command line arguments
+    let config = Config::parse();
+
+    let toml_content = std::fs::read_to_string(&config.secrets_file)?;
+    let db_config: DbConfig = toml::from_str(&toml_content)?;
+
+    // Set up the connection to the database
+    let client = connect(&db_config).await?;
+
+    // Dashboard queries get their own connection, so they do not hold up
+    // the inserts
+    if let Some(addr) = config.dashboard_addr {
+        dispatch_dashboard(addr, connect(&db_config).await?).await?;
+    }
 
     let (thput_tx, thput_rx) = tokio::sync::mpsc::unbounded_channel();
 
@@ -135,6 +299,7 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             thput_rx,
             config.experiment_name,
             config.description,
+            config.trace,
         )
         .await
         .unwrap_or(());
@@ -0,0 +1,124 @@
+//! Completeness of the data streamed by each node, from the sequence numbers
+//! on its data messages.
+//!
+//! Messages from one node arrive in order, so a sequence number at or below
+//! the last one seen is a duplicate, and one that skips ahead means the
+//! messages in between were lost on the node, on the way or in the queues.
+
+use std::collections::HashMap;
+
+/// What a message's sequence number says about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqCheck {
+    /// Carries no sequence number, like the opening hello.
+    Unsequenced,
+    InOrder,
+    /// In order after this many missing messages.
+    Gap(u64),
+    /// Already seen, or from before the node restarted.
+    Duplicate,
+}
+
+/// Counters for one node, over all its restarts.
+#[derive(Debug, Default, Clone)]
+pub struct NodeSequence {
+    /// Start of the node's current sequence.
+    pub epoch: i64,
+    /// Highest sequence number seen in this epoch.
+    pub last: u64,
+    pub received: u64,
+    pub missing: u64,
+    pub duplicates: u64,
+}
+
+impl NodeSequence {
+    /// Fraction of the messages sent since the first one seen that arrived.
+    pub fn completeness(&self) -> f64 {
+        let expected = self.received + self.missing;
+        if expected == 0 {
+            return 1.0;
+        }
+        self.received as f64 / expected as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    nodes: HashMap<String, NodeSequence>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        SequenceTracker::default()
+    }
+
+    /// Records a message from `node_id`. Duplicates should be skipped.
+    pub fn check(&mut self, node_id: &str, epoch: i64, seq: u64) -> SeqCheck {
+        if seq == 0 {
+            return SeqCheck::Unsequenced;
+        }
+        // Count from the first message seen, not from 1
+        let node = self
+            .nodes
+            .entry(node_id.to_string())
+            .or_insert_with(|| NodeSequence {
+                epoch,
+                last: seq - 1,
+                ..Default::default()
+            });
+        if epoch > node.epoch {
+            // Restarted, the new sequence counts from 1
+            node.epoch = epoch;
+            node.last = 0;
+        } else if epoch < node.epoch || seq <= node.last {
+            node.duplicates += 1;
+            return SeqCheck::Duplicate;
+        }
+        let missing = seq - node.last - 1;
+        node.last = seq;
+        node.received += 1;
+        node.missing += missing;
+        if missing > 0 {
+            SeqCheck::Gap(missing)
+        } else {
+            SeqCheck::InOrder
+        }
+    }
+
+    /// Snapshot of the counters per node.
+    pub fn nodes(&self) -> Vec<(String, NodeSequence)> {
+        let mut nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gaps_duplicates_and_restarts() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.check("a", 100, 0), SeqCheck::Unsequenced);
+        // Joined mid sequence
+        assert_eq!(tracker.check("a", 100, 5), SeqCheck::InOrder);
+        assert_eq!(tracker.check("a", 100, 6), SeqCheck::InOrder);
+        assert_eq!(tracker.check("a", 100, 6), SeqCheck::Duplicate);
+        assert_eq!(tracker.check("a", 100, 9), SeqCheck::Gap(2));
+        assert_eq!(tracker.check("a", 100, 8), SeqCheck::Duplicate);
+
+        // Restart
+        assert_eq!(tracker.check("a", 200, 1), SeqCheck::InOrder);
+        assert_eq!(tracker.check("a", 100, 10), SeqCheck::Duplicate);
+        assert_eq!(tracker.check("a", 200, 3), SeqCheck::Gap(1));
+
+        let (_, a) = &tracker.nodes()[0];
+        assert_eq!((a.received, a.missing, a.duplicates), (5, 3, 3));
+        assert_eq!(a.completeness(), 5.0 / 8.0);
+    }
+}
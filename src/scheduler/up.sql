@@ -32,6 +32,111 @@ CREATE TABLE
         delay DOUBLE PRECISION,
         jitter DOUBLE PRECISION,
         loss DOUBLE PRECISION,
+        probe_thp_in DOUBLE PRECISION,
+        probe_thp_out DOUBLE PRECISION,
+        retry_rate DOUBLE PRECISION,
+        link_alive BOOLEAN,
+        other_bytes BIGINT,
+        other_protocols INTEGER[],
+        abw_down DOUBLE PRECISION,
+        latency_down DOUBLE PRECISION,
+        loss_up DOUBLE PRECISION,
+        loss_down DOUBLE PRECISION,
+        burst_thp_in DOUBLE PRECISION,
+        abw_std_err DOUBLE PRECISION,
+        abw_samples INTEGER,
+        abw_down_std_err DOUBLE PRECISION,
+        abw_down_samples INTEGER,
+        estimator TEXT,
+        train_abw_in DOUBLE PRECISION,
+        clock_offset BIGINT,
+        thp_in_p5 DOUBLE PRECISION,
+        thp_in_p50 DOUBLE PRECISION,
+        thp_in_p95 DOUBLE PRECISION,
+        thp_out_p5 DOUBLE PRECISION,
+        thp_out_p50 DOUBLE PRECISION,
+        thp_out_p95 DOUBLE PRECISION,
+        idle BOOLEAN,
+        rtt_baseline DOUBLE PRECISION,
+        rtt_loaded DOUBLE PRECISION,
+        bufferbloat DOUBLE PRECISION,
+        fct_p50 DOUBLE PRECISION,
+        fct_p95 DOUBLE PRECISION,
+        fct_max DOUBLE PRECISION,
+        fct_flows INTEGER,
+        rwnd_avg DOUBLE PRECISION,
+        rwnd_min BIGINT,
+        rwnd_max BIGINT,
+        rwnd_stalls INTEGER,
+        rwnd_stalled DOUBLE PRECISION,
+        next_hop TEXT,
+        egress_iface TEXT,
+        degraded BOOLEAN,
+        capacity DOUBLE PRECISION,
+        ping_rtt DOUBLE PRECISION,
+        ping_loss DOUBLE PRECISION,
+        hops INTEGER,
+        path_changes INTEGER,
+        fragmented_bytes BIGINT,
+        node_id TEXT,
+        peer_id TEXT,
+        PRIMARY KEY (time, id)
+    );
+
+-- Estimates from every estimator, with client.compare_estimators set.
+CREATE TABLE
+    IF NOT EXISTS abw_estimate (
+        time TIMESTAMPTZ NOT NULL,
+        id SERIAL,
+        link_id INTEGER NOT NULL REFERENCES link (id) ON DELETE CASCADE,
+        experiment_id INTEGER NOT NULL REFERENCES experiment (id) ON DELETE CASCADE,
+        estimator TEXT NOT NULL,
+        abw DOUBLE PRECISION,
+        std_err DOUBLE PRECISION,
+        samples INTEGER,
+        PRIMARY KEY (time, id)
+    );
+
+-- TCP connections that ended, with client.flow_records set.
+CREATE TABLE
+    IF NOT EXISTS flow (
+        time TIMESTAMPTZ NOT NULL,
+        id SERIAL,
+        link_id INTEGER NOT NULL REFERENCES link (id) ON DELETE CASCADE,
+        experiment_id INTEGER NOT NULL REFERENCES experiment (id) ON DELETE CASCADE,
+        remote_ip TEXT NOT NULL,
+        local_port INTEGER,
+        remote_port INTEGER,
+        outgoing BOOLEAN,
+        duration DOUBLE PRECISION,
+        bytes BIGINT,
+        process TEXT,
+        PRIMARY KEY (time, id)
+    );
+
+-- Sequence number of every data message accepted from a node.
+CREATE TABLE
+    IF NOT EXISTS data_sequence (
+        time TIMESTAMPTZ NOT NULL,
+        id SERIAL,
+        experiment_id INTEGER NOT NULL REFERENCES experiment (id) ON DELETE CASCADE,
+        node_id TEXT NOT NULL,
+        epoch BIGINT NOT NULL,
+        seq BIGINT NOT NULL,
+        PRIMARY KEY (time, id)
+    );
+
+-- Tags from the [metadata] section of each node config, once per node
+-- start and whenever they change.
+CREATE TABLE
+    IF NOT EXISTS node_metadata (
+        time TIMESTAMPTZ NOT NULL,
+        id SERIAL,
+        experiment_id INTEGER NOT NULL REFERENCES experiment (id) ON DELETE CASCADE,
+        node_id TEXT NOT NULL,
+        epoch BIGINT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
         PRIMARY KEY (time, id)
     );
 
@@ -45,6 +150,7 @@ CREATE TABLE
         gout DOUBLE PRECISION,
         len INTEGER,
         num_acked INTEGER,
+        app_limited BOOLEAN,
         PRIMARY KEY (id, link_id)
     );
 
@@ -53,10 +159,24 @@ CREATE TABLE
         time TIMESTAMPTZ NOT NULL,
         id SERIAL,
         link_id INTEGER NOT NULL REFERENCES link (id) ON DELETE CASCADE,
+        experiment_id INTEGER NOT NULL REFERENCES experiment (id) ON DELETE CASCADE,
         rtt DOUBLE PRECISION,
         PRIMARY KEY (time, id)
     );
 
+CREATE TABLE
+    IF NOT EXISTS rtt_bucket (
+        time TIMESTAMPTZ NOT NULL,
+        id SERIAL,
+        link_id INTEGER NOT NULL REFERENCES link (id) ON DELETE CASCADE,
+        experiment_id INTEGER NOT NULL REFERENCES experiment (id) ON DELETE CASCADE,
+        rtt_min DOUBLE PRECISION,
+        rtt_mean DOUBLE PRECISION,
+        rtt_max DOUBLE PRECISION,
+        samples INTEGER,
+        PRIMARY KEY (time, id)
+    );
+
 CREATE TABLE
     IF NOT EXISTS throughput (
         time TIMESTAMPTZ NOT NULL,
@@ -71,6 +191,64 @@ CREATE TABLE
         throughput DOUBLE PRECISION
     );
 
+-- Columns added after the first release. CREATE TABLE IF NOT EXISTS leaves
+-- the tables of an existing database as they are, so they get them here.
+ALTER TABLE link_state
+    ADD COLUMN IF NOT EXISTS probe_thp_in DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS probe_thp_out DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS retry_rate DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS link_alive BOOLEAN,
+    ADD COLUMN IF NOT EXISTS other_bytes BIGINT,
+    ADD COLUMN IF NOT EXISTS other_protocols INTEGER[],
+    ADD COLUMN IF NOT EXISTS abw_down DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS latency_down DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS loss_up DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS loss_down DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS burst_thp_in DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS abw_std_err DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS abw_samples INTEGER,
+    ADD COLUMN IF NOT EXISTS abw_down_std_err DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS abw_down_samples INTEGER,
+    ADD COLUMN IF NOT EXISTS estimator TEXT,
+    ADD COLUMN IF NOT EXISTS train_abw_in DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS clock_offset BIGINT,
+    ADD COLUMN IF NOT EXISTS thp_in_p5 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS thp_in_p50 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS thp_in_p95 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS thp_out_p5 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS thp_out_p50 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS thp_out_p95 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS idle BOOLEAN,
+    ADD COLUMN IF NOT EXISTS rtt_baseline DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS rtt_loaded DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS bufferbloat DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS fct_p50 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS fct_p95 DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS fct_max DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS fct_flows INTEGER,
+    ADD COLUMN IF NOT EXISTS rwnd_avg DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS rwnd_min BIGINT,
+    ADD COLUMN IF NOT EXISTS rwnd_max BIGINT,
+    ADD COLUMN IF NOT EXISTS rwnd_stalls INTEGER,
+    ADD COLUMN IF NOT EXISTS rwnd_stalled DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS next_hop TEXT,
+    ADD COLUMN IF NOT EXISTS egress_iface TEXT,
+    ADD COLUMN IF NOT EXISTS degraded BOOLEAN,
+    ADD COLUMN IF NOT EXISTS capacity DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS ping_rtt DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS ping_loss DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS hops INTEGER,
+    ADD COLUMN IF NOT EXISTS path_changes INTEGER,
+    ADD COLUMN IF NOT EXISTS fragmented_bytes BIGINT,
+    ADD COLUMN IF NOT EXISTS node_id TEXT,
+    ADD COLUMN IF NOT EXISTS peer_id TEXT;
+
+ALTER TABLE pgm
+    ADD COLUMN IF NOT EXISTS app_limited BOOLEAN;
+
+-- Left without NOT NULL, rows from before it have no experiment
+ALTER TABLE rtt
+    ADD COLUMN IF NOT EXISTS experiment_id INTEGER REFERENCES experiment (id) ON DELETE CASCADE;
 
 CREATE VIEW
     throughputs_filtered AS
@@ -111,6 +289,7 @@ SELECT
     pgm.gout as gout,
     pgm.len as len,
     pgm.num_acked as num_acked,
+    pgm.app_limited as app_limited,
     pgm.time as time
 FROM
     pgm pgm
@@ -129,6 +308,54 @@ SELECT
     ls.delay as delay,
     ls.jitter as jitter,
     ls.loss as loss,
+    ls.probe_thp_in as probe_thp_in,
+    ls.probe_thp_out as probe_thp_out,
+    ls.retry_rate as retry_rate,
+    ls.link_alive as link_alive,
+    ls.other_bytes as other_bytes,
+    ls.other_protocols as other_protocols,
+    ls.abw_down as abw_down,
+    ls.latency_down as latency_down,
+    ls.loss_up as loss_up,
+    ls.loss_down as loss_down,
+    ls.burst_thp_in as burst_thp_in,
+    ls.abw_std_err as abw_std_err,
+    ls.abw_samples as abw_samples,
+    ls.abw_down_std_err as abw_down_std_err,
+    ls.abw_down_samples as abw_down_samples,
+    ls.estimator as estimator,
+    ls.train_abw_in as train_abw_in,
+    ls.clock_offset as clock_offset,
+    ls.thp_in_p5 as thp_in_p5,
+    ls.thp_in_p50 as thp_in_p50,
+    ls.thp_in_p95 as thp_in_p95,
+    ls.thp_out_p5 as thp_out_p5,
+    ls.thp_out_p50 as thp_out_p50,
+    ls.thp_out_p95 as thp_out_p95,
+    ls.idle as idle,
+    ls.rtt_baseline as rtt_baseline,
+    ls.rtt_loaded as rtt_loaded,
+    ls.bufferbloat as bufferbloat,
+    ls.fct_p50 as fct_p50,
+    ls.fct_p95 as fct_p95,
+    ls.fct_max as fct_max,
+    ls.fct_flows as fct_flows,
+    ls.rwnd_avg as rwnd_avg,
+    ls.rwnd_min as rwnd_min,
+    ls.rwnd_max as rwnd_max,
+    ls.rwnd_stalls as rwnd_stalls,
+    ls.rwnd_stalled as rwnd_stalled,
+    ls.next_hop as next_hop,
+    ls.egress_iface as egress_iface,
+    ls.degraded as degraded,
+    ls.capacity as capacity,
+    ls.ping_rtt as ping_rtt,
+    ls.ping_loss as ping_loss,
+    ls.hops as hops,
+    ls.path_changes as path_changes,
+    ls.fragmented_bytes as fragmented_bytes,
+    ls.node_id as node_id,
+    ls.peer_id as peer_id,
     ls.experiment_id as experiment_id,
     ls.time as time
 FROM
@@ -151,6 +378,34 @@ ORDER BY
     metric,
     time ASC;
 
+-- Fraction of the data messages each experiment got from the nodes,
+-- counting from the first message of each node start.
+CREATE VIEW
+    data_completeness AS
+SELECT
+    exp.name AS experiment_name,
+    seqs.experiment_id,
+    SUM(seqs.received) AS received,
+    SUM(seqs.expected) AS expected,
+    SUM(seqs.received)::DOUBLE PRECISION / SUM(seqs.expected) AS completeness
+FROM
+    (
+        SELECT
+            experiment_id,
+            COUNT(*) AS received,
+            MAX(seq) - MIN(seq) + 1 AS expected
+        FROM
+            data_sequence
+        GROUP BY
+            experiment_id,
+            node_id,
+            epoch
+    ) seqs
+    JOIN experiment exp ON seqs.experiment_id = exp.id
+GROUP BY
+    exp.name,
+    seqs.experiment_id;
+
 CREATE VIEW
     links AS
 SELECT
@@ -169,6 +424,12 @@ CREATE INDEX ON link_state (link_id);
 
 CREATE INDEX ON rtt (link_id);
 
+CREATE INDEX ON rtt_bucket (link_id);
+
+CREATE INDEX ON abw_estimate (link_id);
+
+CREATE INDEX ON flow (link_id);
+
 CREATE INDEX ON pgm (link_id);
 
 CREATE INDEX ON pgm (experiment_id);
@@ -177,11 +438,21 @@ CREATE INDEX ON link_state (experiment_id);
 
 CREATE INDEX ON throughput (experiment_id);
 
+CREATE INDEX ON data_sequence (experiment_id);
+
+CREATE INDEX ON node_metadata (experiment_id, node_id);
+
 SELECT
     create_hypertable ('link_state', 'time');
 
 SELECT
     create_hypertable ('rtt', 'time');
 
+SELECT
+    create_hypertable ('rtt_bucket', 'time');
+
+SELECT
+    create_hypertable ('data_sequence', 'time');
+
 SELECT
     create_hypertable ('pgm', 'link_id');
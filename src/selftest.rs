@@ -0,0 +1,225 @@
+//! `--selftest`: checks that the node can run as configured, prints a
+//! report and exits instead of starting. Failed checks would stop the node
+//! or leave it without data, warnings leave it running with less.
+
+use std::fmt;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::time::Duration;
+
+use pcap::{Capture, Device};
+
+use crate::listener::tstamp::{self, ClockDomain};
+use crate::CONFIG;
+
+/// How long a collector has to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Outcome::Ok => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+        write!(f, "{:<4}", label)
+    }
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+/// Outcome of each check, in the order they ran.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn push(&mut self, name: &'static str, outcome: Outcome, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            outcome,
+            detail: detail.into(),
+        });
+    }
+
+    /// True if no check failed, warnings allowed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.outcome != Outcome::Fail)
+    }
+
+    /// Exit code of the self-test.
+    pub fn exit_code(&self) -> i32 {
+        if self.passed() {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{}] {:<12} {}", check.outcome, check.name, check.detail)?;
+        }
+        let count = |outcome| self.checks.iter().filter(|c| c.outcome == outcome).count();
+        write!(
+            f,
+            "{} ok, {} warnings, {} failed",
+            count(Outcome::Ok),
+            count(Outcome::Warn),
+            count(Outcome::Fail)
+        )
+    }
+}
+
+/// Runs every check.
+pub fn run() -> Report {
+    let mut report = Report::default();
+    if let Some(device) = check_device(&mut report) {
+        check_capture(&mut report, &device);
+        check_timestamps(&mut report, &device);
+    }
+    check_iperf(&mut report);
+    check_grpc_port(&mut report);
+    check_collectors(&mut report);
+    report
+}
+
+fn check_device(report: &mut Report) -> Option<Device> {
+    let device = match &CONFIG.client.iface {
+        Some(name) => Device::list()
+            .map_err(|e| e.to_string())
+            .and_then(|devices| {
+                devices
+                    .into_iter()
+                    .find(|d| &d.name == name)
+                    .ok_or(format!("No device {}", name))
+            }),
+        None => Device::lookup()
+            .map_err(|e| e.to_string())
+            .and_then(|device| device.ok_or("No device available for capture".to_string())),
+    };
+    match device {
+        Ok(device) if device.flags.is_up() => {
+            report.push("interface", Outcome::Ok, device.name.clone());
+            Some(device)
+        }
+        Ok(device) => {
+            report.push("interface", Outcome::Warn, format!("{} is down", device.name));
+            Some(device)
+        }
+        Err(e) => {
+            report.push("interface", Outcome::Fail, e);
+            None
+        }
+    }
+}
+
+fn check_capture(report: &mut Report, device: &Device) {
+    match Capture::from_device(device.clone()).and_then(|cap| cap.open()) {
+        Ok(_) => report.push("capture", Outcome::Ok, format!("Can capture on {}", device.name)),
+        Err(e) => report.push("capture", Outcome::Fail, e.to_string()),
+    }
+}
+
+fn check_timestamps(report: &mut Report, device: &Device) {
+    let configured = CONFIG.client.tstamp_type;
+    let supported = match tstamp::supported(&device.name) {
+        Ok(supported) => supported,
+        Err(e) => {
+            report.push("timestamps", Outcome::Warn, e.to_string());
+            return;
+        }
+    };
+    let names: Vec<_> = supported.iter().map(|&t| tstamp::name(t)).collect();
+    let listed = match names.is_empty() {
+        true => "host only".to_string(),
+        false => names.join(", "),
+    };
+    let chosen = tstamp::choose(configured, &supported);
+    let domain = ClockDomain::of(chosen).as_str();
+    if chosen == configured {
+        let detail = format!("{} ({}), {} clock", tstamp::name(chosen), listed, domain);
+        report.push("timestamps", Outcome::Ok, detail);
+    } else {
+        let detail = format!(
+            "No {}, falls back to {} ({}), {} clock",
+            tstamp::name(configured),
+            tstamp::name(chosen),
+            listed,
+            domain
+        );
+        report.push("timestamps", Outcome::Warn, detail);
+    }
+}
+
+/// The node always runs an iperf3 server.
+fn check_iperf(report: &mut Report) {
+    match Command::new("iperf3").arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            let version = version.lines().next().unwrap_or_default().trim().to_string();
+            report.push("iperf3", Outcome::Ok, version);
+        }
+        Ok(output) => report.push("iperf3", Outcome::Fail, format!("Exited {}", output.status)),
+        Err(e) => report.push("iperf3", Outcome::Fail, e.to_string()),
+    }
+}
+
+fn check_grpc_port(report: &mut Report) {
+    let addr = SocketAddr::new(CONFIG.client.listen_addr, CONFIG.client.listen_port);
+    match TcpListener::bind(addr) {
+        Ok(_) => report.push("grpc port", Outcome::Ok, format!("{} is free", addr)),
+        Err(e) => report.push("grpc port", Outcome::Fail, format!("{}: {}", addr, e)),
+    }
+}
+
+/// Data is queued until a collector is reachable, so these only warn.
+fn check_collectors(report: &mut Report) {
+    for collector in CONFIG.server.collector_addrs() {
+        let addr = collector
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next());
+        let outcome = match addr {
+            Some(addr) => match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(_) => (Outcome::Ok, format!("{} is reachable", collector)),
+                Err(e) => (Outcome::Warn, format!("{}: {}", collector, e)),
+            },
+            None => (Outcome::Warn, format!("Failed to resolve {}", collector)),
+        };
+        report.push("scheduler", outcome.0, outcome.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_exit_code() {
+        let mut report = Report::default();
+        report.push("interface", Outcome::Ok, "eth0");
+        report.push("timestamps", Outcome::Warn, "No adapter");
+        assert!(report.passed());
+        assert_eq!(report.exit_code(), 0);
+
+        report.push("grpc port", Outcome::Fail, "In use");
+        assert_eq!(report.exit_code(), 1);
+        assert!(report.to_string().ends_with("1 ok, 1 warnings, 1 failed"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Table of the links printed to stdout now and then, for a quick look at a
+//! node over SSH without the scheduler or a metrics stack. Enabled by
+//! `--summary` or `client.summary_interval`.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::prost_net::metrics_store::MetricsStore;
+use crate::proto_bw::LinkState;
+
+/// Bytes/sec as Mbit/s, "-" if unknown.
+fn mbits(bytes_per_sec: f64) -> String {
+    if bytes_per_sec > 0.0 {
+        format!("{:.2}", bytes_per_sec * 8.0 / 1e6)
+    } else {
+        "-".to_string()
+    }
+}
+
+/// One line per link, under a header. Throughputs in Mbit/s, RTT in ms and
+/// loss in % of TCP segments, toward and from the peer.
+pub fn format_table(links: &[LinkState]) -> String {
+    let mut table = format!(
+        "{:<40} {:>9} {:>9} {:>9} {:>9} {:>13}\n",
+        "peer", "in", "out", "abw", "rtt ms", "loss up/down"
+    );
+    for link in links {
+        let rtt = if link.latency > 0.0 {
+            format!("{:.1}", link.latency / 1000.0)
+        } else {
+            "-".to_string()
+        };
+        let loss = format!("{:.1}/{:.1}", link.loss_up, link.loss_down);
+        let _ = writeln!(
+            table,
+            "{:<40} {:>9} {:>9} {:>9} {:>9} {:>13}",
+            link.receiver_ip,
+            mbits(link.thp_in),
+            mbits(link.thp_out),
+            mbits(link.abw),
+            rtt,
+            loss
+        );
+    }
+    table
+}
+
+/// Prints the last state of every link in `store` each `interval`.
+pub fn dispatch_summary(store: MetricsStore, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate, with nothing to show yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let links = store.links();
+            if links.is_empty() {
+                println!("No links reported yet");
+                continue;
+            }
+            println!("{}", format_table(&links));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let links = [LinkState {
+            receiver_ip: "10.0.0.2".to_string(),
+            thp_in: 1_250_000.0,
+            thp_out: 0.0,
+            abw: 2_500_000.0,
+            latency: 12_345.0,
+            loss_up: 0.5,
+            ..Default::default()
+        }];
+        let table = format_table(&links);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(fields, ["10.0.0.2", "10.00", "-", "20.00", "12.3", "0.5/0.0"]);
+    }
+}
@@ -0,0 +1,328 @@
+//! Read only view of what the listener sees, for using the crate as a
+//! library. Events are published on a broadcast channel, so a slow
+//! subscriber misses events instead of holding up the capture. Missed events
+//! are counted, see `Tap::lagged`.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::warn;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::features::FeatureVector;
+use crate::hops::PathChange;
+use crate::probe::session::ProbeTechnique;
+use crate::proto_bw::LinkState;
+use crate::stream_id::IpPair;
+use crate::tcp_tracker::Burst;
+use crate::{Direction, ParsedPacket, Timestamp};
+
+/// Events buffered per subscriber before the oldest are dropped.
+const TAP_CAPACITY: usize = 1024;
+
+/// Headers of a packet that made it past the loopback, multicast and
+/// self traffic filters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketSummary {
+    pub timestamp: Timestamp,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    /// IP protocol number.
+    pub protocol: u8,
+    /// Source and destination port, TCP and UDP only.
+    pub ports: Option<(u16, u16)>,
+    pub total_length: u16,
+    pub direction: Direction,
+    /// Generated by an active probe.
+    pub probe: bool,
+}
+
+impl PacketSummary {
+    pub fn new(packet: &ParsedPacket, probe: bool) -> Self {
+        PacketSummary {
+            timestamp: packet.timestamp,
+            src_ip: packet.src_ip,
+            dst_ip: packet.dst_ip,
+            protocol: packet.transport.get_ip_proto().0,
+            ports: packet.get_src_dst_port(),
+            total_length: packet.total_length,
+            direction: packet.direction,
+            probe,
+        }
+    }
+}
+
+/// A completed burst, before it is handed to the estimators.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BurstSummary {
+    pub direction: Direction,
+    /// IP protocol number.
+    pub protocol: u8,
+    pub packets: usize,
+    pub bytes: u64,
+    /// Bytes/sec from the first to the last packet, 0 if unknown.
+    pub throughput: f64,
+}
+
+impl BurstSummary {
+    pub fn new(burst: &Burst, direction: Direction, protocol: u8) -> Self {
+        BurstSummary {
+            direction,
+            protocol,
+            packets: burst.len(),
+            bytes: burst.burst_size_bytes(),
+            throughput: burst.throughput(),
+        }
+    }
+}
+
+/// Result of an active probe toward or from `remote`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeSample {
+    pub remote: IpAddr,
+    pub technique: ProbeTechnique,
+    /// Bytes/sec
+    pub throughput: f64,
+}
+
+#[derive(Debug, Clone)]
+pub enum TapEvent {
+    Packet(PacketSummary),
+    Burst(IpPair, BurstSummary),
+    /// Link state as sent to the scheduler.
+    LinkState(LinkState),
+    Probe(ProbeSample),
+    /// Only produced if `features.interval` is set.
+    Features(FeatureVector),
+    /// A host on the link moved to a path through another number of hops.
+    PathChange(IpPair, PathChange),
+}
+
+/// Which events a subscriber gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapFilter {
+    pub packets: bool,
+    pub bursts: bool,
+    pub link_states: bool,
+    pub probes: bool,
+    pub features: bool,
+    pub path_changes: bool,
+}
+
+impl TapFilter {
+    pub fn all() -> Self {
+        TapFilter {
+            packets: true,
+            bursts: true,
+            link_states: true,
+            probes: true,
+            features: true,
+            path_changes: true,
+        }
+    }
+
+    /// Everything but the per packet events.
+    pub fn summaries() -> Self {
+        TapFilter {
+            packets: false,
+            ..TapFilter::all()
+        }
+    }
+
+    /// Only the feature vectors.
+    pub fn features() -> Self {
+        TapFilter {
+            packets: false,
+            bursts: false,
+            link_states: false,
+            probes: false,
+            features: true,
+            path_changes: false,
+        }
+    }
+
+    /// Only the probe results.
+    pub fn probes() -> Self {
+        TapFilter {
+            packets: false,
+            bursts: false,
+            link_states: false,
+            probes: true,
+            features: false,
+            path_changes: false,
+        }
+    }
+
+    pub fn accepts(&self, event: &TapEvent) -> bool {
+        match event {
+            TapEvent::Packet(_) => self.packets,
+            TapEvent::Burst(..) => self.bursts,
+            TapEvent::LinkState(_) => self.link_states,
+            TapEvent::Probe(_) => self.probes,
+            TapEvent::Features(_) => self.features,
+            TapEvent::PathChange(..) => self.path_changes,
+        }
+    }
+}
+
+/// Gets every link state as it is reported, on the parser task and before
+/// it is serialized for the scheduler. Unlike the tap nothing is dropped, so
+/// the parser waits for it; hand anything slow off to another task.
+pub trait LinkStateConsumer: Send + Sync {
+    fn on_link_state(&self, ls: &LinkState);
+}
+
+/// The link state consumers registered with the parser.
+#[derive(Clone, Default)]
+pub struct LinkStateConsumers(Vec<Arc<dyn LinkStateConsumer>>);
+
+impl LinkStateConsumers {
+    pub fn add(&mut self, consumer: Arc<dyn LinkStateConsumer>) {
+        self.0.push(consumer);
+    }
+
+    pub fn notify(&self, ls: &LinkState) {
+        for consumer in &self.0 {
+            consumer.on_link_state(ls);
+        }
+    }
+}
+
+impl std::fmt::Debug for LinkStateConsumers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LinkStateConsumers({})", self.0.len())
+    }
+}
+
+/// Publishing end, shared by the tasks that produce events.
+#[derive(Debug, Clone)]
+pub struct Tap {
+    sender: Arc<broadcast::Sender<TapEvent>>,
+    /// Events missed by subscribers that fell behind, over all of them.
+    lagged: Arc<AtomicU64>,
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        Tap::new()
+    }
+}
+
+impl Tap {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(TAP_CAPACITY);
+        Tap {
+            sender: Arc::new(sender),
+            lagged: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// True if anyone is subscribed. Check before building an event.
+    pub fn is_active(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, event: TapEvent) {
+        // Only fails without subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Events missed by subscribers that fell behind, filtered out or not.
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+
+    /// Stream of the events accepted by `filter`, from now on. Events
+    /// missed by falling behind are skipped, logged and added to `lagged`.
+    pub fn subscribe(&self, filter: TapFilter) -> impl Stream<Item = TapEvent> {
+        let lagged = self.lagged.clone();
+        BroadcastStream::new(self.sender.subscribe()).filter_map(move |event| match event {
+            Ok(event) => Some(event).filter(|event| filter.accepts(event)),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                lagged.fetch_add(missed, Ordering::Relaxed);
+                warn!("Tap subscriber fell behind, missed {} events", missed);
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribe_filters_events() {
+        let tap = Tap::new();
+        assert!(!tap.is_active());
+        // Dropped, nobody is listening
+        tap.publish(TapEvent::Probe(ProbeSample {
+            remote: [10, 0, 0, 2].into(),
+            technique: ProbeTechnique::Iperf3,
+            throughput: 1.0,
+        }));
+
+        let mut probes = Box::pin(tap.subscribe(TapFilter {
+            packets: false,
+            bursts: false,
+            link_states: false,
+            probes: true,
+            features: false,
+            path_changes: false,
+        }));
+        assert!(tap.is_active());
+        tap.publish(TapEvent::LinkState(LinkState::default()));
+        tap.publish(TapEvent::Probe(ProbeSample {
+            remote: [10, 0, 0, 2].into(),
+            technique: ProbeTechnique::Train,
+            throughput: 2.0,
+        }));
+
+        match probes.next().await {
+            Some(TapEvent::Probe(sample)) => assert_eq!(sample.throughput, 2.0),
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_is_counted() {
+        let tap = Tap::new();
+        let mut probes = Box::pin(tap.subscribe(TapFilter::probes()));
+        for _ in 0..TAP_CAPACITY + 10 {
+            tap.publish(TapEvent::LinkState(LinkState::default()));
+        }
+        tap.publish(TapEvent::Probe(ProbeSample {
+            remote: [10, 0, 0, 2].into(),
+            technique: ProbeTechnique::Train,
+            throughput: 2.0,
+        }));
+
+        assert!(matches!(probes.next().await, Some(TapEvent::Probe(_))));
+        assert_eq!(tap.lagged(), 11);
+    }
+
+    #[test]
+    fn test_consumers_get_every_link_state() {
+        struct Counter(std::sync::Mutex<Vec<f64>>);
+        impl LinkStateConsumer for Counter {
+            fn on_link_state(&self, ls: &LinkState) {
+                self.0.lock().unwrap().push(ls.thp_in);
+            }
+        }
+
+        let counter = Arc::new(Counter(Default::default()));
+        let mut consumers = LinkStateConsumers::default();
+        consumers.add(counter.clone());
+        consumers.add(counter.clone());
+        consumers.notify(&LinkState {
+            thp_in: 3.0,
+            ..Default::default()
+        });
+        assert_eq!(*counter.0.lock().unwrap(), vec![3.0, 3.0]);
+        assert_eq!(format!("{:?}", consumers), "LinkStateConsumers(2)");
+    }
+}
@@ -0,0 +1,174 @@
+use std::ops::Add;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+
+use crate::CONFIG;
+
+/// A point in time on the capture clock, in nanoseconds since the Unix epoch.
+///
+/// Packet times come from pcap, and everything derived from them (gaps, RTTs,
+/// burst durations, timeouts) stays in this domain. Differences saturate at
+/// zero instead of failing when samples are out of order or the clock steps.
+/// Conversion to wall clock time only happens when building protobuf messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    pub const ZERO: Timestamp = Timestamp(0);
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Timestamp(nanos)
+    }
+
+    pub const fn from_micros(micros: u64) -> Self {
+        Timestamp(micros * 1_000)
+    }
+
+    pub const fn from_millis(millis: u64) -> Self {
+        Timestamp(millis * 1_000_000)
+    }
+
+    /// Converts a timestamp from the capture, which is opened with
+    /// `client.timestamp_precision`, onto the system clock by adding
+    /// `offset` nanoseconds, see `PCAPMeta::capture_offset`.
+    pub fn from_timeval(tv: libc::timeval, offset: i64) -> Self {
+        Self::from_timeval_with(tv, CONFIG.client.timestamp_precision).offset_by(offset)
+    }
+
+    /// Moved by `nanos` either way, saturating.
+    pub fn offset_by(self, nanos: i64) -> Self {
+        Timestamp(self.0.saturating_add_signed(nanos))
+    }
+
+    /// Nanoseconds from `self` to `later`, negative if it is earlier.
+    pub fn offset_to(self, later: Timestamp) -> i64 {
+        (later.0 as i128 - self.0 as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /// Converts a pcap timestamp whose sub-second field is in `precision`.
+    pub fn from_timeval_with(tv: libc::timeval, precision: pcap::Precision) -> Self {
+        let secs = tv.tv_sec.max(0) as u64;
+        let frac = tv.tv_usec.max(0) as u64;
+        let nanos = match precision {
+            pcap::Precision::Micro => frac * 1_000,
+            pcap::Precision::Nano => frac,
+        };
+        Timestamp(secs * 1_000_000_000 + nanos)
+    }
+
+    /// Current reading of the clock pcap stamps packets with.
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Milliseconds since the Unix epoch, as used in the protobuf messages.
+    pub const fn as_millis(self) -> i64 {
+        (self.0 / 1_000_000) as i64
+    }
+
+    /// Reverse of `as_millis`, for times read from protobuf messages. None
+    /// before the epoch or past what fits.
+    pub fn from_unix_millis(millis: i64) -> Option<Self> {
+        let millis = u64::try_from(millis).ok()?;
+        millis.checked_mul(1_000_000).map(Timestamp)
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.0)
+    }
+
+    pub fn to_datetime(self) -> DateTime<Utc> {
+        DateTime::from(self.to_system_time())
+    }
+
+    /// Time elapsed since `earlier`, zero if `earlier` is later than `self`.
+    pub fn saturating_duration_since(self, earlier: Timestamp) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time elapsed since `earlier`, None if `earlier` is later than `self`.
+    pub fn checked_duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    /// Times before the epoch map to `Timestamp::ZERO`.
+    fn from(time: SystemTime) -> Self {
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos().min(u64::MAX as u128) as u64);
+        Timestamp(nanos)
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_add(rhs.as_nanos().min(u64::MAX as u128) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_timeval() {
+        let tv = libc::timeval {
+            tv_sec: 2,
+            tv_usec: 500,
+        };
+        let ts = Timestamp::from_timeval_with(tv, pcap::Precision::Micro);
+        assert_eq!(ts.as_nanos(), 2_000_500_000);
+        assert_eq!(ts.as_millis(), 2000);
+        let ts = Timestamp::from_timeval_with(tv, pcap::Precision::Nano);
+        assert_eq!(ts.as_nanos(), 2_000_000_500);
+        // The capture is opened with the configured precision
+        let configured = Timestamp::from_timeval_with(tv, CONFIG.client.timestamp_precision);
+        assert_eq!(Timestamp::from_timeval(tv, 0), configured);
+        // Moved by the offset of the capture
+        assert_eq!(Timestamp::from_timeval(tv, 1_000), configured.offset_by(1_000));
+    }
+
+    #[test]
+    fn test_offset() {
+        let nic = Timestamp::from_millis(5_000);
+        let system = Timestamp::from_millis(1_700_000_000_000);
+        let offset = nic.offset_to(system);
+        assert_eq!(nic.offset_by(offset), system);
+        assert_eq!(system.offset_to(nic), -offset);
+        assert_eq!(nic.offset_by(i64::MIN), Timestamp::ZERO);
+    }
+
+    #[test]
+    fn test_duration_since_out_of_order() {
+        let early = Timestamp::from_millis(1000);
+        let late = early + Duration::from_millis(5);
+        assert_eq!(late.saturating_duration_since(early), Duration::from_millis(5));
+        assert_eq!(early.saturating_duration_since(late), Duration::ZERO);
+        assert_eq!(early.checked_duration_since(late), None);
+    }
+
+    #[test]
+    fn test_system_time_round_trip() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(Timestamp::from(time).to_system_time(), time);
+        assert_eq!(Timestamp::from(UNIX_EPOCH - Duration::from_secs(1)), Timestamp::ZERO);
+    }
+
+    #[test]
+    fn test_unix_millis_round_trip() {
+        let ts = Timestamp::from_unix_millis(1_700_000_000_123).unwrap();
+        assert_eq!(ts.as_millis(), 1_700_000_000_123);
+        assert_eq!(ts.to_datetime().timestamp_millis(), 1_700_000_000_123);
+        assert_eq!(Timestamp::from_unix_millis(-1), None);
+        assert_eq!(Timestamp::from_unix_millis(i64::MAX), None);
+    }
+}